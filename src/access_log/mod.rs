@@ -0,0 +1,423 @@
+//! Structured access logging.
+//!
+//! An [`AccessLog`] configured with [`ProxyBuilder::with_access_log`] emits one JSON record for
+//! each request that the proxy handles, once the response body has been sent to the client.
+//! Records are written to a writer, emitted as `INFO` events with the `hudsucker::access_log`
+//! tracing target, or sent to a syslog server in CEF or LEEF for a SIEM to ingest.
+//!
+//! [`ProxyBuilder::with_access_log`]: crate::builder::ProxyBuilder::with_access_log
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::access_log::{AccessLog, Field};
+//!
+//! let log = AccessLog::to_writer(std::io::stdout()).with_fields([
+//!     Field::Timestamp,
+//!     Field::Method,
+//!     Field::Host,
+//!     Field::Path,
+//!     Field::Status,
+//! ]);
+//! ```
+
+mod syslog;
+
+pub use syslog::{Syslog, SyslogFormat};
+
+use crate::{Body, Error};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    Method, Response, StatusCode,
+};
+use std::{
+    fmt::{self, Write as _},
+    io::Write,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use syslog::SyslogSender;
+use tracing::{info, warn};
+
+/// A field of an access log record.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Field {
+    /// When the request was received, in milliseconds since the Unix epoch.
+    Timestamp,
+    /// Address of the client that sent the request.
+    Client,
+    /// Method of the request.
+    Method,
+    /// Host of the request.
+    Host,
+    /// Path of the request.
+    Path,
+    /// Status of the response.
+    Status,
+    /// Number of response body bytes sent to the client.
+    Bytes,
+    /// Time from receiving the request to sending the end of the response, in milliseconds.
+    Duration,
+    /// ID of the `CONNECT` tunnel that the request was sent through.
+    TunnelId,
+}
+
+impl Field {
+    const ALL: [Field; 9] = [
+        Field::Timestamp,
+        Field::Client,
+        Field::Method,
+        Field::Host,
+        Field::Path,
+        Field::Status,
+        Field::Bytes,
+        Field::Duration,
+        Field::TunnelId,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Timestamp => "timestamp",
+            Field::Client => "client",
+            Field::Method => "method",
+            Field::Host => "host",
+            Field::Path => "path",
+            Field::Status => "status",
+            Field::Bytes => "bytes",
+            Field::Duration => "duration",
+            Field::TunnelId => "tunnel_id",
+        }
+    }
+}
+
+/// A record of a request that was handled by the proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct AccessRecord {
+    /// When the request was received.
+    pub timestamp: SystemTime,
+    /// Address of the client that sent the request.
+    pub client: SocketAddr,
+    /// Method of the request.
+    pub method: Method,
+    /// Host of the request, if it has one.
+    pub host: Option<String>,
+    /// Path of the request.
+    pub path: String,
+    /// Status of the response.
+    pub status: StatusCode,
+    /// Number of response body bytes sent to the client.
+    pub bytes: u64,
+    /// Time from receiving the request to sending the end of the response.
+    pub duration: Duration,
+    /// ID of the `CONNECT` tunnel that the request was sent through, or that the request opened.
+    pub tunnel_id: Option<u64>,
+}
+
+impl AccessRecord {
+    /// Formats the record as a JSON object with the selected fields.
+    pub fn to_json(&self, fields: &[Field]) -> String {
+        let mut out = String::from("{");
+
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            let _ = write!(out, "\"{}\":", field.name());
+
+            match field {
+                Field::Timestamp => {
+                    let millis = self
+                        .timestamp
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map(|d| d.as_millis())
+                        .unwrap_or_default();
+                    let _ = write!(out, "{}", millis);
+                }
+                Field::Client => push_string(&mut out, &self.client.to_string()),
+                Field::Method => push_string(&mut out, self.method.as_str()),
+                Field::Host => match &self.host {
+                    Some(host) => push_string(&mut out, host),
+                    None => out.push_str("null"),
+                },
+                Field::Path => push_string(&mut out, &self.path),
+                Field::Status => {
+                    let _ = write!(out, "{}", self.status.as_u16());
+                }
+                Field::Bytes => {
+                    let _ = write!(out, "{}", self.bytes);
+                }
+                Field::Duration => {
+                    let _ = write!(out, "{}", self.duration.as_millis());
+                }
+                Field::TunnelId => match self.tunnel_id {
+                    Some(id) => {
+                        let _ = write!(out, "{}", id);
+                    }
+                    None => out.push_str("null"),
+                },
+            }
+        }
+
+        out.push('}');
+        out
+    }
+}
+
+pub(crate) fn push_string(out: &mut String, s: &str) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+#[derive(Clone)]
+enum Sink {
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    Tracing,
+    Syslog(Arc<SyslogSender>),
+}
+
+/// Where access log records are emitted, and which fields they contain.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Sink,
+    fields: Arc<[Field]>,
+}
+
+impl AccessLog {
+    /// Write records to a writer, one per line.
+    pub fn to_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Sink::Writer(Arc::new(Mutex::new(writer))),
+            fields: Arc::new(Field::ALL),
+        }
+    }
+
+    /// Emit records as `INFO` events with the `hudsucker::access_log` target.
+    pub fn to_tracing() -> Self {
+        Self {
+            sink: Sink::Tracing,
+            fields: Arc::new(Field::ALL),
+        }
+    }
+
+    /// Send records to a syslog server.
+    pub fn to_syslog(syslog: Syslog) -> Self {
+        Self {
+            sink: Sink::Syslog(Arc::new(syslog.start())),
+            fields: Arc::new(Field::ALL),
+        }
+    }
+
+    /// Set the fields that records contain, in order. Defaults to every field.
+    pub fn with_fields(mut self, fields: impl IntoIterator<Item = Field>) -> Self {
+        self.fields = fields.into_iter().collect();
+        self
+    }
+
+    fn emit(&self, record: &AccessRecord) {
+        match &self.sink {
+            Sink::Writer(writer) => {
+                let json = record.to_json(&self.fields);
+                let mut writer = writer.lock().expect("Failed to lock access log writer");
+
+                if let Err(e) = writeln!(writer, "{}", json).and_then(|_| writer.flush()) {
+                    warn!("Failed to write access log record: {}", e);
+                }
+            }
+            Sink::Tracing => {
+                let json = record.to_json(&self.fields);
+                info!(target: "hudsucker::access_log", "{}", json)
+            }
+            Sink::Syslog(sender) => sender.send(record, &self.fields),
+        }
+    }
+
+    /// Emit a record for a response once its body has been sent.
+    pub(crate) fn log(
+        &self,
+        record: AccessRecord,
+        start: Instant,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let mut record = record;
+        record.status = parts.status;
+
+        let body = Logged {
+            body,
+            log: self.clone(),
+            record: Some(record),
+            start,
+        };
+
+        Response::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sink = match &self.sink {
+            Sink::Writer(_) => "Writer".to_owned(),
+            Sink::Tracing => "Tracing".to_owned(),
+            Sink::Syslog(sender) => format!("Syslog({})", sender.addr()),
+        };
+
+        f.debug_struct("AccessLog")
+            .field("sink", &sink)
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+/// A body that counts the bytes that are sent, and emits a record when it ends or is dropped.
+struct Logged {
+    body: Body,
+    log: AccessLog,
+    record: Option<AccessRecord>,
+    start: Instant,
+}
+
+impl Logged {
+    fn finish(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.duration = self.start.elapsed();
+            self.log.emit(&record);
+        }
+    }
+}
+
+impl HttpBody for Logged {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                let len = frame.data_ref().map_or(0, Bytes::len) as u64;
+
+                if let Some(record) = &mut self.record {
+                    record.bytes += len;
+                }
+
+                if self.body.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Some(Err(_)) | None => self.finish(),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_500),
+            client: "127.0.0.1:8080".parse().unwrap(),
+            method: Method::GET,
+            host: Some("example.com".to_owned()),
+            path: "/a\"b".to_owned(),
+            status: StatusCode::OK,
+            bytes: 0,
+            duration: Duration::ZERO,
+            tunnel_id: None,
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn formats_json() {
+        assert_eq!(
+            record().to_json(&Field::ALL),
+            r#"{"timestamp":1500,"client":"127.0.0.1:8080","method":"GET","host":"example.com","path":"/a\"b","status":200,"bytes":0,"duration":0,"tunnel_id":null}"#
+        );
+        assert_eq!(
+            record().to_json(&[Field::Status, Field::Method]),
+            r#"{"status":200,"method":"GET"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_when_body_ends() {
+        let buffer = Buffer::default();
+        let log = AccessLog::to_writer(buffer.clone()).with_fields([Field::Status, Field::Bytes]);
+
+        let mut res = Response::new(Body::from("hello"));
+        *res.status_mut() = StatusCode::CREATED;
+
+        let res = log.log(record(), Instant::now(), res);
+        assert!(buffer.0.lock().unwrap().is_empty());
+
+        res.into_body().collect().await.unwrap();
+        assert_eq!(
+            buffer.0.lock().unwrap().as_slice(),
+            b"{\"status\":201,\"bytes\":5}\n"
+        );
+    }
+
+    #[test]
+    fn logs_when_dropped() {
+        let buffer = Buffer::default();
+        let log = AccessLog::to_writer(buffer.clone()).with_fields([Field::Bytes]);
+
+        drop(log.log(record(), Instant::now(), Response::new(Body::from("hello"))));
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"{\"bytes\":0}\n");
+    }
+}
@@ -0,0 +1,508 @@
+use super::{AccessRecord, Field};
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+use tracing::warn;
+
+/// The number of messages that are queued while the syslog server is slow or unreachable. Later
+/// messages are dropped.
+const QUEUE_LEN: usize = 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The format of the access records that are sent to a syslog server.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum SyslogFormat {
+    /// ArcSight Common Event Format.
+    #[default]
+    Cef,
+    /// IBM QRadar Log Event Extended Format 1.0.
+    Leef,
+    /// The JSON records that [`AccessLog::to_writer`](super::AccessLog::to_writer) writes.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+enum Transport {
+    Udp,
+    Tcp,
+    Tls(ServerName<'static>, Arc<ClientConfig>),
+}
+
+/// A syslog server that access records are sent to, for [`AccessLog::to_syslog`].
+///
+/// Records are sent as RFC 5424 syslog messages, in one datagram each over UDP, or with octet
+/// counting framing (RFC 6587) over TCP and TLS. Messages are sent from a background thread, which
+/// reconnects to TCP and TLS servers when their connection fails. If the server can't keep up,
+/// records are dropped rather than delaying responses.
+///
+/// [`AccessLog::to_syslog`]: super::AccessLog::to_syslog
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::access_log::{AccessLog, Syslog, SyslogFormat};
+///
+/// let log = AccessLog::to_syslog(
+///     Syslog::udp("127.0.0.1:514".parse().unwrap()).with_format(SyslogFormat::Leef),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Syslog {
+    addr: SocketAddr,
+    transport: Transport,
+    format: SyslogFormat,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+}
+
+impl Syslog {
+    fn new(addr: SocketAddr, transport: Transport) -> Self {
+        Self {
+            addr,
+            transport,
+            format: SyslogFormat::default(),
+            facility: 16,
+            hostname: "-".to_owned(),
+            app_name: "hudsucker".to_owned(),
+        }
+    }
+
+    /// Send records to a server over UDP.
+    pub fn udp(addr: SocketAddr) -> Self {
+        Self::new(addr, Transport::Udp)
+    }
+
+    /// Send records to a server over TCP.
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self::new(addr, Transport::Tcp)
+    }
+
+    /// Send records to a server over TLS, verifying its certificate for `server_name`.
+    pub fn tls(
+        addr: SocketAddr,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Self {
+        Self::new(addr, Transport::Tls(server_name, config))
+    }
+
+    /// Set the format of the records. Defaults to [`SyslogFormat::Cef`].
+    pub fn with_format(mut self, format: SyslogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the syslog facility of the messages, from 0 to 23. Defaults to 16 (`local0`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the facility is larger than 23.
+    pub fn with_facility(mut self, facility: u8) -> Self {
+        assert!(facility <= 23, "syslog facility must be from 0 to 23");
+        self.facility = facility;
+        self
+    }
+
+    /// Set the hostname of the messages. Defaults to `-`, which leaves it to the server.
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    /// Set the application name of the messages. Defaults to `hudsucker`.
+    pub fn with_app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = app_name.into();
+        self
+    }
+
+    /// Formats a record as a syslog message.
+    fn message(&self, record: &AccessRecord, fields: &[Field]) -> Vec<u8> {
+        let severity = if record.status.is_server_error() {
+            4
+        } else {
+            6
+        };
+        let body = match self.format {
+            SyslogFormat::Cef => cef(record, fields),
+            SyslogFormat::Leef => leef(record, fields),
+            SyslogFormat::Json => record.to_json(fields),
+        };
+
+        format!(
+            "<{}>1 {} {} {} - access - {}",
+            self.facility * 8 + severity,
+            rfc3339(record.timestamp),
+            self.hostname,
+            self.app_name,
+            body
+        )
+        .into_bytes()
+    }
+
+    /// Starts the thread that sends messages to the server.
+    pub(super) fn start(self) -> SyslogSender {
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LEN);
+        let syslog = self.clone();
+
+        let spawned = thread::Builder::new()
+            .name("hudsucker-syslog".to_owned())
+            .spawn(move || {
+                let mut conn = None;
+
+                for message in rx {
+                    if let Err(e) = syslog.send(&mut conn, &message) {
+                        warn!("Failed to send access record to {}: {}", syslog.addr, e);
+                    }
+                }
+            });
+
+        if let Err(e) = spawned {
+            warn!("Failed to start syslog thread: {}", e);
+        }
+
+        SyslogSender { syslog: self, tx }
+    }
+
+    /// Sends a message, reconnecting once if the connection failed.
+    fn send(&self, conn: &mut Option<Connection>, message: &[u8]) -> io::Result<()> {
+        for retry in [false, true] {
+            let result = match conn {
+                Some(conn) => conn.send(message),
+                None => self
+                    .connect()
+                    .and_then(|new| conn.insert(new).send(message)),
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if retry => return Err(e),
+                Err(_) => *conn = None,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        match &self.transport {
+            Transport::Udp => {
+                let local = match self.addr {
+                    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(self.addr)?;
+                Ok(Connection::Udp(socket))
+            }
+            Transport::Tcp => Ok(Connection::Tcp(TcpStream::connect_timeout(
+                &self.addr,
+                CONNECT_TIMEOUT,
+            )?)),
+            Transport::Tls(server_name, config) => {
+                let tcp = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)?;
+                let tls = ClientConnection::new(Arc::clone(config), server_name.clone())
+                    .map_err(io::Error::other)?;
+                Ok(Connection::Tls(Box::new(StreamOwned::new(tls, tcp))))
+            }
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut framed = format!("{} ", message.len()).into_bytes();
+        framed.extend_from_slice(message);
+
+        match self {
+            Connection::Udp(socket) => socket.send(message).map(|_| ()),
+            Connection::Tcp(stream) => stream.write_all(&framed).and_then(|_| stream.flush()),
+            Connection::Tls(stream) => stream.write_all(&framed).and_then(|_| stream.flush()),
+        }
+    }
+}
+
+/// The queue of messages for a syslog server.
+pub(super) struct SyslogSender {
+    syslog: Syslog,
+    tx: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl SyslogSender {
+    pub(super) fn send(&self, record: &AccessRecord, fields: &[Field]) {
+        if let Err(mpsc::TrySendError::Full(_)) =
+            self.tx.try_send(self.syslog.message(record, fields))
+        {
+            warn!("Dropped access record for {}", self.syslog.addr);
+        }
+    }
+
+    pub(super) fn addr(&self) -> SocketAddr {
+        self.syslog.addr
+    }
+}
+
+/// The UTC date and time of a timestamp, as the year, month, day, hours, minutes, seconds and
+/// milliseconds.
+fn civil(timestamp: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, (secs % 86_400) as u32);
+
+    // Howard Hinnant's days_from_civil, in reverse.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+fn rfc3339(timestamp: SystemTime) -> String {
+    let (year, month, day, hours, minutes, seconds, millis) = civil(timestamp);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hours, minutes, seconds, millis
+    )
+}
+
+fn millis(timestamp: SystemTime) -> u128 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Escapes a value of a CEF extension.
+fn cef_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '=' => out.push_str("\\="),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn cef(record: &AccessRecord, fields: &[Field]) -> String {
+    let severity = match record.status.as_u16() {
+        500.. => 7,
+        400.. => 5,
+        _ => 3,
+    };
+
+    let mut out = format!(
+        "CEF:0|hudsucker|hudsucker|{}|access|HTTP request|{}|",
+        env!("CARGO_PKG_VERSION"),
+        severity
+    );
+    let mut extensions = Vec::new();
+
+    for field in fields {
+        match field {
+            Field::Timestamp => extensions.push(format!("rt={}", millis(record.timestamp))),
+            Field::Client => {
+                extensions.push(format!("src={}", record.client.ip()));
+                extensions.push(format!("spt={}", record.client.port()));
+            }
+            Field::Method => extensions.push(format!("requestMethod={}", record.method)),
+            Field::Host => {
+                if let Some(host) = &record.host {
+                    extensions.push(format!("dhost={}", cef_value(host)));
+                }
+            }
+            Field::Path => extensions.push(format!("request={}", cef_value(&record.path))),
+            Field::Status => {
+                extensions.push(format!("cn1={}", record.status.as_u16()));
+                extensions.push("cn1Label=status".to_owned());
+            }
+            Field::Bytes => extensions.push(format!("out={}", record.bytes)),
+            Field::Duration => {
+                extensions.push(format!("cn2={}", record.duration.as_millis()));
+                extensions.push("cn2Label=durationMs".to_owned());
+            }
+            Field::TunnelId => {
+                if let Some(id) = record.tunnel_id {
+                    extensions.push(format!("cn3={}", id));
+                    extensions.push("cn3Label=tunnelId".to_owned());
+                }
+            }
+        }
+    }
+
+    out.push_str(&extensions.join(" "));
+    out
+}
+
+/// Escapes a value of a LEEF attribute.
+fn leef_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn leef(record: &AccessRecord, fields: &[Field]) -> String {
+    let mut out = format!(
+        "LEEF:1.0|hudsucker|hudsucker|{}|access|",
+        env!("CARGO_PKG_VERSION")
+    );
+    let mut attributes = Vec::new();
+
+    for field in fields {
+        match field {
+            Field::Timestamp => {
+                let (year, month, day, hours, minutes, seconds, millis) = civil(record.timestamp);
+                attributes.push(format!(
+                    "devTime={} {:02} {} {:02}:{:02}:{:02}.{:03}",
+                    MONTHS[month as usize - 1],
+                    day,
+                    year,
+                    hours,
+                    minutes,
+                    seconds,
+                    millis
+                ));
+                attributes.push("devTimeFormat=MMM dd yyyy HH:mm:ss.SSS".to_owned());
+            }
+            Field::Client => {
+                attributes.push(format!("src={}", record.client.ip()));
+                attributes.push(format!("srcPort={}", record.client.port()));
+            }
+            Field::Method => attributes.push(format!("method={}", record.method)),
+            Field::Host => {
+                if let Some(host) = &record.host {
+                    attributes.push(format!("host={}", leef_value(host)));
+                }
+            }
+            Field::Path => attributes.push(format!("url={}", leef_value(&record.path))),
+            Field::Status => attributes.push(format!("status={}", record.status.as_u16())),
+            Field::Bytes => attributes.push(format!("dstBytes={}", record.bytes)),
+            Field::Duration => attributes.push(format!("duration={}", record.duration.as_millis())),
+            Field::TunnelId => {
+                if let Some(id) = record.tunnel_id {
+                    attributes.push(format!("tunnelId={}", id));
+                }
+            }
+        }
+    }
+
+    out.push_str(&attributes.join("\t"));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode};
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            client: "127.0.0.1:8080".parse().unwrap(),
+            method: Method::GET,
+            host: Some("example.com".to_owned()),
+            path: "/a=b".to_owned(),
+            status: StatusCode::NOT_FOUND,
+            bytes: 5,
+            duration: Duration::from_millis(12),
+            tunnel_id: None,
+        }
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(
+            rfc3339(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(
+            rfc3339(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn formats_cef() {
+        assert_eq!(
+            cef(&record(), &Field::ALL),
+            format!(
+                "CEF:0|hudsucker|hudsucker|{}|access|HTTP request|5|rt=1700000000123 \
+                 src=127.0.0.1 spt=8080 requestMethod=GET dhost=example.com request=/a\\=b \
+                 cn1=404 cn1Label=status out=5 cn2=12 cn2Label=durationMs",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn formats_leef() {
+        assert_eq!(
+            leef(&record(), &[Field::Timestamp, Field::Status, Field::Path]),
+            format!(
+                "LEEF:1.0|hudsucker|hudsucker|{}|access|devTime=Nov 14 2023 22:13:20.123\t\
+                 devTimeFormat=MMM dd yyyy HH:mm:ss.SSS\tstatus=404\turl=/a=b",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn sends_messages_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let sender = Syslog::udp(server.local_addr().unwrap())
+            .with_format(SyslogFormat::Json)
+            .with_hostname("proxy")
+            .start();
+        sender.send(&record(), &[Field::Status]);
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            "<134>1 2023-11-14T22:13:20.123Z proxy hudsucker - access - {\"status\":404}"
+        );
+    }
+}
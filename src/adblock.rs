@@ -0,0 +1,859 @@
+//! Filtering of requests with adblock filter lists, such as EasyList.
+//!
+//! A [`FilterList`] holds the network rules of lists in the Adblock Plus and uBlock Origin syntax,
+//! including their options, and [`AdblockHandler`] applies it to requests. Requests that match a
+//! blocking rule are either answered with a status, or annotated with a [`FilterMatch`] in their
+//! extensions and passed on, so that a content-filtering proxy can decide what to do with them.
+//!
+//! The rules are matched against the full URL of each request, so they only apply to the
+//! requests of tunnels that are intercepted. `CONNECT` requests are matched against rules that
+//! block whole domains, such as `||ads.example^`, which blocks the tunnel before it is opened.
+//!
+//! The page that a request is made from is taken from its `Referer` or `Origin` header, and its
+//! resource type from its `Sec-Fetch-Dest` or `Accept` header. Requests are third-party if their
+//! host and the host of their page do not share the last two labels, which is an approximation of
+//! the registrable domain that does not need a public suffix list.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::adblock::{AdblockHandler, FilterAction, FilterList};
+//!
+//! let list = FilterList::new()
+//!     .with_list(
+//!         "! Title: Example list\n\
+//!          ||ads.example^\n\
+//!          /banner/*$image,third-party\n\
+//!          @@||ads.example/consent.js$script",
+//!     )
+//!     .unwrap();
+//!
+//! let handler = AdblockHandler::new(list).with_action(FilterAction::Annotate);
+//! ```
+
+use crate::{
+    auth::host, Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use http_body_util::Empty;
+use hyper::{
+    header::{ACCEPT, ORIGIN, REFERER, UPGRADE},
+    Method, Request, Response, StatusCode, Uri,
+};
+use regex::RegexSet;
+use std::{fmt, net::IpAddr, sync::Arc};
+use tracing::debug;
+
+/// The type of resource that a request is for, which rules can be restricted to.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ResourceType {
+    /// A top-level document, matched by the `document` option.
+    Document,
+    /// A document in a frame, matched by the `subdocument` option.
+    Subdocument,
+    /// A script or worker, matched by the `script` option.
+    Script,
+    /// A stylesheet, matched by the `stylesheet` option.
+    Stylesheet,
+    /// An image, matched by the `image` option.
+    Image,
+    /// A font, matched by the `font` option.
+    Font,
+    /// Audio, video or a text track, matched by the `media` option.
+    Media,
+    /// The content of a plugin, matched by the `object` option.
+    Object,
+    /// A request with `fetch` or `XMLHttpRequest`, matched by the `xmlhttprequest` option.
+    XmlHttpRequest,
+    /// A WebSocket connection, matched by the `websocket` option.
+    WebSocket,
+    /// A hyperlink auditing ping or beacon, matched by the `ping` option.
+    Ping,
+    /// Any other resource, matched by the `other` option.
+    Other,
+}
+
+impl ResourceType {
+    const ALL: u16 = (1 << 12) - 1;
+
+    /// The resource type of a request, from its headers.
+    pub fn of<T>(req: &Request<T>) -> Self {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_ascii_lowercase)
+        };
+
+        if header(UPGRADE.as_str()).is_some_and(|upgrade| upgrade == "websocket") {
+            return Self::WebSocket;
+        }
+
+        if req.headers().contains_key("ping-to") || req.headers().contains_key("ping-from") {
+            return Self::Ping;
+        }
+
+        if let Some(dest) = header("sec-fetch-dest") {
+            return match dest.as_str() {
+                "document" => Self::Document,
+                "iframe" | "frame" | "fencedframe" => Self::Subdocument,
+                "script" | "worker" | "sharedworker" | "serviceworker" | "audioworklet"
+                | "paintworklet" => Self::Script,
+                "style" => Self::Stylesheet,
+                "image" => Self::Image,
+                "font" => Self::Font,
+                "audio" | "video" | "track" => Self::Media,
+                "object" | "embed" => Self::Object,
+                "empty" => Self::XmlHttpRequest,
+                _ => Self::Other,
+            };
+        }
+
+        match header(ACCEPT.as_str()) {
+            Some(accept) if accept.starts_with("text/html") => Self::Document,
+            Some(accept) if accept.starts_with("text/css") => Self::Stylesheet,
+            Some(accept) if accept.starts_with("image/") => Self::Image,
+            _ => Self::Other,
+        }
+    }
+
+    fn from_option(option: &str) -> Option<Self> {
+        Some(match option {
+            "document" | "doc" => Self::Document,
+            "subdocument" | "frame" => Self::Subdocument,
+            "script" => Self::Script,
+            "stylesheet" | "css" => Self::Stylesheet,
+            "image" => Self::Image,
+            "font" => Self::Font,
+            "media" => Self::Media,
+            "object" => Self::Object,
+            "xmlhttprequest" | "xhr" => Self::XmlHttpRequest,
+            "websocket" => Self::WebSocket,
+            "ping" | "beacon" => Self::Ping,
+            "other" => Self::Other,
+            _ => return None,
+        })
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A network rule of a filter list.
+#[derive(Clone, Debug)]
+struct Filter {
+    text: Arc<str>,
+    exception: bool,
+    important: bool,
+    /// The resource types that the rule applies to.
+    types: u16,
+    third_party: Option<bool>,
+    /// The domains of the pages that the rule applies to, and whether they are included.
+    domains: Vec<(String, bool)>,
+    /// The domain of a rule that starts with `||`, if the domain is complete.
+    domain: Option<String>,
+    /// Whether the rule matches every URL of its domain, without any options.
+    whole_domain: bool,
+}
+
+impl Filter {
+    /// Parse a rule, returning it with the regular expression of its pattern, or `None` if it is
+    /// not a network rule or has options that are not supported.
+    fn parse(line: &str) -> Option<(Self, String)> {
+        if line.is_empty()
+            || line.starts_with('!')
+            || line.starts_with('[')
+            || ["##", "#@#", "#?#", "#$#", "#%#"]
+                .iter()
+                .any(|separator| line.contains(separator))
+        {
+            return None;
+        }
+
+        let (rule, exception) = match line.strip_prefix("@@") {
+            Some(rule) => (rule, true),
+            None => (line, false),
+        };
+
+        let (pattern, options) = split_options(rule);
+        let mut filter = Self {
+            text: Arc::from(line),
+            exception,
+            important: false,
+            types: ResourceType::ALL,
+            third_party: None,
+            domains: Vec::new(),
+            domain: None,
+            whole_domain: false,
+        };
+        let mut match_case = false;
+        let (mut included, mut excluded) = (0, 0);
+
+        for option in options.into_iter().flat_map(|options| options.split(',')) {
+            let (negated, name) = match option.strip_prefix('~') {
+                Some(name) => (true, name),
+                None => (false, option),
+            };
+
+            if let Some(domains) = name
+                .strip_prefix("domain=")
+                .or_else(|| name.strip_prefix("from="))
+            {
+                for domain in domains.split('|').filter(|domain| !domain.is_empty()) {
+                    filter.domains.push(match domain.strip_prefix('~') {
+                        Some(domain) => (domain.to_ascii_lowercase(), false),
+                        None => (domain.to_ascii_lowercase(), true),
+                    });
+                }
+            } else if let Some(resource_type) = ResourceType::from_option(name) {
+                if negated {
+                    excluded |= resource_type.bit();
+                } else {
+                    included |= resource_type.bit();
+                }
+            } else {
+                match name {
+                    "all" if !negated => included |= ResourceType::ALL,
+                    "third-party" | "3p" => filter.third_party = Some(!negated),
+                    "first-party" | "1p" => filter.third_party = Some(negated),
+                    "match-case" if !negated => match_case = true,
+                    "important" if !negated => filter.important = true,
+                    _ => return None,
+                }
+            }
+        }
+
+        if included != 0 {
+            filter.types = included;
+        }
+        filter.types &= !excluded;
+
+        let regex = match pattern
+            .strip_prefix('/')
+            .and_then(|regex| regex.strip_suffix('/'))
+            .filter(|regex| !regex.is_empty())
+        {
+            Some(regex) => regex.to_owned(),
+            None => {
+                if let Some(anchored) = pattern.strip_prefix("||") {
+                    let end = anchored
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '.'))
+                        .unwrap_or(anchored.len());
+                    let (domain, rest) = anchored.split_at(end);
+
+                    if !domain.is_empty() && !rest.starts_with('*') {
+                        filter.domain = Some(domain.to_ascii_lowercase());
+                        filter.whole_domain =
+                            (rest.is_empty() || rest == "^") && options.is_none() && !exception;
+                    }
+                }
+
+                pattern_regex(pattern)
+            }
+        };
+
+        let regex = if match_case {
+            regex
+        } else {
+            format!("(?i){}", regex)
+        };
+
+        Some((filter, regex))
+    }
+
+    fn applies(&self, info: &RequestInfo<'_>) -> bool {
+        self.types & info.resource_type.bit() != 0
+            && self
+                .third_party
+                .map_or(true, |third_party| third_party == info.third_party)
+            && self.applies_to_page(info.source.as_deref())
+    }
+
+    fn applies_to_page(&self, source: Option<&str>) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+
+        // The most specific domain that the page is on decides, and pages on none of the domains
+        // are only included if every domain is excluded.
+        match self
+            .domains
+            .iter()
+            .filter(|(domain, _)| source.is_some_and(|source| is_subdomain(source, domain)))
+            .max_by_key(|(domain, _)| domain.len())
+        {
+            Some(&(_, included)) => included,
+            None => self.domains.iter().all(|(_, included)| !included),
+        }
+    }
+}
+
+/// Split a rule into its pattern and options, which follow the last `$` of the rule.
+fn split_options(rule: &str) -> (&str, Option<&str>) {
+    match rule.rsplit_once('$') {
+        Some((pattern, options))
+            if !options.is_empty()
+                && options
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"~,=|.-_*".contains(&b)) =>
+        {
+            (pattern, Some(options))
+        }
+        _ => (rule, None),
+    }
+}
+
+/// A regular expression for the pattern of a rule that is not a regular expression.
+fn pattern_regex(pattern: &str) -> String {
+    let mut regex = String::new();
+    let mut rest = pattern;
+
+    if let Some(domain) = rest.strip_prefix("||") {
+        regex.push_str(r"^[a-z][a-z0-9+.-]*://(?:[^/?#]*\.)?");
+        rest = domain;
+    } else if let Some(start) = rest.strip_prefix('|') {
+        regex.push('^');
+        rest = start;
+    }
+
+    let (rest, anchored_end) = match rest.strip_suffix('|') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+
+    for c in rest.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '^' => regex.push_str(r"(?:[^A-Za-z0-9_.%-]|$)"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    if anchored_end {
+        regex.push('$');
+    }
+
+    regex
+}
+
+/// Whether a host is a domain or one of its subdomains.
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    host.strip_suffix(domain)
+        .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+}
+
+/// The last two labels of a host, or the host if it is an IP address.
+fn site(host: &str) -> &str {
+    if host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+
+    match host.rmatch_indices('.').nth(1) {
+        Some((i, _)) => &host[i + 1..],
+        None => host,
+    }
+}
+
+/// What the rules of a filter list are matched against.
+struct RequestInfo<'a> {
+    url: &'a str,
+    source: Option<String>,
+    resource_type: ResourceType,
+    third_party: bool,
+}
+
+/// The rule of a filter list that matched a request.
+///
+/// This is inserted into the extensions of each request that matches a rule before it is passed
+/// to the handler wrapped by an [`AdblockHandler`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FilterMatch {
+    filter: Arc<str>,
+    blocked: bool,
+}
+
+impl FilterMatch {
+    /// The text of the rule that matched, as it appears in the filter list.
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Whether the request is blocked. This is `false` if the rule that matched is an exception
+    /// that overrides a blocking rule.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+}
+
+/// The network rules of adblock filter lists.
+///
+/// Blocking rules, exception rules that start with `@@`, and regular expressions between slashes
+/// are supported, with the `domain`, `third-party`, `match-case`, `important` and resource type
+/// options and their negations. Comments, cosmetic rules, and rules with other options, such as
+/// `redirect` or `csp`, are ignored.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Debug, Default)]
+pub struct FilterList {
+    filters: Vec<Filter>,
+    patterns: Vec<String>,
+    set: Option<RegexSet>,
+}
+
+impl FilterList {
+    /// Creates a new filter list without any rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the rules of a filter list.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a regular expression in the list is invalid, or if the rules are too
+    /// many to be compiled.
+    pub fn with_list(mut self, list: &str) -> Result<Self, regex::Error> {
+        let mut patterns = self.patterns.clone();
+
+        for line in list.lines().map(str::trim) {
+            if let Some((filter, regex)) = Filter::parse(line) {
+                self.filters.push(filter);
+                patterns.push(regex);
+            }
+        }
+
+        self.set = Some(RegexSet::new(&patterns)?);
+        self.patterns = patterns;
+        Ok(self)
+    }
+
+    /// The number of rules in the list.
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Whether the list has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// The rule that matches a request, if any.
+    ///
+    /// Exception rules override blocking rules, unless the blocking rule has the `important`
+    /// option. An exception rule is only returned if it overrides a blocking rule.
+    pub fn check<T>(&self, req: &Request<T>) -> Option<FilterMatch> {
+        let host = host(req)?.trim_end_matches('.').to_ascii_lowercase();
+
+        if req.method() == Method::CONNECT {
+            return self.check_domain(&host);
+        }
+
+        req.uri().scheme()?;
+
+        let url = req.uri().to_string();
+        let source = req
+            .headers()
+            .get(REFERER)
+            .or_else(|| req.headers().get(ORIGIN))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Uri>().ok())
+            .and_then(|uri| uri.host().map(str::to_ascii_lowercase));
+        let info = RequestInfo {
+            url: &url,
+            third_party: source
+                .as_deref()
+                .is_some_and(|source| site(source) != site(&host)),
+            source,
+            resource_type: ResourceType::of(req),
+        };
+
+        let (mut blocking, mut exception) = (None, None);
+
+        for filter in self
+            .set
+            .as_ref()?
+            .matches(info.url)
+            .into_iter()
+            .map(|i| &self.filters[i])
+            .filter(|filter| filter.applies(&info))
+        {
+            if filter.exception {
+                exception.get_or_insert(filter);
+            } else if filter.important {
+                return Some(FilterMatch {
+                    filter: Arc::clone(&filter.text),
+                    blocked: true,
+                });
+            } else {
+                blocking.get_or_insert(filter);
+            }
+        }
+
+        blocking.map(|blocking| match exception {
+            Some(exception) => FilterMatch {
+                filter: Arc::clone(&exception.text),
+                blocked: false,
+            },
+            None => FilterMatch {
+                filter: Arc::clone(&blocking.text),
+                blocked: true,
+            },
+        })
+    }
+
+    /// The rule that blocks a whole domain, unless an exception rule for the domain may allow
+    /// some of its URLs.
+    fn check_domain(&self, host: &str) -> Option<FilterMatch> {
+        let covers = |filter: &&Filter| {
+            filter
+                .domain
+                .as_deref()
+                .is_some_and(|domain| is_subdomain(host, domain))
+        };
+
+        let blocking = self
+            .filters
+            .iter()
+            .filter(|filter| filter.whole_domain)
+            .find(covers)?;
+
+        if let Some(exception) = self
+            .filters
+            .iter()
+            .filter(|filter| filter.exception)
+            .find(covers)
+        {
+            return Some(FilterMatch {
+                filter: Arc::clone(&exception.text),
+                blocked: false,
+            });
+        }
+
+        Some(FilterMatch {
+            filter: Arc::clone(&blocking.text),
+            blocked: true,
+        })
+    }
+}
+
+/// What is done with requests that match a blocking rule.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum FilterAction {
+    /// Respond with a status.
+    Block(StatusCode),
+    /// Pass the request to the wrapped handler, with a [`FilterMatch`] in its extensions.
+    Annotate,
+}
+
+impl Default for FilterAction {
+    fn default() -> Self {
+        Self::Block(StatusCode::FORBIDDEN)
+    }
+}
+
+/// An HTTP handler that filters requests with a [`FilterList`].
+///
+/// Requests that match a blocking rule are answered with `403 Forbidden` by default. Requests that
+/// match a rule are passed to the wrapped handler with a [`FilterMatch`] in their extensions.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone)]
+pub struct AdblockHandler<H = NoopHandler> {
+    list: Arc<FilterList>,
+    action: FilterAction,
+    inner: H,
+}
+
+impl AdblockHandler {
+    /// Creates a new handler that blocks the requests that match a filter list.
+    pub fn new(list: FilterList) -> Self {
+        Self {
+            list: Arc::new(list),
+            action: FilterAction::default(),
+            inner: NoopHandler::new(),
+        }
+    }
+}
+
+impl<H> AdblockHandler<H> {
+    /// Set the handler that requests that are not blocked and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> AdblockHandler<H2> {
+        AdblockHandler {
+            list: self.list,
+            action: self.action,
+            inner,
+        }
+    }
+
+    /// Set what is done with requests that match a blocking rule.
+    pub fn with_action(mut self, action: FilterAction) -> Self {
+        self.action = action;
+        self
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for AdblockHandler<H> {
+    async fn handle_request(
+        &mut self,
+        ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        if let Some(filter_match) = self.list.check(&req) {
+            if let (true, FilterAction::Block(status)) = (filter_match.blocked, self.action) {
+                debug!(uri = %req.uri(), filter = %filter_match.filter, "Blocked request");
+
+                return Response::builder()
+                    .status(status)
+                    .body(Body::from(Empty::new()))
+                    .expect("Failed to build response")
+                    .into();
+            }
+
+            req.extensions_mut().insert(filter_match);
+        }
+
+        self.inner.handle_request(ctx, req).await
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+impl<H: fmt::Debug> fmt::Debug for AdblockHandler<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdblockHandler")
+            .field("rules", &self.list.len())
+            .field("action", &self.action)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri(uri);
+
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+
+        req.body(Body::from(Empty::new())).unwrap()
+    }
+
+    fn check(list: &FilterList, uri: &str, headers: &[(&str, &str)]) -> Option<(String, bool)> {
+        list.check(&request(Method::GET, uri, headers))
+            .map(|m| (m.filter().to_owned(), m.is_blocked()))
+    }
+
+    #[test]
+    fn applies_options() {
+        let list = FilterList::new()
+            .with_list(
+                "[Adblock Plus 2.0]\n\
+                 ! Comment\n\
+                 example.com##.ad\n\
+                 /banner/*$image,third-party\n\
+                 /track.js$script,domain=news.example|~sports.news.example\n\
+                 /Pixel.gif$match-case\n\
+                 ||cdn.example^$~xhr\n\
+                 ||redirect.example^$redirect=noop.js\n",
+            )
+            .unwrap();
+        assert_eq!(list.len(), 4);
+
+        let image = [
+            ("sec-fetch-dest", "image"),
+            ("referer", "https://a.example/"),
+        ];
+        assert_eq!(
+            check(&list, "https://img.other/banner/1.png", &image),
+            Some(("/banner/*$image,third-party".to_owned(), true))
+        );
+        assert_eq!(
+            check(&list, "https://img.a.example/banner/1.png", &image),
+            None
+        );
+        assert_eq!(
+            check(
+                &list,
+                "https://img.other/banner/1.png",
+                &[("accept", "text/css")]
+            ),
+            None
+        );
+
+        let script = |referer| [("sec-fetch-dest", "script"), ("referer", referer)];
+        assert!(check(
+            &list,
+            "https://t.example/track.js",
+            &script("https://news.example/")
+        )
+        .is_some());
+        assert!(check(
+            &list,
+            "https://t.example/track.js",
+            &script("https://sports.news.example/")
+        )
+        .is_none());
+        assert!(check(
+            &list,
+            "https://t.example/track.js",
+            &script("https://b.example/")
+        )
+        .is_none());
+
+        assert!(check(&list, "https://a.example/Pixel.gif", &[]).is_some());
+        assert!(check(&list, "https://a.example/pixel.gif", &[]).is_none());
+
+        assert!(check(&list, "https://cdn.example/lib.js", &[]).is_some());
+        assert!(check(
+            &list,
+            "https://cdn.example/api",
+            &[("sec-fetch-dest", "empty")]
+        )
+        .is_none());
+        assert!(check(&list, "https://redirect.example/", &[]).is_none());
+    }
+
+    #[test]
+    fn applies_exceptions() {
+        let list = FilterList::new()
+            .with_list(
+                "||ads.example^\n\
+                 @@||ads.example/consent.js\n\
+                 ||tracker.example^\n\
+                 /beacon^$important\n\
+                 @@/beacon^\n",
+            )
+            .unwrap();
+
+        assert_eq!(
+            check(&list, "https://ads.example/consent.js", &[]),
+            Some(("@@||ads.example/consent.js".to_owned(), false))
+        );
+        assert_eq!(
+            check(&list, "https://www.ads.example/ad.js", &[]),
+            Some(("||ads.example^".to_owned(), true))
+        );
+        assert_eq!(
+            check(&list, "https://a.example/beacon?1", &[]),
+            Some(("/beacon^$important".to_owned(), true))
+        );
+
+        let connect = |authority| {
+            list.check(&request(Method::CONNECT, authority, &[]))
+                .map(|m| m.is_blocked())
+        };
+        assert_eq!(connect("cdn.tracker.example:443"), Some(true));
+        assert_eq!(connect("ads.example:443"), Some(false));
+        assert_eq!(connect("example.com:443"), None);
+    }
+
+    #[tokio::test]
+    async fn blocks_and_annotates_requests() {
+        let ctx = HttpContext {
+            client_addr: "127.0.0.1:8080".parse().unwrap(),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            upstream_version: None,
+        };
+        let list = FilterList::new().with_list("||ads.example^").unwrap();
+
+        let mut handler = AdblockHandler::new(list.clone());
+        let res = handler
+            .handle_request(&ctx, request(Method::GET, "http://ads.example/", &[]))
+            .await;
+        let RequestOrResponse::Response(res) = res else {
+            panic!("expected a response");
+        };
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let mut handler = AdblockHandler::new(list).with_action(FilterAction::Annotate);
+        let req = handler
+            .handle_request(&ctx, request(Method::GET, "http://ads.example/", &[]))
+            .await;
+        let RequestOrResponse::Request(req) = req else {
+            panic!("expected a request");
+        };
+        assert_eq!(
+            req.extensions()
+                .get::<FilterMatch>()
+                .map(FilterMatch::filter),
+            Some("||ads.example^")
+        );
+    }
+}
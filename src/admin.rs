@@ -0,0 +1,830 @@
+//! An embedded admin API for managing a running proxy.
+//!
+//! An [`Admin`] that is set with [`ProxyBuilder::with_admin`](crate::ProxyBuilder::with_admin)
+//! collects statistics about the proxy and its active connections, holds the lists of hosts whose
+//! `CONNECT` tunnels are intercepted, and serves a JSON API on its own listener:
+//!
+//! | Request              | Description                                                    |
+//! | -------------------- | -------------------------------------------------------------- |
+//! | `GET /health`        | Returns `{"status": "ok"}`.                                    |
+//! | `GET /stats`         | Returns the [`Stats`] of the proxy.                            |
+//! | `GET /connections`   | Returns the active client [`Connection`]s.                     |
+//! | `GET /bandwidth`     | Returns the [`Bandwidth`] used by each host.                   |
+//! | `GET /interception`  | Returns the [`Interception`] lists.                            |
+//! | `PUT /interception`  | Replaces the [`Interception`] lists with the ones in the body. |
+//! | `POST /reload`       | Runs the triggers set with [`Admin::on_reload`].               |
+//!
+//! The API is not authenticated, so it should only listen on a loopback or otherwise trusted
+//! interface.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::admin::Admin;
+//! use std::net::SocketAddr;
+//!
+//! let admin = Admin::bind(SocketAddr::from(([127, 0, 0, 1], 8081))).on_reload(|| async {
+//!     // Reload rules from disk...
+//!     Ok(())
+//! });
+//! ```
+
+use crate::{auth::HostPattern, Body, Bounded, Error};
+use futures::future::BoxFuture;
+use http_body_util::{combinators::BoxBody, Full};
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, Incoming, SizeHint},
+    header::{HeaderValue, ALLOW, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tokio_graceful::ShutdownGuard;
+use tracing::error;
+
+/// The largest body that is accepted by the API.
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+type Reload = dyn Fn() -> BoxFuture<'static, Result<(), Box<dyn std::error::Error + Send + Sync>>>
+    + Send
+    + Sync;
+
+pub(crate) enum AdminListener {
+    Addr(SocketAddr),
+    Listener(TcpListener),
+}
+
+/// Statistics about a proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The time since the proxy was built.
+    pub uptime: Duration,
+    /// The number of client connections that have been accepted.
+    pub connections: u64,
+    /// The number of client connections that are open.
+    pub active_connections: usize,
+    /// The number of requests that have been received, including `CONNECT` requests.
+    pub requests: u64,
+    /// The number of `CONNECT` tunnels that have been opened.
+    pub tunnels: u64,
+    /// The number of `CONNECT` tunnels that have been intercepted.
+    pub intercepted_tunnels: u64,
+}
+
+/// An active client connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Connection {
+    /// Unique ID of the connection.
+    pub id: u64,
+    /// Address of the client.
+    pub client_addr: SocketAddr,
+    /// The time that the connection was accepted.
+    pub since: SystemTime,
+    /// The number of requests that have been received on the connection.
+    pub requests: u64,
+}
+
+/// The number of bytes that were transferred to and from a host.
+///
+/// Bytes are counted for the bodies of the requests that are sent to the host and of the responses
+/// that are received from it, and for the data that is forwarded in both directions through the
+/// `CONNECT` tunnels to the host that are not intercepted. The bytes of a tunnel are counted when
+/// it is closed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Bandwidth {
+    /// The number of bytes sent to the host.
+    pub sent: u64,
+    /// The number of bytes received from the host.
+    pub received: u64,
+}
+
+/// The lists of hosts whose `CONNECT` tunnels are intercepted.
+///
+/// A tunnel is not intercepted if its host matches a pattern in the deny list, or if the allow
+/// list is not empty and the host does not match any of its patterns. Otherwise, whether the
+/// tunnel is intercepted is decided by [`HttpHandler::should_intercept`](crate::HttpHandler::should_intercept).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Interception {
+    /// Patterns of hosts that may be intercepted.
+    pub allow: Vec<HostPattern>,
+    /// Patterns of hosts that are never intercepted.
+    pub deny: Vec<HostPattern>,
+}
+
+impl Interception {
+    /// Whether the lists allow a tunnel to a host to be intercepted.
+    pub fn allows(&self, host: &str) -> bool {
+        !self.deny.iter().any(|pattern| pattern.matches(host))
+            && (self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(host)))
+    }
+
+    fn to_json(&self) -> Value {
+        let patterns = |list: &[HostPattern]| {
+            list.iter()
+                .map(|pattern| pattern.as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        json!({
+            "allow": patterns(&self.allow),
+            "deny": patterns(&self.deny),
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let patterns = |key: &str| match value.get(key) {
+            Some(Value::Array(list)) => list
+                .iter()
+                .map(|pattern| pattern.as_str().map(HostPattern::new))
+                .collect(),
+            None => Some(Vec::new()),
+            Some(_) => None,
+        };
+
+        Some(Self {
+            allow: patterns("allow")?,
+            deny: patterns("deny")?,
+        })
+    }
+}
+
+struct Entry {
+    client_addr: SocketAddr,
+    since: SystemTime,
+    requests: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Transfer {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+struct Shared {
+    started: Instant,
+    listener: Mutex<Option<AdminListener>>,
+    next_connection_id: AtomicU64,
+    connections_total: AtomicU64,
+    requests: AtomicU64,
+    tunnels: AtomicU64,
+    intercepted_tunnels: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Entry>>,
+    bandwidth: Mutex<BTreeMap<String, Arc<Transfer>>>,
+    interception: RwLock<Interception>,
+    reloads: Mutex<Vec<Arc<Reload>>>,
+}
+
+/// Shared state of the admin API.
+///
+/// Clones of an `Admin` share the same state, so a clone can be kept to read statistics or to
+/// change the interception lists from code.
+#[derive(Clone)]
+pub struct Admin {
+    shared: Arc<Shared>,
+}
+
+impl Admin {
+    fn new(listener: AdminListener) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                started: Instant::now(),
+                listener: Mutex::new(Some(listener)),
+                next_connection_id: AtomicU64::new(1),
+                connections_total: AtomicU64::new(0),
+                requests: AtomicU64::new(0),
+                tunnels: AtomicU64::new(0),
+                intercepted_tunnels: AtomicU64::new(0),
+                connections: Mutex::new(BTreeMap::new()),
+                bandwidth: Mutex::new(BTreeMap::new()),
+                interception: RwLock::new(Interception::default()),
+                reloads: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Creates an admin API that listens on an address when the proxy is started.
+    pub fn bind(addr: SocketAddr) -> Self {
+        Self::new(AdminListener::Addr(addr))
+    }
+
+    /// Creates an admin API that accepts connections from a listener when the proxy is started.
+    pub fn with_listener(listener: TcpListener) -> Self {
+        Self::new(AdminListener::Listener(listener))
+    }
+
+    /// Adds a trigger that is run by `POST /reload`, such as reloading rules from a file.
+    ///
+    /// Triggers are run in the order that they were added, and the request fails with the error
+    /// of the first trigger that fails.
+    pub fn on_reload<F, Fut>(self, reload: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>> + Send + 'static,
+    {
+        self.shared
+            .reloads
+            .lock()
+            .expect("Failed to lock reload triggers")
+            .push(Arc::new(move || Box::pin(reload())));
+        self
+    }
+
+    /// Statistics about the proxy.
+    pub fn stats(&self) -> Stats {
+        let shared = &self.shared;
+
+        Stats {
+            uptime: shared.started.elapsed(),
+            connections: shared.connections_total.load(Ordering::Relaxed),
+            active_connections: shared
+                .connections
+                .lock()
+                .expect("Failed to lock connections")
+                .len(),
+            requests: shared.requests.load(Ordering::Relaxed),
+            tunnels: shared.tunnels.load(Ordering::Relaxed),
+            intercepted_tunnels: shared.intercepted_tunnels.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The active client connections, ordered by when they were accepted.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.shared
+            .connections
+            .lock()
+            .expect("Failed to lock connections")
+            .iter()
+            .map(|(id, entry)| Connection {
+                id: *id,
+                client_addr: entry.client_addr,
+                since: entry.since,
+                requests: entry.requests.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// The bandwidth used by each host, by lowercase host name.
+    pub fn bandwidth(&self) -> BTreeMap<String, Bandwidth> {
+        self.shared
+            .bandwidth
+            .lock()
+            .expect("Failed to lock bandwidth")
+            .iter()
+            .map(|(host, transfer)| {
+                (
+                    host.clone(),
+                    Bandwidth {
+                        sent: transfer.sent.load(Ordering::Relaxed),
+                        received: transfer.received.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The lists of hosts whose tunnels are intercepted.
+    pub fn interception(&self) -> Interception {
+        self.shared
+            .interception
+            .read()
+            .expect("Failed to lock interception lists")
+            .clone()
+    }
+
+    /// Replaces the lists of hosts whose tunnels are intercepted.
+    pub fn set_interception(&self, interception: Interception) {
+        *self
+            .shared
+            .interception
+            .write()
+            .expect("Failed to lock interception lists") = interception;
+    }
+
+    /// Adds a pattern of hosts whose tunnels are passed through without being intercepted, to the
+    /// deny list. Applies to the tunnels that are opened after this is called.
+    pub fn add_passthrough(&self, pattern: HostPattern) {
+        let mut interception = self
+            .shared
+            .interception
+            .write()
+            .expect("Failed to lock interception lists");
+
+        if !interception.deny.contains(&pattern) {
+            interception.deny.push(pattern);
+        }
+    }
+
+    /// Removes a pattern from the deny list, returning whether it was in the list.
+    pub fn remove_passthrough(&self, pattern: &str) -> bool {
+        let mut interception = self
+            .shared
+            .interception
+            .write()
+            .expect("Failed to lock interception lists");
+        let pattern = HostPattern::new(pattern);
+        let len = interception.deny.len();
+
+        interception.deny.retain(|p| *p != pattern);
+        interception.deny.len() != len
+    }
+
+    pub(crate) fn take_listener(&self) -> Option<AdminListener> {
+        self.shared
+            .listener
+            .lock()
+            .expect("Failed to lock admin listener")
+            .take()
+    }
+
+    /// Registers a client connection, which is removed when the returned guard is dropped.
+    pub(crate) fn open_connection(&self, client_addr: SocketAddr) -> ConnectionGuard {
+        let shared = &self.shared;
+        let id = shared.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let requests = Arc::new(AtomicU64::new(0));
+
+        shared.connections_total.fetch_add(1, Ordering::Relaxed);
+        shared
+            .connections
+            .lock()
+            .expect("Failed to lock connections")
+            .insert(
+                id,
+                Entry {
+                    client_addr,
+                    since: SystemTime::now(),
+                    requests: Arc::clone(&requests),
+                },
+            );
+
+        ConnectionGuard {
+            admin: self.clone(),
+            id,
+            requests,
+        }
+    }
+
+    pub(crate) fn record_tunnel(&self, intercepted: bool) {
+        self.shared.tunnels.fetch_add(1, Ordering::Relaxed);
+
+        if intercepted {
+            self.shared
+                .intercepted_tunnels
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn transfer(&self, host: &str) -> Arc<Transfer> {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+
+        Arc::clone(
+            self.shared
+                .bandwidth
+                .lock()
+                .expect("Failed to lock bandwidth")
+                .entry(host)
+                .or_default(),
+        )
+    }
+
+    /// Adds the bytes that were forwarded through a tunnel to the bandwidth of its host.
+    pub(crate) fn record_bandwidth(&self, host: &str, sent: u64, received: u64) {
+        let transfer = self.transfer(host);
+        transfer.sent.fetch_add(sent, Ordering::Relaxed);
+        transfer.received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    /// Counts the bytes of a request body that is sent to a host.
+    pub(crate) fn count_request(&self, host: &str, req: Request<Body>) -> Request<Body> {
+        let (parts, body) = req.into_parts();
+        let body = Counted {
+            body,
+            transfer: self.transfer(host),
+            sent: true,
+        };
+
+        Request::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+
+    /// Counts the bytes of a response body that is received from a host.
+    pub(crate) fn count_response(&self, host: &str, res: Response<Body>) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let body = Counted {
+            body,
+            transfer: self.transfer(host),
+            sent: false,
+        };
+
+        Response::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+
+    async fn reload(&self) -> Result<(), String> {
+        let reloads = self
+            .shared
+            .reloads
+            .lock()
+            .expect("Failed to lock reload triggers")
+            .clone();
+
+        for reload in reloads {
+            reload().await.map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let allow = match req.uri().path() {
+            "/health" | "/stats" | "/connections" | "/bandwidth" => "GET",
+            "/interception" => "GET, PUT",
+            "/reload" => "POST",
+            _ => return reply(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        };
+
+        match (req.method().clone(), req.uri().path()) {
+            (Method::GET, "/health") => reply(StatusCode::OK, json!({ "status": "ok" })),
+            (Method::GET, "/stats") => {
+                let stats = self.stats();
+
+                reply(
+                    StatusCode::OK,
+                    json!({
+                        "uptime_secs": stats.uptime.as_secs(),
+                        "connections": stats.connections,
+                        "active_connections": stats.active_connections,
+                        "requests": stats.requests,
+                        "tunnels": stats.tunnels,
+                        "intercepted_tunnels": stats.intercepted_tunnels,
+                    }),
+                )
+            }
+            (Method::GET, "/connections") => {
+                let connections = self
+                    .connections()
+                    .into_iter()
+                    .map(|conn| {
+                        json!({
+                            "id": conn.id,
+                            "client_addr": conn.client_addr.to_string(),
+                            "since": conn
+                                .since
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs(),
+                            "requests": conn.requests,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                reply(StatusCode::OK, Value::Array(connections))
+            }
+            (Method::GET, "/bandwidth") => {
+                let bandwidth = self
+                    .bandwidth()
+                    .into_iter()
+                    .map(|(host, bandwidth)| {
+                        (
+                            host,
+                            json!({
+                                "sent": bandwidth.sent,
+                                "received": bandwidth.received,
+                            }),
+                        )
+                    })
+                    .collect();
+
+                reply(StatusCode::OK, Value::Object(bandwidth))
+            }
+            (Method::GET, "/interception") => reply(StatusCode::OK, self.interception().to_json()),
+            (Method::PUT, "/interception") => {
+                let data = match req.into_body().collect_up_to(MAX_BODY_LEN).await {
+                    Ok(Bounded::Complete { data, .. }) => data,
+                    Ok(Bounded::TooLarge(_)) => {
+                        return reply(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            json!({ "error": "body too large" }),
+                        )
+                    }
+                    Err(_) => {
+                        return reply(
+                            StatusCode::BAD_REQUEST,
+                            json!({ "error": "failed to read body" }),
+                        )
+                    }
+                };
+
+                let Some(interception) = serde_json::from_slice(&data)
+                    .ok()
+                    .as_ref()
+                    .and_then(Interception::from_json)
+                else {
+                    return reply(
+                        StatusCode::BAD_REQUEST,
+                        json!({ "error": "expected lists of host patterns" }),
+                    );
+                };
+
+                let value = interception.to_json();
+                self.set_interception(interception);
+                reply(StatusCode::OK, value)
+            }
+            (Method::POST, "/reload") => match self.reload().await {
+                Ok(()) => reply(StatusCode::OK, json!({ "status": "reloaded" })),
+                Err(e) => reply(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": e })),
+            },
+            _ => {
+                let mut res = reply(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    json!({ "error": "method not allowed" }),
+                );
+                res.headers_mut()
+                    .insert(ALLOW, HeaderValue::from_static(allow));
+                res
+            }
+        }
+    }
+
+    /// Serves the API until the proxy is shut down.
+    pub(crate) async fn serve(self, listener: TcpListener, guard: ShutdownGuard) {
+        loop {
+            tokio::select! {
+                res = listener.accept() => {
+                    let tcp = match res {
+                        Ok((tcp, _)) => tcp,
+                        Err(e) => {
+                            error!("Failed to accept admin connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let admin = self.clone();
+
+                    guard.spawn_task_fn(move |guard| async move {
+                        let service = service_fn(|req: Request<Incoming>| {
+                            let admin = admin.clone();
+                            async move { Ok::<_, std::convert::Infallible>(admin.handle(req.map(Body::from)).await) }
+                        });
+
+                        let conn = http1::Builder::new().serve_connection(TokioIo::new(tcp), service);
+                        let mut conn = std::pin::pin!(conn);
+
+                        if let Err(err) = tokio::select! {
+                            conn = conn.as_mut() => conn,
+                            _ = guard.cancelled() => {
+                                conn.as_mut().graceful_shutdown();
+                                conn.await
+                            }
+                        } {
+                            error!("Error serving admin connection: {}", err);
+                        }
+                    });
+                }
+                _ = guard.cancelled() => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin")
+            .field("stats", &self.stats())
+            .field("interception", &self.interception())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A registered client connection.
+pub(crate) struct ConnectionGuard {
+    admin: Admin,
+    id: u64,
+    requests: Arc<AtomicU64>,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.admin.shared.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.admin
+            .shared
+            .connections
+            .lock()
+            .expect("Failed to lock connections")
+            .remove(&self.id);
+    }
+}
+
+/// A body that adds the length of each of its chunks to the bandwidth of a host.
+struct Counted {
+    body: Body,
+    transfer: Arc<Transfer>,
+    /// Whether the body is sent to the host, rather than received from it.
+    sent: bool,
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        if let Some(Ok(frame)) = &frame {
+            let len = frame.data_ref().map_or(0, Bytes::len) as u64;
+            let counter = if self.sent {
+                &self.transfer.sent
+            } else {
+                &self.transfer.received
+            };
+
+            counter.fetch_add(len, Ordering::Relaxed);
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+fn reply(status: StatusCode, value: Value) -> Response<Body> {
+    let mut res = Response::new(Body::from(Full::new(Bytes::from(value.to_string()))));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty};
+
+    fn admin() -> Admin {
+        Admin::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    async fn request(
+        admin: &Admin,
+        method: Method,
+        path: &str,
+        body: &'static str,
+    ) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body))
+            .unwrap();
+        let res = admin.handle(req).await;
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn filters_hosts() {
+        let mut interception = Interception::default();
+        assert!(interception.allows("example.com"));
+
+        interception.allow = vec![HostPattern::new("*.example.com")];
+        interception.deny = vec![HostPattern::new("bank.example.com")];
+        assert!(interception.allows("www.example.com"));
+        assert!(!interception.allows("bank.example.com"));
+        assert!(!interception.allows("example.org"));
+    }
+
+    #[test]
+    fn adds_and_removes_passthrough_hosts() {
+        let admin = Admin::bind(SocketAddr::from(([127, 0, 0, 1], 0)));
+
+        admin.add_passthrough(HostPattern::new("*.bank.example"));
+        admin.add_passthrough(HostPattern::new("*.bank.example"));
+        assert_eq!(admin.interception().deny.len(), 1);
+        assert!(!admin.interception().allows("www.bank.example"));
+
+        assert!(admin.remove_passthrough("*.bank.example"));
+        assert!(!admin.remove_passthrough("*.bank.example"));
+        assert!(admin.interception().allows("www.bank.example"));
+    }
+
+    #[tokio::test]
+    async fn reports_stats_and_connections() {
+        let admin = admin();
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+
+        let conn = admin.open_connection(client_addr);
+        conn.record_request();
+        admin.record_tunnel(true);
+        admin.record_bandwidth("Example.com", 3, 5);
+
+        let (status, stats) = request(&admin, Method::GET, "/stats", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["connections"], 1);
+        assert_eq!(stats["active_connections"], 1);
+        assert_eq!(stats["requests"], 1);
+        assert_eq!(stats["intercepted_tunnels"], 1);
+
+        let (_, connections) = request(&admin, Method::GET, "/connections", "").await;
+        assert_eq!(connections[0]["client_addr"], "127.0.0.1:8080");
+        assert_eq!(connections[0]["requests"], 1);
+
+        let (_, bandwidth) = request(&admin, Method::GET, "/bandwidth", "").await;
+        assert_eq!(
+            bandwidth,
+            json!({"example.com": {"sent": 3, "received": 5}})
+        );
+
+        drop(conn);
+        assert_eq!(admin.stats().active_connections, 0);
+        assert!(admin.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replaces_interception_lists() {
+        let admin = admin();
+
+        let (status, _) = request(
+            &admin,
+            Method::PUT,
+            "/interception",
+            r#"{"allow": ["*.Example.com"], "deny": ["bank.example.com"]}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!admin.interception().allows("bank.example.com"));
+
+        let (_, lists) = request(&admin, Method::GET, "/interception", "").await;
+        assert_eq!(
+            lists,
+            json!({"allow": ["*.example.com"], "deny": ["bank.example.com"]})
+        );
+
+        let (status, _) = request(&admin, Method::PUT, "/interception", r#"{"allow": [1]}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(admin.interception().deny.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn runs_reload_triggers() {
+        let admin = admin()
+            .on_reload(|| async { Ok(()) })
+            .on_reload(|| async { Err("missing rules file".into()) });
+
+        let (status, body) = request(&admin, Method::POST, "/reload", "").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"], "missing rules file");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_requests() {
+        let admin = admin();
+
+        let (status, _) = request(&admin, Method::GET, "/unknown", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let res = admin
+            .handle(
+                Request::post("/stats")
+                    .body(Body::from(Empty::new()))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[ALLOW], "GET");
+    }
+}
@@ -0,0 +1,653 @@
+//! Tamper-evident audit logging of modifications made by handlers.
+//!
+//! An [`AuditLog`] configured with [`ProxyBuilder::with_audit_log`] records every change that the
+//! HTTP handler makes to requests and responses: changed methods, URIs, and statuses, added and
+//! removed headers, rewritten bodies, and responses that are returned without forwarding the
+//! request. Each change is appended to the log as a JSON line with the flow ID of the request
+//! (see [`HttpContext::flow_id`](crate::HttpContext::flow_id)).
+//!
+//! Every line contains the SHA-256 hash of the line before it, and its own hash, so that edited,
+//! reordered, or removed lines can be detected with [`AuditLog::verify`]. Bodies are identified by
+//! their SHA-256 hashes rather than their contents.
+//!
+//! [`ProxyBuilder::with_audit_log`]: crate::builder::ProxyBuilder::with_audit_log
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::audit::AuditLog;
+//!
+//! let log = AuditLog::open("audit.log").expect("Failed to open audit log");
+//! ```
+
+use crate::{access_log::push_string, Body, BodyDirection, Error};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use ring::digest::{self, Context as Digest, SHA256};
+use std::{
+    fmt::{self, Write as _},
+    fs::OpenOptions,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+use tracing::warn;
+
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const HASH_FIELD: &str = ",\"hash\":\"";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    hex(digest::digest(&SHA256, bytes).as_ref())
+}
+
+#[derive(Debug)]
+enum Modification {
+    MethodChanged {
+        from: Method,
+        to: Method,
+    },
+    UriRewritten {
+        from: Uri,
+        to: Uri,
+    },
+    StatusChanged {
+        from: StatusCode,
+        to: StatusCode,
+    },
+    HeaderAdded {
+        name: HeaderName,
+        value: HeaderValue,
+    },
+    HeaderRemoved {
+        name: HeaderName,
+        value: HeaderValue,
+    },
+    BodyRewritten {
+        original: Option<String>,
+        modified: Option<String>,
+    },
+    ResponseMocked {
+        status: StatusCode,
+    },
+}
+
+impl Modification {
+    fn write_json(&self, out: &mut String) {
+        let string = |out: &mut String, key: &str, value: &str| {
+            let _ = write!(out, ",\"{}\":", key);
+            push_string(out, value);
+        };
+        let hash = |out: &mut String, key: &str, value: &Option<String>| match value {
+            Some(value) => string(out, key, value),
+            None => {
+                let _ = write!(out, ",\"{}\":null", key);
+            }
+        };
+
+        match self {
+            Modification::MethodChanged { from, to } => {
+                string(out, "type", "method_changed");
+                string(out, "from", from.as_str());
+                string(out, "to", to.as_str());
+            }
+            Modification::UriRewritten { from, to } => {
+                string(out, "type", "uri_rewritten");
+                string(out, "from", &from.to_string());
+                string(out, "to", &to.to_string());
+            }
+            Modification::StatusChanged { from, to } => {
+                string(out, "type", "status_changed");
+                let _ = write!(out, ",\"from\":{},\"to\":{}", from.as_u16(), to.as_u16());
+            }
+            Modification::HeaderAdded { name, value } => {
+                string(out, "type", "header_added");
+                string(out, "name", name.as_str());
+                string(out, "value", &String::from_utf8_lossy(value.as_bytes()));
+            }
+            Modification::HeaderRemoved { name, value } => {
+                string(out, "type", "header_removed");
+                string(out, "name", name.as_str());
+                string(out, "value", &String::from_utf8_lossy(value.as_bytes()));
+            }
+            Modification::BodyRewritten { original, modified } => {
+                string(out, "type", "body_rewritten");
+                hash(out, "original", original);
+                hash(out, "modified", modified);
+            }
+            Modification::ResponseMocked { status } => {
+                string(out, "type", "response_mocked");
+                let _ = write!(out, ",\"status\":{}", status.as_u16());
+            }
+        }
+    }
+}
+
+struct State {
+    writer: Box<dyn Write + Send>,
+    sequence: u64,
+    last_hash: String,
+}
+
+/// An append-only log of modifications made by handlers.
+///
+/// Clones of a log append to the same writer.
+#[derive(Clone)]
+pub struct AuditLog {
+    state: Arc<Mutex<State>>,
+}
+
+impl AuditLog {
+    /// Starts a new log that is written to a writer.
+    pub fn to_writer(writer: impl Write + Send + 'static) -> Self {
+        Self::with_state(Box::new(writer), 0, GENESIS.to_owned())
+    }
+
+    /// Opens a log file for appending, creating it if it does not exist.
+    ///
+    /// The existing entries in the file are verified, and new entries continue their hash chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened, or if its entries have been tampered with.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let (sequence, last_hash) = verify_chain(BufReader::new(&mut file))?;
+        Ok(Self::with_state(Box::new(file), sequence, last_hash))
+    }
+
+    fn with_state(writer: Box<dyn Write + Send>, sequence: u64, last_hash: String) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                writer,
+                sequence,
+                last_hash,
+            })),
+        }
+    }
+
+    /// Verifies the hash chain of a log, and returns the number of entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with [`io::ErrorKind::InvalidData`] if an entry has been modified,
+    /// reordered, or removed, other than from the end of the log.
+    pub fn verify(reader: impl BufRead) -> io::Result<u64> {
+        verify_chain(reader).map(|(entries, _)| entries)
+    }
+
+    fn record(&self, flow_id: u64, direction: BodyDirection, modification: Modification) {
+        let mut state = self.state.lock().expect("Failed to lock audit log");
+        let sequence = state.sequence + 1;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let direction = match direction {
+            BodyDirection::Request => "request",
+            BodyDirection::Response => "response",
+        };
+
+        let mut line = format!(
+            "{{\"sequence\":{},\"timestamp\":{},\"flow_id\":{},\"direction\":\"{}\"",
+            sequence, timestamp, flow_id, direction
+        );
+        modification.write_json(&mut line);
+        let _ = write!(line, ",\"prev\":\"{}\"", state.last_hash);
+
+        let hash = sha256(line.as_bytes());
+        let _ = writeln!(line, "{}{}\"}}", HASH_FIELD, hash);
+
+        if let Err(e) = state
+            .writer
+            .write_all(line.as_bytes())
+            .and_then(|_| state.writer.flush())
+        {
+            warn!("Failed to write audit log entry: {}", e);
+            return;
+        }
+
+        state.sequence = sequence;
+        state.last_hash = hash;
+    }
+
+    /// Start tracking the modifications made to a request by a handler.
+    pub(crate) fn track_request(
+        &self,
+        flow_id: u64,
+        req: Request<Body>,
+    ) -> (Request<Body>, Tracker) {
+        let (parts, body) = req.into_parts();
+        let (body, digests) = self.track_body(flow_id, BodyDirection::Request, body);
+
+        let tracker = Tracker {
+            log: self.clone(),
+            flow_id,
+            direction: BodyDirection::Request,
+            method: Some(parts.method.clone()),
+            uri: Some(parts.uri.clone()),
+            status: None,
+            headers: parts.headers.clone(),
+            digests,
+        };
+
+        (Request::from_parts(parts, body), tracker)
+    }
+
+    /// Start tracking the modifications made to a response by a handler.
+    pub(crate) fn track_response(
+        &self,
+        flow_id: u64,
+        res: Response<Body>,
+    ) -> (Response<Body>, Tracker) {
+        let (parts, body) = res.into_parts();
+        let (body, digests) = self.track_body(flow_id, BodyDirection::Response, body);
+
+        let tracker = Tracker {
+            log: self.clone(),
+            flow_id,
+            direction: BodyDirection::Response,
+            method: None,
+            uri: None,
+            status: Some(parts.status),
+            headers: parts.headers.clone(),
+            digests,
+        };
+
+        (Response::from_parts(parts, body), tracker)
+    }
+
+    /// Record a response that a handler returned without forwarding the request.
+    pub(crate) fn mocked(&self, flow_id: u64, res: &Response<Body>) {
+        self.record(
+            flow_id,
+            BodyDirection::Response,
+            Modification::ResponseMocked {
+                status: res.status(),
+            },
+        );
+    }
+
+    fn track_body(
+        &self,
+        flow_id: u64,
+        direction: BodyDirection,
+        body: Body,
+    ) -> (Body, Arc<Mutex<Digests>>) {
+        let digests = Arc::new(Mutex::new(Digests {
+            log: self.clone(),
+            flow_id,
+            direction,
+            original: None,
+            modified: None,
+        }));
+
+        let body = Hashing::wrap(body, Arc::clone(&digests), Side::Original);
+        (body, digests)
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+fn invalid(line: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid audit log entry on line {}: {}", line, reason),
+    )
+}
+
+fn verify_chain(reader: impl BufRead) -> io::Result<(u64, String)> {
+    let mut sequence = 0;
+    let mut last_hash = GENESIS.to_owned();
+
+    for line in reader.lines() {
+        let line = line?;
+        sequence += 1;
+
+        let (content, hash) = line
+            .rsplit_once(HASH_FIELD)
+            .ok_or_else(|| invalid(sequence, "missing hash"))?;
+        let hash = hash
+            .strip_suffix("\"}")
+            .ok_or_else(|| invalid(sequence, "missing hash"))?;
+
+        if !content.starts_with(&format!("{{\"sequence\":{},", sequence)) {
+            return Err(invalid(sequence, "unexpected sequence number"));
+        }
+
+        if !content.ends_with(&format!(",\"prev\":\"{}\"", last_hash)) {
+            return Err(invalid(sequence, "previous hash does not match"));
+        }
+
+        if sha256(content.as_bytes()) != hash {
+            return Err(invalid(sequence, "hash does not match"));
+        }
+
+        last_hash = hash.to_owned();
+    }
+
+    Ok((sequence, last_hash))
+}
+
+/// A snapshot of a request or response before it was passed to a handler.
+pub(crate) struct Tracker {
+    log: AuditLog,
+    flow_id: u64,
+    direction: BodyDirection,
+    method: Option<Method>,
+    uri: Option<Uri>,
+    status: Option<StatusCode>,
+    headers: HeaderMap,
+    digests: Arc<Mutex<Digests>>,
+}
+
+impl Tracker {
+    /// Record the modifications made to a request, and track modifications of its body.
+    pub(crate) fn finish_request(self, req: Request<Body>) -> Request<Body> {
+        if let Some(from) = self.method.clone().filter(|from| from != req.method()) {
+            self.record(Modification::MethodChanged {
+                from,
+                to: req.method().clone(),
+            });
+        }
+
+        if let Some(from) = self.uri.clone().filter(|from| from != req.uri()) {
+            self.record(Modification::UriRewritten {
+                from,
+                to: req.uri().clone(),
+            });
+        }
+
+        self.diff_headers(req.headers());
+
+        let (parts, body) = req.into_parts();
+        Request::from_parts(parts, Hashing::wrap(body, self.digests, Side::Modified))
+    }
+
+    /// Record the modifications made to a response, and track modifications of its body.
+    pub(crate) fn finish_response(self, res: Response<Body>) -> Response<Body> {
+        if let Some(from) = self.status.filter(|from| *from != res.status()) {
+            self.record(Modification::StatusChanged {
+                from,
+                to: res.status(),
+            });
+        }
+
+        self.diff_headers(res.headers());
+
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, Hashing::wrap(body, self.digests, Side::Modified))
+    }
+
+    fn diff_headers(&self, headers: &HeaderMap) {
+        for (name, value) in &self.headers {
+            if !headers.get_all(name).iter().any(|v| v == value) {
+                self.record(Modification::HeaderRemoved {
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+
+        for (name, value) in headers {
+            if !self.headers.get_all(name).iter().any(|v| v == value) {
+                self.record(Modification::HeaderAdded {
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+
+    fn record(&self, modification: Modification) {
+        self.log.record(self.flow_id, self.direction, modification);
+    }
+}
+
+/// The hashes of a body before and after it was passed to a handler. A hash is `None` if the body
+/// was not read to the end.
+struct Digests {
+    log: AuditLog,
+    flow_id: u64,
+    direction: BodyDirection,
+    original: Option<Option<String>>,
+    modified: Option<Option<String>>,
+}
+
+impl Digests {
+    fn set(&mut self, side: Side, hash: Option<String>) {
+        match side {
+            Side::Original => self.original = Some(hash),
+            Side::Modified => self.modified = Some(hash),
+        }
+
+        if let (Some(original), Some(modified)) = (&self.original, &self.modified) {
+            if original != modified && (original.is_some() || modified.is_some()) {
+                self.log.record(
+                    self.flow_id,
+                    self.direction,
+                    Modification::BodyRewritten {
+                        original: original.clone(),
+                        modified: modified.clone(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Original,
+    Modified,
+}
+
+/// A body that hashes its data as it is streamed.
+struct Hashing {
+    body: Body,
+    digest: Option<Digest>,
+    digests: Arc<Mutex<Digests>>,
+    side: Side,
+}
+
+impl Hashing {
+    fn wrap(body: Body, digests: Arc<Mutex<Digests>>, side: Side) -> Body {
+        Body::from(BoxBody::new(Self {
+            body,
+            digest: Some(Digest::new(&SHA256)),
+            digests,
+            side,
+        }))
+    }
+
+    fn finish(&mut self, complete: bool) {
+        if let Some(digest) = self.digest.take() {
+            let hash = complete.then(|| hex(digest.finish().as_ref()));
+            self.digests
+                .lock()
+                .expect("Failed to lock body digests")
+                .set(self.side, hash);
+        }
+    }
+}
+
+impl HttpBody for Hashing {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(digest), Some(data)) = (&mut self.digest, frame.data_ref()) {
+                    digest.update(data);
+                }
+
+                if self.body.is_end_stream() {
+                    self.finish(true);
+                }
+            }
+            Some(Err(_)) => self.finish(false),
+            None => self.finish(true),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Hashing {
+    fn drop(&mut self) {
+        let complete = self.body.is_end_stream();
+        self.finish(complete);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request() -> Request<Body> {
+        Request::builder()
+            .uri("http://example.com/")
+            .header("x-removed", "a")
+            .header("x-kept", "b")
+            .body(Body::from("original"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_request_modifications() {
+        let buffer = Buffer::default();
+        let log = AuditLog::to_writer(buffer.clone());
+
+        let (req, tracker) = log.track_request(7, request());
+        let (mut parts, _) = req.into_parts();
+        parts.uri = Uri::from_static("http://example.org/");
+        parts.headers.remove("x-removed");
+        parts
+            .headers
+            .insert("x-added", HeaderValue::from_static("c"));
+
+        let req = tracker.finish_request(Request::from_parts(parts, Body::from("modified")));
+        req.into_body().collect().await.unwrap();
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains(r#""flow_id":7,"direction":"request","type":"uri_rewritten""#));
+        assert!(lines[1].contains(r#""type":"header_removed","name":"x-removed","value":"a""#));
+        assert!(lines[2].contains(r#""type":"header_added","name":"x-added","value":"c""#));
+        assert!(lines[3].contains(&format!(
+            r#""type":"body_rewritten","original":null,"modified":"{}""#,
+            sha256(b"modified")
+        )));
+        assert_eq!(
+            AuditLog::verify(buffer.lines().join("\n").as_bytes()).unwrap(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_unmodified_requests() {
+        let buffer = Buffer::default();
+        let log = AuditLog::to_writer(buffer.clone());
+
+        let (req, tracker) = log.track_request(1, request());
+        let req = tracker.finish_request(req);
+        req.into_body().collect().await.unwrap();
+
+        assert!(buffer.lines().is_empty());
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let buffer = Buffer::default();
+        let log = AuditLog::to_writer(buffer.clone());
+        let res = Response::new(Body::from(http_body_util::Empty::new()));
+
+        for flow_id in 1..=3 {
+            log.mocked(flow_id, &res);
+        }
+
+        let lines = buffer.lines();
+        assert_eq!(AuditLog::verify(lines.join("\n").as_bytes()).unwrap(), 3);
+
+        let edited = lines[1].replace("\"flow_id\":2", "\"flow_id\":5");
+        let tampered = [lines[0].clone(), edited, lines[2].clone()].join("\n");
+        assert!(AuditLog::verify(tampered.as_bytes()).is_err());
+
+        let removed = [lines[0].clone(), lines[2].clone()].join("\n");
+        assert!(AuditLog::verify(removed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn continues_chain() {
+        let path = std::env::temp_dir().join(format!("hudsucker-audit-{}.log", std::process::id()));
+        let res = Response::new(Body::from(http_body_util::Empty::new()));
+
+        AuditLog::open(&path).unwrap().mocked(1, &res);
+        AuditLog::open(&path).unwrap().mocked(2, &res);
+
+        let file = std::fs::File::open(&path).unwrap();
+        assert_eq!(AuditLog::verify(BufReader::new(file)).unwrap(), 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
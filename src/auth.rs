@@ -0,0 +1,594 @@
+//! Injection of credentials into upstream requests.
+//!
+//! [`AuthHandler`] matches requests against a list of [`AuthRule`]s and adds the credentials from
+//! the first matching rule, so that secrets can be kept in the proxy instead of in every client.
+
+use crate::{Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse};
+use futures::future::BoxFuture;
+use hyper::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    http::uri::Scheme,
+    Request, Response, StatusCode,
+};
+use std::{
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// A credential that is added to requests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Credential {
+    /// HTTP Basic authentication, sent in the `Authorization` header.
+    Basic {
+        /// The username.
+        username: String,
+        /// The password.
+        password: String,
+    },
+    /// A bearer token, sent in the `Authorization` header.
+    Bearer(String),
+    /// An arbitrary header, such as an API key.
+    Header(HeaderName, HeaderValue),
+}
+
+impl Credential {
+    fn header(&self) -> Option<(HeaderName, HeaderValue)> {
+        let value = match self {
+            Self::Basic { username, password } => {
+                format!(
+                    "Basic {}",
+                    base64(format!("{username}:{password}").as_bytes())
+                )
+            }
+            Self::Bearer(token) => format!("Bearer {token}"),
+            Self::Header(name, value) => {
+                let mut value = value.clone();
+                value.set_sensitive(true);
+                return Some((name.clone(), value));
+            }
+        };
+
+        let mut value = HeaderValue::from_str(&value).ok()?;
+        value.set_sensitive(true);
+        Some((AUTHORIZATION, value))
+    }
+}
+
+pub(crate) fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// A pattern that is matched against the host of a request.
+///
+/// Patterns are either an exact host (`example.com`), a wildcard that matches all subdomains of a
+/// host (`*.example.com`), or `*`, which matches every host. Matching is case-insensitive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    /// Creates a new host pattern.
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self(pattern.into().to_ascii_lowercase())
+    }
+
+    /// The pattern, in lowercase.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the pattern matches a host.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+
+        match self.0.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => host == self.0,
+        }
+    }
+}
+
+impl<T: Into<String>> From<T> for HostPattern {
+    fn from(pattern: T) -> Self {
+        Self::new(pattern)
+    }
+}
+
+type Refresh = dyn Fn() -> BoxFuture<'static, Option<(Credential, Option<Duration>)>> + Send + Sync;
+
+struct Token {
+    credential: Credential,
+    expires: Option<Instant>,
+}
+
+enum Source {
+    Static(Credential),
+    Refreshing {
+        refresh: Box<Refresh>,
+        token: Mutex<Option<Token>>,
+    },
+}
+
+impl Source {
+    async fn credential(&self) -> Option<Credential> {
+        match self {
+            Self::Static(credential) => Some(credential.clone()),
+            Self::Refreshing { refresh, token } => {
+                let mut token = token.lock().await;
+
+                if let Some(t) = &*token {
+                    if t.expires.map_or(true, |expires| expires > Instant::now()) {
+                        return Some(t.credential.clone());
+                    }
+                }
+
+                let (credential, ttl) = refresh().await?;
+                *token = Some(Token {
+                    credential: credential.clone(),
+                    expires: ttl.map(|ttl| Instant::now() + ttl),
+                });
+
+                Some(credential)
+            }
+        }
+    }
+
+    async fn invalidate(&self) {
+        if let Self::Refreshing { token, .. } = self {
+            *token.lock().await = None;
+        }
+    }
+}
+
+/// A rule that adds a credential to requests for matching hosts.
+///
+/// Credentials are only added to `https` requests, unless cleartext requests are allowed with
+/// [`AuthRule::with_cleartext`].
+#[derive(Clone)]
+pub struct AuthRule {
+    pattern: HostPattern,
+    source: Arc<Source>,
+    cleartext: bool,
+}
+
+impl AuthRule {
+    /// Creates a new rule that adds a fixed credential.
+    pub fn new(pattern: impl Into<HostPattern>, credential: Credential) -> Self {
+        Self {
+            pattern: pattern.into(),
+            source: Arc::new(Source::Static(credential)),
+            cleartext: false,
+        }
+    }
+
+    /// Creates a new rule that obtains its credential from `refresh`.
+    ///
+    /// `refresh` is called when the rule is first used, when the previous credential's lifetime
+    /// has passed, and after an upstream server responds with `401 Unauthorized`. It returns the
+    /// credential together with how long it remains valid for, or `None` if no credential could be
+    /// obtained, in which case the request is sent without one.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::auth::{AuthRule, Credential};
+    /// use std::time::Duration;
+    ///
+    /// let rule = AuthRule::refreshing("api.example.com", || async {
+    ///     // Fetch a new token from an identity provider.
+    ///     let token = "token".to_owned();
+    ///     Some((Credential::Bearer(token), Some(Duration::from_secs(3600))))
+    /// });
+    /// ```
+    pub fn refreshing<F, Fut>(pattern: impl Into<HostPattern>, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<(Credential, Option<Duration>)>> + Send + 'static,
+    {
+        Self {
+            pattern: pattern.into(),
+            source: Arc::new(Source::Refreshing {
+                refresh: Box::new(move || Box::pin(refresh())),
+                token: Mutex::new(None),
+            }),
+            cleartext: false,
+        }
+    }
+
+    /// Set whether the credential is also added to cleartext `http` requests, where it can be read
+    /// by anyone on the network path to the server. Defaults to `false`.
+    pub fn with_cleartext(mut self, cleartext: bool) -> Self {
+        self.cleartext = cleartext;
+        self
+    }
+
+    /// The pattern that the rule matches hosts with.
+    pub fn pattern(&self) -> &HostPattern {
+        &self.pattern
+    }
+}
+
+impl fmt::Debug for AuthRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRule")
+            .field("pattern", &self.pattern)
+            .field("cleartext", &self.cleartext)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The host of a request, from its URI or its `Host` header.
+pub(crate) fn host<T>(req: &Request<T>) -> Option<&str> {
+    req.uri().host().or_else(|| {
+        req.headers()
+            .get(hyper::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.rsplit_once(':').map_or(host, |(host, _)| host))
+    })
+}
+
+/// An HTTP handler that adds credentials to requests.
+///
+/// Requests are passed to the wrapped handler before credentials are added, so credentials are
+/// never visible to it. Credentials replace any that the client sent, and are only added to
+/// `https` requests unless a rule allows cleartext requests.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::auth::{AuthHandler, AuthRule, Credential};
+///
+/// let handler = AuthHandler::new()
+///     .with_rule(AuthRule::new(
+///         "*.example.com",
+///         Credential::Bearer("secret".to_owned()),
+///     ))
+///     .with_rule(AuthRule::new(
+///         "example.org",
+///         Credential::Basic {
+///             username: "user".to_owned(),
+///             password: "password".to_owned(),
+///         },
+///     ));
+/// ```
+#[derive(Clone, Debug)]
+pub struct AuthHandler<H = NoopHandler> {
+    rules: Arc<Vec<AuthRule>>,
+    inner: H,
+    used: Option<AuthRule>,
+}
+
+impl AuthHandler {
+    /// Creates a new auth handler without any rules.
+    pub fn new() -> Self {
+        Self {
+            rules: Arc::new(Vec::new()),
+            inner: NoopHandler::default(),
+            used: None,
+        }
+    }
+}
+
+impl Default for AuthHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> AuthHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> AuthHandler<H2> {
+        AuthHandler {
+            rules: self.rules,
+            inner,
+            used: None,
+        }
+    }
+
+    /// Add a rule. Rules are matched in the order that they are added, and only the first matching
+    /// rule is applied.
+    pub fn with_rule(mut self, rule: AuthRule) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    async fn authorize(&mut self, mut req: Request<Body>) -> Request<Body> {
+        self.used = None;
+
+        let Some(rule) = host(&req)
+            .and_then(|host| self.rules.iter().find(|rule| rule.pattern.matches(host)))
+            .filter(|rule| rule.cleartext || req.uri().scheme() == Some(&Scheme::HTTPS))
+            .cloned()
+        else {
+            return req;
+        };
+
+        if let Some((name, value)) = rule
+            .source
+            .credential()
+            .await
+            .and_then(|credential| credential.header())
+        {
+            req.headers_mut().insert(name, value);
+            self.used = Some(rule);
+        }
+
+        req
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for AuthHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.authorize(req).await.into(),
+            res => {
+                self.used = None;
+                res
+            }
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        if let Some(rule) = self.used.take() {
+            if res.status() == StatusCode::UNAUTHORIZED {
+                rule.source.invalidate().await;
+            }
+        }
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.used = None;
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn request(uri: &'static str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .body(Body::from(Empty::new()))
+            .unwrap()
+    }
+
+    async fn authorization(handler: &mut AuthHandler, uri: &'static str) -> Option<HeaderValue> {
+        match handler
+            .handle_request(&HttpContext::for_test(), request(uri))
+            .await
+        {
+            RequestOrResponse::Request(req) => req.headers().get(AUTHORIZATION).cloned(),
+            RequestOrResponse::Response(_) => panic!("expected request"),
+        }
+    }
+
+    mod host_pattern {
+        use super::*;
+
+        #[test]
+        fn matches_hosts() {
+            assert!(HostPattern::new("example.com").matches("EXAMPLE.com"));
+            assert!(!HostPattern::new("example.com").matches("www.example.com"));
+            assert!(HostPattern::new("*.example.com").matches("www.example.com"));
+            assert!(!HostPattern::new("*.example.com").matches("badexample.com"));
+            assert!(HostPattern::new("*").matches("example.org"));
+        }
+    }
+
+    mod credential {
+        use super::*;
+
+        #[test]
+        fn encodes_basic() {
+            let credential = Credential::Basic {
+                username: "Aladdin".to_owned(),
+                password: "open sesame".to_owned(),
+            };
+
+            assert_eq!(
+                credential.header().unwrap().1,
+                "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+            );
+        }
+
+        #[test]
+        fn marks_headers_sensitive() {
+            let credential = Credential::Header(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_static("secret"),
+            );
+
+            assert!(credential.header().unwrap().1.is_sensitive());
+        }
+    }
+
+    mod auth_handler {
+        use super::*;
+
+        #[tokio::test]
+        async fn applies_first_matching_rule() {
+            let mut handler = AuthHandler::new()
+                .with_rule(AuthRule::new(
+                    "api.example.com",
+                    Credential::Bearer("a".to_owned()),
+                ))
+                .with_rule(AuthRule::new(
+                    "*.example.com",
+                    Credential::Bearer("b".to_owned()),
+                ));
+
+            assert_eq!(
+                authorization(&mut handler, "https://api.example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer a"
+            );
+            assert_eq!(
+                authorization(&mut handler, "https://www.example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer b"
+            );
+            assert!(authorization(&mut handler, "https://example.org/")
+                .await
+                .is_none());
+        }
+
+        #[tokio::test]
+        async fn skips_cleartext_requests() {
+            let mut handler = AuthHandler::new()
+                .with_rule(AuthRule::new(
+                    "api.example.com",
+                    Credential::Bearer("a".to_owned()),
+                ))
+                .with_rule(
+                    AuthRule::new("*.example.com", Credential::Bearer("b".to_owned()))
+                        .with_cleartext(true),
+                );
+
+            assert!(authorization(&mut handler, "http://api.example.com/")
+                .await
+                .is_none());
+            assert_eq!(
+                authorization(&mut handler, "http://www.example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer b"
+            );
+        }
+
+        #[tokio::test]
+        async fn refreshes_after_unauthorized() {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let counter = Arc::clone(&calls);
+
+            let mut handler = AuthHandler::new().with_rule(AuthRule::refreshing("*", move || {
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                async move { Some((Credential::Bearer(n.to_string()), None)) }
+            }));
+
+            assert_eq!(
+                authorization(&mut handler, "https://example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer 0"
+            );
+            assert_eq!(
+                authorization(&mut handler, "https://example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer 0"
+            );
+
+            handler
+                .handle_response(
+                    &HttpContext::for_test(),
+                    Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .body(Body::from(Empty::new()))
+                        .unwrap(),
+                )
+                .await;
+
+            assert_eq!(
+                authorization(&mut handler, "https://example.com/")
+                    .await
+                    .unwrap(),
+                "Bearer 1"
+            );
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+    }
+}
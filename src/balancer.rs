@@ -0,0 +1,536 @@
+//! Load balancing of requests across multiple upstream servers.
+//!
+//! [`LoadBalancer`] sends the requests that match a [`Route`] to one of the route's upstream
+//! servers, which allows the proxy to be used as a programmable reverse proxy. Upstream servers
+//! that fail are taken out of rotation for a while (passive health checking).
+
+use crate::{
+    auth::{host, HostPattern},
+    Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use hyper::{
+    http::uri::{Authority, Scheme},
+    Request, Response, StatusCode, Uri,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// How an upstream server is selected for a request.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Strategy {
+    /// Select each upstream server in turn.
+    #[default]
+    RoundRobin,
+    /// Select the upstream server with the fewest requests in flight.
+    LeastConnections,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    scheme: Scheme,
+    authority: Authority,
+    active: AtomicUsize,
+    failures: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .map_or(true, |until| until <= now)
+    }
+}
+
+/// Decrements the number of requests in flight for an upstream server when dropped.
+struct Active {
+    route: Arc<Route>,
+    index: usize,
+}
+
+impl Active {
+    fn upstream(&self) -> &Upstream {
+        &self.route.upstreams[self.index]
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.upstream().active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A set of upstream servers that matching requests are balanced across.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     balancer::{Route, Strategy},
+///     hyper::Uri,
+/// };
+/// use std::time::Duration;
+///
+/// let route = Route::new(
+///     "api.example.com",
+///     [
+///         Uri::from_static("http://10.0.0.1:8080"),
+///         Uri::from_static("http://10.0.0.2:8080"),
+///     ],
+/// )
+/// .with_path_prefix("/v1")
+/// .with_strategy(Strategy::LeastConnections)
+/// .with_health_check(3, Duration::from_secs(10));
+/// ```
+#[derive(Debug)]
+pub struct Route {
+    pattern: HostPattern,
+    path_prefix: String,
+    strategy: Strategy,
+    max_failures: usize,
+    ejection: Duration,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+impl Route {
+    /// Creates a new route for requests to hosts that match `pattern`.
+    ///
+    /// Only the scheme and authority of the upstream URIs are used, and upstream URIs without a
+    /// scheme use HTTP. Upstream URIs without an authority are ignored.
+    pub fn new(pattern: impl Into<HostPattern>, upstreams: impl IntoIterator<Item = Uri>) -> Self {
+        let upstreams = upstreams
+            .into_iter()
+            .filter_map(|uri| {
+                let parts = uri.into_parts();
+
+                Some(Upstream {
+                    scheme: parts.scheme.unwrap_or(Scheme::HTTP),
+                    authority: parts.authority?,
+                    active: AtomicUsize::new(0),
+                    failures: AtomicUsize::new(0),
+                    ejected_until: Mutex::new(None),
+                })
+            })
+            .collect();
+
+        Self {
+            pattern: pattern.into(),
+            path_prefix: "/".to_owned(),
+            strategy: Strategy::default(),
+            max_failures: 5,
+            ejection: Duration::from_secs(30),
+            upstreams,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Only match requests whose path starts with `prefix`.
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = prefix.into();
+        self
+    }
+
+    /// Set how an upstream server is selected. Defaults to [`Strategy::RoundRobin`].
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Take an upstream server out of rotation for `ejection` after `max_failures` consecutive
+    /// failures. Defaults to 5 failures and 30 seconds.
+    ///
+    /// A failure is an error while forwarding a request, or a `502 Bad Gateway`,
+    /// `503 Service Unavailable`, or `504 Gateway Timeout` response. If every upstream server is
+    /// out of rotation, they are all used.
+    pub fn with_health_check(mut self, max_failures: usize, ejection: Duration) -> Self {
+        self.max_failures = max_failures.max(1);
+        self.ejection = ejection;
+        self
+    }
+
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        host(req).is_some_and(|host| self.pattern.matches(host))
+            && req.uri().path().starts_with(&self.path_prefix)
+    }
+
+    fn select(&self) -> Option<usize> {
+        let now = Instant::now();
+        let healthy: Vec<usize> = (0..self.upstreams.len())
+            .filter(|&i| self.upstreams[i].is_healthy(now))
+            .collect();
+
+        let candidates = if healthy.is_empty() {
+            (0..self.upstreams.len()).collect()
+        } else {
+            healthy
+        };
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        match self.strategy {
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                Some(candidates[next % candidates.len()])
+            }
+            Strategy::LeastConnections => candidates
+                .into_iter()
+                .min_by_key(|&i| self.upstreams[i].active.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn record(&self, index: usize, success: bool) {
+        let upstream = &self.upstreams[index];
+
+        if success {
+            upstream.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        if upstream.failures.fetch_add(1, Ordering::Relaxed) + 1 >= self.max_failures {
+            upstream.failures.store(0, Ordering::Relaxed);
+            *upstream.ejected_until.lock().unwrap() = Some(Instant::now() + self.ejection);
+            warn!(
+                "Removing {} from rotation for {:?}",
+                upstream.authority, self.ejection
+            );
+        }
+    }
+}
+
+/// An HTTP handler that balances requests across upstream servers.
+///
+/// Requests are passed to the wrapped handler before they are routed, and are matched against the
+/// routes in the order that they were added. Requests that do not match a route are forwarded
+/// unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     balancer::{LoadBalancer, Route},
+///     hyper::Uri,
+/// };
+///
+/// let handler = LoadBalancer::new().with_route(Route::new(
+///     "*",
+///     [
+///         Uri::from_static("http://10.0.0.1:8080"),
+///         Uri::from_static("http://10.0.0.2:8080"),
+///     ],
+/// ));
+/// ```
+pub struct LoadBalancer<H = NoopHandler> {
+    routes: Arc<Vec<Arc<Route>>>,
+    inner: H,
+    active: Option<Arc<Active>>,
+}
+
+impl LoadBalancer {
+    /// Creates a new load balancer without any routes.
+    pub fn new() -> Self {
+        Self {
+            routes: Arc::new(Vec::new()),
+            inner: NoopHandler::default(),
+            active: None,
+        }
+    }
+}
+
+impl<H: Clone> Clone for LoadBalancer<H> {
+    fn clone(&self) -> Self {
+        Self {
+            routes: Arc::clone(&self.routes),
+            inner: self.inner.clone(),
+            active: None,
+        }
+    }
+}
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: std::fmt::Debug> std::fmt::Debug for LoadBalancer<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoadBalancer")
+            .field("routes", &self.routes)
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> LoadBalancer<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> LoadBalancer<H2> {
+        LoadBalancer {
+            routes: self.routes,
+            inner,
+            active: None,
+        }
+    }
+
+    /// Add a route.
+    pub fn with_route(mut self, route: Route) -> Self {
+        Arc::make_mut(&mut self.routes).push(Arc::new(route));
+        self
+    }
+
+    fn route(&mut self, mut req: Request<Body>) -> Request<Body> {
+        self.active = None;
+
+        let Some(route) = self.routes.iter().find(|route| route.matches(&req)) else {
+            return req;
+        };
+
+        let Some(index) = route.select() else {
+            return req;
+        };
+
+        let upstream = &route.upstreams[index];
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = Some(upstream.scheme.clone());
+        parts.authority = Some(upstream.authority.clone());
+
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some("/".try_into().expect("Failed to build path"));
+        }
+
+        match Uri::from_parts(parts) {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(e) => {
+                warn!("Failed to route request to {}: {}", upstream.authority, e);
+                return req;
+            }
+        }
+
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        self.active = Some(Arc::new(Active {
+            route: Arc::clone(route),
+            index,
+        }));
+
+        req
+    }
+
+    fn record(&mut self, success: bool) {
+        if let Some(active) = self.active.take() {
+            active.route.record(active.index, success);
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for LoadBalancer<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.route(req).into(),
+            res => {
+                self.active = None;
+                res
+            }
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.record(!matches!(
+            res.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ));
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.record(false);
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.active = None;
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+
+    fn upstreams() -> [Uri; 2] {
+        [
+            Uri::from_static("http://10.0.0.1:8080"),
+            Uri::from_static("http://10.0.0.2:8080"),
+        ]
+    }
+
+    async fn routed_authority(handler: &mut LoadBalancer, uri: &'static str) -> String {
+        let req = Request::builder()
+            .uri(uri)
+            .body(Body::from(Empty::new()))
+            .unwrap();
+
+        match handler.handle_request(&HttpContext::for_test(), req).await {
+            RequestOrResponse::Request(req) => req.uri().authority().unwrap().to_string(),
+            RequestOrResponse::Response(_) => panic!("expected request"),
+        }
+    }
+
+    fn response(status: StatusCode) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .body(Body::from(Empty::new()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let mut handler = LoadBalancer::new().with_route(Route::new("example.com", upstreams()));
+
+        assert_eq!(
+            routed_authority(&mut handler, "http://example.com/a").await,
+            "10.0.0.1:8080"
+        );
+        assert_eq!(
+            routed_authority(&mut handler, "http://example.com/b").await,
+            "10.0.0.2:8080"
+        );
+        assert_eq!(
+            routed_authority(&mut handler, "http://example.org/").await,
+            "example.org"
+        );
+    }
+
+    #[tokio::test]
+    async fn least_connections() {
+        let handler = LoadBalancer::new()
+            .with_route(Route::new("*", upstreams()).with_strategy(Strategy::LeastConnections));
+
+        let mut first = handler.clone();
+        assert_eq!(
+            routed_authority(&mut first, "http://example.com/").await,
+            "10.0.0.1:8080"
+        );
+
+        let mut second = handler.clone();
+        assert_eq!(
+            routed_authority(&mut second, "http://example.com/").await,
+            "10.0.0.2:8080"
+        );
+
+        first
+            .handle_response(&HttpContext::for_test(), response(StatusCode::OK))
+            .await;
+
+        let mut third = handler.clone();
+        assert_eq!(
+            routed_authority(&mut third, "http://example.com/").await,
+            "10.0.0.1:8080"
+        );
+    }
+
+    #[tokio::test]
+    async fn ejects_failing_upstreams() {
+        let handler = LoadBalancer::new()
+            .with_route(Route::new("*", upstreams()).with_health_check(1, Duration::from_secs(60)));
+
+        let mut first = handler.clone();
+        routed_authority(&mut first, "http://example.com/").await;
+        first
+            .handle_response(&HttpContext::for_test(), response(StatusCode::BAD_GATEWAY))
+            .await;
+
+        for _ in 0..3 {
+            let mut handler = handler.clone();
+            assert_eq!(
+                routed_authority(&mut handler, "http://example.com/").await,
+                "10.0.0.2:8080"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_path_prefix() {
+        let mut handler =
+            LoadBalancer::new().with_route(Route::new("*", upstreams()).with_path_prefix("/api"));
+
+        assert_eq!(
+            routed_authority(&mut handler, "http://example.com/").await,
+            "example.com"
+        );
+        assert_eq!(
+            routed_authority(&mut handler, "http://example.com/api/users").await,
+            "10.0.0.1:8080"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! Blocking of hosts and URLs before they are contacted.
+//!
+//! A [`Blocklist`] is set with [`ProxyBuilder::with_blocklist`](crate::ProxyBuilder), and is
+//! checked for every `CONNECT` request and every request, including the requests of intercepted
+//! tunnels, before the HTTP handler is called and before any upstream server is contacted. Blocked
+//! requests are answered with a status, `403 Forbidden` by default, or their connections are closed
+//! without a response.
+//!
+//! Hosts are blocked by [`HostPattern`]s, and URLs by regular expressions that are matched against
+//! the full URL of a request. The URLs of requests in tunnels that are not intercepted are not
+//! known, so only their hosts are checked. Lists in the format of adblock filter lists, such as
+//! EasyList, can be loaded with [`Blocklist::with_adblock_list`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     blocklist::{BlockAction, Blocklist},
+//!     hyper::StatusCode,
+//! };
+//!
+//! let blocklist = Blocklist::new()
+//!     .with_host("tracker.example")
+//!     .with_host("*.ads.example")
+//!     .with_url_regex(r"^https?://[^/]+/telemetry/")
+//!     .unwrap()
+//!     .with_adblock_list("||doubleclick.example^\n@@||doubleclick.example/allowed/")
+//!     .unwrap()
+//!     .with_action(BlockAction::Status(StatusCode::NOT_FOUND));
+//! ```
+
+use crate::auth::{host, HostPattern};
+use hyper::{Method, Request, StatusCode};
+use regex::RegexSet;
+use std::collections::HashSet;
+
+/// What is done with a blocked request.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BlockAction {
+    /// Respond with a status.
+    Status(StatusCode),
+    /// Close the connection of the request without a response.
+    ///
+    /// For a request in an intercepted tunnel, this closes the tunnel.
+    Close,
+}
+
+impl Default for BlockAction {
+    fn default() -> Self {
+        Self::Status(StatusCode::FORBIDDEN)
+    }
+}
+
+/// A set of hosts.
+#[derive(Clone, Debug, Default)]
+struct Hosts {
+    /// Whether every host is in the set.
+    all: bool,
+    exact: HashSet<String>,
+    /// Domains whose subdomains are in the set, with a leading dot.
+    subdomains: HashSet<String>,
+}
+
+impl Hosts {
+    fn insert(&mut self, pattern: &HostPattern) {
+        match pattern.as_str().strip_prefix('*') {
+            Some("") => self.all = true,
+            Some(suffix) if suffix.starts_with('.') => {
+                self.subdomains.insert(suffix.to_owned());
+            }
+            _ => {
+                self.exact.insert(pattern.as_str().to_owned());
+            }
+        }
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        self.all
+            || self.exact.contains(&host)
+            || host
+                .match_indices('.')
+                .any(|(i, _)| self.subdomains.contains(&host[i..]))
+    }
+}
+
+/// Hosts and URLs that are blocked, or that are exempt from being blocked.
+#[derive(Clone, Debug, Default)]
+struct Rules {
+    hosts: Hosts,
+    url_patterns: Vec<String>,
+    urls: Option<RegexSet>,
+}
+
+impl Rules {
+    fn add_url_patterns(&mut self, patterns: Vec<String>) -> Result<(), regex::Error> {
+        if patterns.is_empty() {
+            return Ok(());
+        }
+
+        let mut url_patterns = self.url_patterns.clone();
+        url_patterns.extend(patterns);
+        self.urls = Some(RegexSet::new(&url_patterns)?);
+        self.url_patterns = url_patterns;
+        Ok(())
+    }
+
+    fn matches(&self, host: Option<&str>, url: Option<&str>) -> bool {
+        host.is_some_and(|host| self.hosts.contains(host))
+            || url
+                .zip(self.urls.as_ref())
+                .is_some_and(|(url, urls)| urls.is_match(url))
+    }
+}
+
+/// A regular expression for the pattern of an adblock filter rule.
+fn adblock_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?i)");
+    let mut rest = pattern;
+
+    if let Some(domain) = rest.strip_prefix("||") {
+        regex.push_str(r"^[a-z][a-z0-9+.-]*://(?:[^/?#]*\.)?");
+        rest = domain;
+    } else if let Some(start) = rest.strip_prefix('|') {
+        regex.push('^');
+        rest = start;
+    }
+
+    let (rest, anchored_end) = match rest.strip_suffix('|') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+
+    for c in rest.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '^' => regex.push_str(r"(?:[^A-Za-z0-9_.%-]|$)"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    if anchored_end {
+        regex.push('$');
+    }
+
+    regex
+}
+
+/// A domain that an adblock rule blocks with all of its subdomains, for rules such as
+/// `||example.com^`.
+fn adblock_domain(pattern: &str) -> Option<&str> {
+    let domain = pattern.strip_prefix("||")?;
+    let domain = domain.strip_suffix('^').unwrap_or(domain);
+
+    domain
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        .then_some(domain)
+        .filter(|domain| !domain.is_empty())
+}
+
+/// Hosts and URLs that are blocked.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    blocked: Rules,
+    allowed: Rules,
+    block_by_default: bool,
+    action: BlockAction,
+}
+
+impl Blocklist {
+    /// Creates a new blocklist that does not block anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block the hosts that match a pattern.
+    pub fn with_host(mut self, pattern: impl Into<HostPattern>) -> Self {
+        self.blocked.hosts.insert(&pattern.into());
+        self
+    }
+
+    /// Block the URLs that match a regular expression.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the regular expression is invalid.
+    pub fn with_url_regex(mut self, regex: &str) -> Result<Self, regex::Error> {
+        self.blocked.add_url_patterns(vec![regex.to_owned()])?;
+        Ok(self)
+    }
+
+    /// Exempt the hosts that match a pattern from being blocked.
+    pub fn with_allowed_host(mut self, pattern: impl Into<HostPattern>) -> Self {
+        self.allowed.hosts.insert(&pattern.into());
+        self
+    }
+
+    /// Exempt the URLs that match a regular expression from being blocked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the regular expression is invalid.
+    pub fn with_allowed_url_regex(mut self, regex: &str) -> Result<Self, regex::Error> {
+        self.allowed.add_url_patterns(vec![regex.to_owned()])?;
+        Ok(self)
+    }
+
+    /// Add the rules of an adblock filter list.
+    ///
+    /// Blocking rules such as `||example.com^` and `/ads/*.js`, exception rules that start with
+    /// `@@`, and regular expressions between slashes are supported. Comments, cosmetic rules, and
+    /// rules with options after `$` are ignored, since options depend on the page that a request
+    /// is made from, which the proxy does not know.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a regular expression in the list is invalid, or if the list is too
+    /// large to be compiled.
+    pub fn with_adblock_list(mut self, list: &str) -> Result<Self, regex::Error> {
+        let mut blocked = Vec::new();
+        let mut allowed = Vec::new();
+
+        for line in list.lines().map(str::trim) {
+            if line.is_empty()
+                || line.starts_with('!')
+                || line.starts_with('[')
+                || line.contains("##")
+                || line.contains("#@#")
+                || line.contains("#?#")
+            {
+                continue;
+            }
+
+            let (rule, rules, patterns) = match line.strip_prefix("@@") {
+                Some(rule) => (rule, &mut self.allowed, &mut allowed),
+                None => (line, &mut self.blocked, &mut blocked),
+            };
+
+            if rule.len() > 2 && rule.starts_with('/') && rule.ends_with('/') {
+                patterns.push(format!("(?i){}", &rule[1..rule.len() - 1]));
+            } else if rule.contains('$') {
+                continue;
+            } else if let Some(domain) = adblock_domain(rule) {
+                rules.hosts.insert(&HostPattern::new(domain));
+                rules
+                    .hosts
+                    .insert(&HostPattern::new(format!("*.{}", domain)));
+            } else {
+                patterns.push(adblock_regex(rule));
+            }
+        }
+
+        self.blocked.add_url_patterns(blocked)?;
+        self.allowed.add_url_patterns(allowed)?;
+        Ok(self)
+    }
+
+    /// Set whether hosts and URLs that are not allowed are blocked, which turns the blocklist into
+    /// an allowlist of the hosts and URLs that are exempt from being blocked.
+    pub fn with_block_by_default(mut self, block: bool) -> Self {
+        self.block_by_default = block;
+        self
+    }
+
+    /// Set what is done with blocked requests.
+    pub fn with_action(mut self, action: BlockAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Whether a request is blocked.
+    pub fn is_blocked<T>(&self, req: &Request<T>) -> bool {
+        let host = host(req);
+        let url = (req.method() != Method::CONNECT && req.uri().scheme().is_some())
+            .then(|| req.uri().to_string());
+        let url = url.as_deref();
+
+        !self.allowed.matches(host, url)
+            && (self.block_by_default || self.blocked.matches(host, url))
+    }
+
+    /// What is done with a request, if it is blocked.
+    pub(crate) fn check<T>(&self, req: &Request<T>) -> Option<BlockAction> {
+        self.is_blocked(req).then_some(self.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked(blocklist: &Blocklist, method: Method, uri: &str) -> bool {
+        blocklist.is_blocked(&Request::builder().method(method).uri(uri).body(()).unwrap())
+    }
+
+    #[test]
+    fn blocks_hosts_and_urls() {
+        let blocklist = Blocklist::new()
+            .with_host("tracker.example")
+            .with_host("*.ads.example")
+            .with_url_regex(r"^https://[^/]+/telemetry/")
+            .unwrap()
+            .with_allowed_host("ok.ads.example");
+
+        assert!(blocked(&blocklist, Method::CONNECT, "TRACKER.example:443"));
+        assert!(blocked(&blocklist, Method::CONNECT, "a.b.ads.example:443"));
+        assert!(!blocked(&blocklist, Method::CONNECT, "ads.example:443"));
+        assert!(!blocked(&blocklist, Method::CONNECT, "ok.ads.example:443"));
+        assert!(blocked(
+            &blocklist,
+            Method::GET,
+            "https://example.com/telemetry/1"
+        ));
+        assert!(!blocked(
+            &blocklist,
+            Method::GET,
+            "http://example.com/telemetry/1"
+        ));
+        assert!(!blocked(&blocklist, Method::CONNECT, "example.com:443"));
+    }
+
+    #[test]
+    fn parses_adblock_lists() {
+        let blocklist = Blocklist::new()
+            .with_adblock_list(
+                "[Adblock Plus 2.0]\n\
+                 ! Title: Test\n\
+                 ||doubleclick.example^\n\
+                 @@||doubleclick.example/allowed/\n\
+                 /banner/*/img^\n\
+                 |https://exact.example/ad.js|\n\
+                 /\\/pixel\\.gif$/\n\
+                 ||third-party.example^$third-party\n\
+                 example.com##.ad\n",
+            )
+            .unwrap();
+
+        assert!(blocked(
+            &blocklist,
+            Method::CONNECT,
+            "doubleclick.example:443"
+        ));
+        assert!(blocked(
+            &blocklist,
+            Method::CONNECT,
+            "ad.doubleclick.example:443"
+        ));
+        assert!(!blocked(
+            &blocklist,
+            Method::GET,
+            "https://doubleclick.example/allowed/1"
+        ));
+        assert!(blocked(
+            &blocklist,
+            Method::GET,
+            "http://a.example/banner/x/img?1"
+        ));
+        assert!(!blocked(
+            &blocklist,
+            Method::GET,
+            "http://a.example/banner/x/imgs"
+        ));
+        assert!(blocked(
+            &blocklist,
+            Method::GET,
+            "https://exact.example/ad.js"
+        ));
+        assert!(!blocked(
+            &blocklist,
+            Method::GET,
+            "https://exact.example/ad.js?1"
+        ));
+        assert!(blocked(
+            &blocklist,
+            Method::GET,
+            "https://a.example/PIXEL.gif"
+        ));
+        assert!(!blocked(
+            &blocklist,
+            Method::CONNECT,
+            "third-party.example:443"
+        ));
+        assert!(!blocked(&blocklist, Method::GET, "https://example.com/"));
+    }
+
+    #[test]
+    fn blocks_by_default() {
+        let blocklist = Blocklist::new()
+            .with_block_by_default(true)
+            .with_allowed_host("*.example.com");
+
+        assert!(!blocked(&blocklist, Method::CONNECT, "www.example.com:443"));
+        assert!(blocked(&blocklist, Method::CONNECT, "example.org:443"));
+    }
+}
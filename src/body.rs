@@ -1,8 +1,26 @@
-use crate::Error;
-use futures::{Stream, StreamExt};
-use http_body_util::{combinators::BoxBody, Collected, Empty, Full, StreamBody};
-use hyper::body::{Body as HttpBody, Bytes, Frame, Incoming, SizeHint};
-use std::pin::Pin;
+use crate::{BufferPool, Error};
+use bytes::BytesMut;
+use futures::{channel::mpsc, future, stream, Stream, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Collected, Empty, Full, StreamBody};
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, Incoming, SizeHint},
+    HeaderMap,
+};
+use std::{
+    collections::VecDeque,
+    io, mem,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The size of the buffer that is used to read bodies from readers.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
 
 #[derive(Debug)]
 enum Internal {
@@ -20,6 +38,20 @@ pub struct Body {
 }
 
 impl Body {
+    /// Create a body from a stream of chunks.
+    ///
+    /// The stream is sent as it is polled, without being buffered in memory.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::{futures::stream, hyper::body::Bytes, Body};
+    ///
+    /// let body = Body::wrap_stream(stream::iter([
+    ///     Ok::<_, std::io::Error>(Bytes::from_static(b"hello ")),
+    ///     Ok(Bytes::from_static(b"world")),
+    /// ]));
+    /// ```
     pub fn wrap_stream<S, O, E>(stream: S) -> Self
     where
         S: Stream<Item = Result<O, E>> + Send + Sync + 'static,
@@ -32,6 +64,660 @@ impl Body {
             ))),
         }
     }
+
+    /// Create a body from a reader.
+    ///
+    /// The body is read in chunks as it is sent, without being buffered in memory.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::Body;
+    ///
+    /// let body = Body::from_reader(&b"hello world"[..]);
+    /// ```
+    pub fn from_reader<R>(reader: R) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        Self::reader(reader, None)
+    }
+
+    /// Create a body from the contents of a file.
+    ///
+    /// The file is read in chunks as the body is sent, and the size of the body is the size of the
+    /// file when it is opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened.
+    pub async fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+
+        Ok(Self::reader(file, Some(len)))
+    }
+
+    /// Create a body from a reader, like [`Body::from_reader`], reading it into buffers from a
+    /// pool.
+    ///
+    /// The buffer is returned to the pool when the body is dropped. A buffer is reused for the
+    /// following chunks once the chunks that were read into it are dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::{Body, BufferPool};
+    ///
+    /// let pool = BufferPool::default();
+    /// let body = Body::from_reader_with_pool(&b"hello world"[..], &pool);
+    /// ```
+    pub fn from_reader_with_pool<R>(reader: R, pool: &BufferPool) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        Self::reader_with_pool(reader, None, Some(pool.clone()))
+    }
+
+    fn reader<R>(reader: R, len: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        Self::reader_with_pool(reader, len, None)
+    }
+
+    fn reader_with_pool<R>(reader: R, len: Option<u64>, pool: Option<BufferPool>) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let buf = match &pool {
+            Some(pool) => pool.get(),
+            None => BytesMut::new(),
+        };
+
+        Self {
+            inner: Internal::BoxBody(BoxBody::new(Reader {
+                reader: Box::pin(reader),
+                buf,
+                pool,
+                remaining: len,
+                done: len == Some(0),
+            })),
+        }
+    }
+
+    /// Map the trailers of the body.
+    ///
+    /// The provided function will be called once with the trailers of the body when they are
+    /// received, or with `None` if the body ends without trailers. The trailers that are returned by
+    /// the function will be sent in place of the original trailers.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::{hyper::header::HeaderValue, Body};
+    ///
+    /// let body = Body::from("hello").map_trailers(|trailers| {
+    ///     let mut trailers = trailers.unwrap_or_default();
+    ///     trailers.insert("grpc-status", HeaderValue::from_static("0"));
+    ///     Some(trailers)
+    /// });
+    /// ```
+    pub fn map_trailers<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Option<HeaderMap>) -> Option<HeaderMap> + Send + Sync + 'static,
+    {
+        Self {
+            inner: Internal::BoxBody(BoxBody::new(MapTrailers {
+                body: self,
+                f: Some(f),
+            })),
+        }
+    }
+}
+
+/// The result of [`Body::collect_up_to`].
+#[derive(Debug)]
+pub enum Bounded {
+    /// The whole body was collected.
+    Complete {
+        /// The data of the body.
+        data: Bytes,
+        /// The trailers of the body, if it had any.
+        trailers: Option<HeaderMap>,
+    },
+    /// The body exceeded the limit.
+    ///
+    /// The body yields the same data as the original body, including the data that was read
+    /// before the limit was exceeded.
+    TooLarge(Body),
+}
+
+impl Body {
+    /// Collect the body into memory, if it is no larger than `max` bytes.
+    ///
+    /// If the body is larger than `max` bytes, only enough of it is read to find that out, and
+    /// [`Bounded::TooLarge`] is returned with a body that can be forwarded unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body fails before the limit is exceeded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::{Body, Bounded};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// match Body::from("hello").collect_up_to(1024).await.unwrap() {
+    ///     Bounded::Complete { data, .. } => assert_eq!(data, "hello"),
+    ///     Bounded::TooLarge(body) => unreachable!(),
+    /// }
+    /// # }
+    /// ```
+    pub async fn collect_up_to(self, max: usize) -> Result<Bounded, Error> {
+        self.collect_bounded(max, |_| true).await
+    }
+
+    /// Collect the body like [`Body::collect_up_to`], calling `admit` with the length of each
+    /// chunk before it is kept, and treating the body as too large if it returns `false`.
+    pub(crate) async fn collect_bounded(
+        mut self,
+        max: usize,
+        mut admit: impl FnMut(usize) -> bool,
+    ) -> Result<Bounded, Error> {
+        if self.size_hint().lower() > max as u64 {
+            return Ok(Bounded::TooLarge(self));
+        }
+
+        let mut prefix = VecDeque::new();
+        let mut len = 0;
+
+        while let Some(frame) = self.frame().await {
+            let frame = match frame?.into_data() {
+                Ok(data) => data,
+                Err(frame) => {
+                    return Ok(Bounded::Complete {
+                        data: concat(prefix, len),
+                        trailers: frame.into_trailers().ok(),
+                    })
+                }
+            };
+
+            len += frame.len();
+            let admitted = len <= max && admit(frame.len());
+            prefix.push_back(frame);
+
+            if !admitted {
+                return Ok(Bounded::TooLarge(Self {
+                    inner: Internal::BoxBody(BoxBody::new(Prefixed { prefix, body: self })),
+                }));
+            }
+        }
+
+        Ok(Bounded::Complete {
+            data: concat(prefix, len),
+            trailers: None,
+        })
+    }
+
+    /// Read the start of the body, without consuming it.
+    ///
+    /// Data is read until at least `len` bytes have been read or the body ends, and is returned
+    /// with a body that yields the same data as the original body. The returned data may be
+    /// shorter or longer than `len` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body fails before `len` bytes have been read.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::Body;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let (start, body) = Body::from("hello").peek(2).await.unwrap();
+    /// assert_eq!(start, "hello");
+    /// # }
+    /// ```
+    pub async fn peek(mut self, len: usize) -> Result<(Bytes, Body), Error> {
+        let mut prefix = VecDeque::new();
+        let mut read = 0;
+        let mut trailers = None;
+
+        while read < len {
+            let Some(frame) = self.frame().await else {
+                break;
+            };
+
+            match frame?.into_data() {
+                Ok(data) => {
+                    read += data.len();
+                    prefix.push_back(data);
+                }
+                Err(frame) => {
+                    trailers = frame.into_trailers().ok();
+                    self = Self::from(Empty::new());
+                    break;
+                }
+            }
+        }
+
+        let data = concat(prefix.clone(), read);
+        let mut body = Self {
+            inner: Internal::BoxBody(BoxBody::new(Prefixed { prefix, body: self })),
+        };
+
+        if let Some(trailers) = trailers {
+            body = body.map_trailers(|_| Some(trailers));
+        }
+
+        Ok((data, body))
+    }
+}
+
+fn concat(chunks: VecDeque<Bytes>, len: usize) -> Bytes {
+    if chunks.len() == 1 {
+        return chunks.into_iter().next().unwrap_or_default();
+    }
+
+    let mut data = Vec::with_capacity(len);
+    for chunk in chunks {
+        data.extend_from_slice(&chunk);
+    }
+
+    data.into()
+}
+
+/// A body that yields buffered data before the rest of another body.
+struct Prefixed {
+    prefix: VecDeque<Bytes>,
+    body: Body,
+}
+
+impl HttpBody for Prefixed {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.prefix.pop_front() {
+            Some(data) => Poll::Ready(Some(Ok(Frame::data(data)))),
+            None => Pin::new(&mut self.body).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_empty() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered = self
+            .prefix
+            .iter()
+            .map(|data| data.len() as u64)
+            .sum::<u64>();
+        let hint = self.body.size_hint();
+
+        let mut size = SizeHint::new();
+        size.set_lower(hint.lower() + buffered);
+        if let Some(upper) = hint.upper() {
+            size.set_upper(upper + buffered);
+        }
+        size
+    }
+}
+
+impl Body {
+    /// Limit the body to `max` bytes, calling `on_exceeded` once if the body is larger.
+    ///
+    /// If `truncate` is true, the body will end after `max` bytes, otherwise the body will yield
+    /// [`Error::BodyTooLarge`].
+    pub(crate) fn limited(
+        self,
+        max: usize,
+        truncate: bool,
+        on_exceeded: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Internal::BoxBody(BoxBody::new(Limited {
+                body: self,
+                remaining: max,
+                truncate,
+                on_exceeded: Some(Box::new(on_exceeded)),
+                exceeded: false,
+            })),
+        }
+    }
+}
+
+impl Body {
+    /// Copy the body, returning a second body that yields the same frames as this one.
+    ///
+    /// Frames are sent to the copy as this body is read, so the copy can be streamed into a
+    /// recorder or scanner while this body is streamed to its destination, without collecting
+    /// either of them. Up to `capacity` frames are buffered for the copy. If the copy falls further
+    /// behind, or this body fails or is dropped before it ends, the copy yields [`Error::Unknown`]
+    /// instead of delaying this body.
+    pub fn tee(self, capacity: usize) -> (Self, Self) {
+        let (body, mut copies) = self.tee_many(1, capacity);
+        (body, copies.remove(0))
+    }
+
+    /// Copy the body for several consumers, like [`Body::tee`].
+    ///
+    /// Each copy is buffered separately, so a copy that falls behind is abandoned without affecting
+    /// the others.
+    pub fn tee_many(self, copies: usize, capacity: usize) -> (Self, Vec<Self>) {
+        if self.is_end_stream() {
+            return (
+                self,
+                (0..copies).map(|_| Body::from(Empty::new())).collect(),
+            );
+        }
+
+        let mut sinks = Vec::with_capacity(copies);
+        let mut bodies = Vec::with_capacity(copies);
+
+        for _ in 0..copies {
+            let (tx, rx) = mpsc::channel(capacity);
+            let complete = Arc::new(AtomicBool::new(false));
+
+            let copy = {
+                let complete = Arc::clone(&complete);
+                let end = stream::once(async move {
+                    (!complete.load(Ordering::Acquire)).then_some(Err(Error::Unknown))
+                })
+                .filter_map(future::ready);
+
+                Body::from(StreamBody::new(rx.chain(end)))
+            };
+
+            sinks.push(TeeSink {
+                tx: Some(tx),
+                complete,
+            });
+            bodies.push(copy);
+        }
+
+        let body = Self {
+            inner: Internal::BoxBody(BoxBody::new(Tee { body: self, sinks })),
+        };
+
+        (body, bodies)
+    }
+}
+
+struct Tee {
+    body: Body,
+    sinks: Vec<TeeSink>,
+}
+
+struct TeeSink {
+    tx: Option<mpsc::Sender<Result<Frame<Bytes>, Error>>>,
+    complete: Arc<AtomicBool>,
+}
+
+impl TeeSink {
+    fn send(&mut self, frame: &Frame<Bytes>) {
+        let copy = if let Some(data) = frame.data_ref() {
+            Some(Frame::data(data.clone()))
+        } else {
+            frame.trailers_ref().cloned().map(Frame::trailers)
+        };
+
+        if let (Some(tx), Some(copy)) = (&mut self.tx, copy) {
+            if tx.try_send(Ok(copy)).is_err() {
+                self.tx = None;
+            }
+        }
+    }
+
+    /// Marks the copy as complete, unless it was abandoned. This may be called again if the body
+    /// is polled after it ends.
+    fn finish(&mut self) {
+        if self.tx.take().is_some() {
+            self.complete.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl HttpBody for Tee {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        let end = match &frame {
+            Some(Ok(frame)) => {
+                for sink in &mut self.sinks {
+                    sink.send(frame);
+                }
+
+                self.body.is_end_stream()
+            }
+            Some(Err(_)) => {
+                for sink in &mut self.sinks {
+                    sink.tx = None;
+                }
+
+                false
+            }
+            None => true,
+        };
+
+        if end {
+            for sink in &mut self.sinks {
+                sink.finish();
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+struct Reader<R> {
+    reader: Pin<Box<R>>,
+    buf: BytesMut,
+    pool: Option<BufferPool>,
+    remaining: Option<u64>,
+    done: bool,
+}
+
+impl<R> Drop for Reader<R> {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.put(mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl<R: AsyncRead> HttpBody for Reader<R> {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        let this = &mut *self;
+        let size = this
+            .pool
+            .as_ref()
+            .map_or(READ_BUFFER_SIZE, BufferPool::buffer_size);
+
+        // Reserving reclaims the buffer once the chunks that were split off from it are dropped.
+        this.buf.clear();
+        this.buf.resize(size, 0);
+        let mut buf = ReadBuf::new(&mut this.buf);
+
+        match ready!(this.reader.as_mut().poll_read(cx, &mut buf)) {
+            Ok(()) if buf.filled().is_empty() => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            Ok(()) => {
+                let read = buf.filled().len();
+                let data = this.buf.split_to(read).freeze();
+
+                if let Some(remaining) = &mut this.remaining {
+                    *remaining = remaining.saturating_sub(data.len() as u64);
+                    this.done = *remaining == 0;
+                }
+
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
+            Err(e) => {
+                this.done = true;
+                Poll::Ready(Some(Err(e.into())))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.remaining {
+            Some(remaining) => SizeHint::with_exact(remaining),
+            None => SizeHint::default(),
+        }
+    }
+}
+
+struct Limited {
+    body: Body,
+    remaining: usize,
+    truncate: bool,
+    on_exceeded: Option<Box<dyn FnOnce() + Send + Sync>>,
+    exceeded: bool,
+}
+
+impl HttpBody for Limited {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.exceeded {
+            return Poll::Ready(None);
+        }
+
+        let frame = match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            other => return Poll::Ready(other),
+        };
+
+        let frame = match frame.into_data() {
+            Ok(mut data) if data.len() > self.remaining => {
+                self.exceeded = true;
+
+                if let Some(on_exceeded) = self.on_exceeded.take() {
+                    on_exceeded();
+                }
+
+                if !self.truncate {
+                    return Poll::Ready(Some(Err(Error::BodyTooLarge)));
+                }
+
+                data.truncate(self.remaining);
+                self.remaining = 0;
+                Frame::data(data)
+            }
+            Ok(data) => {
+                self.remaining -= data.len();
+                Frame::data(data)
+            }
+            Err(frame) => frame,
+        };
+
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.exceeded || self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let hint = self.body.size_hint();
+
+        if !self.truncate {
+            return hint;
+        }
+
+        let remaining = self.remaining as u64;
+        let mut limited = SizeHint::new();
+        limited.set_lower(hint.lower().min(remaining));
+        limited.set_upper(hint.upper().unwrap_or(remaining).min(remaining));
+        limited
+    }
+}
+
+struct MapTrailers<F> {
+    body: Body,
+    f: Option<F>,
+}
+
+// `f` is never pinned, so `MapTrailers` can be `Unpin` regardless of `F`.
+impl<F> Unpin for MapTrailers<F> {}
+
+impl<F> HttpBody for MapTrailers<F>
+where
+    F: FnOnce(Option<HeaderMap>) -> Option<HeaderMap>,
+{
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let trailers = match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+            Some(Ok(frame)) => match frame.into_trailers() {
+                Ok(trailers) => Some(trailers),
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            },
+            Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            None => None,
+        };
+
+        Poll::Ready(match self.f.take() {
+            Some(f) => f(trailers).map(Frame::trailers).map(Ok),
+            None => trailers.map(Frame::trailers).map(Ok),
+        })
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.f.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
 }
 
 impl HttpBody for Body {
@@ -39,9 +725,9 @@ impl HttpBody for Body {
     type Error = crate::Error;
 
     fn poll_frame(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         match &mut self.inner {
             Internal::BoxBody(body) => Pin::new(body).poll_frame(cx),
             Internal::Collected(body) => Pin::new(body).poll_frame(cx).map_err(|e| match e {}),
@@ -149,3 +835,268 @@ impl From<&'static [u8]> for Body {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::header::HeaderValue;
+
+    fn trailers() -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers
+    }
+
+    mod from_reader_with_pool {
+        use super::*;
+
+        #[tokio::test]
+        async fn returns_buffer_to_pool() {
+            let pool = BufferPool::new(4, 1);
+            let mut body = Body::from_reader_with_pool(&b"hello world"[..], &pool);
+            assert_eq!(pool.idle(), 0);
+
+            let mut frames = Vec::new();
+            while let Some(frame) = body.frame().await {
+                frames.push(frame.unwrap().into_data().unwrap());
+            }
+            assert_eq!(frames, ["hell", "o wo", "rld"]);
+
+            drop(frames);
+            drop(body);
+            assert_eq!(pool.idle(), 1);
+        }
+    }
+
+    mod tee {
+        use super::*;
+
+        fn chunks(n: usize) -> Body {
+            Body::wrap_stream(stream::iter(
+                (0..n).map(|_| Ok::<_, Error>(Bytes::from_static(b"ab"))),
+            ))
+        }
+
+        #[tokio::test]
+        async fn copies_frames() {
+            let (body, copy) = chunks(3).tee(8);
+
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "ababab");
+            assert_eq!(copy.collect().await.unwrap().to_bytes(), "ababab");
+        }
+
+        #[tokio::test]
+        async fn copies_bodies_that_end_with_their_last_frame() {
+            let (body, copy) = Body::from("hello").tee(8);
+
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+            assert_eq!(copy.collect().await.unwrap().to_bytes(), "hello");
+        }
+
+        #[tokio::test]
+        async fn abandons_lagging_copy() {
+            let (body, copy) = chunks(8).tee(1);
+
+            assert_eq!(body.collect().await.unwrap().to_bytes().len(), 16);
+            assert!(matches!(copy.collect().await, Err(Error::Unknown)));
+        }
+
+        #[tokio::test]
+        async fn copies_frames_for_each_consumer() {
+            let (mut body, mut copies) = chunks(4).tee_many(2, 2);
+            let mut fast = copies.remove(0);
+            let slow = copies.remove(0);
+            let mut copied = Vec::new();
+
+            while let Some(frame) = body.frame().await {
+                frame.unwrap();
+                let frame = fast.frame().await.unwrap().unwrap();
+                copied.extend_from_slice(frame.data_ref().unwrap());
+            }
+
+            assert_eq!(copied, b"abababab");
+            assert!(fast.frame().await.is_none());
+            assert!(matches!(slow.collect().await, Err(Error::Unknown)));
+        }
+    }
+
+    mod reader {
+        use super::*;
+
+        #[tokio::test]
+        async fn reads_to_end() {
+            let data = vec![7; READ_BUFFER_SIZE * 2 + 1];
+            let body = Body::from_reader(io::Cursor::new(data.clone()));
+
+            assert_eq!(body.size_hint().upper(), None);
+            assert_eq!(body.collect().await.unwrap().to_bytes(), data);
+        }
+
+        #[tokio::test]
+        async fn reads_files() {
+            let path = std::env::temp_dir().join(format!("hudsucker-body-{}", std::process::id()));
+            tokio::fs::write(&path, "hello").await.unwrap();
+
+            let body = Body::from_file(&path).await.unwrap();
+            assert_eq!(body.size_hint().exact(), Some(5));
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+
+            tokio::fs::remove_file(path).await.unwrap();
+            assert!(Body::from_file("does-not-exist").await.is_err());
+        }
+    }
+
+    mod collect_up_to {
+        use super::*;
+
+        fn chunks() -> Body {
+            Body::wrap_stream(stream::iter(
+                ["ab", "cd", "ef"]
+                    .map(|chunk| Ok::<_, Error>(Bytes::from_static(chunk.as_bytes()))),
+            ))
+        }
+
+        #[tokio::test]
+        async fn collects_small_bodies() {
+            match chunks().collect_up_to(6).await.unwrap() {
+                Bounded::Complete { data, trailers } => {
+                    assert_eq!(data, "abcdef");
+                    assert!(trailers.is_none());
+                }
+                Bounded::TooLarge(_) => panic!("expected complete body"),
+            }
+        }
+
+        #[tokio::test]
+        async fn keeps_trailers() {
+            let body = Body::from("hello").map_trailers(|_| Some(trailers()));
+
+            match body.collect_up_to(5).await.unwrap() {
+                Bounded::Complete { data, trailers } => {
+                    assert_eq!(data, "hello");
+                    assert_eq!(trailers, Some(super::trailers()));
+                }
+                Bounded::TooLarge(_) => panic!("expected complete body"),
+            }
+        }
+
+        #[tokio::test]
+        async fn passes_large_bodies_through() {
+            match chunks().collect_up_to(3).await.unwrap() {
+                Bounded::TooLarge(body) => {
+                    assert_eq!(body.collect().await.unwrap().to_bytes(), "abcdef")
+                }
+                Bounded::Complete { .. } => panic!("expected large body"),
+            }
+        }
+
+        #[tokio::test]
+        async fn skips_reading_known_large_bodies() {
+            match Body::from("hello").collect_up_to(4).await.unwrap() {
+                Bounded::TooLarge(body) => {
+                    assert_eq!(body.size_hint().exact(), Some(5));
+                    assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+                }
+                Bounded::Complete { .. } => panic!("expected large body"),
+            }
+        }
+    }
+
+    mod peek {
+        use super::*;
+
+        #[tokio::test]
+        async fn reads_enough_chunks() {
+            let body = Body::wrap_stream(stream::iter(
+                ["ab", "cd", "ef"]
+                    .map(|chunk| Ok::<_, Error>(Bytes::from_static(chunk.as_bytes()))),
+            ));
+
+            let (data, body) = body.peek(3).await.unwrap();
+            assert_eq!(data, "abcd");
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "abcdef");
+        }
+
+        #[tokio::test]
+        async fn keeps_trailers() {
+            let body = Body::from("hello").map_trailers(|_| Some(trailers()));
+
+            let (data, body) = body.peek(10).await.unwrap();
+            assert_eq!(data, "hello");
+
+            let collected = body.collect().await.unwrap();
+            assert_eq!(collected.trailers(), Some(&trailers()));
+            assert_eq!(collected.to_bytes(), "hello");
+        }
+    }
+
+    mod limited {
+        use super::*;
+
+        #[tokio::test]
+        async fn within_limit() {
+            let body = Body::from("hello").limited(5, false, || panic!("limit exceeded"));
+
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        }
+
+        #[tokio::test]
+        async fn rejects_when_exceeded() {
+            let exceeded = Arc::new(AtomicBool::new(false));
+            let body = {
+                let exceeded = Arc::clone(&exceeded);
+                Body::from("hello")
+                    .limited(4, false, move || exceeded.store(true, Ordering::Relaxed))
+            };
+
+            assert!(matches!(body.collect().await, Err(Error::BodyTooLarge)));
+            assert!(exceeded.load(Ordering::Relaxed));
+        }
+
+        #[tokio::test]
+        async fn truncates_when_exceeded() {
+            let exceeded = Arc::new(AtomicBool::new(false));
+            let body = {
+                let exceeded = Arc::clone(&exceeded);
+                Body::from("hello")
+                    .limited(4, true, move || exceeded.store(true, Ordering::Relaxed))
+            };
+
+            assert_eq!(body.size_hint().exact(), Some(4));
+            assert_eq!(body.collect().await.unwrap().to_bytes(), "hell");
+            assert!(exceeded.load(Ordering::Relaxed));
+        }
+    }
+
+    mod map_trailers {
+        use super::*;
+
+        #[tokio::test]
+        async fn receives_trailers() {
+            let body = Body::from(Collected::default())
+                .map_trailers(|_| Some(trailers()))
+                .map_trailers(|trailers| {
+                    assert_eq!(trailers, Some(super::trailers()));
+                    None
+                });
+
+            let collected = body.collect().await.unwrap();
+
+            assert_eq!(collected.trailers(), None);
+        }
+
+        #[tokio::test]
+        async fn adds_trailers() {
+            let body = Body::from("hello").map_trailers(|trailers| {
+                assert_eq!(trailers, None);
+                Some(super::trailers())
+            });
+
+            let collected = body.collect().await.unwrap();
+
+            assert_eq!(collected.trailers(), Some(&trailers()));
+            assert_eq!(collected.to_bytes(), "hello");
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use super::{CacheStore, CachedResponse};
+use bstr::ByteSlice;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue},
+    HeaderMap, StatusCode,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
+
+const MAGIC: &[u8] = b"HUDSUCKER-CACHE-1\n";
+
+/// Stores cached responses as files in a directory.
+///
+/// Each response is stored in its own file, named after a hash of its cache key. Responses are
+/// never evicted from the store, other than when they are replaced or invalidated.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::cache::DiskStore;
+///
+/// let store = DiskStore::new(std::env::temp_dir().join("hudsucker-cache"));
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cache")))]
+#[derive(Clone, Debug)]
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    /// Creates a new disk store that stores responses in `dir`.
+    ///
+    /// The directory will be created when the first response is stored.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+
+    async fn write(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(tmp, path).await
+    }
+}
+
+impl CacheStore for DiskStore {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let contents = tokio::fs::read(self.path(key)).await.ok()?;
+        let (stored_key, res) = decode(&contents)?;
+
+        (stored_key == key.as_bytes()).then_some(res)
+    }
+
+    async fn put(&self, key: String, res: CachedResponse) {
+        let path = self.path(&key);
+
+        if let Err(e) = self.write(&path, encode(&key, &res)).await {
+            warn!(
+                "Failed to write cached response to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        let _ = tokio::fs::remove_file(self.path(key)).await;
+    }
+}
+
+/// A stable 64-bit FNV-1a hash, used to name cache files.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn encode_headers(out: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+}
+
+fn encode(key: &str, res: &CachedResponse) -> Vec<u8> {
+    let mut out = Vec::with_capacity(res.body.len() + 512);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(key.as_bytes());
+    out.push(b'\n');
+    out.extend_from_slice(
+        format!(
+            "{} {} {}\n",
+            res.status.as_u16(),
+            millis(res.request_time),
+            millis(res.response_time)
+        )
+        .as_bytes(),
+    );
+    encode_headers(&mut out, &res.headers);
+    encode_headers(&mut out, &res.vary);
+    out.extend_from_slice(&res.body);
+    out
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn line(&mut self) -> Option<&'a [u8]> {
+        let (line, rest) = self.0.split_once_str(b"\n")?;
+        self.0 = rest;
+        Some(line)
+    }
+
+    fn headers(&mut self) -> Option<HeaderMap> {
+        let mut headers = HeaderMap::new();
+
+        loop {
+            let line = self.line()?;
+
+            if line.is_empty() {
+                return Some(headers);
+            }
+
+            let (name, value) = line.split_once_str(b": ")?;
+            headers.append(
+                HeaderName::from_bytes(name).ok()?,
+                HeaderValue::from_bytes(value).ok()?,
+            );
+        }
+    }
+}
+
+fn decode(contents: &[u8]) -> Option<(&[u8], CachedResponse)> {
+    let mut reader = Reader(contents.strip_prefix(MAGIC)?);
+    let key = reader.line()?;
+
+    let mut meta = reader.line()?.to_str().ok()?.split(' ');
+    let status = StatusCode::from_u16(meta.next()?.parse().ok()?).ok()?;
+    let mut time = || {
+        let millis = meta.next()?.parse().ok()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    };
+    let request_time = time()?;
+    let response_time = time()?;
+
+    let headers = reader.headers()?;
+    let vary = reader.headers()?;
+
+    Some((
+        key,
+        CachedResponse {
+            status,
+            headers,
+            body: Bytes::copy_from_slice(reader.0),
+            vary,
+            request_time,
+            response_time,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_response() -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", HeaderValue::from_static("max-age=60"));
+        headers.append("set-cookie", HeaderValue::from_static("a=b"));
+        headers.append("set-cookie", HeaderValue::from_static("c=d"));
+
+        let mut vary = HeaderMap::new();
+        vary.insert("accept-encoding", HeaderValue::from_static("gzip"));
+
+        CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from_static(b"hello\n\nworld"),
+            vary,
+            request_time: SystemTime::UNIX_EPOCH + Duration::from_millis(1_000),
+            response_time: SystemTime::UNIX_EPOCH + Duration::from_millis(2_000),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let res = cached_response();
+        let encoded = encode("http://example.com/", &res);
+        let (key, decoded) = decode(&encoded).unwrap();
+
+        assert_eq!(key, b"http://example.com/");
+        assert_eq!(decoded.status, res.status);
+        assert_eq!(decoded.headers, res.headers);
+        assert_eq!(decoded.vary, res.vary);
+        assert_eq!(decoded.body, res.body);
+        assert_eq!(decoded.request_time, res.request_time);
+        assert_eq!(decoded.response_time, res.response_time);
+    }
+
+    #[test]
+    fn rejects_invalid_contents() {
+        assert!(decode(b"not a cached response").is_none());
+    }
+
+    #[tokio::test]
+    async fn stores_responses() {
+        let dir = std::env::temp_dir().join(format!("hudsucker-cache-test-{}", std::process::id()));
+        let store = DiskStore::new(&dir);
+        let key = "http://example.com/";
+
+        store.put(key.to_owned(), cached_response()).await;
+        assert_eq!(store.get(key).await.unwrap().body, cached_response().body);
+        assert!(store.get("http://example.org/").await.is_none());
+
+        store.remove(key).await;
+        assert!(store.get(key).await.is_none());
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use super::{CacheStore, CachedResponse};
+use crate::memory::{MemoryBudget, Pool};
+use moka::future::Cache;
+
+/// Stores cached responses in memory.
+///
+/// Responses are evicted once the total size of the stored bodies exceeds the capacity that is
+/// provided when creating the store.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::cache::MemoryStore;
+///
+/// // Store up to 64 MiB of response bodies.
+/// let store = MemoryStore::new(64 * 1024 * 1024);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cache")))]
+#[derive(Clone)]
+pub struct MemoryStore {
+    cache: Cache<String, CachedResponse>,
+    capacity: u64,
+    budget: Option<MemoryBudget>,
+}
+
+impl MemoryStore {
+    /// Creates a new memory store that holds up to `capacity` bytes of response bodies.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            cache: Self::cache(capacity, None),
+            capacity,
+            budget: None,
+        }
+    }
+
+    /// Count the stored responses against the response pool of a budget.
+    ///
+    /// If the pool has a limit, responses are evicted once their estimated size exceeds it,
+    /// instead of once their bodies exceed the capacity of the store.
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.cache = Self::cache(self.capacity, Some(budget));
+        self.budget = Some(budget.clone());
+        self
+    }
+
+    fn cache(capacity: u64, budget: Option<&MemoryBudget>) -> Cache<String, CachedResponse> {
+        let builder = Cache::builder()
+            .max_capacity(capacity)
+            .weigher(|_, res: &CachedResponse| u32::try_from(res.body.len()).unwrap_or(u32::MAX));
+
+        match budget {
+            Some(budget) => budget
+                .cache_builder(Pool::Responses, builder, response_size)
+                .build(),
+            None => builder.build(),
+        }
+    }
+}
+
+/// An estimate of the memory that a stored response holds.
+fn response_size(res: &CachedResponse) -> u64 {
+    let headers = res
+        .headers
+        .iter()
+        .chain(res.vary.iter())
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum::<usize>();
+
+    (res.body.len() + headers) as u64
+}
+
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.cache.get(key).await
+    }
+
+    async fn put(&self, key: String, res: CachedResponse) {
+        if let Some(budget) = &self.budget {
+            budget.add(Pool::Responses, response_size(&res));
+        }
+
+        self.cache.insert(key, res).await;
+    }
+
+    async fn remove(&self, key: &str) {
+        self.cache.invalidate(key).await;
+    }
+}
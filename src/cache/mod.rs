@@ -0,0 +1,643 @@
+//! Caching of upstream responses.
+//!
+//! [`CacheHandler`] turns the proxy into a caching forward proxy. Responses that are cacheable
+//! according to RFC 9111 are stored in a [`CacheStore`], fresh responses are served from the store
+//! without contacting the upstream server, and stale responses are revalidated with a conditional
+//! request.
+
+mod disk;
+mod memory;
+mod policy;
+
+use crate::{Body, BodyDirection, Error, HttpContext, HttpHandler, NoopHandler, RequestOrResponse};
+use http_body_util::{combinators::BoxBody, Empty, Full};
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::{
+        HeaderValue, AGE, CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        VARY,
+    },
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+
+pub use disk::DiskStore;
+pub use memory::MemoryStore;
+
+const DEFAULT_MAX_ENTRY_SIZE: usize = 8 * 1024 * 1024;
+
+/// A response that has been stored in a cache.
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    /// Status of the response.
+    pub status: StatusCode,
+    /// Headers of the response.
+    pub headers: HeaderMap,
+    /// Body of the response.
+    pub body: Bytes,
+    /// Request headers that were selected by the `Vary` header of the response.
+    pub vary: HeaderMap,
+    /// When the request for the response was sent.
+    pub request_time: SystemTime,
+    /// When the response was received.
+    pub response_time: SystemTime,
+}
+
+impl CachedResponse {
+    /// Whether the response can be used for a request, based on the `Vary` header.
+    fn matches(&self, req_headers: &HeaderMap) -> bool {
+        vary_names(&self.headers).all(|name| {
+            req_headers
+                .get_all(&name)
+                .iter()
+                .eq(self.vary.get_all(&name).iter())
+        })
+    }
+
+    fn to_response(&self, method: &Method, now: SystemTime) -> Response<Body> {
+        let mut res = Response::new(if method == Method::HEAD {
+            Body::from(Empty::new())
+        } else {
+            Body::from(Full::new(self.body.clone()))
+        });
+
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut().insert(
+            AGE,
+            HeaderValue::from(policy::current_age(self, now).as_secs()),
+        );
+        res
+    }
+
+    /// Update the stored headers with the headers from a `304 Not Modified` response.
+    fn update(&mut self, headers: &HeaderMap, request_time: SystemTime, now: SystemTime) {
+        for name in headers.keys() {
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+
+            self.headers.remove(name);
+
+            for value in headers.get_all(name) {
+                self.headers.append(name, value.clone());
+            }
+        }
+
+        self.request_time = request_time;
+        self.response_time = now;
+    }
+}
+
+fn vary_names(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers.get_all(VARY).iter().flat_map(|val| {
+        val.to_str()
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+    })
+}
+
+fn cache_key<T>(req: &Request<T>) -> String {
+    req.uri().to_string()
+}
+
+/// Stores responses for a [`CacheHandler`].
+pub trait CacheStore: Send + Sync + 'static {
+    /// Get the response stored for a key.
+    fn get(&self, key: &str) -> impl Future<Output = Option<CachedResponse>> + Send;
+
+    /// Store a response for a key, replacing any response that is already stored.
+    fn put(&self, key: String, res: CachedResponse) -> impl Future<Output = ()> + Send;
+
+    /// Remove the response stored for a key.
+    fn remove(&self, key: &str) -> impl Future<Output = ()> + Send;
+}
+
+type Policy = dyn Fn(&Request<()>, &Response<Body>) -> Option<bool> + Send + Sync;
+
+/// The request that a response is expected for.
+struct Pending {
+    key: String,
+    req: Request<()>,
+    request_time: SystemTime,
+    stale: Option<CachedResponse>,
+}
+
+/// An HTTP handler that caches responses.
+///
+/// Requests are passed to the wrapped handler before the cache is checked, and responses are
+/// stored before they are passed to the wrapped handler. Responses that are served from the cache
+/// are also passed to the wrapped handler.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     cache::{CacheHandler, MemoryStore},
+///     hyper::header::CONTENT_TYPE,
+/// };
+///
+/// let handler = CacheHandler::new(MemoryStore::new(64 * 1024 * 1024))
+///     .with_max_entry_size(1024 * 1024)
+///     // Never cache HTML documents, regardless of their cache headers.
+///     .with_policy(|_req, res| {
+///         res.headers()
+///             .get(CONTENT_TYPE)
+///             .filter(|val| val.as_bytes().starts_with(b"text/html"))
+///             .map(|_| false)
+///     });
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cache")))]
+pub struct CacheHandler<S, H = NoopHandler> {
+    store: Arc<S>,
+    inner: H,
+    policy: Option<Arc<Policy>>,
+    max_entry_size: usize,
+    pending: Option<Pending>,
+}
+
+impl<S: CacheStore> CacheHandler<S> {
+    /// Creates a new cache handler that stores responses in `store`.
+    pub fn new(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            inner: NoopHandler::default(),
+            policy: None,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            pending: None,
+        }
+    }
+}
+
+impl<S, H> CacheHandler<S, H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> CacheHandler<S, H2> {
+        CacheHandler {
+            store: self.store,
+            inner,
+            policy: self.policy,
+            max_entry_size: self.max_entry_size,
+            pending: None,
+        }
+    }
+
+    /// Set the maximum size of a response body that will be stored. Defaults to 8 MiB.
+    pub fn with_max_entry_size(mut self, max: usize) -> Self {
+        self.max_entry_size = max;
+        self
+    }
+
+    /// Set a function that can override whether a response is stored.
+    ///
+    /// The function is called with each response that is received from an upstream server. If it
+    /// returns `None`, the rules from RFC 9111 are used to determine whether the response is stored.
+    pub fn with_policy<P>(mut self, policy: P) -> Self
+    where
+        P: Fn(&Request<()>, &Response<Body>) -> Option<bool> + Send + Sync + 'static,
+    {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+}
+
+impl<S, H: Clone> Clone for CacheHandler<S, H> {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            inner: self.inner.clone(),
+            policy: self.policy.clone(),
+            max_entry_size: self.max_entry_size,
+            pending: None,
+        }
+    }
+}
+
+impl<S, H: fmt::Debug> fmt::Debug for CacheHandler<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheHandler")
+            .field("inner", &self.inner)
+            .field("max_entry_size", &self.max_entry_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: CacheStore, H: HttpHandler> CacheHandler<S, H> {
+    async fn lookup(&mut self, ctx: &HttpContext, mut req: Request<Body>) -> RequestOrResponse {
+        self.pending = None;
+        let key = cache_key(&req);
+
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            if !req.method().is_safe() {
+                self.store.remove(&key).await;
+            }
+
+            return req.into();
+        }
+
+        let request_time = SystemTime::now();
+        let mut stale = None;
+
+        if let Some(entry) = self.store.get(&key).await {
+            if entry.matches(req.headers()) {
+                if policy::is_fresh(&entry, req.headers(), request_time) {
+                    let res = entry.to_response(req.method(), request_time);
+                    return self.inner.handle_response(ctx, res).await.into();
+                }
+
+                let is_conditional = req.headers().contains_key(IF_NONE_MATCH)
+                    || req.headers().contains_key(IF_MODIFIED_SINCE);
+
+                if !is_conditional {
+                    if let Some(etag) = entry.headers.get(ETAG) {
+                        req.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+                        stale = Some(entry);
+                    } else if let Some(last_modified) = entry.headers.get(LAST_MODIFIED) {
+                        req.headers_mut()
+                            .insert(IF_MODIFIED_SINCE, last_modified.clone());
+                        stale = Some(entry);
+                    }
+                }
+            }
+        }
+
+        let mut head = Request::new(());
+        *head.method_mut() = req.method().clone();
+        *head.uri_mut() = req.uri().clone();
+        *head.headers_mut() = req.headers().clone();
+
+        self.pending = Some(Pending {
+            key,
+            req: head,
+            request_time,
+            stale,
+        });
+
+        req.into()
+    }
+
+    fn store(&self, pending: Pending, res: Response<Body>) -> Response<Body> {
+        let now = SystemTime::now();
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            if let Some(mut entry) = pending.stale {
+                entry.update(res.headers(), pending.request_time, now);
+
+                let store = Arc::clone(&self.store);
+                let res = entry.to_response(pending.req.method(), now);
+                tokio::spawn(async move { store.put(pending.key, entry).await });
+
+                return res;
+            }
+        }
+
+        let storable = policy::is_storable(
+            pending.req.method(),
+            pending.req.headers(),
+            res.status(),
+            res.headers(),
+        );
+
+        let storable = self
+            .policy
+            .as_ref()
+            .and_then(|policy| policy(&pending.req, &res))
+            .unwrap_or(storable);
+
+        if !storable {
+            return res;
+        }
+
+        let (parts, body) = res.into_parts();
+
+        let mut vary = HeaderMap::new();
+        for name in vary_names(&parts.headers) {
+            for value in pending.req.headers().get_all(&name) {
+                if let Ok(name) = hyper::header::HeaderName::from_bytes(name.as_bytes()) {
+                    vary.append(name, value.clone());
+                }
+            }
+        }
+
+        let mut entry = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: Bytes::new(),
+            vary,
+            request_time: pending.request_time,
+            response_time: now,
+        };
+
+        let store = Arc::clone(&self.store);
+        let key = pending.key;
+
+        if body.is_end_stream() {
+            tokio::spawn(async move { store.put(key, entry).await });
+            return Response::from_parts(parts, body);
+        }
+
+        let body = Recording {
+            body,
+            buf: Vec::new(),
+            max: self.max_entry_size,
+            on_complete: Some(Box::new(move |body| {
+                entry.body = body;
+                tokio::spawn(async move { store.put(key, entry).await });
+            })),
+        };
+
+        Response::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+}
+
+impl<S: CacheStore, H: HttpHandler> HttpHandler for CacheHandler<S, H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.lookup(ctx, req).await,
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = match self.pending.take() {
+            Some(pending) => self.store(pending, res),
+            None => res,
+        };
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.pending = None;
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+/// A body that records its data as it is streamed, up to a maximum size.
+struct Recording {
+    body: Body,
+    buf: Vec<u8>,
+    max: usize,
+    on_complete: Option<Box<dyn FnOnce(Bytes) + Send + Sync>>,
+}
+
+impl HttpBody for Recording {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    if self.buf.len() + data.len() > self.max {
+                        self.on_complete = None;
+                        self.buf = Vec::new();
+                    } else if self.on_complete.is_some() {
+                        self.buf.extend_from_slice(data);
+                    }
+                }
+            }
+            Some(Err(_)) => self.on_complete = None,
+            None => {
+                if let Some(on_complete) = self.on_complete.take() {
+                    on_complete(std::mem::take(&mut self.buf).into());
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.on_complete.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::header::CACHE_CONTROL;
+    use std::time::Duration;
+
+    fn request(method: Method) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("http://example.com/")
+            .body(Body::from(Empty::new()))
+            .unwrap()
+    }
+
+    fn response(cache_control: &'static str) -> Response<Body> {
+        Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .header(ETAG, "\"v1\"")
+            .body(Body::from("hello"))
+            .unwrap()
+    }
+
+    async fn wait_for_store() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    async fn round_trip(
+        handler: &CacheHandler<MemoryStore>,
+        req: Request<Body>,
+        res: impl FnOnce(Request<Body>) -> Response<Body>,
+    ) -> (bool, Response<Body>) {
+        let mut handler = handler.clone();
+
+        match handler.handle_request(&HttpContext::for_test(), req).await {
+            RequestOrResponse::Request(req) => {
+                let res = handler
+                    .handle_response(&HttpContext::for_test(), res(req))
+                    .await;
+                let (parts, body) = res.into_parts();
+                let body = body.collect().await.unwrap().to_bytes();
+                wait_for_store().await;
+                (
+                    false,
+                    Response::from_parts(parts, Body::from(Full::new(body))),
+                )
+            }
+            RequestOrResponse::Response(res) => (true, res),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_responses() {
+        let handler = CacheHandler::new(MemoryStore::new(1024));
+
+        let (cached, _) =
+            round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+        assert!(!cached);
+
+        let (cached, res) = round_trip(&handler, request(Method::GET), |_| unreachable!()).await;
+        assert!(cached);
+        assert!(res.headers().contains_key(AGE));
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_responses() {
+        let handler = CacheHandler::new(MemoryStore::new(1024));
+
+        round_trip(&handler, request(Method::GET), |_| response("max-age=0")).await;
+
+        let (cached, res) = round_trip(&handler, request(Method::GET), |req| {
+            assert_eq!(req.headers().get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+
+            Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(CACHE_CONTROL, "max-age=60")
+                .body(Body::from(Empty::new()))
+                .unwrap()
+        })
+        .await;
+
+        assert!(!cached);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+
+        let (cached, _) = round_trip(&handler, request(Method::GET), |_| unreachable!()).await;
+        assert!(cached);
+    }
+
+    #[tokio::test]
+    async fn invalidates_on_unsafe_methods() {
+        let handler = CacheHandler::new(MemoryStore::new(1024));
+
+        round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+        round_trip(&handler, request(Method::POST), |_| response("no-store")).await;
+
+        let (cached, _) =
+            round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+        assert!(!cached);
+    }
+
+    #[tokio::test]
+    async fn policy_overrides_cacheability() {
+        let handler = CacheHandler::new(MemoryStore::new(1024)).with_policy(|_, _| Some(false));
+
+        round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+
+        let (cached, _) =
+            round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+        assert!(!cached);
+    }
+
+    #[tokio::test]
+    async fn skips_large_responses() {
+        let handler = CacheHandler::new(MemoryStore::new(1024)).with_max_entry_size(4);
+
+        round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+
+        let (cached, _) =
+            round_trip(&handler, request(Method::GET), |_| response("max-age=60")).await;
+        assert!(!cached);
+    }
+
+    #[test]
+    fn matches_vary() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+        let mut vary = HeaderMap::new();
+        vary.insert("accept-encoding", HeaderValue::from_static("gzip"));
+
+        let entry = CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::new(),
+            vary,
+            request_time: SystemTime::now(),
+            response_time: SystemTime::now(),
+        };
+
+        let mut req_headers = HeaderMap::new();
+        assert!(!entry.matches(&req_headers));
+
+        req_headers.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        assert!(entry.matches(&req_headers));
+    }
+}
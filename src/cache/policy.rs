@@ -0,0 +1,413 @@
+use super::CachedResponse;
+use bstr::ByteSlice;
+use hyper::{
+    header::{AGE, AUTHORIZATION, CACHE_CONTROL, DATE, EXPIRES, LAST_MODIFIED, PRAGMA, VARY},
+    HeaderMap, Method, StatusCode,
+};
+use std::time::{Duration, SystemTime};
+
+/// Status codes that are heuristically cacheable (RFC 9110 section 15.1).
+const HEURISTICALLY_CACHEABLE: [StatusCode; 11] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::PERMANENT_REDIRECT,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::GONE,
+    StatusCode::URI_TOO_LONG,
+    StatusCode::NOT_IMPLEMENTED,
+];
+
+/// The fraction of the time since a response was last modified that it will be considered fresh
+/// for when it has no explicit expiration time.
+const HEURISTIC_FRACTION: u32 = 10;
+
+/// The longest a response will be considered fresh for when it has no explicit expiration time.
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Directives from a `Cache-Control` header.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub min_fresh: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+
+        for directive in headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .flat_map(|val| val.as_bytes().split_str(b","))
+        {
+            let (name, value) = match directive.split_once_str(b"=") {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_with(|c| c == '"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = || value.and_then(|v| v.to_str().ok()?.parse().ok());
+
+            match name.to_ascii_lowercase().as_slice() {
+                b"no-store" => cc.no_store = true,
+                b"no-cache" => cc.no_cache = true,
+                b"private" => cc.private = true,
+                b"public" => cc.public = true,
+                b"must-revalidate" | b"proxy-revalidate" => cc.must_revalidate = true,
+                b"max-age" => cc.max_age = seconds(),
+                b"s-maxage" => cc.s_maxage = seconds(),
+                b"min-fresh" => cc.min_fresh = seconds(),
+                _ => (),
+            }
+        }
+
+        // HTTP/1.0 caches use `Pragma: no-cache` in place of `Cache-Control: no-cache`.
+        if headers.get_all(CACHE_CONTROL).iter().next().is_none()
+            && headers
+                .get_all(PRAGMA)
+                .iter()
+                .any(|val| val.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+        {
+            cc.no_cache = true;
+        }
+
+        cc
+    }
+}
+
+fn header_date(headers: &HeaderMap, name: impl hyper::header::AsHeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+/// Whether a response to a request may be stored by a shared cache (RFC 9111 section 3).
+pub(crate) fn is_storable(
+    method: &Method,
+    req_headers: &HeaderMap,
+    status: StatusCode,
+    res_headers: &HeaderMap,
+) -> bool {
+    if method != Method::GET {
+        return false;
+    }
+
+    let req_cc = CacheControl::parse(req_headers);
+    let res_cc = CacheControl::parse(res_headers);
+
+    if req_cc.no_store || res_cc.no_store || res_cc.private {
+        return false;
+    }
+
+    if res_headers
+        .get_all(VARY)
+        .iter()
+        .any(|val| val.as_bytes().split_str(b",").any(|v| v.trim() == b"*"))
+    {
+        return false;
+    }
+
+    if req_headers.contains_key(AUTHORIZATION)
+        && !(res_cc.public || res_cc.must_revalidate || res_cc.s_maxage.is_some())
+    {
+        return false;
+    }
+
+    res_cc.public
+        || res_cc.max_age.is_some()
+        || res_cc.s_maxage.is_some()
+        || res_headers.contains_key(EXPIRES)
+        || HEURISTICALLY_CACHEABLE.contains(&status)
+}
+
+/// How long a response is fresh for after it was generated (RFC 9111 section 4.2.1).
+pub(crate) fn freshness_lifetime(entry: &CachedResponse) -> Duration {
+    let cc = CacheControl::parse(&entry.headers);
+
+    if let Some(secs) = cc.s_maxage.or(cc.max_age) {
+        return Duration::from_secs(secs);
+    }
+
+    let date = header_date(&entry.headers, DATE).unwrap_or(entry.response_time);
+
+    if entry.headers.contains_key(EXPIRES) {
+        return header_date(&entry.headers, EXPIRES)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default();
+    }
+
+    if !HEURISTICALLY_CACHEABLE.contains(&entry.status) {
+        return Duration::ZERO;
+    }
+
+    header_date(&entry.headers, LAST_MODIFIED)
+        .and_then(|last_modified| date.duration_since(last_modified).ok())
+        .map(|age| (age / HEURISTIC_FRACTION).min(MAX_HEURISTIC_LIFETIME))
+        .unwrap_or_default()
+}
+
+/// The current age of a stored response (RFC 9111 section 4.2.3).
+pub(crate) fn current_age(entry: &CachedResponse, now: SystemTime) -> Duration {
+    let date = header_date(&entry.headers, DATE).unwrap_or(entry.response_time);
+    let apparent_age = entry.response_time.duration_since(date).unwrap_or_default();
+
+    let age_value = entry
+        .headers
+        .get(AGE)
+        .and_then(|val| val.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let response_delay = entry
+        .response_time
+        .duration_since(entry.request_time)
+        .unwrap_or_default();
+
+    let corrected_initial_age = apparent_age.max(age_value + response_delay);
+    let resident_time = now.duration_since(entry.response_time).unwrap_or_default();
+
+    corrected_initial_age + resident_time
+}
+
+/// Whether a stored response can be used to satisfy a request without revalidation.
+pub(crate) fn is_fresh(entry: &CachedResponse, req_headers: &HeaderMap, now: SystemTime) -> bool {
+    let req_cc = CacheControl::parse(req_headers);
+    let res_cc = CacheControl::parse(&entry.headers);
+
+    if req_cc.no_cache || res_cc.no_cache {
+        return false;
+    }
+
+    let mut lifetime = freshness_lifetime(entry);
+
+    if let Some(max_age) = req_cc.max_age {
+        lifetime = lifetime.min(Duration::from_secs(max_age));
+    }
+
+    let age = current_age(entry, now) + Duration::from_secs(req_cc.min_fresh.unwrap_or_default());
+
+    age < lifetime
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    hyper::header::HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn entry(res_headers: HeaderMap, status: StatusCode) -> CachedResponse {
+        let now = SystemTime::now();
+
+        CachedResponse {
+            status,
+            headers: res_headers,
+            body: Default::default(),
+            vary: HeaderMap::new(),
+            request_time: now,
+            response_time: now,
+        }
+    }
+
+    mod cache_control {
+        use super::*;
+
+        #[test]
+        fn parses_directives() {
+            let cc = CacheControl::parse(&headers(&[
+                ("cache-control", "Public, max-age=60"),
+                ("cache-control", "s-maxage=\"120\", no-cache"),
+            ]));
+
+            assert!(cc.public);
+            assert!(cc.no_cache);
+            assert!(!cc.no_store);
+            assert_eq!(cc.max_age, Some(60));
+            assert_eq!(cc.s_maxage, Some(120));
+        }
+
+        #[test]
+        fn pragma_no_cache() {
+            let cc = CacheControl::parse(&headers(&[("pragma", "no-cache")]));
+
+            assert!(cc.no_cache);
+        }
+    }
+
+    mod is_storable {
+        use super::*;
+
+        #[test]
+        fn heuristically_cacheable() {
+            assert!(is_storable(
+                &Method::GET,
+                &HeaderMap::new(),
+                StatusCode::OK,
+                &HeaderMap::new()
+            ));
+            assert!(!is_storable(
+                &Method::GET,
+                &HeaderMap::new(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &HeaderMap::new()
+            ));
+        }
+
+        #[test]
+        fn only_get() {
+            assert!(!is_storable(
+                &Method::POST,
+                &HeaderMap::new(),
+                StatusCode::OK,
+                &headers(&[("cache-control", "max-age=60")])
+            ));
+        }
+
+        #[test]
+        fn no_store_and_private() {
+            for value in ["no-store", "private"] {
+                assert!(!is_storable(
+                    &Method::GET,
+                    &HeaderMap::new(),
+                    StatusCode::OK,
+                    &headers(&[("cache-control", value)])
+                ));
+            }
+
+            assert!(!is_storable(
+                &Method::GET,
+                &headers(&[("cache-control", "no-store")]),
+                StatusCode::OK,
+                &HeaderMap::new()
+            ));
+        }
+
+        #[test]
+        fn vary_star() {
+            assert!(!is_storable(
+                &Method::GET,
+                &HeaderMap::new(),
+                StatusCode::OK,
+                &headers(&[("vary", "accept, *")])
+            ));
+        }
+
+        #[test]
+        fn authorization() {
+            let req_headers = headers(&[("authorization", "Bearer foo")]);
+
+            assert!(!is_storable(
+                &Method::GET,
+                &req_headers,
+                StatusCode::OK,
+                &headers(&[("cache-control", "max-age=60")])
+            ));
+            assert!(is_storable(
+                &Method::GET,
+                &req_headers,
+                StatusCode::OK,
+                &headers(&[("cache-control", "public, max-age=60")])
+            ));
+        }
+    }
+
+    mod freshness_lifetime {
+        use super::*;
+
+        #[test]
+        fn prefers_s_maxage() {
+            let entry = entry(
+                headers(&[("cache-control", "max-age=60, s-maxage=120")]),
+                StatusCode::OK,
+            );
+
+            assert_eq!(freshness_lifetime(&entry), Duration::from_secs(120));
+        }
+
+        #[test]
+        fn expires() {
+            let entry = entry(
+                headers(&[
+                    ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                    ("expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+                ]),
+                StatusCode::OK,
+            );
+
+            assert_eq!(freshness_lifetime(&entry), Duration::from_secs(60));
+        }
+
+        #[test]
+        fn heuristic() {
+            let entry = entry(
+                headers(&[
+                    ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                    ("last-modified", "Sun, 06 Nov 1994 08:32:57 GMT"),
+                ]),
+                StatusCode::OK,
+            );
+
+            assert_eq!(freshness_lifetime(&entry), Duration::from_secs(100));
+        }
+    }
+
+    mod is_fresh {
+        use super::*;
+
+        #[test]
+        fn within_lifetime() {
+            let entry = entry(headers(&[("cache-control", "max-age=60")]), StatusCode::OK);
+
+            assert!(is_fresh(&entry, &HeaderMap::new(), SystemTime::now()));
+        }
+
+        #[test]
+        fn past_lifetime() {
+            let entry = entry(headers(&[("cache-control", "max-age=60")]), StatusCode::OK);
+            let now = SystemTime::now() + Duration::from_secs(61);
+
+            assert!(!is_fresh(&entry, &HeaderMap::new(), now));
+        }
+
+        #[test]
+        fn age_header() {
+            let entry = entry(
+                headers(&[("cache-control", "max-age=60"), ("age", "60")]),
+                StatusCode::OK,
+            );
+
+            assert!(!is_fresh(&entry, &HeaderMap::new(), SystemTime::now()));
+        }
+
+        #[test]
+        fn request_directives() {
+            let entry = entry(headers(&[("cache-control", "max-age=60")]), StatusCode::OK);
+
+            assert!(!is_fresh(
+                &entry,
+                &headers(&[("cache-control", "no-cache")]),
+                SystemTime::now()
+            ));
+            assert!(!is_fresh(
+                &entry,
+                &headers(&[("cache-control", "max-age=0")]),
+                SystemTime::now()
+            ));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Capturing the traffic of a child process.
+//!
+//! [`Capture`] starts a proxy on an ephemeral port with a newly generated CA, and spawns a command
+//! whose environment routes its HTTP and HTTPS requests through the proxy and trusts the CA, so
+//! that a handler sees everything that the command does.
+//!
+//! The child's environment has:
+//!
+//! - `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`, in upper and lower case, set to the URL of the
+//!   proxy, and `NO_PROXY` removed.
+//! - `SSL_CERT_FILE`, `REQUESTS_CA_BUNDLE`, `CURL_CA_BUNDLE`, `GIT_SSL_CAINFO` and
+//!   `CARGO_HTTP_CAINFO` set to a file containing the CA certificate, which replaces the trusted
+//!   roots of OpenSSL, Python, curl, Git and Cargo.
+//! - `NODE_EXTRA_CA_CERTS` set to the same file, which Node.js trusts in addition to its roots.
+//!
+//! The proxy verifies upstream servers with the [webpki roots](crate::builder::ProxyBuilder::with_rustls_client),
+//! so the child only needs to trust the CA. Programs that ignore these variables, or that pin
+//! certificates, are not captured.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::{capture::Capture, LogHandler};
+//! use tokio::process::Command;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let mut command = Command::new("curl");
+//! command.arg("https://example.com");
+//!
+//! let mut child = Capture::new(command)
+//!     .with_http_handler(LogHandler::new())
+//!     .spawn()
+//!     .await?;
+//! let status = child.wait().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    certificate_authority::RcgenAuthority,
+    rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose},
+    HttpHandler, NoopHandler, Proxy, WebSocketHandler,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+use tokio::{
+    net::TcpListener,
+    process::{Child, Command},
+    sync::oneshot,
+};
+use tracing::debug;
+
+const PROXY_VARS: [&str; 6] = [
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "ALL_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "all_proxy",
+];
+
+const CA_VARS: [&str; 6] = [
+    "SSL_CERT_FILE",
+    "REQUESTS_CA_BUNDLE",
+    "CURL_CA_BUNDLE",
+    "GIT_SSL_CAINFO",
+    "CARGO_HTTP_CAINFO",
+    "NODE_EXTRA_CA_CERTS",
+];
+
+/// Generates a CA for a capture, returning its certificate in PEM format and an authority that
+/// issues certificates signed by it.
+fn generate_ca() -> io::Result<(String, RcgenAuthority)> {
+    let key_pair = KeyPair::generate().map_err(io::Error::other)?;
+
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Hudsucker Capture CA");
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+
+    let cert = params.self_signed(&key_pair).map_err(io::Error::other)?;
+    let pem = cert.pem();
+
+    Ok((pem, RcgenAuthority::new(key_pair, cert, 1_000)))
+}
+
+/// A command whose traffic is captured by a proxy.
+///
+/// See the [module documentation](self) for the environment of the command.
+#[derive(Debug)]
+pub struct Capture<H = NoopHandler, W = NoopHandler> {
+    command: Command,
+    http_handler: H,
+    websocket_handler: W,
+}
+
+impl Capture {
+    /// Creates a capture of a command. Its environment is changed when it is spawned.
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            http_handler: NoopHandler::new(),
+            websocket_handler: NoopHandler::new(),
+        }
+    }
+}
+
+impl<H, W> Capture<H, W> {
+    /// Set the HTTP handler of the proxy.
+    pub fn with_http_handler<H2: HttpHandler>(self, http_handler: H2) -> Capture<H2, W> {
+        Capture {
+            command: self.command,
+            http_handler,
+            websocket_handler: self.websocket_handler,
+        }
+    }
+
+    /// Set the WebSocket handler of the proxy.
+    pub fn with_websocket_handler<W2: WebSocketHandler>(
+        self,
+        websocket_handler: W2,
+    ) -> Capture<H, W2> {
+        Capture {
+            command: self.command,
+            http_handler: self.http_handler,
+            websocket_handler,
+        }
+    }
+}
+
+impl<H: HttpHandler, W: WebSocketHandler> Capture<H, W> {
+    /// Starts the proxy and spawns the command.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proxy cannot listen on an ephemeral port, if the CA cannot be
+    /// generated or written to a temporary file, or if the command cannot be spawned.
+    pub async fn spawn(mut self) -> io::Result<CapturedChild> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let (cert_pem, ca) = generate_ca()?;
+
+        let ca_path = std::env::temp_dir().join(format!(
+            "hudsucker-capture-{}-{}.pem",
+            std::process::id(),
+            addr.port()
+        ));
+        tokio::fs::write(&ca_path, cert_pem).await?;
+
+        let url = format!("http://{}", addr);
+
+        for var in PROXY_VARS {
+            self.command.env(var, &url);
+        }
+
+        for var in CA_VARS {
+            self.command.env(var, &ca_path);
+        }
+
+        self.command.env_remove("NO_PROXY").env_remove("no_proxy");
+
+        let (tx, rx) = oneshot::channel();
+        let proxy = Proxy::builder()
+            .with_listener(listener)
+            .with_rustls_client()
+            .with_ca(ca)
+            .with_http_handler(self.http_handler)
+            .with_websocket_handler(self.websocket_handler)
+            .with_graceful_shutdown(async {
+                rx.await.unwrap_or_default();
+            })
+            .build();
+
+        tokio::spawn(async move {
+            if let Err(e) = proxy.start().await {
+                debug!("Capture proxy failed: {}", e);
+            }
+        });
+
+        match self.command.spawn() {
+            Ok(child) => Ok(CapturedChild {
+                child,
+                addr,
+                ca_path,
+                _shutdown: tx,
+            }),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&ca_path).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A child process whose traffic is captured.
+///
+/// The proxy is shut down and the CA file is removed when this is dropped. Like
+/// [`tokio::process::Child`], the process keeps running unless it was configured with
+/// [`Command::kill_on_drop`].
+#[derive(Debug)]
+pub struct CapturedChild {
+    child: Child,
+    addr: SocketAddr,
+    ca_path: PathBuf,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl CapturedChild {
+    /// The child process.
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Waits for the child process to exit.
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.child.wait().await
+    }
+
+    /// The address that the proxy is listening on.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The path of the file that contains the CA certificate, in PEM format.
+    pub fn ca_path(&self) -> &Path {
+        &self.ca_path
+    }
+}
+
+impl Drop for CapturedChild {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.ca_path) {
+            debug!("Failed to remove {}: {}", self.ca_path.display(), e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn configures_the_environment() {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(r#"printf '%s %s %s\n' "$HTTPS_PROXY" "$NODE_EXTRA_CA_CERTS" "${NO_PROXY-unset}"; cat "$SSL_CERT_FILE""#)
+            .env("NO_PROXY", "*")
+            .stdout(Stdio::piped());
+
+        let mut child = Capture::new(command).spawn().await.unwrap();
+        let mut stdout = String::new();
+        child
+            .child_mut()
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .await
+            .unwrap();
+        assert!(child.wait().await.unwrap().success());
+
+        let (vars, pem) = stdout.split_once('\n').unwrap();
+        assert_eq!(
+            vars,
+            format!(
+                "http://{} {} unset",
+                child.proxy_addr(),
+                child.ca_path().display()
+            )
+        );
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----"));
+
+        let ca_path = child.ca_path().to_owned();
+        drop(child);
+        assert!(!ca_path.exists());
+    }
+}
@@ -3,10 +3,21 @@ mod openssl_authority;
 #[cfg(feature = "rcgen-ca")]
 mod rcgen_authority;
 
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+use crate::memory::{MemoryBudget, Pool};
 use http::uri::Authority;
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+use moka::future::Cache;
 use std::future::Future;
 use std::sync::Arc;
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+use tokio::sync::Semaphore;
 use tokio_rustls::rustls::ServerConfig;
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+use tokio_rustls::rustls::{
+    crypto::ring::Ticketer,
+    server::{ProducesTickets, ServerSessionMemoryCache, StoresServerSessions},
+};
 
 #[cfg(feature = "openssl-ca")]
 pub use openssl_authority::*;
@@ -16,6 +27,112 @@ pub use rcgen_authority::*;
 const TTL_SECS: i64 = 365 * 24 * 60 * 60;
 const CACHE_TTL: u64 = TTL_SECS as u64 / 2;
 const NOT_BEFORE_OFFSET: i64 = 60;
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+const SESSION_CACHE_SIZE: usize = 1024;
+/// An estimate of the memory that a server config holds besides its certificate and key.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+const SERVER_CONFIG_SIZE: u64 = 1024;
+
+/// A generated server config, and an estimate of the memory that it holds.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+#[derive(Clone)]
+pub(crate) struct CachedConfig {
+    pub config: Arc<ServerConfig>,
+    pub size: u64,
+}
+
+/// The cache of the server configs generated by an authority, which holds up to `cache_size`
+/// configs unless they are counted against the certificate limit of a budget.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+pub(crate) fn config_cache(
+    cache_size: u64,
+    budget: Option<&MemoryBudget>,
+) -> Cache<Authority, CachedConfig> {
+    let builder = Cache::builder()
+        .max_capacity(cache_size)
+        .time_to_live(std::time::Duration::from_secs(CACHE_TTL));
+
+    match budget {
+        Some(budget) => budget
+            .cache_builder(Pool::Certificates, builder, |cached: &CachedConfig| {
+                cached.size
+            })
+            .build(),
+        None => builder.build(),
+    }
+}
+
+/// Wrap a generated server config for the cache, counting it against the certificate pool of a
+/// budget.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+pub(crate) fn cached_config(
+    budget: Option<&MemoryBudget>,
+    config: Arc<ServerConfig>,
+    der_len: usize,
+) -> CachedConfig {
+    let size = der_len as u64 + SERVER_CONFIG_SIZE;
+
+    if let Some(budget) = budget {
+        budget.add(Pool::Certificates, size);
+    }
+
+    CachedConfig { config, size }
+}
+
+/// Signs certificates on the blocking thread pool, off the tasks that serve connections.
+///
+/// At most one certificate is signed per available CPU, so that a burst of new hosts queues up
+/// instead of occupying the whole blocking pool.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+#[derive(Clone)]
+pub(crate) struct Signer(Arc<Semaphore>);
+
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+impl Signer {
+    pub(crate) fn new() -> Self {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self(Arc::new(Semaphore::new(workers)))
+    }
+
+    /// Run a signing function on a worker, resuming its panic if it panics.
+    pub(crate) async fn sign<T, F>(&self, sign: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let _permit = self.0.acquire().await.expect("Signer semaphore closed");
+
+        match tokio::task::spawn_blocking(sign).await {
+            Ok(signed) => signed,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// The ticket keys and session cache that clients resume TLS sessions with, which are shared by
+/// the server configs of all hosts.
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+#[derive(Clone)]
+pub(crate) struct SessionResumption {
+    ticketer: Arc<dyn ProducesTickets>,
+    storage: Arc<dyn StoresServerSessions + Send + Sync>,
+}
+
+#[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+impl SessionResumption {
+    pub(crate) fn new() -> Self {
+        Self {
+            ticketer: Ticketer::new().expect("Failed to create session ticketer"),
+            storage: ServerSessionMemoryCache::new(SESSION_CACHE_SIZE),
+        }
+    }
+
+    /// Enable resumption with session tickets and session IDs for a server config.
+    pub(crate) fn enable(&self, server_cfg: &mut ServerConfig) {
+        server_cfg.ticketer = Arc::clone(&self.ticketer);
+        server_cfg.session_storage = Arc::clone(&self.storage);
+    }
+}
 
 /// Issues certificates for use when communicating with clients.
 ///
@@ -27,4 +144,18 @@ pub trait CertificateAuthority: Send + Sync + 'static {
         &self,
         authority: &Authority,
     ) -> impl Future<Output = Arc<ServerConfig>> + Send;
+
+    /// Start generating the ServerConfig for an authority before a client connects to it, so
+    /// that it is ready by the time of the TLS handshake.
+    ///
+    /// This is called for the hosts of CONNECT requests when certificate prefetching is enabled
+    /// with [`ProxyBuilder::with_certificate_prefetch`]. Authorities that cache their configs,
+    /// and make concurrent calls to [`CertificateAuthority::gen_server_config`] wait for a config
+    /// that is being generated, should generate it here. By default, this does nothing.
+    ///
+    /// [`ProxyBuilder::with_certificate_prefetch`]: crate::builder::ProxyBuilder::with_certificate_prefetch
+    fn prefetch_server_config(&self, authority: &Authority) -> impl Future<Output = ()> + Send {
+        let _ = authority;
+        async {}
+    }
 }
@@ -1,4 +1,10 @@
-use crate::certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS};
+use crate::{
+    certificate_authority::{
+        cached_config, config_cache, CachedConfig, CertificateAuthority, SessionResumption, Signer,
+        NOT_BEFORE_OFFSET, TTL_SECS,
+    },
+    memory::MemoryBudget,
+};
 use http::uri::Authority;
 use moka::future::Cache;
 use openssl::{
@@ -10,10 +16,7 @@ use openssl::{
     rand,
     x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder, X509},
 };
-use std::{
-    sync::Arc,
-    time::{Duration, SystemTime},
-};
+use std::{sync::Arc, time::SystemTime};
 use tokio_rustls::rustls::{
     pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
     ServerConfig,
@@ -26,6 +29,13 @@ use tracing::debug;
 /// up to a max size that is provided when creating the authority. Certificates are generated using
 /// the `openssl` crate.
 ///
+/// Clients can resume their TLS sessions with session tickets or session IDs. The ticket keys and
+/// session cache are shared by the certificates of all hosts, so sessions can be resumed after a
+/// host's certificate is evicted from the cache.
+///
+/// Certificates are signed on the blocking thread pool, and concurrent handshakes for a host wait
+/// for the same certificate to be generated.
+///
 /// # Examples
 ///
 /// ```rust
@@ -43,11 +53,20 @@ use tracing::debug;
 /// ```
 #[cfg_attr(docsrs, doc(cfg(feature = "openssl-ca")))]
 pub struct OpensslAuthority {
-    pkey: PKey<Private>,
+    issuer: Arc<Issuer>,
     private_key: PrivateKeyDer<'static>,
+    cache: Cache<Authority, CachedConfig>,
+    cache_size: u64,
+    budget: Option<MemoryBudget>,
+    resumption: SessionResumption,
+    signer: Signer,
+}
+
+/// The CA that signs the certificates of an authority.
+struct Issuer {
+    pkey: PKey<Private>,
     ca_cert: X509,
     hash: MessageDigest,
-    cache: Cache<Authority, Arc<ServerConfig>>,
 }
 
 impl OpensslAuthority {
@@ -59,17 +78,32 @@ impl OpensslAuthority {
         ));
 
         Self {
-            pkey,
+            issuer: Arc::new(Issuer {
+                pkey,
+                ca_cert,
+                hash,
+            }),
             private_key,
-            ca_cert,
-            hash,
-            cache: Cache::builder()
-                .max_capacity(cache_size)
-                .time_to_live(Duration::from_secs(CACHE_TTL))
-                .build(),
+            cache: config_cache(cache_size, None),
+            cache_size,
+            budget: None,
+            resumption: SessionResumption::new(),
+            signer: Signer::new(),
         }
     }
 
+    /// Count the cached certificates against the certificate pool of a budget.
+    ///
+    /// If the pool has a limit, certificates are evicted once their estimated size exceeds it,
+    /// instead of once the cache holds `cache_size` certificates.
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.cache = config_cache(self.cache_size, Some(budget));
+        self.budget = Some(budget.clone());
+        self
+    }
+}
+
+impl Issuer {
     fn gen_cert(&self, authority: &Authority) -> Result<CertificateDer<'static>, ErrorStack> {
         let mut name_builder = X509NameBuilder::new()?;
         name_builder.append_entry_by_text("CN", authority.host())?;
@@ -110,34 +144,49 @@ impl OpensslAuthority {
 
 impl CertificateAuthority for OpensslAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
-        if let Some(server_cfg) = self.cache.get(authority).await {
+        let entry = self
+            .cache
+            .entry_by_ref(authority)
+            .or_insert_with(async {
+                debug!("Generating server config");
+
+                let issuer = Arc::clone(&self.issuer);
+                let host = authority.clone();
+                let certs = vec![self
+                    .signer
+                    .sign(move || issuer.gen_cert(&host))
+                    .await
+                    .unwrap_or_else(|_| {
+                        panic!("Failed to generate certificate for {}", authority)
+                    })];
+
+                let der_len = certs[0].len() + self.private_key.secret_der().len();
+
+                let mut server_cfg = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, self.private_key.clone_key())
+                    .expect("Failed to build ServerConfig");
+
+                server_cfg.alpn_protocols = vec![
+                    #[cfg(feature = "http2")]
+                    b"h2".to_vec(),
+                    b"http/1.1".to_vec(),
+                ];
+                self.resumption.enable(&mut server_cfg);
+
+                cached_config(self.budget.as_ref(), Arc::new(server_cfg), der_len)
+            })
+            .await;
+
+        if !entry.is_fresh() {
             debug!("Using cached server config");
-            return server_cfg;
         }
-        debug!("Generating server config");
-
-        let certs = vec![self
-            .gen_cert(authority)
-            .unwrap_or_else(|_| panic!("Failed to generate certificate for {}", authority))];
-
-        let mut server_cfg = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, self.private_key.clone_key())
-            .expect("Failed to build ServerConfig");
 
-        server_cfg.alpn_protocols = vec![
-            #[cfg(feature = "http2")]
-            b"h2".to_vec(),
-            b"http/1.1".to_vec(),
-        ];
-
-        let server_cfg = Arc::new(server_cfg);
-
-        self.cache
-            .insert(authority.clone(), Arc::clone(&server_cfg))
-            .await;
+        entry.into_value().config
+    }
 
-        server_cfg
+    async fn prefetch_server_config(&self, authority: &Authority) {
+        self.gen_server_config(authority).await;
     }
 }
 
@@ -162,10 +211,10 @@ mod tests {
         let authority1 = Authority::from_static("example.com");
         let authority2 = Authority::from_static("example2.com");
 
-        let c1 = ca.gen_cert(&authority1).unwrap();
-        let c2 = ca.gen_cert(&authority2).unwrap();
-        let c3 = ca.gen_cert(&authority1).unwrap();
-        let c4 = ca.gen_cert(&authority2).unwrap();
+        let c1 = ca.issuer.gen_cert(&authority1).unwrap();
+        let c2 = ca.issuer.gen_cert(&authority2).unwrap();
+        let c3 = ca.issuer.gen_cert(&authority1).unwrap();
+        let c4 = ca.issuer.gen_cert(&authority2).unwrap();
 
         let (_, cert1) = x509_parser::parse_x509_certificate(&c1).unwrap();
         let (_, cert2) = x509_parser::parse_x509_certificate(&c2).unwrap();
@@ -1,4 +1,10 @@
-use crate::certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS};
+use crate::{
+    certificate_authority::{
+        cached_config, config_cache, CachedConfig, CertificateAuthority, SessionResumption, Signer,
+        NOT_BEFORE_OFFSET, TTL_SECS,
+    },
+    memory::MemoryBudget,
+};
 use http::uri::Authority;
 use moka::future::Cache;
 use rand::{thread_rng, Rng};
@@ -19,6 +25,13 @@ use tracing::debug;
 /// up to a max size that is provided when creating the authority. Certificates are generated using
 /// the `rcgen` crate.
 ///
+/// Clients can resume their TLS sessions with session tickets or session IDs. The ticket keys and
+/// session cache are shared by the certificates of all hosts, so sessions can be resumed after a
+/// host's certificate is evicted from the cache.
+///
+/// Certificates are signed on the blocking thread pool, and concurrent handshakes for a host wait
+/// for the same certificate to be generated.
+///
 /// # Examples
 ///
 /// ```rust
@@ -37,10 +50,19 @@ use tracing::debug;
 /// ```
 #[cfg_attr(docsrs, doc(cfg(feature = "rcgen-ca")))]
 pub struct RcgenAuthority {
+    issuer: Arc<Issuer>,
+    private_key: PrivateKeyDer<'static>,
+    cache: Cache<Authority, CachedConfig>,
+    cache_size: u64,
+    budget: Option<MemoryBudget>,
+    resumption: SessionResumption,
+    signer: Signer,
+}
+
+/// The CA that signs the certificates of an authority.
+struct Issuer {
     key_pair: KeyPair,
     ca_cert: Certificate,
-    private_key: PrivateKeyDer<'static>,
-    cache: Cache<Authority, Arc<ServerConfig>>,
 }
 
 impl RcgenAuthority {
@@ -49,16 +71,28 @@ impl RcgenAuthority {
         let private_key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
 
         Self {
-            key_pair,
-            ca_cert,
+            issuer: Arc::new(Issuer { key_pair, ca_cert }),
             private_key,
-            cache: Cache::builder()
-                .max_capacity(cache_size)
-                .time_to_live(std::time::Duration::from_secs(CACHE_TTL))
-                .build(),
+            cache: config_cache(cache_size, None),
+            cache_size,
+            budget: None,
+            resumption: SessionResumption::new(),
+            signer: Signer::new(),
         }
     }
 
+    /// Count the cached certificates against the certificate pool of a budget.
+    ///
+    /// If the pool has a limit, certificates are evicted once their estimated size exceeds it,
+    /// instead of once the cache holds `cache_size` certificates.
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.cache = config_cache(self.cache_size, Some(budget));
+        self.budget = Some(budget.clone());
+        self
+    }
+}
+
+impl Issuer {
     fn gen_cert(&self, authority: &Authority) -> CertificateDer<'static> {
         let mut params = CertificateParams::default();
         params.serial_number = Some(thread_rng().gen::<u64>().into());
@@ -84,32 +118,43 @@ impl RcgenAuthority {
 
 impl CertificateAuthority for RcgenAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
-        if let Some(server_cfg) = self.cache.get(authority).await {
+        let entry = self
+            .cache
+            .entry_by_ref(authority)
+            .or_insert_with(async {
+                debug!("Generating server config");
+
+                let issuer = Arc::clone(&self.issuer);
+                let host = authority.clone();
+                let certs = vec![self.signer.sign(move || issuer.gen_cert(&host)).await];
+
+                let der_len = certs[0].len() + self.private_key.secret_der().len();
+
+                let mut server_cfg = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, self.private_key.clone_key())
+                    .expect("Failed to build ServerConfig");
+
+                server_cfg.alpn_protocols = vec![
+                    #[cfg(feature = "http2")]
+                    b"h2".to_vec(),
+                    b"http/1.1".to_vec(),
+                ];
+                self.resumption.enable(&mut server_cfg);
+
+                cached_config(self.budget.as_ref(), Arc::new(server_cfg), der_len)
+            })
+            .await;
+
+        if !entry.is_fresh() {
             debug!("Using cached server config");
-            return server_cfg;
         }
-        debug!("Generating server config");
-
-        let certs = vec![self.gen_cert(authority)];
-
-        let mut server_cfg = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, self.private_key.clone_key())
-            .expect("Failed to build ServerConfig");
 
-        server_cfg.alpn_protocols = vec![
-            #[cfg(feature = "http2")]
-            b"h2".to_vec(),
-            b"http/1.1".to_vec(),
-        ];
-
-        let server_cfg = Arc::new(server_cfg);
-
-        self.cache
-            .insert(authority.clone(), Arc::clone(&server_cfg))
-            .await;
+        entry.into_value().config
+    }
 
-        server_cfg
+    async fn prefetch_server_config(&self, authority: &Authority) {
+        self.gen_server_config(authority).await;
     }
 }
 
@@ -136,10 +181,10 @@ mod tests {
         let authority1 = Authority::from_static("example.com");
         let authority2 = Authority::from_static("example2.com");
 
-        let c1 = ca.gen_cert(&authority1);
-        let c2 = ca.gen_cert(&authority2);
-        let c3 = ca.gen_cert(&authority1);
-        let c4 = ca.gen_cert(&authority2);
+        let c1 = ca.issuer.gen_cert(&authority1);
+        let c2 = ca.issuer.gen_cert(&authority2);
+        let c3 = ca.issuer.gen_cert(&authority1);
+        let c4 = ca.issuer.gen_cert(&authority2);
 
         let (_, cert1) = x509_parser::parse_x509_certificate(&c1).unwrap();
         let (_, cert2) = x509_parser::parse_x509_certificate(&c2).unwrap();
@@ -154,4 +199,49 @@ mod tests {
         assert_ne!(cert1.raw_serial(), cert3.raw_serial());
         assert_ne!(cert2.raw_serial(), cert4.raw_serial());
     }
+
+    #[tokio::test]
+    async fn shares_session_tickets() {
+        let ca = build_ca(0);
+
+        let config1 = ca
+            .gen_server_config(&Authority::from_static("example.com"))
+            .await;
+        let config2 = ca
+            .gen_server_config(&Authority::from_static("example2.com"))
+            .await;
+
+        assert!(config1.ticketer.enabled());
+
+        let ticket = config1.ticketer.encrypt(b"session").unwrap();
+        assert_eq!(config2.ticketer.decrypt(&ticket).unwrap(), b"session");
+    }
+
+    #[tokio::test]
+    async fn generates_one_config_for_concurrent_handshakes() {
+        let ca = build_ca(1_000);
+        let authority = Authority::from_static("example.com");
+
+        let (config1, config2) = tokio::join!(
+            ca.gen_server_config(&authority),
+            ca.gen_server_config(&authority)
+        );
+
+        assert!(Arc::ptr_eq(&config1, &config2));
+    }
+
+    #[tokio::test]
+    async fn evicts_certificates_over_budget() {
+        let budget = MemoryBudget::new().with_certificate_limit(4096);
+        let ca = build_ca(1_000).with_memory_budget(&budget);
+
+        for host in ["a.example.com", "b.example.com", "c.example.com"] {
+            ca.gen_server_config(&host.parse().unwrap()).await;
+            ca.cache.run_pending_tasks().await;
+        }
+
+        let usage = budget.usage().certificates;
+        assert!(usage > 0 && usage <= 4096, "{} bytes cached", usage);
+        assert!(ca.cache.entry_count() < 3);
+    }
 }
@@ -0,0 +1,591 @@
+use crate::{
+    auth::{host, HostPattern},
+    Body, BodyDirection, FlowId, HandlerStack, HttpContext, HttpHandler, NoopHandler,
+    RequestOrResponse, TlsFailure,
+};
+use futures::future::BoxFuture;
+use hyper::{header::CONTENT_TYPE, http::uri::Authority, HeaderMap, Request, Response};
+use std::{fmt, future::Future, sync::Arc};
+
+/// The number of frames of a response body that are buffered for a [`TeeResponses`] consumer.
+const TEE_BUFFER: usize = 64;
+
+type Consumer = dyn Fn(HttpContext, Response<Body>) -> BoxFuture<'static, ()> + Send + Sync;
+
+/// Combinators for building a pipeline out of HTTP handlers.
+///
+/// This is implemented for every [`HttpHandler`].
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{HttpHandlerExt, LogHandler, NoopHandler};
+///
+/// let handler = LogHandler::new().and_then(
+///     NoopHandler::new()
+///         .filter_hosts(["*.example.com"])
+///         .on_content_type("text/html"),
+/// );
+/// ```
+pub trait HttpHandlerExt: HttpHandler + Sized {
+    /// Pass requests to this handler and then to `next`, unless this handler responds to them
+    /// directly. See [`HandlerStack`] for how the other hooks are combined.
+    fn and_then<H: HttpHandler>(self, next: H) -> HandlerStack<Self, H> {
+        HandlerStack::new(self, next)
+    }
+
+    /// Only pass requests whose host matches one of the patterns, and their responses, to this
+    /// handler. Other requests are forwarded unmodified.
+    ///
+    /// Patterns are matched like a [`HostPattern`]. `CONNECT` requests are matched by the host of
+    /// their authority, so this handler only decides whether the tunnels to the matching hosts are
+    /// intercepted.
+    fn filter_hosts<P: Into<HostPattern>>(
+        self,
+        patterns: impl IntoIterator<Item = P>,
+    ) -> FilterHosts<Self> {
+        FilterHosts {
+            patterns: patterns.into_iter().map(Into::into).collect(),
+            matched: false,
+            inner: self,
+        }
+    }
+
+    /// Only pass requests and responses whose `Content-Type` matches `pattern` to this handler.
+    /// Others are forwarded unmodified.
+    ///
+    /// The pattern is either a media type (`text/html`), a type followed by a wildcard (`text/*`),
+    /// or `*/*`, which matches every body that has a `Content-Type`. Parameters of the
+    /// `Content-Type`, such as its charset, are ignored, and matching is case-insensitive.
+    fn on_content_type(self, pattern: &str) -> OnContentType<Self> {
+        OnContentType {
+            pattern: pattern.to_ascii_lowercase().into(),
+            inner: self,
+        }
+    }
+
+    /// Stream a copy of each response that this handler returns to `consumer`, such as a recorder
+    /// or a virus scanner, while the response is streamed to the client.
+    ///
+    /// The consumer is spawned with the context of the flow and a response with the same status,
+    /// headers and [`FlowId`] as the original, whose body yields the frames of the original as
+    /// they are sent to the client. The client is never delayed by the consumer: if it falls more
+    /// than 64 frames behind, its body yields an error instead. Stack several of these combinators
+    /// for several consumers.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use http_body_util::BodyExt;
+    /// use hudsucker::{HttpHandlerExt, NoopHandler};
+    ///
+    /// let handler = NoopHandler::new().tee_responses(|ctx, res| async move {
+    ///     if let Ok(body) = res.into_body().collect().await {
+    ///         println!("flow {} sent {} bytes", ctx.flow_id, body.to_bytes().len());
+    ///     }
+    /// });
+    /// ```
+    fn tee_responses<F, Fut>(self, consumer: F) -> TeeResponses<Self>
+    where
+        F: Fn(HttpContext, Response<Body>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        TeeResponses {
+            consumer: Arc::new(move |ctx, res| Box::pin(consumer(ctx, res))),
+            inner: self,
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandlerExt for H {}
+
+/// An HTTP handler that only passes the requests for some hosts to another handler.
+///
+/// This is created by [`HttpHandlerExt::filter_hosts`].
+#[derive(Clone, Debug)]
+pub struct FilterHosts<H> {
+    patterns: Arc<[HostPattern]>,
+    /// Whether the host of the current request matched.
+    matched: bool,
+    inner: H,
+}
+
+impl<H> FilterHosts<H> {
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        host(req).is_some_and(|host| self.matches_host(host))
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.patterns.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for FilterHosts<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.matched = self.matches(&req);
+
+        if self.matched {
+            self.inner.handle_request(ctx, req).await
+        } else {
+            req.into()
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        if self.matches(req) {
+            self.inner.handle_expect_continue(ctx, req).await
+        } else {
+            None
+        }
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        if self.matched {
+            self.inner.inspect_informational(ctx, res).await
+        } else {
+            Some(res)
+        }
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        if self.matched {
+            self.inner.handle_response(ctx, res).await
+        } else {
+            res
+        }
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        if self.matched {
+            self.inner.handle_body_limit_exceeded(ctx, direction).await
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        if self.matched {
+            self.inner.handle_error(ctx, err).await
+        } else {
+            NoopHandler::new().handle_error(ctx, err).await
+        }
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        if self.matched {
+            self.inner.handle_circuit_open(ctx, host).await
+        } else {
+            NoopHandler::new().handle_circuit_open(ctx, host).await
+        }
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        !self.matches(req) || self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: Authority,
+    ) -> Authority {
+        if self.matches(req) {
+            self.inner.handle_connect_target(ctx, req, authority).await
+        } else {
+            authority
+        }
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        if self.matches_host(failure.authority.host()) {
+            self.inner.handle_tls_failure(ctx, failure).await
+        }
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        if self.matched {
+            self.inner.handle_dns_query(ctx, query).await
+        } else {
+            query
+        }
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        if self.matched {
+            self.inner.handle_dns_response(ctx, res).await
+        } else {
+            res
+        }
+    }
+}
+
+/// An HTTP handler that only passes the requests and responses with some content types to another
+/// handler.
+///
+/// Requests that expect a `100 Continue` response are only passed on if they match, while hooks
+/// for messages without content types, such as CONNECT requests and errors, are always passed on.
+///
+/// This is created by [`HttpHandlerExt::on_content_type`].
+#[derive(Clone, Debug)]
+pub struct OnContentType<H> {
+    pattern: Arc<str>,
+    inner: H,
+}
+
+impl<H> OnContentType<H> {
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let Some(essence) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase())
+        else {
+            return false;
+        };
+
+        match self.pattern.strip_suffix('*') {
+            Some("*/") => true,
+            Some(prefix) if prefix.ends_with('/') => essence.starts_with(prefix),
+            _ => essence == *self.pattern,
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for OnContentType<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        if self.matches(req.headers()) {
+            self.inner.handle_request(ctx, req).await
+        } else {
+            req.into()
+        }
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        if self.matches(res.headers()) {
+            self.inner.handle_response(ctx, res).await
+        } else {
+            res
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        if self.matches(req.headers()) {
+            self.inner.handle_expect_continue(ctx, req).await
+        } else {
+            None
+        }
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: Authority,
+    ) -> Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+/// An HTTP handler that streams a copy of each response to a consumer.
+///
+/// This is created by [`HttpHandlerExt::tee_responses`].
+#[derive(Clone)]
+pub struct TeeResponses<H> {
+    consumer: Arc<Consumer>,
+    inner: H,
+}
+
+impl<H: fmt::Debug> fmt::Debug for TeeResponses<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeeResponses")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for TeeResponses<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.inner.handle_request(ctx, req).await
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.inner.handle_response(ctx, res).await;
+        let (parts, body) = res.into_parts();
+        let (body, copy) = body.tee(TEE_BUFFER);
+
+        let mut copy = Response::new(copy);
+        *copy.status_mut() = parts.status;
+        *copy.version_mut() = parts.version;
+        *copy.headers_mut() = parts.headers.clone();
+
+        if let Some(flow_id) = parts.extensions.get::<FlowId>() {
+            copy.extensions_mut().insert(*flow_id);
+        }
+
+        tokio::spawn((self.consumer)(ctx.clone(), copy));
+        Response::from_parts(parts, body)
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: Authority,
+    ) -> Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct Counter {
+        requests: Arc<AtomicUsize>,
+        responses: Arc<AtomicUsize>,
+    }
+
+    impl HttpHandler for Counter {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            req.into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            res: Response<Body>,
+        ) -> Response<Body> {
+            self.responses.fetch_add(1, Ordering::Relaxed);
+            res
+        }
+    }
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::from("")).unwrap()
+    }
+
+    fn response(content_type: &str) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(""))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn filters_hosts() {
+        let counter = Counter::default();
+        let mut handler = counter.clone().filter_hosts(["*.example.com"]);
+
+        for uri in ["http://www.example.com/", "http://example.org/"] {
+            let mut handler = handler.clone();
+            handler
+                .handle_request(&HttpContext::for_test(), request(uri))
+                .await;
+            handler
+                .handle_response(&HttpContext::for_test(), response("text/html"))
+                .await;
+        }
+
+        assert_eq!(counter.requests.load(Ordering::Relaxed), 1);
+        assert_eq!(counter.responses.load(Ordering::Relaxed), 1);
+        assert!(
+            handler
+                .should_intercept(&HttpContext::for_test(), &request("other.test:443"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_copies_of_responses() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = std::sync::Mutex::new(Some(tx));
+        let mut handler = NoopHandler::new().tee_responses(move |ctx, res| {
+            let tx = tx.lock().unwrap().take();
+
+            async move {
+                let flow_id = res.extensions().get::<FlowId>().copied();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+
+                if let Some(tx) = tx {
+                    let _ = tx.send((ctx.flow_id, flow_id, body));
+                }
+            }
+        });
+
+        let mut res = Response::new(Body::from("hello"));
+        res.extensions_mut().insert(FlowId(7));
+        let res = handler.handle_response(&HttpContext::for_test(), res).await;
+
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+        assert_eq!(rx.await.unwrap(), (0, Some(FlowId(7)), "hello".into()));
+    }
+
+    #[tokio::test]
+    async fn matches_content_types() {
+        let counter = Counter::default();
+
+        for (pattern, content_type, matched) in [
+            ("text/html", "text/html; charset=utf-8", true),
+            ("text/html", "TEXT/HTML", true),
+            ("text/html", "text/plain", false),
+            ("text/*", "text/plain", true),
+            ("text/*", "application/json", false),
+            ("*/*", "application/json", true),
+        ] {
+            let before = counter.responses.load(Ordering::Relaxed);
+            let mut handler = counter.clone().on_content_type(pattern);
+            handler
+                .handle_response(&HttpContext::for_test(), response(content_type))
+                .await;
+
+            assert_eq!(
+                counter.responses.load(Ordering::Relaxed) > before,
+                matched,
+                "{} {}",
+                pattern,
+                content_type
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use hyper::Uri;
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+/// A cookie that has been stored in a [`CookieJar`](super::CookieJar).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cookie {
+    /// Name of the cookie.
+    pub name: String,
+    /// Value of the cookie.
+    pub value: String,
+    /// Domain that the cookie is sent to.
+    pub domain: String,
+    /// Whether the cookie is only sent to `domain`, and not to its subdomains.
+    pub host_only: bool,
+    /// Path prefix that the cookie is sent to.
+    pub path: String,
+    /// Whether the cookie is only sent over HTTPS.
+    pub secure: bool,
+    /// Whether the cookie was marked `HttpOnly`.
+    pub http_only: bool,
+    /// When the cookie expires, or `None` for a session cookie.
+    pub expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Creates a new session cookie that is sent to `domain` and all of its paths.
+    pub fn new(
+        name: impl Into<String>,
+        value: impl Into<String>,
+        domain: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            domain: domain.into().to_ascii_lowercase(),
+            host_only: true,
+            path: "/".to_owned(),
+            secure: false,
+            http_only: false,
+            expires: None,
+        }
+    }
+
+    /// Parses a `Set-Cookie` header that was received in response to a request for `uri`.
+    ///
+    /// Returns `None` if the header is malformed, or if it sets a cookie for a domain that `uri`
+    /// does not belong to or for a single-label domain such as `com`.
+    pub fn parse(set_cookie: &str, uri: &Uri, now: SystemTime) -> Option<Self> {
+        let host = uri.host()?.trim_matches(|c| c == '[' || c == ']');
+        let host = host.to_ascii_lowercase();
+
+        let mut attrs = set_cookie.split(';');
+        let (name, value) = attrs.next()?.split_once('=')?;
+        let name = name.trim();
+
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Self::new(name, value.trim(), host.as_str());
+        cookie.path = default_path(uri.path()).to_owned();
+
+        let mut max_age = None;
+        let mut expires = None;
+
+        for attr in attrs {
+            let (key, value) = attr.split_once('=').unwrap_or((attr, ""));
+            let value = value.trim();
+
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+
+                    // A domain without a dot, such as a top-level domain, could be a public
+                    // suffix, so it only sets a host-only cookie for that exact host (RFC 6265
+                    // section 5.3, step 5).
+                    if domain.is_empty() || (!domain.contains('.') && domain == host) {
+                        continue;
+                    }
+
+                    if !domain.contains('.') || !domain_matches(&host, &domain) {
+                        return None;
+                    }
+
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "max-age" => {
+                    if let Ok(secs) = value.parse::<i64>() {
+                        max_age = Some(match u64::try_from(secs) {
+                            Ok(secs) if secs > 0 => now + Duration::from_secs(secs),
+                            _ => SystemTime::UNIX_EPOCH,
+                        });
+                    }
+                }
+                "expires" => expires = httpdate::parse_http_date(value).ok(),
+                _ => {}
+            }
+        }
+
+        cookie.expires = max_age.or(expires);
+        Some(cookie)
+    }
+
+    /// Whether the cookie has expired.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Whether the cookie should be sent with a request for `uri`.
+    pub fn matches(&self, uri: &Uri) -> bool {
+        let Some(host) = uri.host() else {
+            return false;
+        };
+        let host = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_ascii_lowercase();
+
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+
+        domain_ok
+            && path_matches(uri.path(), &self.path)
+            && (!self.secure || uri.scheme_str() == Some("https"))
+    }
+
+    pub(super) fn same_identity(&self, other: &Self) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+/// Domain matching (RFC 6265 section 5.1.3).
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<IpAddr>().is_err())
+}
+
+/// Path matching (RFC 6265 section 5.1.4).
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    let path = if path.is_empty() { "/" } else { path };
+
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The default path of a cookie (RFC 6265 section 5.1.4).
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(s: &'static str) -> Uri {
+        Uri::from_static(s)
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn parses_attributes() {
+            let now = SystemTime::UNIX_EPOCH;
+            let cookie = Cookie::parse(
+                "id=abc; Domain=.example.com; Path=/app; Secure; HttpOnly; Max-Age=60",
+                &uri("https://www.example.com/login"),
+                now,
+            )
+            .unwrap();
+
+            assert_eq!(cookie.name, "id");
+            assert_eq!(cookie.value, "abc");
+            assert_eq!(cookie.domain, "example.com");
+            assert!(!cookie.host_only);
+            assert_eq!(cookie.path, "/app");
+            assert!(cookie.secure);
+            assert!(cookie.http_only);
+            assert_eq!(cookie.expires, Some(now + Duration::from_secs(60)));
+        }
+
+        #[test]
+        fn defaults_to_request_host_and_path() {
+            let cookie =
+                Cookie::parse("id=abc", &uri("http://example.com/a/b"), SystemTime::now()).unwrap();
+
+            assert_eq!(cookie.domain, "example.com");
+            assert!(cookie.host_only);
+            assert_eq!(cookie.path, "/a");
+            assert_eq!(cookie.expires, None);
+        }
+
+        #[test]
+        fn rejects_foreign_domains() {
+            assert!(Cookie::parse(
+                "id=abc; Domain=example.org",
+                &uri("http://example.com/"),
+                SystemTime::now(),
+            )
+            .is_none());
+        }
+
+        #[test]
+        fn rejects_single_label_domains() {
+            assert!(Cookie::parse(
+                "id=abc; Domain=com",
+                &uri("http://example.com/"),
+                SystemTime::now(),
+            )
+            .is_none());
+
+            let cookie = Cookie::parse(
+                "id=abc; Domain=localhost",
+                &uri("http://localhost/"),
+                SystemTime::now(),
+            )
+            .unwrap();
+
+            assert_eq!(cookie.domain, "localhost");
+            assert!(cookie.host_only);
+        }
+
+        #[test]
+        fn expires_with_non_positive_max_age() {
+            let now = SystemTime::now();
+            let cookie =
+                Cookie::parse("id=abc; Max-Age=0", &uri("http://example.com/"), now).unwrap();
+
+            assert!(cookie.is_expired(now));
+        }
+    }
+
+    mod matches {
+        use super::*;
+
+        #[test]
+        fn matches_subdomains() {
+            let mut cookie = Cookie::new("id", "abc", "example.com");
+            assert!(cookie.matches(&uri("http://example.com/")));
+            assert!(!cookie.matches(&uri("http://www.example.com/")));
+
+            cookie.host_only = false;
+            assert!(cookie.matches(&uri("http://www.example.com/")));
+            assert!(!cookie.matches(&uri("http://badexample.com/")));
+        }
+
+        #[test]
+        fn matches_paths() {
+            let mut cookie = Cookie::new("id", "abc", "example.com");
+            cookie.path = "/app".to_owned();
+
+            assert!(cookie.matches(&uri("http://example.com/app")));
+            assert!(cookie.matches(&uri("http://example.com/app/page")));
+            assert!(!cookie.matches(&uri("http://example.com/apple")));
+            assert!(!cookie.matches(&uri("http://example.com/")));
+        }
+
+        #[test]
+        fn secure_requires_https() {
+            let mut cookie = Cookie::new("id", "abc", "example.com");
+            cookie.secure = true;
+
+            assert!(cookie.matches(&uri("https://example.com/")));
+            assert!(!cookie.matches(&uri("http://example.com/")));
+        }
+    }
+}
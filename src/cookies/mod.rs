@@ -0,0 +1,432 @@
+//! Tracking of cookies for each client of the proxy.
+//!
+//! [`CookieHandler`] records the cookies that upstream servers set in a [`CookieJar`], and can
+//! attach them to later requests from the same client. The jar can be inspected and modified while
+//! the proxy is running, which is useful when driving crawlers or automated logins through the
+//! proxy.
+
+mod cookie;
+
+use crate::{Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse};
+use hyper::{
+    header::{HeaderValue, COOKIE, SET_COOKIE},
+    Request, Response, Uri,
+};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub use cookie::Cookie;
+
+/// A store of cookies, kept separately for each client IP address.
+///
+/// Cloning a jar is cheap, and all clones share the same cookies.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::cookies::{Cookie, CookieJar};
+/// use std::net::{IpAddr, Ipv4Addr};
+///
+/// let jar = CookieJar::new();
+/// let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+///
+/// jar.insert(client, Cookie::new("session", "abc123", "example.com"));
+/// assert_eq!(jar.cookies(client).len(), 1);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cookies")))]
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar {
+    clients: Arc<Mutex<HashMap<IpAddr, Vec<Cookie>>>>,
+}
+
+impl CookieJar {
+    /// Creates a new, empty cookie jar.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get all unexpired cookies that are stored for a client.
+    pub fn cookies(&self, client: IpAddr) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        clients
+            .get_mut(&client)
+            .map(|cookies| {
+                cookies.retain(|cookie| !cookie.is_expired(now));
+                cookies.clone()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get the cookies that would be sent with a request for `uri` from a client.
+    ///
+    /// Cookies with longer paths are listed first.
+    pub fn cookies_for(&self, client: IpAddr, uri: &Uri) -> Vec<Cookie> {
+        let mut cookies: Vec<_> = self
+            .cookies(client)
+            .into_iter()
+            .filter(|cookie| cookie.matches(uri))
+            .collect();
+
+        cookies.sort_by_key(|cookie| Reverse(cookie.path.len()));
+        cookies
+    }
+
+    /// Store a cookie for a client, replacing any cookie with the same name, domain, and path.
+    ///
+    /// Storing an expired cookie removes the cookie that it would replace.
+    pub fn insert(&self, client: IpAddr, cookie: Cookie) {
+        let mut clients = self.clients.lock().unwrap();
+        let cookies = clients.entry(client).or_default();
+
+        cookies.retain(|c| !c.same_identity(&cookie));
+
+        if !cookie.is_expired(SystemTime::now()) {
+            cookies.push(cookie);
+        }
+    }
+
+    /// Remove the cookies for a client that match `f`.
+    pub fn remove<F: FnMut(&Cookie) -> bool>(&self, client: IpAddr, mut f: F) {
+        if let Some(cookies) = self.clients.lock().unwrap().get_mut(&client) {
+            cookies.retain(|cookie| !f(cookie));
+        }
+    }
+
+    /// Remove all cookies for a client.
+    pub fn clear(&self, client: IpAddr) {
+        self.clients.lock().unwrap().remove(&client);
+    }
+
+    /// Remove all cookies for all clients.
+    pub fn clear_all(&self) {
+        self.clients.lock().unwrap().clear();
+    }
+
+    /// Store the cookies from the `Set-Cookie` headers of a response to a request for `uri`.
+    pub fn store_response<T>(&self, client: IpAddr, uri: &Uri, res: &Response<T>) {
+        let now = SystemTime::now();
+
+        for value in res.headers().get_all(SET_COOKIE) {
+            if let Some(cookie) = value
+                .to_str()
+                .ok()
+                .and_then(|value| Cookie::parse(value, uri, now))
+            {
+                self.insert(client, cookie);
+            }
+        }
+    }
+
+    /// Add the stored cookies that match a request to its `Cookie` header.
+    ///
+    /// Cookies that the request already contains are left unchanged.
+    pub fn inject<T>(&self, client: IpAddr, req: &mut Request<T>) {
+        let cookies = self.cookies_for(client, req.uri());
+
+        if cookies.is_empty() {
+            return;
+        }
+
+        let mut pairs: Vec<String> = req
+            .headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .map(|pair| pair.trim().to_owned())
+            .filter(|pair| !pair.is_empty())
+            .collect();
+
+        let existing: Vec<String> = pairs
+            .iter()
+            .map(|pair| pair.split('=').next().unwrap_or_default().to_owned())
+            .collect();
+
+        for cookie in cookies {
+            if !existing.contains(&cookie.name) {
+                pairs.push(format!("{}={}", cookie.name, cookie.value));
+            }
+        }
+
+        if let Ok(value) = HeaderValue::from_str(&pairs.join("; ")) {
+            req.headers_mut().insert(COOKIE, value);
+        }
+    }
+}
+
+/// An HTTP handler that records cookies in a [`CookieJar`].
+///
+/// Requests are passed to the wrapped handler before cookies are injected, and responses are
+/// recorded before they are passed to the wrapped handler.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::cookies::{CookieHandler, CookieJar};
+///
+/// let jar = CookieJar::new();
+/// let handler = CookieHandler::new(jar.clone());
+///
+/// // `jar` can be used to inspect and modify the cookies while the proxy is running.
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "cookies")))]
+#[derive(Clone, Debug)]
+pub struct CookieHandler<H = NoopHandler> {
+    jar: CookieJar,
+    inner: H,
+    inject: bool,
+    uri: Option<Uri>,
+}
+
+impl CookieHandler {
+    /// Creates a new cookie handler that records cookies in `jar`.
+    pub fn new(jar: CookieJar) -> Self {
+        Self {
+            jar,
+            inner: NoopHandler::default(),
+            inject: true,
+            uri: None,
+        }
+    }
+}
+
+impl<H> CookieHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> CookieHandler<H2> {
+        CookieHandler {
+            jar: self.jar,
+            inner,
+            inject: self.inject,
+            uri: None,
+        }
+    }
+
+    /// Set whether stored cookies are added to requests. Defaults to `true`.
+    ///
+    /// When disabled, cookies are only recorded.
+    pub fn with_injection(mut self, inject: bool) -> Self {
+        self.inject = inject;
+        self
+    }
+
+    /// Get the jar that cookies are recorded in.
+    pub fn jar(&self) -> &CookieJar {
+        &self.jar
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for CookieHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.uri = None;
+
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(mut req) => {
+                if self.inject {
+                    self.jar.inject(ctx.client_addr.ip(), &mut req);
+                }
+
+                self.uri = Some(req.uri().clone());
+                req.into()
+            }
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        if let Some(uri) = self.uri.take() {
+            self.jar.store_response(ctx.client_addr.ip(), &uri, &res);
+        }
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.uri = None;
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn request(uri: &'static str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .body(Body::from(Empty::new()))
+            .unwrap()
+    }
+
+    mod cookie_jar {
+        use super::*;
+
+        #[test]
+        fn keeps_clients_separate() {
+            let jar = CookieJar::new();
+            let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+            jar.insert(CLIENT, Cookie::new("id", "abc", "example.com"));
+
+            assert_eq!(jar.cookies(CLIENT).len(), 1);
+            assert!(jar.cookies(other).is_empty());
+        }
+
+        #[test]
+        fn replaces_cookies() {
+            let jar = CookieJar::new();
+
+            jar.insert(CLIENT, Cookie::new("id", "abc", "example.com"));
+            jar.insert(CLIENT, Cookie::new("id", "def", "example.com"));
+
+            let cookies = jar.cookies(CLIENT);
+            assert_eq!(cookies.len(), 1);
+            assert_eq!(cookies[0].value, "def");
+        }
+
+        #[test]
+        fn inject_preserves_existing_cookies() {
+            let jar = CookieJar::new();
+            jar.insert(CLIENT, Cookie::new("id", "abc", "example.com"));
+            jar.insert(CLIENT, Cookie::new("theme", "dark", "example.com"));
+
+            let mut req = request("http://example.com/");
+            req.headers_mut()
+                .insert(COOKIE, HeaderValue::from_static("id=mine"));
+
+            jar.inject(CLIENT, &mut req);
+
+            assert_eq!(req.headers().get(COOKIE).unwrap(), "id=mine; theme=dark");
+        }
+    }
+
+    mod cookie_handler {
+        use super::*;
+
+        #[tokio::test]
+        async fn records_and_injects_cookies() {
+            let jar = CookieJar::new();
+            let mut handler = CookieHandler::new(jar.clone());
+
+            handler
+                .handle_request(
+                    &HttpContext::for_test(),
+                    request("http://example.com/login"),
+                )
+                .await;
+            handler
+                .handle_response(
+                    &HttpContext::for_test(),
+                    Response::builder()
+                        .header(SET_COOKIE, "session=abc; Path=/")
+                        .body(Body::from(Empty::new()))
+                        .unwrap(),
+                )
+                .await;
+
+            assert_eq!(jar.cookies(CLIENT)[0].value, "abc");
+
+            match handler
+                .handle_request(
+                    &HttpContext::for_test(),
+                    request("http://example.com/account"),
+                )
+                .await
+            {
+                RequestOrResponse::Request(req) => {
+                    assert_eq!(req.headers().get(COOKIE).unwrap(), "session=abc")
+                }
+                RequestOrResponse::Response(_) => panic!("expected request"),
+            }
+        }
+
+        #[tokio::test]
+        async fn injection_can_be_disabled() {
+            let jar = CookieJar::new();
+            jar.insert(CLIENT, Cookie::new("session", "abc", "example.com"));
+
+            let mut handler = CookieHandler::new(jar).with_injection(false);
+
+            match handler
+                .handle_request(&HttpContext::for_test(), request("http://example.com/"))
+                .await
+            {
+                RequestOrResponse::Request(req) => assert!(!req.headers().contains_key(COOKIE)),
+                RequestOrResponse::Response(_) => panic!("expected request"),
+            }
+        }
+    }
+}
@@ -1,37 +1,81 @@
 use crate::{Body, Error};
-use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use async_compression::{
+    tokio::bufread::{
+        BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder,
+        ZstdDecoder, ZstdEncoder,
+    },
+    Level,
+};
 use bstr::ByteSlice;
-use futures::Stream;
+use futures::{stream, Stream, StreamExt};
+use http_body_util::StreamBody;
 use hyper::{
-    body::{Body as HttpBody, Bytes},
-    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH},
-    Request, Response,
+    body::{Body as HttpBody, Bytes, Frame},
+    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, VARY},
+    Request, Response, StatusCode,
 };
 use std::{
     io,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
 use tokio_util::io::{ReaderStream, StreamReader};
 
-struct IoStream<T>(T);
-
-impl<T: HttpBody<Data = Bytes, Error = Error> + Unpin> Stream for IoStream<T> {
-    type Item = Result<Bytes, io::Error>;
+/// An encoded body that is shared between its decoder and the stream that forwards its trailers.
+///
+/// Decoders may stop reading once they reach the end of the encoded data, so any trailers that
+/// have not been read by the decoder are read once the decoded body has ended.
+struct Encoded<T> {
+    body: T,
+    trailers: Option<HeaderMap>,
+    done: bool,
+}
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        loop {
-            return match futures::ready!(Pin::new(&mut self.0).poll_frame(cx)) {
+impl<T: HttpBody<Data = Bytes, Error = Error> + Unpin> Encoded<T> {
+    fn poll_data(&mut self, cx: &mut Context) -> Poll<Option<Result<Bytes, io::Error>>> {
+        while !self.done {
+            match futures::ready!(Pin::new(&mut self.body).poll_frame(cx)) {
                 Some(Ok(frame)) => match frame.into_data() {
-                    Ok(buf) => Poll::Ready(Some(Ok(buf))),
-                    Err(_) => continue,
+                    Ok(buf) => return Poll::Ready(Some(Ok(buf))),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            self.trailers = Some(trailers);
+                        }
+                    }
                 },
-                Some(Err(Error::Io(err))) => Poll::Ready(Some(Err(err))),
-                Some(Err(err)) => Poll::Ready(Some(Err(io::Error::other(err)))),
-                None => Poll::Ready(None),
-            };
+                Some(Err(Error::Io(err))) => return Poll::Ready(Some(Err(err))),
+                Some(Err(err)) => return Poll::Ready(Some(Err(io::Error::other(err)))),
+                None => self.done = true,
+            }
         }
+
+        Poll::Ready(None)
+    }
+
+    fn poll_trailers(&mut self, cx: &mut Context) -> Poll<Option<HeaderMap>> {
+        while self.trailers.is_none() {
+            match futures::ready!(self.poll_data(cx)) {
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => break,
+            }
+        }
+
+        self.done = true;
+        Poll::Ready(self.trailers.take())
+    }
+}
+
+type Shared<T> = Arc<Mutex<Encoded<T>>>;
+
+struct IoStream<T>(Shared<T>);
+
+impl<T: HttpBody<Data = Bytes, Error = Error> + Unpin> Stream for IoStream<T> {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.lock().expect("Failed to lock body").poll_data(cx)
     }
 }
 
@@ -50,7 +94,7 @@ fn decode(
 
 enum Decoder<T> {
     Body(T),
-    Decoder(Box<dyn AsyncRead + Send + Sync + Unpin>),
+    Decoder(Box<dyn AsyncRead + Send + Sync + Unpin>, Shared<T>),
 }
 
 impl Decoder<Body> {
@@ -59,10 +103,20 @@ impl Decoder<Body> {
             return Ok(self);
         }
 
-        Ok(Self::Decoder(match self {
-            Self::Body(body) => decode(encoding, StreamReader::new(IoStream(body))),
-            Self::Decoder(decoder) => decode(encoding, BufReader::new(decoder)),
-        }?))
+        Ok(match self {
+            Self::Body(body) => {
+                let encoded = Arc::new(Mutex::new(Encoded {
+                    body,
+                    trailers: None,
+                    done: false,
+                }));
+                let reader = StreamReader::new(IoStream(Arc::clone(&encoded)));
+                Self::Decoder(decode(encoding, reader)?, encoded)
+            }
+            Self::Decoder(decoder, encoded) => {
+                Self::Decoder(decode(encoding, BufReader::new(decoder))?, encoded)
+            }
+        })
     }
 }
 
@@ -70,7 +124,20 @@ impl From<Decoder<Body>> for Body {
     fn from(decoder: Decoder<Body>) -> Body {
         match decoder {
             Decoder::Body(body) => body,
-            Decoder::Decoder(decoder) => Body::wrap_stream(ReaderStream::new(decoder)),
+            Decoder::Decoder(decoder, encoded) => {
+                let data = ReaderStream::new(decoder).map(|res| res.map(Frame::data));
+                let trailers = stream::poll_fn(move |cx| {
+                    encoded
+                        .lock()
+                        .expect("Failed to lock body")
+                        .poll_trailers(cx)
+                        .map(|trailers| trailers.map(Frame::trailers).map(Ok))
+                });
+
+                Body::from(StreamBody::new(
+                    data.chain(trailers).map(|res| res.map_err(Error::from)),
+                ))
+            }
         }
     }
 }
@@ -202,10 +269,195 @@ pub fn decode_response(mut res: Response<Body>) -> Result<Response<Body>, Error>
     };
 
     parts.headers.remove(CONTENT_ENCODING);
+    parts.extensions.insert(Decoded);
 
     Ok(Response::from_parts(parts, body))
 }
 
+/// Marks a response whose body was decoded by [`decode_response`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Decoded;
+
+/// The compression level used by [`encode_response`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum CompressionLevel {
+    /// The fastest level, which usually produces the largest bodies.
+    Fastest,
+    /// The best level, which usually produces the smallest bodies.
+    Best,
+    /// The default level of each encoding.
+    #[default]
+    Default,
+    /// A level specific to each encoding, which is clamped to the levels that it supports.
+    Precise(i32),
+}
+
+impl From<CompressionLevel> for Level {
+    fn from(level: CompressionLevel) -> Self {
+        match level {
+            CompressionLevel::Fastest => Level::Fastest,
+            CompressionLevel::Best => Level::Best,
+            CompressionLevel::Default => Level::Default,
+            CompressionLevel::Precise(level) => Level::Precise(level),
+        }
+    }
+}
+
+/// Supported encodings, in order of preference when a client accepts several equally.
+const ENCODINGS: [&str; 4] = ["zstd", "br", "gzip", "deflate"];
+
+/// Choose the supported encoding that an `Accept-Encoding` header value prefers.
+fn negotiate(accept_encoding: &HeaderValue) -> Option<&'static str> {
+    let mut weights = [None; ENCODINGS.len()];
+    let mut wildcard = None;
+
+    for item in accept_encoding.as_bytes().split_str(b",") {
+        let mut params = item.split_str(b";");
+        let coding = params.next().unwrap_or_default().trim();
+
+        let weight = params
+            .filter_map(|param| param.trim().strip_prefix(b"q="))
+            .find_map(|q| q.to_str().ok()?.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if coding == b"*" {
+            wildcard = Some(weight);
+        } else if let Some(i) = ENCODINGS
+            .iter()
+            .position(|e| coding.eq_ignore_ascii_case(e.as_bytes()))
+        {
+            weights[i] = Some(weight);
+        } else if coding.eq_ignore_ascii_case(b"x-gzip") {
+            weights[2] = weights[2].or(Some(weight));
+        }
+    }
+
+    let mut best: Option<(&'static str, f32)> = None;
+
+    for (encoding, weight) in ENCODINGS.iter().zip(weights) {
+        let Some(weight) = weight.or(wildcard) else {
+            continue;
+        };
+
+        if weight > 0.0 && best.map_or(true, |(_, best)| weight > best) {
+            best = Some((encoding, weight));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn encode_body(encoding: &str, level: Level, body: Body) -> Body {
+    let encoded = Arc::new(Mutex::new(Encoded {
+        body,
+        trailers: None,
+        done: false,
+    }));
+    let reader = StreamReader::new(IoStream(Arc::clone(&encoded)));
+
+    let encoder: Box<dyn AsyncRead + Send + Sync + Unpin> = match encoding {
+        "zstd" => Box::new(ZstdEncoder::with_quality(reader, level)),
+        "br" => Box::new(BrotliEncoder::with_quality(reader, level)),
+        "gzip" => Box::new(GzipEncoder::with_quality(reader, level)),
+        _ => Box::new(ZlibEncoder::with_quality(reader, level)),
+    };
+
+    Decoder::Decoder(encoder, encoded).into()
+}
+
+/// Encode the body of a response with the encoding that the client prefers.
+///
+/// The encoding is chosen from the value of the client's `Accept-Encoding` header, and the
+/// `Content-Encoding`, `Content-Length` and `Vary` headers are updated to match the encoded body.
+/// The response is returned unchanged if it is already encoded, if it can not have a body, if it
+/// is a partial response, or if the client does not accept any of the supported encodings.
+///
+/// Responses to `HEAD` requests should not be encoded, as they do not have a body.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     decode_response, encode_response,
+///     hyper::{header::ACCEPT_ENCODING, Request, Response},
+///     Body, CompressionLevel, HttpContext, HttpHandler, RequestOrResponse,
+/// };
+///
+/// #[derive(Clone, Default)]
+/// pub struct MyHandler {
+///     accept_encoding: Option<hudsucker::hyper::header::HeaderValue>,
+/// }
+///
+/// impl HttpHandler for MyHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         self.accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+///         req.into()
+///     }
+///
+///     async fn handle_response(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         res: Response<Body>,
+///     ) -> Response<Body> {
+///         let res = decode_response(res).unwrap();
+///
+///         // Modify the response
+///
+///         encode_response(res, self.accept_encoding.as_ref(), CompressionLevel::Fastest)
+///     }
+/// }
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+pub fn encode_response(
+    res: Response<Body>,
+    accept_encoding: Option<&HeaderValue>,
+    level: CompressionLevel,
+) -> Response<Body> {
+    let status = res.status();
+
+    if res.headers().contains_key(CONTENT_ENCODING)
+        || res.headers().contains_key(CONTENT_RANGE)
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || res.body().size_hint().exact() == Some(0)
+    {
+        return res;
+    }
+
+    let Some(encoding) = accept_encoding.and_then(negotiate) else {
+        return res;
+    };
+
+    let (mut parts, body) = res.into_parts();
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+
+    let varies = parts.headers.get_all(VARY).iter().any(|value| {
+        value
+            .as_bytes()
+            .split_str(b",")
+            .any(|v| v.trim() == b"*" || v.trim().eq_ignore_ascii_case(b"accept-encoding"))
+    });
+
+    if !varies {
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    let body = encode_body(encoding, level.into(), body);
+
+    Response::from_parts(parts, body)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -279,7 +531,7 @@ mod tests {
     mod decode_body {
         use super::*;
         use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
-        use http_body_util::Empty;
+        use http_body_util::{BodyExt, Empty};
 
         #[tokio::test]
         async fn no_encodings() {
@@ -328,6 +580,28 @@ mod tests {
             );
         }
 
+        #[tokio::test]
+        async fn preserves_trailers() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+            let body = {
+                let trailers = trailers.clone();
+                Body::wrap_stream(ReaderStream::new(encoder)).map_trailers(|_| Some(trailers))
+            };
+
+            let collected = decode_body(vec![&b"gzip"[..]], body)
+                .unwrap()
+                .collect()
+                .await
+                .unwrap();
+
+            assert_eq!(collected.trailers(), Some(&trailers));
+            assert_eq!(&collected.to_bytes()[..], content);
+        }
+
         #[test]
         fn invalid_encoding() {
             let body = Body::from(Empty::<Bytes>::new());
@@ -358,6 +632,87 @@ mod tests {
         }
     }
 
+    mod negotiate {
+        use super::*;
+
+        #[test]
+        fn prefers_highest_weight() {
+            let accept = HeaderValue::from_static("gzip;q=0.8, br;q=0.9, identity");
+            assert_eq!(negotiate(&accept), Some("br"));
+        }
+
+        #[test]
+        fn breaks_ties_by_preference() {
+            let accept = HeaderValue::from_static("gzip, deflate, br, zstd");
+            assert_eq!(negotiate(&accept), Some("zstd"));
+        }
+
+        #[test]
+        fn respects_wildcard_and_rejections() {
+            let accept = HeaderValue::from_static("*, zstd;q=0, br;q=0");
+            assert_eq!(negotiate(&accept), Some("gzip"));
+
+            let accept = HeaderValue::from_static("identity, *;q=0");
+            assert_eq!(negotiate(&accept), None);
+        }
+    }
+
+    mod encode_response {
+        use super::*;
+        use http_body_util::BodyExt;
+
+        #[tokio::test]
+        async fn round_trips() {
+            let content = b"hello, world";
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, content.len())
+                .body(Body::from(&content[..]).map_trailers(|_| {
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("x-trailer", HeaderValue::from_static("value"));
+                    Some(trailers)
+                }))
+                .unwrap();
+
+            let res = encode_response(
+                res,
+                Some(&HeaderValue::from_static("gzip")),
+                CompressionLevel::Best,
+            );
+
+            assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+            assert_eq!(res.headers()[VARY], "accept-encoding");
+            assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+            let res = decode_response(res).unwrap();
+            let collected = res.into_body().collect().await.unwrap();
+            assert_eq!(collected.trailers().unwrap()["x-trailer"], "value");
+            assert_eq!(&collected.to_bytes()[..], content);
+        }
+
+        #[test]
+        fn skips_unencodable_responses() {
+            let accept = HeaderValue::from_static("gzip");
+
+            let res = Response::builder()
+                .header(CONTENT_ENCODING, "br")
+                .body(Body::from("hello"))
+                .unwrap();
+            let res = encode_response(res, Some(&accept), CompressionLevel::Default);
+            assert_eq!(res.headers()[CONTENT_ENCODING], "br");
+
+            let res = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::from("hello"))
+                .unwrap();
+            let res = encode_response(res, Some(&accept), CompressionLevel::Default);
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+
+            let res = Response::new(Body::from("hello"));
+            let res = encode_response(res, None, CompressionLevel::Default);
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        }
+    }
+
     mod decode_response {
         use super::*;
         use async_compression::tokio::bufread::GzipEncoder;
@@ -0,0 +1,449 @@
+//! Structured diffs of responses.
+//!
+//! [`Differ`] compares two responses, such as a recorded response and the response of a backend
+//! that the recorded request is replayed against. Statuses are compared, then headers other than
+//! volatile ones such as `Date`, then bodies. JSON bodies are compared structurally, so formatting
+//! and the order of object keys do not matter. The resulting [`ResponseDiff`] can be serialized to
+//! JSON for regression-testing tools.
+//!
+//! Bodies are compared as they are, so encoded bodies should be decoded first, for example with
+//! `decode_response` when the `decoder` feature is enabled.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{diff::Differ, hyper::Response};
+//!
+//! let expected = Response::builder()
+//!     .header("date", "Mon, 01 Jan 2024 00:00:00 GMT")
+//!     .header("content-type", "application/json")
+//!     .body(r#"{"id": 1, "name": "hudsucker", "updated_at": 1700000000}"#)
+//!     .unwrap();
+//! let actual = Response::builder()
+//!     .header("date", "Tue, 02 Jan 2024 00:00:00 GMT")
+//!     .header("content-type", "application/json")
+//!     .body(r#"{"updated_at":1700000100,"name":"Hudsucker","id":1}"#)
+//!     .unwrap();
+//!
+//! let diff = Differ::new()
+//!     .ignore_json_pointer("/updated_at")
+//!     .diff(&expected, &actual);
+//!
+//! assert_eq!(
+//!     diff.to_json(),
+//!     r#"[{"kind":"json","pointer":"/name","expected":"hudsucker","actual":"Hudsucker"}]"#
+//! );
+//! ```
+
+use hyper::{
+    header::{HeaderName, CONTENT_TYPE},
+    HeaderMap, Response,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+
+/// The headers that are ignored by default, because their values usually change between
+/// otherwise identical responses.
+const VOLATILE_HEADERS: &[&str] = &[
+    "age",
+    "cf-ray",
+    "content-length",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "server-timing",
+    "transfer-encoding",
+    "via",
+    "x-amz-cf-id",
+    "x-amzn-requestid",
+    "x-amzn-trace-id",
+    "x-cache",
+    "x-correlation-id",
+    "x-request-id",
+    "x-served-by",
+    "x-timer",
+];
+
+/// A difference between two responses.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Change {
+    /// The statuses differ.
+    Status {
+        /// The status of the expected response.
+        expected: u16,
+        /// The status of the actual response.
+        actual: u16,
+    },
+    /// The values of a header differ. Headers that are missing have no values.
+    Header {
+        /// The name of the header.
+        name: String,
+        /// The values of the header in the expected response.
+        expected: Vec<String>,
+        /// The values of the header in the actual response.
+        actual: Vec<String>,
+    },
+    /// A value in the JSON bodies differs. Values that are missing are `None`.
+    Json {
+        /// The JSON pointer of the value, such as `/items/0/name`.
+        pointer: String,
+        /// The value in the expected body.
+        expected: Option<Value>,
+        /// The value in the actual body.
+        actual: Option<Value>,
+    },
+    /// The bodies differ, and are not both JSON.
+    Body {
+        /// The offset of the first byte that differs.
+        offset: usize,
+        /// The length of the expected body.
+        expected_len: usize,
+        /// The length of the actual body.
+        actual_len: usize,
+    },
+}
+
+/// The differences between two responses.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct ResponseDiff {
+    changes: Vec<Change>,
+}
+
+impl ResponseDiff {
+    /// The differences, with the status first, then headers by name, then the body.
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Whether the responses are the same.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The differences as a JSON array.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize diff")
+    }
+}
+
+/// Compares responses, ignoring volatile headers and values.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Debug)]
+pub struct Differ {
+    ignored_headers: HashSet<HeaderName>,
+    ignored_pointers: Vec<String>,
+}
+
+impl Default for Differ {
+    fn default() -> Self {
+        Self {
+            ignored_headers: VOLATILE_HEADERS
+                .iter()
+                .map(|name| HeaderName::from_static(name))
+                .collect(),
+            ignored_pointers: Vec::new(),
+        }
+    }
+}
+
+impl Differ {
+    /// Creates a new differ that ignores common volatile headers, such as `Date`, `Age`, `ETag`,
+    /// `Content-Length`, and request IDs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore a header.
+    pub fn ignore_header(mut self, name: HeaderName) -> Self {
+        self.ignored_headers.insert(name);
+        self
+    }
+
+    /// Compare a header, even if it is ignored by default.
+    pub fn compare_header(mut self, name: HeaderName) -> Self {
+        self.ignored_headers.remove(&name);
+        self
+    }
+
+    /// Ignore a value in JSON bodies, and everything inside it, by its JSON pointer, such as
+    /// `/meta/timestamp`. A `*` segment matches any key or index, as in `/items/*/id`.
+    pub fn ignore_json_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.ignored_pointers.push(pointer.into());
+        self
+    }
+
+    /// Compares two responses.
+    pub fn diff<A, B>(&self, expected: &Response<A>, actual: &Response<B>) -> ResponseDiff
+    where
+        A: AsRef<[u8]>,
+        B: AsRef<[u8]>,
+    {
+        let mut changes = Vec::new();
+
+        if expected.status() != actual.status() {
+            changes.push(Change::Status {
+                expected: expected.status().as_u16(),
+                actual: actual.status().as_u16(),
+            });
+        }
+
+        self.diff_headers(expected.headers(), actual.headers(), &mut changes);
+
+        let (expected_body, actual_body) = (expected.body().as_ref(), actual.body().as_ref());
+
+        match (
+            json(expected.headers(), expected_body),
+            json(actual.headers(), actual_body),
+        ) {
+            (Some(expected), Some(actual)) => {
+                self.diff_json(&mut String::new(), &expected, &actual, &mut changes)
+            }
+            _ if expected_body != actual_body => changes.push(Change::Body {
+                offset: expected_body
+                    .iter()
+                    .zip(actual_body)
+                    .take_while(|(a, b)| a == b)
+                    .count(),
+                expected_len: expected_body.len(),
+                actual_len: actual_body.len(),
+            }),
+            _ => {}
+        }
+
+        ResponseDiff { changes }
+    }
+
+    fn diff_headers(&self, expected: &HeaderMap, actual: &HeaderMap, changes: &mut Vec<Change>) {
+        let names: BTreeSet<&str> = expected
+            .keys()
+            .chain(actual.keys())
+            .filter(|name| !self.ignored_headers.contains(*name))
+            .map(HeaderName::as_str)
+            .collect();
+
+        for name in names {
+            let values = |headers: &HeaderMap| {
+                let mut values: Vec<String> = headers
+                    .get_all(name)
+                    .iter()
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                    .collect();
+                values.sort();
+                values
+            };
+
+            let (expected, actual) = (values(expected), values(actual));
+
+            if expected != actual {
+                changes.push(Change::Header {
+                    name: name.to_owned(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+
+    fn is_ignored(&self, pointer: &str) -> bool {
+        self.ignored_pointers.iter().any(|ignored| {
+            let mut segments = pointer.split('/');
+
+            ignored.split('/').all(|ignored| {
+                segments
+                    .next()
+                    .is_some_and(|s| ignored == "*" || ignored == s)
+            })
+        })
+    }
+
+    fn diff_json(
+        &self,
+        pointer: &mut String,
+        expected: &Value,
+        actual: &Value,
+        changes: &mut Vec<Change>,
+    ) {
+        if self.is_ignored(pointer) {
+            return;
+        }
+
+        match (expected, actual) {
+            (Value::Object(expected), Value::Object(actual)) => {
+                let keys: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+
+                for key in keys {
+                    self.diff_child(
+                        pointer,
+                        &key.replace('~', "~0").replace('/', "~1"),
+                        expected.get(key),
+                        actual.get(key),
+                        changes,
+                    );
+                }
+            }
+            (Value::Array(expected), Value::Array(actual)) => {
+                for i in 0..expected.len().max(actual.len()) {
+                    self.diff_child(
+                        pointer,
+                        &i.to_string(),
+                        expected.get(i),
+                        actual.get(i),
+                        changes,
+                    );
+                }
+            }
+            (Value::Number(a), Value::Number(b)) if a.as_f64() == b.as_f64() => {}
+            (expected, actual) if expected == actual => {}
+            (expected, actual) => changes.push(Change::Json {
+                pointer: pointer.clone(),
+                expected: Some(expected.clone()),
+                actual: Some(actual.clone()),
+            }),
+        }
+    }
+
+    fn diff_child(
+        &self,
+        pointer: &mut String,
+        segment: &str,
+        expected: Option<&Value>,
+        actual: Option<&Value>,
+        changes: &mut Vec<Change>,
+    ) {
+        let len = pointer.len();
+        pointer.push('/');
+        pointer.push_str(segment);
+
+        match (expected, actual) {
+            (Some(expected), Some(actual)) => self.diff_json(pointer, expected, actual, changes),
+            (expected, actual) if !self.is_ignored(pointer) => changes.push(Change::Json {
+                pointer: pointer.clone(),
+                expected: expected.cloned(),
+                actual: actual.cloned(),
+            }),
+            _ => {}
+        }
+
+        pointer.truncate(len);
+    }
+}
+
+/// The JSON value of a body, if the response has a JSON content type or the body is JSON.
+fn json(headers: &HeaderMap, body: &[u8]) -> Option<Value> {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let mime = value.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+        });
+
+    if is_json || body.first().is_some_and(|b| matches!(b, b'{' | b'[')) {
+        serde_json::from_slice(body).ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    fn response(
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &'static str,
+    ) -> Response<&'static str> {
+        let mut res = Response::new(body);
+        *res.status_mut() = StatusCode::from_u16(status).unwrap();
+
+        for (name, value) in headers {
+            res.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+
+        res
+    }
+
+    #[test]
+    fn ignores_volatile_headers() {
+        let expected = response(200, &[("date", "a"), ("x-version", "1")], "body");
+        let actual = response(200, &[("date", "b"), ("x-version", "1")], "body");
+
+        assert!(Differ::new().diff(&expected, &actual).is_empty());
+        assert_eq!(
+            Differ::new()
+                .compare_header(HeaderName::from_static("date"))
+                .diff(&expected, &actual)
+                .changes(),
+            [Change::Header {
+                name: "date".to_owned(),
+                expected: vec!["a".to_owned()],
+                actual: vec!["b".to_owned()],
+            }]
+        );
+    }
+
+    #[test]
+    fn diffs_statuses_headers_and_bodies() {
+        let expected = response(200, &[("x-a", "1"), ("x-b", "1")], "hello world");
+        let actual = response(500, &[("x-b", "2"), ("x-b", "1")], "hello there");
+
+        assert_eq!(
+            Differ::new().diff(&expected, &actual).to_json(),
+            concat!(
+                r#"[{"kind":"status","expected":200,"actual":500},"#,
+                r#"{"kind":"header","name":"x-a","expected":["1"],"actual":[]},"#,
+                r#"{"kind":"header","name":"x-b","expected":["1"],"actual":["1","2"]},"#,
+                r#"{"kind":"body","offset":6,"expected_len":11,"actual_len":11}]"#
+            )
+        );
+    }
+
+    #[test]
+    fn diffs_json_structurally() {
+        let expected = response(
+            200,
+            &[],
+            r#"{"items": [{"id": 1, "at": 5}, {"id": 2.0, "at": 6}], "a/b": true, "gone": 1}"#,
+        );
+        let actual = response(
+            200,
+            &[],
+            r#"{"a/b": false, "items": [{"id": 1, "at": 7}, {"id": 2, "at": 8}, {"id": 3}]}"#,
+        );
+
+        assert_eq!(
+            Differ::new()
+                .ignore_json_pointer("/items/*/at")
+                .diff(&expected, &actual)
+                .changes(),
+            [
+                Change::Json {
+                    pointer: "/a~1b".to_owned(),
+                    expected: Some(Value::Bool(true)),
+                    actual: Some(Value::Bool(false)),
+                },
+                Change::Json {
+                    pointer: "/gone".to_owned(),
+                    expected: Some(1.into()),
+                    actual: None,
+                },
+                Change::Json {
+                    pointer: "/items/2".to_owned(),
+                    expected: None,
+                    actual: Some(serde_json::json!({"id": 3})),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,869 @@
+//! DNS-over-HTTPS and DNS-over-TLS interception.
+//!
+//! The proxy recognizes DNS-over-HTTPS (DoH) requests and responses, which have an
+//! `application/dns-message` body or a `dns` query parameter, and the messages sent over
+//! intercepted DNS-over-TLS (DoT) tunnels, which are `CONNECT` requests to port 853. Each DNS
+//! message is decoded into a [`Message`] and passed to [`HttpHandler::handle_dns_query`] or
+//! [`HttpHandler::handle_dns_response`], which can rewrite it before it is forwarded. Messages
+//! that can not be decoded are forwarded unchanged.
+//!
+//! DoT tunnels are forwarded to the server over TLS, which is verified with the roots set with
+//! [`ProxyBuilder::with_dns_tls_config`](crate::ProxyBuilder::with_dns_tls_config), or with the
+//! Mozilla root certificates by default.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     dns::{self, Message, Record},
+//!     HttpContext, HttpHandler,
+//! };
+//! use std::net::Ipv4Addr;
+//!
+//! #[derive(Clone)]
+//! struct Redirect;
+//!
+//! impl HttpHandler for Redirect {
+//!     async fn handle_dns_response(&mut self, _ctx: &HttpContext, mut res: Message) -> Message {
+//!         for answer in &mut res.answers {
+//!             if answer.kind == dns::kind::A && answer.name.eq_ignore_ascii_case("example.com") {
+//!                 *answer = Record::a(answer.name.clone(), answer.ttl, Ipv4Addr::LOCALHOST);
+//!             }
+//!         }
+//!
+//!         res
+//!     }
+//! }
+//! ```
+
+use crate::{Body, Bounded, Error, HttpContext, HttpHandler};
+use http_body_util::{Empty, Full};
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING,
+    },
+    http::{request, response},
+    Method, Request, Response, Uri,
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, OnceLock},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tracing::warn;
+
+/// The largest DNS message, which is also the largest body that is read from a DoH message.
+pub(crate) const MAX_LEN: usize = u16::MAX as usize;
+
+/// The `IN` (internet) class.
+pub const CLASS_IN: u16 = 1;
+
+/// Common record types.
+pub mod kind {
+    /// An IPv4 address.
+    pub const A: u16 = 1;
+    /// An authoritative name server.
+    pub const NS: u16 = 2;
+    /// The canonical name for an alias.
+    pub const CNAME: u16 = 5;
+    /// The start of a zone of authority.
+    pub const SOA: u16 = 6;
+    /// A domain name pointer.
+    pub const PTR: u16 = 12;
+    /// A mail exchange.
+    pub const MX: u16 = 15;
+    /// Text strings.
+    pub const TXT: u16 = 16;
+    /// An IPv6 address.
+    pub const AAAA: u16 = 28;
+    /// A service location.
+    pub const SRV: u16 = 33;
+    /// A redirection of a subtree of the domain name tree.
+    pub const DNAME: u16 = 39;
+    /// An EDNS pseudo-record.
+    pub const OPT: u16 = 41;
+    /// HTTPS service binding.
+    pub const HTTPS: u16 = 65;
+}
+
+/// A DNS query or response.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Message {
+    /// The ID that is used to match a response to its query.
+    pub id: u16,
+    /// The flags, opcode and response code of the message.
+    pub flags: u16,
+    /// The question section.
+    pub questions: Vec<Question>,
+    /// The answer section.
+    pub answers: Vec<Record>,
+    /// The authority section.
+    pub authorities: Vec<Record>,
+    /// The additional section.
+    pub additionals: Vec<Record>,
+}
+
+/// A question in a DNS message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Question {
+    /// The name that is queried, without a trailing dot.
+    pub name: String,
+    /// The record type that is queried.
+    pub kind: u16,
+    /// The class that is queried.
+    pub class: u16,
+}
+
+/// A resource record in a DNS message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// The name that the record belongs to, without a trailing dot.
+    pub name: String,
+    /// The type of the record.
+    pub kind: u16,
+    /// The class of the record.
+    pub class: u16,
+    /// The number of seconds that the record may be cached for.
+    pub ttl: u32,
+    /// The data of the record.
+    ///
+    /// Any names in the data of the standard record types that may be compressed are expanded, so
+    /// the data does not depend on the rest of the message.
+    pub data: Bytes,
+}
+
+impl Record {
+    /// Creates an `A` record.
+    pub fn a(name: impl Into<String>, ttl: u32, addr: Ipv4Addr) -> Self {
+        Self {
+            name: name.into(),
+            kind: kind::A,
+            class: CLASS_IN,
+            ttl,
+            data: Bytes::copy_from_slice(&addr.octets()),
+        }
+    }
+
+    /// Creates an `AAAA` record.
+    pub fn aaaa(name: impl Into<String>, ttl: u32, addr: Ipv6Addr) -> Self {
+        Self {
+            name: name.into(),
+            kind: kind::AAAA,
+            class: CLASS_IN,
+            ttl,
+            data: Bytes::copy_from_slice(&addr.octets()),
+        }
+    }
+
+    /// The address of an `A` or `AAAA` record.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.kind {
+            kind::A => <[u8; 4]>::try_from(&self.data[..]).ok().map(IpAddr::from),
+            kind::AAAA => <[u8; 16]>::try_from(&self.data[..]).ok().map(IpAddr::from),
+            _ => None,
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+
+        loop {
+            let len = usize::from(*self.data.get(pos)?);
+
+            match len & 0xc0 {
+                0xc0 => {
+                    let ptr = (len & 0x3f) << 8 | usize::from(*self.data.get(pos + 1)?);
+
+                    // Pointers must point backwards, which also prevents loops.
+                    if ptr >= pos {
+                        return None;
+                    }
+
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+
+                    pos = ptr;
+                }
+                0 if len == 0 => break,
+                0 => {
+                    let label = self.data.get(pos + 1..pos + 1 + len)?;
+
+                    if !label.is_ascii() || label.contains(&b'.') {
+                        return None;
+                    }
+
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+
+                    name.push_str(std::str::from_utf8(label).ok()?);
+                    pos += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+
+        if !jumped {
+            self.pos = pos + 1;
+        }
+
+        Some(name)
+    }
+
+    fn question(&mut self) -> Option<Question> {
+        Some(Question {
+            name: self.name()?,
+            kind: self.u16()?,
+            class: self.u16()?,
+        })
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = usize::from(self.u16()?);
+        let end = self.pos + len;
+
+        if end > self.data.len() {
+            return None;
+        }
+
+        let mut data = Vec::new();
+
+        match kind {
+            kind::NS | kind::CNAME | kind::PTR | kind::DNAME => {
+                write_name(&mut data, &self.name()?);
+            }
+            kind::MX => {
+                data.extend_from_slice(self.bytes(2)?);
+                write_name(&mut data, &self.name()?);
+            }
+            kind::SOA => {
+                write_name(&mut data, &self.name()?);
+                write_name(&mut data, &self.name()?);
+                data.extend_from_slice(self.bytes(20)?);
+            }
+            _ => data.extend_from_slice(self.bytes(len)?),
+        }
+
+        if self.pos != end {
+            return None;
+        }
+
+        Some(Record {
+            name,
+            kind,
+            class,
+            ttl,
+            data: Bytes::from(data),
+        })
+    }
+
+    fn records(&mut self, count: u16) -> Option<Vec<Record>> {
+        (0..count).map(|_| self.record()).collect()
+    }
+
+    fn message(&mut self) -> Option<Message> {
+        let id = self.u16()?;
+        let flags = self.u16()?;
+        let counts = [self.u16()?, self.u16()?, self.u16()?, self.u16()?];
+
+        let questions = (0..counts[0])
+            .map(|_| self.question())
+            .collect::<Option<_>>()?;
+        let answers = self.records(counts[1])?;
+        let authorities = self.records(counts[2])?;
+        let additionals = self.records(counts[3])?;
+
+        if self.pos != self.data.len() {
+            return None;
+        }
+
+        Some(Message {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+}
+
+/// Writes a name without compression.
+///
+/// # Panics
+///
+/// Panics if a label is longer than 63 bytes.
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|len| *len <= 63)
+            .expect("DNS label is longer than 63 bytes");
+
+        buf.push(len);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+}
+
+fn write_count(buf: &mut Vec<u8>, count: usize) {
+    let count = u16::try_from(count).expect("Too many entries in DNS message section");
+    buf.extend_from_slice(&count.to_be_bytes());
+}
+
+impl Message {
+    /// Decodes a message from its wire format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message is malformed, has data after its last record, or has a
+    /// name that is not ASCII.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        Reader { data, pos: 0 }.message().ok_or(Error::Dns)
+    }
+
+    /// Encodes the message into its wire format, without compressing names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a name has a label that is longer than 63 bytes, or a section has more than
+    /// 65535 entries.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        write_count(&mut buf, self.questions.len());
+        write_count(&mut buf, self.answers.len());
+        write_count(&mut buf, self.authorities.len());
+        write_count(&mut buf, self.additionals.len());
+
+        for question in &self.questions {
+            write_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.kind.to_be_bytes());
+            buf.extend_from_slice(&question.class.to_be_bytes());
+        }
+
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            let len = u16::try_from(record.data.len()).expect("DNS record data is too large");
+
+            write_name(&mut buf, &record.name);
+            buf.extend_from_slice(&record.kind.to_be_bytes());
+            buf.extend_from_slice(&record.class.to_be_bytes());
+            buf.extend_from_slice(&record.ttl.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(&record.data);
+        }
+
+        Bytes::from(buf)
+    }
+
+    /// Whether the message is a response.
+    pub fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    /// The response code of the message.
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+}
+
+/// The result of [`from_request`] or [`from_response`].
+#[derive(Debug)]
+pub enum Parsed<T, P> {
+    /// The message was a DoH message.
+    Dns {
+        /// The HTTP message, without its body.
+        parts: P,
+        /// The DNS message.
+        message: Message,
+    },
+    /// The message was not a DoH message, its body exceeded the limit, had a `Content-Encoding`,
+    /// or could not be decoded.
+    ///
+    /// The message yields the same body as the original message.
+    Unchanged(T),
+}
+
+/// Whether the `Content-Type` header is `application/dns-message`.
+pub fn is_dns_message(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/dns-message"))
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn encode_base64url(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+
+        for i in 0..=chunk.len() {
+            encoded.push(char::from(BASE64URL[(n >> (18 - 6 * i) & 0x3f) as usize]));
+        }
+    }
+
+    encoded
+}
+
+fn decode_base64url(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for c in data.bytes() {
+        n = n << 6 | BASE64URL.iter().position(|b| *b == c)? as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+/// The value of the `dns` query parameter of a DoH `GET` request.
+fn query_param(uri: &Uri) -> Option<&str> {
+    uri.query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix("dns="))
+}
+
+fn with_query_param(uri: Uri, value: &str) -> Uri {
+    let query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|param| match param.strip_prefix("dns=") {
+            Some(_) => format!("dns={}", value),
+            None => param.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let path_and_query = format!("{}?{}", uri.path(), query);
+    let mut parts = uri.into_parts();
+    parts.path_and_query = Some(path_and_query.parse().expect("Failed to build URI"));
+    Uri::from_parts(parts).expect("Failed to build URI")
+}
+
+/// Reads a DoH body, returning `Err` with a body that yields the original data if it is not
+/// used.
+async fn read(headers: &HeaderMap, body: Body, max: usize) -> Result<Result<Message, Body>, Error> {
+    if !is_dns_message(headers) || headers.contains_key(CONTENT_ENCODING) {
+        return Ok(Err(body));
+    }
+
+    let data = match body.collect_up_to(max).await? {
+        Bounded::Complete { data, .. } => data,
+        Bounded::TooLarge(body) => return Ok(Err(body)),
+    };
+
+    match Message::parse(&data) {
+        Ok(message) => Ok(Ok(message)),
+        Err(_) => Ok(Err(Body::from(Full::new(data)))),
+    }
+}
+
+fn encode(headers: &mut HeaderMap, message: &Message) -> Body {
+    let data = message.to_bytes();
+
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(data.len()));
+
+    Body::from(Full::new(data))
+}
+
+/// Reads the DNS query of a DoH request, from its `dns` query parameter if it is a `GET` request,
+/// or from its body if it is no larger than `max` bytes.
+///
+/// # Errors
+///
+/// Returns an error if the request body fails before it has been read.
+pub async fn from_request(
+    req: Request<Body>,
+    max: usize,
+) -> Result<Parsed<Request<Body>, request::Parts>, Error> {
+    if req.method() == Method::GET {
+        let message = query_param(req.uri())
+            .and_then(decode_base64url)
+            .and_then(|data| Message::parse(&data).ok());
+
+        return Ok(match message {
+            Some(message) => Parsed::Dns {
+                parts: req.into_parts().0,
+                message,
+            },
+            None => Parsed::Unchanged(req),
+        });
+    }
+
+    let (parts, body) = req.into_parts();
+
+    Ok(match read(&parts.headers, body, max).await? {
+        Ok(message) => Parsed::Dns { parts, message },
+        Err(body) => Parsed::Unchanged(Request::from_parts(parts, body)),
+    })
+}
+
+/// Reads the DNS response of a DoH response, if its body is no larger than `max` bytes.
+///
+/// # Errors
+///
+/// Returns an error if the response body fails before it has been read.
+pub async fn from_response(
+    res: Response<Body>,
+    max: usize,
+) -> Result<Parsed<Response<Body>, response::Parts>, Error> {
+    let (parts, body) = res.into_parts();
+
+    Ok(match read(&parts.headers, body, max).await? {
+        Ok(message) => Parsed::Dns { parts, message },
+        Err(body) => Parsed::Unchanged(Response::from_parts(parts, body)),
+    })
+}
+
+/// Creates a DoH request with a DNS query.
+///
+/// The query is set as the `dns` query parameter of a `GET` request, and as the body of other
+/// requests, with the `Content-Length` header set to its length.
+///
+/// # Panics
+///
+/// Panics if the message can not be encoded.
+pub fn into_request(mut parts: request::Parts, message: &Message) -> Request<Body> {
+    if parts.method == Method::GET {
+        parts.uri = with_query_param(parts.uri, &encode_base64url(&message.to_bytes()));
+        return Request::from_parts(parts, Empty::new().into());
+    }
+
+    let body = encode(&mut parts.headers, message);
+    Request::from_parts(parts, body)
+}
+
+/// Creates a DoH response with a DNS response.
+///
+/// The `Content-Length` header is set to the length of the message.
+///
+/// # Panics
+///
+/// Panics if the message can not be encoded.
+pub fn into_response(mut parts: response::Parts, message: &Message) -> Response<Body> {
+    let body = encode(&mut parts.headers, message);
+    Response::from_parts(parts, body)
+}
+
+/// Passes the query of a DoH request to the handler.
+pub(crate) async fn handle_request<H: HttpHandler>(
+    handler: &mut H,
+    ctx: &HttpContext,
+    req: Request<Body>,
+) -> Result<Request<Body>, Error> {
+    Ok(match from_request(req, MAX_LEN).await? {
+        Parsed::Dns { parts, message } => {
+            let message = handler.handle_dns_query(ctx, message).await;
+            into_request(parts, &message)
+        }
+        Parsed::Unchanged(req) => req,
+    })
+}
+
+/// Passes the DNS response of a DoH response to the handler.
+pub(crate) async fn handle_response<H: HttpHandler>(
+    handler: &mut H,
+    ctx: &HttpContext,
+    res: Response<Body>,
+) -> Result<Response<Body>, Error> {
+    Ok(match from_response(res, MAX_LEN).await? {
+        Parsed::Dns { parts, message } => {
+            let message = handler.handle_dns_response(ctx, message).await;
+            into_response(parts, &message)
+        }
+        Parsed::Unchanged(res) => res,
+    })
+}
+
+/// Forwards the length-prefixed messages of a DoT stream, passing each one to the handler.
+pub(crate) async fn forward<R, W, H>(
+    mut reader: R,
+    mut writer: W,
+    mut handler: H,
+    ctx: HttpContext,
+    queries: bool,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    H: HttpHandler,
+{
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+
+        let mut data = vec![0; usize::from(len)];
+        reader.read_exact(&mut data).await?;
+
+        let data = match Message::parse(&data) {
+            Ok(message) if queries => handler.handle_dns_query(&ctx, message).await.to_bytes(),
+            Ok(message) => handler.handle_dns_response(&ctx, message).await.to_bytes(),
+            Err(_) => Bytes::from(data),
+        };
+
+        let Ok(len) = u16::try_from(data.len()) else {
+            warn!("Dropping DNS message of {} bytes", data.len());
+            continue;
+        };
+
+        writer.write_u16(len).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+    }
+
+    writer.shutdown().await
+}
+
+/// The TLS configuration that is used to connect to DoT servers by default.
+pub(crate) fn default_tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+            Arc::new(
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    /// A response for `example.com` with a compressed `A` and `CNAME` answer.
+    const RESPONSE: &[u8] = b"\x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+        \x07example\x03com\x00\x00\x01\x00\x01\
+        \xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03www\xc0\x0c\
+        \xc0\x29\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x5d\xb8\xd8\x22";
+
+    fn query() -> Message {
+        Message {
+            id: 0xabcd,
+            flags: 0x0100,
+            questions: vec![Question {
+                name: "example.com".to_owned(),
+                kind: kind::A,
+                class: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_compressed_names() {
+        let message = Message::parse(RESPONSE).unwrap();
+
+        assert!(message.is_response());
+        assert_eq!(message.rcode(), 0);
+        assert_eq!(message.questions[0].name, "example.com");
+        assert_eq!(message.answers[0].name, "example.com");
+        assert_eq!(
+            &message.answers[0].data[..],
+            b"\x03www\x07example\x03com\x00"
+        );
+        assert_eq!(message.answers[1].name, "www.example.com");
+        assert_eq!(
+            message.answers[1].ip(),
+            Some(IpAddr::from([93, 184, 216, 34]))
+        );
+
+        assert_eq!(Message::parse(&message.to_bytes()).unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        for data in [
+            &RESPONSE[..RESPONSE.len() - 1],
+            &[RESPONSE, b"\x00"].concat(),
+            b"\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x01\x00\x01",
+        ] {
+            assert!(Message::parse(data).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn encodes_base64url() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"\xfb", "-w"),
+            (b"\xfb\xff", "-_8"),
+            (b"\xfb\xff\x00", "-_8A"),
+        ] {
+            assert_eq!(encode_base64url(data), encoded);
+            assert_eq!(decode_base64url(encoded).unwrap(), data);
+        }
+
+        assert_eq!(decode_base64url("invalid+"), None);
+    }
+
+    #[tokio::test]
+    async fn rewrites_get_requests() {
+        let uri = format!(
+            "https://dns.example/dns-query?a=1&dns={}&b=2",
+            encode_base64url(&query().to_bytes())
+        );
+        let req = Request::get(uri).body(Body::from("")).unwrap();
+
+        let Parsed::Dns { parts, mut message } = from_request(req, MAX_LEN).await.unwrap() else {
+            panic!("request was not parsed");
+        };
+        assert_eq!(message, query());
+
+        message.questions[0].name = "example.org".to_owned();
+
+        let req = into_request(parts, &message);
+        let query = req.uri().query().unwrap();
+        assert!(query.starts_with("a=1&dns=") && query.ends_with("&b=2"));
+        assert_eq!(
+            Message::parse(&decode_base64url(query_param(req.uri()).unwrap()).unwrap()).unwrap(),
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_post_requests() {
+        let req = Request::post("https://dns.example/dns-query")
+            .header(CONTENT_TYPE, "application/dns-message")
+            .body(Body::from(Full::new(query().to_bytes())))
+            .unwrap();
+
+        let Parsed::Dns { parts, message } = from_request(req, MAX_LEN).await.unwrap() else {
+            panic!("request was not parsed");
+        };
+        assert_eq!(message, query());
+
+        let req = into_request(parts, &message);
+        assert_eq!(req.headers()[CONTENT_LENGTH], "29");
+        assert_eq!(
+            req.into_body().collect().await.unwrap().to_bytes(),
+            query().to_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_unchanged() {
+        for (content_type, body) in [
+            ("text/plain", RESPONSE),
+            ("application/dns-message", &b"not dns"[..]),
+        ] {
+            let res = Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+
+            let Parsed::Unchanged(res) = from_response(res, MAX_LEN).await.unwrap() else {
+                panic!("response was parsed");
+            };
+            assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), body);
+        }
+    }
+
+    #[derive(Clone)]
+    struct Rename;
+
+    impl HttpHandler for Rename {
+        async fn handle_dns_query(&mut self, _ctx: &HttpContext, mut query: Message) -> Message {
+            query.questions[0].name = "example.org".to_owned();
+            query
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_dot_messages() {
+        let (mut client, proxy) = tokio::io::duplex(1024);
+        let (server, mut upstream) = tokio::io::duplex(1024);
+        let ctx = HttpContext {
+            client_addr: "127.0.0.1:8080".parse().unwrap(),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            upstream_version: None,
+        };
+
+        let forwarder = tokio::spawn(forward(proxy, server, Rename, ctx, true));
+
+        let data = query().to_bytes();
+        client.write_u16(data.len() as u16).await.unwrap();
+        client.write_all(&data).await.unwrap();
+        client.write_all(b"\x00\x03abc").await.unwrap();
+        drop(client);
+
+        let mut forwarded = Vec::new();
+        upstream.read_to_end(&mut forwarded).await.unwrap();
+        forwarder.await.unwrap().unwrap();
+
+        let len = usize::from(u16::from_be_bytes([forwarded[0], forwarded[1]]));
+        let message = Message::parse(&forwarded[2..2 + len]).unwrap();
+        assert_eq!(message.questions[0].name, "example.org");
+        assert_eq!(&forwarded[2 + len..], b"\x00\x03abc");
+    }
+}
@@ -13,6 +13,14 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("unable to decode body")]
     Decode,
+    #[error("malformed multipart body")]
+    Multipart,
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    #[error("malformed DNS message")]
+    Dns,
+    #[error("body exceeded size limit")]
+    BodyTooLarge,
     #[error("unknown error")]
     Unknown,
 }
@@ -0,0 +1,365 @@
+use super::{Event, EventSink};
+use std::{
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const PRODUCE: i16 = 0;
+const PRODUCE_VERSION: i16 = 3;
+
+/// An [`EventSink`] that produces events to a Kafka topic.
+///
+/// Each event is serialized as JSON and produced as the value of a record without a key. Batches
+/// of events are produced to a single partition of the topic, through the broker that leads it,
+/// and are acknowledged by the leader before the next batch is sent. The connection is opened when
+/// the first batch is sent, and again after it fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::events::{Events, KafkaSink};
+///
+/// # async fn run() {
+/// let events = Events::new(1024);
+/// events.forward(KafkaSink::new("127.0.0.1:9092", "hudsucker-events"));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KafkaSink {
+    addr: String,
+    topic: String,
+    partition: i32,
+    client_id: String,
+    timeout: Duration,
+    correlation_id: i32,
+    conn: Option<TcpStream>,
+}
+
+impl KafkaSink {
+    /// Creates a sink that produces events to `topic` through the broker at `addr`.
+    pub fn new(addr: impl Into<String>, topic: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            topic: topic.into(),
+            partition: 0,
+            client_id: "hudsucker".to_owned(),
+            timeout: Duration::from_secs(10),
+            correlation_id: 0,
+            conn: None,
+        }
+    }
+
+    /// Set the partition that events are produced to. Defaults to 0.
+    pub fn with_partition(mut self, partition: i32) -> Self {
+        self.partition = partition;
+        self
+    }
+
+    /// Set the client ID that is sent to the broker. Defaults to `hudsucker`.
+    pub fn with_client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = client_id.into();
+        self
+    }
+
+    /// Set the timeout for producing a batch of events. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn produce(&mut self, request: &[u8]) -> io::Result<()> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(TcpStream::connect(&self.addr).await?),
+        };
+
+        conn.write_all(request).await?;
+
+        let len = conn.read_i32().await?;
+        let mut response = vec![0; usize::try_from(len).map_err(io::Error::other)?];
+        conn.read_exact(&mut response).await?;
+
+        let mut response = Reader(&response);
+
+        if response.i32()? != self.correlation_id {
+            return Err(io::Error::other("mismatched correlation ID"));
+        }
+
+        for _ in 0..response.i32()? {
+            response.string()?;
+
+            for _ in 0..response.i32()? {
+                let partition = response.i32()?;
+                let error_code = response.i16()?;
+                response.skip(16)?;
+
+                if error_code != 0 {
+                    return Err(io::Error::other(format!(
+                        "broker returned error {} for partition {}",
+                        error_code, partition
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl EventSink for KafkaSink {
+    async fn send(&mut self, events: &[Event]) -> io::Result<()> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let values = events
+            .iter()
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<_>, _>>()?;
+        let request = produce_request(
+            self.correlation_id,
+            &self.client_id,
+            &self.topic,
+            self.partition,
+            self.timeout,
+            &record_batch(timestamp, &values),
+        );
+
+        let produced = timeout(self.timeout, self.produce(&request))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+        if produced.is_err() {
+            self.conn = None;
+        }
+
+        produced
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as i16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Appends a zigzag encoded variable length integer.
+fn put_varint(out: &mut Vec<u8>, n: i64) {
+    let mut n = ((n << 1) ^ (n >> 63)) as u64;
+
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+
+    out.push(n as u8);
+}
+
+/// The CRC-32C checksum of a record batch.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= u32::from(b);
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+/// Encodes a version 2 record batch, with one record for each value.
+fn record_batch(timestamp: i64, values: &[Vec<u8>]) -> Vec<u8> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+
+    for (i, value) in values.iter().enumerate() {
+        record.clear();
+        record.push(0);
+        put_varint(&mut record, 0);
+        put_varint(&mut record, i as i64);
+        put_varint(&mut record, -1);
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 0);
+
+        put_varint(&mut records, record.len() as i64);
+        records.extend_from_slice(&record);
+    }
+
+    // The part of the batch that the checksum covers, from the attributes to the records.
+    let mut checked = Vec::with_capacity(records.len() + 40);
+    checked.extend_from_slice(&0i16.to_be_bytes());
+    checked.extend_from_slice(&(values.len() as i32 - 1).to_be_bytes());
+    checked.extend_from_slice(&timestamp.to_be_bytes());
+    checked.extend_from_slice(&timestamp.to_be_bytes());
+    checked.extend_from_slice(&(-1i64).to_be_bytes());
+    checked.extend_from_slice(&(-1i16).to_be_bytes());
+    checked.extend_from_slice(&(-1i32).to_be_bytes());
+    checked.extend_from_slice(&(values.len() as i32).to_be_bytes());
+    checked.extend_from_slice(&records);
+
+    let mut batch = Vec::with_capacity(checked.len() + 21);
+    batch.extend_from_slice(&0i64.to_be_bytes());
+    batch.extend_from_slice(&(checked.len() as i32 + 9).to_be_bytes());
+    batch.extend_from_slice(&(-1i32).to_be_bytes());
+    batch.push(2);
+    batch.extend_from_slice(&crc32c(&checked).to_be_bytes());
+    batch.extend_from_slice(&checked);
+    batch
+}
+
+/// Encodes a produce request for a record batch, that is acknowledged by the partition leader.
+fn produce_request(
+    correlation_id: i32,
+    client_id: &str,
+    topic: &str,
+    partition: i32,
+    timeout: Duration,
+    batch: &[u8],
+) -> Vec<u8> {
+    let mut out = vec![0; 4];
+    out.extend_from_slice(&PRODUCE.to_be_bytes());
+    out.extend_from_slice(&PRODUCE_VERSION.to_be_bytes());
+    out.extend_from_slice(&correlation_id.to_be_bytes());
+    put_string(&mut out, client_id);
+
+    out.extend_from_slice(&(-1i16).to_be_bytes());
+    out.extend_from_slice(&1i16.to_be_bytes());
+    out.extend_from_slice(&(timeout.as_millis().min(i32::MAX as u128) as i32).to_be_bytes());
+    out.extend_from_slice(&1i32.to_be_bytes());
+    put_string(&mut out, topic);
+    out.extend_from_slice(&1i32.to_be_bytes());
+    out.extend_from_slice(&partition.to_be_bytes());
+    out.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+    out.extend_from_slice(batch);
+
+    let len = (out.len() - 4) as i32;
+    out[..4].copy_from_slice(&len.to_be_bytes());
+    out
+}
+
+/// Reads the fields of a response.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<()> {
+        let len = self.i16()?;
+        self.skip(len.max(0) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn computes_checksums() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn encodes_varints() {
+        let mut out = Vec::new();
+        put_varint(&mut out, 0);
+        put_varint(&mut out, -1);
+        put_varint(&mut out, 1);
+        put_varint(&mut out, 300);
+        assert_eq!(out, [0x00, 0x01, 0x02, 0xd8, 0x04]);
+    }
+
+    #[test]
+    fn encodes_record_batches() {
+        let batch = record_batch(1_000, &[b"a".to_vec(), b"bc".to_vec()]);
+
+        assert_eq!(
+            i32::from_be_bytes(batch[8..12].try_into().unwrap()) as usize,
+            batch.len() - 12
+        );
+        assert_eq!(batch[16], 2);
+        assert_eq!(
+            u32::from_be_bytes(batch[17..21].try_into().unwrap()),
+            crc32c(&batch[21..])
+        );
+        assert_eq!(&batch[57..61], 2i32.to_be_bytes());
+        assert_eq!(
+            &batch[61..],
+            b"\x0e\x00\x00\x00\x01\x02a\x00\x10\x00\x00\x02\x01\x04bc\x00"
+        );
+    }
+
+    #[tokio::test]
+    async fn produces_to_brokers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_i32().await.unwrap();
+            let mut request = vec![0; len as usize];
+            stream.read_exact(&mut request).await.unwrap();
+
+            let mut response = Vec::new();
+            response.extend_from_slice(&request[4..8]);
+            response.extend_from_slice(&1i32.to_be_bytes());
+            put_string(&mut response, "events");
+            response.extend_from_slice(&1i32.to_be_bytes());
+            response.extend_from_slice(&0i32.to_be_bytes());
+            response.extend_from_slice(&0i16.to_be_bytes());
+            response.extend_from_slice(&[0; 16]);
+            response.extend_from_slice(&0i32.to_be_bytes());
+
+            stream
+                .write_all(&(response.len() as i32).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+            request
+        });
+
+        let mut sink = KafkaSink::new(addr.to_string(), "events");
+        let event = Event::FlowCompleted {
+            flow_id: 1,
+            status: 200,
+            bytes: 5,
+            duration: 3,
+        };
+        sink.send(std::slice::from_ref(&event)).await.unwrap();
+
+        let request = broker.await.unwrap();
+        let value = serde_json::to_vec(&event).unwrap();
+        assert_eq!(&request[..4], [0, 0, 0, 3]);
+        assert!(request.windows(value.len()).any(|w| w == value));
+    }
+}
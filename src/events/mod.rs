@@ -0,0 +1,622 @@
+//! A live stream of proxy events.
+//!
+//! [`Events`] configured with [`ProxyBuilder::with_events`] broadcasts an [`Event`] when a flow
+//! starts, when the headers of its upstream response are received, as its bodies are streamed and
+//! when it completes, for each WebSocket message, and when a tunnel is closed. Events can be serialized, so that they can
+//! be forwarded to a web UI or rendered by a TUI without implementing any handlers.
+//!
+//! Events are only created while there are subscribers. A subscriber that falls behind by more
+//! than the capacity of the channel misses the oldest events, and is told how many it missed by
+//! [`Receiver::recv`](broadcast::Receiver::recv).
+//!
+//! Events can also be streamed to a pipeline with [`Events::forward`] and an [`EventSink`], such
+//! as the [`KafkaSink`] and [`NatsSink`] that are enabled by the `kafka` and `nats` features.
+//!
+//! [`ProxyBuilder::with_events`]: crate::builder::ProxyBuilder::with_events
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::events::{Event, Events};
+//!
+//! # async fn run() {
+//! let events = Events::new(1024);
+//! let mut rx = events.subscribe();
+//!
+//! // Pass `events` to `ProxyBuilder::with_events`...
+//!
+//! tokio::spawn(async move {
+//!     while let Ok(event) = rx.recv().await {
+//!         if let Event::FlowStarted { flow_id, uri, .. } = event {
+//!             println!("{} {}", flow_id, uri);
+//!         }
+//!     }
+//! });
+//! # }
+//! ```
+
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+#[cfg(feature = "kafka")]
+#[cfg_attr(docsrs, doc(cfg(feature = "kafka")))]
+pub use kafka::KafkaSink;
+#[cfg(feature = "nats")]
+#[cfg_attr(docsrs, doc(cfg(feature = "nats")))]
+pub use nats::NatsSink;
+
+use crate::{Body, BodyDirection, Error, WebSocketContext, WebSocketDirection};
+use futures::{Stream, StreamExt};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    HeaderMap, Request, Response,
+};
+use serde::Serialize;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError, error::TryRecvError},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::warn;
+
+/// An event that happened in the proxy.
+///
+/// Events are serialized as objects with a `type` field that is the name of the variant in
+/// snake case.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event {
+    /// A request was received from a client.
+    #[non_exhaustive]
+    FlowStarted {
+        /// ID of the flow, which is the same as [`HttpContext::flow_id`](crate::HttpContext::flow_id).
+        flow_id: u64,
+        /// Address of the client.
+        client_addr: SocketAddr,
+        /// When the request was received, in milliseconds since the Unix epoch.
+        timestamp: u64,
+        /// Method of the request.
+        method: String,
+        /// URI of the request.
+        uri: String,
+        /// Headers of the request, in order. Values that are not valid UTF-8 are replaced lossily.
+        headers: Vec<(String, String)>,
+    },
+    /// The headers of a response were received from the server, before the response was passed to
+    /// [`HttpHandler::handle_response`](crate::HttpHandler::handle_response).
+    #[non_exhaustive]
+    ResponseHeaders {
+        /// ID of the flow.
+        flow_id: u64,
+        /// Status of the response.
+        status: u16,
+        /// Headers of the response, in order.
+        headers: Vec<(String, String)>,
+    },
+    /// Part of a body was streamed, from the client for a request body, or to the client for a
+    /// response body.
+    #[non_exhaustive]
+    BodyProgress {
+        /// ID of the flow.
+        flow_id: u64,
+        /// Whether the body is the request or response body.
+        direction: BodyDirection,
+        /// The number of bytes of the body that have been streamed so far.
+        bytes: u64,
+        /// The chunk that was streamed, if [capturing bodies](Events::set_capture_bodies) is
+        /// enabled. Bytes that are not valid UTF-8 are replaced lossily.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+    },
+    /// The response of a flow was sent to the client, or the client stopped reading it.
+    #[non_exhaustive]
+    FlowCompleted {
+        /// ID of the flow.
+        flow_id: u64,
+        /// Status of the response that was sent to the client.
+        status: u16,
+        /// The number of response body bytes that were sent to the client.
+        bytes: u64,
+        /// The time from receiving the request to sending the end of the response, in
+        /// milliseconds.
+        duration: u64,
+    },
+    /// A WebSocket message was received, before it was passed to the
+    /// [`WebSocketHandler`](crate::WebSocketHandler).
+    #[non_exhaustive]
+    WebSocketMessage {
+        /// ID of the WebSocket.
+        socket_id: u64,
+        /// The direction that the message was sent in.
+        direction: WebSocketDirection,
+        /// The kind of message.
+        kind: MessageKind,
+        /// The length of the message payload, in bytes.
+        len: usize,
+        /// The payload of a text message.
+        text: Option<String>,
+    },
+    /// A tunnel that was not intercepted was closed.
+    #[non_exhaustive]
+    TunnelClosed {
+        /// ID of the tunnel, which is the same as the `tunnel_id` of the
+        /// [`AccessRecord`](crate::access_log::AccessRecord::tunnel_id) of the `CONNECT` request.
+        tunnel_id: u64,
+        /// The authority of the `CONNECT` request that opened the tunnel.
+        authority: String,
+        /// Why the tunnel was closed.
+        reason: TunnelCloseReason,
+        /// The number of bytes that were sent by the client.
+        bytes_sent: u64,
+        /// The number of bytes that were received by the client.
+        bytes_received: u64,
+        /// How long the tunnel was open, in milliseconds.
+        duration: u64,
+    },
+}
+
+/// Why a tunnel was closed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TunnelCloseReason {
+    /// The client or the server closed the tunnel.
+    Closed,
+    /// No data was sent in either direction for the
+    /// [idle timeout](crate::builder::ProxyBuilder::with_tunnel_idle_timeout).
+    IdleTimeout,
+    /// The tunnel was open for its
+    /// [maximum lifetime](crate::builder::ProxyBuilder::with_tunnel_max_lifetime).
+    MaxLifetime,
+    /// Data could not be forwarded.
+    Error,
+}
+
+/// The kind of a WebSocket message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// A text message.
+    Text,
+    /// A binary message.
+    Binary,
+    /// A ping.
+    Ping,
+    /// A pong.
+    Pong,
+    /// A close frame.
+    Close,
+}
+
+/// The maximum number of events that are passed to [`EventSink::send`] at once.
+const MAX_BATCH: usize = 256;
+
+/// A destination that [`Events::forward`] streams events to, such as a message queue.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::events::{Event, EventSink};
+/// use std::io;
+///
+/// struct Stdout;
+///
+/// impl EventSink for Stdout {
+///     async fn send(&mut self, events: &[Event]) -> io::Result<()> {
+///         for event in events {
+///             println!("{:?}", event);
+///         }
+///
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait EventSink: Send + 'static {
+    /// Send a batch of events, in the order that they happened.
+    ///
+    /// If this returns an error, the events are dropped and the next batch is sent as usual.
+    fn send(&mut self, events: &[Event]) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_owned(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// A broadcast channel of proxy events.
+///
+/// Clones of an `Events` share the same channel.
+#[derive(Clone, Debug)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+    capture_bodies: Arc<AtomicBool>,
+}
+
+impl Events {
+    /// Creates a channel that buffers up to `capacity` events for each subscriber.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+            capture_bodies: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set whether [`Event::BodyProgress`] contains the chunks of bodies. Defaults to `false`.
+    ///
+    /// Applies to the chunks that are streamed after this is called, including those of flows
+    /// that are in progress.
+    pub fn set_capture_bodies(&self, capture: bool) {
+        self.capture_bodies.store(capture, Ordering::Relaxed);
+    }
+
+    /// Whether [`Event::BodyProgress`] contains the chunks of bodies.
+    pub fn captures_bodies(&self) -> bool {
+        self.capture_bodies.load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the events that are sent after this is called.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Streams the events that are sent after this is called to a sink, until every clone of this
+    /// `Events` is dropped.
+    ///
+    /// Events that are sent while the sink is busy are batched. If the sink falls behind by
+    /// more than the capacity of the channel, the oldest events are dropped.
+    pub fn forward(&self, mut sink: impl EventSink) -> JoinHandle<()> {
+        let mut rx = self.subscribe();
+
+        tokio::spawn(async move {
+            let mut batch = Vec::new();
+
+            loop {
+                match rx.recv().await {
+                    Ok(event) => batch.push(event),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Event sink missed {} events", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+
+                while batch.len() < MAX_BATCH {
+                    match rx.try_recv() {
+                        Ok(event) => batch.push(event),
+                        Err(TryRecvError::Lagged(n)) => warn!("Event sink missed {} events", n),
+                        Err(_) => break,
+                    }
+                }
+
+                if let Err(e) = sink.send(&batch).await {
+                    warn!("Failed to send {} events to sink: {}", batch.len(), e);
+                }
+
+                batch.clear();
+            }
+        })
+    }
+
+    /// Whether there are any subscribers.
+    pub(crate) fn is_active(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    /// Sends an event, if there are any subscribers.
+    pub(crate) fn emit(&self, event: impl FnOnce() -> Event) {
+        if self.is_active() {
+            let _ = self.tx.send(event());
+        }
+    }
+
+    pub(crate) fn flow_started(
+        &self,
+        flow_id: u64,
+        client_addr: SocketAddr,
+        req: Request<Body>,
+    ) -> Request<Body> {
+        self.emit(|| Event::FlowStarted {
+            flow_id,
+            client_addr,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: headers(req.headers()),
+        });
+
+        let (parts, body) = req.into_parts();
+        let body = Tracked {
+            body,
+            events: self.clone(),
+            flow_id,
+            direction: BodyDirection::Request,
+            bytes: 0,
+            completion: None,
+        };
+
+        Request::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+
+    pub(crate) fn response_headers<T>(&self, flow_id: u64, res: &Response<T>) {
+        self.emit(|| Event::ResponseHeaders {
+            flow_id,
+            status: res.status().as_u16(),
+            headers: headers(res.headers()),
+        });
+    }
+
+    /// Tracks the body of the response that is sent to the client, and sends
+    /// [`Event::FlowCompleted`] when it ends or is dropped.
+    pub(crate) fn flow_completed(
+        &self,
+        flow_id: u64,
+        start: Instant,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let body = Tracked {
+            body,
+            events: self.clone(),
+            flow_id,
+            direction: BodyDirection::Response,
+            bytes: 0,
+            completion: Some((parts.status.as_u16(), start)),
+        };
+
+        Response::from_parts(parts, Body::from(BoxBody::new(body)))
+    }
+}
+
+/// Sends [`Event::WebSocketMessage`] for each message of a stream, if events are configured.
+pub(crate) fn websocket_messages<S>(
+    events: Option<Events>,
+    ctx: &WebSocketContext,
+    stream: S,
+) -> impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+{
+    let socket_id = ctx.socket_id();
+    let direction = ctx.direction();
+
+    stream.inspect(move |msg| {
+        let (Some(events), Ok(msg)) = (&events, msg) else {
+            return;
+        };
+
+        events.emit(|| {
+            let (kind, text) = match msg {
+                Message::Text(text) => (MessageKind::Text, Some(text.clone())),
+                Message::Binary(_) | Message::Frame(_) => (MessageKind::Binary, None),
+                Message::Ping(_) => (MessageKind::Ping, None),
+                Message::Pong(_) => (MessageKind::Pong, None),
+                Message::Close(_) => (MessageKind::Close, None),
+            };
+
+            Event::WebSocketMessage {
+                socket_id,
+                direction,
+                kind,
+                len: msg.len(),
+                text,
+            }
+        });
+    })
+}
+
+/// A body that sends [`Event::BodyProgress`] for each chunk, and optionally
+/// [`Event::FlowCompleted`] when it ends or is dropped.
+struct Tracked {
+    body: Body,
+    events: Events,
+    flow_id: u64,
+    direction: BodyDirection,
+    bytes: u64,
+    completion: Option<(u16, Instant)>,
+}
+
+impl Tracked {
+    fn finish(&mut self) {
+        if let Some((status, start)) = self.completion.take() {
+            let (flow_id, bytes) = (self.flow_id, self.bytes);
+
+            self.events.emit(|| Event::FlowCompleted {
+                flow_id,
+                status,
+                bytes,
+                duration: start.elapsed().as_millis() as u64,
+            });
+        }
+    }
+}
+
+impl HttpBody for Tracked {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                let data = frame.data_ref().filter(|data| !data.is_empty());
+
+                if let Some(data) = data {
+                    self.bytes += data.len() as u64;
+
+                    let (flow_id, direction, bytes) = (self.flow_id, self.direction, self.bytes);
+                    self.events.emit(|| Event::BodyProgress {
+                        flow_id,
+                        direction,
+                        bytes,
+                        data: self
+                            .events
+                            .captures_bodies()
+                            .then(|| String::from_utf8_lossy(data).into_owned()),
+                    });
+                }
+
+                if self.body.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Some(Err(_)) | None => self.finish(),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+
+    #[test]
+    fn serializes_events() {
+        let event = Event::BodyProgress {
+            flow_id: 1,
+            direction: BodyDirection::Response,
+            bytes: 5,
+            data: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"body_progress","flow_id":1,"direction":"response","bytes":5}"#
+        );
+    }
+
+    #[test]
+    fn skips_events_without_subscribers() {
+        let events = Events::new(1);
+        events.emit(|| panic!("event was created"));
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<Event>>>>);
+
+    impl EventSink for Recorder {
+        async fn send(&mut self, events: &[Event]) -> io::Result<()> {
+            self.0.lock().unwrap().push(events.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_batches_to_sinks() {
+        let events = Events::new(16);
+        let recorder = Recorder::default();
+        let forwarding = events.forward(recorder.clone());
+
+        for bytes in 1..=3 {
+            events.emit(|| Event::BodyProgress {
+                flow_id: 1,
+                direction: BodyDirection::Request,
+                bytes,
+                data: None,
+            });
+        }
+
+        drop(events);
+        forwarding.await.unwrap();
+
+        let batches = recorder.0.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 3);
+    }
+
+    #[tokio::test]
+    async fn tracks_response_bodies() {
+        let events = Events::new(16);
+        let mut rx = events.subscribe();
+
+        let res = events.flow_completed(7, Instant::now(), Response::new(Body::from("hello")));
+        res.into_body().collect().await.unwrap();
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Event::BodyProgress {
+                flow_id: 7,
+                direction: BodyDirection::Response,
+                bytes: 5,
+                data: None,
+            }
+        );
+
+        match rx.recv().await.unwrap() {
+            Event::FlowCompleted {
+                flow_id,
+                status,
+                bytes,
+                ..
+            } => assert_eq!((flow_id, status, bytes), (7, 200, 5)),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn captures_bodies_when_enabled() {
+        let events = Events::new(16);
+        let mut rx = events.subscribe();
+        events.set_capture_bodies(true);
+
+        let res = events.flow_completed(7, Instant::now(), Response::new(Body::from("hello")));
+        res.into_body().collect().await.unwrap();
+
+        match rx.recv().await.unwrap() {
+            Event::BodyProgress { data, .. } => assert_eq!(data.as_deref(), Some("hello")),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use super::{Event, EventSink};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// An [`EventSink`] that publishes events to a NATS subject.
+///
+/// Each event is serialized as JSON and published as a message. After each batch the sink waits
+/// for the server to answer a `PING`, so that errors such as rejected credentials are reported for
+/// the batch that caused them. The connection is opened when the first batch is sent, and again
+/// after it fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::events::{Events, NatsSink};
+///
+/// # async fn run() {
+/// let events = Events::new(1024);
+/// events.forward(NatsSink::new("127.0.0.1:4222", "hudsucker.events"));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct NatsSink {
+    addr: String,
+    subject: String,
+    token: Option<String>,
+    timeout: Duration,
+    conn: Option<BufStream<TcpStream>>,
+}
+
+impl NatsSink {
+    /// Creates a sink that publishes events to `subject` on the server at `addr`.
+    pub fn new(addr: impl Into<String>, subject: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            subject: subject.into(),
+            token: None,
+            timeout: Duration::from_secs(10),
+            conn: None,
+        }
+    }
+
+    /// Authenticate with a token.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Set the timeout for publishing a batch of events. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let mut conn = BufStream::new(TcpStream::connect(&self.addr).await?);
+
+        let mut info = String::new();
+        conn.read_line(&mut info).await?;
+
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::other("server did not send INFO"));
+        }
+
+        let mut options = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "hudsucker",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 0,
+        });
+
+        if let Some(token) = &self.token {
+            options["auth_token"] = token.as_str().into();
+        }
+
+        conn.write_all(format!("CONNECT {}\r\n", options).as_bytes())
+            .await?;
+        Ok(conn)
+    }
+
+    async fn publish(&mut self, messages: &[Vec<u8>]) -> io::Result<()> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let conn = self.connect().await?;
+                self.conn.insert(conn)
+            }
+        };
+
+        for message in messages {
+            conn.write_all(format!("PUB {} {}\r\n", self.subject, message.len()).as_bytes())
+                .await?;
+            conn.write_all(message).await?;
+            conn.write_all(b"\r\n").await?;
+        }
+
+        conn.write_all(b"PING\r\n").await?;
+        conn.flush().await?;
+
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            if conn.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => {
+                    conn.write_all(b"PONG\r\n").await?;
+                    conn.flush().await?;
+                }
+                err if err.starts_with("-ERR") => {
+                    return Err(io::Error::other(format!("server returned {}", err)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl EventSink for NatsSink {
+    async fn send(&mut self, events: &[Event]) -> io::Result<()> {
+        let messages = events
+            .iter()
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let published = timeout(self.timeout, self.publish(&messages))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+        if published.is_err() {
+            self.conn = None;
+        }
+
+        published
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn publishes_to_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+
+            let mut lines = Vec::new();
+
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+
+                if line == "PING\r\n" {
+                    break;
+                }
+
+                if line.starts_with("PUB ") {
+                    let len: usize = line.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+                    let mut payload = vec![0; len + 2];
+                    stream.read_exact(&mut payload).await.unwrap();
+                    line.push_str(std::str::from_utf8(&payload).unwrap());
+                }
+
+                lines.push(line);
+            }
+
+            stream.write_all(b"PING\r\nPONG\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+
+            let mut pong = String::new();
+            stream.read_line(&mut pong).await.unwrap();
+            (lines, pong)
+        });
+
+        let mut sink = NatsSink::new(addr.to_string(), "events").with_token("secret");
+        let event = Event::BodyProgress {
+            flow_id: 1,
+            direction: crate::BodyDirection::Request,
+            bytes: 5,
+            data: None,
+        };
+        sink.send(&[event]).await.unwrap();
+
+        let (lines, pong) = server.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        assert!(lines[0].contains(r#""auth_token":"secret""#));
+        assert_eq!(
+            lines[1],
+            "PUB events 68\r\n{\"type\":\"body_progress\",\"flow_id\":1,\"direction\":\"request\",\"bytes\":5}\r\n"
+        );
+        assert_eq!(pong, "PONG\r\n");
+    }
+}
@@ -0,0 +1,369 @@
+//! Exporting of captured requests as commands and code that reproduce them.
+//!
+//! [`Export`] turns a request, such as one captured in [`HttpHandler::handle_request`] after its
+//! body has been collected, into a `curl` command or a `reqwest` code snippet that sends the same
+//! request, optionally through the proxy.
+//!
+//! [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{export::Export, hyper::Request};
+//!
+//! let req = Request::post("https://example.com/api")
+//!     .header("content-type", "application/json")
+//!     .body(r#"{"name":"hudsucker"}"#)
+//!     .unwrap();
+//!
+//! let curl = Export::new()
+//!     .with_proxy("http://127.0.0.1:3000")
+//!     .with_ca_cert("ca.pem")
+//!     .curl(&req);
+//!
+//! assert_eq!(
+//!     curl,
+//!     "curl 'https://example.com/api' \\\n  \
+//!        -H 'content-type: application/json' \\\n  \
+//!        --data-raw '{\"name\":\"hudsucker\"}' \\\n  \
+//!        --proxy 'http://127.0.0.1:3000' \\\n  \
+//!        --cacert 'ca.pem'"
+//! );
+//! ```
+
+use hyper::{
+    header::{CONTENT_LENGTH, HOST},
+    Method, Request, Version,
+};
+use std::fmt::Write;
+
+/// Quote bytes as a single shell word, with ANSI-C quoting if they are not printable text.
+fn shell_quote(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(char::is_control) => format!("'{}'", s.replace('\'', r"'\''")),
+        _ => {
+            let mut quoted = String::from("$'");
+
+            for &b in bytes {
+                match b {
+                    b'\'' => quoted.push_str(r"\'"),
+                    b'\\' => quoted.push_str(r"\\"),
+                    b' '..=b'~' => quoted.push(char::from(b)),
+                    _ => {
+                        let _ = write!(quoted, "\\x{:02x}", b);
+                    }
+                }
+            }
+
+            quoted.push('\'');
+            quoted
+        }
+    }
+}
+
+/// Escape bytes for the format of `printf '%b'`, which unlike shell words can contain NUL bytes.
+fn printf_escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+
+    for &b in bytes {
+        match b {
+            b' '..=b'~' if b != b'\\' && b != b'\'' => escaped.push(char::from(b)),
+            _ => {
+                let _ = write!(escaped, "\\0{:03o}", b);
+            }
+        }
+    }
+
+    escaped
+}
+
+/// A Rust expression for bytes, which is a string literal if they are valid UTF-8.
+fn rust_literal(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => format!("{:?}", s),
+        Err(_) => {
+            let escaped: String = bytes
+                .iter()
+                .flat_map(|&b| std::ascii::escape_default(b))
+                .map(char::from)
+                .collect();
+            format!("&b\"{}\"[..]", escaped)
+        }
+    }
+}
+
+/// The headers of a request that are exported. `Content-Length` is computed from the exported
+/// body, and `Host` is left out if it matches the URI.
+fn exported_headers<B>(req: &Request<B>) -> impl Iterator<Item = (&str, &[u8])> {
+    let authority = req.uri().authority().map(|authority| authority.as_str());
+
+    req.headers()
+        .iter()
+        .filter(move |(name, value)| match **name {
+            CONTENT_LENGTH => false,
+            HOST => authority.map_or(true, |authority| {
+                !value.as_bytes().eq_ignore_ascii_case(authority.as_bytes())
+            }),
+            _ => true,
+        })
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+}
+
+/// Converts requests into commands and code that reproduce them.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Debug, Default)]
+pub struct Export {
+    proxy: Option<String>,
+    ca_cert: Option<String>,
+    insecure: bool,
+}
+
+impl Export {
+    /// Creates a new exporter for requests that are sent directly to the upstream server.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send the exported requests through a proxy, such as `http://127.0.0.1:3000`.
+    pub fn with_proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Trust the CA certificate in a PEM file, such as the proxy's CA certificate.
+    pub fn with_ca_cert(mut self, path: impl Into<String>) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    /// Set whether certificate errors are ignored.
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// A `curl` command that sends the request.
+    pub fn curl<B: AsRef<[u8]>>(&self, req: &Request<B>) -> String {
+        let body = req.body().as_ref();
+        let mut args = vec![shell_quote(req.uri().to_string().as_bytes())];
+
+        match *req.method() {
+            Method::GET if body.is_empty() => {}
+            Method::POST if !body.is_empty() => {}
+            Method::HEAD if body.is_empty() => args.push("--head".to_owned()),
+            ref method => args.push(format!("-X {}", shell_quote(method.as_str().as_bytes()))),
+        }
+
+        match req.version() {
+            Version::HTTP_10 => args.push("--http1.0".to_owned()),
+            Version::HTTP_2 => args.push("--http2".to_owned()),
+            _ => {}
+        }
+
+        for (name, value) in exported_headers(req) {
+            let mut header = format!("{}:", name).into_bytes();
+
+            if value.is_empty() {
+                // `-H 'name:'` removes a header, while `-H 'name;'` sends it empty.
+                header.pop();
+                header.push(b';');
+            } else {
+                header.push(b' ');
+                header.extend_from_slice(value);
+            }
+
+            args.push(format!("-H {}", shell_quote(&header)));
+        }
+
+        // Arguments can not contain NUL bytes, so bodies with them are piped to standard input.
+        let piped = body.contains(&0);
+
+        if piped {
+            args.push("--data-binary @-".to_owned());
+        } else if !body.is_empty() {
+            args.push(format!("--data-raw {}", shell_quote(body)));
+        }
+
+        if let Some(proxy) = &self.proxy {
+            args.push(format!("--proxy {}", shell_quote(proxy.as_bytes())));
+        }
+
+        if let Some(ca_cert) = &self.ca_cert {
+            args.push(format!("--cacert {}", shell_quote(ca_cert.as_bytes())));
+        }
+
+        if self.insecure {
+            args.push("--insecure".to_owned());
+        }
+
+        let curl = format!("curl {}", args.join(" \\\n  "));
+
+        if piped {
+            format!("printf '%b' '{}' | {}", printf_escape(body), curl)
+        } else {
+            curl
+        }
+    }
+
+    /// A snippet of Rust code that sends the request with `reqwest`. The snippet uses `?`, and
+    /// must be in an async function that returns a boxed error.
+    pub fn reqwest<B: AsRef<[u8]>>(&self, req: &Request<B>) -> String {
+        let mut code = String::from("let client = reqwest::Client::builder()\n");
+
+        if let Some(proxy) = &self.proxy {
+            let _ = writeln!(
+                code,
+                "    .proxy(reqwest::Proxy::all({})?)",
+                rust_literal(proxy.as_bytes())
+            );
+        }
+
+        if let Some(ca_cert) = &self.ca_cert {
+            let _ = writeln!(
+                code,
+                "    .add_root_certificate(reqwest::Certificate::from_pem(&std::fs::read({})?)?)",
+                rust_literal(ca_cert.as_bytes())
+            );
+        }
+
+        if self.insecure {
+            code.push_str("    .danger_accept_invalid_certs(true)\n");
+        }
+
+        code.push_str("    .build()?;\n\nlet res = client\n");
+
+        let method = match *req.method() {
+            Method::GET => "reqwest::Method::GET".to_owned(),
+            Method::POST => "reqwest::Method::POST".to_owned(),
+            Method::PUT => "reqwest::Method::PUT".to_owned(),
+            Method::DELETE => "reqwest::Method::DELETE".to_owned(),
+            Method::HEAD => "reqwest::Method::HEAD".to_owned(),
+            Method::OPTIONS => "reqwest::Method::OPTIONS".to_owned(),
+            Method::PATCH => "reqwest::Method::PATCH".to_owned(),
+            ref method => format!("reqwest::Method::from_bytes(b{:?})?", method.as_str()),
+        };
+
+        let _ = writeln!(
+            code,
+            "    .request({}, {})",
+            method,
+            rust_literal(req.uri().to_string().as_bytes())
+        );
+
+        match req.version() {
+            Version::HTTP_10 => code.push_str("    .version(reqwest::Version::HTTP_10)\n"),
+            Version::HTTP_2 => code.push_str("    .version(reqwest::Version::HTTP_2)\n"),
+            _ => {}
+        }
+
+        for (name, value) in exported_headers(req) {
+            let _ = writeln!(code, "    .header({:?}, {})", name, rust_literal(value));
+        }
+
+        let body = req.body().as_ref();
+
+        if !body.is_empty() {
+            let _ = writeln!(code, "    .body({})", rust_literal(body));
+        }
+
+        code.push_str("    .send()\n    .await?;");
+        code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_shell_words() {
+        assert_eq!(shell_quote(b"it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(b"a\nb\xff'"), r"$'a\x0ab\xff\''");
+    }
+
+    #[test]
+    fn exports_curl_commands() {
+        let req = Request::put("http://example.com/a?b=c")
+            .header(HOST, "example.com")
+            .header("x-empty", "")
+            .header("x-multi", "1")
+            .header("x-multi", "2")
+            .header(CONTENT_LENGTH, "3")
+            .body("abc")
+            .unwrap();
+
+        assert_eq!(
+            Export::new().with_insecure(true).curl(&req),
+            "curl 'http://example.com/a?b=c' \\\n  \
+               -X 'PUT' \\\n  \
+               -H 'x-empty;' \\\n  \
+               -H 'x-multi: 1' \\\n  \
+               -H 'x-multi: 2' \\\n  \
+               --data-raw 'abc' \\\n  \
+               --insecure"
+        );
+
+        let req = Request::head("http://example.com/")
+            .header(HOST, "other.example")
+            .body(b"".as_slice())
+            .unwrap();
+
+        assert_eq!(
+            Export::new().curl(&req),
+            "curl 'http://example.com/' \\\n  --head \\\n  -H 'host: other.example'"
+        );
+    }
+
+    #[test]
+    fn exports_binary_bodies() {
+        let req = Request::post("http://example.com/")
+            .body(b"\x00a'\xff".as_slice())
+            .unwrap();
+
+        assert_eq!(
+            Export::new().curl(&req),
+            r"printf '%b' '\0000a\0047\0377' | curl 'http://example.com/' \
+  --data-binary @-"
+        );
+        assert!(Export::new()
+            .reqwest(&req)
+            .contains(r#"    .body(&b"\x00a\'\xff"[..])"#));
+
+        let req = Request::post("http://example.com/")
+            .body(b"a\nb\xff".as_slice())
+            .unwrap();
+
+        assert_eq!(
+            Export::new().curl(&req),
+            "curl 'http://example.com/' \\\n  --data-raw $'a\\x0ab\\xff'"
+        );
+    }
+
+    #[test]
+    fn exports_reqwest_code() {
+        let req = Request::builder()
+            .method("PURGE")
+            .uri("https://example.com/")
+            .header("accept", "*/*")
+            .body("")
+            .unwrap();
+
+        assert_eq!(
+            Export::new()
+                .with_proxy("http://127.0.0.1:3000")
+                .with_ca_cert("ca.pem")
+                .reqwest(&req),
+            r#"let client = reqwest::Client::builder()
+    .proxy(reqwest::Proxy::all("http://127.0.0.1:3000")?)
+    .add_root_certificate(reqwest::Certificate::from_pem(&std::fs::read("ca.pem")?)?)
+    .build()?;
+
+let res = client
+    .request(reqwest::Method::from_bytes(b"PURGE")?, "https://example.com/")
+    .header("accept", "*/*")
+    .send()
+    .await?;"#
+        );
+    }
+}
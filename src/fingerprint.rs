@@ -0,0 +1,488 @@
+//! Identifying clients by their TLS fingerprints.
+//!
+//! When a `CONNECT` tunnel is intercepted, the proxy reads the TLS ClientHello that the client
+//! sends and derives a [`TlsFingerprint`] from it, with the client's [JA3] and [JA4] fingerprints
+//! and the ALPN protocols that it offered. A [`ClientIdentity`] that combines the fingerprint
+//! with the `User-Agent`, `Accept-Language` and `Accept-Encoding` headers of each request is
+//! inserted into the [`HttpContext::extensions`](crate::HttpContext::extensions), so that flows
+//! can be grouped by the device that made them without cookies, even when many devices share an
+//! IP address behind NAT.
+//!
+//! The identity of a client is the same for all of its requests with the same headers, and across
+//! connections and restarts of the proxy, but it is not unique: clients running the same version
+//! of the same software on the same platform usually have the same identity.
+//!
+//! [JA3]: https://github.com/salesforce/ja3
+//! [JA4]: https://github.com/FoxIO-LLC/ja4
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     fingerprint::ClientIdentity,
+//!     hyper::{Request, Response},
+//!     Body, HttpContext, HttpHandler, RequestOrResponse,
+//! };
+//!
+//! #[derive(Clone)]
+//! struct Identify;
+//!
+//! impl HttpHandler for Identify {
+//!     async fn handle_request(
+//!         &mut self,
+//!         ctx: &HttpContext,
+//!         req: Request<Body>,
+//!     ) -> RequestOrResponse {
+//!         if let Some(identity) = ctx.extensions.get::<ClientIdentity>() {
+//!             println!("{} {}", identity.id(), req.uri());
+//!         }
+//!
+//!         req.into()
+//!     }
+//! }
+//! ```
+
+use crate::Rewind;
+use hyper::{
+    body::Bytes,
+    header::{ACCEPT_ENCODING, ACCEPT_LANGUAGE, USER_AGENT},
+    HeaderMap,
+};
+use ring::digest::{self, SHA256};
+use std::fmt::Write;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The largest TLS record, including the header.
+const MAX_RECORD_LEN: usize = 5 + (1 << 14) + 2048;
+
+const SERVER_NAME: u16 = 0x0000;
+const SUPPORTED_GROUPS: u16 = 0x000a;
+const EC_POINT_FORMATS: u16 = 0x000b;
+const SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+/// The first 12 hex digits of the SHA-256 hash of `input`, or zeros if it is empty.
+fn truncated_sha256(input: &str) -> String {
+    if input.is_empty() {
+        return "0".repeat(12);
+    }
+
+    hex(&digest::digest(&SHA256, input.as_bytes()).as_ref()[..6])
+}
+
+/// Whether a value is one of the reserved GREASE values, which clients send at random and which
+/// are left out of fingerprints.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join(values: impl IntoIterator<Item = String>, separator: &str) -> String {
+    values.into_iter().collect::<Vec<_>>().join(separator)
+}
+
+/// A reader of the fields of a ClientHello.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// Reads a field that is prefixed with its length as a `u8`.
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()?;
+        self.take(len.into()).map(Reader)
+    }
+
+    /// Reads a field that is prefixed with its length as a `u16`.
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()?;
+        self.take(len.into()).map(Reader)
+    }
+
+    fn u16s(mut self) -> Vec<u16> {
+        let mut values = Vec::new();
+
+        while let Some(value) = self.u16() {
+            values.push(value);
+        }
+
+        values
+    }
+}
+
+/// The fingerprint of the TLS ClientHello that a client sent.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TlsFingerprint {
+    ja3: String,
+    ja4: String,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl TlsFingerprint {
+    /// Parses the fingerprint of a TLS record that contains a ClientHello.
+    pub(crate) fn parse(record: &[u8]) -> Option<Self> {
+        let mut record = Reader(record);
+
+        if record.u8()? != 0x16 {
+            return None;
+        }
+
+        record.u16()?;
+        let mut handshake = record.vec16()?;
+
+        if handshake.u8()? != 0x01 {
+            return None;
+        }
+
+        let len = handshake.u24()?;
+        let mut hello = Reader(handshake.take(len)?);
+
+        let legacy_version = hello.u16()?;
+        hello.take(32)?;
+        hello.vec8()?;
+        let ciphers = hello
+            .vec16()?
+            .u16s()
+            .into_iter()
+            .filter(|cipher| !is_grease(*cipher))
+            .collect::<Vec<_>>();
+        hello.vec8()?;
+
+        let mut extensions = Vec::new();
+        let mut groups = Vec::new();
+        let mut point_formats = Vec::new();
+        let mut signature_algorithms = Vec::new();
+        let mut alpn_protocols = Vec::new();
+        let mut versions = Vec::new();
+
+        if let Some(mut list) = hello.vec16() {
+            while let (Some(kind), Some(mut data)) = (list.u16(), list.vec16()) {
+                if is_grease(kind) {
+                    continue;
+                }
+
+                extensions.push(kind);
+
+                match kind {
+                    SUPPORTED_GROUPS => groups = data.vec16()?.u16s(),
+                    EC_POINT_FORMATS => point_formats = data.vec8()?.0.to_vec(),
+                    SIGNATURE_ALGORITHMS => signature_algorithms = data.vec16()?.u16s(),
+                    ALPN => {
+                        let mut protocols = data.vec16()?;
+
+                        while let Some(protocol) = protocols.vec8() {
+                            alpn_protocols.push(protocol.0.to_vec());
+                        }
+                    }
+                    SUPPORTED_VERSIONS => versions = data.vec8()?.u16s(),
+                    _ => {}
+                }
+            }
+        }
+
+        let ja3 = format!(
+            "{},{},{},{},{}",
+            legacy_version,
+            join(ciphers.iter().map(u16::to_string), "-"),
+            join(extensions.iter().map(u16::to_string), "-"),
+            join(
+                groups
+                    .iter()
+                    .filter(|group| !is_grease(**group))
+                    .map(u16::to_string),
+                "-"
+            ),
+            join(point_formats.iter().map(u8::to_string), "-"),
+        );
+
+        let version = versions
+            .into_iter()
+            .filter(|version| !is_grease(*version))
+            .max()
+            .unwrap_or(legacy_version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+
+        let alpn = match alpn_protocols.first() {
+            Some(protocol) if !protocol.is_empty() => {
+                let (first, last) = (protocol[0], protocol[protocol.len() - 1]);
+
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let hex = hex(protocol);
+                    format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+                }
+            }
+            _ => "00".to_owned(),
+        };
+
+        let mut sorted_ciphers = ciphers.clone();
+        sorted_ciphers.sort_unstable();
+
+        let mut sorted_extensions = extensions
+            .iter()
+            .copied()
+            .filter(|kind| *kind != SERVER_NAME && *kind != ALPN)
+            .collect::<Vec<_>>();
+        sorted_extensions.sort_unstable();
+
+        let mut hashed_extensions = join(
+            sorted_extensions.iter().map(|kind| format!("{:04x}", kind)),
+            ",",
+        );
+
+        if !signature_algorithms.is_empty() {
+            hashed_extensions.push('_');
+            hashed_extensions.push_str(&join(
+                signature_algorithms
+                    .iter()
+                    .map(|algorithm| format!("{:04x}", algorithm)),
+                ",",
+            ));
+        }
+
+        let ja4 = format!(
+            "t{}{}{:02}{:02}{}_{}_{}",
+            version,
+            if extensions.contains(&SERVER_NAME) {
+                'd'
+            } else {
+                'i'
+            },
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn,
+            truncated_sha256(&join(
+                sorted_ciphers
+                    .iter()
+                    .map(|cipher| format!("{:04x}", cipher)),
+                ","
+            )),
+            truncated_sha256(&hashed_extensions),
+        );
+
+        Some(Self {
+            ja3,
+            ja4,
+            alpn_protocols,
+        })
+    }
+
+    /// The JA3 string of the ClientHello.
+    ///
+    /// This is the string that is hashed with MD5 for the usual 32-digit JA3 fingerprint.
+    pub fn ja3(&self) -> &str {
+        &self.ja3
+    }
+
+    /// The JA4 fingerprint of the ClientHello, such as `t13d1516h2_8daaf6152771_b186095e22b6`.
+    pub fn ja4(&self) -> &str {
+        &self.ja4
+    }
+
+    /// The ALPN protocols that the client offered, in its order of preference.
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.alpn_protocols
+    }
+}
+
+/// Reads the first TLS record that a client sends, and parses its fingerprint if it is a
+/// ClientHello.
+///
+/// The returned stream yields the bytes of the record again, so the TLS handshake can be done on
+/// it. If the stream ends before the whole record is read, the handshake fails as it would have.
+pub(crate) async fn read_client_hello<S: AsyncRead + Unpin>(
+    mut stream: S,
+) -> (Rewind<S>, Option<TlsFingerprint>) {
+    let mut record = vec![0; 5];
+
+    let len = match stream.read_exact(&mut record).await {
+        Ok(_) => 5 + u16::from_be_bytes([record[3], record[4]]) as usize,
+        Err(_) => return (Rewind::new(stream, Bytes::new()), None),
+    };
+
+    if len <= MAX_RECORD_LEN {
+        record.resize(len, 0);
+
+        if stream.read_exact(&mut record[5..]).await.is_err() {
+            return (Rewind::new(stream, Bytes::new()), None);
+        }
+    }
+
+    let fingerprint = TlsFingerprint::parse(&record);
+    (Rewind::new(stream, Bytes::from(record)), fingerprint)
+}
+
+/// A stable identifier of a client, derived from its TLS fingerprint and request headers.
+///
+/// Requests that are not sent over an intercepted TLS connection are identified by their headers
+/// alone.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClientIdentity {
+    id: String,
+    tls: Option<TlsFingerprint>,
+}
+
+impl ClientIdentity {
+    /// Derives the identity of the client that sent a request.
+    pub fn new(tls: Option<&TlsFingerprint>, headers: &HeaderMap) -> Self {
+        let mut signals = String::new();
+
+        if let Some(tls) = tls {
+            signals.push_str(&tls.ja4);
+
+            for protocol in &tls.alpn_protocols {
+                signals.push(',');
+                signals.push_str(&String::from_utf8_lossy(protocol));
+            }
+        }
+
+        for name in [USER_AGENT, ACCEPT_LANGUAGE, ACCEPT_ENCODING] {
+            signals.push('\n');
+
+            if let Some(value) = headers.get(name) {
+                signals.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+
+        Self {
+            id: hex(&digest::digest(&SHA256, signals.as_bytes()).as_ref()[..8]),
+            tls: tls.cloned(),
+        }
+    }
+
+    /// The identifier, as 16 hex digits.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The fingerprint of the client's TLS connection, if it was intercepted.
+    pub fn tls(&self) -> Option<&TlsFingerprint> {
+        self.tls.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    /// A ClientHello with GREASE values, SNI, ALPN and TLS 1.3.
+    fn client_hello() -> Vec<u8> {
+        let mut extensions = Vec::new();
+        let mut extension = |kind: u16, data: &[u8]| {
+            extensions.extend_from_slice(&kind.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(data);
+        };
+
+        extension(0x0a0a, &[]);
+        extension(SERVER_NAME, b"\x00\x0e\x00\x00\x0bexample.com");
+        extension(
+            SUPPORTED_GROUPS,
+            &[0x00, 0x06, 0x2a, 0x2a, 0x00, 0x1d, 0x00, 0x17],
+        );
+        extension(EC_POINT_FORMATS, &[0x01, 0x00]);
+        extension(SIGNATURE_ALGORITHMS, &[0x00, 0x04, 0x04, 0x03, 0x08, 0x04]);
+        extension(ALPN, b"\x00\x0c\x02h2\x08http/1.1");
+        extension(SUPPORTED_VERSIONS, &[0x04, 0x3a, 0x3a, 0x03, 0x04]);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.push(0);
+        hello.extend_from_slice(&[0x00, 0x06, 0x1a, 0x1a, 0x13, 0x01, 0x13, 0x02]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn fingerprints_client_hellos() {
+        let fingerprint = TlsFingerprint::parse(&client_hello()).unwrap();
+
+        assert_eq!(fingerprint.ja3(), "771,4865-4866,0-10-11-13-16-43,29-23,0");
+        assert!(fingerprint.ja4().starts_with("t13d0206h2_"));
+        assert_eq!(
+            fingerprint.ja4(),
+            format!(
+                "t13d0206h2_{}_{}",
+                truncated_sha256("1301,1302"),
+                truncated_sha256("000a,000b,000d,002b_0403,0804")
+            )
+        );
+        assert_eq!(
+            fingerprint.alpn_protocols(),
+            [b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        assert_eq!(TlsFingerprint::parse(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn rewinds_client_hellos() {
+        let hello = client_hello();
+        let (mut stream, fingerprint) = read_client_hello(&hello[..]).await;
+        assert!(fingerprint.is_some());
+
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, hello);
+    }
+
+    #[test]
+    fn identifies_clients() {
+        let tls = TlsFingerprint::parse(&client_hello()).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("curl/8.0"));
+
+        let identity = ClientIdentity::new(Some(&tls), &headers);
+        assert_eq!(identity.id().len(), 16);
+        assert_eq!(identity, ClientIdentity::new(Some(&tls), &headers));
+        assert_ne!(identity.id(), ClientIdentity::new(None, &headers).id());
+
+        headers.insert(USER_AGENT, HeaderValue::from_static("curl/8.1"));
+        assert_ne!(identity, ClientIdentity::new(Some(&tls), &headers));
+    }
+}
@@ -0,0 +1,599 @@
+//! Authentication of clients and per-user policies.
+//!
+//! [`GatewayHandler`] authenticates each client with the `Proxy-Authorization` header or with the
+//! certificate that it presented to the proxy, and applies the [`Policy`] of the resulting
+//! [`Identity`] to every request of the client, which turns the proxy into a lightweight egress
+//! gateway. The identity is inserted into the extensions of each request, including the requests
+//! of intercepted tunnels, so that the wrapped handler can apply its own per-user logic.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::gateway::{GatewayHandler, Policy};
+//! use std::time::Duration;
+//!
+//! let handler = GatewayHandler::new()
+//!     .with_user("alice", "secret")
+//!     .with_user("bob", "hunter2")
+//!     .with_policy(
+//!         "bob",
+//!         Policy::new()
+//!             .with_allowed_host("*.example.com")
+//!             .with_rate_limit(100, Duration::from_secs(60))
+//!             .with_log_level(tracing::Level::INFO),
+//!     );
+//! ```
+
+use crate::{
+    auth::{base64, host, HostPattern},
+    Body, BodyDirection, ClientCertificate, HttpContext, HttpHandler, NoopHandler,
+    RequestOrResponse,
+};
+use http_body_util::Empty;
+use hyper::{
+    header::{HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RETRY_AFTER},
+    Method, Request, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::Level;
+
+/// The identity of an authenticated client.
+///
+/// This is inserted into the extensions of each request of an authenticated client before it is
+/// passed to the handler wrapped by a [`GatewayHandler`].
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Identity(Arc<str>);
+
+impl Identity {
+    /// Creates a new identity with a name, such as a username.
+    pub fn new(name: impl Into<Arc<str>>) -> Self {
+        Self(name.into())
+    }
+
+    /// The name of the identity.
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    requests: u32,
+    per: Duration,
+}
+
+/// What an identity is allowed to do.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    allowed_hosts: Vec<HostPattern>,
+    rate_limit: Option<RateLimit>,
+    log_level: Option<Level>,
+}
+
+impl Policy {
+    /// Creates a new policy that allows every host without a rate limit, and does not log
+    /// requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow requests for hosts that match a pattern. Once a host is allowed, requests for hosts
+    /// that are not allowed are rejected with `403 Forbidden`.
+    pub fn with_allowed_host(mut self, pattern: impl Into<HostPattern>) -> Self {
+        self.allowed_hosts.push(pattern.into());
+        self
+    }
+
+    /// Limit the identity to `requests` requests every `per`, in bursts of up to `requests`
+    /// requests. Requests above the limit are rejected with `429 Too Many Requests`.
+    pub fn with_rate_limit(mut self, requests: u32, per: Duration) -> Self {
+        self.rate_limit = Some(RateLimit { requests, per });
+        self
+    }
+
+    /// Log each request of the identity at a level.
+    pub fn with_log_level(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    fn allows(&self, host: Option<&str>) -> bool {
+        self.allowed_hosts.is_empty()
+            || host.is_some_and(|host| self.allowed_hosts.iter().any(|p| p.matches(host)))
+    }
+}
+
+/// Finds the identity of a client from its certificate.
+type CertificateIdentity = dyn Fn(&ClientCertificate) -> Option<Identity> + Send + Sync;
+
+#[derive(Clone)]
+struct Config {
+    realm: HeaderValue,
+    /// The identities of users, by their encoded `Basic` credentials.
+    users: HashMap<String, Identity>,
+    certificate_identity: Option<Arc<CertificateIdentity>>,
+    policies: HashMap<Identity, Policy>,
+    default_policy: Policy,
+}
+
+/// A token bucket, which holds the number of requests that an identity can make immediately.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// An HTTP handler that authenticates clients and applies per-user policies.
+///
+/// Requests of clients that are not authenticated are rejected with
+/// `407 Proxy Authentication Required`. The `Proxy-Authorization` header is removed from the
+/// requests of authenticated clients, so it is not forwarded or visible to the wrapped handler.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone)]
+pub struct GatewayHandler<H = NoopHandler> {
+    config: Arc<Config>,
+    buckets: Arc<Mutex<HashMap<Identity, Bucket>>>,
+    /// The identity of the client, which is kept for the requests of its intercepted tunnels.
+    identity: Option<Identity>,
+    /// Whether the current request is in a tunnel.
+    tunneled: bool,
+    inner: H,
+}
+
+impl GatewayHandler {
+    /// Creates a new handler without any users.
+    pub fn new() -> Self {
+        Self {
+            config: Arc::new(Config {
+                realm: HeaderValue::from_static("Basic realm=\"hudsucker\""),
+                users: HashMap::new(),
+                certificate_identity: None,
+                policies: HashMap::new(),
+                default_policy: Policy::default(),
+            }),
+            buckets: Default::default(),
+            identity: None,
+            tunneled: false,
+            inner: NoopHandler::new(),
+        }
+    }
+}
+
+impl Default for GatewayHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> GatewayHandler<H> {
+    /// Set the handler that the requests and responses of authenticated clients are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> GatewayHandler<H2> {
+        GatewayHandler {
+            config: self.config,
+            buckets: self.buckets,
+            identity: None,
+            tunneled: false,
+            inner,
+        }
+    }
+
+    /// Add a user that authenticates with HTTP Basic authentication.
+    pub fn with_user(mut self, username: impl Into<String>, password: impl AsRef<str>) -> Self {
+        let username = username.into();
+        let credentials = base64(format!("{}:{}", username, password.as_ref()).as_bytes());
+
+        Arc::make_mut(&mut self.config)
+            .users
+            .insert(credentials, Identity::new(username));
+        self
+    }
+
+    /// Set the realm that clients are asked to authenticate for.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the realm contains characters that are not allowed in a header.
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        let challenge = format!("Basic realm=\"{}\"", realm.replace(['\\', '"'], ""));
+
+        Arc::make_mut(&mut self.config).realm =
+            HeaderValue::from_str(&challenge).expect("Invalid realm");
+        self
+    }
+
+    /// Authenticate clients by the certificates that they present in the TLS handshakes of
+    /// intercepted tunnels, which are verified with the proxy's
+    /// [`ClientAuth`](crate::ClientAuth).
+    ///
+    /// `identity` finds the identity of a certificate, or returns `None` if the certificate does
+    /// not belong to a user. Once this is set, `CONNECT` requests without credentials are accepted
+    /// and their tunnels are always intercepted, so that the client's certificate can be checked.
+    /// Requests in those tunnels are rejected with `403 Forbidden` if the client presented no
+    /// certificate of a user.
+    pub fn with_certificate_identity<F>(mut self, identity: F) -> Self
+    where
+        F: Fn(&ClientCertificate) -> Option<Identity> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).certificate_identity = Some(Arc::new(identity));
+        self
+    }
+
+    /// Set the policy of an identity.
+    pub fn with_policy(mut self, identity: impl Into<Arc<str>>, policy: Policy) -> Self {
+        Arc::make_mut(&mut self.config)
+            .policies
+            .insert(Identity::new(identity), policy);
+        self
+    }
+
+    /// Set the policy of the identities that do not have their own policy.
+    pub fn with_default_policy(mut self, policy: Policy) -> Self {
+        Arc::make_mut(&mut self.config).default_policy = policy;
+        self
+    }
+
+    /// The identity of a request, from the client's certificate or the `Proxy-Authorization`
+    /// header.
+    fn authenticate<T>(&self, req: &Request<T>) -> Option<Identity> {
+        if let Some((certificate_identity, certificate)) = self
+            .config
+            .certificate_identity
+            .as_ref()
+            .zip(req.extensions().get::<ClientCertificate>())
+        {
+            if let Some(identity) = certificate_identity(certificate) {
+                return Some(identity);
+            }
+        }
+
+        let (scheme, credentials) = req
+            .headers()
+            .get(PROXY_AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .trim()
+            .split_once(' ')?;
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        self.config.users.get(credentials.trim()).cloned()
+    }
+
+    /// Take a token from the bucket of an identity, returning how long to wait for the next token
+    /// if the bucket is empty.
+    fn take_token(&self, identity: &Identity, limit: RateLimit) -> Result<(), Duration> {
+        let capacity = f64::from(limit.requests);
+        let per_token = limit.per.as_secs_f64() / capacity;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().expect("Failed to lock rate limits");
+        let bucket = buckets.entry(identity.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() / per_token)
+            .min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) * per_token))
+        }
+    }
+
+    /// Apply the policy of an identity to a request, returning the response of a rejected request.
+    fn enforce<T>(&self, identity: &Identity, req: &Request<T>) -> Option<Response<Body>> {
+        let policy = self
+            .config
+            .policies
+            .get(identity)
+            .unwrap_or(&self.config.default_policy);
+
+        if let Some(level) = policy.log_level {
+            log(level, identity, req);
+        }
+
+        if !policy.allows(host(req)) {
+            return Some(response(StatusCode::FORBIDDEN));
+        }
+
+        if let Some(limit) = policy.rate_limit {
+            if let Err(wait) = self.take_token(identity, limit) {
+                let mut res = response(StatusCode::TOO_MANY_REQUESTS);
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                res.headers_mut().insert(RETRY_AFTER, seconds.into());
+                return Some(res);
+            }
+        }
+
+        None
+    }
+}
+
+fn response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(Empty::new()))
+        .expect("Failed to build response")
+}
+
+fn log<T>(level: Level, identity: &Identity, req: &Request<T>) {
+    let (method, uri) = (req.method(), req.uri());
+
+    match level {
+        Level::ERROR => tracing::error!(%identity, %method, %uri, "Gateway request"),
+        Level::WARN => tracing::warn!(%identity, %method, %uri, "Gateway request"),
+        Level::INFO => tracing::info!(%identity, %method, %uri, "Gateway request"),
+        Level::DEBUG => tracing::debug!(%identity, %method, %uri, "Gateway request"),
+        Level::TRACE => tracing::trace!(%identity, %method, %uri, "Gateway request"),
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for GatewayHandler<H> {
+    async fn handle_request(
+        &mut self,
+        ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        if let Some(identity) = self.authenticate(&req) {
+            self.identity = Some(identity);
+        }
+
+        let Some(identity) = self.identity.clone() else {
+            if self.tunneled {
+                return response(StatusCode::FORBIDDEN).into();
+            }
+
+            if req.method() == Method::CONNECT && self.config.certificate_identity.is_some() {
+                self.tunneled = true;
+                return req.into();
+            }
+
+            let mut res = response(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+            res.headers_mut()
+                .insert(PROXY_AUTHENTICATE, self.config.realm.clone());
+            return res.into();
+        };
+
+        if req.method() == Method::CONNECT {
+            self.tunneled = true;
+        }
+
+        req.headers_mut().remove(PROXY_AUTHORIZATION);
+
+        if let Some(res) = self.enforce(&identity, &req) {
+            return res.into();
+        }
+
+        req.extensions_mut().insert(identity);
+        self.inner.handle_request(ctx, req).await
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        // The tunnel of a client that has not authenticated yet is intercepted, so that its
+        // certificate can be checked.
+        self.identity.is_none() || self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+impl<H: fmt::Debug> fmt::Debug for GatewayHandler<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GatewayHandler")
+            .field("identity", &self.identity)
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri(uri);
+
+        if let Some(authorization) = authorization {
+            req = req.header(PROXY_AUTHORIZATION, authorization);
+        }
+
+        req.body(Body::from(Empty::new())).unwrap()
+    }
+
+    fn status(res: RequestOrResponse) -> Option<StatusCode> {
+        match res {
+            RequestOrResponse::Request(_) => None,
+            RequestOrResponse::Response(res) => Some(res.status()),
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_users() {
+        let handler = GatewayHandler::new().with_user("Aladdin", "open sesame");
+
+        let res = handler
+            .clone()
+            .handle_request(
+                &HttpContext::for_test(),
+                request(Method::GET, "http://example.com/", None),
+            )
+            .await;
+        let RequestOrResponse::Response(res) = res else {
+            panic!("expected a response");
+        };
+        assert_eq!(res.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert_eq!(
+            res.headers()[PROXY_AUTHENTICATE],
+            "Basic realm=\"hudsucker\""
+        );
+
+        let wrong = request(
+            Method::GET,
+            "http://example.com/",
+            Some("Basic QWxhZGRpbjo="),
+        );
+        assert_eq!(
+            status(
+                handler
+                    .clone()
+                    .handle_request(&HttpContext::for_test(), wrong)
+                    .await
+            ),
+            Some(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        );
+
+        // Requests in the tunnel of an authenticated `CONNECT` request keep its identity.
+        let mut tunnel = handler.clone();
+        let connect = request(
+            Method::CONNECT,
+            "example.com:443",
+            Some("basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+        );
+        let RequestOrResponse::Request(connect) = tunnel
+            .handle_request(&HttpContext::for_test(), connect)
+            .await
+        else {
+            panic!("expected a request");
+        };
+        assert!(!connect.headers().contains_key(PROXY_AUTHORIZATION));
+        assert_eq!(connect.extensions().get(), Some(&Identity::new("Aladdin")));
+
+        let req = request(Method::GET, "https://example.com/", None);
+        let RequestOrResponse::Request(req) = tunnel
+            .clone()
+            .handle_request(&HttpContext::for_test(), req)
+            .await
+        else {
+            panic!("expected a request");
+        };
+        assert_eq!(req.extensions().get(), Some(&Identity::new("Aladdin")));
+    }
+
+    #[tokio::test]
+    async fn applies_policies() {
+        let authorization = Some("Basic dXNlcjpwYXNzd29yZA==");
+        let handler = GatewayHandler::new()
+            .with_user("user", "password")
+            .with_policy(
+                "user",
+                Policy::new()
+                    .with_allowed_host("*.example.com")
+                    .with_rate_limit(1, Duration::from_secs(60)),
+            );
+
+        let forbidden = request(Method::GET, "http://example.org/", authorization);
+        assert_eq!(
+            status(
+                handler
+                    .clone()
+                    .handle_request(&HttpContext::for_test(), forbidden)
+                    .await
+            ),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        let allowed = || request(Method::GET, "http://www.example.com/", authorization);
+        assert_eq!(
+            status(
+                handler
+                    .clone()
+                    .handle_request(&HttpContext::for_test(), allowed())
+                    .await
+            ),
+            None
+        );
+
+        let RequestOrResponse::Response(res) = handler
+            .clone()
+            .handle_request(&HttpContext::for_test(), allowed())
+            .await
+        else {
+            panic!("expected a response");
+        };
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "60");
+    }
+}
@@ -0,0 +1,872 @@
+//! GeoIP annotation and routing of requests.
+//!
+//! [`GeoIpHandler`] looks up the IP address of the upstream server of each request in a MaxMind
+//! DB file, such as a GeoLite2 Country or City database, and inserts the [`GeoIp`] result into the
+//! extensions of the request and its response. With the `rustls-client` feature, [`GeoRoute`]s
+//! forward the requests for servers in some countries through an [`UpstreamProxy`].
+//!
+//! The upstream server's address is resolved separately from the connection that the request is
+//! forwarded on, so a host with several addresses may be located by a different address than the
+//! one that the request is sent to.
+//!
+//! [`UpstreamProxy`]: crate::UpstreamProxy
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::{
+//!     geoip::{Database, GeoIpHandler, GeoRoute},
+//!     UpstreamProxy,
+//! };
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let database = Database::open("GeoLite2-Country.mmdb").await?;
+//!
+//! // Send EU-destined traffic through a proxy in the EU.
+//! let handler = GeoIpHandler::new(database).with_route(GeoRoute::european_union(
+//!     UpstreamProxy::new("eu.proxy.example:3128".parse().unwrap()),
+//! ));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    auth::host, Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use hyper::{http::uri::Scheme, Request, Response};
+use std::{fmt, io, net::IpAddr, path::Path, sync::Arc};
+
+/// The marker that the metadata section of a database starts after.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// The size of the zeroed separator between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// The maximum depth of nested maps, arrays, and pointers that are decoded.
+const MAX_DEPTH: usize = 32;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid MaxMind DB: {}", message),
+    )
+}
+
+/// A value in the data section of a database.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    String(String),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Float(f32),
+    Bool(bool),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// A decoder of the values of a section of a database, which pointers are relative to.
+struct Decoder<'a> {
+    section: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.section.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.section.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<u128> {
+        if len > 16 {
+            return None;
+        }
+
+        Some(
+            self.bytes(len)?
+                .iter()
+                .fold(0, |n, &b| (n << 8) | u128::from(b)),
+        )
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let control = self.byte()?;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = (control >> 3) & 0x3;
+            let high = usize::from(control & 0x7);
+            let offset = match size {
+                0 => (high << 8) | self.uint(1)? as usize,
+                1 => ((high << 16) | self.uint(2)? as usize) + 2048,
+                2 => ((high << 24) | self.uint(3)? as usize) + 526336,
+                _ => self.uint(4)? as usize,
+            };
+
+            return Decoder {
+                section: self.section,
+                pos: offset,
+            }
+            .value(depth + 1);
+        }
+
+        if kind == 0 {
+            kind = self.byte()?.checked_add(7)?;
+        }
+
+        let len = match control & 0x1f {
+            29 => 29 + self.uint(1)? as usize,
+            30 => 285 + self.uint(2)? as usize,
+            31 => 65821 + self.uint(3)? as usize,
+            len => usize::from(len),
+        };
+
+        Some(match kind {
+            2 => Value::String(String::from_utf8(self.bytes(len)?.to_vec()).ok()?),
+            3 if len == 8 => Value::Double(f64::from_be_bytes(self.bytes(8)?.try_into().ok()?)),
+            4 => Value::Bytes(self.bytes(len)?.to_vec()),
+            5 | 6 | 9 | 10 => Value::Uint(self.uint(len)?),
+            7 => {
+                let mut entries = Vec::with_capacity(len.min(64));
+
+                for _ in 0..len {
+                    let Value::String(key) = self.value(depth + 1)? else {
+                        return None;
+                    };
+                    entries.push((key, self.value(depth + 1)?));
+                }
+
+                Value::Map(entries)
+            }
+            8 if len <= 4 => Value::Int(self.uint(len)? as u32 as i32),
+            11 => {
+                let mut values = Vec::with_capacity(len.min(64));
+
+                for _ in 0..len {
+                    values.push(self.value(depth + 1)?);
+                }
+
+                Value::Array(values)
+            }
+            14 => Value::Bool(len != 0),
+            15 if len == 4 => Value::Float(f32::from_be_bytes(self.bytes(4)?.try_into().ok()?)),
+            _ => return None,
+        })
+    }
+}
+
+/// A MaxMind DB file, such as a GeoLite2 Country or City database.
+pub struct Database {
+    data: Vec<u8>,
+    node_count: u32,
+    record_size: usize,
+    ip_version: u16,
+    ipv4_start: u32,
+}
+
+impl Database {
+    /// Reads a database from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can not be read, or is not a valid database.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(tokio::fs::read(path).await?)
+    }
+
+    /// Reads a database from the contents of a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidData`] if the data is not a valid
+    /// database.
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<Self> {
+        let start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| invalid("missing metadata"))?
+            + METADATA_MARKER.len();
+
+        let metadata = Decoder {
+            section: &data[start..],
+            pos: 0,
+        }
+        .value(0)
+        .ok_or_else(|| invalid("malformed metadata"))?;
+
+        let field = |key| metadata.get(key).and_then(Value::as_uint);
+
+        let node_count = field("node_count")
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| invalid("missing node count"))?;
+        let record_size = match field("record_size") {
+            Some(size @ (24 | 28 | 32)) => size as usize,
+            _ => return Err(invalid("unsupported record size")),
+        };
+        let ip_version = match field("ip_version") {
+            Some(version @ (4 | 6)) => version as u16,
+            _ => return Err(invalid("unsupported IP version")),
+        };
+
+        let tree_size = node_count as usize * record_size / 4;
+
+        if tree_size + DATA_SEPARATOR > start {
+            return Err(invalid("search tree is truncated"));
+        }
+
+        let mut database = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+
+        if ip_version == 6 {
+            let mut node = 0;
+
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+
+                node = database.record(node, false)?;
+            }
+
+            database.ipv4_start = node;
+        }
+
+        Ok(database)
+    }
+
+    fn record(&self, node: u32, right: bool) -> io::Result<u32> {
+        let len = self.record_size / 4;
+        let start = node as usize * len;
+        let b = self
+            .data
+            .get(start..start + len)
+            .ok_or_else(|| invalid("search tree is truncated"))?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| (n << 8) | u32::from(b));
+
+        Ok(match (self.record_size, right) {
+            (24, false) => be(&b[0..3]),
+            (24, true) => be(&b[3..6]),
+            (28, false) => (u32::from(b[3] & 0xf0) << 20) | be(&b[0..3]),
+            (28, true) => (u32::from(b[3] & 0x0f) << 24) | be(&b[4..7]),
+            (_, false) => be(&b[0..4]),
+            (_, true) => be(&b[4..8]),
+        })
+    }
+
+    /// Finds the offset in the data section of the record for an address.
+    fn find(&self, ip: IpAddr) -> Option<usize> {
+        let (bytes, mut node) = match (ip, self.ip_version) {
+            (IpAddr::V4(ip), 4) => (ip.octets().to_vec(), 0),
+            (IpAddr::V4(ip), _) => (ip.octets().to_vec(), self.ipv4_start),
+            (IpAddr::V6(ip), 4) => (ip.to_ipv4_mapped()?.octets().to_vec(), 0),
+            (IpAddr::V6(ip), _) => (ip.octets().to_vec(), 0),
+        };
+
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit == 1).ok()?;
+        }
+
+        let offset = node.checked_sub(self.node_count)? as usize;
+        offset.checked_sub(DATA_SEPARATOR)
+    }
+
+    fn value(&self, ip: IpAddr) -> Option<Value> {
+        let offset = self.find(ip)?;
+        let section = self
+            .data
+            .get(self.node_count as usize * self.record_size / 4 + DATA_SEPARATOR..)?;
+
+        Decoder {
+            section,
+            pos: offset,
+        }
+        .value(0)
+    }
+
+    /// Looks up the location of an address. Returns `None` if the address is not in the database.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let value = self.value(ip)?;
+        let country = value
+            .get("country")
+            .or_else(|| value.get("registered_country"));
+
+        Some(Location {
+            country: country
+                .and_then(|country| country.get("iso_code")?.as_str())
+                .map(str::to_owned),
+            continent: value
+                .get("continent")
+                .and_then(|continent| continent.get("code")?.as_str())
+                .map(str::to_owned),
+            in_european_union: country.and_then(|country| country.get("is_in_european_union"))
+                == Some(&Value::Bool(true)),
+        })
+    }
+}
+
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Database")
+            .field("node_count", &self.node_count)
+            .field("record_size", &self.record_size)
+            .field("ip_version", &self.ip_version)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The location of an address in a [`Database`].
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct Location {
+    /// The ISO 3166-1 alpha-2 code of the country, such as `DE`.
+    pub country: Option<String>,
+    /// The code of the continent, such as `EU`.
+    pub continent: Option<String>,
+    /// Whether the country is a member state of the European Union.
+    pub in_european_union: bool,
+}
+
+/// The address of the upstream server of a request, and its location.
+///
+/// [`GeoIpHandler`] inserts this into the extensions of requests and responses.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct GeoIp {
+    /// The address of the upstream server.
+    pub ip: IpAddr,
+    /// The location of the address, if it is in the database.
+    pub location: Option<Location>,
+}
+
+/// A rule that forwards requests for servers in some countries through an upstream proxy.
+#[cfg(feature = "rustls-client")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+#[derive(Clone, Debug)]
+pub struct GeoRoute {
+    countries: Vec<String>,
+    european_union: bool,
+    proxy: crate::UpstreamProxy,
+}
+
+#[cfg(feature = "rustls-client")]
+impl GeoRoute {
+    /// Creates a new route for servers in countries with the ISO 3166-1 alpha-2 codes
+    /// `countries`.
+    pub fn new<I>(countries: I, proxy: crate::UpstreamProxy) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self {
+            countries: countries
+                .into_iter()
+                .map(|country| country.into().to_ascii_uppercase())
+                .collect(),
+            european_union: false,
+            proxy,
+        }
+    }
+
+    /// Creates a new route for servers in member states of the European Union.
+    pub fn european_union(proxy: crate::UpstreamProxy) -> Self {
+        Self {
+            countries: Vec::new(),
+            european_union: true,
+            proxy,
+        }
+    }
+
+    fn matches(&self, location: &Location) -> bool {
+        (self.european_union && location.in_european_union)
+            || location
+                .country
+                .as_ref()
+                .is_some_and(|country| self.countries.contains(country))
+    }
+}
+
+/// An HTTP handler that locates the upstream servers of requests.
+///
+/// See the [module documentation](self) for an example. The [`GeoIp`] of a request is inserted
+/// into its extensions before it is passed to the wrapped handler, and into the extensions of its
+/// response before the response is passed to the wrapped handler. Requests are routed after they
+/// are returned by the wrapped handler, unless it has already set an
+/// [`UpstreamProxy`](crate::UpstreamProxy). Each request is routed by at most one route, the
+/// first that matches.
+#[derive(Clone, Debug)]
+pub struct GeoIpHandler<H = NoopHandler> {
+    database: Arc<Database>,
+    #[cfg(feature = "rustls-client")]
+    routes: Arc<Vec<GeoRoute>>,
+    geoip: Option<GeoIp>,
+    inner: H,
+}
+
+impl GeoIpHandler {
+    /// Creates a new handler that locates servers in a database.
+    pub fn new(database: Database) -> Self {
+        Self {
+            database: Arc::new(database),
+            #[cfg(feature = "rustls-client")]
+            routes: Arc::new(Vec::new()),
+            geoip: None,
+            inner: NoopHandler::default(),
+        }
+    }
+}
+
+impl<H> GeoIpHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> GeoIpHandler<H2> {
+        GeoIpHandler {
+            database: self.database,
+            #[cfg(feature = "rustls-client")]
+            routes: self.routes,
+            geoip: None,
+            inner,
+        }
+    }
+
+    /// Add a route.
+    #[cfg(feature = "rustls-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    pub fn with_route(mut self, route: GeoRoute) -> Self {
+        Arc::make_mut(&mut self.routes).push(route);
+        self
+    }
+
+    async fn locate<T>(&self, req: &Request<T>) -> Option<GeoIp> {
+        let host = host(req)?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+
+        let ip = match host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let port =
+                    req.uri()
+                        .port_u16()
+                        .unwrap_or(if req.uri().scheme() == Some(&Scheme::HTTPS) {
+                            443
+                        } else {
+                            80
+                        });
+
+                tokio::net::lookup_host((host, port))
+                    .await
+                    .ok()?
+                    .next()?
+                    .ip()
+            }
+        };
+
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        Some(GeoIp {
+            ip,
+            location: self.database.lookup(ip),
+        })
+    }
+
+    #[cfg(feature = "rustls-client")]
+    fn route(&self, req: &mut Request<Body>) {
+        if req.extensions().get::<crate::UpstreamProxy>().is_some() {
+            return;
+        }
+
+        let Some(location) = self
+            .geoip
+            .as_ref()
+            .and_then(|geoip| geoip.location.as_ref())
+        else {
+            return;
+        };
+
+        if let Some(route) = self.routes.iter().find(|route| route.matches(location)) {
+            req.extensions_mut().insert(route.proxy.clone());
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for GeoIpHandler<H> {
+    async fn handle_request(
+        &mut self,
+        ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        self.geoip = self.locate(&req).await;
+
+        if let Some(geoip) = &self.geoip {
+            req.extensions_mut().insert(geoip.clone());
+        }
+
+        match self.inner.handle_request(ctx, req).await {
+            #[cfg(feature = "rustls-client")]
+            RequestOrResponse::Request(mut req) => {
+                self.route(&mut req);
+                req.into()
+            }
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(
+        &mut self,
+        ctx: &HttpContext,
+        mut res: Response<Body>,
+    ) -> Response<Body> {
+        if let Some(geoip) = self.geoip.take() {
+            res.extensions_mut().insert(geoip);
+        }
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![(2 << 5) | s.len() as u8];
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn uint(n: u32) -> Vec<u8> {
+        let mut out = vec![(6 << 5) | 4];
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+
+    fn boolean(b: bool) -> Vec<u8> {
+        vec![u8::from(b), 14 - 7]
+    }
+
+    fn pointer(offset: usize) -> Vec<u8> {
+        vec![(1 << 5) | (offset >> 8) as u8, offset as u8]
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![(7 << 5) | entries.len() as u8];
+
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend_from_slice(value);
+        }
+
+        out
+    }
+
+    /// A record pointing at data, or at no data if `None`.
+    fn data(node_count: usize, offset: Option<usize>) -> u32 {
+        (node_count + offset.map_or(0, |offset| DATA_SEPARATOR + offset)) as u32
+    }
+
+    /// Builds a database from the records of its nodes, and the record data.
+    fn database(ip_version: u32, record_size: u32, nodes: &[(u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for &(left, right) in nodes {
+            match record_size {
+                24 => {
+                    out.extend_from_slice(&left.to_be_bytes()[1..]);
+                    out.extend_from_slice(&right.to_be_bytes()[1..]);
+                }
+                28 => {
+                    out.extend_from_slice(&left.to_be_bytes()[1..]);
+                    out.push((((left >> 24) as u8) << 4) | (right >> 24) as u8);
+                    out.extend_from_slice(&right.to_be_bytes()[1..]);
+                }
+                _ => {
+                    out.extend_from_slice(&left.to_be_bytes());
+                    out.extend_from_slice(&right.to_be_bytes());
+                }
+            }
+        }
+
+        out.extend_from_slice(&[0; DATA_SEPARATOR]);
+        out.extend_from_slice(data);
+        out.extend_from_slice(METADATA_MARKER);
+        out.extend(map(&[
+            ("node_count", uint(nodes.len() as u32)),
+            ("record_size", uint(record_size)),
+            ("ip_version", uint(ip_version)),
+        ]));
+        out
+    }
+
+    /// Record data for Germany, whose continent is behind a pointer, followed by the United
+    /// States. Returns the data and the offsets of both records.
+    fn records() -> (Vec<u8>, usize, usize) {
+        let mut data = map(&[("code", string("EU"))]);
+        let germany = data.len();
+        data.extend(map(&[
+            (
+                "country",
+                map(&[
+                    ("iso_code", string("DE")),
+                    ("is_in_european_union", boolean(true)),
+                ]),
+            ),
+            ("continent", pointer(0)),
+        ]));
+        let united_states = data.len();
+        data.extend(map(&[
+            ("registered_country", map(&[("iso_code", string("US"))])),
+            ("continent", map(&[("code", string("NA"))])),
+        ]));
+
+        (data, germany, united_states)
+    }
+
+    fn germany() -> Location {
+        Location {
+            country: Some("DE".to_owned()),
+            continent: Some("EU".to_owned()),
+            in_european_union: true,
+        }
+    }
+
+    fn ipv4_database(record_size: u32) -> Database {
+        let (data, germany, united_states) = records();
+
+        // 0.0.0.0/1 is in Germany, and 192.0.0.0/2 is in the United States.
+        Database::from_bytes(database(
+            4,
+            record_size,
+            &[
+                (self::data(2, Some(germany)), 1),
+                (self::data(2, None), self::data(2, Some(united_states))),
+            ],
+            &data,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn looks_up_ipv4_addresses() {
+        for record_size in [24, 28, 32] {
+            let database = ipv4_database(record_size);
+
+            assert_eq!(
+                database.lookup("1.2.3.4".parse().unwrap()),
+                Some(germany()),
+                "{}",
+                record_size
+            );
+            assert_eq!(
+                database.lookup("200.1.1.1".parse().unwrap()),
+                Some(Location {
+                    country: Some("US".to_owned()),
+                    continent: Some("NA".to_owned()),
+                    in_european_union: false,
+                })
+            );
+            assert_eq!(database.lookup("150.1.1.1".parse().unwrap()), None);
+            assert_eq!(
+                database.lookup("::ffff:1.2.3.4".parse().unwrap()),
+                Some(germany())
+            );
+        }
+    }
+
+    #[test]
+    fn looks_up_ipv4_addresses_in_ipv6_databases() {
+        let (data, germany, _) = records();
+        let node_count = 97;
+
+        // ::/96 leads to node 96, where ::0.0.0.0/97 is in Germany.
+        let mut nodes: Vec<_> = (1..=96)
+            .map(|next| (next, self::data(node_count, None)))
+            .collect();
+        nodes.push((
+            self::data(node_count, Some(germany)),
+            self::data(node_count, None),
+        ));
+
+        let database = Database::from_bytes(database(6, 28, &nodes, &data)).unwrap();
+
+        assert_eq!(
+            database.lookup("1.2.3.4".parse().unwrap()),
+            Some(self::germany())
+        );
+        assert_eq!(database.lookup("200.1.1.1".parse().unwrap()), None);
+        assert_eq!(database.lookup("2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn rejects_invalid_databases() {
+        let (data, ..) = records();
+
+        for contents in [
+            Vec::new(),
+            data.clone(),
+            database(5, 24, &[(0, 0)], &data),
+            database(4, 20, &[(0, 0)], &data),
+        ] {
+            let err = Database::from_bytes(contents).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[cfg(feature = "rustls-client")]
+    #[tokio::test]
+    async fn annotates_and_routes_requests() {
+        let ctx = HttpContext {
+            client_addr: "127.0.0.1:8080".parse().unwrap(),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            upstream_version: None,
+        };
+        let proxy = crate::UpstreamProxy::new("eu.proxy.example:3128".parse().unwrap());
+        let mut handler = GeoIpHandler::new(ipv4_database(24))
+            .with_route(GeoRoute::new(
+                ["us"],
+                crate::UpstreamProxy::new("us.proxy.example".parse().unwrap()),
+            ))
+            .with_route(GeoRoute::european_union(proxy.clone()));
+
+        let req = Request::builder()
+            .uri("http://1.2.3.4/")
+            .body(Body::from(http_body_util::Empty::new()))
+            .unwrap();
+
+        let RequestOrResponse::Request(req) = handler.handle_request(&ctx, req).await else {
+            panic!("expected a request");
+        };
+
+        let geoip = GeoIp {
+            ip: "1.2.3.4".parse().unwrap(),
+            location: Some(germany()),
+        };
+        assert_eq!(req.extensions().get::<GeoIp>(), Some(&geoip));
+        assert_eq!(req.extensions().get::<crate::UpstreamProxy>(), Some(&proxy));
+
+        let res = handler
+            .handle_response(
+                &ctx,
+                Response::new(Body::from(http_body_util::Empty::new())),
+            )
+            .await;
+        assert_eq!(res.extensions().get::<GeoIp>(), Some(&geoip));
+    }
+}
@@ -0,0 +1,224 @@
+//! Draining connections and restarting a proxy without downtime.
+//!
+//! A [`Handoff`] that is set with [`ProxyBuilder::with_handoff`] can pass the proxy's listening
+//! socket to a successor process, and then drain the proxy: it stops accepting connections, but
+//! finishes the flows that are in flight before [`Proxy::start`] returns. Because both processes
+//! share the socket, connections that arrive during the upgrade wait in its backlog until the
+//! successor accepts them instead of being refused.
+//!
+//! The successor is spawned with [`Handoff::spawn_successor`], which makes it inherit the socket
+//! and sets [`LISTEN_FD`] to its file descriptor. A proxy built with
+//! [`ProxyBuilder::with_inherited_listener`] listens on that socket, or binds a new one if the
+//! process was not started by a predecessor.
+//!
+//! [`Proxy::start`]: crate::Proxy::start
+//! [`ProxyBuilder::with_handoff`]: crate::builder::ProxyBuilder::with_handoff
+//! [`ProxyBuilder::with_inherited_listener`]: crate::builder::ProxyBuilder::with_inherited_listener
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::{handoff::Handoff, Proxy};
+//! # use hudsucker::{
+//! #     certificate_authority::RcgenAuthority,
+//! #     rcgen::{CertificateParams, KeyPair},
+//! # };
+//! use std::net::SocketAddr;
+//! use tokio::signal::unix::{signal, SignalKind};
+//!
+//! # #[cfg(all(feature = "rcgen-ca", feature = "rustls-client"))]
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! # let key_pair = include_str!("../examples/ca/hudsucker.key");
+//! # let ca_cert = include_str!("../examples/ca/hudsucker.cer");
+//! # let key_pair = KeyPair::from_pem(key_pair)?;
+//! # let ca_cert = CertificateParams::from_ca_cert_pem(ca_cert)?.self_signed(&key_pair)?;
+//! # let ca = RcgenAuthority::new(key_pair, ca_cert, 1_000);
+//! let handoff = Handoff::new();
+//!
+//! let proxy = Proxy::builder()
+//!     .with_inherited_listener(SocketAddr::from(([127, 0, 0, 1], 3000)))
+//!     .with_rustls_client()
+//!     .with_ca(ca)
+//!     .with_handoff(handoff.clone())
+//!     .build();
+//!
+//! // Start a new version of the proxy on SIGHUP, then drain this one.
+//! tokio::spawn(async move {
+//!     signal(SignalKind::hangup())?.recv().await;
+//!     let exe = std::env::current_exe()?;
+//!     handoff.spawn_successor(&mut std::process::Command::new(exe))?;
+//!     handoff.drain();
+//!     Ok::<_, std::io::Error>(())
+//! });
+//!
+//! proxy.start().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use socket2::Socket;
+use std::{
+    io,
+    net::TcpListener,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::watch;
+
+/// The environment variable that holds the file descriptor of an inherited listening socket.
+pub const LISTEN_FD: &str = "HUDSUCKER_LISTEN_FD";
+
+/// Whether the inherited listening socket has been taken by this process.
+static INHERITED: AtomicBool = AtomicBool::new(false);
+
+/// A handle for handing a running proxy's listening socket to a successor and draining it.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone, Debug, Default)]
+pub struct Handoff {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    listener: Mutex<Option<OwnedFd>>,
+    draining: watch::Sender<bool>,
+}
+
+impl Handoff {
+    /// Creates a handle that is not attached to a proxy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Duplicates the listening socket of the proxy, so that it can be passed to another process.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proxy has not started or has been drained, or if the socket cannot
+    /// be duplicated.
+    pub fn listener(&self) -> io::Result<OwnedFd> {
+        self.shared
+            .listener
+            .lock()
+            .expect("Failed to lock handoff listener")
+            .as_ref()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "the proxy is not listening")
+            })?
+            .try_clone()
+    }
+
+    /// Spawns a successor that inherits the listening socket of the proxy, with [`LISTEN_FD`] set
+    /// to its file descriptor.
+    ///
+    /// The socket is only inheritable while the command is spawned, but other commands that are
+    /// spawned at the same time by other threads inherit it too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket cannot be duplicated, or if the command cannot be spawned.
+    pub fn spawn_successor(&self, command: &mut Command) -> io::Result<Child> {
+        let socket = Socket::from(self.listener()?);
+        socket.set_cloexec(false)?;
+        command.env(LISTEN_FD, socket.as_raw_fd().to_string());
+        command.spawn()
+    }
+
+    /// Stops accepting connections, so that the proxy shuts down once the flows in flight finish.
+    pub fn drain(&self) {
+        self.shared.draining.send_replace(true);
+    }
+
+    /// Whether the proxy has been drained.
+    pub fn is_draining(&self) -> bool {
+        *self.shared.draining.borrow()
+    }
+
+    pub(crate) fn set_listener(&self, listener: Option<OwnedFd>) {
+        *self
+            .shared
+            .listener
+            .lock()
+            .expect("Failed to lock handoff listener") = listener;
+    }
+
+    pub(crate) async fn drained(&self) {
+        let mut draining = self.shared.draining.subscribe();
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+}
+
+/// Takes the listening socket that this process inherited from a predecessor, if [`LISTEN_FD`] is
+/// set. The variable is removed, and the socket is only returned by the first call.
+///
+/// # Errors
+///
+/// Returns an error if [`LISTEN_FD`] is not a file descriptor of a socket.
+pub fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    let Some(fd) = std::env::var_os(LISTEN_FD) else {
+        return Ok(None);
+    };
+
+    if INHERITED.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+
+    std::env::remove_var(LISTEN_FD);
+
+    let fd: RawFd = fd
+        .to_str()
+        .and_then(|fd| fd.parse().ok())
+        .filter(|fd| *fd > 2)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a file descriptor", LISTEN_FD),
+            )
+        })?;
+
+    // SAFETY: the descriptor was left open for this process by `Handoff::spawn_successor`, and is
+    // only taken once.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    socket.local_addr()?;
+    socket.set_cloexec(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(Some(socket.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsFd;
+
+    #[test]
+    fn passes_listeners_to_successors() {
+        let handoff = Handoff::new();
+        assert_eq!(
+            handoff.listener().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        handoff.set_listener(Some(listener.as_fd().try_clone_to_owned().unwrap()));
+
+        let status = handoff
+            .spawn_successor(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("test -e /dev/fd/${}", LISTEN_FD)),
+            )
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+
+        assert!(!handoff.is_draining());
+        handoff.drain();
+        assert!(handoff.is_draining());
+    }
+}
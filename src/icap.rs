@@ -0,0 +1,738 @@
+//! Content adaptation with an ICAP server.
+//!
+//! [`IcapHandler`] sends requests and responses to the REQMOD and RESPMOD services of an ICAP
+//! server ([RFC 3507]), such as an antivirus scanner or a DLP appliance, and forwards the messages
+//! that the server returns in their place. A REQMOD service may also answer a request with a
+//! response, such as a block page, which is returned to the client without forwarding the request.
+//!
+//! Bodies are collected before they are sent to the server, so bodies larger than
+//! [`IcapHandler::with_max_body`] are forwarded without being adapted. If the server can't be
+//! reached or fails, messages are forwarded without being adapted, unless
+//! [`IcapHandler::with_fail_closed`] is set.
+//!
+//! [RFC 3507]: https://www.rfc-editor.org/rfc/rfc3507
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::icap::IcapHandler;
+//!
+//! let handler = IcapHandler::new()
+//!     .with_reqmod("icap://127.0.0.1:1344/reqmod".parse().unwrap())
+//!     .with_respmod("icap://127.0.0.1:1344/respmod".parse().unwrap());
+//! ```
+
+use crate::{
+    Body, BodyDirection, Bounded, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::request::Parts,
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use std::{fmt::Write as _, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{debug, warn};
+
+/// The port of ICAP servers, if their URI doesn't have one.
+const DEFAULT_PORT: u16 = 1344;
+
+/// The longest line of an ICAP message head or chunked body that is accepted.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Debug)]
+struct Config {
+    reqmod: Option<Uri>,
+    respmod: Option<Uri>,
+    max_body: usize,
+    timeout: Duration,
+    fail_closed: bool,
+}
+
+/// A message that an ICAP server returned in place of the one it was sent.
+#[derive(Debug, Default, PartialEq)]
+struct Adapted {
+    request: Option<Vec<u8>>,
+    response: Option<Vec<u8>>,
+    body: Bytes,
+}
+
+/// An HTTP handler that adapts requests and responses with an ICAP server.
+///
+/// See the [module documentation](self) for an example. Requests and responses are passed to the
+/// wrapped handler before they are sent to the server.
+#[derive(Clone, Debug)]
+pub struct IcapHandler<H = NoopHandler> {
+    config: Arc<Config>,
+    /// The encoded head of the current request, which is sent with its response to RESPMOD.
+    request: Option<Vec<u8>>,
+    inner: H,
+}
+
+impl IcapHandler {
+    /// Creates a new handler with no ICAP services, which forwards messages unmodified.
+    pub fn new() -> Self {
+        Self {
+            config: Arc::new(Config {
+                reqmod: None,
+                respmod: None,
+                max_body: 1024 * 1024,
+                timeout: Duration::from_secs(30),
+                fail_closed: false,
+            }),
+            request: None,
+            inner: NoopHandler::default(),
+        }
+    }
+}
+
+impl Default for IcapHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> IcapHandler<H> {
+    /// Set the handler that requests and responses are passed to before they are adapted.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> IcapHandler<H2> {
+        IcapHandler {
+            config: self.config,
+            request: None,
+            inner,
+        }
+    }
+
+    /// Send requests to a REQMOD service, such as `icap://127.0.0.1:1344/reqmod`.
+    pub fn with_reqmod(mut self, service: Uri) -> Self {
+        self.config_mut().reqmod = Some(service);
+        self
+    }
+
+    /// Send responses to a RESPMOD service, such as `icap://127.0.0.1:1344/respmod`.
+    pub fn with_respmod(mut self, service: Uri) -> Self {
+        self.config_mut().respmod = Some(service);
+        self
+    }
+
+    /// Set the largest body that is sent to the ICAP server. Larger messages are forwarded
+    /// without being adapted. Defaults to 1 MiB.
+    pub fn with_max_body(mut self, max: usize) -> Self {
+        self.config_mut().max_body = max;
+        self
+    }
+
+    /// Set how long an exchange with the ICAP server may take before it fails. Defaults to 30
+    /// seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().timeout = timeout;
+        self
+    }
+
+    /// Set whether messages are answered with `502 Bad Gateway` when the ICAP server fails,
+    /// instead of being forwarded without being adapted. Defaults to `false`.
+    pub fn with_fail_closed(mut self, fail_closed: bool) -> Self {
+        self.config_mut().fail_closed = fail_closed;
+        self
+    }
+
+    fn config_mut(&mut self) -> &mut Config {
+        Arc::get_mut(&mut self.config).expect("ICAP handler configured after it was cloned")
+    }
+
+    /// Sends a message to an ICAP service, returning the adapted message, or `None` if the server
+    /// responded with `204 No Content`.
+    async fn exchange(
+        &self,
+        service: &Uri,
+        method: &str,
+        sections: &[(&str, &[u8])],
+        direction: BodyDirection,
+        body: &[u8],
+    ) -> io::Result<Option<Adapted>> {
+        let exchange = async {
+            let host = service
+                .host()
+                .ok_or_else(|| invalid("ICAP service has no host"))?;
+            let port = service.port_u16().unwrap_or(DEFAULT_PORT);
+            let mut stream = TcpStream::connect((host, port)).await?;
+
+            let message = encode_message(method, service, sections, direction, body);
+            stream.write_all(&message).await?;
+
+            read_message(&mut BufReader::new(stream), self.config.max_body).await
+        };
+
+        tokio::time::timeout(self.config.timeout, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "ICAP exchange timed out"))?
+    }
+
+    async fn reqmod(&self, service: &Uri, req: Request<Body>) -> RequestOrResponse {
+        let (mut parts, body) = req.into_parts();
+
+        let data = match body.collect_up_to(self.config.max_body).await {
+            Ok(Bounded::Complete { data, .. }) => data,
+            Ok(Bounded::TooLarge(body)) => {
+                debug!("Not adapting request to {} with a large body", parts.uri);
+                return Request::from_parts(parts, body).into();
+            }
+            Err(_) => {
+                return RequestOrResponse::respond(StatusCode::BAD_REQUEST, "Bad Request");
+            }
+        };
+
+        let head = request_head(&parts);
+        let adapted = self
+            .exchange(
+                service,
+                "REQMOD",
+                &[("req-hdr", &head)],
+                BodyDirection::Request,
+                &data,
+            )
+            .await;
+
+        let error = match adapted {
+            Ok(Some(Adapted {
+                response: Some(head),
+                body,
+                ..
+            })) => match parse_response(&head, body) {
+                Ok(res) => return res.into(),
+                Err(e) => e,
+            },
+            Ok(Some(Adapted {
+                request: Some(head),
+                body,
+                ..
+            })) => match apply_request_head(&mut parts, &head) {
+                Ok(()) => return Request::from_parts(parts, Body::from(Full::new(body))).into(),
+                Err(e) => e,
+            },
+            Ok(_) => return Request::from_parts(parts, Body::from(Full::new(data))).into(),
+            Err(e) => e,
+        };
+
+        match self.failed(service, "request", error) {
+            Some(res) => res.into(),
+            None => Request::from_parts(parts, Body::from(Full::new(data))).into(),
+        }
+    }
+
+    async fn respmod(&self, service: &Uri, res: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = res.into_parts();
+
+        let data = match body.collect_up_to(self.config.max_body).await {
+            Ok(Bounded::Complete { data, .. }) => data,
+            Ok(Bounded::TooLarge(body)) => {
+                debug!("Not adapting response with a large body");
+                return Response::from_parts(parts, body);
+            }
+            Err(_) => return bad_gateway(),
+        };
+
+        let head = encode_head(
+            format!(
+                "HTTP/1.1 {} {}",
+                parts.status.as_u16(),
+                parts.status.canonical_reason().unwrap_or("")
+            ),
+            &parts.headers,
+        );
+
+        let mut sections = Vec::new();
+        if let Some(request) = &self.request {
+            sections.push(("req-hdr", request.as_slice()));
+        }
+        sections.push(("res-hdr", head.as_slice()));
+
+        let adapted = self
+            .exchange(
+                service,
+                "RESPMOD",
+                &sections,
+                BodyDirection::Response,
+                &data,
+            )
+            .await;
+
+        let error = match adapted {
+            Ok(Some(Adapted {
+                response: Some(head),
+                body,
+                ..
+            })) => match parse_response(&head, body) {
+                Ok(adapted) => {
+                    let (adapted, body) = adapted.into_parts();
+                    parts.status = adapted.status;
+                    parts.headers = adapted.headers;
+                    return Response::from_parts(parts, body);
+                }
+                Err(e) => e,
+            },
+            Ok(_) => return Response::from_parts(parts, Body::from(Full::new(data))),
+            Err(e) => e,
+        };
+
+        self.failed(service, "response", error)
+            .unwrap_or_else(|| Response::from_parts(parts, Body::from(Full::new(data))))
+    }
+
+    /// Logs a failed exchange, returning the response that is sent instead of the message if the
+    /// handler fails closed.
+    fn failed(&self, service: &Uri, kind: &str, error: io::Error) -> Option<Response<Body>> {
+        warn!("Failed to adapt {} with {}: {}", kind, service, error);
+        self.config.fail_closed.then(bad_gateway)
+    }
+}
+
+fn bad_gateway() -> Response<Body> {
+    let mut res = Response::new(Body::from("Bad Gateway"));
+    *res.status_mut() = StatusCode::BAD_GATEWAY;
+    res
+}
+
+/// Encodes the start line and headers of an HTTP message.
+fn encode_head(start: String, headers: &HeaderMap) -> Vec<u8> {
+    let mut head = start.into_bytes();
+    head.extend_from_slice(b"\r\n");
+
+    for (name, value) in headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn request_head(parts: &Parts) -> Vec<u8> {
+    encode_head(
+        format!("{} {} HTTP/1.1", parts.method, parts.uri),
+        &parts.headers,
+    )
+}
+
+/// Encodes an ICAP request with encapsulated HTTP message heads and a chunked body.
+fn encode_message(
+    method: &str,
+    service: &Uri,
+    sections: &[(&str, &[u8])],
+    direction: BodyDirection,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut encapsulated = String::new();
+    let mut offset = 0;
+
+    for (name, section) in sections {
+        let _ = write!(encapsulated, "{}={}, ", name, offset);
+        offset += section.len();
+    }
+
+    let body_name = match (body.is_empty(), direction) {
+        (true, _) => "null-body",
+        (false, BodyDirection::Request) => "req-body",
+        (false, BodyDirection::Response) => "res-body",
+    };
+    let _ = write!(encapsulated, "{}={}", body_name, offset);
+
+    let mut message = format!(
+        "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: {}\r\n\r\n",
+        method,
+        service,
+        service
+            .authority()
+            .map_or("", |authority| authority.as_str()),
+        encapsulated
+    )
+    .into_bytes();
+
+    for (_, section) in sections {
+        message.extend_from_slice(section);
+    }
+
+    if !body.is_empty() {
+        message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        message.extend_from_slice(body);
+        message.extend_from_slice(b"\r\n0\r\n\r\n");
+    }
+
+    message
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if read == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    if !line.ends_with(b"\n") {
+        return Err(invalid("ICAP line too long"));
+    }
+
+    String::from_utf8(line)
+        .map(|line| line.trim_end_matches(['\r', '\n']).to_owned())
+        .map_err(|_| invalid("ICAP line is not UTF-8"))
+}
+
+/// Reads an ICAP response, returning the encapsulated messages, or `None` if the message was not
+/// modified.
+async fn read_message<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_body: usize,
+) -> io::Result<Option<Adapted>> {
+    let status = read_line(reader).await?;
+    let code = status
+        .strip_prefix("ICAP/1.0 ")
+        .and_then(|status| status.get(..3))
+        .ok_or_else(|| invalid("malformed ICAP status line"))?;
+
+    let mut encapsulated = None;
+
+    loop {
+        let line = read_line(reader).await?;
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("encapsulated") {
+                encapsulated = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    match code {
+        "204" => return Ok(None),
+        "200" => {}
+        _ => {
+            return Err(io::Error::other(format!(
+                "ICAP server responded {}",
+                status
+            )))
+        }
+    }
+
+    let mut entries = Vec::new();
+
+    for entry in encapsulated
+        .ok_or_else(|| invalid("missing Encapsulated header"))?
+        .split(',')
+    {
+        let (name, offset) = entry
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| invalid("malformed Encapsulated header"))?;
+        let offset = offset
+            .parse::<usize>()
+            .map_err(|_| invalid("malformed Encapsulated header"))?;
+        entries.push((name.to_owned(), offset));
+    }
+
+    let Some((last, heads_len)) = entries.last().cloned() else {
+        return Err(invalid("empty Encapsulated header"));
+    };
+
+    if heads_len > MAX_LINE_LEN || entries.windows(2).any(|pair| pair[0].1 > pair[1].1) {
+        return Err(invalid("malformed Encapsulated header"));
+    }
+
+    let mut heads = vec![0; heads_len];
+    reader.read_exact(&mut heads).await?;
+
+    let mut adapted = Adapted::default();
+
+    for (i, (name, offset)) in entries[..entries.len() - 1].iter().enumerate() {
+        let section = heads[*offset..entries[i + 1].1].to_vec();
+
+        match name.as_str() {
+            "req-hdr" => adapted.request = Some(section),
+            "res-hdr" => adapted.response = Some(section),
+            _ => {}
+        }
+    }
+
+    if last.ends_with("-body") && last != "null-body" {
+        adapted.body = read_chunked(reader, max_body).await?;
+    }
+
+    Ok(Some(adapted))
+}
+
+/// Reads a chunked body of at most `max` bytes.
+async fn read_chunked<R: AsyncBufRead + Unpin>(reader: &mut R, max: usize) -> io::Result<Bytes> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("malformed chunk size"))?;
+
+        if size == 0 {
+            while !read_line(reader).await?.is_empty() {}
+            return Ok(body.into());
+        }
+
+        if body.len() + size > max {
+            return Err(invalid("adapted body too large"));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+
+        if !read_line(reader).await?.is_empty() {
+            return Err(invalid("malformed chunk"));
+        }
+    }
+}
+
+/// Parses the start line and headers of an encapsulated HTTP message.
+fn parse_head(head: &[u8]) -> io::Result<(String, HeaderMap)> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid("HTTP head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let start = lines.next().unwrap_or_default().to_owned();
+    let mut headers = HeaderMap::new();
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed HTTP header"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| invalid("malformed HTTP header"))?;
+        let value =
+            HeaderValue::from_str(value.trim()).map_err(|_| invalid("malformed HTTP header"))?;
+
+        // The body is forwarded in full, so hyper sets its framing.
+        if name != CONTENT_LENGTH && name != TRANSFER_ENCODING {
+            headers.append(name, value);
+        }
+    }
+
+    Ok((start, headers))
+}
+
+fn apply_request_head(parts: &mut Parts, head: &[u8]) -> io::Result<()> {
+    let (start, headers) = parse_head(head)?;
+    let mut start = start.split_whitespace();
+
+    let (Some(method), Some(uri)) = (start.next(), start.next()) else {
+        return Err(invalid("malformed HTTP request line"));
+    };
+
+    let method = Method::from_bytes(method.as_bytes())
+        .map_err(|_| invalid("malformed HTTP request line"))?;
+    let uri = uri
+        .parse()
+        .map_err(|_| invalid("malformed HTTP request line"))?;
+
+    parts.method = method;
+    parts.uri = uri;
+    parts.headers = headers;
+    Ok(())
+}
+
+fn parse_response(head: &[u8], body: Bytes) -> io::Result<Response<Body>> {
+    let (start, headers) = parse_head(head)?;
+    let status = start
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| invalid("malformed HTTP status line"))?;
+
+    let mut res = Response::new(Body::from(Full::new(body)));
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    Ok(res)
+}
+
+impl<H: HttpHandler> HttpHandler for IcapHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let req = match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => req,
+            res => return res,
+        };
+
+        if req.method() == Method::CONNECT {
+            return req.into();
+        }
+
+        let (parts, body) = req.into_parts();
+        self.request = Some(request_head(&parts));
+        let req = Request::from_parts(parts, body);
+
+        match self.config.reqmod.clone() {
+            Some(service) => self.reqmod(&service, req).await,
+            None => req.into(),
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.inner.handle_response(ctx, res).await;
+
+        match self.config.respmod.clone() {
+            Some(service) => self.respmod(&service, res).await,
+            None => res,
+        }
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_messages() {
+        let service = Uri::from_static("icap://icap.test/respmod");
+        let message = encode_message(
+            "RESPMOD",
+            &service,
+            &[
+                ("req-hdr", b"GET / HTTP/1.1\r\n\r\n"),
+                ("res-hdr", b"HTTP/1.1 200 OK\r\n\r\n"),
+            ],
+            BodyDirection::Response,
+            b"hello",
+        );
+
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "RESPMOD icap://icap.test/respmod ICAP/1.0\r\n\
+             Host: icap.test\r\n\
+             Allow: 204\r\n\
+             Encapsulated: req-hdr=0, res-hdr=18, res-body=37\r\n\r\n\
+             GET / HTTP/1.1\r\n\r\n\
+             HTTP/1.1 200 OK\r\n\r\n\
+             5\r\nhello\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_adapted_messages() {
+        let message = "ICAP/1.0 200 OK\r\n\
+                       Encapsulated: res-hdr=0, res-body=34\r\n\r\n\
+                       HTTP/1.1 403 Forbidden\r\n\
+                       x-a: b\r\n\r\n\
+                       3; ieof\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let adapted = read_message(&mut message.as_bytes(), 1024)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(adapted.request, None);
+        assert_eq!(
+            adapted.response.unwrap(),
+            b"HTTP/1.1 403 Forbidden\r\nx-a: b\r\n\r\n"
+        );
+        assert_eq!(adapted.body, "abcde");
+
+        let unmodified = "ICAP/1.0 204 No Content\r\nEncapsulated: null-body=0\r\n\r\n";
+        assert_eq!(
+            read_message(&mut unmodified.as_bytes(), 1024)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let failed = "ICAP/1.0 500 Server Error\r\n\r\n";
+        assert!(read_message(&mut failed.as_bytes(), 1024).await.is_err());
+    }
+
+    #[test]
+    fn parses_heads() {
+        let res = parse_response(
+            b"HTTP/1.1 403 Forbidden\r\nContent-Length: 3\r\nX-Blocked: yes\r\n\r\n",
+            Bytes::from_static(b"no!"),
+        )
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers()["x-blocked"], "yes");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+    }
+}
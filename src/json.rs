@@ -0,0 +1,295 @@
+//! Viewing and editing JSON bodies.
+//!
+//! [`from_request`] and [`from_response`] read a JSON body into a [`Value`] that can be modified,
+//! decoding it first if it has a `Content-Encoding`. [`into_request`] and [`into_response`]
+//! serialize the value back into a body, and correct the `Content-Length`, `Content-Encoding` and
+//! `Transfer-Encoding` headers to match it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     hyper::Response,
+//!     json::{self, Parsed},
+//!     serde_json::Value,
+//!     Body, HttpContext, HttpHandler,
+//! };
+//!
+//! #[derive(Clone)]
+//! struct Redact;
+//!
+//! impl HttpHandler for Redact {
+//!     async fn handle_response(
+//!         &mut self,
+//!         _ctx: &HttpContext,
+//!         res: Response<Body>,
+//!     ) -> Response<Body> {
+//!         match json::from_response(res, 1024 * 1024).await {
+//!             Ok(Parsed::Json { parts, mut value }) => {
+//!                 if let Some(object) = value.as_object_mut() {
+//!                     object.insert("token".to_owned(), Value::Null);
+//!                 }
+//!
+//!                 json::into_response(parts, &value)
+//!             }
+//!             Ok(Parsed::Unchanged(res)) => res,
+//!             Err(_) => Response::new(Body::from("")),
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{decode_request, Body, Bounded, Error};
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING,
+    },
+    http::{request, response},
+    Request, Response,
+};
+use serde_json::Value;
+
+/// The result of [`from_request`] or [`from_response`].
+#[derive(Debug)]
+pub enum Parsed<T, P> {
+    /// The message had a JSON body.
+    Json {
+        /// The message, without its body.
+        parts: P,
+        /// The decoded body of the message.
+        value: Value,
+    },
+    /// The message did not have a JSON content type, its body exceeded the limit, or its body
+    /// could not be decoded or parsed.
+    ///
+    /// The message yields the same body as the original message.
+    Unchanged(T),
+}
+
+/// Whether the `Content-Type` header is `application/json`, or another type with a `+json`
+/// suffix.
+pub fn is_json(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime == "application/json" || (mime.contains('/') && mime.ends_with("+json"))
+}
+
+/// Reads a JSON body, returning `Err` with a body that yields the original data if it is not
+/// used.
+async fn read(headers: &HeaderMap, body: Body, max: usize) -> Result<Result<Value, Body>, Error> {
+    if !is_json(headers) {
+        return Ok(Err(body));
+    }
+
+    let data = match body.collect_up_to(max).await? {
+        Bounded::Complete { data, .. } => data,
+        Bounded::TooLarge(body) => return Ok(Err(body)),
+    };
+
+    let unchanged = |data: Bytes| Ok(Err(Body::from(Full::new(data))));
+
+    let decoded = if headers.contains_key(CONTENT_ENCODING) {
+        let mut req = Request::new(Body::from(Full::new(data.clone())));
+        *req.headers_mut() = headers.clone();
+
+        let Ok(req) = decode_request(req) else {
+            return unchanged(data);
+        };
+
+        match req.into_body().collect_up_to(max).await {
+            Ok(Bounded::Complete { data, .. }) => data,
+            Ok(Bounded::TooLarge(_)) | Err(_) => return unchanged(data),
+        }
+    } else {
+        data.clone()
+    };
+
+    match serde_json::from_slice(&decoded) {
+        Ok(value) => Ok(Ok(value)),
+        Err(_) => unchanged(data),
+    }
+}
+
+fn encode(headers: &mut HeaderMap, value: &Value) -> Body {
+    let data = serde_json::to_vec(value).expect("Failed to serialize JSON");
+
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(data.len()));
+
+    Body::from(Full::new(Bytes::from(data)))
+}
+
+/// Reads the JSON body of a request, if it is no larger than `max` bytes before and after
+/// decoding.
+///
+/// # Errors
+///
+/// Returns an error if the request body fails before it has been read.
+pub async fn from_request(
+    req: Request<Body>,
+    max: usize,
+) -> Result<Parsed<Request<Body>, request::Parts>, Error> {
+    let (parts, body) = req.into_parts();
+
+    Ok(match read(&parts.headers, body, max).await? {
+        Ok(value) => Parsed::Json { parts, value },
+        Err(body) => Parsed::Unchanged(Request::from_parts(parts, body)),
+    })
+}
+
+/// Reads the JSON body of a response, if it is no larger than `max` bytes before and after
+/// decoding.
+///
+/// # Errors
+///
+/// Returns an error if the response body fails before it has been read.
+pub async fn from_response(
+    res: Response<Body>,
+    max: usize,
+) -> Result<Parsed<Response<Body>, response::Parts>, Error> {
+    let (parts, body) = res.into_parts();
+
+    Ok(match read(&parts.headers, body, max).await? {
+        Ok(value) => Parsed::Json { parts, value },
+        Err(body) => Parsed::Unchanged(Response::from_parts(parts, body)),
+    })
+}
+
+/// Creates a request with a JSON body.
+///
+/// The body is not encoded, and the `Content-Length` header is set to its length.
+///
+/// # Panics
+///
+/// Panics if the value cannot be serialized.
+pub fn into_request(mut parts: request::Parts, value: &Value) -> Request<Body> {
+    let body = encode(&mut parts.headers, value);
+    Request::from_parts(parts, body)
+}
+
+/// Creates a response with a JSON body.
+///
+/// The body is not encoded, and the `Content-Length` header is set to its length.
+///
+/// # Panics
+///
+/// Panics if the value cannot be serialized.
+pub fn into_response(mut parts: response::Parts, value: &Value) -> Response<Body> {
+    let body = encode(&mut parts.headers, value);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipEncoder;
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use tokio_util::io::ReaderStream;
+
+    fn response(content_type: &'static str, body: Body) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap()
+    }
+
+    async fn to_bytes(body: Body) -> Bytes {
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn detects_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_json(&headers));
+
+        for (content_type, expected) in [
+            ("application/json", true),
+            ("Application/JSON; charset=utf-8", true),
+            ("application/problem+json", true),
+            ("text/plain", false),
+            ("application/jsonp", false),
+        ] {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            assert_eq!(is_json(&headers), expected, "{}", content_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn edits_request() {
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, "13")
+            .body(Body::from(r#"{"a":1,"b":2}"#))
+            .unwrap();
+
+        let Parsed::Json { parts, mut value } = from_request(req, 1024).await.unwrap() else {
+            panic!("body was not parsed");
+        };
+
+        value["a"] = json!("changed");
+
+        let req = into_request(parts, &value);
+        assert_eq!(req.headers()[CONTENT_LENGTH], "21");
+        assert_eq!(to_bytes(req.into_body()).await, r#"{"a":"changed","b":2}"#);
+    }
+
+    #[tokio::test]
+    async fn decodes_response() {
+        let encoder = GzipEncoder::new(&br#"{"a":1}"#[..]);
+        let mut res = response(
+            "application/json",
+            Body::wrap_stream(ReaderStream::new(encoder)),
+        );
+        res.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        res.headers_mut()
+            .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+
+        let Parsed::Json { parts, value } = from_response(res, 1024).await.unwrap() else {
+            panic!("body was not parsed");
+        };
+        assert_eq!(value, json!({"a": 1}));
+
+        let res = into_response(parts, &value);
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert!(!res.headers().contains_key(TRANSFER_ENCODING));
+        assert_eq!(res.headers()[CONTENT_LENGTH], "7");
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_unchanged() {
+        for res in [
+            response("text/plain", Body::from("{}")),
+            response("application/json", Body::from("not json")),
+            response("application/json", Body::from(r#"{"too":"large"}"#)),
+        ] {
+            let Parsed::Unchanged(res) = from_response(res, 10).await.unwrap() else {
+                panic!("body was parsed");
+            };
+            assert!(!to_bytes(res.into_body()).await.is_empty());
+        }
+
+        let mut res = response("application/json", Body::from("{}"));
+        res.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("unknown"));
+
+        let Parsed::Unchanged(res) = from_response(res, 10).await.unwrap() else {
+            panic!("body was parsed");
+        };
+        assert_eq!(res.headers()[CONTENT_ENCODING], "unknown");
+        assert_eq!(to_bytes(res.into_body()).await, "{}");
+    }
+}
@@ -8,30 +8,143 @@
 //!
 //! ## Features
 //!
-//! - `decoder`: Enables [`decode_request`] and [`decode_response`] helpers (enabled by default).
+//! - `adblock`: Enables the [`adblock`] module for filtering requests with adblock filter lists,
+//!   such as EasyList.
+//! - `admin`: Enables the [`admin`] module for managing a running proxy through an embedded REST
+//!   API.
+//! - `audit`: Enables the [`audit`] module for tamper-evident logging of modifications made by
+//!   handlers.
+//! - `blocklist`: Enables the [`blocklist`] module for blocking hosts and URLs before they are
+//!   contacted.
+//! - `cache`: Enables the [`cache`] module for caching upstream responses.
+//! - `capture`: Enables the [`capture`] module for capturing the traffic of a child process.
+//! - `cookies`: Enables the [`cookies`] module for tracking cookies for each client.
+//! - `decoder`: Enables [`decode_request`], [`decode_response`] and [`encode_response`] helpers
+//!   (enabled by default).
+//! - `diff`: Enables the [`diff`] module for comparing responses, such as replayed ones.
+//! - `dns`: Enables the [`dns`] module for intercepting DNS-over-HTTPS and DNS-over-TLS messages.
+//! - `events`: Enables the [`events`] module for streaming live proxy events to a UI.
+//! - `fingerprint`: Enables the [`fingerprint`] module for identifying clients by their TLS
+//!   fingerprints.
 //! - `full`: Enables all features.
+//! - `geoip`: Enables the [`geoip`] module for locating and routing requests by the country of
+//!   their upstream servers.
+//! - `handoff`: Enables the [`handoff`] module for draining a proxy and passing its listener to a
+//!   successor process on Unix.
 //! - `http2`: Enables HTTP/2 support.
+//! - `icap`: Enables the [`icap`] module for adapting requests and responses with an ICAP server.
+//! - `json`: Enables the [`json`] module for viewing and editing JSON bodies.
+//! - `kafka`: Enables [`events::KafkaSink`] for streaming events to a Kafka topic.
+//! - `nats`: Enables [`events::NatsSink`] for streaming events to a NATS subject.
 //! - `native-tls-client`: Enables [`ProxyBuilder::with_native_tls_client`].
 //! - `openssl-ca`: Enables [`certificate_authority::OpensslAuthority`].
 //! - `rcgen-ca`: Enables [`certificate_authority::RcgenAuthority`] (enabled by default).
+//! - `rules`: Enables the [`rules`] module for loading interception rules from a reloadable
+//!   configuration file.
 //! - `rustls-client`: Enables [`ProxyBuilder::with_rustls_client`] (enabled by default).
+//! - `sslstrip`: Enables the [`sslstrip`] module for downgrading HTTPS to HTTP in security
+//!   research deployments.
+//! - `test`: Enables the [`test`](mod@test) module with utilities for testing code that runs
+//!   through a proxy.
+//! - `vcr`: Enables the [`vcr`] module for recording and replaying upstream responses.
 
 mod body;
+mod combinators;
 #[cfg(feature = "decoder")]
 mod decoder;
 mod error;
+mod logging;
 mod noop;
+mod pool;
 mod proxy;
 mod rewind;
+mod split;
+mod stack;
 
+pub mod access_log;
+#[cfg(feature = "adblock")]
+#[cfg_attr(docsrs, doc(cfg(feature = "adblock")))]
+pub mod adblock;
+#[cfg(feature = "admin")]
+#[cfg_attr(docsrs, doc(cfg(feature = "admin")))]
+pub mod admin;
+#[cfg(feature = "audit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "audit")))]
+pub mod audit;
+pub mod auth;
+pub mod balancer;
+#[cfg(feature = "blocklist")]
+#[cfg_attr(docsrs, doc(cfg(feature = "blocklist")))]
+pub mod blocklist;
+#[cfg(feature = "cache")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cache")))]
+pub mod cache;
+#[cfg(feature = "capture")]
+#[cfg_attr(docsrs, doc(cfg(feature = "capture")))]
+pub mod capture;
 pub mod certificate_authority;
+#[cfg(feature = "cookies")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cookies")))]
+pub mod cookies;
+#[cfg(feature = "diff")]
+#[cfg_attr(docsrs, doc(cfg(feature = "diff")))]
+pub mod diff;
+#[cfg(feature = "dns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+pub mod dns;
+#[cfg(feature = "events")]
+#[cfg_attr(docsrs, doc(cfg(feature = "events")))]
+pub mod events;
+pub mod export;
+#[cfg(feature = "fingerprint")]
+#[cfg_attr(docsrs, doc(cfg(feature = "fingerprint")))]
+pub mod fingerprint;
+pub mod gateway;
+#[cfg(feature = "geoip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "geoip")))]
+pub mod geoip;
+#[cfg(all(feature = "handoff", unix))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "handoff", unix))))]
+pub mod handoff;
+#[cfg(feature = "icap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "icap")))]
+pub mod icap;
+#[cfg(feature = "json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+pub mod json;
+pub mod memory;
+pub mod mirror;
+pub mod mock;
+pub mod multipart;
+#[cfg(feature = "rules")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rules")))]
+pub mod rules;
+pub mod sampling;
+pub mod sniff;
+#[cfg(feature = "sslstrip")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sslstrip")))]
+pub mod sslstrip;
+#[cfg(feature = "test")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test")))]
+pub mod test;
+#[cfg(feature = "vcr")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vcr")))]
+pub mod vcr;
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use http_body_util::Empty;
-use hyper::{Request, Response, StatusCode, Uri};
-use std::{future::Future, net::SocketAddr};
-use tokio_tungstenite::tungstenite::{self, Message};
-use tracing::error;
+use hyper::{http::uri::Authority, Request, Response, StatusCode, Uri, Version};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use tracing::{error, warn};
 
 pub(crate) use rewind::Rewind;
 
@@ -42,25 +155,66 @@ pub use hyper_util;
 pub use openssl;
 #[cfg(feature = "rcgen-ca")]
 pub use rcgen;
+#[cfg(feature = "json")]
+pub use serde_json;
 pub use tokio_rustls::rustls;
 pub use tokio_tungstenite;
 
-pub use body::Body;
+pub use body::{Body, Bounded};
+pub use combinators::{FilterHosts, HttpHandlerExt, OnContentType, TeeResponses};
 #[cfg(feature = "decoder")]
-pub use decoder::{decode_request, decode_response};
+pub use decoder::{decode_request, decode_response, encode_response, CompressionLevel};
 pub use error::Error;
+pub use logging::LogHandler;
 pub use noop::*;
+pub use pool::BufferPool;
 pub use proxy::*;
+pub use split::{RequestHandler, ResponseHandler, SplitHandler};
+pub use stack::HandlerStack;
 
-/// Enum representing either an HTTP request or response.
+/// The decision of [`HttpHandler::handle_request`] about a request.
+///
+/// A handler either forwards a request, which may have been modified, to the upstream server, or
+/// responds to it directly. A direct response is sent to the client as it is, without contacting
+/// the upstream server and without being passed to [`HttpHandler::handle_response`]. This applies
+/// to every kind of request: a response to a `CONNECT` request rejects the tunnel before it is
+/// established, and a response to a WebSocket upgrade request rejects the WebSocket before the
+/// upstream server is dialed.
+///
+/// A forwarded request does not have to be sent to the server that the client asked for. Its URI
+/// can be replaced with one for any scheme and authority, such as to send an intercepted `http`
+/// request to an `https` service elsewhere. The proxy connects to the new authority with the
+/// client for its scheme and [`UpstreamProtocol`], and sets the `Host` header of the request to
+/// the new authority.
 #[derive(Debug)]
 pub enum RequestOrResponse {
-    /// HTTP Request
+    /// Forward the request to the upstream server.
     Request(Request<Body>),
-    /// HTTP Response
+    /// Respond to the client without contacting the upstream server.
     Response(Response<Body>),
 }
 
+impl RequestOrResponse {
+    /// Respond to the client with an empty response, without contacting the upstream server.
+    ///
+    /// `reason` is sent as the reason phrase of HTTP/1 responses, such as
+    /// `HTTP/1.1 403 Blocked by policy`, instead of the canonical reason of the status. It is
+    /// left out if it contains characters that are not allowed in a reason phrase, and HTTP/2
+    /// responses do not have reason phrases.
+    pub fn respond(status: StatusCode, reason: &str) -> Self {
+        let mut res = Response::builder()
+            .status(status)
+            .body(Empty::new().into())
+            .expect("Failed to build response");
+
+        if let Ok(reason) = hyper::ext::ReasonPhrase::try_from(reason.as_bytes()) {
+            res.extensions_mut().insert(reason);
+        }
+
+        Self::Response(res)
+    }
+}
+
 impl From<Request<Body>> for RequestOrResponse {
     fn from(req: Request<Body>) -> Self {
         Self::Request(req)
@@ -79,9 +233,165 @@ impl From<Response<Body>> for RequestOrResponse {
 pub struct HttpContext {
     /// Address of the client that is sending the request.
     pub client_addr: SocketAddr,
+    /// ID of the request and its response, unique within the process.
+    ///
+    /// The same ID is passed to every hook that is called for a request, so it can be used to
+    /// associate a call to [`HttpHandler::handle_request`] with the later call to
+    /// [`HttpHandler::handle_response`] or [`HttpHandler::handle_error`].
+    pub flow_id: u64,
+    /// Data that handlers attach to the request and its response.
+    pub extensions: FlowExtensions,
+    /// HTTP version of the connection between the client and the proxy.
+    pub downstream_version: Version,
+    /// HTTP version of the connection between the proxy and the upstream server, which may differ
+    /// from [`HttpContext::downstream_version`].
+    ///
+    /// This is `None` until a response has been received from the server, so it is only set for
+    /// [`HttpHandler::inspect_informational`] and [`HttpHandler::handle_response`].
+    pub upstream_version: Option<Version>,
+}
+
+#[cfg(test)]
+impl HttpContext {
+    /// A context for a request from `127.0.0.1:8080` over HTTP/1.1.
+    pub(crate) fn for_test() -> Self {
+        Self {
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: Version::HTTP_11,
+            upstream_version: None,
+        }
+    }
+}
+
+/// A typed map of data that handlers attach to a flow.
+///
+/// Each flow starts with an empty map, which is shared by every hook that is called for the flow,
+/// so that values inserted in [`HttpHandler::handle_request`] can be read in
+/// [`HttpHandler::handle_response`] or [`HttpHandler::handle_error`], and by the
+/// [`WebSocketHandler`] of a WebSocket that the request upgraded to. Clones of a map share the
+/// same values.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     hyper::{Request, Response},
+///     Body, HttpContext, HttpHandler, RequestOrResponse,
+/// };
+/// use std::time::Instant;
+///
+/// #[derive(Clone)]
+/// struct Timer;
+///
+/// impl HttpHandler for Timer {
+///     async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+///         ctx.extensions.insert(Instant::now());
+///         req.into()
+///     }
+///
+///     async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+///         if let Some(start) = ctx.extensions.get::<Instant>() {
+///             println!("{:?}", start.elapsed());
+///         }
+///         res
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FlowExtensions(Arc<Mutex<hyper::http::Extensions>>);
+
+impl FlowExtensions {
+    /// Insert a value, returning the previous value of the same type.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.lock().insert(value)
+    }
+
+    /// A clone of the value of a type.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock().get::<T>().cloned()
+    }
+
+    /// Whether there is a value of a type.
+    pub fn contains<T: Clone + Send + Sync + 'static>(&self) -> bool {
+        self.lock().get::<T>().is_some()
+    }
+
+    /// Remove the value of a type.
+    pub fn remove<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock().remove::<T>()
+    }
+
+    /// Modify the value of a type, inserting a default value if there is none.
+    pub fn update<T, F, R>(&self, f: F) -> R
+    where
+        T: Clone + Default + Send + Sync + 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        f(self.lock().get_or_insert_default::<T>())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, hyper::http::Extensions> {
+        self.0.lock().expect("Failed to lock flow extensions")
+    }
+}
+
+/// Flow extensions are equal if they are the same map.
+impl PartialEq for FlowExtensions {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for FlowExtensions {}
+
+impl std::hash::Hash for FlowExtensions {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state)
+    }
+}
+
+/// The flow ID of a request and its response.
+///
+/// This is inserted into the extensions of each request before it is passed to
+/// [`HttpHandler::handle_request`], and into the extensions of each upstream response before it is
+/// passed to [`HttpHandler::handle_response`]. It has the same value as [`HttpContext::flow_id`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FlowId(pub u64);
+
+impl std::fmt::Display for FlowId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The direction in which an HTTP body is sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "events", derive(serde::Serialize))]
+#[cfg_attr(feature = "events", serde(rename_all = "snake_case"))]
+pub enum BodyDirection {
+    /// The body of a request, sent from the client to the server.
+    Request,
+    /// The body of a response, sent from the server to the client.
+    Response,
+}
+
+/// The direction of a websocket message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "events", derive(serde::Serialize))]
+#[cfg_attr(feature = "events", serde(rename_all = "snake_case"))]
+pub enum WebSocketDirection {
+    /// A message sent from the client to the server.
+    ClientToServer,
+    /// A message sent from the server to the client.
+    ServerToClient,
 }
 
 /// Context for websocket messages.
+///
+/// Both directions of a websocket share the same socket ID, so a single handler can tell apart
+/// the messages of concurrent websockets.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum WebSocketContext {
     #[non_exhaustive]
@@ -90,6 +400,12 @@ pub enum WebSocketContext {
         src: SocketAddr,
         /// URI of the server.
         dst: Uri,
+        /// Unique ID of the websocket, which is the flow ID of its upgrade request.
+        socket_id: u64,
+        /// Subprotocol negotiated with the server, if any.
+        subprotocol: Option<String>,
+        /// Data that handlers attached to the upgrade request.
+        extensions: FlowExtensions,
     },
     #[non_exhaustive]
     ServerToClient {
@@ -97,16 +413,83 @@ pub enum WebSocketContext {
         src: Uri,
         /// Address of the client.
         dst: SocketAddr,
+        /// Unique ID of the websocket, which is the flow ID of its upgrade request.
+        socket_id: u64,
+        /// Subprotocol negotiated with the server, if any.
+        subprotocol: Option<String>,
+        /// Data that handlers attached to the upgrade request.
+        extensions: FlowExtensions,
     },
 }
 
+impl WebSocketContext {
+    /// Unique ID of the websocket, which is the same as [`HttpContext::flow_id`] for its upgrade
+    /// request.
+    pub fn socket_id(&self) -> u64 {
+        match self {
+            Self::ClientToServer { socket_id, .. } | Self::ServerToClient { socket_id, .. } => {
+                *socket_id
+            }
+        }
+    }
+
+    /// Address of the client.
+    pub fn client_addr(&self) -> SocketAddr {
+        match self {
+            Self::ClientToServer { src, .. } => *src,
+            Self::ServerToClient { dst, .. } => *dst,
+        }
+    }
+
+    /// URI of the server, with a `ws` or `wss` scheme.
+    pub fn uri(&self) -> &Uri {
+        match self {
+            Self::ClientToServer { dst, .. } => dst,
+            Self::ServerToClient { src, .. } => src,
+        }
+    }
+
+    /// The direction of the messages.
+    pub fn direction(&self) -> WebSocketDirection {
+        match self {
+            Self::ClientToServer { .. } => WebSocketDirection::ClientToServer,
+            Self::ServerToClient { .. } => WebSocketDirection::ServerToClient,
+        }
+    }
+
+    /// Subprotocol negotiated with the server, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        match self {
+            Self::ClientToServer { subprotocol, .. } | Self::ServerToClient { subprotocol, .. } => {
+                subprotocol.as_deref()
+            }
+        }
+    }
+
+    /// Data that handlers attached to the upgrade request, which is shared by both directions of
+    /// the WebSocket.
+    pub fn extensions(&self) -> &FlowExtensions {
+        match self {
+            Self::ClientToServer { extensions, .. } | Self::ServerToClient { extensions, .. } => {
+                extensions
+            }
+        }
+    }
+}
+
 /// Handler for HTTP requests and responses.
 ///
 /// Each request/response pair is passed to the same instance of the handler.
+///
+/// Request and response bodies that are passed through unchanged are streamed frame by frame as
+/// they arrive, without being buffered, and are sent with the same `Content-Length` or chunked
+/// `Transfer-Encoding` that they were received with. This is also true of bodies that are wrapped
+/// in a body that yields the same frames, but replacing a body with one of a known size may cause
+/// it to be sent with a `Content-Length` instead.
 pub trait HttpHandler: Clone + Send + Sync + 'static {
     /// This handler will be called for each HTTP request. It can either return a modified request,
     /// or a response. If a request is returned, it will be sent to the upstream server. If a
-    /// response is returned, it will be sent to the client.
+    /// response is returned, it will be sent to the client. See [`RequestOrResponse`] for details.
     fn handle_request(
         &mut self,
         _ctx: &HttpContext,
@@ -115,6 +498,34 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
         async { req.into() }
     }
 
+    /// This handler will be called for each HTTP request that has an `Expect: 100-continue`
+    /// header, before the request body has been read. If a response is returned, it will be sent
+    /// to the client as the final response and the request body will not be read. If `None` is
+    /// returned, the request will be passed to [`HttpHandler::handle_request`].
+    fn handle_expect_continue(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+    ) -> impl Future<Output = Option<Response<Body>>> + Send {
+        async { None }
+    }
+
+    /// This handler will be called for each informational (1xx) response received from an HTTP/1
+    /// upstream server, once the final response has been received and before
+    /// [`HttpHandler::handle_response`] is called.
+    ///
+    /// Informational responses are not forwarded to the client, as hyper's server can not send
+    /// them. Instead, the `Link` headers of the `103 Early Hints` responses that are returned by
+    /// this handler are added to the final response. Defaults to returning the response
+    /// unmodified.
+    fn inspect_informational(
+        &mut self,
+        _ctx: &HttpContext,
+        res: Response<()>,
+    ) -> impl Future<Output = Option<Response<()>>> + Send {
+        async { Some(res) }
+    }
+
     /// This handler will be called for each HTTP response. It can modify a response before it is
     /// forwarded to the client.
     fn handle_response(
@@ -125,6 +536,21 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
         async { res }
     }
 
+    /// This handler will be called when a request or response body exceeds the size limit set
+    /// with [`ProxyBuilder::with_max_request_body_size`] or
+    /// [`ProxyBuilder::with_max_response_body_size`].
+    ///
+    /// If the size of the body is known in advance, this will be called before the request or
+    /// response is rejected or passed to the other handlers. Otherwise it will be called from a
+    /// separate task while the body is being streamed.
+    fn handle_body_limit_exceeded(
+        &mut self,
+        _ctx: &HttpContext,
+        _direction: BodyDirection,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
     /// This handler will be called if a proxy request fails. Default response is a 502 Bad Gateway.
     fn handle_error(
         &mut self,
@@ -140,6 +566,23 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
         }
     }
 
+    /// This handler will be called instead of forwarding a request when the circuit breaker set
+    /// with [`ProxyBuilder::with_circuit_breaker`] is open for the request's host. Default response
+    /// is a 503 Service Unavailable.
+    fn handle_circuit_open(
+        &mut self,
+        _ctx: &HttpContext,
+        host: &str,
+    ) -> impl Future<Output = Response<Body>> + Send {
+        async move {
+            warn!("Circuit breaker is open for {}", host);
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Empty::new().into())
+                .expect("Failed to build response")
+        }
+    }
+
     /// Whether a CONNECT request should be intercepted. Defaults to `true` for all requests.
     fn should_intercept(
         &mut self,
@@ -148,14 +591,80 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
     ) -> impl Future<Output = bool> + Send {
         async { true }
     }
+
+    /// This handler will be called for each CONNECT request after [`HttpHandler::handle_request`],
+    /// with the authority of the request, before its tunnel is established. It can return a
+    /// different authority to redirect the tunnel, such as to a local test server. Defaults to
+    /// returning the authority unmodified.
+    ///
+    /// Tunnels that are not intercepted are connected to the returned authority. Intercepted
+    /// tunnels still present a certificate for the authority of the request to the client, and the
+    /// requests in them are sent to the returned authority with their original `Host` header.
+    fn handle_connect_target(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+        authority: Authority,
+    ) -> impl Future<Output = Authority> + Send {
+        async { authority }
+    }
+
+    /// This handler will be called when the TLS handshake of an intercepted tunnel fails, either
+    /// because the client rejected the certificate presented by the proxy, such as when it pins
+    /// certificates, or because the handshake with the upstream server failed. See [`TlsFailure`]
+    /// for the details that are reported, and [`ProxyBuilder::with_auto_passthrough`] for
+    /// tunneling the affected hosts without interception.
+    fn handle_tls_failure(
+        &mut self,
+        _ctx: &HttpContext,
+        _failure: &TlsFailure,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called for each DNS query of a DNS-over-HTTPS request, after
+    /// [`HttpHandler::handle_request`], and for each query sent over an intercepted DNS-over-TLS
+    /// tunnel. It can modify a query before it is forwarded to the server.
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    fn handle_dns_query(
+        &mut self,
+        _ctx: &HttpContext,
+        query: dns::Message,
+    ) -> impl Future<Output = dns::Message> + Send {
+        async { query }
+    }
+
+    /// This handler will be called for each DNS response of a DNS-over-HTTPS response, before
+    /// [`HttpHandler::handle_response`], and for each response sent over an intercepted
+    /// DNS-over-TLS tunnel. It can modify a response before it is forwarded to the client.
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    fn handle_dns_response(
+        &mut self,
+        _ctx: &HttpContext,
+        res: dns::Message,
+    ) -> impl Future<Output = dns::Message> + Send {
+        async { res }
+    }
 }
 
 /// Handler for WebSocket messages.
 ///
 /// Messages sent over the same WebSocket Stream are passed to the same instance of the handler.
+///
+/// Only WebSockets that are upgraded from HTTP/1.1 requests are intercepted. WebTransport runs
+/// over HTTP/3, which uses QUIC rather than TCP and so can not be tunneled through the proxy, so
+/// WebTransport sessions are not intercepted. Browsers that are configured to use the proxy do
+/// not use HTTP/3, so sites fall back to WebSockets or HTTP where they support it.
 pub trait WebSocketHandler: Clone + Send + Sync + 'static {
     /// This handler is responsible for forwarding WebSocket messages from a Stream to a Sink and
     /// recovering from any potential errors.
+    ///
+    /// The default implementation calls [`WebSocketHandler::handle_close`] when the stream is
+    /// closed, or [`WebSocketHandler::handle_protocol_error`] if it fails, so exactly one of them
+    /// is called for each direction of a connection. It then closes the sink, so that the end of
+    /// one side of the connection is forwarded to the other side.
     fn handle_websocket(
         mut self,
         ctx: WebSocketContext,
@@ -163,9 +672,24 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
         mut sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
     ) -> impl Future<Output = ()> + Send {
         async move {
+            let mut closed = false;
+
             while let Some(message) = stream.next().await {
                 match message {
                     Ok(message) => {
+                        if let Message::Close(frame) = &message {
+                            if !closed {
+                                closed = true;
+
+                                match frame {
+                                    Some(frame) => {
+                                        self.handle_close(&ctx, frame.code, &frame.reason).await
+                                    }
+                                    None => self.handle_close(&ctx, CloseCode::Status, "").await,
+                                }
+                            }
+                        }
+
                         let Some(message) = self.handle_message(&ctx, message).await else {
                             continue;
                         };
@@ -176,10 +700,21 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
                             _ => (),
                         }
                     }
+                    Err(_) if closed => break,
                     Err(e) => {
+                        closed = true;
                         error!("WebSocket message error: {}", e);
+                        self.handle_protocol_error(&ctx, &e).await;
 
-                        match sink.send(Message::Close(None)).await {
+                        let frame = match e {
+                            tungstenite::Error::Capacity(_) => Some(CloseFrame {
+                                code: CloseCode::Size,
+                                reason: "Message too big".into(),
+                            }),
+                            _ => None,
+                        };
+
+                        match sink.send(Message::Close(frame)).await {
                             Err(tungstenite::Error::ConnectionClosed) => (),
                             Err(e) => error!("WebSocket close error: {}", e),
                             _ => (),
@@ -189,6 +724,18 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
                     }
                 }
             }
+
+            if !closed {
+                self.handle_close(&ctx, CloseCode::Abnormal, "").await;
+            }
+
+            // Close the sink when the stream ends, so that the end of one connection is forwarded
+            // to the other instead of leaving it open.
+            match sink.close().await {
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => (),
+                Err(e) => error!("WebSocket close error: {}", e),
+                _ => (),
+            }
         }
     }
 
@@ -201,4 +748,39 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
     ) -> impl Future<Output = Option<Message>> + Send {
         async { Some(message) }
     }
+
+    /// This handler will be called when the source of `ctx` closes its side of a WebSocket
+    /// connection, with the code and reason of its close frame.
+    ///
+    /// If the close frame did not have a code, [`CloseCode::Status`] is passed. If the connection
+    /// ended without a close frame, [`CloseCode::Abnormal`] is passed.
+    fn handle_close(
+        &mut self,
+        _ctx: &WebSocketContext,
+        _code: CloseCode,
+        _reason: &str,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called when reading a message from the source of `ctx` fails, such as
+    /// when the source violates the WebSocket protocol or sends a message that is too large.
+    fn handle_protocol_error(
+        &mut self,
+        _ctx: &WebSocketContext,
+        _err: &tungstenite::Error,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called when the proxy fails to connect to the WebSocket server that a
+    /// client is trying to reach. The client's connection is then closed with
+    /// [`CloseCode::Error`].
+    fn handle_connect_error(
+        &mut self,
+        _ctx: &WebSocketContext,
+        _err: &tungstenite::Error,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
 }
@@ -0,0 +1,145 @@
+use crate::{
+    Body, HttpContext, HttpHandler, RequestOrResponse, WebSocketContext, WebSocketHandler,
+};
+use hyper::{Method, Request, Response, Uri};
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+use tracing::Level;
+
+/// Logs an event at a level that is only known at runtime.
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        match $level {
+            Level::ERROR => tracing::error!($($arg)+),
+            Level::WARN => tracing::warn!($($arg)+),
+            Level::INFO => tracing::info!($($arg)+),
+            Level::DEBUG => tracing::debug!($($arg)+),
+            Level::TRACE => tracing::trace!($($arg)+),
+        }
+    };
+}
+
+/// A handler that logs requests, responses and WebSocket messages with [`tracing`].
+///
+/// Each request is logged when it is received, and each response is logged with the method and
+/// URI of its request and the time it took. WebSocket messages are logged one level below the
+/// configured level, since there are usually many more of them. Nothing is modified.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::LogHandler;
+/// use tracing::Level;
+///
+/// let handler = LogHandler::new().with_level(Level::DEBUG);
+/// ```
+#[derive(Clone, Debug)]
+pub struct LogHandler {
+    level: Level,
+    /// The method and URI of the current request, and when it was received.
+    request: Option<(Method, Uri, Instant)>,
+}
+
+impl LogHandler {
+    /// Creates a new handler that logs at the `INFO` level.
+    pub fn new() -> Self {
+        Self {
+            level: Level::INFO,
+            request: None,
+        }
+    }
+
+    /// Set the level that requests and responses are logged at.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The level that WebSocket messages are logged at.
+    fn message_level(&self) -> Level {
+        match self.level {
+            Level::ERROR => Level::WARN,
+            Level::WARN => Level::INFO,
+            Level::INFO => Level::DEBUG,
+            _ => Level::TRACE,
+        }
+    }
+}
+
+impl Default for LogHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpHandler for LogHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        log!(
+            self.level,
+            flow_id = ctx.flow_id,
+            client = %ctx.client_addr,
+            "{} {} {:?}",
+            req.method(),
+            req.uri(),
+            req.version()
+        );
+
+        self.request = Some((req.method().clone(), req.uri().clone(), Instant::now()));
+        req.into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        match self.request.take() {
+            Some((method, uri, start)) => log!(
+                self.level,
+                flow_id = ctx.flow_id,
+                "{} {} -> {} in {:?}",
+                method,
+                uri,
+                res.status(),
+                start.elapsed()
+            ),
+            None => log!(self.level, flow_id = ctx.flow_id, "-> {}", res.status()),
+        }
+
+        res
+    }
+}
+
+impl WebSocketHandler for LogHandler {
+    async fn handle_message(
+        &mut self,
+        ctx: &WebSocketContext,
+        message: Message,
+    ) -> Option<Message> {
+        let kind = match &message {
+            Message::Text(_) => "text",
+            Message::Binary(_) | Message::Frame(_) => "binary",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Close(_) => "close",
+        };
+
+        log!(
+            self.message_level(),
+            socket_id = ctx.socket_id(),
+            direction = ?ctx.direction(),
+            "WebSocket {} message of {} bytes",
+            kind,
+            message.len()
+        );
+
+        Some(message)
+    }
+
+    async fn handle_close(&mut self, ctx: &WebSocketContext, code: CloseCode, reason: &str) {
+        log!(
+            self.level,
+            socket_id = ctx.socket_id(),
+            direction = ?ctx.direction(),
+            "WebSocket closed with {} {}",
+            code,
+            reason
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! Memory budgets for the caches and buffers of a proxy.
+//!
+//! A [`MemoryBudget`] counts the bytes that are held by the certificate caches of the certificate
+//! authorities, by bodies that are buffered with [`MemoryBudget::collect_up_to`], and by the
+//! responses that are stored in a [`MemoryStore`], so that the footprint of a proxy stays
+//! predictable under adversarial traffic:
+//!
+//! - Certificates and stored responses are evicted once they exceed the limit of their pool. The
+//!   limit applies to each cache that is counted against the pool.
+//! - Bodies are streamed instead of buffered once their pool is full.
+//!
+//! The current usage of each pool can be read with [`MemoryBudget::usage`], to be exported as
+//! metrics. The sizes of certificates and responses are estimates of the memory that they hold.
+//!
+//! [`MemoryStore`]: crate::cache::MemoryStore
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{memory::MemoryBudget, Body, Bounded};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let budget = MemoryBudget::new()
+//!     .with_certificate_limit(16 * 1024 * 1024)
+//!     .with_body_limit(256 * 1024 * 1024);
+//!
+//! // Pass `budget` to `RcgenAuthority::with_memory_budget`...
+//!
+//! match budget.collect_up_to(Body::from("hello"), 1024).await.unwrap() {
+//!     Bounded::Complete { data, .. } => {
+//!         assert_eq!(budget.usage().bodies, 5);
+//!         drop(data);
+//!         assert_eq!(budget.usage().bodies, 0);
+//!     }
+//!     Bounded::TooLarge(body) => unreachable!(),
+//! }
+//! # }
+//! ```
+
+use crate::{Body, Bounded, Error};
+use hyper::body::Bytes;
+#[cfg(any(feature = "cache", feature = "openssl-ca", feature = "rcgen-ca"))]
+use moka::future::{Cache, CacheBuilder};
+#[cfg(any(feature = "cache", feature = "openssl-ca", feature = "rcgen-ca"))]
+use std::hash::Hash;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// The number of bytes that are held by each pool of a [`MemoryBudget`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct MemoryUsage {
+    /// The estimated size of the certificates in the caches of the certificate authorities.
+    pub certificates: u64,
+    /// The size of the bodies that are buffered.
+    pub bodies: u64,
+    /// The estimated size of the responses that are stored in memory stores.
+    pub responses: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Pool {
+    #[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+    Certificates,
+    Bodies,
+    #[cfg(feature = "cache")]
+    Responses,
+}
+
+#[derive(Debug)]
+struct Counter {
+    used: AtomicU64,
+    limit: AtomicU64,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            limit: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    certificates: Counter,
+    bodies: Counter,
+    responses: Counter,
+}
+
+/// Limits on the memory that is held by the caches and buffers of a proxy.
+///
+/// Clones of a budget share the same pools, so one budget can be passed to several certificate
+/// authorities and stores, and kept to read the usage. Pools are unlimited by default.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget(Arc<Shared>);
+
+impl MemoryBudget {
+    /// Creates a budget without limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of bytes that cached certificates may hold, above which the least recently
+    /// used certificates are evicted.
+    pub fn with_certificate_limit(self, bytes: u64) -> Self {
+        self.0.certificates.limit.store(bytes, Ordering::Relaxed);
+        self
+    }
+
+    /// Set the number of bytes that buffered bodies may hold, above which bodies are streamed
+    /// instead of buffered.
+    pub fn with_body_limit(self, bytes: u64) -> Self {
+        self.0.bodies.limit.store(bytes, Ordering::Relaxed);
+        self
+    }
+
+    /// Set the number of bytes that stored responses may hold, above which the least recently
+    /// used responses are evicted.
+    pub fn with_response_limit(self, bytes: u64) -> Self {
+        self.0.responses.limit.store(bytes, Ordering::Relaxed);
+        self
+    }
+
+    /// The number of bytes that are currently held by each pool.
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            certificates: self.0.certificates.used.load(Ordering::Relaxed),
+            bodies: self.0.bodies.used.load(Ordering::Relaxed),
+            responses: self.0.responses.used.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Collect a body into memory like [`Body::collect_up_to`], counting it against the body
+    /// pool.
+    ///
+    /// If collecting the body would exceed the limit of the pool, [`Bounded::TooLarge`] is
+    /// returned as if the body was larger than `max` bytes, so that it can be streamed instead.
+    /// The data of a complete body is counted until it and all of its clones are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body fails before either limit is exceeded.
+    pub async fn collect_up_to(&self, body: Body, max: usize) -> Result<Bounded, Error> {
+        let mut reservation = Reservation {
+            budget: self.clone(),
+            len: 0,
+        };
+
+        match body
+            .collect_bounded(max, |len| reservation.grow(len))
+            .await?
+        {
+            Bounded::Complete { data, trailers } if reservation.len > 0 => Ok(Bounded::Complete {
+                data: Bytes::from_owner(Reserved {
+                    data,
+                    _reservation: reservation,
+                }),
+                trailers,
+            }),
+            bounded => Ok(bounded),
+        }
+    }
+
+    fn counter(&self, pool: Pool) -> &Counter {
+        match pool {
+            #[cfg(any(feature = "openssl-ca", feature = "rcgen-ca"))]
+            Pool::Certificates => &self.0.certificates,
+            Pool::Bodies => &self.0.bodies,
+            #[cfg(feature = "cache")]
+            Pool::Responses => &self.0.responses,
+        }
+    }
+
+    /// Stop counting bytes against a pool.
+    pub(crate) fn remove(&self, pool: Pool, bytes: u64) {
+        let _ =
+            self.counter(pool)
+                .used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    Some(used.saturating_sub(bytes))
+                });
+    }
+}
+
+#[cfg(any(feature = "cache", feature = "openssl-ca", feature = "rcgen-ca"))]
+impl MemoryBudget {
+    /// The limit of a pool, if it has one.
+    fn limit(&self, pool: Pool) -> Option<u64> {
+        let limit = self.counter(pool).limit.load(Ordering::Relaxed);
+        (limit != u64::MAX).then_some(limit)
+    }
+
+    /// Count bytes against a pool, regardless of its limit.
+    pub(crate) fn add(&self, pool: Pool, bytes: u64) {
+        self.counter(pool).used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count the entries of a cache against a pool when they are removed, weighing them by their
+    /// `size`. Entries must be counted with [`MemoryBudget::add`] before they are inserted.
+    ///
+    /// If the pool has a limit, the cache is limited to it by weight instead of its own capacity.
+    pub(crate) fn cache_builder<K, V>(
+        &self,
+        pool: Pool,
+        builder: CacheBuilder<K, V, Cache<K, V>>,
+        size: fn(&V) -> u64,
+    ) -> CacheBuilder<K, V, Cache<K, V>>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+    {
+        let budget = self.clone();
+        let builder =
+            builder.eviction_listener(move |_, value, _| budget.remove(pool, size(&value)));
+
+        match self.limit(pool) {
+            Some(limit) => builder
+                .max_capacity(limit)
+                .weigher(move |_, value| u32::try_from(size(value)).unwrap_or(u32::MAX)),
+            None => builder,
+        }
+    }
+}
+
+/// Bytes that are counted against the body pool until they are dropped.
+struct Reservation {
+    budget: MemoryBudget,
+    len: u64,
+}
+
+impl Reservation {
+    /// Count `len` more bytes, if they fit within the limit of the pool.
+    fn grow(&mut self, len: usize) -> bool {
+        let counter = &self.budget.0.bodies;
+        let limit = counter.limit.load(Ordering::Relaxed);
+        let len = len as u64;
+
+        let reserved = counter
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(len).filter(|&used| used <= limit)
+            })
+            .is_ok();
+
+        if reserved {
+            self.len += len;
+        }
+
+        reserved
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.remove(Pool::Bodies, self.len);
+    }
+}
+
+/// The data of a body that was collected with a budget.
+struct Reserved {
+    data: Bytes,
+    _reservation: Reservation,
+}
+
+impl AsRef<[u8]> for Reserved {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn streams_bodies_over_the_limit() {
+        let budget = MemoryBudget::new().with_body_limit(8);
+
+        let Bounded::Complete { data, .. } = budget
+            .collect_up_to(Body::from("hello"), 1024)
+            .await
+            .unwrap()
+        else {
+            panic!("expected a complete body");
+        };
+        assert_eq!(budget.usage().bodies, 5);
+
+        let body = Body::wrap_stream(stream::iter([
+            Ok::<_, Error>(Bytes::from_static(b"wor")),
+            Ok(Bytes::from_static(b"ld")),
+        ]));
+        let Bounded::TooLarge(body) = budget.collect_up_to(body, 1024).await.unwrap() else {
+            panic!("expected the body to be streamed");
+        };
+        assert_eq!(budget.usage().bodies, 5);
+        assert!(matches!(
+            body.collect_up_to(1024).await.unwrap(),
+            Bounded::Complete { data, .. } if data == "world"
+        ));
+
+        let copy = data.clone();
+        drop(data);
+        assert_eq!(budget.usage().bodies, 5);
+        drop(copy);
+        assert_eq!(budget.usage().bodies, 0);
+    }
+}
@@ -0,0 +1,413 @@
+//! Mirroring of requests to secondary upstream servers.
+//!
+//! [`MirrorHandler`] sends a copy of each request that matches a [`MirrorRule`] to another
+//! upstream server, without delaying the original request. Responses to mirrored requests are
+//! discarded, or can be compared with the original responses, which is useful for validating a new
+//! backend against live traffic.
+
+use crate::{
+    auth::{host, HostPattern},
+    Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use futures::channel::oneshot;
+use http_body_util::BodyExt;
+use hyper::{
+    http::uri::{Authority, Scheme},
+    Request, Response, Uri,
+};
+use hyper_util::client::legacy::{connect::Connect, Client, Error};
+use std::{fmt, sync::Arc};
+use tracing::{debug, warn};
+
+/// The number of request body frames that are buffered for a mirrored request.
+const MIRROR_BUFFER: usize = 16;
+
+/// A rule that mirrors matching requests to an upstream server.
+#[derive(Clone, Debug)]
+pub struct MirrorRule {
+    pattern: HostPattern,
+    path_prefix: String,
+    scheme: Scheme,
+    authority: Option<Authority>,
+}
+
+impl MirrorRule {
+    /// Creates a new rule that mirrors requests for hosts that match `pattern` to `target`.
+    ///
+    /// Only the scheme and authority of `target` are used, and a target without a scheme uses
+    /// HTTP.
+    pub fn new(pattern: impl Into<HostPattern>, target: Uri) -> Self {
+        let parts = target.into_parts();
+
+        Self {
+            pattern: pattern.into(),
+            path_prefix: "/".to_owned(),
+            scheme: parts.scheme.unwrap_or(Scheme::HTTP),
+            authority: parts.authority,
+        }
+    }
+
+    /// Only mirror requests whose path starts with `prefix`.
+    pub fn with_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = prefix.into();
+        self
+    }
+
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        self.authority.is_some()
+            && host(req).is_some_and(|host| self.pattern.matches(host))
+            && req.uri().path().starts_with(&self.path_prefix)
+    }
+
+    fn mirror<T>(&self, req: &Request<T>, body: Body) -> Option<Request<Body>> {
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = Some(self.scheme.clone());
+        parts.authority = self.authority.clone();
+
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some("/".try_into().expect("Failed to build path"));
+        }
+
+        let mut mirror = Request::new(body);
+        *mirror.method_mut() = req.method().clone();
+        *mirror.uri_mut() = Uri::from_parts(parts).ok()?;
+        *mirror.headers_mut() = req.headers().clone();
+        mirror.headers_mut().remove(hyper::header::HOST);
+        Some(mirror)
+    }
+}
+
+/// The responses to a request and its mirrored copy.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct MirrorComparison {
+    /// The original request, without its body.
+    pub request: Request<()>,
+    /// The response from the original upstream server, without its body.
+    pub primary: Response<()>,
+    /// The response from the mirror, without its body, or the error that occurred while sending
+    /// the mirrored request.
+    pub mirror: Result<Response<()>, Error>,
+}
+
+type Compare = dyn Fn(MirrorComparison) + Send + Sync;
+
+struct Pending {
+    request: Request<()>,
+    mirror: oneshot::Receiver<Result<Response<()>, Error>>,
+}
+
+fn head<T>(res: &Response<T>) -> Response<()> {
+    let mut head = Response::new(());
+    *head.status_mut() = res.status();
+    *head.version_mut() = res.version();
+    *head.headers_mut() = res.headers().clone();
+    head
+}
+
+/// An HTTP handler that mirrors requests to secondary upstream servers.
+///
+/// Mirrored requests are sent with the provided client. Requests are passed to the wrapped handler
+/// before they are mirrored, so the mirror receives the same request as the original upstream
+/// server. If the mirror reads a request body more slowly than the original upstream server, the
+/// mirrored request is abandoned.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     hyper::Uri,
+///     hyper_util::{
+///         client::legacy::{connect::HttpConnector, Client},
+///         rt::TokioExecutor,
+///     },
+///     mirror::{MirrorHandler, MirrorRule},
+/// };
+///
+/// let client = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+///
+/// let handler = MirrorHandler::new(client)
+///     .with_rule(MirrorRule::new(
+///         "api.example.com",
+///         Uri::from_static("http://canary.internal:8080"),
+///     ))
+///     .with_comparison(|comparison| {
+///         if let Ok(mirror) = &comparison.mirror {
+///             if mirror.status() != comparison.primary.status() {
+///                 println!("Mismatch for {}", comparison.request.uri());
+///             }
+///         }
+///     });
+/// ```
+pub struct MirrorHandler<C, H = NoopHandler> {
+    client: Client<C, Body>,
+    rules: Arc<Vec<MirrorRule>>,
+    inner: H,
+    compare: Option<Arc<Compare>>,
+    pending: Option<Pending>,
+}
+
+impl<C> MirrorHandler<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Creates a new mirror handler without any rules.
+    pub fn new(client: Client<C, Body>) -> Self {
+        Self {
+            client,
+            rules: Arc::new(Vec::new()),
+            inner: NoopHandler::default(),
+            compare: None,
+            pending: None,
+        }
+    }
+}
+
+impl<C: Clone, H: Clone> Clone for MirrorHandler<C, H> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            rules: Arc::clone(&self.rules),
+            inner: self.inner.clone(),
+            compare: self.compare.clone(),
+            pending: None,
+        }
+    }
+}
+
+impl<C, H: fmt::Debug> fmt::Debug for MirrorHandler<C, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MirrorHandler")
+            .field("rules", &self.rules)
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C, H> MirrorHandler<C, H>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> MirrorHandler<C, H2> {
+        MirrorHandler {
+            client: self.client,
+            rules: self.rules,
+            inner,
+            compare: self.compare,
+            pending: None,
+        }
+    }
+
+    /// Add a rule. Each request is mirrored by at most one rule, the first that matches.
+    pub fn with_rule(mut self, rule: MirrorRule) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    /// Set a function that is called with the responses to each mirrored request and its
+    /// original, once both have been received.
+    pub fn with_comparison<F>(mut self, compare: F) -> Self
+    where
+        F: Fn(MirrorComparison) + Send + Sync + 'static,
+    {
+        self.compare = Some(Arc::new(compare));
+        self
+    }
+
+    fn mirror(&mut self, req: Request<Body>) -> Request<Body> {
+        self.pending = None;
+
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(&req)) else {
+            return req;
+        };
+
+        let (parts, body) = req.into_parts();
+        let (body, copy) = body.tee(MIRROR_BUFFER);
+        let req = Request::from_parts(parts, body);
+
+        let Some(mirror) = rule.mirror(&req, copy) else {
+            return req;
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let client = self.client.clone();
+        let uri = mirror.uri().clone();
+
+        tokio::spawn(async move {
+            let res = match client.request(mirror).await {
+                Ok(res) => {
+                    let head = head(&res);
+
+                    if let Err(e) = res.into_body().collect().await {
+                        debug!("Failed to read mirrored response from {}: {}", uri, e);
+                    }
+
+                    Ok(head)
+                }
+                Err(e) => {
+                    warn!("Failed to mirror request to {}: {}", uri, e);
+                    Err(e)
+                }
+            };
+
+            let _ = tx.send(res);
+        });
+
+        if self.compare.is_some() {
+            let mut request = Request::new(());
+            *request.method_mut() = req.method().clone();
+            *request.uri_mut() = req.uri().clone();
+            *request.headers_mut() = req.headers().clone();
+
+            self.pending = Some(Pending {
+                request,
+                mirror: rx,
+            });
+        }
+
+        req
+    }
+
+    fn compare(&mut self, res: &Response<Body>) {
+        let (Some(pending), Some(compare)) = (self.pending.take(), self.compare.clone()) else {
+            return;
+        };
+
+        let primary = head(res);
+
+        tokio::spawn(async move {
+            if let Ok(mirror) = pending.mirror.await {
+                compare(MirrorComparison {
+                    request: pending.request,
+                    primary,
+                    mirror,
+                });
+            }
+        });
+    }
+}
+
+impl<C, H> HttpHandler for MirrorHandler<C, H>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    H: HttpHandler,
+{
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.mirror(req).into(),
+            res => {
+                self.pending = None;
+                res
+            }
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.compare(&res);
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(&mut self, ctx: &HttpContext, err: Error) -> Response<Body> {
+        self.pending = None;
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.pending = None;
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &'static str) -> Request<()> {
+        Request::builder()
+            .uri(uri)
+            .header(hyper::header::HOST, "example.com")
+            .body(())
+            .unwrap()
+    }
+
+    mod mirror_rule {
+        use super::*;
+
+        #[test]
+        fn matches_requests() {
+            let rule = MirrorRule::new("example.com", Uri::from_static("http://mirror:8080"))
+                .with_path_prefix("/api");
+
+            assert!(rule.matches(&request("http://example.com/api/users")));
+            assert!(!rule.matches(&request("http://example.com/")));
+            assert!(!rule.matches(&request("http://example.org/api")));
+        }
+
+        #[test]
+        fn rewrites_target() {
+            let rule = MirrorRule::new("*", Uri::from_static("https://mirror:8443"));
+            let mirror = rule
+                .mirror(&request("http://example.com/a?b=c"), Body::from("body"))
+                .unwrap();
+
+            assert_eq!(mirror.uri(), "https://mirror:8443/a?b=c");
+            assert!(!mirror.headers().contains_key(hyper::header::HOST));
+        }
+    }
+}
@@ -0,0 +1,690 @@
+//! Mock responses for matching requests.
+//!
+//! [`MockHandler`] answers requests that match a [`Mock`] without contacting the upstream server,
+//! and forwards every other request as usual.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     hyper::{Method, StatusCode},
+//!     mock::{Matcher, Mock, MockHandler, MockResponse},
+//! };
+//! use std::time::Duration;
+//!
+//! let handler = MockHandler::new()
+//!     .with_mock(Mock::new(
+//!         Matcher::new()
+//!             .method(Method::GET)
+//!             .host("api.example.com")
+//!             .path("/users/*"),
+//!         MockResponse::new(StatusCode::OK)
+//!             .with_header("content-type", "application/json")
+//!             .with_template(r#"{"path": "{{path}}"}"#)
+//!             .with_delay(Duration::from_millis(100)),
+//!     ))
+//!     .with_mock(Mock::new(
+//!         Matcher::new().method(Method::POST).body_contains("\"fail\": true"),
+//!         MockResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+//!     ));
+//! ```
+
+use crate::{
+    auth::{host, HostPattern},
+    Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use bstr::ByteSlice;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+type BodyPredicate = dyn Fn(&[u8]) -> bool + Send + Sync;
+type RequestPredicate = dyn Fn(&Request<()>) -> bool + Send + Sync;
+
+/// Predicates that a request must satisfy for a [`Mock`] to be used.
+///
+/// A request matches if it satisfies every predicate. A matcher without predicates matches every
+/// request.
+#[derive(Clone, Default)]
+pub struct Matcher {
+    method: Option<Method>,
+    host: Option<HostPattern>,
+    path: Option<String>,
+    headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    body: Vec<Arc<BodyPredicate>>,
+    request: Vec<Arc<RequestPredicate>>,
+}
+
+impl Matcher {
+    /// Creates a new matcher that matches every request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match requests with a method.
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Match requests for hosts that match a pattern.
+    pub fn host(mut self, pattern: impl Into<HostPattern>) -> Self {
+        self.host = Some(pattern.into());
+        self
+    }
+
+    /// Match requests whose path matches a glob, where `*` matches any sequence of characters.
+    pub fn path(mut self, glob: impl Into<String>) -> Self {
+        self.path = Some(glob.into());
+        self
+    }
+
+    /// Match requests with a header value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` or `value` is not a valid header name or value.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((
+            HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name"),
+            Some(HeaderValue::from_str(value).expect("Invalid header value")),
+        ));
+        self
+    }
+
+    /// Match requests that have a header, with any value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid header name.
+    pub fn header_exists(mut self, name: &str) -> Self {
+        self.headers.push((
+            HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name"),
+            None,
+        ));
+        self
+    }
+
+    /// Match requests whose body satisfies a predicate.
+    ///
+    /// The request body is read into memory before it is matched, if the rest of the matcher
+    /// matches the request.
+    pub fn body<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.body.push(Arc::new(predicate));
+        self
+    }
+
+    /// Match requests whose body contains a sequence of bytes.
+    pub fn body_contains(self, needle: impl Into<Bytes>) -> Self {
+        let needle = needle.into();
+        self.body(move |body| body.find(&needle).is_some())
+    }
+
+    /// Match requests that satisfy a predicate.
+    pub fn matching<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Request<()>) -> bool + Send + Sync + 'static,
+    {
+        self.request.push(Arc::new(predicate));
+        self
+    }
+
+    fn matches_head(&self, req: &Request<()>) -> bool {
+        self.method
+            .as_ref()
+            .map_or(true, |method| method == req.method())
+            && self.host.as_ref().map_or(true, |pattern| {
+                host(req).is_some_and(|host| pattern.matches(host))
+            })
+            && self.path.as_ref().map_or(true, |glob| {
+                glob_matches(glob.as_bytes(), req.uri().path().as_bytes())
+            })
+            && self.headers.iter().all(|(name, value)| match value {
+                Some(value) => req.headers().get_all(name).iter().any(|v| v == value),
+                None => req.headers().contains_key(name),
+            })
+            && self.request.iter().all(|predicate| predicate(req))
+    }
+
+    fn matches_body(&self, body: &[u8]) -> bool {
+        self.body.iter().all(|predicate| predicate(body))
+    }
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Matcher")
+            .field("method", &self.method)
+            .field("host", &self.host)
+            .field("path", &self.path)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn glob_matches(glob: &[u8], input: &[u8]) -> bool {
+    match glob.split_first() {
+        None => input.is_empty(),
+        Some((b'*', rest)) => (0..=input.len()).any(|i| glob_matches(rest, &input[i..])),
+        Some((c, rest)) => input
+            .split_first()
+            .is_some_and(|(i, input)| i == c && glob_matches(rest, input)),
+    }
+}
+
+#[derive(Clone, Debug)]
+enum MockBody {
+    Bytes(Bytes),
+    Template(String),
+}
+
+/// A response that is returned for a [`Mock`].
+///
+/// Templates can contain the placeholders `{{method}}`, `{{host}}`, `{{path}}`, `{{query}}`, and
+/// `{{header:<name>}}`, which are replaced with the corresponding parts of the request. Header
+/// values are always treated as templates.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, String)>,
+    body: MockBody,
+    delay: Duration,
+}
+
+impl MockResponse {
+    /// Creates a new mock response with an empty body.
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: MockBody::Bytes(Bytes::new()),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Add a header to the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid header name.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((
+            HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name"),
+            value.into(),
+        ));
+        self
+    }
+
+    /// Set the body of the response.
+    pub fn with_body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = MockBody::Bytes(body.into());
+        self
+    }
+
+    /// Set the body of the response to a template.
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.body = MockBody::Template(template.into());
+        self
+    }
+
+    /// Wait before returning the response.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn render(&self, req: &Request<()>) -> Response<Body> {
+        let body = match &self.body {
+            MockBody::Bytes(body) if body.is_empty() => Body::from(Empty::new()),
+            MockBody::Bytes(body) => Body::from(Full::new(body.clone())),
+            MockBody::Template(template) => Body::from(render(template, req)),
+        };
+
+        let mut res = Response::new(body);
+        *res.status_mut() = self.status;
+
+        for (name, value) in &self.headers {
+            match HeaderValue::from_str(&render(value, req)) {
+                Ok(value) => {
+                    res.headers_mut().append(name, value);
+                }
+                Err(e) => warn!("Invalid mock header value for {}: {}", name, e),
+            }
+        }
+
+        res
+    }
+}
+
+fn render(template: &str, req: &Request<()>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+
+        let Some(end) = rest.find("}}") else {
+            out.push_str("{{");
+            break;
+        };
+
+        let placeholder = rest[..end].trim();
+        rest = &rest[end + 2..];
+
+        match placeholder {
+            "method" => out.push_str(req.method().as_str()),
+            "host" => out.push_str(host(req).unwrap_or_default()),
+            "path" => out.push_str(req.uri().path()),
+            "query" => out.push_str(req.uri().query().unwrap_or_default()),
+            _ => {
+                if let Some(name) = placeholder.strip_prefix("header:") {
+                    if let Some(value) = req.headers().get(name.trim()) {
+                        out.push_str(value.to_str().unwrap_or_default());
+                    }
+                } else {
+                    out.push_str("{{");
+                    out.push_str(placeholder);
+                    out.push_str("}}");
+                }
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// A mock response, and the requests that it is returned for.
+///
+/// Clones of a mock share the count of requests that it has been used for.
+#[derive(Clone, Debug)]
+pub struct Mock {
+    matcher: Matcher,
+    response: MockResponse,
+    limit: Option<usize>,
+    hits: Arc<AtomicUsize>,
+}
+
+impl Mock {
+    /// Creates a new mock that returns `response` for requests that match `matcher`.
+    pub fn new(matcher: Matcher, response: MockResponse) -> Self {
+        Self {
+            matcher,
+            response,
+            limit: None,
+            hits: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Only use the mock for the first `times` matching requests.
+    pub fn with_times(mut self, times: usize) -> Self {
+        self.limit = Some(times);
+        self
+    }
+
+    /// The number of requests that the mock has been used for.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn take(&self) -> bool {
+        match self.limit {
+            Some(limit) => self
+                .hits
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hits| {
+                    (hits < limit).then_some(hits + 1)
+                })
+                .is_ok(),
+            None => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+}
+
+/// An HTTP handler that returns mock responses.
+///
+/// See the [module documentation](self) for an example. Requests are passed to the wrapped handler
+/// before they are matched against the mocks, in the order that the mocks were added. Mock
+/// responses are passed to the wrapped handler's [`HttpHandler::handle_response`].
+#[derive(Clone, Debug)]
+pub struct MockHandler<H = NoopHandler> {
+    mocks: Arc<Vec<Mock>>,
+    inner: H,
+}
+
+impl MockHandler {
+    /// Creates a new mock handler without any mocks.
+    pub fn new() -> Self {
+        Self {
+            mocks: Arc::new(Vec::new()),
+            inner: NoopHandler::default(),
+        }
+    }
+}
+
+impl Default for MockHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> MockHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> MockHandler<H2> {
+        MockHandler {
+            mocks: self.mocks,
+            inner,
+        }
+    }
+
+    /// Add a mock.
+    pub fn with_mock(mut self, mock: Mock) -> Self {
+        Arc::make_mut(&mut self.mocks).push(mock);
+        self
+    }
+}
+
+impl<H: HttpHandler> MockHandler<H> {
+    async fn respond(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let (parts, body) = req.into_parts();
+        let head = Request::from_parts(parts, ());
+
+        let candidates: Vec<&Mock> = self
+            .mocks
+            .iter()
+            .filter(|mock| mock.matcher.matches_head(&head))
+            .collect();
+
+        if candidates.is_empty() {
+            let (parts, ()) = head.into_parts();
+            return Request::from_parts(parts, body).into();
+        }
+
+        let (body, bytes) = if candidates.iter().any(|mock| !mock.matcher.body.is_empty()) {
+            match body.collect().await {
+                Ok(body) => {
+                    let bytes = body.to_bytes();
+                    (Body::from(Full::new(bytes.clone())), bytes)
+                }
+                Err(e) => {
+                    warn!("Failed to read request body: {}", e);
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(Empty::new()))
+                        .expect("Failed to build response")
+                        .into();
+                }
+            }
+        } else {
+            (body, Bytes::new())
+        };
+
+        let mock = candidates
+            .into_iter()
+            .find(|mock| mock.matcher.matches_body(&bytes) && mock.take())
+            .cloned();
+
+        match mock {
+            Some(mock) => {
+                if !mock.response.delay.is_zero() {
+                    tokio::time::sleep(mock.response.delay).await;
+                }
+
+                let res = mock.response.render(&head);
+                self.inner.handle_response(ctx, res).await.into()
+            }
+            None => {
+                let (parts, ()) = head.into_parts();
+                Request::from_parts(parts, body).into()
+            }
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for MockHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.respond(ctx, req).await,
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &'static str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-user", "alice")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn body(res: Response<Body>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    mod glob_matches {
+        use super::*;
+
+        #[test]
+        fn wildcards() {
+            assert!(glob_matches(b"/users/*", b"/users/1"));
+            assert!(glob_matches(b"/users/*/posts", b"/users/1/posts"));
+            assert!(!glob_matches(b"/users/*/posts", b"/users/1"));
+            assert!(glob_matches(b"*", b""));
+        }
+    }
+
+    mod render {
+        use super::*;
+
+        #[test]
+        fn replaces_placeholders() {
+            let req = Request::builder()
+                .uri("http://example.com/a?b=c")
+                .header("x-user", "alice")
+                .body(())
+                .unwrap();
+
+            assert_eq!(
+                render(
+                    "{{method}} {{host}}{{path}}?{{query}} {{header:x-user}} {{unknown}}",
+                    &req
+                ),
+                "GET example.com/a?b=c alice {{unknown}}"
+            );
+        }
+    }
+
+    mod mock_handler {
+        use super::*;
+
+        #[tokio::test]
+        async fn returns_mock_responses() {
+            let mut handler = MockHandler::new().with_mock(Mock::new(
+                Matcher::new()
+                    .method(Method::GET)
+                    .path("/users/*")
+                    .header("x-user", "alice"),
+                MockResponse::new(StatusCode::CREATED)
+                    .with_header("x-path", "{{path}}")
+                    .with_template("hello {{header:x-user}}"),
+            ));
+
+            match handler
+                .handle_request(
+                    &HttpContext::for_test(),
+                    request(Method::GET, "http://example.com/users/1", ""),
+                )
+                .await
+            {
+                RequestOrResponse::Response(res) => {
+                    assert_eq!(res.status(), StatusCode::CREATED);
+                    assert_eq!(res.headers()["x-path"], "/users/1");
+                    assert_eq!(body(res).await, "hello alice");
+                }
+                RequestOrResponse::Request(_) => panic!("expected response"),
+            }
+
+            assert!(matches!(
+                handler
+                    .handle_request(
+                        &HttpContext::for_test(),
+                        request(Method::GET, "http://example.com/", "")
+                    )
+                    .await,
+                RequestOrResponse::Request(_)
+            ));
+        }
+
+        #[tokio::test]
+        async fn matches_bodies() {
+            let mut handler = MockHandler::new().with_mock(Mock::new(
+                Matcher::new().body_contains("fail"),
+                MockResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            ));
+
+            match handler
+                .handle_request(
+                    &HttpContext::for_test(),
+                    request(Method::POST, "http://example.com/", "ok"),
+                )
+                .await
+            {
+                RequestOrResponse::Request(req) => {
+                    assert_eq!(req.into_body().collect().await.unwrap().to_bytes(), "ok")
+                }
+                RequestOrResponse::Response(_) => panic!("expected request"),
+            }
+
+            assert!(matches!(
+                handler
+                    .handle_request(
+                        &HttpContext::for_test(),
+                        request(Method::POST, "http://example.com/", "fail")
+                    )
+                    .await,
+                RequestOrResponse::Response(_)
+            ));
+        }
+
+        #[tokio::test]
+        async fn limits_uses() {
+            let mock = Mock::new(Matcher::new(), MockResponse::new(StatusCode::OK)).with_times(1);
+            let mut handler = MockHandler::new().with_mock(mock.clone());
+
+            assert!(matches!(
+                handler
+                    .handle_request(
+                        &HttpContext::for_test(),
+                        request(Method::GET, "http://example.com/", "")
+                    )
+                    .await,
+                RequestOrResponse::Response(_)
+            ));
+            assert!(matches!(
+                handler
+                    .handle_request(
+                        &HttpContext::for_test(),
+                        request(Method::GET, "http://example.com/", "")
+                    )
+                    .await,
+                RequestOrResponse::Request(_)
+            ));
+            assert_eq!(mock.hits(), 1);
+        }
+    }
+}
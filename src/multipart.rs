@@ -0,0 +1,625 @@
+//! Parsing and rebuilding `multipart/form-data` bodies.
+//!
+//! A [`Multipart`] body is parsed from a buffered request body, and can be modified and turned
+//! back into a request. The boundary, preamble and epilogue of the original body are preserved,
+//! so a body that has not been modified is rebuilt with the same parts and delimiters.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use http_body_util::Empty;
+//! use hudsucker::{
+//!     hyper::{Request, Response, StatusCode},
+//!     multipart::{Multipart, Parsed},
+//!     Body, HttpContext, HttpHandler, RequestOrResponse,
+//! };
+//!
+//! #[derive(Clone)]
+//! struct ReplaceUpload;
+//!
+//! impl HttpHandler for ReplaceUpload {
+//!     async fn handle_request(
+//!         &mut self,
+//!         _ctx: &HttpContext,
+//!         req: Request<Body>,
+//!     ) -> RequestOrResponse {
+//!         match Multipart::from_request(req, 1024 * 1024).await {
+//!             Ok(Parsed::Multipart {
+//!                 parts,
+//!                 mut multipart,
+//!             }) => {
+//!                 if let Some(part) = multipart.get_mut("upload") {
+//!                     part.set_file("replaced.txt", "text/plain", "replaced");
+//!                 }
+//!
+//!                 multipart.into_request(parts).into()
+//!             }
+//!             Ok(Parsed::Unchanged(req)) => req.into(),
+//!             Err(_) => Response::builder()
+//!                 .status(StatusCode::BAD_REQUEST)
+//!                 .body(Empty::new().into())
+//!                 .unwrap()
+//!                 .into(),
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{Body, Bounded, Error};
+use bstr::ByteSlice;
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE,
+        TRANSFER_ENCODING,
+    },
+    http::request,
+    Request,
+};
+
+/// Returns the boundary of a multipart body from the `Content-Type` header.
+///
+/// Returns `None` if the content type is not a multipart type, or does not have a boundary.
+pub fn boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let (mime, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+
+    if !mime
+        .trim()
+        .get(..10)
+        .is_some_and(|t| t.eq_ignore_ascii_case("multipart/"))
+    {
+        return None;
+    }
+
+    param(params, "boundary").filter(|b| !b.is_empty())
+}
+
+/// Returns the value of a parameter of a header value such as `form-data; name="field"`.
+fn param(params: &str, key: &str) -> Option<String> {
+    let mut rest = params;
+
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (name, after) = rest.split_once('=').unwrap_or((rest, ""));
+        let name = name.trim();
+        let after = after.trim_start();
+
+        let value = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, c)) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+
+            rest = quoted[end..].split_once(';').map_or("", |(_, after)| after);
+            value
+        } else {
+            let (value, after) = after.split_once(';').unwrap_or((after, ""));
+            rest = after;
+            value.trim().to_owned()
+        };
+
+        if name.eq_ignore_ascii_case(key) {
+            return Some(value);
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+
+        out.push(c);
+    }
+
+    out.push('"');
+    out
+}
+
+/// A part of a multipart body.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Part {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Part {
+    /// Creates a part with headers and a body.
+    pub fn new(headers: HeaderMap, body: impl Into<Bytes>) -> Self {
+        Self {
+            headers,
+            body: body.into(),
+        }
+    }
+
+    /// Creates a form field with a text value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name contains characters that are not valid in a header value.
+    pub fn text(name: &str, value: impl Into<Bytes>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::try_from(format!("form-data; name={}", quote(name)))
+                .expect("Invalid field name"),
+        );
+
+        Self::new(headers, value)
+    }
+
+    /// Creates a form field with a file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name, filename or content type contain characters that are not valid in a
+    /// header value.
+    pub fn file(name: &str, filename: &str, content_type: &str, body: impl Into<Bytes>) -> Self {
+        let mut part = Self::text(name, Bytes::new());
+        part.set_file(filename, content_type, body);
+        part
+    }
+
+    /// The headers of the part.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The headers of the part, for modification.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// The name of the form field, from the `Content-Disposition` header.
+    pub fn name(&self) -> Option<String> {
+        self.disposition_param("name")
+    }
+
+    /// The filename of the form field, from the `Content-Disposition` header.
+    pub fn filename(&self) -> Option<String> {
+        self.disposition_param("filename")
+    }
+
+    /// The `Content-Type` of the part.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get(CONTENT_TYPE)?.to_str().ok()
+    }
+
+    /// The body of the part.
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Replace the body of the part.
+    ///
+    /// The `Content-Length` header of the part is updated if it has one.
+    pub fn set_body(&mut self, body: impl Into<Bytes>) {
+        self.body = body.into();
+
+        if self.headers.contains_key(CONTENT_LENGTH) {
+            self.headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        }
+    }
+
+    /// Replace the body of the part with a file.
+    ///
+    /// The filename in the `Content-Disposition` header and the `Content-Type` header are
+    /// replaced, and the name of the form field is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name, filename or content type contain characters that are not valid in a
+    /// header value.
+    pub fn set_file(&mut self, filename: &str, content_type: &str, body: impl Into<Bytes>) {
+        let mut disposition = String::from("form-data");
+
+        if let Some(name) = self.name() {
+            disposition.push_str("; name=");
+            disposition.push_str(&quote(&name));
+        }
+
+        disposition.push_str("; filename=");
+        disposition.push_str(&quote(filename));
+
+        self.headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::try_from(disposition).expect("Invalid filename"),
+        );
+        self.headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::try_from(content_type).expect("Invalid content type"),
+        );
+        self.set_body(body);
+    }
+
+    fn disposition_param(&self, key: &str) -> Option<String> {
+        let disposition = self.headers.get(CONTENT_DISPOSITION)?.to_str().ok()?;
+        let (_, params) = disposition.split_once(';')?;
+        param(params, key)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        let (head, body) = if let Some(body) = data.strip_prefix(b"\r\n") {
+            (&data[..0], body)
+        } else {
+            let end = data.find(b"\r\n\r\n").ok_or(Error::Multipart)?;
+            (&data[..end], &data[end + 4..])
+        };
+
+        let mut headers = HeaderMap::new();
+
+        for line in head.split_str(b"\r\n") {
+            let colon = line.find_byte(b':').ok_or(Error::Multipart)?;
+            let name =
+                HeaderName::from_bytes(line[..colon].trim()).map_err(|_| Error::Multipart)?;
+            let value =
+                HeaderValue::from_bytes(line[colon + 1..].trim()).map_err(|_| Error::Multipart)?;
+            headers.append(name, value);
+        }
+
+        Ok(Self::new(headers, Bytes::copy_from_slice(body)))
+    }
+}
+
+/// A multipart body.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Multipart {
+    boundary: String,
+    preamble: Bytes,
+    parts: Vec<Part>,
+    epilogue: Bytes,
+}
+
+/// The result of [`Multipart::from_request`].
+#[derive(Debug)]
+pub enum Parsed {
+    /// The request had a multipart body.
+    Multipart {
+        /// The request, without its body.
+        parts: request::Parts,
+        /// The body of the request.
+        multipart: Multipart,
+    },
+    /// The request did not have a multipart body, its body exceeded the limit, or its body was
+    /// malformed.
+    ///
+    /// The request yields the same body as the original request.
+    Unchanged(Request<Body>),
+}
+
+impl Multipart {
+    /// Creates an empty multipart body with a boundary.
+    pub fn new(boundary: impl Into<String>) -> Self {
+        Self {
+            boundary: boundary.into(),
+            preamble: Bytes::new(),
+            parts: Vec::new(),
+            epilogue: Bytes::from_static(b"\r\n"),
+        }
+    }
+
+    /// Parses a multipart body with a boundary.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Multipart`] if the body is malformed.
+    pub fn parse(boundary: impl Into<String>, data: &[u8]) -> Result<Self, Error> {
+        let boundary = boundary.into();
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let separator = [b"\r\n".as_slice(), &delimiter].concat();
+
+        let (preamble, mut rest) = if data.starts_with(&delimiter) {
+            (&data[..0], &data[delimiter.len()..])
+        } else {
+            let start = data.find(&separator).ok_or(Error::Multipart)?;
+            (&data[..start], &data[start + separator.len()..])
+        };
+
+        let mut parts = Vec::new();
+
+        loop {
+            if let Some(epilogue) = rest.strip_prefix(b"--") {
+                return Ok(Self {
+                    boundary,
+                    preamble: Bytes::copy_from_slice(preamble),
+                    parts,
+                    epilogue: Bytes::copy_from_slice(epilogue),
+                });
+            }
+
+            let rest_start = rest
+                .find(b"\r\n")
+                .filter(|&i| rest[..i].iter().all(|b| *b == b' ' || *b == b'\t'))
+                .ok_or(Error::Multipart)?;
+            rest = &rest[rest_start + 2..];
+
+            let end = rest.find(&separator).ok_or(Error::Multipart)?;
+            parts.push(Part::parse(&rest[..end])?);
+            rest = &rest[end + separator.len()..];
+        }
+    }
+
+    /// Reads a multipart body from a request, if it is no larger than `max` bytes.
+    ///
+    /// The request is returned unchanged if it does not have a multipart content type, or if its
+    /// body exceeds the limit or is malformed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request body fails before it has been read.
+    pub async fn from_request(req: Request<Body>, max: usize) -> Result<Parsed, Error> {
+        let Some(boundary) = boundary(req.headers()) else {
+            return Ok(Parsed::Unchanged(req));
+        };
+
+        let (parts, body) = req.into_parts();
+
+        let data = match body.collect_up_to(max).await? {
+            Bounded::Complete { data, .. } => data,
+            Bounded::TooLarge(body) => {
+                return Ok(Parsed::Unchanged(Request::from_parts(parts, body)))
+            }
+        };
+
+        Ok(match Self::parse(boundary, &data) {
+            Ok(multipart) => Parsed::Multipart { parts, multipart },
+            Err(_) => Parsed::Unchanged(Request::from_parts(parts, Body::from(Full::new(data)))),
+        })
+    }
+
+    /// The boundary of the body.
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// The parts of the body.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// The parts of the body, for modification.
+    pub fn parts_mut(&mut self) -> &mut Vec<Part> {
+        &mut self.parts
+    }
+
+    /// The first part with a form field name.
+    pub fn get(&self, name: &str) -> Option<&Part> {
+        self.parts
+            .iter()
+            .find(|part| part.name().as_deref() == Some(name))
+    }
+
+    /// The first part with a form field name, for modification.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Part> {
+        self.parts
+            .iter_mut()
+            .find(|part| part.name().as_deref() == Some(name))
+    }
+
+    /// Add a part to the end of the body.
+    pub fn push(&mut self, part: Part) {
+        self.parts.push(part);
+    }
+
+    /// Remove every part with a form field name, returning the number of parts that were removed.
+    pub fn remove(&mut self, name: &str) -> usize {
+        let len = self.parts.len();
+        self.parts
+            .retain(|part| part.name().as_deref() != Some(name));
+        len - self.parts.len()
+    }
+
+    /// Serializes the body.
+    ///
+    /// Part bodies are written unchanged, so a part whose body contains the delimiter will be
+    /// split when the body is parsed.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut out = Vec::new();
+
+        if !self.preamble.is_empty() {
+            out.extend_from_slice(&self.preamble);
+            out.extend_from_slice(b"\r\n");
+        }
+
+        for part in &self.parts {
+            out.extend_from_slice(b"--");
+            out.extend_from_slice(self.boundary.as_bytes());
+            out.extend_from_slice(b"\r\n");
+
+            for (name, value) in &part.headers {
+                out.extend_from_slice(name.as_str().as_bytes());
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(&part.body);
+            out.extend_from_slice(b"\r\n");
+        }
+
+        out.extend_from_slice(b"--");
+        out.extend_from_slice(self.boundary.as_bytes());
+        out.extend_from_slice(b"--");
+        out.extend_from_slice(&self.epilogue);
+
+        Bytes::from(out)
+    }
+
+    /// Creates a request with the body.
+    ///
+    /// The `Content-Length` header is set to the length of the body. The `Content-Type` header is
+    /// kept if it has the same boundary as the body, and is otherwise replaced with
+    /// `multipart/form-data`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the boundary contains characters that are not valid in a header value.
+    pub fn into_request(self, mut parts: request::Parts) -> Request<Body> {
+        let body = self.to_bytes();
+
+        if boundary(&parts.headers).as_deref() != Some(self.boundary.as_str()) {
+            parts.headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::try_from(format!(
+                    "multipart/form-data; boundary={}",
+                    quote(&self.boundary)
+                ))
+                .expect("Invalid boundary"),
+            );
+        }
+
+        parts.headers.remove(TRANSFER_ENCODING);
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+        Request::from_parts(parts, Body::from(Full::new(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --xyz\r\n\
+        content-disposition: form-data; name=\"field\"\r\n\
+        \r\n\
+        value\r\n\
+        --xyz\r\n\
+        content-disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        content-type: text/plain\r\n\
+        \r\n\
+        line one\r\nline two\r\n\
+        --xyz--\r\n";
+
+    fn request(body: &'static [u8]) -> Request<Body> {
+        Request::builder()
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=\"xyz\"")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_boundary() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("Multipart/Form-Data; charset=utf-8; boundary=abc"),
+        );
+        assert_eq!(boundary(&headers).as_deref(), Some("abc"));
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert_eq!(boundary(&headers), None);
+    }
+
+    #[test]
+    fn parses_parts() {
+        let multipart = Multipart::parse("xyz", BODY).unwrap();
+
+        assert_eq!(multipart.parts().len(), 2);
+        assert_eq!(multipart.get("field").unwrap().body(), "value");
+
+        let upload = multipart.get("upload").unwrap();
+        assert_eq!(upload.filename().as_deref(), Some("a \"b\".txt"));
+        assert_eq!(upload.content_type(), Some("text/plain"));
+        assert_eq!(upload.body(), "line one\r\nline two");
+    }
+
+    #[test]
+    fn round_trips() {
+        let multipart = Multipart::parse("xyz", BODY).unwrap();
+        assert_eq!(multipart.to_bytes(), BODY);
+    }
+
+    #[test]
+    fn rejects_malformed_body() {
+        assert!(matches!(
+            Multipart::parse("xyz", b"--xyz\r\nno end"),
+            Err(Error::Multipart)
+        ));
+        assert!(matches!(
+            Multipart::parse("xyz", b"no delimiter"),
+            Err(Error::Multipart)
+        ));
+    }
+
+    #[tokio::test]
+    async fn replaces_file_part() {
+        let Parsed::Multipart {
+            parts,
+            mut multipart,
+        } = Multipart::from_request(request(BODY), 1024).await.unwrap()
+        else {
+            panic!("body was not parsed");
+        };
+
+        multipart
+            .get_mut("upload")
+            .unwrap()
+            .set_file("new.bin", "application/octet-stream", "new");
+        multipart.push(Part::text("extra", "1"));
+
+        let req = multipart.into_request(parts);
+        let len = req.headers()[CONTENT_LENGTH].to_str().unwrap().parse();
+        assert_eq!(
+            req.headers()[CONTENT_TYPE],
+            "multipart/form-data; boundary=\"xyz\""
+        );
+
+        let data = req.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(len, Ok(data.len()));
+
+        let multipart = Multipart::parse("xyz", &data).unwrap();
+        let upload = multipart.get("upload").unwrap();
+        assert_eq!(upload.filename().as_deref(), Some("new.bin"));
+        assert_eq!(upload.content_type(), Some("application/octet-stream"));
+        assert_eq!(upload.body(), "new");
+        assert_eq!(multipart.get("extra").unwrap().body(), "1");
+    }
+
+    #[tokio::test]
+    async fn leaves_other_requests_unchanged() {
+        let res = Multipart::from_request(request(BODY), 8).await.unwrap();
+        let Parsed::Unchanged(req) = res else {
+            panic!("body was parsed");
+        };
+        let data = req.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(data, BODY);
+
+        let req = Request::new(Body::from("hello"));
+        assert!(matches!(
+            Multipart::from_request(req, 1024).await.unwrap(),
+            Parsed::Unchanged(_)
+        ));
+    }
+}
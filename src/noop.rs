@@ -8,7 +8,8 @@ use crate::{HttpHandler, WebSocketHandler};
 pub struct NoopHandler(());
 
 impl NoopHandler {
-    pub(crate) fn new() -> Self {
+    /// Creates a new no-op handler.
+    pub fn new() -> Self {
         NoopHandler(())
     }
 }
@@ -0,0 +1,121 @@
+use bytes::BytesMut;
+use std::sync::{Arc, Mutex};
+
+/// The default size of the buffers of a pool.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+/// The default number of idle buffers that a pool keeps.
+const DEFAULT_MAX_IDLE: usize = 1024;
+
+#[derive(Debug)]
+struct Shared {
+    buffer_size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<BytesMut>>,
+}
+
+/// A pool of buffers that are reused to copy data through tunnels and bodies, instead of
+/// allocating buffers for each of them.
+///
+/// A pool can be configured for a proxy with [`ProxyBuilder::with_buffer_pool`], which copies
+/// the tunnels that are not intercepted with its buffers, and passed to
+/// [`Body::from_reader_with_pool`]. Clones of a pool share the same buffers.
+///
+/// [`ProxyBuilder::with_buffer_pool`]: crate::builder::ProxyBuilder::with_buffer_pool
+/// [`Body::from_reader_with_pool`]: crate::Body::from_reader_with_pool
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::BufferPool;
+///
+/// // Copy with 32 KiB buffers, keeping up to 4096 of them when they are not used.
+/// let pool = BufferPool::new(32 * 1024, 4096);
+/// ```
+#[derive(Clone, Debug)]
+pub struct BufferPool(Arc<Shared>);
+
+impl BufferPool {
+    /// Creates a pool of buffers of `buffer_size` bytes, which keeps up to `max_idle` buffers
+    /// that are not used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer_size` is 0.
+    pub fn new(buffer_size: usize, max_idle: usize) -> Self {
+        assert!(buffer_size > 0, "buffer size must be greater than 0");
+
+        Self(Arc::new(Shared {
+            buffer_size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// The size of the buffers of the pool.
+    pub fn buffer_size(&self) -> usize {
+        self.0.buffer_size
+    }
+
+    /// The number of buffers that are kept by the pool and not used.
+    pub fn idle(&self) -> usize {
+        self.0.idle.lock().unwrap().len()
+    }
+
+    /// Take an empty buffer from the pool, or allocate one if the pool has none.
+    pub(crate) fn get(&self) -> BytesMut {
+        self.0
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.0.buffer_size))
+    }
+
+    /// Return a buffer to the pool, unless it is full or the buffer has shrunk.
+    pub(crate) fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+
+        if buf.capacity() < self.0.buffer_size {
+            return;
+        }
+
+        let mut idle = self.0.idle.lock().unwrap();
+
+        if idle.len() < self.0.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER_SIZE, DEFAULT_MAX_IDLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = BufferPool::new(16, 1);
+
+        let mut buf = pool.get();
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.idle(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+
+        pool.put(BytesMut::with_capacity(16));
+        pool.put(BytesMut::with_capacity(16));
+        assert_eq!(pool.idle(), 1);
+
+        pool.put(BytesMut::with_capacity(4));
+        assert_eq!(pool.idle(), 1);
+    }
+}
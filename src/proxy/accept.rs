@@ -0,0 +1,239 @@
+use super::{detect, internal, Clients, InternalProxy, Options};
+use crate::{certificate_authority::CertificateAuthority, HttpHandler, WebSocketHandler};
+use hyper::service::service_fn;
+use hyper_util::{
+    client::legacy::connect::Connect,
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_graceful::{ShutdownGuard, WeakShutdownGuard};
+use tokio_tungstenite::Connector;
+use tracing::{debug, error};
+
+/// The number of pending connections that each listener that is bound by [`bind`] queues.
+const BACKLOG: u32 = 1024;
+
+/// Binds the listeners of the accept loops of a proxy to an address.
+///
+/// On Unix, each loop has its own socket with `SO_REUSEPORT` set, so that the kernel distributes
+/// incoming connections between the loops instead of them all accepting from one socket.
+/// Elsewhere, or with a single loop, only one socket is bound, which the loops share.
+#[cfg_attr(
+    not(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    )),
+    allow(unused_variables)
+)]
+pub(crate) async fn bind(addr: SocketAddr, loops: usize) -> io::Result<Vec<TcpListener>> {
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    if loops > 1 {
+        use tokio::net::TcpSocket;
+
+        let mut addr = addr;
+        let mut listeners = Vec::with_capacity(loops);
+
+        for _ in 0..loops {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            socket.set_reuseport(true)?;
+            socket.bind(addr)?;
+
+            let listener = socket.listen(BACKLOG)?;
+            // The remaining sockets are bound to the port that the first one was assigned.
+            addr = listener.local_addr()?;
+            listeners.push(listener);
+        }
+
+        return Ok(listeners);
+    }
+
+    Ok(vec![TcpListener::bind(addr).await?])
+}
+
+/// The state that is needed to serve the connections accepted by one accept loop of a proxy.
+///
+/// Each accept loop has its own clone, which it clones again for each connection that it accepts.
+pub(crate) struct Acceptor<C, CA, H, W> {
+    pub ca: Arc<CA>,
+    pub clients: Clients<C>,
+    pub http_handler: H,
+    pub websocket_handler: W,
+    pub websocket_connector: Option<Connector>,
+    pub server: Builder<TokioExecutor>,
+    pub options: Arc<Options>,
+}
+
+impl<C, CA, H, W> Clone for Acceptor<C, CA, H, W>
+where
+    C: Clone,
+    H: Clone,
+    W: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            ca: Arc::clone(&self.ca),
+            clients: self.clients.clone(),
+            http_handler: self.http_handler.clone(),
+            websocket_handler: self.websocket_handler.clone(),
+            websocket_connector: self.websocket_connector.clone(),
+            server: self.server.clone(),
+            options: Arc::clone(&self.options),
+        }
+    }
+}
+
+impl<C, CA, H, W> Acceptor<C, CA, H, W>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    CA: CertificateAuthority,
+    H: HttpHandler,
+    W: WebSocketHandler,
+{
+    /// Accepts connections from a listener until the proxy is shut down, serving each of them in
+    /// its own task.
+    pub(crate) async fn accept(&self, listener: &TcpListener, guard: WeakShutdownGuard) {
+        loop {
+            tokio::select! {
+                res = listener.accept() => {
+                    let (tcp, client_addr) = match res {
+                        Ok((tcp, client_addr)) => (tcp, client_addr),
+                        Err(e) => {
+                            error!("Failed to accept incoming connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let acceptor = self.clone();
+                    guard
+                        .clone()
+                        .upgrade()
+                        .into_spawn_task_fn(move |guard| acceptor.serve(tcp, client_addr, guard));
+                }
+                _ = guard.cancelled() => {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn serve(self, tcp: TcpStream, mut client_addr: SocketAddr, guard: ShutdownGuard) {
+        let options = self.options;
+        let detected = match detect::detect(
+            tcp,
+            options.protocol_detection,
+            options.header_read_timeout,
+            &mut client_addr,
+        )
+        .await
+        {
+            Ok(detected) => detected,
+            Err(e) => {
+                debug!("Failed to detect protocol: {}", e);
+                return;
+            }
+        };
+
+        #[cfg(feature = "admin")]
+        let connection = options
+            .admin
+            .as_ref()
+            .map(|admin| Arc::new(admin.open_connection(client_addr)));
+
+        let proxy = InternalProxy {
+            ca: self.ca,
+            clients: self.clients,
+            server: self.server.clone(),
+            http_handler: self.http_handler,
+            websocket_handler: self.websocket_handler,
+            websocket_connector: self.websocket_connector,
+            options,
+            client_addr,
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            tunnel_id: None,
+            client_certificate: None,
+            #[cfg(feature = "fingerprint")]
+            tls_fingerprint: None,
+            connect_target: None,
+            tunnel_authority: None,
+            #[cfg(feature = "admin")]
+            connection,
+        };
+
+        let tcp = match detected {
+            detect::Detected::Http(tcp) => tcp,
+            detect::Detected::Tls(tcp, authority) => {
+                proxy.proxy_direct(tcp, authority, false).await;
+                return;
+            }
+            detect::Detected::Socks(tcp, authority) => {
+                proxy.proxy_direct(tcp, authority, true).await;
+                return;
+            }
+        };
+
+        let conn = self.server.serve_connection_with_upgrades(
+            TokioIo::new(tcp),
+            service_fn(|req| proxy.clone().proxy(req)),
+        );
+
+        let mut conn = std::pin::pin!(conn);
+
+        if let Err(err) = tokio::select! {
+            conn = conn.as_mut() => conn,
+            _ = guard.cancelled() => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        } {
+            if internal::is_closed(err.as_ref()) {
+                debug!("Closed connection without a response");
+            } else {
+                error!("Error serving connection: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_a_listener_per_loop() {
+        let listeners = bind(SocketAddr::from(([127, 0, 0, 1], 0)), 4)
+            .await
+            .unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        if cfg!(all(
+            unix,
+            not(any(
+                target_os = "solaris",
+                target_os = "illumos",
+                target_os = "cygwin"
+            ))
+        )) {
+            assert_eq!(listeners.len(), 4);
+            assert!(listeners
+                .iter()
+                .all(|listener| listener.local_addr().unwrap() == addr));
+        } else {
+            assert_eq!(listeners.len(), 1);
+        }
+
+        for _ in 0..8 {
+            TcpStream::connect(addr).await.unwrap();
+        }
+    }
+}
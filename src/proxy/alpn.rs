@@ -0,0 +1,132 @@
+use super::happy_eyeballs::{self, LocalBind};
+use hyper::http::uri::Authority;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// The TLS configuration that upstream servers are probed with by default.
+fn default_tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+            Arc::new(
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+/// Probes upstream servers for the protocol that they negotiate with ALPN, remembering the
+/// protocol of each server.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AlpnProbe {
+    pub tls_config: Option<Arc<ClientConfig>>,
+    negotiated: Arc<Mutex<HashMap<Authority, Option<Vec<u8>>>>>,
+}
+
+impl AlpnProbe {
+    /// The protocol that an upstream server negotiates when it is offered HTTP/2 and HTTP/1.1.
+    pub(crate) async fn negotiate(
+        &self,
+        authority: &Authority,
+        bind: &LocalBind,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if let Some(protocol) = self.negotiated.lock().unwrap().get(authority) {
+            return Ok(protocol.clone());
+        }
+
+        let mut config = (*self.tls_config.clone().unwrap_or_else(default_tls_config)).clone();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let server =
+            happy_eyeballs::connect(host, authority.port_u16().unwrap_or(443), bind).await?;
+        let server = TlsConnector::from(Arc::new(config))
+            .connect(server_name, server)
+            .await?;
+
+        let protocol = server.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+
+        self.negotiated
+            .lock()
+            .unwrap()
+            .insert(authority.clone(), protocol.clone());
+
+        Ok(protocol)
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::{certificate_authority::CertificateAuthority, test::TestCa};
+    use std::net::SocketAddr;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio_rustls::TlsAcceptor;
+
+    async fn start_server(ca: &TestCa, alpn_protocols: Vec<Vec<u8>>) -> Authority {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let authority: Authority = format!("localhost:{}", listener.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+
+        let mut config = (*ca.authority().gen_server_config(&authority).await).clone();
+        config.alpn_protocols = alpn_protocols;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        authority
+    }
+
+    #[tokio::test]
+    async fn negotiates_upstream_protocol() {
+        let ca = TestCa::generate();
+        let probe = AlpnProbe {
+            tls_config: Some(Arc::new(ca.client_config())),
+            ..Default::default()
+        };
+
+        let bind = LocalBind::default();
+
+        let http1 = start_server(&ca, vec![b"http/1.1".to_vec()]).await;
+        let http2 = start_server(&ca, vec![b"h2".to_vec(), b"http/1.1".to_vec()]).await;
+        let none = start_server(&ca, Vec::new()).await;
+
+        assert_eq!(
+            probe.negotiate(&http1, &bind).await.unwrap(),
+            Some(b"http/1.1".to_vec())
+        );
+        assert_eq!(
+            probe.negotiate(&http2, &bind).await.unwrap(),
+            Some(b"h2".to_vec())
+        );
+        assert_eq!(probe.negotiate(&none, &bind).await.unwrap(), None);
+    }
+}
@@ -1,26 +1,32 @@
+use super::{
+    happy_eyeballs::LocalBind, CircuitBreaker, ClientAuth, Clients, InterceptionCache, Options,
+};
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+use crate::UpstreamConnector;
 use crate::{
-    certificate_authority::CertificateAuthority, Body, HttpHandler, NoopHandler, Proxy,
-    WebSocketHandler,
+    access_log::AccessLog, certificate_authority::CertificateAuthority, AlpnPolicy, Body,
+    BodyLimitAction, BufferPool, DnsCache, Dscp, ExpectContinue, HttpHandler, NoopHandler,
+    PassthroughList, ProtocolDetection, Proxy, RedirectPolicy, RequestHandler, ResponseHandler,
+    RetryPolicy, SniRoute, SplitHandler, UpstreamProtocol, WebSocketHandler,
 };
 #[cfg(feature = "rustls-client")]
 use hyper_rustls::{HttpsConnector as RustlsConnector, HttpsConnectorBuilder};
 #[cfg(feature = "native-tls-client")]
 use hyper_tls::HttpsConnector as NativeTlsConnector;
 use hyper_util::{
-    client::legacy::{
-        connect::{Connect, HttpConnector},
-        Client,
-    },
-    rt::TokioExecutor,
+    client::legacy::{connect::Connect, Client},
+    rt::{TokioExecutor, TokioTimer},
     server::conn::auto::Builder,
 };
 use std::{
+    any::TypeId,
     future::{pending, Future, Pending},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 use tokio::net::TcpListener;
-use tokio_tungstenite::Connector;
+use tokio_tungstenite::{tungstenite::protocol::WebSocketConfig, Connector};
 
 /// A builder for creating a [`Proxy`].
 ///
@@ -65,6 +71,8 @@ pub struct WantsAddr(());
 pub(crate) enum AddrOrListener {
     Addr(SocketAddr),
     Listener(TcpListener),
+    #[cfg(all(feature = "handoff", unix))]
+    Inherited(SocketAddr),
 }
 
 impl ProxyBuilder<WantsAddr> {
@@ -77,6 +85,7 @@ impl ProxyBuilder<WantsAddr> {
     pub fn with_addr(self, addr: SocketAddr) -> ProxyBuilder<WantsClient> {
         ProxyBuilder(WantsClient {
             al: AddrOrListener::Addr(addr),
+            bind: LocalBind::default(),
         })
     }
 
@@ -84,6 +93,20 @@ impl ProxyBuilder<WantsAddr> {
     pub fn with_listener(self, listener: TcpListener) -> ProxyBuilder<WantsClient> {
         ProxyBuilder(WantsClient {
             al: AddrOrListener::Listener(listener),
+            bind: LocalBind::default(),
+        })
+    }
+
+    /// Listen on the socket that was inherited from a predecessor that called
+    /// [`Handoff::spawn_successor`], or bind to an address if there is none.
+    ///
+    /// [`Handoff::spawn_successor`]: crate::handoff::Handoff::spawn_successor
+    #[cfg(all(feature = "handoff", unix))]
+    #[cfg_attr(docsrs, doc(cfg(all(feature = "handoff", unix))))]
+    pub fn with_inherited_listener(self, addr: SocketAddr) -> ProxyBuilder<WantsClient> {
+        ProxyBuilder(WantsClient {
+            al: AddrOrListener::Inherited(addr),
+            bind: LocalBind::default(),
         })
     }
 }
@@ -98,29 +121,61 @@ impl Default for ProxyBuilder<WantsAddr> {
 #[derive(Debug)]
 pub struct WantsClient {
     al: AddrOrListener,
+    bind: LocalBind,
 }
 
 impl ProxyBuilder<WantsClient> {
+    /// Bind the sockets of upstream connections to a local address, so that proxied traffic
+    /// leaves from it on a multi-homed machine.
+    ///
+    /// Only the connections to upstream servers of the same address family as the local address
+    /// are bound. This applies to the clients of [`ProxyBuilder::with_rustls_client`] and
+    /// `ProxyBuilder::with_native_tls_client`, and to the connections that the proxy opens itself,
+    /// such as for tunnels that are not intercepted. The connector of a client set with
+    /// [`ProxyBuilder::with_client`] must be bound separately.
+    pub fn with_local_address(mut self, addr: IpAddr) -> Self {
+        self.0.bind.address = Some(addr);
+        self
+    }
+
+    /// Bind the sockets of upstream connections to a network interface with `SO_BINDTODEVICE`,
+    /// so that proxied traffic leaves through a particular link or VPN.
+    ///
+    /// This applies to the same connections as [`ProxyBuilder::with_local_address`]. Binding to an
+    /// interface usually requires the `CAP_NET_RAW` capability.
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    #[cfg_attr(
+        docsrs,
+        doc(cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))
+    )]
+    pub fn with_interface(mut self, interface: impl Into<String>) -> Self {
+        self.0.bind.interface = Some(interface.into());
+        self
+    }
+
+    /// Resolve the hosts of upstream connections with a cache, which is shared by the built-in
+    /// clients, the connections to WebSocket servers and the tunnels that are not intercepted.
+    ///
+    /// Clients that are set with [`ProxyBuilder::with_client`] can resolve hosts with the cache
+    /// by building their connector with [`HttpConnector::new_with_resolver`].
+    ///
+    /// [`HttpConnector::new_with_resolver`]: hyper_util::client::legacy::connect::HttpConnector::new_with_resolver
+    pub fn with_dns_cache(mut self, cache: DnsCache) -> Self {
+        self.0.bind.dns_cache = Some(cache);
+        self
+    }
+
     /// Use a hyper-rustls connector.
     #[cfg(feature = "rustls-client")]
     #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
-    pub fn with_rustls_client(self) -> ProxyBuilder<WantsCa<RustlsConnector<HttpConnector>>> {
-        let https = HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_or_http()
-            .enable_http1();
-
-        #[cfg(feature = "http2")]
-        let https = https.enable_http2();
-
-        let https = https.build();
+    pub fn with_rustls_client(self) -> ProxyBuilder<WantsCa<RustlsConnector<UpstreamConnector>>> {
+        let mut clients = rustls_clients(self.0.bind.clone(), None);
+        clients.rebind = Some(Arc::new(rustls_clients));
 
         ProxyBuilder(WantsCa {
             al: self.0.al,
-            client: Client::builder(TokioExecutor::new())
-                .http1_title_case_headers(true)
-                .http1_preserve_header_case(true)
-                .build(https),
+            bind: self.0.bind,
+            clients,
         })
     }
 
@@ -129,38 +184,139 @@ impl ProxyBuilder<WantsClient> {
     #[cfg_attr(docsrs, doc(cfg(feature = "native-tls-client")))]
     pub fn with_native_tls_client(
         self,
-    ) -> ProxyBuilder<WantsCa<NativeTlsConnector<HttpConnector>>> {
-        let https = NativeTlsConnector::new();
+    ) -> ProxyBuilder<WantsCa<NativeTlsConnector<UpstreamConnector>>> {
+        let mut clients = native_tls_clients(self.0.bind.clone(), None);
+        clients.rebind = Some(Arc::new(native_tls_clients));
 
         ProxyBuilder(WantsCa {
             al: self.0.al,
-            client: Client::builder(TokioExecutor::new())
-                .http1_title_case_headers(true)
-                .http1_preserve_header_case(true)
-                .build(https),
+            bind: self.0.bind,
+            clients,
         })
     }
 
     /// Use a custom client.
+    ///
+    /// This client will be used for [`UpstreamProtocol::Auto`], and for any other protocol that
+    /// does not have a client set with [`ProxyBuilder::with_http1_client`] or
+    /// `ProxyBuilder::with_http2_client`.
     pub fn with_client<C>(self, client: Client<C, Body>) -> ProxyBuilder<WantsCa<C>>
     where
         C: Connect + Clone + Send + Sync + 'static,
     {
         ProxyBuilder(WantsCa {
             al: self.0.al,
-            client,
+            bind: self.0.bind,
+            clients: Clients::new(client),
         })
     }
 }
 
+/// The built-in hyper-rustls clients, whose connector binds its sockets with `bind` and connects
+/// to `addr` if it is set.
+#[cfg(feature = "rustls-client")]
+fn rustls_clients(
+    bind: LocalBind,
+    addr: Option<SocketAddr>,
+) -> Clients<RustlsConnector<UpstreamConnector>> {
+    let https = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1();
+
+    #[cfg(feature = "http2")]
+    let https = https.enable_http2();
+
+    let https = https.wrap_connector(UpstreamConnector::new(bind.clone(), addr));
+
+    let http1 = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(UpstreamConnector::new(bind, addr));
+
+    let mut clients = Clients::new(
+        Client::builder(TokioExecutor::new())
+            .http1_title_case_headers(true)
+            .http1_preserve_header_case(true)
+            .build(https.clone()),
+    );
+
+    clients.http1 = Some(
+        Client::builder(TokioExecutor::new())
+            .http1_title_case_headers(true)
+            .http1_preserve_header_case(true)
+            .build(http1),
+    );
+
+    #[cfg(feature = "http2")]
+    {
+        clients.http2 = Some(
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(https),
+        );
+    }
+
+    clients
+}
+
+/// The built-in hyper-tls clients, whose connector binds its sockets with `bind` and connects to
+/// `addr` if it is set.
+#[cfg(feature = "native-tls-client")]
+fn native_tls_clients(
+    bind: LocalBind,
+    addr: Option<SocketAddr>,
+) -> Clients<NativeTlsConnector<UpstreamConnector>> {
+    let https = NativeTlsConnector::new_with_connector(UpstreamConnector::new(bind, addr));
+
+    #[allow(unused_mut)]
+    let mut clients = Clients::new(
+        Client::builder(TokioExecutor::new())
+            .http1_title_case_headers(true)
+            .http1_preserve_header_case(true)
+            .build(https.clone()),
+    );
+
+    #[cfg(feature = "http2")]
+    {
+        clients.http2 = Some(
+            Client::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build(https),
+        );
+    }
+
+    clients
+}
+
 /// Builder state that needs a certificate authority.
 #[derive(Debug)]
 pub struct WantsCa<C> {
     al: AddrOrListener,
-    client: Client<C, Body>,
+    bind: LocalBind,
+    clients: Clients<C>,
 }
 
 impl<C> ProxyBuilder<WantsCa<C>> {
+    /// Set the client to use for [`UpstreamProtocol::Http1`].
+    ///
+    /// The client's connector should not negotiate HTTP/2 with ALPN.
+    pub fn with_http1_client(mut self, client: Client<C, Body>) -> Self {
+        self.0.clients.http1 = Some(client);
+        self
+    }
+
+    /// Set the client to use for [`UpstreamProtocol::Http2`].
+    ///
+    /// The client should be built with `http2_only` enabled.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    pub fn with_http2_client(mut self, client: Client<C, Body>) -> Self {
+        self.0.clients.http2 = Some(client);
+        self
+    }
+
     /// Set the certificate authority to use.
     pub fn with_ca<CA: CertificateAuthority>(
         self,
@@ -168,12 +324,16 @@ impl<C> ProxyBuilder<WantsCa<C>> {
     ) -> ProxyBuilder<WantsHandlers<C, CA, NoopHandler, NoopHandler, Pending<()>>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
-            client: self.0.client,
+            clients: self.0.clients,
             ca,
             http_handler: NoopHandler::new(),
             websocket_handler: NoopHandler::new(),
             websocket_connector: None,
-            server: None,
+            server: default_server(),
+            options: Options {
+                local_bind: self.0.bind,
+                ..Default::default()
+            },
             graceful_shutdown: pending(),
         })
     }
@@ -182,33 +342,124 @@ impl<C> ProxyBuilder<WantsCa<C>> {
 /// Builder state that can take additional handlers.
 pub struct WantsHandlers<C, CA, H, W, F> {
     al: AddrOrListener,
-    client: Client<C, Body>,
+    clients: Clients<C>,
     ca: CA,
     http_handler: H,
     websocket_handler: W,
     websocket_connector: Option<Connector>,
-    server: Option<Builder<TokioExecutor>>,
+    server: Builder<TokioExecutor>,
+    options: Options,
     graceful_shutdown: F,
 }
 
+/// Whether a handler is the built-in [`NoopHandler`], on its own or for both sides of a
+/// [`SplitHandler`].
+fn is_noop<T: 'static>() -> bool {
+    TypeId::of::<T>() == TypeId::of::<NoopHandler>()
+        || TypeId::of::<T>() == TypeId::of::<SplitHandler<NoopHandler, NoopHandler>>()
+}
+
+fn default_server() -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .title_case_headers(true)
+        .preserve_header_case(true);
+    builder
+}
+
+/// A builder whose HTTP handler is a [`SplitHandler`].
+type SplitBuilder<C, CA, Req, Res, W, F> =
+    ProxyBuilder<WantsHandlers<C, CA, SplitHandler<Req, Res>, W, F>>;
+
+impl<C, CA, W, F> ProxyBuilder<WantsHandlers<C, CA, NoopHandler, W, F>> {
+    /// Set a handler for requests only, leaving responses unmodified.
+    ///
+    /// A response handler can be added with [`ProxyBuilder::with_response_handler`], and may be
+    /// of a different type. See [`SplitHandler`].
+    pub fn with_request_handler<Req: RequestHandler>(
+        self,
+        handler: Req,
+    ) -> SplitBuilder<C, CA, Req, NoopHandler, W, F> {
+        self.with_http_handler(SplitHandler::new(handler, NoopHandler::new()))
+    }
+
+    /// Set a handler for responses only, leaving requests unmodified.
+    ///
+    /// A request handler can be added with [`ProxyBuilder::with_request_handler`], and may be of
+    /// a different type. See [`SplitHandler`].
+    pub fn with_response_handler<Res: ResponseHandler>(
+        self,
+        handler: Res,
+    ) -> SplitBuilder<C, CA, NoopHandler, Res, W, F> {
+        self.with_http_handler(SplitHandler::new(NoopHandler::new(), handler))
+    }
+}
+
+impl<C, CA, Req, Res, W, F> SplitBuilder<C, CA, Req, Res, W, F> {
+    /// Set the handler for requests, keeping the handler for responses.
+    pub fn with_request_handler<Req2: RequestHandler>(
+        self,
+        handler: Req2,
+    ) -> SplitBuilder<C, CA, Req2, Res, W, F> {
+        self.map_http_handler(|http_handler| http_handler.with_request_handler(handler))
+    }
+
+    /// Set the handler for responses, keeping the handler for requests.
+    pub fn with_response_handler<Res2: ResponseHandler>(
+        self,
+        handler: Res2,
+    ) -> SplitBuilder<C, CA, Req, Res2, W, F> {
+        self.map_http_handler(|http_handler| http_handler.with_response_handler(handler))
+    }
+}
+
 impl<C, CA, H, W, F> ProxyBuilder<WantsHandlers<C, CA, H, W, F>> {
     /// Set the HTTP handler.
     pub fn with_http_handler<H2: HttpHandler>(
         self,
         http_handler: H2,
+    ) -> ProxyBuilder<WantsHandlers<C, CA, H2, W, F>> {
+        self.map_http_handler(|_| http_handler)
+    }
+
+    fn map_http_handler<H2>(
+        self,
+        f: impl FnOnce(H) -> H2,
     ) -> ProxyBuilder<WantsHandlers<C, CA, H2, W, F>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
-            client: self.0.client,
+            clients: self.0.clients,
             ca: self.0.ca,
-            http_handler,
+            http_handler: f(self.0.http_handler),
             websocket_handler: self.0.websocket_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            options: self.0.options,
             graceful_shutdown: self.0.graceful_shutdown,
         })
     }
 
+    /// Set the address to listen on, replacing the address or listener that was set before.
+    ///
+    /// This is useful for changing the address of a builder that was created by a preset, such
+    /// as [`Proxy::reverse`].
+    pub fn with_addr(self, addr: SocketAddr) -> Self {
+        ProxyBuilder(WantsHandlers {
+            al: AddrOrListener::Addr(addr),
+            ..self.0
+        })
+    }
+
+    /// Set a listener to use for the proxy server, replacing the address or listener that was set
+    /// before.
+    pub fn with_listener(self, listener: TcpListener) -> Self {
+        ProxyBuilder(WantsHandlers {
+            al: AddrOrListener::Listener(listener),
+            ..self.0
+        })
+    }
+
     /// Set the WebSocket handler.
     pub fn with_websocket_handler<W2: WebSocketHandler>(
         self,
@@ -216,12 +467,13 @@ impl<C, CA, H, W, F> ProxyBuilder<WantsHandlers<C, CA, H, W, F>> {
     ) -> ProxyBuilder<WantsHandlers<C, CA, H, W2, F>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
-            client: self.0.client,
+            clients: self.0.clients,
             ca: self.0.ca,
             http_handler: self.0.http_handler,
             websocket_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            options: self.0.options,
             graceful_shutdown: self.0.graceful_shutdown,
         })
     }
@@ -234,12 +486,432 @@ impl<C, CA, H, W, F> ProxyBuilder<WantsHandlers<C, CA, H, W, F>> {
         })
     }
 
+    /// Set the configuration of intercepted WebSocket connections.
+    ///
+    /// The configuration is used for both the connection with the client and the connection with
+    /// the server, and sets the maximum size of messages and frames that are read from either of
+    /// them. Fragmented messages are always reassembled before they are passed to the
+    /// [`WebSocketHandler`], so the maximum message size applies to the whole message. When a
+    /// message exceeds a limit, the default [`WebSocketHandler::handle_websocket`] stops forwarding
+    /// messages in that direction and sends a `1009 Message Too Big` close frame instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+    ///
+    /// let config = WebSocketConfig {
+    ///     max_message_size: Some(256 << 20),
+    ///     max_frame_size: None,
+    ///     ..Default::default()
+    /// };
+    /// ```
+    pub fn with_websocket_config(mut self, config: WebSocketConfig) -> Self {
+        self.0.options.websocket_config = Some(config);
+        self
+    }
+
+    /// Set the number of messages that are buffered in each direction of an intercepted WebSocket
+    /// while they are sent to the receiving peer.
+    ///
+    /// Messages are read from one peer while earlier messages are still being sent to the other,
+    /// until the buffer is full, at which point reading stops until the receiving peer catches up.
+    /// By default, each message is sent before the next one is read.
+    pub fn with_websocket_buffer(mut self, messages: usize) -> Self {
+        self.0.options.websocket_buffer = Some(messages);
+        self
+    }
+
     /// Set a custom server builder to use for the proxy server.
+    ///
+    /// This replaces any server options that have previously been set on this builder.
     pub fn with_server(self, server: Builder<TokioExecutor>) -> Self {
-        ProxyBuilder(WantsHandlers {
-            server: Some(server),
-            ..self.0
-        })
+        ProxyBuilder(WantsHandlers { server, ..self.0 })
+    }
+
+    /// Set the maximum size of the buffer used to read request headers.
+    ///
+    /// For HTTP/1 this limits the size of the read buffer, and must be at least 8192 bytes. For
+    /// HTTP/2 this limits the size of the header list.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `max` is less than 8192.
+    pub fn with_max_header_size(mut self, max: usize) -> Self {
+        self.0.server.http1().max_buf_size(max);
+        #[cfg(feature = "http2")]
+        self.0
+            .server
+            .http2()
+            .max_header_list_size(u32::try_from(max).unwrap_or(u32::MAX));
+        self
+    }
+
+    /// Set the maximum number of headers that will be accepted in an HTTP/1 request.
+    ///
+    /// Requests with more headers than this will be rejected with a 431 response.
+    pub fn with_max_headers(mut self, max: usize) -> Self {
+        self.0.server.http1().max_headers(max);
+        self
+    }
+
+    /// Set a timeout for reading the headers of an HTTP/1 request.
+    ///
+    /// If a client does not send the entire header within this time, the connection is closed.
+    /// This also limits how long the protocol of a connection may take to be detected, as
+    /// configured with [`ProxyBuilder::with_protocol_detection`].
+    pub fn with_header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.0.options.header_read_timeout = Some(timeout);
+        self.0
+            .server
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(timeout);
+        self
+    }
+
+    /// Set how long a tunnel that is not intercepted is kept open without any data being sent in
+    /// either direction.
+    ///
+    /// By default, tunnels are kept open until the client or the server closes them.
+    pub fn with_tunnel_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.0.options.tunnel.idle = Some(timeout);
+        self
+    }
+
+    /// Set the maximum time that a tunnel that is not intercepted is kept open, whether or not data
+    /// is being sent through it.
+    pub fn with_tunnel_max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.0.options.tunnel.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Set whether a tunnel that is not intercepted stays open in one direction after the other
+    /// direction is closed.
+    ///
+    /// By default, when the client or the server shuts down its side of a tunnel, the shutdown is
+    /// forwarded to the other side, and data is still forwarded in the other direction until it is
+    /// shut down too, which protocols such as git and SMTP rely on. If this is `false`, the tunnel
+    /// is closed as soon as either side shuts down.
+    pub fn with_tunnel_half_close(mut self, enabled: bool) -> Self {
+        self.0.options.tunnel.close_on_eof = !enabled;
+        self
+    }
+
+    /// Set the number of loops that accept connections from the proxy's listener.
+    ///
+    /// Each loop runs in its own task with its own clones of the clients and handlers, so that
+    /// connections are accepted on several worker threads of a multi-threaded runtime when a
+    /// single loop cannot keep up with the rate of new connections. Defaults to 1.
+    ///
+    /// When the proxy binds its address itself on Unix, each loop accepts from its own socket,
+    /// which has `SO_REUSEPORT` set so that the kernel balances connections between them. This
+    /// also lets other sockets of the same user with `SO_REUSEPORT` set bind the address. With a
+    /// listener that was set with [`ProxyBuilder::with_listener`], or on other platforms, the
+    /// loops share one socket.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `loops` is 0.
+    pub fn with_accept_loops(mut self, loops: usize) -> Self {
+        assert!(loops > 0, "number of accept loops must be greater than 0");
+        self.0.options.accept_loops = loops;
+        self
+    }
+
+    /// Start generating the certificate for the host of a CONNECT request as soon as it is
+    /// received, while the client starts its TLS handshake, instead of during the handshake.
+    ///
+    /// This calls [`CertificateAuthority::prefetch_server_config`], which generates and caches the
+    /// certificate with the built-in authorities. Certificates are also generated for hosts that
+    /// are not intercepted. Defaults to `false`.
+    pub fn with_certificate_prefetch(mut self, enabled: bool) -> Self {
+        self.0.options.certificate_prefetch = enabled;
+        self
+    }
+
+    /// Set the pool of buffers that the data of tunnels that are not intercepted is copied
+    /// through.
+    ///
+    /// By default, each proxy has its own pool of 8 KiB buffers. A pool can be shared with other
+    /// proxies, or with bodies created with [`Body::from_reader_with_pool`].
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.0.options.buffer_pool = pool;
+        self
+    }
+
+    /// Detect TLS, SOCKS5 and PROXY protocol connections on the proxy's listener, so that one port
+    /// can serve clients with different configurations.
+    pub fn with_protocol_detection(mut self, detection: ProtocolDetection) -> Self {
+        self.0.options.protocol_detection = detection;
+        self
+    }
+
+    /// Route the TLS connections that are detected on the proxy's listener for server names that
+    /// match a pattern, such as `example.com` or `*.example.com`, instead of intercepting them.
+    ///
+    /// This makes the proxy usable as an SNI router that only intercepts some hosts. TLS must be
+    /// detected with [`ProtocolDetection::with_tls`] for routes to apply.
+    pub fn with_sni_route(mut self, pattern: impl Into<String>, route: SniRoute) -> Self {
+        self.0.options.sni_routes.insert(pattern, route);
+        self
+    }
+
+    /// Set the maximum HTTP/2 frame size that will be accepted from clients.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    pub fn with_http2_max_frame_size(mut self, max: u32) -> Self {
+        self.0.server.http2().max_frame_size(max);
+        self
+    }
+
+    /// Set the maximum number of concurrent HTTP/2 streams per client connection.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    pub fn with_http2_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.0.server.http2().max_concurrent_streams(max);
+        self
+    }
+
+    /// Set the HTTP version to use when forwarding requests to a host.
+    ///
+    /// This can be overridden for a single request by inserting an [`UpstreamProtocol`] into the
+    /// request's extensions.
+    pub fn with_upstream_protocol(
+        mut self,
+        host: impl Into<String>,
+        protocol: UpstreamProtocol,
+    ) -> Self {
+        let mut host = host.into();
+        host.make_ascii_lowercase();
+        self.0.options.upstream_protocols.insert(host, protocol);
+        self
+    }
+
+    /// Set the DSCP that the packets of upstream connections to a host are marked with, such as
+    /// [`Dscp::LOWER_EFFORT`] to deprioritize bulk downloads.
+    ///
+    /// This applies to requests that are forwarded to the host, and to tunnels to the host that
+    /// are not intercepted. It can be overridden for a single request by inserting a [`Dscp`] into
+    /// the request's extensions.
+    ///
+    /// Requests are only marked when they are sent with the built-in clients of
+    /// `ProxyBuilder::with_rustls_client` or `ProxyBuilder::with_native_tls_client`, whose
+    /// connections are pooled separately for each DSCP. [`ProxyBuilder::build`] panics if a DSCP
+    /// is set for a custom client.
+    pub fn with_dscp(mut self, host: impl Into<String>, dscp: Dscp) -> Self {
+        let mut host = host.into();
+        host.make_ascii_lowercase();
+        self.0.options.dscp.insert(host, dscp);
+        self
+    }
+
+    /// Set the protocols that are offered with ALPN to clients whose tunnels to a host are
+    /// intercepted.
+    ///
+    /// By default, the protocols of the certificate authority's server config are offered. Some
+    /// clients misbehave when the proxy negotiates HTTP/2 with them for a host that only speaks
+    /// HTTP/1.1, which [`AlpnPolicy::Http1`] or `AlpnPolicy::Upstream` avoid.
+    pub fn with_alpn_policy(mut self, host: impl Into<String>, policy: AlpnPolicy) -> Self {
+        let mut host = host.into();
+        host.make_ascii_lowercase();
+        self.0.options.alpn_policies.insert(host, policy);
+        self
+    }
+
+    /// Set the TLS configuration that upstream servers are probed with for
+    /// [`AlpnPolicy::Upstream`].
+    ///
+    /// By default, servers are verified with the Mozilla root certificates.
+    #[cfg(feature = "rustls-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    pub fn with_alpn_probe_tls_config(
+        mut self,
+        config: Arc<tokio_rustls::rustls::ClientConfig>,
+    ) -> Self {
+        self.0.options.alpn_probe.tls_config = Some(config);
+        self
+    }
+
+    /// Connect to an address for a host and port, instead of the addresses that the host resolves
+    /// to, like curl's `--resolve` option.
+    ///
+    /// Requests keep their URI and `Host` header, and HTTPS requests send the host as their SNI.
+    /// `CONNECT` tunnels to the host and port that are not intercepted are also connected to the
+    /// address. This can be overridden for a single request by inserting a
+    /// [`ConnectTo`](crate::ConnectTo) into the request's extensions.
+    ///
+    /// Requests are only connected to the address when they are sent with the built-in clients of
+    /// `ProxyBuilder::with_rustls_client` or `ProxyBuilder::with_native_tls_client`, whose
+    /// connections are pooled separately for each address. [`ProxyBuilder::build`] panics if an
+    /// address is set for a custom client.
+    pub fn with_resolve(mut self, host: &str, port: u16, addr: SocketAddr) -> Self {
+        self.0.options.resolver.insert(host, port, addr);
+        self
+    }
+
+    /// Set how requests with an `Expect: 100-continue` header are handled.
+    ///
+    /// Defaults to [`ExpectContinue::Forward`].
+    pub fn with_expect_continue(mut self, expect_continue: ExpectContinue) -> Self {
+        self.0.options.expect_continue = expect_continue;
+        self
+    }
+
+    /// Set the maximum size of request bodies that will be passed to the HTTP handler and
+    /// forwarded to upstream servers.
+    pub fn with_max_request_body_size(mut self, max: usize) -> Self {
+        self.0.options.max_request_body_size = Some(max);
+        self
+    }
+
+    /// Set the maximum size of response bodies that will be passed to the HTTP handler and
+    /// forwarded to clients.
+    pub fn with_max_response_body_size(mut self, max: usize) -> Self {
+        self.0.options.max_response_body_size = Some(max);
+        self
+    }
+
+    /// Set what to do when a body exceeds its size limit.
+    ///
+    /// Defaults to [`BodyLimitAction::Reject`].
+    pub fn with_body_limit_action(mut self, action: BodyLimitAction) -> Self {
+        self.0.options.body_limit_action = action;
+        self
+    }
+
+    /// Set whether redirects from upstream servers are followed by the proxy.
+    ///
+    /// Defaults to [`RedirectPolicy::None`]. The policy can be overridden for a single request by
+    /// inserting a [`RedirectPolicy`] into its extensions.
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.0.options.redirect_policy = policy;
+        self
+    }
+
+    /// Set when requests that fail upstream are retried.
+    ///
+    /// By default, requests are not retried. The policy can be overridden for a single request by
+    /// inserting a [`RetryPolicy`] into its extensions.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.0.options.retry_policy = Some(policy);
+        self
+    }
+
+    /// Set a circuit breaker that stops forwarding requests to failing upstream hosts.
+    ///
+    /// While the breaker is open for a host, requests for it are passed to
+    /// [`HttpHandler::handle_circuit_open`] instead of being forwarded.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.0.options.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Set a cache of the decisions of [`HttpHandler::should_intercept`], so that repeated
+    /// `CONNECT` requests for the same authority are not decided again until the decision expires.
+    pub fn with_interception_cache(mut self, cache: InterceptionCache) -> Self {
+        self.0.options.interception_cache = Some(cache);
+        self
+    }
+
+    /// Stop intercepting the tunnels to a host once one of its TLS handshakes fails in a way that
+    /// suggests passthrough, such as when a client rejects the proxy's certificate because it pins
+    /// the certificates of the host.
+    ///
+    /// Failed handshakes are passed to [`HttpHandler::handle_tls_failure`] whether or not this is
+    /// set. The hosts of the tunnels that are not intercepted are added to `list`, and tunnels to
+    /// the hosts in it are not intercepted, without calling [`HttpHandler::should_intercept`].
+    pub fn with_auto_passthrough(mut self, list: PassthroughList) -> Self {
+        self.0.options.auto_passthrough = Some(list);
+        self
+    }
+
+    /// Require clients to authenticate with certificates in the TLS handshakes of intercepted
+    /// tunnels.
+    ///
+    /// The verified certificate of each tunnel is inserted into the extensions of its requests as
+    /// a [`ClientCertificate`](crate::ClientCertificate).
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.0.options.client_auth = Some(client_auth);
+        self
+    }
+
+    /// Set an access log that records each request handled by the proxy.
+    pub fn with_access_log(mut self, log: AccessLog) -> Self {
+        self.0.options.access_log = Some(log);
+        self
+    }
+
+    /// Set an audit log that records each modification the HTTP handler makes to requests and
+    /// responses.
+    #[cfg(feature = "audit")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "audit")))]
+    pub fn with_audit_log(mut self, log: crate::audit::AuditLog) -> Self {
+        self.0.options.audit_log = Some(log);
+        self
+    }
+
+    /// Set a blocklist of hosts and URLs, which is checked before the HTTP handler is called and
+    /// before any upstream server is contacted.
+    #[cfg(feature = "blocklist")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "blocklist")))]
+    pub fn with_blocklist(mut self, blocklist: crate::blocklist::Blocklist) -> Self {
+        self.0.options.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Re-encode response bodies that were decoded with [`decode_response`](crate::decode_response).
+    ///
+    /// When the HTTP handler returns a response whose body it decoded, the body is encoded with
+    /// the encoding that the client prefers, as described by [`encode_response`]. By default,
+    /// decoded bodies are sent without an encoding.
+    ///
+    /// [`encode_response`]: crate::encode_response
+    #[cfg(feature = "decoder")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+    pub fn with_recompression(mut self, level: crate::CompressionLevel) -> Self {
+        self.0.options.recompression = Some(level);
+        self
+    }
+
+    /// Set the TLS configuration that is used to connect to the servers of intercepted
+    /// DNS-over-TLS tunnels.
+    ///
+    /// By default, servers are verified with the Mozilla root certificates.
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    pub fn with_dns_tls_config(mut self, config: Arc<tokio_rustls::rustls::ClientConfig>) -> Self {
+        self.0.options.dns_tls_config = Some(config);
+        self
+    }
+
+    /// Set the admin API, which is served on its own listener while the proxy is running.
+    ///
+    /// The `CONNECT` tunnels that are intercepted are limited by the admin API's
+    /// [`Interception`](crate::admin::Interception) lists, before
+    /// [`HttpHandler::should_intercept`] is called.
+    #[cfg(feature = "admin")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "admin")))]
+    pub fn with_admin(mut self, admin: crate::admin::Admin) -> Self {
+        self.0.options.admin = Some(admin);
+        self
+    }
+
+    /// Set the handle that drains the proxy and passes its listener to a
+    /// [successor](crate::handoff).
+    #[cfg(all(feature = "handoff", unix))]
+    #[cfg_attr(docsrs, doc(cfg(all(feature = "handoff", unix))))]
+    pub fn with_handoff(mut self, handoff: crate::handoff::Handoff) -> Self {
+        self.0.options.handoff = Some(handoff);
+        self
+    }
+
+    /// Set the channel that live [events](crate::events) of the proxy are broadcast on.
+    #[cfg(feature = "events")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "events")))]
+    pub fn with_events(mut self, events: crate::events::Events) -> Self {
+        self.0.options.events = Some(events);
+        self
     }
 
     /// Set a future that when ready will gracefully shutdown the proxy server.
@@ -249,26 +921,54 @@ impl<C, CA, H, W, F> ProxyBuilder<WantsHandlers<C, CA, H, W, F>> {
     ) -> ProxyBuilder<WantsHandlers<C, CA, H, W, F2>> {
         ProxyBuilder(WantsHandlers {
             al: self.0.al,
-            client: self.0.client,
+            clients: self.0.clients,
             ca: self.0.ca,
             http_handler: self.0.http_handler,
             websocket_handler: self.0.websocket_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            options: self.0.options,
             graceful_shutdown,
         })
     }
 
     /// Build the proxy.
-    pub fn build(self) -> Proxy<C, CA, H, W, F> {
+    ///
+    /// If both handlers are [`NoopHandler`]s and nothing else needs to see the requests, such as
+    /// an access log or a body size limit, HTTP/1.1 requests are forwarded without invoking the
+    /// handlers or rewriting their headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a DSCP is set with [`ProxyBuilder::with_dscp`], or an address with
+    /// [`ProxyBuilder::with_resolve`], for a custom client, whose connector the proxy cannot mark
+    /// or pin.
+    pub fn build(self) -> Proxy<C, CA, H, W, F>
+    where
+        H: 'static,
+        W: 'static,
+    {
+        assert!(
+            self.0.clients.rebind.is_some() || self.0.options.dscp.is_empty(),
+            "DSCPs can only be set for the built-in clients"
+        );
+        assert!(
+            self.0.clients.rebind.is_some() || self.0.options.resolver.is_empty(),
+            "Addresses can only be pinned for the built-in clients"
+        );
+
+        let mut options = self.0.options;
+        options.passthrough = is_noop::<H>() && is_noop::<W>() && options.allows_passthrough();
+
         Proxy {
             al: self.0.al,
-            client: self.0.client,
+            clients: self.0.clients,
             ca: Arc::new(self.0.ca),
             http_handler: self.0.http_handler,
             websocket_handler: self.0.websocket_handler,
             websocket_connector: self.0.websocket_connector,
             server: self.0.server,
+            options: Arc::new(options),
             graceful_shutdown: self.0.graceful_shutdown,
         }
     }
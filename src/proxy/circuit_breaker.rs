@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct HostState {
+    failures: usize,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+/// Stops forwarding requests to upstream hosts that are failing.
+///
+/// After `failure_threshold` consecutive failures for a host, the breaker opens and requests for
+/// the host are answered by [`HttpHandler::handle_circuit_open`](crate::HttpHandler::handle_circuit_open)
+/// without being forwarded. Once the cooldown has passed, a single request is forwarded to probe
+/// the host: the breaker closes if it succeeds, and opens again if it fails.
+///
+/// A failure is an error while forwarding a request, or a `502 Bad Gateway`,
+/// `503 Service Unavailable`, or `504 Gateway Timeout` response.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::CircuitBreaker;
+/// use std::time::Duration;
+///
+/// let breaker = CircuitBreaker::new(5, Duration::from_secs(30));
+/// ```
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    hosts: Arc<Mutex<HashMap<String, HostState>>>,
+}
+
+impl CircuitBreaker {
+    /// Creates a new circuit breaker.
+    pub fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            hosts: Default::default(),
+        }
+    }
+
+    /// Whether the breaker is open for a host.
+    pub fn is_open(&self, host: &str) -> bool {
+        self.hosts
+            .lock()
+            .expect("Failed to lock circuit breaker")
+            .get(&host.to_ascii_lowercase())
+            .and_then(|state| state.open_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Whether a request for a host may be forwarded, and if so, the attempt whose outcome is to
+    /// be recorded.
+    pub(crate) fn allow(&self, host: &str) -> Option<Attempt> {
+        let mut hosts = self.hosts.lock().expect("Failed to lock circuit breaker");
+
+        let probe = match hosts.get_mut(host) {
+            None => false,
+            Some(state) => match state.open_until {
+                Some(until) if until > Instant::now() => return None,
+                Some(_) if state.probing => return None,
+                Some(_) => {
+                    state.probing = true;
+                    true
+                }
+                None => false,
+            },
+        };
+
+        Some(Attempt {
+            breaker: self.clone(),
+            host: host.to_owned(),
+            probe,
+        })
+    }
+
+    /// Record the outcome of a request for a host.
+    pub(crate) fn record(&self, host: &str, success: bool) {
+        let mut hosts = self.hosts.lock().expect("Failed to lock circuit breaker");
+
+        if success {
+            hosts.remove(host);
+            return;
+        }
+
+        let state = hosts.entry(host.to_owned()).or_default();
+        state.failures += 1;
+
+        if state.probing || state.failures >= self.failure_threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.probing = false;
+        }
+    }
+}
+
+/// A request that a [`CircuitBreaker`] allowed to be forwarded.
+///
+/// If the request probes an open breaker and is dropped before its outcome is recorded, such as
+/// when the client disconnects, the breaker allows another probe instead of staying open.
+pub(crate) struct Attempt {
+    breaker: CircuitBreaker,
+    host: String,
+    probe: bool,
+}
+
+impl Attempt {
+    /// Record the outcome of the request.
+    pub(crate) fn record(mut self, success: bool) {
+        self.probe = false;
+        self.breaker.record(&self.host, success);
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if !self.probe {
+            return;
+        }
+
+        if let Some(state) = self
+            .breaker
+            .hosts
+            .lock()
+            .expect("Failed to lock circuit breaker")
+            .get_mut(&self.host)
+        {
+            state.probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record("example.com", false);
+        assert!(breaker.allow("example.com").is_some());
+
+        breaker.record("example.com", false);
+        assert!(breaker.allow("example.com").is_none());
+        assert!(breaker.is_open("example.com"));
+        assert!(breaker.allow("example.org").is_some());
+    }
+
+    #[test]
+    fn successes_reset_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record("example.com", false);
+        breaker.record("example.com", true);
+        breaker.record("example.com", false);
+        assert!(breaker.allow("example.com").is_some());
+    }
+
+    #[test]
+    fn probes_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        breaker.record("example.com", false);
+        let probe = breaker.allow("example.com").unwrap();
+        assert!(breaker.allow("example.com").is_none());
+
+        probe.record(false);
+        let probe = breaker.allow("example.com").unwrap();
+
+        probe.record(true);
+        assert!(breaker.allow("example.com").is_some());
+        assert!(breaker.allow("example.com").is_some());
+    }
+
+    #[test]
+    fn allows_another_probe_when_probe_is_dropped() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        breaker.record("example.com", false);
+        let probe = breaker.allow("example.com").unwrap();
+        assert!(breaker.allow("example.com").is_none());
+
+        drop(probe);
+        assert!(breaker.allow("example.com").is_some());
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    pki_types::CertificateDer,
+    server::{danger::ClientCertVerifier, VerifierBuilderError, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+
+/// Authentication of clients with certificates in the TLS handshakes of intercepted tunnels.
+///
+/// The certificates that clients present are verified with a [`ClientCertVerifier`], and a client
+/// that does not present a certificate that the verifier accepts fails the handshake. The verified
+/// certificate is inserted into the extensions of each request of the tunnel as a
+/// [`ClientCertificate`], so that handlers can apply per-user policies.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{rustls::RootCertStore, ClientAuth};
+///
+/// # fn example(client_ca: hudsucker::rustls::pki_types::CertificateDer<'static>) {
+/// let mut roots = RootCertStore::empty();
+/// roots.add(client_ca).expect("Failed to add client CA");
+///
+/// let client_auth = ClientAuth::from_roots(roots).expect("Failed to build verifier");
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientAuth {
+    /// Creates a new client authentication that requires certificates issued by one of `roots`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `roots` is empty.
+    pub fn from_roots(roots: RootCertStore) -> Result<Self, VerifierBuilderError> {
+        Ok(Self::new(
+            WebPkiClientVerifier::builder(Arc::new(roots)).build()?,
+        ))
+    }
+
+    /// Creates a new client authentication with a verifier, such as one built with
+    /// [`WebPkiClientVerifier::builder`] that also allows unauthenticated clients or checks
+    /// revocation lists.
+    pub fn new(verifier: Arc<dyn ClientCertVerifier>) -> Self {
+        Self { verifier }
+    }
+
+    /// A copy of a server config that authenticates clients, with the same certificates and
+    /// settings.
+    pub(crate) fn apply(&self, config: &ServerConfig) -> ServerConfig {
+        let mut authenticated = ServerConfig::builder()
+            .with_client_cert_verifier(Arc::clone(&self.verifier))
+            .with_cert_resolver(Arc::clone(&config.cert_resolver));
+
+        authenticated.ignore_client_order = config.ignore_client_order;
+        authenticated.max_fragment_size = config.max_fragment_size;
+        authenticated.session_storage = Arc::clone(&config.session_storage);
+        authenticated.ticketer = Arc::clone(&config.ticketer);
+        authenticated.alpn_protocols = config.alpn_protocols.clone();
+        authenticated.key_log = Arc::clone(&config.key_log);
+        authenticated.enable_secret_extraction = config.enable_secret_extraction;
+        authenticated.max_early_data_size = config.max_early_data_size;
+        authenticated.send_half_rtt_data = config.send_half_rtt_data;
+        authenticated.send_tls13_tickets = config.send_tls13_tickets;
+        authenticated
+    }
+}
+
+/// The certificate chain that a client presented in the TLS handshake of an intercepted tunnel.
+///
+/// This is inserted into the extensions of each request of a tunnel before it is passed to
+/// [`HttpHandler::handle_request`](crate::HttpHandler::handle_request), if the client presented a
+/// certificate that was verified by the proxy's [`ClientAuth`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientCertificate {
+    chain: Arc<[CertificateDer<'static>]>,
+}
+
+impl ClientCertificate {
+    /// The client certificate of a handshake, if the client presented one.
+    pub(crate) fn from_chain(chain: Option<&[CertificateDer<'_>]>) -> Option<Self> {
+        let chain: Arc<[CertificateDer<'static>]> = chain?
+            .iter()
+            .map(|cert| cert.clone().into_owned())
+            .collect();
+
+        (!chain.is_empty()).then_some(Self { chain })
+    }
+
+    /// The certificate of the client, in DER.
+    pub fn end_entity(&self) -> &CertificateDer<'static> {
+        &self.chain[0]
+    }
+
+    /// The certificate of the client followed by the intermediate certificates that it presented.
+    pub fn chain(&self) -> &[CertificateDer<'static>] {
+        &self.chain
+    }
+}
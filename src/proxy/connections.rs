@@ -0,0 +1,154 @@
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{CaptureConnection, HttpInfo};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The number of tracked connections above which idle connections are forgotten.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// How long a connection is tracked after its last request, which is the default idle timeout of
+/// the connection pool.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The upstream connection that a response was received over.
+///
+/// This is inserted into the extensions of each upstream response before it is passed to
+/// [`HttpHandler::handle_response`](crate::HttpHandler::handle_response), if the connector of the
+/// client reports the addresses of its connections, as connectors built on
+/// [`HttpConnector`](hyper_util::client::legacy::connect::HttpConnector) do.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct UpstreamConnection {
+    id: u64,
+    requests: u64,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+}
+
+impl UpstreamConnection {
+    /// ID of the connection, unique within the process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the connection was reused from the connection pool, rather than opened for the
+    /// request.
+    pub fn is_reused(&self) -> bool {
+        self.requests > 1
+    }
+
+    /// The number of requests that have been sent over the connection, including this one.
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    /// The local address of the connection.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address of the upstream server, or of the upstream proxy that the request was sent
+    /// through.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+/// Send a request over a new upstream connection.
+///
+/// When this is inserted into the extensions of a request in
+/// [`HttpHandler::handle_request`](crate::HttpHandler::handle_request), the pooled connections to
+/// the request's upstream server are closed once they are idle, and the request is sent over a
+/// new connection, such as to make a new TLS handshake. Other requests may reuse the new
+/// connection.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct FreshConnection;
+
+#[derive(Debug)]
+struct Tracked {
+    id: u64,
+    requests: u64,
+    /// The scheme and authority of the connection's pool.
+    key: String,
+    capture: CaptureConnection,
+    last_used: Instant,
+}
+
+/// Tracks the upstream connections that requests are sent over.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionTracker {
+    connections: Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Tracked>>>,
+}
+
+impl ConnectionTracker {
+    /// The key of the pool that a request's connection is taken from.
+    pub(crate) fn key(uri: &Uri) -> String {
+        format!(
+            "{}://{}",
+            uri.scheme_str().unwrap_or("http"),
+            uri.authority().map_or("", |authority| authority.as_str())
+        )
+        .to_ascii_lowercase()
+    }
+
+    /// Poison the tracked connections of a pool, so that they are not reused.
+    pub(crate) fn poison(&self, key: &str) {
+        self.connections
+            .lock()
+            .expect("Failed to lock connections")
+            .retain(|_, tracked| {
+                if tracked.key != key {
+                    return true;
+                }
+
+                if let Some(connected) = tracked.capture.connection_metadata().as_ref() {
+                    connected.poison();
+                }
+
+                false
+            });
+    }
+
+    /// Record a request that was sent over a connection, whose addresses are in the extensions
+    /// of its response.
+    pub(crate) fn record(
+        &self,
+        key: String,
+        capture: CaptureConnection,
+        info: Option<&HttpInfo>,
+    ) -> Option<UpstreamConnection> {
+        let info = info?;
+        let addrs = (info.local_addr(), info.remote_addr());
+        let now = Instant::now();
+        let mut connections = self.connections.lock().expect("Failed to lock connections");
+
+        if connections.len() >= PURGE_THRESHOLD {
+            connections.retain(|_, tracked| now.duration_since(tracked.last_used) < IDLE_TIMEOUT);
+        }
+
+        let tracked = connections.entry(addrs).or_insert_with(|| Tracked {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            requests: 0,
+            key,
+            capture,
+            last_used: now,
+        });
+        tracked.requests += 1;
+        tracked.last_used = now;
+
+        Some(UpstreamConnection {
+            id: tracked.id,
+            requests: tracked.requests,
+            local_addr: addrs.0,
+            remote_addr: addrs.1,
+        })
+    }
+}
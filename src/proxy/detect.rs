@@ -0,0 +1,619 @@
+use crate::Rewind;
+use http::uri::Authority;
+use hyper::{body::Bytes, upgrade::Upgraded};
+use hyper_util::{rt::TokioIo, server::conn::auto};
+use std::{
+    io::{self, IoSlice},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    sync::oneshot,
+    time::Instant,
+};
+
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const MAX_PROXY_V1_LEN: usize = 107;
+const MAX_RECORD_LEN: usize = 5 + 16384;
+/// How long detection may take when no header read timeout is set.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The protocols besides HTTP that are detected on the connections that the proxy accepts, so
+/// that clients with different configurations can share a port.
+///
+/// Each connection is routed by its first bytes:
+///
+/// - A TLS ClientHello, from a client that was redirected to the proxy by a firewall rather than
+///   configured to use it, is tunneled to port 443 of the server named by its SNI.
+/// - A SOCKS5 greeting starts a SOCKS5 handshake without authentication, whose `CONNECT` command
+///   is tunneled to its destination.
+/// - A PROXY protocol header, version 1 or 2, replaces the client address of the connection with
+///   the one that it carries, and the rest of the connection is detected again.
+/// - Anything else is served as HTTP.
+///
+/// Tunnels from TLS and SOCKS5 connections are handled like those opened by `CONNECT` requests:
+/// [`HttpHandler::handle_request`] is called with a `CONNECT` request for their authority, and
+/// they are intercepted unless [`HttpHandler::should_intercept`] returns `false`. Nothing is
+/// detected by default.
+///
+/// Connections that do not send enough bytes to be detected within the timeout that is set with
+/// [`ProxyBuilder::with_header_read_timeout`], or within 30 seconds if it is not set, are closed.
+///
+/// [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+/// [`HttpHandler::should_intercept`]: crate::HttpHandler::should_intercept
+/// [`ProxyBuilder::with_header_read_timeout`]: crate::builder::ProxyBuilder::with_header_read_timeout
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::ProtocolDetection;
+///
+/// let detection = ProtocolDetection::new().with_tls(true).with_socks(true);
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProtocolDetection {
+    tls: bool,
+    socks: bool,
+    proxy_protocol: bool,
+}
+
+impl ProtocolDetection {
+    /// Creates a configuration that detects nothing but HTTP.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Detect TLS connections that are sent directly to the proxy.
+    pub fn with_tls(mut self, enabled: bool) -> Self {
+        self.tls = enabled;
+        self
+    }
+
+    /// Detect SOCKS5 connections.
+    pub fn with_socks(mut self, enabled: bool) -> Self {
+        self.socks = enabled;
+        self
+    }
+
+    /// Detect PROXY protocol headers.
+    ///
+    /// Any client can claim an address with a header, so this should only be enabled when the
+    /// proxy can only be reached through a load balancer that sends them.
+    pub fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+}
+
+/// A connection whose first bytes have been read to detect its protocol.
+pub(crate) enum Detected {
+    Http(Rewind<TcpStream>),
+    Tls(Rewind<TcpStream>, Authority),
+    Socks(Rewind<TcpStream>, Authority),
+}
+
+/// Reads the first bytes of a connection to detect its protocol, and consumes the PROXY protocol
+/// header or SOCKS5 handshake that it starts with.
+pub(crate) async fn detect(
+    tcp: TcpStream,
+    detection: ProtocolDetection,
+    timeout: Option<Duration>,
+    client_addr: &mut SocketAddr,
+) -> io::Result<Detected> {
+    let mut conn = Buffered {
+        tcp,
+        buf: Vec::new(),
+        pos: 0,
+        deadline: Instant::now() + timeout.unwrap_or(DEFAULT_TIMEOUT),
+    };
+
+    if detection == ProtocolDetection::default() {
+        return Ok(Detected::Http(conn.into_stream()));
+    }
+
+    if detection.proxy_protocol {
+        if let Some(addr) = read_proxy_header(&mut conn).await? {
+            *client_addr = addr;
+        }
+    }
+
+    let first = conn.peek(1).await?[0];
+
+    match first {
+        0x16 if detection.tls => {
+            let header = conn.peek(5).await?;
+            let len = 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+
+            if len > MAX_RECORD_LEN {
+                return Err(invalid("TLS record is too long"));
+            }
+
+            let host = server_name(conn.peek(len).await?)
+                .ok_or_else(|| invalid("TLS ClientHello without a server name"))?;
+            let authority = format!("{}:443", host).parse().map_err(io::Error::other)?;
+            Ok(Detected::Tls(conn.into_stream(), authority))
+        }
+        0x05 if detection.socks => {
+            let authority = socks_handshake(&mut conn).await?;
+            Ok(Detected::Socks(conn.into_stream(), authority))
+        }
+        _ => Ok(Detected::Http(conn.into_stream())),
+    }
+}
+
+/// Sends the reply to the SOCKS5 `CONNECT` command of a connection.
+pub(crate) async fn socks_reply(stream: &mut Rewind<TcpStream>, ok: bool) -> io::Result<()> {
+    // 0x02 means that the connection is not allowed by the ruleset.
+    let reply = if ok { 0x00 } else { 0x02 };
+    stream
+        .write_all(&[0x05, reply, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A connection with the bytes that have been read from it.
+struct Buffered {
+    tcp: TcpStream,
+    buf: Vec<u8>,
+    pos: usize,
+    /// When reading the bytes that the protocol is detected from times out.
+    deadline: Instant,
+}
+
+impl Buffered {
+    /// Returns the next `n` bytes without consuming them, reading until they are available.
+    async fn peek(&mut self, n: usize) -> io::Result<&[u8]> {
+        while self.buf.len() - self.pos < n {
+            let mut chunk = [0; 4096];
+            let read = tokio::time::timeout_at(self.deadline, self.tcp.read(&mut chunk))
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "Timed out detecting protocol")
+                })??;
+
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+
+        Ok(&self.buf[self.pos..self.pos + n])
+    }
+
+    /// Returns the bytes that have been read but not consumed, without reading.
+    fn available(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    async fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        self.peek(n).await?;
+        self.pos += n;
+        Ok(&self.buf[self.pos - n..self.pos])
+    }
+
+    fn into_stream(self) -> Rewind<TcpStream> {
+        let rest = Bytes::copy_from_slice(&self.buf[self.pos..]);
+        Rewind::new(self.tcp, rest)
+    }
+}
+
+/// Consumes a PROXY protocol header, returning the source address that it carries unless it is
+/// for a local or unknown connection.
+async fn read_proxy_header(conn: &mut Buffered) -> io::Result<Option<SocketAddr>> {
+    let first = conn.peek(1).await?[0];
+
+    match first {
+        b'P' if conn.peek(6).await? == b"PROXY " => {
+            let end = loop {
+                if let Some(end) = conn.available().windows(2).position(|w| w == b"\r\n") {
+                    break end;
+                }
+
+                if conn.available().len() >= MAX_PROXY_V1_LEN {
+                    return Err(invalid("PROXY protocol header is too long"));
+                }
+
+                let len = conn.available().len();
+                conn.peek(len + 1).await?;
+            };
+
+            let line = conn.take(end + 2).await?;
+            let line = std::str::from_utf8(&line[6..line.len() - 2])
+                .map_err(|_| invalid("invalid PROXY protocol header"))?;
+            let fields: Vec<_> = line.split(' ').collect();
+
+            match fields[..] {
+                ["UNKNOWN", ..] => Ok(None),
+                ["TCP4" | "TCP6", src, _, port, _] => {
+                    let ip = src.parse().map_err(|_| invalid("invalid PROXY address"))?;
+                    let port = port.parse().map_err(|_| invalid("invalid PROXY port"))?;
+                    Ok(Some(SocketAddr::new(ip, port)))
+                }
+                _ => Err(invalid("invalid PROXY protocol header")),
+            }
+        }
+        b'\r' if conn.peek(12).await? == PROXY_V2_SIGNATURE => {
+            let header = conn.take(16).await?;
+            let (command, family) = (header[12], header[13]);
+            let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+            if command >> 4 != 2 {
+                return Err(invalid("unsupported PROXY protocol version"));
+            }
+
+            let addresses = conn.take(len).await?;
+
+            // Local connections, such as health checks, keep the address of the load balancer.
+            if command & 0x0f == 0 {
+                return Ok(None);
+            }
+
+            match family >> 4 {
+                1 if addresses.len() >= 12 => {
+                    let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+                    let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                    Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+                }
+                2 if addresses.len() >= 36 => {
+                    let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+                    let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                    Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+                }
+                _ => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Negotiates a SOCKS5 connection without authentication, returning the destination of its
+/// `CONNECT` command.
+async fn socks_handshake(conn: &mut Buffered) -> io::Result<Authority> {
+    let methods = conn.take(2).await?[1] as usize;
+
+    if !conn.take(methods).await?.contains(&0x00) {
+        conn.tcp.write_all(&[0x05, 0xff]).await?;
+        return Err(invalid(
+            "SOCKS5 client does not allow connecting without authentication",
+        ));
+    }
+
+    conn.tcp.write_all(&[0x05, 0x00]).await?;
+
+    let request = conn.take(4).await?;
+    let (command, address_type) = (request[1], request[3]);
+
+    if command != 0x01 {
+        // 0x07 means that the command is not supported.
+        conn.tcp
+            .write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await?;
+        return Err(invalid("unsupported SOCKS5 command"));
+    }
+
+    let host = match address_type {
+        0x01 => {
+            let ip: [u8; 4] = conn.take(4).await?.try_into().unwrap();
+            IpAddr::from(ip).to_string()
+        }
+        0x03 => {
+            let len = conn.take(1).await?[0] as usize;
+            String::from_utf8(conn.take(len).await?.to_vec())
+                .map_err(|_| invalid("invalid SOCKS5 domain name"))?
+        }
+        0x04 => {
+            let ip: [u8; 16] = conn.take(16).await?.try_into().unwrap();
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        _ => return Err(invalid("unsupported SOCKS5 address type")),
+    };
+
+    let port = conn.take(2).await?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+
+    format!("{}:{}", host, port)
+        .parse()
+        .map_err(|_| invalid("invalid SOCKS5 destination"))
+}
+
+fn split8(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&len, rest) = bytes.split_first()?;
+    (rest.len() >= len as usize).then(|| rest.split_at(len as usize))
+}
+
+fn split16(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+    let rest = &bytes[2..];
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+/// The server name of the ClientHello in a TLS record.
+fn server_name(record: &[u8]) -> Option<&str> {
+    let hello = record.get(5..)?;
+
+    if *hello.first()? != 0x01 {
+        return None;
+    }
+
+    // Skip the handshake header, the version and the random bytes.
+    let (_, rest) = split8(hello.get(4 + 2 + 32..)?)?;
+    let (_, rest) = split16(rest)?;
+    let (_, rest) = split8(rest)?;
+    let (mut extensions, _) = split16(rest)?;
+
+    while !extensions.is_empty() {
+        let kind = u16::from_be_bytes([*extensions.first()?, *extensions.get(1)?]);
+        let (data, rest) = split16(&extensions[2..])?;
+
+        if kind == 0x0000 {
+            let (names, _) = split16(data)?;
+            let (&name_type, names) = names.split_first()?;
+            let (name, _) = split16(names)?;
+
+            if name_type != 0 {
+                return None;
+            }
+
+            return std::str::from_utf8(name).ok();
+        }
+
+        extensions = rest;
+    }
+
+    None
+}
+
+/// The connection of a TLS or SOCKS5 tunnel, which is sent to the task that serves its `CONNECT`
+/// request once it is accepted, in the place of a connection upgraded by hyper.
+#[derive(Clone)]
+pub(crate) struct DirectUpgrade(Arc<Mutex<Option<oneshot::Receiver<Rewind<TcpStream>>>>>);
+
+impl DirectUpgrade {
+    pub(crate) fn new() -> (Self, oneshot::Sender<Rewind<TcpStream>>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(Arc::new(Mutex::new(Some(rx)))), tx)
+    }
+
+    pub(crate) async fn on(self) -> io::Result<Rewind<TcpStream>> {
+        let rx = self
+            .0
+            .lock()
+            .expect("Failed to lock direct upgrade")
+            .take()
+            .ok_or_else(|| io::Error::other("connection was already upgraded"))?;
+        rx.await
+            .map_err(|_| io::Error::other("connection was not upgraded"))
+    }
+}
+
+/// The client side of a tunnel.
+pub(crate) enum TunnelStream {
+    Upgraded(TokioIo<Upgraded>),
+    Direct(Rewind<TcpStream>),
+}
+
+impl TunnelStream {
+    /// The client's TCP connection, and the data that was read from it but not consumed yet, if
+    /// the tunnel was opened on a plain HTTP/1 connection.
+    pub(crate) fn into_tcp(self) -> Result<(TcpStream, Bytes), Self> {
+        match self {
+            Self::Direct(stream) => Ok(stream.into_parts()),
+            Self::Upgraded(upgraded) => {
+                match auto::upgrade::downcast::<TokioIo<Rewind<TcpStream>>>(upgraded.into_inner()) {
+                    Ok(parts) => {
+                        let (tcp, rest) = parts.io.into_inner().into_parts();
+                        let mut buf = Vec::with_capacity(parts.read_buf.len() + rest.len());
+                        buf.extend_from_slice(&parts.read_buf);
+                        buf.extend_from_slice(&rest);
+                        Ok((tcp, buf.into()))
+                    }
+                    Err(upgraded) => Err(Self::Upgraded(TokioIo::new(upgraded))),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Upgraded(io) => Pin::new(io).poll_read(cx, buf),
+            Self::Direct(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Upgraded(io) => Pin::new(io).poll_write(cx, buf),
+            Self::Direct(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Upgraded(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            Self::Direct(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Upgraded(io) => Pin::new(io).poll_flush(cx),
+            Self::Direct(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Upgraded(io) => Pin::new(io).poll_shutdown(cx),
+            Self::Direct(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Upgraded(io) => io.is_write_vectored(),
+            Self::Direct(io) => io.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn detect_bytes(
+        detection: ProtocolDetection,
+        bytes: &'static [u8],
+    ) -> (io::Result<Detected>, SocketAddr, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(bytes).await.unwrap();
+
+        let (tcp, mut client_addr) = listener.accept().await.unwrap();
+        let detected = detect(tcp, detection, None, &mut client_addr).await;
+        (detected, client_addr, client)
+    }
+
+    fn client_hello(host: &str) -> Vec<u8> {
+        let mut name = vec![0x00];
+        name.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        name.extend_from_slice(host.as_bytes());
+
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&(name.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&body);
+        record
+    }
+
+    #[test]
+    fn parses_server_names() {
+        assert_eq!(
+            server_name(&client_hello("example.com")),
+            Some("example.com")
+        );
+        assert_eq!(server_name(&client_hello("example.com")[..40]), None);
+    }
+
+    #[tokio::test]
+    async fn detects_protocols() {
+        let all = ProtocolDetection::new()
+            .with_tls(true)
+            .with_socks(true)
+            .with_proxy_protocol(true);
+
+        let hello: &'static [u8] = client_hello("example.com").leak();
+        let (detected, _, _) = detect_bytes(all, hello).await;
+        assert!(
+            matches!(detected.unwrap(), Detected::Tls(_, authority) if authority == "example.com:443")
+        );
+
+        let (detected, _, _) = detect_bytes(ProtocolDetection::new(), hello).await;
+        assert!(matches!(detected.unwrap(), Detected::Http(_)));
+
+        let (detected, addr, _) = detect_bytes(
+            all,
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 80\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        let Detected::Http(mut stream) = detected.unwrap() else {
+            panic!("expected HTTP");
+        };
+        assert_eq!(addr, "192.0.2.1:5000".parse().unwrap());
+        let mut start = [0; 4];
+        stream.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, b"GET ");
+
+        let (detected, addr, _) = detect_bytes(
+            all,
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01\xc0\x00\x02\x02\x13\x88\x00\x50GET ",
+        )
+        .await;
+        assert!(matches!(detected.unwrap(), Detected::Http(_)));
+        assert_eq!(addr, "192.0.2.1:5000".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // The start of a TLS record, whose header is never completed.
+        client.write_all(&[0x16, 0x03]).await.unwrap();
+
+        let (tcp, mut client_addr) = listener.accept().await.unwrap();
+        let detection = ProtocolDetection::new().with_tls(true);
+        let err = detect(
+            tcp,
+            detection,
+            Some(Duration::from_millis(50)),
+            &mut client_addr,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn negotiates_socks() {
+        let (detected, _, mut client) = detect_bytes(
+            ProtocolDetection::new().with_socks(true),
+            b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+        )
+        .await;
+        let Detected::Socks(mut stream, authority) = detected.unwrap() else {
+            panic!("expected SOCKS5");
+        };
+        assert_eq!(authority, "example.com:443");
+
+        socks_reply(&mut stream, true).await.unwrap();
+        let mut reply = [0; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let (detected, _, _) =
+            detect_bytes(ProtocolDetection::new().with_socks(true), b"\x05\x01\x02").await;
+        assert!(detected.is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use futures::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The number of hosts that a cache holds by default.
+const DEFAULT_CAPACITY: usize = 10_000;
+/// How long addresses are cached for by default when the lookup does not know their TTL.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+/// How long addresses are cached for at most by default.
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long failed lookups are cached for by default.
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// The addresses that a host resolved to, and how long they may be cached for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Resolved {
+    /// The addresses of the host.
+    pub addrs: Vec<IpAddr>,
+    /// The lowest TTL of the records that the addresses were resolved from, if it is known.
+    pub ttl: Option<Duration>,
+}
+
+impl Resolved {
+    /// Creates a new set of resolved addresses.
+    pub fn new(addrs: Vec<IpAddr>, ttl: Option<Duration>) -> Self {
+        Self { addrs, ttl }
+    }
+}
+
+/// Resolves hosts for a [`DnsCache`].
+///
+/// The system resolver is used by default, which does not report the TTLs of records, so its
+/// addresses are cached for the default TTL of the cache. A lookup that queries DNS servers
+/// itself can report them, so that they are respected by the cache.
+pub trait Lookup: Send + Sync + 'static {
+    /// Resolve a host to its addresses.
+    fn lookup(&self, host: &str) -> BoxFuture<'static, io::Result<Resolved>>;
+}
+
+/// Resolves hosts with the system resolver.
+struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup(&self, host: &str) -> BoxFuture<'static, io::Result<Resolved>> {
+        let host = host.to_owned();
+
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+
+            Ok(Resolved::new(addrs, None))
+        })
+    }
+}
+
+/// A cached lookup, which expires at `expires`.
+#[derive(Clone)]
+struct Entry {
+    result: Result<Arc<[IpAddr]>, (io::ErrorKind, String)>,
+    expires: Instant,
+}
+
+struct Inner {
+    lookup: Box<dyn Lookup>,
+    capacity: usize,
+    default_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// A cache of the addresses that upstream hosts resolve to.
+///
+/// Addresses are cached for the TTL that the [`Lookup`] reports, up to a maximum TTL, or for a
+/// default TTL if it does not know the TTL. Failed lookups are cached for a shorter negative TTL,
+/// so that a host that does not resolve is not looked up again for every request.
+///
+/// A cache can be set for a proxy with [`ProxyBuilder::with_dns_cache`], which resolves the hosts
+/// of requests, tunnels and WebSockets with it. Clones of a cache share the same entries.
+///
+/// # TTLs
+///
+/// By default, hosts are resolved with the system resolver, which does not report the TTLs of
+/// the records that it resolves. Their addresses are cached for the default TTL of 60 seconds
+/// instead, so they may be used for longer than their records allow, or looked up again before
+/// they expire. The default TTL can be changed with [`DnsCacheBuilder::with_default_ttl`], and
+/// TTLs are respected when they are reported by a lookup that is set with
+/// [`DnsCacheBuilder::with_lookup`].
+///
+/// [`ProxyBuilder::with_dns_cache`]: crate::builder::ProxyBuilder::with_dns_cache
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::DnsCache;
+/// use std::time::Duration;
+///
+/// let cache = DnsCache::builder()
+///     .with_max_ttl(Duration::from_secs(300))
+///     .with_negative_ttl(Duration::from_secs(1))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct DnsCache(Arc<Inner>);
+
+impl DnsCache {
+    /// Creates a cache that resolves hosts with the system resolver and the default settings.
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// Create a new [`DnsCacheBuilder`].
+    pub fn builder() -> DnsCacheBuilder {
+        DnsCacheBuilder::new()
+    }
+
+    /// The number of hosts that are cached, including expired entries that have not been removed
+    /// yet.
+    pub fn len(&self) -> usize {
+        self.0
+            .entries
+            .lock()
+            .expect("Failed to lock DNS cache")
+            .len()
+    }
+
+    /// Whether no hosts are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all cached lookups.
+    pub fn clear(&self) {
+        self.0
+            .entries
+            .lock()
+            .expect("Failed to lock DNS cache")
+            .clear();
+    }
+
+    /// Resolve a host to its addresses, from the cache if it was looked up recently.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the host could not be resolved, or a cached copy of the error if it
+    /// could not be resolved recently.
+    pub async fn lookup(&self, host: &str) -> io::Result<Arc<[IpAddr]>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Arc::new([ip]));
+        }
+
+        let key = host.to_ascii_lowercase();
+
+        if let Some(entry) = self.get(&key) {
+            return entry
+                .result
+                .map_err(|(kind, msg)| io::Error::new(kind, msg));
+        }
+
+        let inner = &self.0;
+        let entry = match inner.lookup.lookup(&key).await {
+            Ok(resolved) if resolved.addrs.is_empty() => Entry {
+                result: Err((
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses", host),
+                )),
+                expires: Instant::now() + inner.negative_ttl,
+            },
+            Ok(resolved) => Entry {
+                result: Ok(resolved.addrs.into()),
+                expires: Instant::now()
+                    + resolved.ttl.unwrap_or(inner.default_ttl).min(inner.max_ttl),
+            },
+            Err(e) => Entry {
+                result: Err((e.kind(), e.to_string())),
+                expires: Instant::now() + inner.negative_ttl,
+            },
+        };
+
+        self.insert(key, entry.clone());
+        entry
+            .result
+            .map_err(|(kind, msg)| io::Error::new(kind, msg))
+    }
+
+    fn get(&self, key: &str) -> Option<Entry> {
+        if self.0.capacity == 0 {
+            return None;
+        }
+
+        let mut entries = self.0.entries.lock().expect("Failed to lock DNS cache");
+        let entry = entries.get(key)?;
+
+        if entry.expires > Instant::now() {
+            return Some(entry.clone());
+        }
+
+        entries.remove(key);
+        None
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let capacity = self.0.capacity;
+
+        if capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.0.entries.lock().expect("Failed to lock DNS cache");
+
+        if entries.len() >= capacity && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+
+            if entries.len() >= capacity {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+
+        entries.insert(key, entry);
+    }
+
+    /// Resolve a host and port to socket addresses.
+    pub(crate) async fn lookup_addrs(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(self
+            .lookup(host)
+            .await?
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect())
+    }
+}
+
+/// A builder for a [`DnsCache`].
+pub struct DnsCacheBuilder(Inner);
+
+impl DnsCacheBuilder {
+    /// Create a new [`DnsCacheBuilder`] with the default settings.
+    pub fn new() -> Self {
+        Self(Inner {
+            lookup: Box::new(SystemLookup),
+            capacity: DEFAULT_CAPACITY,
+            default_ttl: DEFAULT_TTL,
+            max_ttl: DEFAULT_MAX_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Set how hosts are resolved. Defaults to the system resolver, which does not report TTLs.
+    pub fn with_lookup(mut self, lookup: impl Lookup) -> Self {
+        self.0.lookup = Box::new(lookup);
+        self
+    }
+
+    /// Set the maximum number of hosts that are cached. Defaults to 10,000.
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.0.capacity = max;
+        self
+    }
+
+    /// Set how long addresses are cached for when their TTL is not known, which is always the
+    /// case with the system resolver. Defaults to 60 seconds.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.0.default_ttl = ttl;
+        self
+    }
+
+    /// Set how long addresses are cached for at most, regardless of their TTL. Defaults to an
+    /// hour.
+    pub fn with_max_ttl(mut self, ttl: Duration) -> Self {
+        self.0.max_ttl = ttl;
+        self
+    }
+
+    /// Set how long failed lookups are cached for. Defaults to 5 seconds.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.0.negative_ttl = ttl;
+        self
+    }
+
+    /// Build the cache.
+    pub fn build(self) -> DnsCache {
+        DnsCache(Arc::new(self.0))
+    }
+}
+
+impl Default for DnsCacheBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for DnsCacheBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCacheBuilder")
+            .field("capacity", &self.0.capacity)
+            .field("default_ttl", &self.0.default_ttl)
+            .field("max_ttl", &self.0.max_ttl)
+            .field("negative_ttl", &self.0.negative_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("capacity", &self.0.capacity)
+            .field("default_ttl", &self.0.default_ttl)
+            .field("max_ttl", &self.0.max_ttl)
+            .field("negative_ttl", &self.0.negative_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+impl tower_service::Service<hyper_util::client::legacy::connect::dns::Name> for DnsCache {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Self::Response>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: hyper_util::client::legacy::connect::dns::Name) -> Self::Future {
+        let cache = self.clone();
+
+        // The connector sets the port of the addresses.
+        Box::pin(async move { Ok(cache.lookup_addrs(name.as_str(), 0).await?.into_iter()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct CountingLookup(Arc<AtomicUsize>);
+
+    impl Lookup for CountingLookup {
+        fn lookup(&self, host: &str) -> BoxFuture<'static, io::Result<Resolved>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            let resolved = match host {
+                "short.example" => Ok(Resolved::new(
+                    vec![IpAddr::from([192, 0, 2, 1])],
+                    Some(Duration::ZERO),
+                )),
+                "long.example" => Ok(Resolved::new(
+                    vec![IpAddr::from([192, 0, 2, 2])],
+                    Some(Duration::from_secs(86_400)),
+                )),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            };
+
+            Box::pin(async move { resolved })
+        }
+    }
+
+    #[tokio::test]
+    async fn respects_ttls() {
+        let lookup = CountingLookup::default();
+        let cache = DnsCache::builder().with_lookup(lookup.clone()).build();
+
+        for _ in 0..2 {
+            let addrs = cache.lookup("short.example").await.unwrap();
+            assert_eq!(*addrs, [IpAddr::from([192, 0, 2, 1])]);
+        }
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 2);
+
+        for _ in 0..2 {
+            cache.lookup("LONG.example").await.unwrap();
+        }
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn caches_failures() {
+        let lookup = CountingLookup::default();
+        let cache = DnsCache::builder().with_lookup(lookup.clone()).build();
+
+        for _ in 0..2 {
+            let err = cache.lookup("missing.example").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 1);
+
+        let cache = DnsCache::builder()
+            .with_lookup(lookup.clone())
+            .with_negative_ttl(Duration::ZERO)
+            .build();
+        cache.lookup("missing.example").await.unwrap_err();
+        cache.lookup("missing.example").await.unwrap_err();
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn clamps_ttls() {
+        let lookup = CountingLookup::default();
+        let cache = DnsCache::builder()
+            .with_lookup(lookup.clone())
+            .with_max_ttl(Duration::ZERO)
+            .build();
+
+        cache.lookup("long.example").await.unwrap();
+        cache.lookup("long.example").await.unwrap();
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_entries_over_capacity() {
+        let cache = DnsCache::builder()
+            .with_lookup(CountingLookup::default())
+            .with_max_entries(1)
+            .build();
+
+        cache.lookup("long.example").await.unwrap();
+        cache.lookup("missing.example").await.unwrap_err();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_look_up_addresses() {
+        let lookup = CountingLookup::default();
+        let cache = DnsCache::builder().with_lookup(lookup.clone()).build();
+
+        let addrs = cache.lookup("[::1]").await.unwrap();
+        assert_eq!(*addrs, [IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]);
+        assert_eq!(lookup.0.load(Ordering::Relaxed), 0);
+    }
+}
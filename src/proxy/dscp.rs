@@ -0,0 +1,74 @@
+/// A Differentiated Services Code Point that the IP packets of an upstream connection are marked
+/// with, so that routers can prioritize or deprioritize them.
+///
+/// Insert this into the extensions of a request in [`HttpHandler::handle_request`] to mark the
+/// connection that the request is sent over, or the tunnel that a `CONNECT` request opens. A DSCP
+/// can also be configured for a host with [`ProxyBuilder::with_dscp`]. Marked requests are sent
+/// with a copy of the configured client, over connections that are only shared with requests that
+/// have the same mark.
+///
+/// HTTP requests are only marked when they are sent with the built-in rustls or native-tls
+/// clients, and marks are only applied on Android, FreeBSD, Linux, macOS, NetBSD and OpenBSD.
+///
+/// [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+/// [`ProxyBuilder::with_dscp`]: crate::builder::ProxyBuilder::with_dscp
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::Dscp;
+///
+/// // Deprioritize bulk downloads.
+/// let dscp = Dscp::LOWER_EFFORT;
+/// assert_eq!(dscp.tos(), 0x04);
+/// assert_eq!(Dscp::new(46), Some(Dscp::EF));
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Default forwarding, which is best effort.
+    pub const DEFAULT: Self = Self(0);
+    /// Lower effort (RFC 8622), for traffic that should yield to best effort traffic.
+    pub const LOWER_EFFORT: Self = Self(1);
+    /// Class selector 1, which is commonly used for bulk and background traffic.
+    pub const CS1: Self = Self(8);
+    /// Assured forwarding class 1, for high-throughput data.
+    pub const AF11: Self = Self(10);
+    /// Assured forwarding class 2, for low-latency data.
+    pub const AF21: Self = Self(18);
+    /// Assured forwarding class 3, for multimedia streaming.
+    pub const AF31: Self = Self(26);
+    /// Assured forwarding class 4, for interactive multimedia.
+    pub const AF41: Self = Self(34);
+    /// Expedited forwarding, for low-loss, low-latency traffic.
+    pub const EF: Self = Self(46);
+
+    /// Creates a code point from its 6-bit value, or returns `None` if it is larger than 63.
+    pub fn new(value: u8) -> Option<Self> {
+        (value < 64).then_some(Self(value))
+    }
+
+    /// The 6-bit value of the code point.
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// The IPv4 type of service or IPv6 traffic class byte that the code point is sent in, without
+    /// ECN bits.
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_code_points() {
+        assert_eq!(Dscp::new(63).map(Dscp::tos), Some(0xfc));
+        assert_eq!(Dscp::new(64), None);
+        assert_eq!(Dscp::CS1.tos(), 0x20);
+    }
+}
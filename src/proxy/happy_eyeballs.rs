@@ -0,0 +1,310 @@
+use super::{DnsCache, Dscp};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    borrow::Cow,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// The delay before the next address is tried while the previous attempts are still pending.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The local address and network interface that the sockets of upstream connections are bound
+/// to, the DSCP that their packets are marked with, and the cache that their hosts are resolved
+/// with.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LocalBind {
+    pub address: Option<IpAddr>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub interface: Option<String>,
+    pub dscp: Option<Dscp>,
+    pub dns_cache: Option<DnsCache>,
+}
+
+impl LocalBind {
+    /// The same binding, with sockets marked with a DSCP.
+    pub(crate) fn with_dscp(&self, dscp: Option<Dscp>) -> Cow<'_, Self> {
+        match dscp {
+            Some(_) => Cow::Owned(Self {
+                dscp,
+                ..self.clone()
+            }),
+            None => Cow::Borrowed(self),
+        }
+    }
+
+    /// Connect to an address from a bound socket. The local address is only bound to if it is of
+    /// the same family as the address.
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+
+        if let Some(local) = self
+            .address
+            .filter(|local| local.is_ipv6() == addr.is_ipv6())
+        {
+            socket.bind(SocketAddr::new(local, 0))?;
+        }
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        ))]
+        if let Some(dscp) = self.dscp {
+            if addr.is_ipv6() {
+                socket.set_tclass_v6(dscp.tos().into())?;
+            } else {
+                socket.set_tos_v4(dscp.tos().into())?;
+            }
+        }
+
+        socket.connect(addr).await
+    }
+}
+
+/// The connector of the clients of `ProxyBuilder::with_rustls_client` and
+/// `ProxyBuilder::with_native_tls_client`.
+///
+/// It connects to the hosts of requests from sockets that are bound to the local address and
+/// network interface of the proxy, and resolves them with its DNS cache. The proxy builds copies
+/// of these clients whose connectors mark their sockets with a [`Dscp`], or connect to a pinned
+/// address, for the requests that need them.
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+#[derive(Clone, Debug)]
+pub struct UpstreamConnector {
+    bind: LocalBind,
+    addr: Option<SocketAddr>,
+}
+
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+impl UpstreamConnector {
+    /// A connector that connects from sockets that are bound with `bind`, to `addr` whatever the
+    /// host of a request is, or to the host of the request if it is `None`.
+    pub(crate) fn new(bind: LocalBind, addr: Option<SocketAddr>) -> Self {
+        Self { bind, addr }
+    }
+}
+
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+impl tower_service::Service<hyper::Uri> for UpstreamConnector {
+    type Response = hyper_util::rt::TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = futures::future::BoxFuture<'static, io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: hyper::Uri) -> Self::Future {
+        let (host, port) = match self.addr {
+            Some(addr) => (addr.ip().to_string(), addr.port()),
+            None => (
+                dst.host().unwrap_or_default().to_owned(),
+                dst.port_u16()
+                    .unwrap_or(if dst.scheme_str() == Some("https") {
+                        443
+                    } else {
+                        80
+                    }),
+            ),
+        };
+        let bind = self.bind.clone();
+
+        Box::pin(async move {
+            let tcp = connect(&host, port, &bind).await?;
+            tcp.set_nodelay(true)?;
+            Ok(hyper_util::rt::TokioIo::new(tcp))
+        })
+    }
+}
+
+/// Connect to a host, racing its IPv6 and IPv4 addresses as described by RFC 8305 (Happy
+/// Eyeballs), so that a broken address of one family does not delay the connection by the full
+/// connection timeout.
+pub(crate) async fn connect(host: &str, port: u16, bind: &LocalBind) -> io::Result<TcpStream> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = match &bind.dns_cache {
+        Some(cache) => cache.lookup_addrs(host, port).await?,
+        None => tokio::net::lookup_host((host, port)).await?.collect(),
+    };
+
+    connect_addrs(interleave(addrs), CONNECTION_ATTEMPT_DELAY, bind).await
+}
+
+/// Order addresses so that their families alternate, starting with the family of the first
+/// address that the resolver returned.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+
+    let first_is_ipv6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to the first address that accepts a connection, starting an attempt for the next
+/// address whenever an attempt fails or `delay` passes without a connection.
+async fn connect_addrs(
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+    bind: &LocalBind,
+) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = None;
+
+    attempts.extend(addrs.next().map(|addr| bind.connect(addr)));
+
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    error = Some(e);
+                    attempts.extend(addrs.next().map(|addr| bind.connect(addr)));
+                }
+            },
+            _ = tokio::time::sleep(delay) => {
+                attempts.extend(addrs.next().map(|addr| bind.connect(addr)));
+            }
+        }
+    }
+
+    Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::2]:1",
+            "127.0.0.1:1",
+            "[::3]:1",
+            "127.0.0.2:1",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+
+        let ordered: Vec<SocketAddr> = [
+            "[::1]:1",
+            "127.0.0.1:1",
+            "[::2]:1",
+            "127.0.0.2:1",
+            "[::3]:1",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+
+        assert_eq!(interleave(addrs), ordered);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_working_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let working = listener.local_addr().unwrap();
+        // An address in TEST-NET-1, which either refuses or never answers connections.
+        let broken = "192.0.2.1:9".parse().unwrap();
+
+        let start = Instant::now();
+        let stream = connect_addrs(
+            vec![broken, working],
+            Duration::from_millis(50),
+            &LocalBind::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), working);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn binds_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind = LocalBind {
+            address: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let stream = connect_addrs(
+            vec![listener.local_addr().unwrap()],
+            Duration::from_millis(50),
+            &bind,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stream.local_addr().unwrap().ip(), bind.address.unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn marks_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind = LocalBind::default();
+        let bind = bind.with_dscp(Some(Dscp::CS1));
+
+        let stream = connect_addrs(
+            vec![listener.local_addr().unwrap()],
+            Duration::from_millis(50),
+            &bind,
+        )
+        .await
+        .unwrap();
+
+        let socket = TcpSocket::from_std_stream(stream.into_std().unwrap());
+        assert_eq!(socket.tos_v4().unwrap(), 0x20);
+    }
+
+    #[tokio::test]
+    async fn returns_last_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let err = connect_addrs(vec![addr], Duration::from_millis(50), &LocalBind::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let err = connect_addrs(Vec::new(), Duration::from_millis(50), &LocalBind::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
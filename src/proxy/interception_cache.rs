@@ -0,0 +1,198 @@
+use hyper::http::uri::Authority;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The number of decisions that a cache holds by default.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Caches the decisions of [`HttpHandler::should_intercept`](crate::HttpHandler::should_intercept)
+/// for each authority.
+///
+/// Once a decision is made for a `CONNECT` request, later `CONNECT` requests for the same
+/// authority are intercepted or tunneled according to that decision until it expires, without
+/// calling the HTTP handler. This is useful when decisions are expensive, such as when they are
+/// looked up in a remote policy service.
+///
+/// A cache holds up to 10,000 decisions by default, which can be changed with
+/// [`InterceptionCache::with_capacity`]. Once it is full, the oldest decisions are forgotten.
+///
+/// Clones of a cache share their decisions, so a cache can be kept to invalidate decisions while
+/// the proxy is running.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::InterceptionCache;
+/// use std::time::Duration;
+///
+/// let cache = InterceptionCache::new(Duration::from_secs(60)).with_capacity(1000);
+/// ```
+#[derive(Clone, Debug)]
+pub struct InterceptionCache {
+    ttl: Duration,
+    capacity: usize,
+    decisions: Arc<Mutex<Decisions>>,
+}
+
+#[derive(Debug, Default)]
+struct Decisions {
+    by_authority: HashMap<String, (bool, Instant)>,
+    /// The authorities and expiry times of the decisions in the order that they were made, which
+    /// is the order that they expire in, as they all live for the same time. Decisions that were
+    /// invalidated or made again are removed from the queue once they reach its front.
+    queue: VecDeque<(String, Instant)>,
+}
+
+impl InterceptionCache {
+    /// Creates a new cache whose decisions expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            capacity: DEFAULT_CAPACITY,
+            decisions: Default::default(),
+        }
+    }
+
+    /// Set the maximum number of decisions that the cache holds.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Forget the decision for an authority, such as `example.com:443`.
+    pub fn invalidate(&self, authority: &str) {
+        self.decisions
+            .lock()
+            .expect("Failed to lock interception cache")
+            .by_authority
+            .remove(&authority.to_ascii_lowercase());
+    }
+
+    /// Forget all decisions.
+    pub fn clear(&self) {
+        let mut decisions = self
+            .decisions
+            .lock()
+            .expect("Failed to lock interception cache");
+        decisions.by_authority.clear();
+        decisions.queue.clear();
+    }
+
+    /// The decision for an authority, if it has not expired.
+    pub(crate) fn get(&self, authority: &Authority) -> Option<bool> {
+        let key = authority.as_str().to_ascii_lowercase();
+        let mut decisions = self
+            .decisions
+            .lock()
+            .expect("Failed to lock interception cache");
+
+        match decisions.by_authority.get(&key) {
+            Some(&(intercept, expires)) if expires > Instant::now() => Some(intercept),
+            Some(_) => {
+                decisions.by_authority.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Record the decision for an authority, forgetting the decisions that have expired and, if
+    /// the cache is full, the oldest ones.
+    pub(crate) fn insert(&self, authority: &Authority, intercept: bool) {
+        let now = Instant::now();
+        let mut decisions = self
+            .decisions
+            .lock()
+            .expect("Failed to lock interception cache");
+
+        while let Some((_, expires)) = decisions.queue.front() {
+            if *expires > now
+                && decisions.by_authority.len() < self.capacity
+                && decisions.queue.len() < self.capacity.saturating_mul(2)
+            {
+                break;
+            }
+
+            let (key, expires) = decisions.queue.pop_front().expect("Queue is not empty");
+            if decisions
+                .by_authority
+                .get(&key)
+                .is_some_and(|&(_, current)| current == expires)
+            {
+                decisions.by_authority.remove(&key);
+            }
+        }
+
+        let key = authority.as_str().to_ascii_lowercase();
+        let expires = now + self.ttl;
+        decisions
+            .by_authority
+            .insert(key.clone(), (intercept, expires));
+        decisions.queue.push_back((key, expires));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_decisions_per_authority() {
+        let cache = InterceptionCache::new(Duration::from_secs(60));
+        let authority = Authority::from_static("example.com:443");
+
+        assert_eq!(cache.get(&authority), None);
+
+        cache.insert(&authority, false);
+        assert_eq!(cache.get(&authority), Some(false));
+        assert_eq!(
+            cache.get(&Authority::from_static("EXAMPLE.com:443")),
+            Some(false)
+        );
+        assert_eq!(cache.get(&Authority::from_static("example.com:8443")), None);
+
+        cache.invalidate("Example.com:443");
+        assert_eq!(cache.get(&authority), None);
+    }
+
+    #[test]
+    fn decisions_expire() {
+        let cache = InterceptionCache::new(Duration::ZERO);
+        let authority = Authority::from_static("example.com:443");
+
+        cache.insert(&authority, true);
+        assert_eq!(cache.get(&authority), None);
+    }
+
+    #[test]
+    fn forgets_oldest_decisions_when_full() {
+        let cache = InterceptionCache::new(Duration::from_secs(60)).with_capacity(2);
+        let authorities = ["a.com:443", "b.com:443", "c.com:443"].map(Authority::from_static);
+
+        for authority in &authorities {
+            cache.insert(authority, true);
+        }
+
+        assert_eq!(cache.get(&authorities[0]), None);
+        assert_eq!(cache.get(&authorities[1]), Some(true));
+        assert_eq!(cache.get(&authorities[2]), Some(true));
+    }
+
+    #[test]
+    fn decisions_that_are_made_again_are_kept() {
+        let cache = InterceptionCache::new(Duration::from_secs(60)).with_capacity(2);
+        let a = Authority::from_static("a.com:443");
+        let b = Authority::from_static("b.com:443");
+
+        cache.insert(&a, true);
+        cache.invalidate("a.com:443");
+        cache.insert(&a, false);
+        cache.insert(&b, true);
+
+        assert_eq!(cache.get(&a), Some(false));
+        assert_eq!(cache.get(&b), Some(true));
+    }
+}
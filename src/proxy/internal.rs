@@ -1,30 +1,90 @@
+use super::{
+    connections::ConnectionTracker,
+    detect::{self, DirectUpgrade, TunnelStream},
+    happy_eyeballs,
+    tunnel::{self, TunnelEnd},
+    ClientCertificate, Clients, Dscp, FreshConnection, Options, SniRoute, TlsFailure,
+};
 use crate::{
-    body::Body, certificate_authority::CertificateAuthority, HttpContext, HttpHandler,
-    RequestOrResponse, Rewind, WebSocketContext, WebSocketHandler,
+    access_log::AccessRecord, body::Body, certificate_authority::CertificateAuthority, AlpnPolicy,
+    BodyDirection, BodyLimitAction, ExpectContinue, FlowExtensions, FlowId, HttpContext,
+    HttpHandler, Idempotent, RedirectChain, RedirectPolicy, RequestOrResponse, RetryPolicy, Rewind,
+    UpstreamProtocol, WebSocketContext, WebSocketHandler,
 };
-use futures::{Sink, Stream, StreamExt};
+use bstr::ByteSlice;
+use futures::{channel::mpsc, Sink, SinkExt, Stream, StreamExt};
 use http::uri::{Authority, Scheme};
 use http_body_util::Empty;
 use hyper::{
-    body::{Bytes, Incoming},
-    header::Entry,
+    body::{Body as _, Bytes, Incoming},
+    header::{
+        Entry, HeaderName, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
+        LOCATION, PROXY_AUTHORIZATION, TRANSFER_ENCODING,
+    },
     service::service_fn,
     upgrade::Upgraded,
-    Method, Request, Response, StatusCode, Uri,
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
 };
 use hyper_util::{
-    client::legacy::{connect::Connect, Client},
+    client::legacy::{
+        connect::{capture_connection, CaptureConnection, Connect, HttpInfo},
+        Client,
+    },
     rt::{TokioExecutor, TokioIo},
     server,
 };
-use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    io, mem,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{io::AsyncReadExt, net::TcpStream, task::JoinHandle};
 use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::{
-    tungstenite::{self, Message},
+    tungstenite::{
+        self,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
     Connector, WebSocketStream,
 };
-use tracing::{error, info_span, instrument, warn, Instrument, Span};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
+
+const HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+static NEXT_FLOW_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The error of a request whose connection is closed without a response.
+#[derive(Debug)]
+pub(crate) struct ConnectionClosed;
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("connection closed without a response")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+/// Whether an error was caused by a [`ConnectionClosed`] error.
+pub(crate) fn is_closed(mut err: &(dyn std::error::Error + 'static)) -> bool {
+    loop {
+        if err.is::<ConnectionClosed>() {
+            return true;
+        }
+
+        match err.source() {
+            Some(source) => err = source,
+            None => return false,
+        }
+    }
+}
 
 fn bad_request() -> Response<Body> {
     Response::builder()
@@ -33,6 +93,24 @@ fn bad_request() -> Response<Body> {
         .expect("Failed to build response")
 }
 
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Empty::new().into())
+        .expect("Failed to build response")
+}
+
+fn payload_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Empty::new().into())
+        .expect("Failed to build response")
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
 fn spawn_with_trace<T: Send + Sync + 'static>(
     fut: impl Future<Output = T> + Send + 'static,
     span: Span,
@@ -42,12 +120,28 @@ fn spawn_with_trace<T: Send + Sync + 'static>(
 
 pub(crate) struct InternalProxy<C, CA, H, W> {
     pub ca: Arc<CA>,
-    pub client: Client<C, Body>,
+    pub clients: Clients<C>,
     pub server: server::conn::auto::Builder<TokioExecutor>,
     pub http_handler: H,
     pub websocket_handler: W,
     pub websocket_connector: Option<Connector>,
+    pub options: Arc<Options>,
     pub client_addr: SocketAddr,
+    pub flow_id: u64,
+    pub extensions: FlowExtensions,
+    /// HTTP version of the current request, as received from the client.
+    pub downstream_version: hyper::Version,
+    pub tunnel_id: Option<u64>,
+    pub client_certificate: Option<ClientCertificate>,
+    /// The fingerprint of the ClientHello of the intercepted tunnel.
+    #[cfg(feature = "fingerprint")]
+    pub tls_fingerprint: Option<crate::fingerprint::TlsFingerprint>,
+    /// The authority that the handler redirected the current tunnel to.
+    pub connect_target: Option<Authority>,
+    /// The authority of the CONNECT request of the current tunnel.
+    pub tunnel_authority: Option<Authority>,
+    #[cfg(feature = "admin")]
+    pub connection: Option<Arc<crate::admin::ConnectionGuard>>,
 }
 
 impl<C, CA, H, W> Clone for InternalProxy<C, CA, H, W>
@@ -59,12 +153,24 @@ where
     fn clone(&self) -> Self {
         InternalProxy {
             ca: Arc::clone(&self.ca),
-            client: self.client.clone(),
+            clients: self.clients.clone(),
             server: self.server.clone(),
             http_handler: self.http_handler.clone(),
             websocket_handler: self.websocket_handler.clone(),
             websocket_connector: self.websocket_connector.clone(),
+            options: Arc::clone(&self.options),
             client_addr: self.client_addr,
+            flow_id: self.flow_id,
+            extensions: self.extensions.clone(),
+            downstream_version: self.downstream_version,
+            tunnel_id: self.tunnel_id,
+            client_certificate: self.client_certificate.clone(),
+            #[cfg(feature = "fingerprint")]
+            tls_fingerprint: self.tls_fingerprint.clone(),
+            connect_target: self.connect_target.clone(),
+            tunnel_authority: self.tunnel_authority.clone(),
+            #[cfg(feature = "admin")]
+            connection: self.connection.clone(),
         }
     }
 }
@@ -79,7 +185,245 @@ where
     fn context(&self) -> HttpContext {
         HttpContext {
             client_addr: self.client_addr,
+            flow_id: self.flow_id,
+            extensions: self.extensions.clone(),
+            downstream_version: self.downstream_version,
+            upstream_version: None,
+        }
+    }
+
+    /// The protocol that a request is forwarded with, from its [`UpstreamProtocol`] extension or
+    /// the protocol of its host.
+    fn protocol<T>(&self, req: &Request<T>) -> UpstreamProtocol {
+        req.extensions()
+            .get::<UpstreamProtocol>()
+            .copied()
+            .or_else(|| {
+                let host = req.uri().host()?.to_ascii_lowercase();
+                self.options.upstream_protocols.get(&host).copied()
+            })
+            .unwrap_or_default()
+    }
+
+    fn client<T>(&self, req: &Request<T>) -> &Client<C, Body> {
+        self.clients.get(self.protocol(req))
+    }
+
+    /// The DSCP that the upstream connection of a request is marked with, from its [`Dscp`]
+    /// extension or the DSCP of its host.
+    fn dscp<T>(&self, req: &Request<T>, host: &str) -> Option<Dscp> {
+        req.extensions().get::<Dscp>().copied().or_else(|| {
+            if self.options.dscp.is_empty() {
+                return None;
+            }
+
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            self.options.dscp.get(&host.to_ascii_lowercase()).copied()
+        })
+    }
+
+    /// Send a request with the client for its protocol, or through its [`UpstreamProxy`], and
+    /// record the connection that it was sent over.
+    ///
+    /// [`UpstreamProxy`]: crate::UpstreamProxy
+    async fn dispatch(
+        &self,
+        mut req: Request<Body>,
+    ) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        let connections = &self.options.connections;
+        let key = ConnectionTracker::key(req.uri());
+
+        if req.extensions().get::<FreshConnection>().is_some() {
+            connections.poison(&key);
+        }
+
+        let capture = capture_connection(&mut req);
+
+        let dscp = self.dscp(&req, req.uri().host().unwrap_or_default());
+        let bind = self.options.local_bind.with_dscp(dscp);
+
+        #[cfg(feature = "rustls-client")]
+        if let Some(proxy) = req.extensions().get::<crate::UpstreamProxy>() {
+            let res = self
+                .options
+                .upstream_proxies
+                .get(proxy, &bind)
+                .request(req)
+                .await;
+
+            return Self::record(connections, key, capture, res);
+        }
+
+        let addr = self
+            .options
+            .resolver
+            .resolve(req.uri(), req.extensions().get::<crate::ConnectTo>());
+
+        let rebound = if dscp.is_some() || addr.is_some() {
+            let rebound = self.clients.rebound(&bind, addr);
+
+            if rebound.is_none() {
+                warn!(
+                    "Sending request to {} with a custom client, which is not marked or pinned",
+                    req.uri()
+                );
+            }
+
+            rebound
+        } else {
+            None
+        };
+
+        let res = match &rebound {
+            Some(clients) => clients.get(self.protocol(&req)).request(req).await,
+            None => self.client(&req).request(req).await,
+        };
+
+        Self::record(connections, key, capture, res)
+    }
+
+    /// Record the connection that a response was received over.
+    fn record(
+        connections: &ConnectionTracker,
+        key: String,
+        capture: CaptureConnection,
+        res: Result<Response<Incoming>, hyper_util::client::legacy::Error>,
+    ) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        let mut res = res?;
+
+        if let Some(connection) =
+            connections.record(key, capture, res.extensions().get::<HttpInfo>())
+        {
+            res.extensions_mut().insert(connection);
+        }
+
+        Ok(res)
+    }
+
+    /// Apply the configured size limit for `direction` to a body.
+    ///
+    /// Returns `None` if the body is known to exceed the limit and should be rejected.
+    async fn limit_body(
+        &mut self,
+        ctx: &HttpContext,
+        headers: &mut HeaderMap,
+        body: Body,
+        direction: BodyDirection,
+    ) -> Option<Body> {
+        let max = match direction {
+            BodyDirection::Request => self.options.max_request_body_size,
+            BodyDirection::Response => self.options.max_response_body_size,
+        };
+
+        let Some(max) = max else {
+            return Some(body);
+        };
+
+        let truncate = self.options.body_limit_action == BodyLimitAction::Truncate;
+
+        if content_length(headers).is_some_and(|len| len > max as u64) {
+            self.http_handler
+                .handle_body_limit_exceeded(ctx, direction)
+                .instrument(info_span!("handle_body_limit_exceeded"))
+                .await;
+
+            if !truncate {
+                return None;
+            }
+
+            headers.remove(CONTENT_LENGTH);
+            return Some(body.limited(max, true, || ()));
+        }
+
+        let mut handler = self.http_handler.clone();
+        let ctx = ctx.clone();
+
+        Some(body.limited(max, truncate, move || {
+            spawn_with_trace(
+                async move { handler.handle_body_limit_exceeded(&ctx, direction).await },
+                info_span!("handle_body_limit_exceeded"),
+            );
+        }))
+    }
+
+    /// Send a request upstream, retrying it if the retry policy allows it.
+    async fn request(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        let policy = req
+            .extensions()
+            .get::<RetryPolicy>()
+            .or(self.options.retry_policy.as_ref())
+            .filter(|_| is_retryable(&req))
+            .cloned();
+
+        let Some(policy) = policy else {
+            return self.dispatch(req).await;
+        };
+
+        let head = copy_request(&req, ());
+        let mut res = self.dispatch(req).await;
+        let mut attempts = 1;
+
+        while attempts < policy.max_attempts() && policy.should_retry(&res) {
+            match &res {
+                Ok(res) => warn!("Retrying request after {} response", res.status()),
+                Err(err) => warn!("Retrying request after error: {}", err),
+            }
+
+            drop(res);
+            tokio::time::sleep(policy.backoff(attempts as u32)).await;
+            attempts += 1;
+
+            let req = copy_request(&head, Body::from(Empty::new()));
+            res = self.dispatch(req).await;
+        }
+
+        res
+    }
+
+    /// Send a request upstream, following redirects if the redirect policy allows it.
+    async fn send(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        let policy = req
+            .extensions()
+            .get::<RedirectPolicy>()
+            .copied()
+            .unwrap_or(self.options.redirect_policy);
+
+        let RedirectPolicy::Follow(max_redirects) = policy else {
+            return self.request(req).await;
+        };
+
+        let mut has_body = !req.body().is_end_stream();
+        let mut prev = copy_request(&req, ());
+
+        let mut res = self.request(req).await?;
+        let mut uris = Vec::new();
+
+        while uris.len() < max_redirects {
+            let Some(next) = redirect(&prev, &res, has_body) else {
+                break;
+            };
+
+            if next.uri() == prev.uri() || uris.contains(next.uri()) {
+                break;
+            }
+
+            // A hop that changes the method drops the body, so later hops can keep their method.
+            has_body &= next.method() == prev.method();
+            uris.push(mem::replace(&mut prev, next).into_parts().0.uri);
+
+            res = self
+                .request(copy_request(&prev, Body::from(Empty::new())))
+                .await?;
         }
+
+        res.extensions_mut().insert(RedirectChain { uris });
+        Ok(res)
     }
 
     #[instrument(
@@ -91,45 +435,526 @@ where
             client_addr = %self.client_addr,
         )
     )]
-    pub(crate) async fn proxy(
+    pub(crate) async fn proxy<B>(
         mut self,
-        req: Request<Incoming>,
-    ) -> Result<Response<Body>, Infallible> {
-        let ctx = self.context();
+        mut req: Request<B>,
+    ) -> Result<Response<Body>, ConnectionClosed>
+    where
+        Body: From<B>,
+    {
+        self.flow_id = NEXT_FLOW_ID.fetch_add(1, Ordering::Relaxed);
+        self.extensions = FlowExtensions::default();
+        self.downstream_version = req.version();
+
+        if self.options.passthrough && is_passthrough(&req) {
+            return Ok(self.passthrough(req.map(Body::from)).await);
+        }
+
+        req.extensions_mut().insert(FlowId(self.flow_id));
+
+        #[cfg(feature = "fingerprint")]
+        self.extensions
+            .insert(crate::fingerprint::ClientIdentity::new(
+                self.tls_fingerprint.as_ref(),
+                req.headers(),
+            ));
+
+        if let Some(certificate) = &self.client_certificate {
+            req.extensions_mut().insert(certificate.clone());
+        }
+
+        #[cfg(feature = "admin")]
+        if let Some(connection) = &self.connection {
+            connection.record_request();
+        }
+
+        if req.method() == Method::CONNECT {
+            self.tunnel_id = Some(NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed));
+        }
+
+        #[cfg(feature = "decoder")]
+        let recompression = self
+            .options
+            .recompression
+            .filter(|_| req.method() != Method::HEAD)
+            .map(|level| {
+                let accept_encoding = req.headers().get(hyper::header::ACCEPT_ENCODING);
+                (level, accept_encoding.cloned())
+            });
+
+        let log = self.options.access_log.clone();
+        let start = Instant::now();
+        let record = log.as_ref().map(|_| AccessRecord {
+            timestamp: SystemTime::now(),
+            client: self.client_addr,
+            method: req.method().clone(),
+            host: req
+                .uri()
+                .host()
+                .or_else(|| req.headers().get(hyper::header::HOST)?.to_str().ok())
+                .map(str::to_owned),
+            path: req.uri().path().to_owned(),
+            status: StatusCode::OK,
+            bytes: 0,
+            duration: Duration::ZERO,
+            tunnel_id: self.tunnel_id,
+        });
+
+        let req = req.map(Body::from);
+
+        #[cfg(feature = "events")]
+        let events = self.options.events.clone().filter(|e| e.is_active());
+        #[cfg(feature = "events")]
+        let req = match &events {
+            Some(events) => events.flow_started(self.flow_id, self.client_addr, req),
+            None => req,
+        };
+        #[cfg(feature = "events")]
+        let flow_id = self.flow_id;
+
+        let res = self.process(req).await?;
+
+        #[cfg(feature = "decoder")]
+        let res = match recompression {
+            Some((level, accept_encoding))
+                if res.extensions().get::<crate::decoder::Decoded>().is_some() =>
+            {
+                crate::encode_response(res, accept_encoding.as_ref(), level)
+            }
+            _ => res,
+        };
+
+        let res = match log.zip(record) {
+            Some((log, record)) => log.log(record, start, res),
+            None => res,
+        };
+
+        #[cfg(feature = "events")]
+        let res = match events {
+            Some(events) => events.flow_completed(flow_id, start, res),
+            None => res,
+        };
+
+        Ok(res)
+    }
+
+    /// Forward a request as it is, for proxies whose handlers are no-ops.
+    async fn passthrough(mut self, req: Request<Body>) -> Response<Body> {
+        let authority = self.failure_authority(&req);
+
+        match self
+            .dispatch(req)
+            .instrument(info_span!("proxy_request"))
+            .await
+        {
+            Ok(res) => res.map(Body::from),
+            Err(err) => {
+                let ctx = self.context();
+
+                if let Some(failure) =
+                    authority.and_then(|authority| TlsFailure::upstream(authority, &err))
+                {
+                    self.report_tls_failure(&ctx, failure).await;
+                }
+
+                self.http_handler.handle_error(&ctx, err).await
+            }
+        }
+    }
+
+    async fn process(mut self, req: Request<Body>) -> Result<Response<Body>, ConnectionClosed> {
+        let mut ctx = self.context();
+
+        #[cfg(feature = "blocklist")]
+        if let Some(blocklist) = &self.options.blocklist {
+            match blocklist.check(&req) {
+                Some(crate::blocklist::BlockAction::Status(status)) => {
+                    return Ok(Response::builder()
+                        .status(status)
+                        .body(Empty::new().into())
+                        .expect("Failed to build response"));
+                }
+                Some(crate::blocklist::BlockAction::Close) => return Err(ConnectionClosed),
+                None => {}
+            }
+        }
+
+        let req = {
+            let (mut parts, body) = req.into_parts();
+
+            match self
+                .limit_body(&ctx, &mut parts.headers, body, BodyDirection::Request)
+                .await
+            {
+                Some(body) => Request::from_parts(parts, body),
+                None => return Ok(payload_too_large()),
+            }
+        };
 
-        let req = match self
+        #[cfg(feature = "audit")]
+        let audit = self.options.audit_log.clone();
+
+        if expects_continue(&req) {
+            if let Some(res) = self
+                .http_handler
+                .handle_expect_continue(&ctx, &req)
+                .instrument(info_span!("handle_expect_continue"))
+                .await
+            {
+                #[cfg(feature = "audit")]
+                if let Some(audit) = &audit {
+                    audit.mocked(ctx.flow_id, &res);
+                }
+
+                return Ok(res);
+            }
+        }
+
+        #[cfg(feature = "audit")]
+        let (req, tracker) = match &audit {
+            Some(audit) => {
+                let (req, tracker) = audit.track_request(ctx.flow_id, req);
+                (req, Some(tracker))
+            }
+            None => (req, None),
+        };
+
+        let mut req = match self
             .http_handler
-            .handle_request(&ctx, req.map(Body::from))
+            .handle_request(&ctx, req)
             .instrument(info_span!("handle_request"))
             .await
         {
             RequestOrResponse::Request(req) => req,
-            RequestOrResponse::Response(res) => return Ok(res),
+            RequestOrResponse::Response(res) => {
+                #[cfg(feature = "audit")]
+                if let Some(audit) = &audit {
+                    audit.mocked(ctx.flow_id, &res);
+                }
+
+                return Ok(res);
+            }
         };
 
+        #[cfg(feature = "dns")]
+        {
+            req = match crate::dns::handle_request(&mut self.http_handler, &ctx, req)
+                .instrument(info_span!("handle_dns_query"))
+                .await
+            {
+                Ok(req) => req,
+                Err(_) => return Ok(bad_request()),
+            };
+        }
+
+        #[cfg(feature = "audit")]
+        if let Some(tracker) = tracker {
+            req = tracker.finish_request(req);
+        }
+
         if req.method() == Method::CONNECT {
             Ok(self.process_connect(req))
         } else if hyper_tungstenite::is_upgrade_request(&req) {
             Ok(self.upgrade_websocket(req))
         } else {
-            let res = self
-                .client
-                .request(normalize_request(req))
-                .instrument(info_span!("proxy_request"))
-                .await;
+            if self.options.expect_continue == ExpectContinue::Local {
+                req.headers_mut().remove(hyper::header::EXPECT);
+            }
+
+            let informational = Arc::new(Mutex::new(Vec::new()));
+
+            {
+                let informational = Arc::clone(&informational);
+                hyper::ext::on_informational(&mut req, move |res| {
+                    let mut interim = Response::new(());
+                    *interim.status_mut() = res.status();
+                    *interim.version_mut() = res.version();
+                    *interim.headers_mut() = res.headers().clone();
+                    informational
+                        .lock()
+                        .expect("Failed to lock informational responses")
+                        .push(interim);
+                });
+            }
+
+            let attempt = match self
+                .options
+                .circuit_breaker
+                .as_ref()
+                .zip(req.uri().host().map(str::to_ascii_lowercase))
+            {
+                Some((breaker, host)) => match breaker.allow(&host) {
+                    Some(attempt) => Some(attempt),
+                    None => {
+                        return Ok(self
+                            .http_handler
+                            .handle_circuit_open(&ctx, &host)
+                            .instrument(info_span!("handle_circuit_open"))
+                            .await);
+                    }
+                },
+                None => None,
+            };
+
+            // The requests of a redirected tunnel keep the Host header of their original authority,
+            // unless the handler sent them somewhere else.
+            let host = self
+                .connect_target
+                .as_ref()
+                .filter(|target| req.uri().authority() == Some(*target))
+                .and_then(|_| req.headers().get(hyper::header::HOST).cloned());
+            let mut req = normalize_request(req);
+
+            if let Some(host) = host {
+                req.headers_mut().insert(hyper::header::HOST, host);
+            }
+
+            #[cfg(feature = "admin")]
+            let bandwidth = self
+                .options
+                .admin
+                .clone()
+                .zip(req.uri().host().map(str::to_owned));
+
+            #[cfg(feature = "admin")]
+            if let Some((admin, host)) = &bandwidth {
+                req = admin.count_request(host, req);
+            }
+
+            let authority = self.failure_authority(&req);
+            let res = self.send(req).instrument(info_span!("proxy_request")).await;
+
+            if let Some(attempt) = attempt {
+                let success = match &res {
+                    Ok(res) => !matches!(
+                        res.status(),
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    ),
+                    Err(_) => false,
+                };
+
+                attempt.record(success);
+            }
 
             match res {
-                Ok(res) => Ok(self
-                    .http_handler
-                    .handle_response(&ctx, res.map(Body::from))
-                    .instrument(info_span!("handle_response"))
-                    .await),
-                Err(err) => Ok(self
+                Ok(res) => {
+                    ctx.upstream_version = Some(res.version());
+
+                    #[cfg(feature = "events")]
+                    if let Some(events) = &self.options.events {
+                        events.response_headers(ctx.flow_id, &res);
+                    }
+
+                    let mut res = {
+                        let (mut parts, body) = res.into_parts();
+
+                        match self
+                            .limit_body(
+                                &ctx,
+                                &mut parts.headers,
+                                body.into(),
+                                BodyDirection::Response,
+                            )
+                            .await
+                        {
+                            Some(body) => Response::from_parts(parts, body),
+                            None => return Ok(bad_gateway()),
+                        }
+                    };
+
+                    #[cfg(feature = "admin")]
+                    if let Some((admin, host)) = &bandwidth {
+                        res = admin.count_response(host, res);
+                    }
+
+                    let informational = mem::take(
+                        &mut *informational
+                            .lock()
+                            .expect("Failed to lock informational responses"),
+                    );
+
+                    res.extensions_mut().insert(FlowId(ctx.flow_id));
+
+                    for interim in informational {
+                        if let Some(interim) = self
+                            .http_handler
+                            .inspect_informational(&ctx, interim)
+                            .instrument(info_span!("inspect_informational"))
+                            .await
+                        {
+                            merge_early_hints(&mut res, &interim);
+                        }
+                    }
+
+                    #[cfg(feature = "audit")]
+                    let (res, tracker) = match &audit {
+                        Some(audit) => {
+                            let (res, tracker) = audit.track_response(ctx.flow_id, res);
+                            (res, Some(tracker))
+                        }
+                        None => (res, None),
+                    };
+
+                    #[cfg(feature = "dns")]
+                    let res = match crate::dns::handle_response(&mut self.http_handler, &ctx, res)
+                        .instrument(info_span!("handle_dns_response"))
+                        .await
+                    {
+                        Ok(res) => res,
+                        Err(_) => return Ok(bad_gateway()),
+                    };
+
+                    let res = self
+                        .http_handler
+                        .handle_response(&ctx, res)
+                        .instrument(info_span!("handle_response"))
+                        .await;
+
+                    #[cfg(feature = "audit")]
+                    let res = match tracker {
+                        Some(tracker) => tracker.finish_response(res),
+                        None => res,
+                    };
+
+                    Ok(res)
+                }
+                Err(err) => {
+                    if let Some(failure) =
+                        authority.and_then(|authority| TlsFailure::upstream(authority, &err))
+                    {
+                        self.report_tls_failure(&ctx, failure).await;
+                    }
+
+                    Ok(self
+                        .http_handler
+                        .handle_error(&ctx, err)
+                        .instrument(info_span!("handle_error"))
+                        .await)
+                }
+            }
+        }
+    }
+
+    /// The authority that a failed TLS handshake with the upstream server of a request is
+    /// reported for, which is the authority of the CONNECT request if the request was sent to
+    /// the authority that its tunnel was redirected to.
+    fn failure_authority<T>(&self, req: &Request<T>) -> Option<Authority> {
+        match (&self.connect_target, &self.tunnel_authority) {
+            (Some(target), Some(authority)) if req.uri().authority() == Some(target) => {
+                Some(authority.clone())
+            }
+            _ => req.uri().authority().cloned(),
+        }
+    }
+
+    /// Pass a failed TLS handshake to the HTTP handler, and stop intercepting the tunnels to its
+    /// host if it suggests passthrough and automatic passthrough is enabled.
+    async fn report_tls_failure(&mut self, ctx: &HttpContext, failure: TlsFailure) {
+        if failure.suggest_passthrough {
+            if let Some(list) = &self.options.auto_passthrough {
+                if list.insert(failure.authority.host()) {
+                    info!(
+                        "No longer intercepting tunnels to {} after a TLS handshake failure",
+                        failure.authority.host()
+                    );
+                }
+            }
+        }
+
+        self.http_handler
+            .handle_tls_failure(ctx, &failure)
+            .instrument(info_span!("handle_tls_failure"))
+            .await;
+    }
+
+    /// Whether a CONNECT request should be intercepted, according to the interception lists of
+    /// the admin API and the HTTP handler, whose decisions may be cached.
+    async fn should_intercept(&mut self, authority: &Authority, req: &Request<Body>) -> bool {
+        #[cfg(feature = "admin")]
+        let admin = self.options.admin.clone();
+
+        #[cfg(feature = "admin")]
+        if let Some(admin) = &admin {
+            if !admin.interception().allows(authority.host()) {
+                admin.record_tunnel(false);
+                return false;
+            }
+        }
+
+        if let Some(list) = &self.options.auto_passthrough {
+            if list.contains(authority.host()) {
+                #[cfg(feature = "admin")]
+                if let Some(admin) = &admin {
+                    admin.record_tunnel(false);
+                }
+
+                return false;
+            }
+        }
+
+        let cache = self.options.interception_cache.clone();
+
+        let intercept = match cache.as_ref().and_then(|cache| cache.get(authority)) {
+            Some(intercept) => intercept,
+            None => {
+                let intercept = self
                     .http_handler
-                    .handle_error(&ctx, err)
-                    .instrument(info_span!("handle_error"))
-                    .await),
+                    .should_intercept(&self.context(), req)
+                    .await;
+
+                if let Some(cache) = &cache {
+                    cache.insert(authority, intercept);
+                }
+
+                intercept
             }
+        };
+
+        #[cfg(feature = "admin")]
+        if let Some(admin) = &admin {
+            admin.record_tunnel(intercept);
+        }
+
+        intercept
+    }
+
+    /// The protocols to offer with ALPN to a client whose tunnel to an authority is intercepted,
+    /// if they differ from those of the server config.
+    async fn alpn_protocols(&self, authority: &Authority) -> Option<Vec<Vec<u8>>> {
+        let policy = self
+            .options
+            .alpn_policies
+            .get(&authority.host().to_ascii_lowercase())?;
+
+        let http1 = || vec![b"http/1.1".to_vec()];
+        #[cfg(feature = "http2")]
+        let http2 = || vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        match policy {
+            AlpnPolicy::Auto => None,
+            AlpnPolicy::Http1 => Some(http1()),
+            #[cfg(feature = "http2")]
+            AlpnPolicy::Http2 => Some(http2()),
+            #[cfg(feature = "rustls-client")]
+            AlpnPolicy::Upstream => match self
+                .options
+                .alpn_probe
+                .negotiate(
+                    self.connect_target.as_ref().unwrap_or(authority),
+                    &self.options.local_bind,
+                )
+                .await
+            {
+                #[cfg(feature = "http2")]
+                Ok(Some(protocol)) if protocol == b"h2" => Some(http2()),
+                Ok(_) => Some(http1()),
+                Err(e) => {
+                    warn!("Failed to probe ALPN protocols of {}: {}", authority, e);
+                    None
+                }
+            },
         }
     }
 
@@ -138,9 +963,42 @@ where
             Some(authority) => {
                 let span = info_span!("process_connect");
                 let fut = async move {
-                    match hyper::upgrade::on(&mut req).await {
-                        Ok(upgraded) => {
-                            let mut upgraded = TokioIo::new(upgraded);
+                    let target = self
+                        .http_handler
+                        .handle_connect_target(&self.context(), &req, authority.clone())
+                        .instrument(info_span!("handle_connect_target"))
+                        .await;
+
+                    if target != authority {
+                        self.connect_target = Some(target);
+                    }
+
+                    self.tunnel_authority = Some(authority.clone());
+
+                    let route = req.extensions().get::<SniRoute>().cloned();
+
+                    if let Some(SniRoute::Backend(backend)) = &route {
+                        self.connect_target = Some(backend.clone());
+                    }
+
+                    if self.options.certificate_prefetch
+                        && !matches!(route, Some(SniRoute::Passthrough | SniRoute::Backend(_)))
+                    {
+                        let ca = Arc::clone(&self.ca);
+                        let authority = authority.clone();
+                        tokio::spawn(async move { ca.prefetch_server_config(&authority).await });
+                    }
+
+                    let upgraded = match req.extensions_mut().remove::<DirectUpgrade>() {
+                        Some(direct) => direct.on().await.map(TunnelStream::Direct),
+                        None => hyper::upgrade::on(&mut req)
+                            .await
+                            .map(|upgraded| TunnelStream::Upgraded(TokioIo::new(upgraded)))
+                            .map_err(io::Error::other),
+                    };
+
+                    match upgraded {
+                        Ok(mut upgraded) => {
                             let mut buffer = [0; 4];
                             let bytes_read = match upgraded.read(&mut buffer).await {
                                 Ok(bytes_read) => bytes_read,
@@ -150,17 +1008,18 @@ where
                                 }
                             };
 
-                            let mut upgraded = Rewind::new(
+                            let upgraded = Rewind::new(
                                 upgraded,
                                 Bytes::copy_from_slice(buffer[..bytes_read].as_ref()),
                             );
 
-                            if self
-                                .http_handler
-                                .should_intercept(&self.context(), &req)
-                                .await
-                            {
-                                if buffer == *b"GET " {
+                            let intercept = match route {
+                                Some(SniRoute::Passthrough | SniRoute::Backend(_)) => false,
+                                _ => self.should_intercept(&authority, &req).await,
+                            };
+
+                            if intercept {
+                                if buffer == *b"GET " || buffer == *b"PRI " {
                                     if let Err(e) = self
                                         .serve_stream(
                                             TokioIo::new(upgraded),
@@ -180,6 +1039,43 @@ where
                                         .instrument(info_span!("gen_server_config"))
                                         .await;
 
+                                    let server_config = match self.alpn_protocols(&authority).await
+                                    {
+                                        Some(protocols) => {
+                                            let mut config = (*server_config).clone();
+                                            config.alpn_protocols = protocols;
+                                            Arc::new(config)
+                                        }
+                                        None => server_config,
+                                    };
+
+                                    let server_config = match &self.options.client_auth {
+                                        Some(client_auth) => {
+                                            Arc::new(client_auth.apply(&server_config))
+                                        }
+                                        None => server_config,
+                                    };
+
+                                    #[cfg(feature = "dns")]
+                                    let is_dot = authority.port_u16() == Some(853);
+
+                                    #[cfg(feature = "dns")]
+                                    let server_config = if is_dot {
+                                        let mut config = (*server_config).clone();
+                                        config.alpn_protocols = vec![b"dot".to_vec()];
+                                        Arc::new(config)
+                                    } else {
+                                        server_config
+                                    };
+
+                                    #[cfg(feature = "fingerprint")]
+                                    let upgraded = {
+                                        let (upgraded, fingerprint) =
+                                            crate::fingerprint::read_client_hello(upgraded).await;
+                                        self.tls_fingerprint = fingerprint;
+                                        upgraded
+                                    };
+
                                     let stream = match TlsAcceptor::from(server_config)
                                         .accept(upgraded)
                                         .await
@@ -187,16 +1083,39 @@ where
                                         Ok(stream) => TokioIo::new(stream),
                                         Err(e) => {
                                             error!("Failed to establish TLS connection: {}", e);
+                                            let ctx = self.context();
+                                            self.report_tls_failure(
+                                                &ctx,
+                                                TlsFailure::client(authority, &e),
+                                            )
+                                            .await;
                                             return;
                                         }
                                     };
 
+                                    self.client_certificate = ClientCertificate::from_chain(
+                                        stream.inner().get_ref().1.peer_certificates(),
+                                    );
+
+                                    #[cfg(feature = "dns")]
+                                    if is_dot {
+                                        if let Err(e) = self
+                                            .serve_dns_over_tls(stream.into_inner(), authority)
+                                            .await
+                                        {
+                                            error!("DNS-over-TLS error: {}", e);
+                                        }
+
+                                        return;
+                                    }
+
                                     if let Err(e) =
                                         self.serve_stream(stream, Scheme::HTTPS, authority).await
                                     {
                                         if !e
                                             .to_string()
                                             .starts_with("error shutting down connection")
+                                            && !is_closed(e.as_ref())
                                         {
                                             error!("HTTPS connect error: {}", e);
                                         }
@@ -211,18 +1130,94 @@ where
                                 }
                             }
 
-                            let mut server = match TcpStream::connect(authority.as_ref()).await {
+                            let target = self.connect_target.as_ref().unwrap_or(&authority);
+                            let (host, port) =
+                                (target.host().to_owned(), target.port_u16().unwrap_or(443));
+
+                            let (host, port) = match self.options.resolver.lookup(&host, port) {
+                                Some(addr) => (addr.ip().to_string(), addr.port()),
+                                None => (host, port),
+                            };
+
+                            let bind = self
+                                .options
+                                .local_bind
+                                .with_dscp(self.dscp(&req, target.host()));
+
+                            let mut server = match happy_eyeballs::connect(&host, port, &bind).await
+                            {
                                 Ok(server) => server,
                                 Err(e) => {
-                                    error!("Failed to connect to {}: {}", authority, e);
+                                    error!("Failed to connect to {}: {}", target, e);
                                     return;
                                 }
                             };
 
-                            if let Err(e) =
-                                tokio::io::copy_bidirectional(&mut upgraded, &mut server).await
+                            let (upgraded, prefix) = upgraded.into_parts();
+
+                            let stats = match upgraded.into_tcp() {
+                                Ok((client, rest)) => {
+                                    let mut buf = Vec::with_capacity(prefix.len() + rest.len());
+                                    buf.extend_from_slice(&prefix);
+                                    buf.extend_from_slice(&rest);
+                                    tunnel::tunnel_tcp(
+                                        client,
+                                        buf.into(),
+                                        &mut server,
+                                        self.options.tunnel,
+                                        &self.options.buffer_pool,
+                                    )
+                                    .await
+                                }
+                                Err(upgraded) => {
+                                    tunnel::tunnel(
+                                        Rewind::new(upgraded, prefix),
+                                        &mut server,
+                                        self.options.tunnel,
+                                        &self.options.buffer_pool,
+                                    )
+                                    .await
+                                }
+                            };
+
+                            match &stats.end {
+                                TunnelEnd::Error(e) => {
+                                    error!("Failed to tunnel to {}: {}", target, e)
+                                }
+                                end => debug!(
+                                    "Tunnel to {} closed ({:?}) after {:?}, {} bytes sent, {} bytes received",
+                                    target, end, stats.duration, stats.sent, stats.received
+                                ),
+                            }
+
+                            #[cfg(feature = "admin")]
+                            if let Some(admin) = &self.options.admin {
+                                admin.record_bandwidth(
+                                    authority.host(),
+                                    stats.sent,
+                                    stats.received,
+                                );
+                            }
+
+                            #[cfg(feature = "events")]
+                            if let (Some(events), Some(tunnel_id)) =
+                                (&self.options.events, self.tunnel_id)
                             {
-                                error!("Failed to tunnel to {}: {}", authority, e);
+                                use crate::events::{Event, TunnelCloseReason};
+
+                                events.emit(|| Event::TunnelClosed {
+                                    tunnel_id,
+                                    authority: authority.to_string(),
+                                    reason: match stats.end {
+                                        TunnelEnd::Closed => TunnelCloseReason::Closed,
+                                        TunnelEnd::IdleTimeout => TunnelCloseReason::IdleTimeout,
+                                        TunnelEnd::MaxLifetime => TunnelCloseReason::MaxLifetime,
+                                        TunnelEnd::Error(_) => TunnelCloseReason::Error,
+                                    },
+                                    bytes_sent: stats.sent,
+                                    bytes_received: stats.received,
+                                    duration: stats.duration.as_millis() as u64,
+                                });
                             }
                         }
                         Err(e) => error!("Upgrade error: {}", e),
@@ -236,6 +1231,45 @@ where
         }
     }
 
+    /// Serves a TLS or SOCKS5 connection that was detected on the listener as a tunnel, by
+    /// processing a `CONNECT` request for its authority.
+    pub(crate) async fn proxy_direct(
+        self,
+        mut stream: Rewind<TcpStream>,
+        authority: Authority,
+        socks: bool,
+    ) {
+        let (direct, tx) = DirectUpgrade::new();
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(authority.as_str())
+            .header(hyper::header::HOST, authority.as_str())
+            .body(Empty::<Bytes>::new())
+            .expect("Failed to build CONNECT request");
+        req.extensions_mut().insert(direct);
+
+        if !socks {
+            if let Some(route) = self.options.sni_routes.get(authority.host()) {
+                req.extensions_mut().insert(route.clone());
+            }
+        }
+
+        let accepted = matches!(self.proxy(req).await, Ok(res) if res.status().is_success());
+
+        if socks {
+            if let Err(e) = detect::socks_reply(&mut stream, accepted).await {
+                debug!("Failed to reply to SOCKS5 client: {}", e);
+                return;
+            }
+        }
+
+        if accepted {
+            let _ = tx.send(stream);
+        } else {
+            debug!("Closing {} tunnel that was not accepted", authority);
+        }
+    }
+
     #[instrument(skip_all)]
     fn upgrade_websocket(self, req: Request<Body>) -> Response<Body> {
         let mut req = {
@@ -258,10 +1292,28 @@ where
                 }
             };
 
+            // The Host header is sent as it is, so it is replaced if the handler sent the request
+            // to a different host, unless the tunnel was redirected.
+            if let Some(authority) = parts.uri.authority() {
+                let host = parts
+                    .headers
+                    .get(hyper::header::HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .map(|host| host.rsplit_once(':').map_or(host, |(host, _)| host));
+
+                if host.is_some_and(|host| !host.eq_ignore_ascii_case(authority.host()))
+                    && self.connect_target.as_ref() != Some(authority)
+                {
+                    if let Ok(value) = authority.as_str().parse() {
+                        parts.headers.insert(hyper::header::HOST, value);
+                    }
+                }
+            }
+
             Request::from_parts(parts, ())
         };
 
-        match hyper_tungstenite::upgrade(&mut req, None) {
+        match hyper_tungstenite::upgrade(&mut req, self.options.websocket_config) {
             Ok((res, websocket)) => {
                 let span = info_span!("websocket");
                 let fut = async move {
@@ -284,6 +1336,38 @@ where
         }
     }
 
+    /// Connect to the server of a WebSocket upgrade request, through its [`UpstreamProxy`] or to
+    /// its pinned address.
+    ///
+    /// [`UpstreamProxy`]: crate::UpstreamProxy
+    #[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+    async fn connect_websocket(&self, req: &Request<()>) -> io::Result<TcpStream> {
+        let uri = req.uri();
+        let host = uri.host().unwrap_or_default();
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("wss") {
+                443
+            } else {
+                80
+            });
+        let bind = self.options.local_bind.with_dscp(self.dscp(req, host));
+
+        #[cfg(feature = "rustls-client")]
+        if let Some(proxy) = req.extensions().get::<crate::UpstreamProxy>() {
+            return super::upstream::tunnel(proxy.authority(), host, port, &bind).await;
+        }
+
+        match self
+            .options
+            .resolver
+            .resolve(uri, req.extensions().get::<crate::ConnectTo>())
+        {
+            Some(addr) => happy_eyeballs::connect(&addr.ip().to_string(), addr.port(), &bind).await,
+            None => happy_eyeballs::connect(host, port, &bind).await,
+        }
+    }
+
     #[instrument(skip_all)]
     async fn handle_websocket(
         self,
@@ -291,45 +1375,163 @@ where
         req: Request<()>,
     ) -> Result<(), tungstenite::Error> {
         let uri = req.uri().clone();
+        let config = self.options.websocket_config;
 
         #[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
-        let (client_socket, _) = tokio_tungstenite::connect_async_tls_with_config(
-            req,
-            None,
-            false,
-            self.websocket_connector,
-        )
-        .await?;
+        let connected = match self.connect_websocket(&req).await {
+            Ok(stream) => {
+                tokio_tungstenite::client_async_tls_with_config(
+                    req,
+                    stream,
+                    config,
+                    self.websocket_connector,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
 
         #[cfg(not(any(feature = "rustls-client", feature = "native-tls-client")))]
-        let (client_socket, _) = tokio_tungstenite::connect_async(req).await?;
+        let connected = tokio_tungstenite::connect_async_with_config(req, config, false).await;
+
+        let InternalProxy {
+            mut websocket_handler,
+            ..
+        } = self;
+
+        let socket_id = self.flow_id;
+
+        let (client_socket, subprotocol) = match connected {
+            Ok((client_socket, res)) => {
+                let subprotocol = res
+                    .headers()
+                    .get(hyper::header::SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_owned);
+
+                (client_socket, subprotocol)
+            }
+            Err(e) => {
+                let ctx = WebSocketContext::ClientToServer {
+                    src: self.client_addr,
+                    dst: uri,
+                    socket_id,
+                    subprotocol: None,
+                    extensions: self.extensions.clone(),
+                };
+                websocket_handler.handle_connect_error(&ctx, &e).await;
+
+                let mut server_socket = server_socket;
+                let frame = CloseFrame {
+                    code: CloseCode::Error,
+                    reason: "Failed to connect to server".into(),
+                };
+
+                if let Err(e) = server_socket.close(Some(frame)).await {
+                    debug!("Failed to close WebSocket: {}", e);
+                }
+
+                return Err(e);
+            }
+        };
 
         let (server_sink, server_stream) = server_socket.split();
         let (client_sink, client_stream) = client_socket.split();
 
-        let InternalProxy {
-            websocket_handler, ..
-        } = self;
+        let client_to_server = WebSocketContext::ClientToServer {
+            src: self.client_addr,
+            dst: uri.clone(),
+            socket_id,
+            subprotocol: subprotocol.clone(),
+            extensions: self.extensions.clone(),
+        };
+        let server_to_client = WebSocketContext::ServerToClient {
+            src: uri,
+            dst: self.client_addr,
+            socket_id,
+            subprotocol,
+            extensions: self.extensions.clone(),
+        };
+
+        #[cfg(feature = "events")]
+        let (server_stream, client_stream) = (
+            crate::events::websocket_messages(
+                self.options.events.clone(),
+                &client_to_server,
+                server_stream,
+            ),
+            crate::events::websocket_messages(
+                self.options.events.clone(),
+                &server_to_client,
+                client_stream,
+            ),
+        );
 
+        // The server socket is connected to the client, and the client socket to the server.
         spawn_message_forwarder(
             server_stream,
             client_sink,
             websocket_handler.clone(),
-            WebSocketContext::ServerToClient {
-                src: uri.clone(),
-                dst: self.client_addr,
-            },
+            client_to_server,
+            self.options.websocket_buffer,
         );
+        spawn_message_forwarder(
+            client_stream,
+            server_sink,
+            websocket_handler,
+            server_to_client,
+            self.options.websocket_buffer,
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "dns")]
+    #[instrument(skip_all)]
+    async fn serve_dns_over_tls<I>(
+        self,
+        stream: I,
+        authority: Authority,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let config = self
+            .options
+            .dns_tls_config
+            .clone()
+            .unwrap_or_else(crate::dns::default_tls_config);
+        let authority = self.connect_target.clone().unwrap_or(authority);
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(host.to_owned())?;
+
+        let server = happy_eyeballs::connect(
+            authority.host(),
+            authority.port_u16().unwrap_or(853),
+            &self.options.local_bind,
+        )
+        .await?;
+        let server = tokio_rustls::TlsConnector::from(config)
+            .connect(server_name, server)
+            .await?;
 
-        spawn_message_forwarder(
-            client_stream,
-            server_sink,
-            websocket_handler,
-            WebSocketContext::ClientToServer {
-                src: self.client_addr,
-                dst: uri,
-            },
-        );
+        let ctx = self.context();
+        let (client_read, client_write) = tokio::io::split(stream);
+        let (server_read, server_write) = tokio::io::split(server);
+
+        tokio::try_join!(
+            crate::dns::forward(
+                client_read,
+                server_write,
+                self.http_handler.clone(),
+                ctx.clone(),
+                true
+            ),
+            crate::dns::forward(server_read, client_write, self.http_handler, ctx, false),
+        )?;
 
         Ok(())
     }
@@ -359,6 +1561,26 @@ where
                 req = Request::from_parts(parts, body);
             };
 
+            if let Some(target) = &self.connect_target {
+                let (mut parts, body) = req.into_parts();
+
+                if let Some(authority) = parts.uri.authority() {
+                    if !parts.headers.contains_key(hyper::header::HOST) {
+                        if let Ok(host) = hyper::header::HeaderValue::from_str(authority.as_str()) {
+                            parts.headers.insert(hyper::header::HOST, host);
+                        }
+                    }
+                }
+
+                parts.uri = {
+                    let mut parts = parts.uri.into_parts();
+                    parts.authority = Some(target.clone());
+                    Uri::from_parts(parts).expect("Failed to build URI")
+                };
+
+                req = Request::from_parts(parts, body);
+            }
+
             self.clone().proxy(req)
         });
 
@@ -373,17 +1595,221 @@ fn spawn_message_forwarder(
     sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
     handler: impl WebSocketHandler,
     ctx: WebSocketContext,
+    buffer: Option<usize>,
 ) {
     let span = info_span!("message_forwarder", context = ?ctx);
+
+    let Some(buffer) = buffer else {
+        let fut = handler.handle_websocket(ctx, stream, sink);
+        spawn_with_trace(fut, span);
+        return;
+    };
+
+    // The handler sends messages into a bounded channel, which applies backpressure to it once
+    // the peer that the messages are sent to falls behind by `buffer` messages.
+    let (tx, mut rx) = mpsc::channel(buffer);
+
+    let send = async move {
+        let mut sink = sink;
+
+        while let Some(message) = rx.next().await {
+            match sink.send(message).await {
+                Ok(()) => (),
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                Err(e) => {
+                    error!("WebSocket send error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let _ = sink.close().await;
+    };
+    spawn_with_trace(send, span.clone());
+
+    let sink = tx.sink_map_err(|_| tungstenite::Error::ConnectionClosed);
     let fut = handler.handle_websocket(ctx, stream, sink);
     spawn_with_trace(fut, span);
 }
 
+/// Add the `Link` headers of a `103 Early Hints` response to the final response.
+fn merge_early_hints<T>(res: &mut Response<T>, interim: &Response<()>) {
+    if interim.status() != StatusCode::EARLY_HINTS {
+        return;
+    }
+
+    for link in interim.headers().get_all(hyper::header::LINK) {
+        if !res
+            .headers()
+            .get_all(hyper::header::LINK)
+            .iter()
+            .any(|val| val == link)
+        {
+            res.headers_mut().append(hyper::header::LINK, link.clone());
+        }
+    }
+}
+
+fn is_retryable<T: hyper::body::Body>(req: &Request<T>) -> bool {
+    (matches!(*req.method(), Method::GET | Method::HEAD)
+        || req.extensions().get::<Idempotent>().is_some())
+        && req.body().is_end_stream()
+}
+
+fn copy_request<T, B>(req: &Request<T>, body: B) -> Request<B> {
+    let mut copy = Request::new(body);
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    *copy.extensions_mut() = req.extensions().clone();
+    copy
+}
+
+/// Resolve the target of a `Location` header against the URI of the request it redirects.
+fn resolve_location(base: &Uri, location: &str) -> Option<Uri> {
+    let location = location.split('#').next().unwrap_or_default();
+    let scheme = base.scheme_str().unwrap_or("http");
+
+    let uri = if location.contains("://") {
+        location.to_owned()
+    } else if location.starts_with("//") {
+        format!("{scheme}:{location}")
+    } else {
+        let authority = base.authority()?;
+
+        if location.starts_with('/') {
+            format!("{scheme}://{authority}{location}")
+        } else {
+            let path = base.path();
+            let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+            let dir = if dir.is_empty() { "/" } else { dir };
+            format!("{scheme}://{authority}{dir}{location}")
+        }
+    };
+
+    uri.parse().ok()
+}
+
+/// Build the request that follows a redirect, or `None` if the response should not be followed.
+fn redirect<T>(req: &Request<()>, res: &Response<T>, has_body: bool) -> Option<Request<()>> {
+    let method = match res.status() {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND if req.method() == Method::POST => {
+            Method::GET
+        }
+        StatusCode::SEE_OTHER if req.method() != Method::HEAD => Method::GET,
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => req.method().clone(),
+        _ => return None,
+    };
+
+    // The body has already been sent and cannot be sent again.
+    if method == req.method() && has_body {
+        return None;
+    }
+
+    let location = res.headers().get(LOCATION)?.to_str().ok()?;
+    let uri = resolve_location(req.uri(), location)?;
+
+    let mut next = copy_request(req, ());
+
+    if method != req.method() {
+        for name in [
+            CONTENT_ENCODING,
+            CONTENT_LENGTH,
+            CONTENT_TYPE,
+            TRANSFER_ENCODING,
+        ] {
+            next.headers_mut().remove(name);
+        }
+    }
+
+    if uri.scheme() != req.uri().scheme() || uri.authority() != req.uri().authority() {
+        for name in [
+            AUTHORIZATION,
+            COOKIE,
+            hyper::header::HOST,
+            PROXY_AUTHORIZATION,
+        ] {
+            next.headers_mut().remove(name);
+        }
+    }
+
+    *next.method_mut() = method;
+    *next.uri_mut() = uri;
+    Some(next)
+}
+
+fn expects_continue<T>(req: &Request<T>) -> bool {
+    req.headers()
+        .get(hyper::header::EXPECT)
+        .is_some_and(|val| val.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+fn is_h2c_upgrade<T>(req: &Request<T>) -> bool {
+    req.headers()
+        .get_all(hyper::header::UPGRADE)
+        .iter()
+        .flat_map(|val| val.as_bytes().split_str(b","))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case(b"h2c"))
+}
+
+/// Remove an `Upgrade: h2c` request so that the request is served over HTTP/1.1.
+///
+/// Servers are permitted to ignore an h2c upgrade (RFC 7540 section 3.2), which is the only option
+/// available to us since the response to the upgraded request would have to be delivered on a
+/// stream that the HTTP/2 server never sees. Clients wanting HTTP/2 over cleartext can use prior
+/// knowledge instead.
+fn strip_h2c_upgrade<T>(req: &mut Request<T>) {
+    if !is_h2c_upgrade(req) {
+        return;
+    }
+
+    let headers = req.headers_mut();
+    headers.remove(hyper::header::UPGRADE);
+    headers.remove(HTTP2_SETTINGS);
+
+    if let Entry::Occupied(mut connection) = headers.entry(hyper::header::CONNECTION) {
+        let options = connection
+            .iter()
+            .flat_map(|val| val.as_bytes().split_str(b","))
+            .map(|option| option.trim())
+            .filter(|option| {
+                !option.eq_ignore_ascii_case(b"upgrade")
+                    && !option.eq_ignore_ascii_case(HTTP2_SETTINGS.as_str().as_bytes())
+            })
+            .collect::<Vec<_>>();
+
+        if options.is_empty() {
+            connection.remove();
+        } else {
+            connection.insert(
+                bstr::join(b", ", options)
+                    .try_into()
+                    .expect("Failed to join connection options"),
+            );
+        }
+    }
+}
+
+/// Whether a request can be forwarded without rewriting it, because it is an HTTP/1.1 request
+/// that does not open a tunnel or upgrade its connection.
+fn is_passthrough<T>(req: &Request<T>) -> bool {
+    req.version() == hyper::Version::HTTP_11
+        && req.method() != Method::CONNECT
+        && !req.headers().contains_key(hyper::header::UPGRADE)
+}
+
 #[instrument(skip_all)]
 fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
     // Hyper will automatically add a Host header if needed.
     req.headers_mut().remove(hyper::header::HOST);
 
+    strip_h2c_upgrade(&mut req);
+
     // HTTP/2 supports multiple cookie headers, but HTTP/1.x only supports one.
     if let Entry::Occupied(mut cookies) = req.headers_mut().entry(hyper::header::COOKIE) {
         let joined_cookies = bstr::join(b"; ", cookies.iter());
@@ -411,12 +1837,53 @@ mod tests {
     fn build_proxy() -> InternalProxy<HttpConnector, CA, crate::NoopHandler, crate::NoopHandler> {
         InternalProxy {
             ca: Arc::new(CA),
-            client: Client::builder(TokioExecutor::new()).build(HttpConnector::new()),
+            clients: Clients::new(
+                Client::builder(TokioExecutor::new()).build(HttpConnector::new()),
+            ),
             server: server::conn::auto::Builder::new(TokioExecutor::new()),
             http_handler: crate::NoopHandler::new(),
             websocket_handler: crate::NoopHandler::new(),
             websocket_connector: None,
+            options: Arc::new(Options::default()),
             client_addr: "127.0.0.1:8080".parse().unwrap(),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            tunnel_id: None,
+            client_certificate: None,
+            #[cfg(feature = "fingerprint")]
+            tls_fingerprint: None,
+            connect_target: None,
+            tunnel_authority: None,
+            #[cfg(feature = "admin")]
+            connection: None,
+        }
+    }
+
+    mod is_passthrough {
+        use super::*;
+
+        #[test]
+        fn forwards_http1_requests() {
+            let req = Request::get("http://example.com/").body(()).unwrap();
+            assert!(is_passthrough(&req));
+        }
+
+        #[test]
+        fn handles_tunnels_upgrades_and_other_versions() {
+            let connect = Request::connect("example.com:443").body(()).unwrap();
+            let upgrade = Request::get("http://example.com/")
+                .header(hyper::header::UPGRADE, "websocket")
+                .body(())
+                .unwrap();
+            let http2 = Request::get("https://example.com/")
+                .version(hyper::Version::HTTP_2)
+                .body(())
+                .unwrap();
+
+            assert!(!is_passthrough(&connect));
+            assert!(!is_passthrough(&upgrade));
+            assert!(!is_passthrough(&http2));
         }
     }
 
@@ -430,6 +1897,228 @@ mod tests {
         }
     }
 
+    mod merge_early_hints {
+        use super::*;
+
+        fn early_hints(links: &[&'static str]) -> Response<()> {
+            let mut res = Response::builder().status(StatusCode::EARLY_HINTS);
+            for link in links {
+                res = res.header(hyper::header::LINK, *link);
+            }
+            res.body(()).unwrap()
+        }
+
+        #[test]
+        fn adds_links() {
+            let mut res = Response::builder()
+                .header(hyper::header::LINK, "</a.css>; rel=preload")
+                .body(())
+                .unwrap();
+
+            merge_early_hints(
+                &mut res,
+                &early_hints(&["</a.css>; rel=preload", "</b.js>; rel=preload"]),
+            );
+
+            assert_eq!(
+                res.headers()
+                    .get_all(hyper::header::LINK)
+                    .iter()
+                    .collect::<Vec<_>>(),
+                vec!["</a.css>; rel=preload", "</b.js>; rel=preload"]
+            );
+        }
+
+        #[test]
+        fn ignores_other_statuses() {
+            let mut res = Response::new(());
+            let mut interim = early_hints(&["</a.css>; rel=preload"]);
+            *interim.status_mut() = StatusCode::PROCESSING;
+
+            merge_early_hints(&mut res, &interim);
+
+            assert!(!res.headers().contains_key(hyper::header::LINK));
+        }
+    }
+
+    mod is_retryable {
+        use super::*;
+
+        #[test]
+        fn safe_methods() {
+            let req = Request::builder()
+                .method(Method::HEAD)
+                .body(Body::from(Empty::new()))
+                .unwrap();
+
+            assert!(is_retryable(&req));
+        }
+
+        #[test]
+        fn marked_idempotent() {
+            let mut req = Request::builder()
+                .method(Method::DELETE)
+                .body(Body::from(Empty::new()))
+                .unwrap();
+
+            assert!(!is_retryable(&req));
+
+            req.extensions_mut().insert(Idempotent);
+            assert!(is_retryable(&req));
+        }
+
+        #[test]
+        fn with_body() {
+            let req = Request::builder()
+                .method(Method::GET)
+                .body(Body::from("body"))
+                .unwrap();
+
+            assert!(!is_retryable(&req));
+        }
+    }
+
+    mod retry_policy {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn exponential_backoff() {
+            let policy = RetryPolicy::new(5)
+                .with_backoff(Duration::from_millis(100), Duration::from_millis(300));
+
+            assert_eq!(policy.backoff(1), Duration::from_millis(100));
+            assert_eq!(policy.backoff(2), Duration::from_millis(200));
+            assert_eq!(policy.backoff(3), Duration::from_millis(300));
+            assert_eq!(policy.backoff(40), Duration::from_millis(300));
+        }
+
+        #[test]
+        fn retries_statuses() {
+            let policy = RetryPolicy::new(2).with_statuses([StatusCode::SERVICE_UNAVAILABLE]);
+            let res = |status| Ok(Response::builder().status(status).body(()).unwrap());
+
+            assert!(policy.should_retry(&res(StatusCode::SERVICE_UNAVAILABLE)));
+            assert!(!policy.should_retry(&res(StatusCode::BAD_GATEWAY)));
+        }
+    }
+
+    mod resolve_location {
+        use super::*;
+
+        #[test]
+        fn resolves_relative_locations() {
+            let base = Uri::from_static("https://example.com/a/b?c=d");
+
+            for (location, expected) in [
+                ("http://example.org/x", "http://example.org/x"),
+                ("//example.org/x", "https://example.org/x"),
+                ("/x?y=z", "https://example.com/x?y=z"),
+                ("x#fragment", "https://example.com/a/x"),
+            ] {
+                assert_eq!(resolve_location(&base, location).unwrap(), expected);
+            }
+        }
+    }
+
+    mod redirect {
+        use super::*;
+
+        fn response(status: StatusCode, location: &'static str) -> Response<()> {
+            Response::builder()
+                .status(status)
+                .header(LOCATION, location)
+                .body(())
+                .unwrap()
+        }
+
+        #[test]
+        fn changes_post_to_get() {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("http://example.com/form")
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(())
+                .unwrap();
+
+            let next = redirect(&req, &response(StatusCode::SEE_OTHER, "/done"), true).unwrap();
+
+            assert_eq!(next.method(), Method::GET);
+            assert_eq!(next.uri(), "http://example.com/done");
+            assert!(!next.headers().contains_key(CONTENT_TYPE));
+        }
+
+        #[test]
+        fn does_not_resend_bodies() {
+            let req = Request::builder()
+                .method(Method::PUT)
+                .uri("http://example.com/")
+                .body(())
+                .unwrap();
+
+            let res = response(StatusCode::TEMPORARY_REDIRECT, "/other");
+            assert!(redirect(&req, &res, true).is_none());
+            assert_eq!(redirect(&req, &res, false).unwrap().method(), Method::PUT);
+        }
+
+        #[test]
+        fn strips_credentials_across_origins() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .header(AUTHORIZATION, "Bearer secret")
+                .header(COOKIE, "id=1")
+                .header(hyper::header::HOST, "example.com")
+                .body(())
+                .unwrap();
+
+            let same = redirect(&req, &response(StatusCode::FOUND, "/other"), false).unwrap();
+            assert!(same.headers().contains_key(AUTHORIZATION));
+            assert!(same.headers().contains_key(hyper::header::HOST));
+
+            let other = redirect(
+                &req,
+                &response(StatusCode::FOUND, "http://example.org/"),
+                false,
+            )
+            .unwrap();
+            assert!(!other.headers().contains_key(AUTHORIZATION));
+            assert!(!other.headers().contains_key(COOKIE));
+            assert!(!other.headers().contains_key(hyper::header::HOST));
+        }
+
+        #[test]
+        fn ignores_other_responses() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .body(())
+                .unwrap();
+
+            assert!(redirect(&req, &response(StatusCode::OK, "/other"), false).is_none());
+            assert!(redirect(&req, &response(StatusCode::NOT_MODIFIED, "/other"), false).is_none());
+        }
+    }
+
+    mod expects_continue {
+        use super::*;
+
+        #[test]
+        fn with_header() {
+            let req = Request::builder()
+                .header(hyper::header::EXPECT, "100-Continue")
+                .body(())
+                .unwrap();
+
+            assert!(expects_continue(&req));
+        }
+
+        #[test]
+        fn without_header() {
+            let req = Request::builder().body(()).unwrap();
+
+            assert!(!expects_continue(&req));
+        }
+    }
+
     mod normalize_request {
         use super::*;
 
@@ -467,6 +2156,129 @@ mod tests {
                 Some(&"foo=bar; baz=qux".parse().unwrap())
             );
         }
+
+        #[test]
+        fn strips_h2c_upgrade() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .header(
+                    hyper::header::CONNECTION,
+                    "Upgrade, HTTP2-Settings, keep-alive",
+                )
+                .header(hyper::header::UPGRADE, "h2c")
+                .header(HTTP2_SETTINGS, "AAMAAABkAARAAAAAAAIAAAAA")
+                .body(())
+                .unwrap();
+
+            let req = normalize_request(req);
+
+            assert_eq!(req.headers().get(hyper::header::UPGRADE), None);
+            assert_eq!(req.headers().get(HTTP2_SETTINGS), None);
+            assert_eq!(
+                req.headers().get(hyper::header::CONNECTION),
+                Some(&"keep-alive".parse().unwrap())
+            );
+        }
+
+        #[test]
+        fn keeps_other_upgrades() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .header(hyper::header::CONNECTION, "Upgrade")
+                .header(hyper::header::UPGRADE, "foo/1")
+                .body(())
+                .unwrap();
+
+            let req = normalize_request(req);
+
+            assert_eq!(
+                req.headers().get(hyper::header::UPGRADE),
+                Some(&"foo/1".parse().unwrap())
+            );
+            assert_eq!(
+                req.headers().get(hyper::header::CONNECTION),
+                Some(&"Upgrade".parse().unwrap())
+            );
+        }
+    }
+
+    mod client {
+        use super::*;
+
+        fn build_proxy_with_http1_client(
+        ) -> InternalProxy<HttpConnector, CA, crate::NoopHandler, crate::NoopHandler> {
+            let mut proxy = build_proxy();
+            proxy.clients.http1 =
+                Some(Client::builder(TokioExecutor::new()).build(HttpConnector::new()));
+            proxy.options = Arc::new(Options {
+                upstream_protocols: [("example.com".to_owned(), UpstreamProtocol::Http1)].into(),
+                ..Default::default()
+            });
+            proxy
+        }
+
+        #[test]
+        fn uses_auto_client_by_default() {
+            let proxy = build_proxy_with_http1_client();
+            let req = Request::get("http://example.org/").body(()).unwrap();
+
+            assert!(std::ptr::eq(proxy.client(&req), &proxy.clients.auto));
+        }
+
+        #[test]
+        fn uses_host_protocol() {
+            let proxy = build_proxy_with_http1_client();
+            let req = Request::get("http://EXAMPLE.com/").body(()).unwrap();
+
+            assert!(std::ptr::eq(
+                proxy.client(&req),
+                proxy.clients.http1.as_ref().unwrap()
+            ));
+        }
+
+        #[test]
+        fn extension_overrides_host_protocol() {
+            let proxy = build_proxy_with_http1_client();
+            let mut req = Request::get("http://example.com/").body(()).unwrap();
+            req.extensions_mut().insert(UpstreamProtocol::Auto);
+
+            assert!(std::ptr::eq(proxy.client(&req), &proxy.clients.auto));
+        }
+
+        #[test]
+        fn falls_back_to_auto_client() {
+            let proxy = build_proxy();
+            let mut req = Request::get("http://example.com/").body(()).unwrap();
+            req.extensions_mut().insert(UpstreamProtocol::Http1);
+
+            assert!(std::ptr::eq(proxy.client(&req), &proxy.clients.auto));
+        }
+    }
+
+    mod alpn_protocols {
+        use super::*;
+
+        #[tokio::test]
+        async fn uses_host_policy() {
+            let mut proxy = build_proxy();
+            proxy.options = Arc::new(Options {
+                alpn_policies: [("example.com".to_owned(), AlpnPolicy::Http1)].into(),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                proxy
+                    .alpn_protocols(&Authority::from_static("EXAMPLE.com:443"))
+                    .await,
+                Some(vec![b"http/1.1".to_vec()])
+            );
+            assert_eq!(
+                proxy
+                    .alpn_protocols(&Authority::from_static("example.org:443"))
+                    .await,
+                None
+            );
+        }
     }
 
     mod process_connect {
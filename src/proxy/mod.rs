@@ -1,25 +1,452 @@
+mod accept;
+#[cfg(feature = "rustls-client")]
+mod alpn;
+mod circuit_breaker;
+mod client_auth;
+mod connections;
+mod detect;
+mod dns_cache;
+mod dscp;
+mod happy_eyeballs;
+mod interception_cache;
 mod internal;
+#[cfg(feature = "rustls-client")]
+mod presets;
+mod resolve;
+mod sni;
+mod tls_failure;
+mod tunnel;
+#[cfg(feature = "rustls-client")]
+mod upstream;
 
 pub mod builder;
 
 use crate::{
-    certificate_authority::CertificateAuthority, Body, Error, HttpHandler, WebSocketHandler,
+    access_log::AccessLog, certificate_authority::CertificateAuthority, Body, Error, HttpHandler,
+    WebSocketHandler,
 };
+use accept::Acceptor;
 use builder::{AddrOrListener, WantsAddr};
-use hyper::service::service_fn;
+use happy_eyeballs::LocalBind;
+use hyper::StatusCode;
 use hyper_util::{
     client::legacy::{connect::Connect, Client},
-    rt::{TokioExecutor, TokioIo},
-    server::conn::auto::{self, Builder},
+    rt::TokioExecutor,
+    server::conn::auto::Builder,
 };
 use internal::InternalProxy;
-use std::{future::Future, sync::Arc};
-use tokio::net::TcpListener;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio_graceful::Shutdown;
-use tokio_tungstenite::Connector;
-use tracing::error;
+use tokio_tungstenite::{tungstenite::protocol::WebSocketConfig, Connector};
+use tracing::debug;
 
 pub use builder::ProxyBuilder;
+pub use circuit_breaker::CircuitBreaker;
+pub use client_auth::{ClientAuth, ClientCertificate};
+pub use connections::{FreshConnection, UpstreamConnection};
+pub use detect::ProtocolDetection;
+pub use dns_cache::{DnsCache, DnsCacheBuilder, Lookup, Resolved};
+pub use dscp::Dscp;
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "rustls-client", feature = "native-tls-client")))
+)]
+pub use happy_eyeballs::UpstreamConnector;
+pub use interception_cache::InterceptionCache;
+pub use resolve::ConnectTo;
+pub use sni::SniRoute;
+pub use tls_failure::{PassthroughList, TlsFailure, TlsFailureKind, TlsFailureSide};
+#[cfg(feature = "rustls-client")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+pub use upstream::UpstreamProxy;
+
+/// The HTTP version to use when forwarding requests to an upstream server.
+///
+/// A protocol can be configured for a host with [`ProxyBuilder`], or for a single request by
+/// inserting it into the request's extensions in [`HttpHandler::handle_request`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum UpstreamProtocol {
+    /// Use the version negotiated with ALPN, or HTTP/1.1 if the connection does not use TLS.
+    #[default]
+    Auto,
+    /// Always use HTTP/1.1.
+    Http1,
+    /// Always use HTTP/2, with prior knowledge if the connection does not use TLS (h2c).
+    ///
+    /// Upstream HTTP/2 connections are opened with server push disabled, so upstream servers
+    /// never push resources to the proxy and no pushes are lost. Clients request the resources
+    /// themselves. The `Link` headers of `103 Early Hints` responses are merged into the final
+    /// response, where clients can find the resources to request.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    Http2,
+}
+
+/// The protocols that are offered with ALPN to clients whose tunnels to a host are intercepted.
+///
+/// A policy can be configured for a host with [`ProxyBuilder::with_alpn_policy`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum AlpnPolicy {
+    /// Offer the protocols of the certificate authority's server config, which are HTTP/2 and
+    /// HTTP/1.1 for the built-in authorities if the `http2` feature is enabled.
+    #[default]
+    Auto,
+    /// Only offer HTTP/1.1.
+    Http1,
+    /// Offer HTTP/2 and HTTP/1.1.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    Http2,
+    /// Offer the protocols that the upstream server supports: HTTP/2 and HTTP/1.1 if it negotiates
+    /// HTTP/2 with the proxy, or only HTTP/1.1 otherwise.
+    ///
+    /// The upstream server is probed with a TLS handshake before the first tunnel to it is
+    /// intercepted, and the result is remembered. If the probe fails, [`AlpnPolicy::Auto`] is
+    /// used.
+    #[cfg(feature = "rustls-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    Upstream,
+}
+
+/// How requests with an `Expect: 100-continue` header are handled.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ExpectContinue {
+    /// Forward the expectation to the upstream server.
+    #[default]
+    Forward,
+    /// Answer the expectation with a `100 Continue` response from the proxy, and remove the
+    /// `Expect` header from the request that is sent to the upstream server.
+    Local,
+}
+
+/// What to do when a body exceeds its size limit.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BodyLimitAction {
+    /// Reject the request or response.
+    ///
+    /// Requests are rejected with a `413 Payload Too Large` response, and responses are replaced
+    /// with a `502 Bad Gateway` response. If the size of the body is not known in advance, the body
+    /// will yield [`Error::BodyTooLarge`] once the limit is exceeded.
+    #[default]
+    Reject,
+    /// Truncate the body to the size limit.
+    Truncate,
+}
+
+/// Whether the proxy follows redirects from upstream servers.
+///
+/// A policy can be configured for all requests with [`ProxyBuilder`], or for a single request by
+/// inserting it into the request's extensions in [`HttpHandler::handle_request`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum RedirectPolicy {
+    /// Return redirects to the client.
+    #[default]
+    None,
+    /// Follow up to the given number of redirects, and return the final response to the client.
+    ///
+    /// The last redirect is returned to the client instead if following it would revisit a URI,
+    /// exceed the limit, or require a request body to be sent again for a `307` or `308` redirect.
+    Follow(usize),
+}
+
+/// The URIs that were requested before a redirect was followed.
+///
+/// When redirects are followed, this is inserted into the extensions of the final response before
+/// it is passed to [`HttpHandler::handle_response`]. The URI of the original request comes first.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RedirectChain {
+    uris: Vec<hyper::Uri>,
+}
+
+impl RedirectChain {
+    /// The URIs that were redirected from, in the order that they were requested.
+    pub fn uris(&self) -> &[hyper::Uri] {
+        &self.uris
+    }
+}
+
+/// When requests that fail upstream are sent again.
+///
+/// Only `GET` and `HEAD` requests, and requests with an [`Idempotent`] extension, are retried.
+/// Requests with a body are never retried, since the body has already been streamed to the
+/// upstream server.
+///
+/// A policy can be configured for all requests with [`ProxyBuilder`], or for a single request by
+/// inserting it into the request's extensions in [`HttpHandler::handle_request`].
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{hyper::StatusCode, RetryPolicy};
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::new(3)
+///     .with_backoff(Duration::from_millis(50), Duration::from_secs(1))
+///     .with_statuses([StatusCode::SERVICE_UNAVAILABLE]);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_connect_errors: bool,
+    statuses: Vec<StatusCode>,
+}
+
+impl RetryPolicy {
+    /// Creates a new retry policy that sends a request at most `max_attempts` times, including the
+    /// first attempt.
+    ///
+    /// By default, requests are retried after connection errors and `502 Bad Gateway`,
+    /// `503 Service Unavailable`, and `504 Gateway Timeout` responses, with an exponential backoff
+    /// that starts at 100ms and is capped at 2s.
+    pub fn new(max_attempts: usize) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_connect_errors: true,
+            statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+        }
+    }
+
+    /// Set the delay before the first retry, and the maximum delay between retries. The delay is
+    /// doubled after each retry.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Set whether requests are retried after failing to connect to the upstream server.
+    pub fn with_connect_error_retries(mut self, retry: bool) -> Self {
+        self.retry_connect_errors = retry;
+        self
+    }
+
+    /// Set the response statuses that cause a request to be retried.
+    pub fn with_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.statuses = statuses.into_iter().collect();
+        self
+    }
+
+    /// The delay before a retry, where `retry` is 1 for the first retry.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    pub(crate) fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    pub(crate) fn should_retry<T>(
+        &self,
+        res: &Result<hyper::Response<T>, hyper_util::client::legacy::Error>,
+    ) -> bool {
+        match res {
+            Ok(res) => self.statuses.contains(&res.status()),
+            Err(err) => self.retry_connect_errors && err.is_connect(),
+        }
+    }
+}
+
+/// Marks a request as idempotent, allowing it to be retried by a [`RetryPolicy`].
+///
+/// Insert this into the extensions of a request in [`HttpHandler::handle_request`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Idempotent;
+
+/// Builds a copy of the built-in clients whose connector binds its sockets with a [`LocalBind`],
+/// and connects to an address instead of the hosts of requests if one is given.
+pub(crate) type Rebind<C> = Arc<dyn Fn(LocalBind, Option<SocketAddr>) -> Clients<C> + Send + Sync>;
+
+/// The pinned address and DSCP of rebuilt clients.
+type RebindKey = (Option<SocketAddr>, Option<Dscp>);
+
+/// The clients used to forward requests, one for each [`UpstreamProtocol`].
+#[derive(Clone)]
+pub(crate) struct Clients<C> {
+    pub auto: Client<C, Body>,
+    pub http1: Option<Client<C, Body>>,
+    #[cfg(feature = "http2")]
+    pub http2: Option<Client<C, Body>>,
+    /// Set for the built-in clients, which can be rebuilt with a marked or pinned connector.
+    pub rebind: Option<Rebind<C>>,
+    /// The rebuilt clients, by pinned address and DSCP.
+    rebound: Arc<Mutex<HashMap<RebindKey, Clients<C>>>>,
+}
+
+impl<C> Clients<C> {
+    pub(crate) fn new(auto: Client<C, Body>) -> Self {
+        Self {
+            auto,
+            http1: None,
+            #[cfg(feature = "http2")]
+            http2: None,
+            rebind: None,
+            rebound: Default::default(),
+        }
+    }
+
+    /// Get the client for a protocol, falling back to the default client if none is configured.
+    pub(crate) fn get(&self, protocol: UpstreamProtocol) -> &Client<C, Body> {
+        let client = match protocol {
+            UpstreamProtocol::Auto => None,
+            UpstreamProtocol::Http1 => self.http1.as_ref(),
+            #[cfg(feature = "http2")]
+            UpstreamProtocol::Http2 => self.http2.as_ref(),
+        };
+
+        client.unwrap_or(&self.auto)
+    }
+
+    /// The clients whose sockets are bound with `bind` and that connect to `addr`, or `None` if
+    /// the clients are custom and cannot be rebuilt.
+    ///
+    /// The rebuilt clients are kept, so that their connections are pooled separately from the
+    /// connections of the configured clients.
+    pub(crate) fn rebound(&self, bind: &LocalBind, addr: Option<SocketAddr>) -> Option<Self>
+    where
+        C: Clone,
+    {
+        let rebind = self.rebind.as_ref()?;
+
+        Some(
+            self.rebound
+                .lock()
+                .expect("Failed to lock rebound clients")
+                .entry((addr, bind.dscp))
+                .or_insert_with(|| rebind(bind.clone(), addr))
+                .clone(),
+        )
+    }
+}
+
+impl<C> fmt::Debug for Clients<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clients")
+            .field("rebindable", &self.rebind.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Options that are shared by every connection served by a proxy.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    pub upstream_protocols: HashMap<String, UpstreamProtocol>,
+    pub connections: connections::ConnectionTracker,
+    pub alpn_policies: HashMap<String, AlpnPolicy>,
+    pub local_bind: happy_eyeballs::LocalBind,
+    pub dscp: HashMap<String, Dscp>,
+    #[cfg(feature = "rustls-client")]
+    pub alpn_probe: alpn::AlpnProbe,
+    pub client_auth: Option<ClientAuth>,
+    pub expect_continue: ExpectContinue,
+    pub max_request_body_size: Option<usize>,
+    pub max_response_body_size: Option<usize>,
+    pub body_limit_action: BodyLimitAction,
+    pub redirect_policy: RedirectPolicy,
+    pub retry_policy: Option<RetryPolicy>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub interception_cache: Option<InterceptionCache>,
+    pub auto_passthrough: Option<PassthroughList>,
+    pub access_log: Option<AccessLog>,
+    pub websocket_config: Option<WebSocketConfig>,
+    pub websocket_buffer: Option<usize>,
+    pub tunnel: tunnel::TunnelOptions,
+    pub buffer_pool: crate::BufferPool,
+    pub accept_loops: usize,
+    pub certificate_prefetch: bool,
+    /// Forward HTTP/1.1 requests without invoking the handlers, which are no-ops.
+    pub passthrough: bool,
+    pub protocol_detection: ProtocolDetection,
+    pub header_read_timeout: Option<Duration>,
+    pub sni_routes: sni::SniRoutes,
+    #[cfg(feature = "audit")]
+    pub audit_log: Option<crate::audit::AuditLog>,
+    #[cfg(feature = "blocklist")]
+    pub blocklist: Option<crate::blocklist::Blocklist>,
+    #[cfg(feature = "decoder")]
+    pub recompression: Option<crate::CompressionLevel>,
+    #[cfg(feature = "dns")]
+    pub dns_tls_config: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+    #[cfg(feature = "admin")]
+    pub admin: Option<crate::admin::Admin>,
+    #[cfg(feature = "events")]
+    pub events: Option<crate::events::Events>,
+    #[cfg(all(feature = "handoff", unix))]
+    pub handoff: Option<crate::handoff::Handoff>,
+    #[cfg(feature = "rustls-client")]
+    pub upstream_proxies: upstream::UpstreamProxies,
+    pub resolver: resolve::Resolver,
+}
+
+impl Options {
+    /// Whether requests can be forwarded without going through the handlers, as nothing else
+    /// needs to see or modify them.
+    fn allows_passthrough(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut allows = self.max_request_body_size.is_none()
+            && self.max_response_body_size.is_none()
+            && self.expect_continue == ExpectContinue::Forward
+            && self.redirect_policy == RedirectPolicy::None
+            && self.retry_policy.is_none()
+            && self.circuit_breaker.is_none()
+            && self.access_log.is_none();
+
+        #[cfg(feature = "audit")]
+        {
+            allows &= self.audit_log.is_none();
+        }
+        #[cfg(feature = "blocklist")]
+        {
+            allows &= self.blocklist.is_none();
+        }
+        #[cfg(feature = "decoder")]
+        {
+            allows &= self.recompression.is_none();
+        }
+        #[cfg(feature = "admin")]
+        {
+            allows &= self.admin.is_none();
+        }
+        #[cfg(feature = "events")]
+        {
+            allows &= self.events.is_none();
+        }
+
+        allows
+    }
+
+    /// Resolves once the proxy should stop accepting connections because it is being drained.
+    async fn drained(&self) {
+        #[cfg(all(feature = "handoff", unix))]
+        if let Some(handoff) = &self.handoff {
+            return handoff.drained().await;
+        }
+
+        std::future::pending().await
+    }
+}
 
 /// A proxy server. This must be constructed with a [`ProxyBuilder`].
 ///
@@ -71,11 +498,12 @@ pub use builder::ProxyBuilder;
 pub struct Proxy<C, CA, H, W, F> {
     al: AddrOrListener,
     ca: Arc<CA>,
-    client: Client<C, Body>,
+    clients: Clients<C>,
     http_handler: H,
     websocket_handler: W,
     websocket_connector: Option<Connector>,
-    server: Option<Builder<TokioExecutor>>,
+    server: Builder<TokioExecutor>,
+    options: Arc<Options>,
     graceful_shutdown: F,
 }
 
@@ -100,77 +528,77 @@ where
     ///
     /// This will return an error if the proxy server is unable to be started.
     pub async fn start(self) -> Result<(), Error> {
-        let server = self.server.unwrap_or_else(|| {
-            let mut builder = auto::Builder::new(TokioExecutor::new());
-            builder
-                .http1()
-                .title_case_headers(true)
-                .preserve_header_case(true);
-            builder
-        });
-
-        let listener = match self.al {
-            AddrOrListener::Addr(addr) => TcpListener::bind(addr).await?,
-            AddrOrListener::Listener(listener) => listener,
+        let loops = self.options.accept_loops.max(1);
+        let listeners = match self.al {
+            AddrOrListener::Addr(addr) => accept::bind(addr, loops).await?,
+            AddrOrListener::Listener(listener) => vec![listener],
+            #[cfg(all(feature = "handoff", unix))]
+            AddrOrListener::Inherited(addr) => match crate::handoff::inherited_listener()? {
+                Some(listener) => vec![tokio::net::TcpListener::from_std(listener)?],
+                None => vec![tokio::net::TcpListener::bind(addr).await?],
+            },
         };
 
-        let shutdown = Shutdown::new(self.graceful_shutdown);
-        let guard = shutdown.guard_weak();
+        #[cfg(all(feature = "handoff", unix))]
+        if let Some(handoff) = &self.options.handoff {
+            use std::os::fd::AsFd;
+            handoff.set_listener(Some(listeners[0].as_fd().try_clone_to_owned()?));
+        }
 
-        loop {
+        let options = Arc::clone(&self.options);
+        let graceful_shutdown = self.graceful_shutdown;
+        let shutdown = Shutdown::new(async move {
             tokio::select! {
-                res = listener.accept() => {
-                    let (tcp, client_addr) = match res {
-                        Ok((tcp, client_addr)) => (tcp, client_addr),
-                        Err(e) => {
-                            error!("Failed to accept incoming connection: {}", e);
-                            continue;
-                        }
-                    };
-
-                    let server = server.clone();
-                    let client = self.client.clone();
-                    let ca = Arc::clone(&self.ca);
-                    let http_handler = self.http_handler.clone();
-                    let websocket_handler = self.websocket_handler.clone();
-                    let websocket_connector = self.websocket_connector.clone();
-
-                    shutdown.spawn_task_fn(move |guard| async move {
-                        let conn = server.serve_connection_with_upgrades(
-                            TokioIo::new(tcp),
-                            service_fn(|req| {
-                                InternalProxy {
-                                    ca: Arc::clone(&ca),
-                                    client: client.clone(),
-                                    server: server.clone(),
-                                    http_handler: http_handler.clone(),
-                                    websocket_handler: websocket_handler.clone(),
-                                    websocket_connector: websocket_connector.clone(),
-                                    client_addr,
-                                }
-                                .proxy(req)
-                            }),
-                        );
-
-                        let mut conn = std::pin::pin!(conn);
-
-                        if let Err(err) = tokio::select! {
-                            conn = conn.as_mut() => conn,
-                            _ = guard.cancelled() => {
-                                conn.as_mut().graceful_shutdown();
-                                conn.await
-                            }
-                        } {
-                            error!("Error serving connection: {}", err);
-                        }
-                    });
-                }
-                _ = guard.cancelled() => {
-                    break;
+                _ = graceful_shutdown => {}
+                _ = options.drained() => debug!("Draining proxy"),
+            }
+        });
+        let guard = shutdown.guard_weak();
+
+        #[cfg(feature = "admin")]
+        if let Some(admin) = &self.options.admin {
+            let listener = match admin.take_listener() {
+                Some(crate::admin::AdminListener::Addr(addr)) => {
+                    Some(tokio::net::TcpListener::bind(addr).await?)
                 }
+                Some(crate::admin::AdminListener::Listener(listener)) => Some(listener),
+                None => None,
+            };
+
+            if let Some(listener) = listener {
+                let admin = admin.clone();
+                shutdown.spawn_task_fn(move |guard| admin.serve(listener, guard));
             }
         }
 
+        let mut listeners: Vec<_> = listeners.into_iter().map(Arc::new).collect();
+        let acceptor = Acceptor {
+            ca: self.ca,
+            clients: self.clients,
+            http_handler: self.http_handler,
+            websocket_handler: self.websocket_handler,
+            websocket_connector: self.websocket_connector,
+            server: self.server,
+            options: self.options,
+        };
+
+        // Loops without a listener of their own share the first one.
+        listeners.resize(loops, Arc::clone(&listeners[0]));
+
+        for listener in listeners.drain(1..) {
+            let acceptor = acceptor.clone();
+            let guard = shutdown.guard_weak();
+            tokio::spawn(async move { acceptor.accept(&listener, guard).await });
+        }
+
+        acceptor.accept(&listeners[0], guard).await;
+
+        #[cfg(all(feature = "handoff", unix))]
+        if let Some(handoff) = &acceptor.options.handoff {
+            handoff.set_listener(None);
+        }
+
+        drop(listeners);
         shutdown.shutdown().await;
 
         Ok(())
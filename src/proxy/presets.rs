@@ -0,0 +1,209 @@
+use super::{
+    builder::{ProxyBuilder, WantsHandlers},
+    DnsCache, Proxy, UpstreamConnector,
+};
+use crate::{
+    balancer::{LoadBalancer, Route},
+    certificate_authority::CertificateAuthority,
+    Body, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use http_body_util::Empty;
+use hyper::{http::uri::Authority, Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use std::{
+    future::Pending,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+/// The address that presets without an address listen on.
+const DEFAULT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080));
+
+/// The connector of the clients that presets use.
+type Connector = HttpsConnector<UpstreamConnector>;
+
+/// A builder created by a preset, which can be configured further before it is built.
+type Preset<CA, H> = ProxyBuilder<WantsHandlers<Connector, CA, H, NoopHandler, Pending<()>>>;
+
+impl Proxy<(), (), (), (), ()> {
+    /// Create a builder for a proxy that intercepts all traffic and records the responses to a
+    /// [`Cassette`](crate::vcr::Cassette) at `path`, which can later be replayed with a
+    /// [`VcrHandler`](crate::vcr::VcrHandler).
+    ///
+    /// The proxy listens on `127.0.0.1:8080`, which can be changed with
+    /// [`ProxyBuilder::with_addr`], and uses a rustls client. Clients must trust `ca`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use hudsucker::{certificate_authority::RcgenAuthority, Proxy};
+    ///
+    /// # async fn example(ca: RcgenAuthority) {
+    /// let proxy = Proxy::recording_proxy(ca, "cassette.vcr").build();
+    /// proxy.start().await.unwrap();
+    /// # }
+    /// ```
+    #[cfg(feature = "vcr")]
+    #[cfg_attr(docsrs, doc(cfg(all(feature = "rustls-client", feature = "vcr"))))]
+    pub fn recording_proxy<CA: CertificateAuthority>(
+        ca: CA,
+        path: impl Into<std::path::PathBuf>,
+    ) -> Preset<CA, crate::vcr::VcrHandler> {
+        use crate::vcr::{Cassette, Mode, VcrHandler};
+
+        Proxy::builder()
+            .with_addr(DEFAULT_ADDR)
+            .with_rustls_client()
+            .with_ca(ca)
+            .with_http_handler(VcrHandler::new(Cassette::new(path), Mode::Record))
+    }
+
+    /// Create a builder for a forward proxy that listens on `addr` and forwards traffic without
+    /// intercepting it, such as an egress gateway.
+    ///
+    /// HTTP requests are forwarded unchanged and CONNECT requests are tunneled, so clients do
+    /// not need to trust a CA. Upstream hosts are resolved with a [`DnsCache`].
+    ///
+    /// The proxy does not have a CA, so a handler that is set on the builder must not intercept
+    /// CONNECT requests, as the TLS handshakes of intercepted tunnels fail.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use hudsucker::Proxy;
+    /// use std::net::SocketAddr;
+    ///
+    /// # async fn example() {
+    /// let proxy = Proxy::passthrough_gateway(SocketAddr::from(([0, 0, 0, 0], 3128))).build();
+    /// proxy.start().await.unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    pub fn passthrough_gateway(
+        addr: SocketAddr,
+    ) -> Preset<impl CertificateAuthority, impl HttpHandler> {
+        Proxy::builder()
+            .with_addr(addr)
+            .with_dns_cache(DnsCache::new())
+            .with_rustls_client()
+            .with_ca(NoCa::new())
+            .with_http_handler(Tunnel)
+    }
+
+    /// Create a builder for a reverse proxy that sends every request that it receives to
+    /// `origin`, such as `http://10.0.0.1:8080`.
+    ///
+    /// The scheme and authority of requests are replaced with those of the origin, and their
+    /// `Host` header with its authority. CONNECT requests are answered with `405 Method Not
+    /// Allowed`. For more than one origin, or for routing by host or path, see
+    /// [`LoadBalancer`].
+    ///
+    /// The proxy listens on `127.0.0.1:8080`, which can be changed with
+    /// [`ProxyBuilder::with_addr`], and uses a rustls client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `origin` does not have an authority.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use hudsucker::{hyper::Uri, Proxy};
+    /// use std::net::SocketAddr;
+    ///
+    /// # async fn example() {
+    /// let proxy = Proxy::reverse(Uri::from_static("http://10.0.0.1:8080"))
+    ///     .with_addr(SocketAddr::from(([0, 0, 0, 0], 80)))
+    ///     .build();
+    /// proxy.start().await.unwrap();
+    /// # }
+    /// ```
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    pub fn reverse(origin: Uri) -> Preset<impl CertificateAuthority, impl HttpHandler> {
+        assert!(
+            origin.authority().is_some(),
+            "origin must have an authority"
+        );
+
+        Proxy::builder()
+            .with_addr(DEFAULT_ADDR)
+            .with_rustls_client()
+            .with_ca(NoCa::new())
+            .with_http_handler(
+                LoadBalancer::new()
+                    .with_route(Route::new("*", [origin]))
+                    .with_handler(RejectConnect),
+            )
+    }
+}
+
+/// An HTTP handler that tunnels CONNECT requests instead of intercepting them.
+#[derive(Clone, Copy, Debug)]
+struct Tunnel;
+
+impl HttpHandler for Tunnel {
+    async fn should_intercept(&mut self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
+        false
+    }
+}
+
+/// An HTTP handler that responds to CONNECT requests with `405 Method Not Allowed`.
+#[derive(Clone, Copy, Debug)]
+struct RejectConnect;
+
+impl HttpHandler for RejectConnect {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> RequestOrResponse {
+        if req.method() != Method::CONNECT {
+            return req.into();
+        }
+
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Empty::new().into())
+            .expect("Failed to build response")
+            .into()
+    }
+
+    async fn should_intercept(&mut self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
+        false
+    }
+}
+
+/// A certificate authority for proxies that do not intercept TLS, which does not issue any
+/// certificates, so the handshakes of intercepted connections fail.
+#[derive(Debug)]
+struct NoCa(Arc<ServerConfig>);
+
+impl NoCa {
+    fn new() -> Self {
+        Self(Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(NoCertificates)),
+        ))
+    }
+}
+
+impl CertificateAuthority for NoCa {
+    async fn gen_server_config(&self, _authority: &Authority) -> Arc<ServerConfig> {
+        Arc::clone(&self.0)
+    }
+}
+
+#[derive(Debug)]
+struct NoCertificates;
+
+impl ResolvesServerCert for NoCertificates {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        None
+    }
+}
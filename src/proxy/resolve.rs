@@ -0,0 +1,96 @@
+use hyper::Uri;
+use std::{collections::HashMap, net::SocketAddr};
+
+/// An address that a request is sent to, instead of an address that its host resolves to.
+///
+/// Insert this into the extensions of a request in [`HttpHandler::handle_request`] to connect to
+/// the address, like curl's `--resolve` option. The request keeps its URI and `Host` header, and
+/// HTTPS requests send the host of the URI as their SNI and are verified against its certificate.
+/// Addresses can also be configured for a host with
+/// [`ProxyBuilder::with_resolve`](crate::ProxyBuilder::with_resolve).
+///
+/// Requests are only connected to the address when they are sent with the built-in rustls or
+/// native-tls clients, or open a WebSocket connection. Requests that are forwarded through an
+/// `UpstreamProxy` are not sent to the address.
+///
+/// [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::ConnectTo;
+///
+/// let connect_to = ConnectTo::new("203.0.113.7:443".parse().unwrap());
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectTo {
+    addr: SocketAddr,
+}
+
+impl ConnectTo {
+    /// Creates a new override that connects to an address.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr }
+    }
+
+    /// The address that is connected to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// The addresses that hosts are pinned to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Resolver {
+    /// The pinned addresses, by host and port.
+    overrides: HashMap<String, SocketAddr>,
+}
+
+impl Resolver {
+    /// Pin a host and port to an address.
+    pub(crate) fn insert(&mut self, host: &str, port: u16, addr: SocketAddr) {
+        self.overrides.insert(key(host, port), addr);
+    }
+
+    /// Whether no hosts are pinned.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.overrides.is_empty()
+    }
+
+    /// The address that a host and port are pinned to.
+    pub(crate) fn lookup(&self, host: &str, port: u16) -> Option<SocketAddr> {
+        if self.overrides.is_empty() {
+            return None;
+        }
+
+        self.overrides.get(&key(host, port)).copied()
+    }
+
+    /// The address that a request is sent to, from its [`ConnectTo`] extension or the pinned
+    /// address of its host.
+    pub(crate) fn resolve(&self, uri: &Uri, connect_to: Option<&ConnectTo>) -> Option<SocketAddr> {
+        if let Some(connect_to) = connect_to {
+            return Some(connect_to.addr);
+        }
+
+        let port = uri
+            .port_u16()
+            .unwrap_or(if matches!(uri.scheme_str(), Some("https" | "wss")) {
+                443
+            } else {
+                80
+            });
+
+        self.lookup(uri.host()?, port)
+    }
+}
+
+fn key(host: &str, port: u16) -> String {
+    format!(
+        "{}:{}",
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase(),
+        port
+    )
+}
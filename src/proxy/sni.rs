@@ -0,0 +1,103 @@
+use http::uri::Authority;
+use std::collections::HashMap;
+
+/// Where a TLS connection that is sent directly to the proxy is routed, by the server name of
+/// its ClientHello.
+///
+/// Routes are configured with [`ProxyBuilder::with_sni_route`], and apply to the TLS connections
+/// that are detected on the proxy's listener with [`ProtocolDetection::with_tls`]. Connections
+/// for server names without a route are intercepted.
+///
+/// Routed connections are still passed to [`HttpHandler::handle_request`] as a `CONNECT` request
+/// for their authority, which can deny them. A route can also be inserted into the extensions of
+/// a `CONNECT` request in [`HttpHandler::handle_request`] to override the configured one.
+///
+/// [`ProxyBuilder::with_sni_route`]: crate::builder::ProxyBuilder::with_sni_route
+/// [`ProtocolDetection::with_tls`]: crate::ProtocolDetection::with_tls
+/// [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::SniRoute;
+///
+/// let route = SniRoute::Backend("10.0.0.2:8443".parse().unwrap());
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SniRoute {
+    /// Intercept the connection unless [`HttpHandler::should_intercept`] returns `false`.
+    ///
+    /// [`HttpHandler::should_intercept`]: crate::HttpHandler::should_intercept
+    #[default]
+    Intercept,
+    /// Tunnel the connection to port 443 of the server that it names, without intercepting it.
+    Passthrough,
+    /// Tunnel the connection to a backend, without intercepting it.
+    Backend(Authority),
+}
+
+/// The routes of TLS connections, by server name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SniRoutes(HashMap<String, SniRoute>);
+
+impl SniRoutes {
+    /// Route the server names that match a pattern, which is a host name such as `example.com`
+    /// or a wildcard such as `*.example.com` that matches its subdomains.
+    pub(crate) fn insert(&mut self, pattern: impl Into<String>, route: SniRoute) {
+        let mut pattern = pattern.into();
+        pattern.make_ascii_lowercase();
+        self.0.insert(pattern, route);
+    }
+
+    /// The route of a server name, preferring its own route to the routes of the closest
+    /// wildcards that match it.
+    pub(crate) fn get(&self, server_name: &str) -> Option<&SniRoute> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let server_name = server_name.to_ascii_lowercase();
+
+        if let Some(route) = self.0.get(&server_name) {
+            return Some(route);
+        }
+
+        let mut parent = server_name.as_str();
+
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(route) = self.0.get(&format!("*.{}", rest)) {
+                return Some(route);
+            }
+
+            parent = rest;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        let mut routes = SniRoutes::default();
+        routes.insert("*.Example.com", SniRoute::Passthrough);
+        routes.insert("api.example.com", SniRoute::Intercept);
+        routes.insert(
+            "*.internal.example.com",
+            SniRoute::Backend("10.0.0.2:8443".parse().unwrap()),
+        );
+
+        assert_eq!(routes.get("API.example.com"), Some(&SniRoute::Intercept));
+        assert_eq!(routes.get("www.example.com"), Some(&SniRoute::Passthrough));
+        assert_eq!(
+            routes.get("db.internal.example.com"),
+            Some(&SniRoute::Backend("10.0.0.2:8443".parse().unwrap()))
+        );
+        assert_eq!(routes.get("example.com"), None);
+        assert_eq!(routes.get("example.org"), None);
+    }
+}
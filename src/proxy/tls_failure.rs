@@ -0,0 +1,299 @@
+use hyper::http::uri::Authority;
+use std::{
+    collections::HashSet,
+    error::Error as StdError,
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio_rustls::rustls::{AlertDescription, Error as TlsError};
+
+/// The side of an intercepted connection that a TLS handshake failed on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TlsFailureSide {
+    /// The handshake between the client and the proxy, which presents a certificate issued by
+    /// its certificate authority.
+    Client,
+    /// The handshake between the proxy and the upstream server.
+    Upstream,
+}
+
+/// Why a TLS handshake failed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum TlsFailureKind {
+    /// The peer rejected the certificate that it was presented, or required one that it was not
+    /// presented. Clients that pin certificates, or that do not trust the proxy's CA, reject the
+    /// proxy's certificates.
+    CertificateRejected,
+    /// The certificate of the peer could not be verified.
+    InvalidCertificate,
+    /// The peers could not agree on a protocol version, cipher suite, or application protocol.
+    ProtocolMismatch,
+    /// The connection was closed during the handshake.
+    ConnectionClosed,
+    /// Any other failure.
+    Other,
+}
+
+/// A diagnostic for a failed TLS handshake, which is passed to
+/// [`HttpHandler::handle_tls_failure`](crate::HttpHandler::handle_tls_failure).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TlsFailure {
+    /// The authority that the handshake was for.
+    pub authority: Authority,
+    /// The side of the connection that the handshake failed on.
+    pub side: TlsFailureSide,
+    /// Why the handshake failed.
+    pub kind: TlsFailureKind,
+    /// The error that the handshake failed with.
+    pub message: String,
+    /// Whether tunnels to the host would likely work if they were not intercepted, such as when
+    /// a client pins the certificates of the host. This is only suggested for failures on the
+    /// client side, as the handshake with the upstream server would fail the same way for the
+    /// client.
+    pub suggest_passthrough: bool,
+}
+
+impl TlsFailure {
+    fn new(
+        authority: Authority,
+        side: TlsFailureSide,
+        kind: TlsFailureKind,
+        message: String,
+    ) -> Self {
+        // Tunneling does not change the handshake with the upstream server, which the client
+        // would then make itself, so only clients rejecting the proxy's certificates are helped.
+        let suggest_passthrough =
+            side == TlsFailureSide::Client && kind == TlsFailureKind::CertificateRejected;
+
+        Self {
+            authority,
+            side,
+            kind,
+            message,
+            suggest_passthrough,
+        }
+    }
+
+    /// A failure of the handshake with a client.
+    pub(crate) fn client(authority: Authority, err: &io::Error) -> Self {
+        let kind = match tls_error(err) {
+            Some(err) => TlsFailureKind::of(err),
+            None => match err.kind() {
+                io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe => TlsFailureKind::ConnectionClosed,
+                _ => TlsFailureKind::Other,
+            },
+        };
+
+        Self::new(authority, TlsFailureSide::Client, kind, err.to_string())
+    }
+
+    /// A failure of the handshake with an upstream server, if a request failed because of one.
+    pub(crate) fn upstream(authority: Authority, err: &(dyn StdError + 'static)) -> Option<Self> {
+        let tls = tls_error(err)?;
+
+        Some(Self::new(
+            authority,
+            TlsFailureSide::Upstream,
+            TlsFailureKind::of(tls),
+            tls.to_string(),
+        ))
+    }
+}
+
+impl TlsFailureKind {
+    fn of(err: &TlsError) -> Self {
+        match err {
+            TlsError::AlertReceived(
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::CertificateRequired
+                | AlertDescription::UnknownCA,
+            ) => Self::CertificateRejected,
+            TlsError::AlertReceived(
+                AlertDescription::HandshakeFailure
+                | AlertDescription::ProtocolVersion
+                | AlertDescription::InsufficientSecurity
+                | AlertDescription::NoApplicationProtocol,
+            )
+            | TlsError::PeerIncompatible(_)
+            | TlsError::NoApplicationProtocol => Self::ProtocolMismatch,
+            TlsError::InvalidCertificate(_) | TlsError::NoCertificatesPresented => {
+                Self::InvalidCertificate
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Find the rustls error in the sources of an error, including the errors wrapped by I/O errors,
+/// whose sources skip the errors that they wrap.
+fn tls_error<'a>(err: &'a (dyn StdError + 'static)) -> Option<&'a TlsError> {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<TlsError>() {
+            return Some(err);
+        }
+
+        source = match err.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+            Some(inner) => Some(inner),
+            None => err.source(),
+        };
+    }
+
+    None
+}
+
+/// A list of hosts whose tunnels are not intercepted.
+///
+/// When set with [`ProxyBuilder::with_auto_passthrough`], the hosts of the TLS handshake failures
+/// that suggest passthrough are added to the list, so that clients which pin certificates keep
+/// working after their first failed connection. Hosts can also be added and removed while the
+/// proxy is running, as clones of a list share their hosts.
+///
+/// [`ProxyBuilder::with_auto_passthrough`]: crate::builder::ProxyBuilder::with_auto_passthrough
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::PassthroughList;
+///
+/// let list = PassthroughList::new();
+/// list.insert("pinned.example.com");
+/// assert!(list.contains("Pinned.example.com"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PassthroughList(Arc<Mutex<HashSet<String>>>);
+
+impl PassthroughList {
+    /// Creates a new empty list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a host to the list. Returns `false` if it was already in the list.
+    pub fn insert(&self, host: &str) -> bool {
+        self.0
+            .lock()
+            .expect("Failed to lock passthrough list")
+            .insert(host.to_ascii_lowercase())
+    }
+
+    /// Remove a host from the list, so that its tunnels are intercepted again. Returns `false`
+    /// if it was not in the list.
+    pub fn remove(&self, host: &str) -> bool {
+        self.0
+            .lock()
+            .expect("Failed to lock passthrough list")
+            .remove(&host.to_ascii_lowercase())
+    }
+
+    /// Whether a host is in the list.
+    pub fn contains(&self, host: &str) -> bool {
+        self.0
+            .lock()
+            .expect("Failed to lock passthrough list")
+            .contains(&host.to_ascii_lowercase())
+    }
+
+    /// The hosts in the list, in no particular order.
+    pub fn hosts(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .expect("Failed to lock passthrough list")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Remove all hosts from the list.
+    pub fn clear(&self) {
+        self.0
+            .lock()
+            .expect("Failed to lock passthrough list")
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_rustls::rustls::CertificateError;
+
+    fn authority() -> Authority {
+        Authority::from_static("example.com:443")
+    }
+
+    #[test]
+    fn suggests_passthrough_for_rejected_certificates() {
+        let err = io::Error::new(
+            io::ErrorKind::InvalidData,
+            TlsError::AlertReceived(AlertDescription::UnknownCA),
+        );
+        let failure = TlsFailure::client(authority(), &err);
+
+        assert_eq!(failure.side, TlsFailureSide::Client);
+        assert_eq!(failure.kind, TlsFailureKind::CertificateRejected);
+        assert!(failure.suggest_passthrough);
+    }
+
+    #[test]
+    fn classifies_closed_connections() {
+        let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+        let failure = TlsFailure::client(authority(), &err);
+
+        assert_eq!(failure.kind, TlsFailureKind::ConnectionClosed);
+        assert!(!failure.suggest_passthrough);
+    }
+
+    #[test]
+    fn finds_upstream_errors_in_sources() {
+        #[derive(Debug)]
+        struct Wrapper(io::Error);
+
+        impl std::fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("connect error")
+            }
+        }
+
+        impl StdError for Wrapper {
+            fn source(&self) -> Option<&(dyn StdError + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let err = Wrapper(io::Error::new(
+            io::ErrorKind::InvalidData,
+            TlsError::InvalidCertificate(CertificateError::UnknownIssuer),
+        ));
+        let failure = TlsFailure::upstream(authority(), &err).unwrap();
+
+        assert_eq!(failure.side, TlsFailureSide::Upstream);
+        assert_eq!(failure.kind, TlsFailureKind::InvalidCertificate);
+        assert!(!failure.suggest_passthrough);
+
+        let err = Wrapper(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(TlsFailure::upstream(authority(), &err).is_none());
+    }
+
+    #[test]
+    fn passthrough_list_ignores_case() {
+        let list = PassthroughList::new();
+
+        assert!(list.insert("Example.com"));
+        assert!(!list.insert("example.COM"));
+        assert!(list.clone().contains("EXAMPLE.com"));
+        assert_eq!(list.hosts(), ["example.com"]);
+        assert!(list.remove("example.com"));
+        assert!(!list.contains("example.com"));
+    }
+}
@@ -0,0 +1,319 @@
+#[cfg(target_os = "linux")]
+mod splice;
+
+use crate::{BufferPool, Rewind};
+use hyper::body::Bytes;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+#[cfg(target_os = "linux")]
+use tracing::debug;
+
+/// How the tunnels that are not intercepted are closed.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TunnelOptions {
+    pub idle: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// Close both directions of a tunnel when either side shuts down its writes, instead of
+    /// propagating the half-close and forwarding the other direction until it is shut down too.
+    pub close_on_eof: bool,
+}
+
+/// Why a tunnel was closed.
+#[derive(Debug)]
+pub(crate) enum TunnelEnd {
+    /// One side of the tunnel closed it.
+    Closed,
+    /// No data was sent in either direction for the idle timeout.
+    IdleTimeout,
+    /// The tunnel was open for its maximum lifetime.
+    MaxLifetime,
+    /// Forwarding data failed.
+    Error(io::Error),
+}
+
+/// The number of bytes that were forwarded through a tunnel, and why it was closed.
+#[derive(Debug)]
+pub(crate) struct TunnelStats {
+    pub end: TunnelEnd,
+    /// The number of bytes sent by the client.
+    pub sent: u64,
+    /// The number of bytes received by the client.
+    pub received: u64,
+    pub duration: Duration,
+}
+
+/// Forwards data between a client and a server until both sides close the tunnel, or until one
+/// of the timeouts elapses.
+///
+/// When one side shuts down its writes, the other side's writes are shut down as well, and data
+/// is still forwarded in the other direction, unless [`TunnelOptions::close_on_eof`] is set.
+pub(crate) async fn tunnel<C, S>(
+    client: C,
+    server: &mut S,
+    options: TunnelOptions,
+    pool: &BufferPool,
+) -> TunnelStats
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let activity = Arc::new(Activity::default());
+    let mut client = Counted {
+        inner: client,
+        start,
+        activity: Arc::clone(&activity),
+    };
+
+    let copy = copy_bidirectional(&mut client, server, options.close_on_eof, pool);
+
+    watch(copy, start, &activity, options).await
+}
+
+/// Forwards data between a client and a server connection like [`tunnel`], moving it between
+/// the sockets with `splice` on Linux instead of copying it through userspace. `prefix` is data
+/// that was already read from the client, which is sent to the server first.
+pub(crate) async fn tunnel_tcp(
+    client: TcpStream,
+    prefix: Bytes,
+    server: &mut TcpStream,
+    options: TunnelOptions,
+    pool: &BufferPool,
+) -> TunnelStats {
+    #[cfg(target_os = "linux")]
+    match splice::Pipes::new() {
+        Ok(pipes) => {
+            let start = Instant::now();
+            let activity = Arc::new(Activity::default());
+
+            let copy = async {
+                if !prefix.is_empty() {
+                    server.write_all(&prefix).await?;
+                    activity.record(start, &activity.sent, prefix.len());
+                }
+
+                pipes
+                    .relay(&client, server, options.close_on_eof, |upstream, bytes| {
+                        let counter = if upstream {
+                            &activity.sent
+                        } else {
+                            &activity.received
+                        };
+                        activity.record(start, counter, bytes);
+                    })
+                    .await
+            };
+
+            return watch(copy, start, &activity, options).await;
+        }
+        Err(e) => debug!(
+            "Failed to create pipes for splicing, copying instead: {}",
+            e
+        ),
+    }
+
+    tunnel(Rewind::new(client, prefix), server, options, pool).await
+}
+
+/// Runs the copying of a tunnel until it finishes, or until one of the timeouts elapses.
+async fn watch(
+    copy: impl Future<Output = io::Result<()>>,
+    start: Instant,
+    activity: &Activity,
+    options: TunnelOptions,
+) -> TunnelStats {
+    let idle = async {
+        let Some(timeout) = options.idle else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let last = start + Duration::from_millis(activity.last.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+
+            if Instant::now() >= deadline {
+                return;
+            }
+
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    };
+
+    let lifetime = async {
+        match options.max_lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let end = tokio::select! {
+        res = copy => match res {
+            Ok(()) => TunnelEnd::Closed,
+            Err(e) => TunnelEnd::Error(e),
+        },
+        _ = idle => TunnelEnd::IdleTimeout,
+        _ = lifetime => TunnelEnd::MaxLifetime,
+    };
+
+    TunnelStats {
+        end,
+        sent: activity.sent.load(Ordering::Relaxed),
+        received: activity.received.load(Ordering::Relaxed),
+        duration: start.elapsed(),
+    }
+}
+
+/// Forwards data in both directions with buffers from a pool.
+///
+/// When one side shuts down its writes, the writes to the other side are shut down and the other
+/// direction is forwarded until it is shut down too, unless `close_on_eof` is set, in which case
+/// the writes to both sides are shut down.
+async fn copy_bidirectional<C, S>(
+    client: &mut C,
+    server: &mut S,
+    close_on_eof: bool,
+    pool: &BufferPool,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut server_read, mut server_write) = tokio::io::split(server);
+
+    if close_on_eof {
+        tokio::select! {
+            res = copy(&mut client_read, &mut server_write, pool) => res?,
+            res = copy(&mut server_read, &mut client_write, pool) => res?,
+        };
+
+        let (client, server) = tokio::join!(client_write.shutdown(), server_write.shutdown());
+        client.and(server)
+    } else {
+        let upstream = async {
+            copy(&mut client_read, &mut server_write, pool).await?;
+            server_write.shutdown().await
+        };
+
+        let downstream = async {
+            copy(&mut server_read, &mut client_write, pool).await?;
+            client_write.shutdown().await
+        };
+
+        tokio::try_join!(upstream, downstream).map(|_| ())
+    }
+}
+
+/// Forwards data from a reader to a writer until the reader reaches EOF, with a buffer from a
+/// pool that is returned to it afterwards.
+async fn copy<R, W>(reader: &mut R, writer: &mut W, pool: &BufferPool) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = pool.get();
+
+    let res = async {
+        loop {
+            buf.clear();
+            buf.reserve(pool.buffer_size());
+
+            if reader.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+
+            writer.write_all(&buf).await?;
+            writer.flush().await?;
+        }
+    }
+    .await;
+
+    pool.put(buf);
+    res
+}
+
+#[derive(Debug, Default)]
+struct Activity {
+    /// When data was last forwarded, in milliseconds since the tunnel was opened.
+    last: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// The client side of a tunnel, which counts the bytes that are forwarded in each direction.
+struct Counted<C> {
+    inner: C,
+    start: Instant,
+    activity: Arc<Activity>,
+}
+
+impl Activity {
+    /// Count bytes that were forwarded in a tunnel that was opened at `start`.
+    fn record(&self, start: Instant, counter: &AtomicU64, bytes: usize) {
+        if bytes > 0 {
+            counter.fetch_add(bytes as u64, Ordering::Relaxed);
+            self.last
+                .store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<C> Counted<C> {
+    fn record(&self, counter: &AtomicU64, bytes: usize) {
+        self.activity.record(self.start, counter, bytes);
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Counted<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = res {
+            self.record(&self.activity.sent, buf.filled().len() - filled);
+        }
+
+        res
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Counted<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = res {
+            self.record(&self.activity.received, written);
+        }
+
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
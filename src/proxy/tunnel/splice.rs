@@ -0,0 +1,210 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+use tokio::{io::Interest, net::TcpStream};
+
+/// The number of bytes that are moved by each call to `splice`, which is the default capacity of
+/// a pipe.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A pipe that data is moved through from one socket to another.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+
+        // SAFETY: `fds` has room for the two file descriptors that `pipe2` writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: `pipe2` succeeded, so both file descriptors are open and owned by nothing else.
+        unsafe {
+            Ok(Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+/// The pipes of a tunnel, one for each direction.
+pub(crate) struct Pipes {
+    upstream: Pipe,
+    downstream: Pipe,
+}
+
+impl Pipes {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            upstream: Pipe::new()?,
+            downstream: Pipe::new()?,
+        })
+    }
+
+    /// Moves data between a client and a server until both of them shut down their writes, or
+    /// until either of them does if `close_on_eof` is set, calling `record` with whether the data
+    /// was sent upstream and its length as it is moved.
+    pub(crate) async fn relay(
+        &self,
+        client: &TcpStream,
+        server: &TcpStream,
+        close_on_eof: bool,
+        record: impl Fn(bool, usize),
+    ) -> io::Result<()> {
+        let upstream = async {
+            relay(client, server, &self.upstream, |bytes| record(true, bytes)).await?;
+            shutdown(server)
+        };
+
+        let downstream = async {
+            relay(server, client, &self.downstream, |bytes| {
+                record(false, bytes)
+            })
+            .await?;
+            shutdown(client)
+        };
+
+        if close_on_eof {
+            tokio::select! {
+                res = upstream => res?,
+                res = downstream => res?,
+            };
+
+            // One of the sockets was already shut down, which may fail again.
+            let _ = shutdown(client);
+            let _ = shutdown(server);
+            Ok(())
+        } else {
+            tokio::try_join!(upstream, downstream).map(|_| ())
+        }
+    }
+}
+
+/// Moves data from one socket to another through a pipe until the first one is shut down.
+async fn relay(
+    from: &TcpStream,
+    to: &TcpStream,
+    pipe: &Pipe,
+    record: impl Fn(usize),
+) -> io::Result<()> {
+    loop {
+        let read = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK_SIZE)
+            })
+            .await?;
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        // The pipe is drained before more data is read into it, so it is never full.
+        let mut pending = read;
+
+        while pending > 0 {
+            let written = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+                })
+                .await?;
+
+            pending -= written;
+            record(written);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: Both file descriptors are open for the duration of the call, and null offsets make
+    // `splice` use and update the file offsets, which sockets and pipes do not have.
+    let moved = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if moved < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(moved as usize)
+    }
+}
+
+/// Shuts down the writes of a socket, propagating a half-close.
+fn shutdown(socket: &TcpStream) -> io::Result<()> {
+    // SAFETY: The file descriptor is open for the duration of the call.
+    if unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn relays_until_both_sides_shut_down() {
+        let (mut client, proxy_client) = pair().await;
+        let (proxy_server, mut server) = pair().await;
+        let sent = AtomicUsize::new(0);
+
+        let relay = async {
+            Pipes::new()
+                .unwrap()
+                .relay(&proxy_client, &proxy_server, false, |upstream, bytes| {
+                    if upstream {
+                        sent.fetch_add(bytes, Ordering::Relaxed);
+                    }
+                })
+                .await
+        };
+
+        let peers = async {
+            client.write_all(&[7; 200_000]).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            server.write_all(b"done").await.unwrap();
+            server.shutdown().await.unwrap();
+
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            (received.len(), reply)
+        };
+
+        let (res, (received, reply)) = tokio::join!(relay, peers);
+        res.unwrap();
+        assert_eq!(received, 200_000);
+        assert_eq!(reply, b"done");
+        assert_eq!(sent.load(Ordering::Relaxed), 200_000);
+    }
+}
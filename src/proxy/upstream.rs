@@ -0,0 +1,250 @@
+use super::{
+    happy_eyeballs::{self, LocalBind},
+    Dscp,
+};
+use crate::Body;
+use futures::future::BoxFuture;
+use hyper::{
+    http::uri::{Authority, Scheme},
+    rt::{Read, ReadBufCursor, Write},
+    Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{
+        connect::{Connected, Connection},
+        Client,
+    },
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tower_service::Service;
+
+/// The maximum size of the head of a response to a `CONNECT` request.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+/// An HTTP proxy that a request is forwarded through, instead of directly to the upstream server.
+///
+/// Insert this into the extensions of a request in [`HttpHandler::handle_request`] to forward the
+/// request through the proxy. Plain HTTP requests are sent to the proxy in absolute form, and
+/// HTTPS requests are sent through a tunnel that is opened with `CONNECT`, and are verified with
+/// the Mozilla root certificates.
+///
+/// WebSocket connections are opened through a tunnel to the proxy, and `CONNECT` tunnels that are
+/// not intercepted are not forwarded through the proxy.
+///
+/// [`HttpHandler::handle_request`]: crate::HttpHandler::handle_request
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::UpstreamProxy;
+///
+/// let proxy = UpstreamProxy::new("proxy.example.com:3128".parse().unwrap());
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct UpstreamProxy {
+    authority: Authority,
+}
+
+impl UpstreamProxy {
+    /// Creates a new upstream proxy at an authority. Authorities without a port use port 80.
+    pub fn new(authority: Authority) -> Self {
+        Self { authority }
+    }
+
+    /// The authority of the proxy.
+    pub fn authority(&self) -> &Authority {
+        &self.authority
+    }
+}
+
+type ProxiedClient = Client<HttpsConnector<TunnelConnector>, Body>;
+
+/// The upstream proxy of a client, and the DSCP that its sockets are marked with.
+type ProxiedKey = (Authority, Option<Dscp>);
+
+/// The clients used to forward requests through upstream proxies, one for each proxy.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UpstreamProxies {
+    clients: Arc<Mutex<HashMap<ProxiedKey, ProxiedClient>>>,
+}
+
+impl UpstreamProxies {
+    pub(crate) fn get(&self, proxy: &UpstreamProxy, bind: &LocalBind) -> ProxiedClient {
+        let mut clients = self
+            .clients
+            .lock()
+            .expect("Failed to lock upstream proxy clients");
+
+        clients
+            .entry((proxy.authority.clone(), bind.dscp))
+            .or_insert_with(|| {
+                let https = HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .wrap_connector(TunnelConnector {
+                        proxy: proxy.authority.clone(),
+                        bind: bind.clone(),
+                    });
+
+                Client::builder(TokioExecutor::new())
+                    .http1_title_case_headers(true)
+                    .http1_preserve_header_case(true)
+                    .build(https)
+            })
+            .clone()
+    }
+}
+
+/// A connector that connects to an upstream proxy, and opens a tunnel through it for HTTPS.
+#[derive(Clone, Debug)]
+pub(crate) struct TunnelConnector {
+    proxy: Authority,
+    bind: LocalBind,
+}
+
+impl Service<Uri> for TunnelConnector {
+    type Response = Tunnel;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Tunnel>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let proxy = self.proxy.clone();
+        let bind = self.bind.clone();
+
+        Box::pin(async move {
+            let https = dst.scheme() == Some(&Scheme::HTTPS);
+
+            let tcp = if https {
+                let host = dst
+                    .host()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?;
+
+                tunnel(&proxy, host, dst.port_u16().unwrap_or(443), &bind).await?
+            } else {
+                let tcp =
+                    happy_eyeballs::connect(proxy.host(), proxy.port_u16().unwrap_or(80), &bind)
+                        .await?;
+                tcp.set_nodelay(true)?;
+                tcp
+            };
+
+            Ok(Tunnel {
+                io: TokioIo::new(tcp),
+                proxied: !https,
+            })
+        })
+    }
+}
+
+/// Open a tunnel to a host and port through an upstream proxy with `CONNECT`.
+pub(crate) async fn tunnel(
+    proxy: &Authority,
+    host: &str,
+    port: u16,
+    bind: &LocalBind,
+) -> io::Result<TcpStream> {
+    let mut tcp =
+        happy_eyeballs::connect(proxy.host(), proxy.port_u16().unwrap_or(80), bind).await?;
+    tcp.set_nodelay(true)?;
+
+    let target = format!("{}:{}", host, port);
+    tcp.write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes())
+        .await?;
+    read_connect_response(&mut tcp).await?;
+
+    Ok(tcp)
+}
+
+/// Read the head of a response to a `CONNECT` request, failing unless it is successful.
+async fn read_connect_response(tcp: &mut TcpStream) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_CONNECT_RESPONSE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "upstream proxy response is too large",
+            ));
+        }
+
+        let n = tcp.read(&mut buf).await?;
+
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let status = head
+        .split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|status| std::str::from_utf8(status).ok()?.parse::<u16>().ok());
+
+    match status {
+        Some(200..=299) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "upstream proxy refused to open a tunnel",
+        )),
+    }
+}
+
+/// A connection to an upstream proxy.
+pub(crate) struct Tunnel {
+    io: TokioIo<TcpStream>,
+    /// Whether requests are sent to the proxy, rather than through a tunnel.
+    proxied: bool,
+}
+
+impl Connection for Tunnel {
+    fn connected(&self) -> Connected {
+        Connected::new().proxy(self.proxied)
+    }
+}
+
+impl Read for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl Write for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -23,6 +23,11 @@ impl<T> Rewind<T> {
             inner: io,
         }
     }
+
+    /// The IO, and the data from the buffer that has not been read yet.
+    pub(crate) fn into_parts(self) -> (T, Bytes) {
+        (self.inner, self.pre.unwrap_or_default())
+    }
 }
 
 impl<T> AsyncRead for Rewind<T>
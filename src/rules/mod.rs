@@ -0,0 +1,1485 @@
+//! Interception rules loaded from a configuration file.
+//!
+//! A [`RulesFile`] loads [`Rules`] from a TOML file, and [`RulesHandler`] applies them to requests
+//! and responses. The file can be reloaded while the proxy is running with
+//! [`RulesFile::reload`], which can be triggered by the admin API's `POST /reload`, or
+//! automatically with [`RulesFile::watch`], which reloads the file when it changes and, on Unix,
+//! when the process receives `SIGHUP`.
+//!
+//! Reloads are atomic. A file that fails to load does not replace the current rules, and each flow
+//! uses the rules that were loaded when its request was received until its response is complete.
+//!
+//! # Format
+//!
+//! Rules files are written in a subset of TOML, without dotted keys, multi-line strings, floats,
+//! or dates. Hosts are [`HostPattern`]s, and paths are globs where `*` matches any sequence of
+//! characters.
+//!
+//! ```toml
+//! # CONNECT tunnels to these hosts are not intercepted.
+//! passthrough = ["*.bank.example", "pinned.example.com"]
+//!
+//! [[rewrite]]
+//! host = "api.example.com"
+//! path = "/v1/*"
+//! to_host = "staging.example.com"
+//! anonymize = "strip"
+//! set_headers = { x-environment = "staging" }
+//! remove_headers = ["cookie"]
+//! set_response_headers = { cache-control = "no-store" }
+//! remove_response_headers = ["set-cookie"]
+//!
+//! [[mock]]
+//! method = "GET"
+//! host = "example.com"
+//! path = "/health"
+//! status = 200
+//! headers = { content-type = "application/json" }
+//! body = '{"ok": true}'
+//! delay_ms = 100
+//!
+//! [[throttle]]
+//! host = "*.cdn.example"
+//! latency_ms = 200
+//! bytes_per_second = 65536
+//!
+//! [[throttle]]
+//! client = "10.0.0.0/8"
+//! preset = "3g"
+//! loss_percent = 5
+//! ```
+//!
+//! Rewrites also accept `dscp`, which marks the upstream connections of the requests that they
+//! match with a [`Dscp`] from 0 to 63, such as 1 to deprioritize bulk downloads.
+//!
+//! Rewrites also accept `anonymize`, which hides the headers that reveal the proxy or the original
+//! client from upstream servers, before the rewrite's other headers are set. With `"strip"`, the
+//! `Forwarded`, `Via`, `Proxy-Connection`, `X-Real-IP` and `X-Forwarded-*` headers are removed.
+//! With `"normalize"`, `Via` and `Proxy-Connection` are removed, `Forwarded`, `X-Forwarded-For`
+//! and `X-Real-IP` are replaced with `for=unknown` and `unknown`, and the other `X-Forwarded-*`
+//! headers are reduced to the values that the original client sent.
+//!
+//! Mocks also accept `template` instead of `body`, which is rendered as a
+//! [`MockResponse::with_template`] template, and `times`, which limits the number of requests that
+//! a mock is used for. A request is rewritten by every rewrite that matches it, in order, and is
+//! throttled by the first throttle that matches it.
+//!
+//! # Network conditions
+//!
+//! Besides `host` and `path`, throttles can be limited to the clients in a network with `client`,
+//! which is an IP address or a CIDR block. A throttle delays each request by `latency_ms` plus a
+//! random amount of up to `jitter_ms`, and streams response bodies at `bytes_per_second`. With
+//! `loss_percent`, that percentage of requests and body chunks is treated as lost, and is delayed
+//! by a retransmission timeout of twice the latency, but at least 200 milliseconds.
+//!
+//! A `preset` sets all of these to simulate a kind of network, and the other keys of the throttle
+//! override the preset's values:
+//!
+//! | Preset       | Latency | Jitter | Bandwidth  | Loss |
+//! |--------------|---------|--------|------------|------|
+//! | `gprs`       | 500 ms  | 200 ms | 50 kbit/s  | 2%   |
+//! | `3g`         | 300 ms  | 100 ms | 1.6 Mbit/s | 1%   |
+//! | `4g`         | 50 ms   | 20 ms  | 9 Mbit/s   | 0%   |
+//! | `flaky-wifi` | 40 ms   | 150 ms | 2 Mbit/s   | 5%   |
+//!
+//! Conditions are switched at runtime by editing the throttles in the file and reloading it, or
+//! from code with [`RulesFile::update`] and [`Rules::set_throttles`].
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::rules::{RulesFile, RulesHandler};
+//! use std::time::Duration;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let rules = RulesFile::load("rules.toml").await?;
+//! rules.watch(Duration::from_secs(1));
+//!
+//! let handler = RulesHandler::new(rules);
+//! # Ok(())
+//! # }
+//! ```
+
+mod toml;
+
+use self::toml::{Entry, Table, Value};
+use crate::{
+    auth::{host, HostPattern},
+    mock::{glob_matches, Matcher, Mock, MockHandler, MockResponse},
+    Body, BodyDirection, Dscp, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Body as HttpBody, Bytes, Frame, SizeHint},
+    header::{HeaderMap, HeaderName, HeaderValue, FORWARDED, VIA},
+    http::uri::Authority,
+    Method, Request, Response, StatusCode, Uri,
+};
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{task::JoinHandle, time::Sleep};
+use tracing::{info, warn};
+
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+fn invalid(line: usize, message: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+fn string(entry: &Entry) -> io::Result<&str> {
+    match &entry.value {
+        Value::String(s) => Ok(s),
+        value => Err(expected(entry, "a string", value)),
+    }
+}
+
+fn integer(entry: &Entry) -> io::Result<u64> {
+    match &entry.value {
+        Value::Integer(n) if *n >= 0 => Ok(*n as u64),
+        Value::Integer(_) => Err(invalid(
+            entry.line,
+            format!("`{}` must not be negative", entry.key),
+        )),
+        value => Err(expected(entry, "an integer", value)),
+    }
+}
+
+fn strings(entry: &Entry) -> io::Result<Vec<&str>> {
+    let Value::Array(values) = &entry.value else {
+        return Err(expected(entry, "an array", &entry.value));
+    };
+
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(s) => Ok(s.as_str()),
+            value => Err(expected(entry, "an array of strings", value)),
+        })
+        .collect()
+}
+
+fn tables(entry: &Entry) -> io::Result<Vec<&Table>> {
+    match &entry.value {
+        Value::Table(table) => Ok(vec![table]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::Table(table) => Ok(table),
+                value => Err(expected(entry, "an array of tables", value)),
+            })
+            .collect(),
+        value => Err(expected(entry, "a table", value)),
+    }
+}
+
+fn header_names(entry: &Entry) -> io::Result<Vec<HeaderName>> {
+    strings(entry)?
+        .into_iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| invalid(entry.line, format!("invalid header name `{}`", name)))
+        })
+        .collect()
+}
+
+fn headers(entry: &Entry) -> io::Result<Vec<(HeaderName, String)>> {
+    let Value::Table(table) = &entry.value else {
+        return Err(expected(entry, "a table", &entry.value));
+    };
+
+    table
+        .0
+        .iter()
+        .map(|header| {
+            let name = HeaderName::from_bytes(header.key.as_bytes()).map_err(|_| {
+                invalid(header.line, format!("invalid header name `{}`", header.key))
+            })?;
+            Ok((name, string(header)?.to_owned()))
+        })
+        .collect()
+}
+
+fn header_values(entry: &Entry) -> io::Result<Vec<(HeaderName, HeaderValue)>> {
+    headers(entry)?
+        .into_iter()
+        .map(|(name, value)| {
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| invalid(entry.line, format!("invalid value for header `{}`", name)))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn expected(entry: &Entry, kind: &str, value: &Value) -> io::Error {
+    invalid(
+        entry.line,
+        format!(
+            "expected `{}` to be {}, found {}",
+            entry.key,
+            kind,
+            value.kind()
+        ),
+    )
+}
+
+fn unknown(entry: &Entry, section: &str) -> io::Error {
+    invalid(
+        entry.line,
+        format!("unknown key `{}` in {}", entry.key, section),
+    )
+}
+
+/// The host and path that a rule applies to.
+#[derive(Clone, Debug, Default)]
+struct Filter {
+    host: Option<HostPattern>,
+    path: Option<String>,
+}
+
+impl Filter {
+    /// Parses a `host` or `path` key, returning `false` for other keys.
+    fn parse(&mut self, entry: &Entry) -> io::Result<bool> {
+        match entry.key.as_str() {
+            "host" => self.host = Some(HostPattern::new(string(entry)?)),
+            "path" => self.path = Some(string(entry)?.to_owned()),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        self.host.as_ref().map_or(true, |pattern| {
+            host(req).is_some_and(|host| pattern.matches(host))
+        }) && self.path.as_ref().map_or(true, |glob| {
+            glob_matches(glob.as_bytes(), req.uri().path().as_bytes())
+        })
+    }
+}
+
+/// How the headers that reveal the proxy or the original client are hidden.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Anonymize {
+    /// Remove the headers.
+    Strip,
+    /// Remove the headers about the proxy, and replace the addresses of clients and proxies.
+    Normalize,
+}
+
+impl Anonymize {
+    fn apply(self, headers: &mut HeaderMap) {
+        headers.remove(PROXY_CONNECTION);
+        headers.remove(VIA);
+
+        let names: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| {
+                *name == FORWARDED
+                    || *name == X_REAL_IP
+                    || name.as_str().starts_with("x-forwarded-")
+            })
+            .cloned()
+            .collect();
+
+        for name in names {
+            if self == Self::Strip {
+                headers.remove(&name);
+                continue;
+            }
+
+            let value = if name == FORWARDED {
+                HeaderValue::from_static("for=unknown")
+            } else if name == X_REAL_IP || name == X_FORWARDED_FOR {
+                HeaderValue::from_static("unknown")
+            } else {
+                // Element lists are in the order of the proxies, so the first element is the
+                // value that the original client sent.
+                let first = headers
+                    .get(&name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(',').next())
+                    .and_then(|value| HeaderValue::from_str(value.trim()).ok());
+
+                match first {
+                    Some(first) => first,
+                    None => {
+                        headers.remove(&name);
+                        continue;
+                    }
+                }
+            };
+
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Rewrites of the requests and responses that match a filter.
+#[derive(Clone, Debug, Default)]
+struct Rewrite {
+    filter: Filter,
+    anonymize: Option<Anonymize>,
+    to_host: Option<Authority>,
+    set_headers: Vec<(HeaderName, HeaderValue)>,
+    remove_headers: Vec<HeaderName>,
+    set_response_headers: Vec<(HeaderName, HeaderValue)>,
+    remove_response_headers: Vec<HeaderName>,
+    dscp: Option<Dscp>,
+}
+
+impl Rewrite {
+    fn from_table(table: &Table) -> io::Result<Self> {
+        let mut rewrite = Self::default();
+
+        for entry in &table.0 {
+            if rewrite.filter.parse(entry)? {
+                continue;
+            }
+
+            match entry.key.as_str() {
+                "anonymize" => {
+                    rewrite.anonymize = match string(entry)? {
+                        "strip" => Some(Anonymize::Strip),
+                        "normalize" => Some(Anonymize::Normalize),
+                        mode => {
+                            return Err(invalid(
+                                entry.line,
+                                format!("unknown anonymization `{}`", mode),
+                            ))
+                        }
+                    }
+                }
+                "to_host" => {
+                    let host = string(entry)?;
+                    rewrite.to_host =
+                        Some(host.parse().map_err(|_| {
+                            invalid(entry.line, format!("invalid host `{}`", host))
+                        })?);
+                }
+                "set_headers" => rewrite.set_headers = header_values(entry)?,
+                "remove_headers" => rewrite.remove_headers = header_names(entry)?,
+                "set_response_headers" => rewrite.set_response_headers = header_values(entry)?,
+                "remove_response_headers" => rewrite.remove_response_headers = header_names(entry)?,
+                "dscp" => {
+                    rewrite.dscp = Some(
+                        u8::try_from(integer(entry)?)
+                            .ok()
+                            .and_then(Dscp::new)
+                            .ok_or_else(|| invalid(entry.line, "`dscp` must be at most 63"))?,
+                    )
+                }
+                _ => return Err(unknown(entry, "[[rewrite]]")),
+            }
+        }
+
+        Ok(rewrite)
+    }
+
+    fn apply_request(&self, req: &mut Request<Body>) {
+        if let Some(anonymize) = self.anonymize {
+            anonymize.apply(req.headers_mut());
+        }
+
+        if let Some(to_host) = &self.to_host {
+            let mut parts = req.uri().clone().into_parts();
+
+            if parts.scheme.is_some() {
+                parts.authority = Some(to_host.clone());
+
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+
+            if req.headers().contains_key(hyper::header::HOST) {
+                if let Ok(value) = HeaderValue::from_str(to_host.as_str()) {
+                    req.headers_mut().insert(hyper::header::HOST, value);
+                }
+            }
+        }
+
+        for name in &self.remove_headers {
+            req.headers_mut().remove(name);
+        }
+
+        for (name, value) in &self.set_headers {
+            req.headers_mut().insert(name, value.clone());
+        }
+
+        if let Some(dscp) = self.dscp {
+            req.extensions_mut().insert(dscp);
+        }
+    }
+
+    fn apply_response(&self, res: &mut Response<Body>) {
+        for name in &self.remove_response_headers {
+            res.headers_mut().remove(name);
+        }
+
+        for (name, value) in &self.set_response_headers {
+            res.headers_mut().insert(name, value.clone());
+        }
+    }
+}
+
+enum MockBody {
+    Bytes(String),
+    Template(String),
+}
+
+fn mock(table: &Table) -> io::Result<Mock> {
+    let mut filter = Filter::default();
+    let mut method = None;
+    let mut status = StatusCode::OK;
+    let mut headers = Vec::new();
+    let mut body = None;
+    let mut delay = Duration::ZERO;
+    let mut times = None;
+
+    for entry in &table.0 {
+        if filter.parse(entry)? {
+            continue;
+        }
+
+        match entry.key.as_str() {
+            "method" => {
+                let name = string(entry)?;
+                method = Some(
+                    Method::from_bytes(name.as_bytes())
+                        .map_err(|_| invalid(entry.line, format!("invalid method `{}`", name)))?,
+                );
+            }
+            "status" => {
+                let code = integer(entry)?;
+                status = u16::try_from(code)
+                    .ok()
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .ok_or_else(|| invalid(entry.line, format!("invalid status {}", code)))?;
+            }
+            "headers" => headers = self::headers(entry)?,
+            "body" | "template" if body.is_some() => {
+                return Err(invalid(
+                    entry.line,
+                    "a mock can only have one of `body` and `template`",
+                ))
+            }
+            "body" => body = Some(MockBody::Bytes(string(entry)?.to_owned())),
+            "template" => body = Some(MockBody::Template(string(entry)?.to_owned())),
+            "delay_ms" => delay = Duration::from_millis(integer(entry)?),
+            "times" => times = Some(integer(entry)? as usize),
+            _ => return Err(unknown(entry, "[[mock]]")),
+        }
+    }
+
+    let mut response = MockResponse::new(status).with_delay(delay);
+
+    for (name, value) in headers {
+        response = response.with_header(name.as_str(), value);
+    }
+
+    response = match body {
+        Some(MockBody::Bytes(body)) => response.with_body(body),
+        Some(MockBody::Template(template)) => response.with_template(template),
+        None => response,
+    };
+
+    let mut matcher = Matcher::new();
+
+    if let Some(method) = method {
+        matcher = matcher.method(method);
+    }
+
+    if let Some(host) = filter.host {
+        matcher = matcher.host(host);
+    }
+
+    if let Some(path) = filter.path {
+        matcher = matcher.path(path);
+    }
+
+    let mock = Mock::new(matcher, response);
+
+    Ok(match times {
+        Some(times) => mock.with_times(times),
+        None => mock,
+    })
+}
+
+/// The minimum delay of a lost request or body chunk, as with the minimum retransmission timeout
+/// of TCP.
+const MIN_RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A random number between `0.0` and `1.0`.
+fn random() -> f64 {
+    // Each `RandomState` has different keys, so hashing nothing with it gives a random number.
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The conditions of a simulated network.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Conditions {
+    latency: Duration,
+    jitter: Duration,
+    bytes_per_second: Option<u64>,
+    loss_percent: u64,
+}
+
+impl Conditions {
+    /// The conditions of a named preset.
+    fn preset(name: &str) -> Option<Self> {
+        let (latency, jitter, bytes_per_second, loss_percent) = match name {
+            "gprs" => (500, 200, 6_250, 2),
+            "3g" => (300, 100, 200_000, 1),
+            "4g" => (50, 20, 1_125_000, 0),
+            "flaky-wifi" => (40, 150, 250_000, 5),
+            _ => return None,
+        };
+
+        Some(Self {
+            latency: Duration::from_millis(latency),
+            jitter: Duration::from_millis(jitter),
+            bytes_per_second: Some(bytes_per_second),
+            loss_percent,
+        })
+    }
+
+    /// The delay of a request, with jitter and the retransmission timeout if it is lost.
+    fn request_delay(&self) -> Duration {
+        self.latency + self.jitter.mul_f64(random()) + self.retransmission()
+    }
+
+    /// The delay after a body chunk of `len` bytes, with the retransmission timeout if it is lost.
+    fn chunk_delay(&self, len: usize) -> Duration {
+        let transmission = self.bytes_per_second.map_or(Duration::ZERO, |rate| {
+            Duration::from_secs_f64(len as f64 / rate as f64)
+        });
+
+        transmission + self.retransmission()
+    }
+
+    fn retransmission(&self) -> Duration {
+        if self.loss_percent > 0 && random() * 100.0 < self.loss_percent as f64 {
+            (self.latency * 2).max(MIN_RETRANSMISSION_TIMEOUT)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Whether response bodies are throttled.
+    fn throttles_bodies(&self) -> bool {
+        self.bytes_per_second.is_some() || self.loss_percent > 0
+    }
+}
+
+/// A network of client addresses, written as an IP address or a CIDR block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse().ok()?, Some(prefix_len.parse().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?.to_canonical(), None),
+        };
+        let max_len = if matches!(addr, IpAddr::V4(_)) {
+            32
+        } else {
+            128
+        };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+
+        (prefix_len <= max_len).then_some(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        fn masked(bits: u128, len: u32, prefix_len: u8) -> u128 {
+            bits.checked_shr(len - u32::from(prefix_len))
+                .unwrap_or_default()
+        }
+
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                masked(u32::from(network).into(), 32, self.prefix_len)
+                    == masked(u32::from(addr).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                masked(network.into(), 128, self.prefix_len)
+                    == masked(addr.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Simulated network conditions for the requests that match a filter.
+#[derive(Clone, Debug, Default)]
+struct Throttle {
+    filter: Filter,
+    client: Option<Network>,
+    conditions: Conditions,
+}
+
+impl Throttle {
+    fn from_table(table: &Table) -> io::Result<Self> {
+        let mut throttle = Self::default();
+
+        // The preset is applied first, so that the other keys override it wherever they are.
+        for entry in table.0.iter().filter(|entry| entry.key == "preset") {
+            let name = string(entry)?;
+            throttle.conditions = Conditions::preset(name)
+                .ok_or_else(|| invalid(entry.line, format!("unknown preset `{}`", name)))?;
+        }
+
+        for entry in &table.0 {
+            if throttle.filter.parse(entry)? {
+                continue;
+            }
+
+            let conditions = &mut throttle.conditions;
+
+            match entry.key.as_str() {
+                "preset" => {}
+                "client" => {
+                    let network = string(entry)?;
+                    throttle.client = Some(Network::parse(network).ok_or_else(|| {
+                        invalid(entry.line, format!("invalid client network `{}`", network))
+                    })?);
+                }
+                "latency_ms" => conditions.latency = Duration::from_millis(integer(entry)?),
+                "jitter_ms" => conditions.jitter = Duration::from_millis(integer(entry)?),
+                "bytes_per_second" => match integer(entry)? {
+                    0 => return Err(invalid(entry.line, "`bytes_per_second` must not be 0")),
+                    rate => conditions.bytes_per_second = Some(rate),
+                },
+                "loss_percent" => match integer(entry)? {
+                    loss @ 0..=100 => conditions.loss_percent = loss,
+                    _ => return Err(invalid(entry.line, "`loss_percent` must be at most 100")),
+                },
+                _ => return Err(unknown(entry, "[[throttle]]")),
+            }
+        }
+
+        Ok(throttle)
+    }
+
+    fn matches<T>(&self, ctx: &HttpContext, req: &Request<T>) -> bool {
+        self.client
+            .map_or(true, |network| network.contains(ctx.client_addr.ip()))
+            && self.filter.matches(req)
+    }
+}
+
+/// A set of interception rules.
+///
+/// See the [module documentation](self) for the format of rules files.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    passthrough: Vec<HostPattern>,
+    rewrites: Vec<Rewrite>,
+    mocks: MockHandler,
+    throttles: Vec<Throttle>,
+}
+
+impl Rules {
+    /// Parses rules from the contents of a rules file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidData`] if the rules are invalid.
+    pub fn parse(input: &str) -> io::Result<Self> {
+        let table = toml::parse(input).map_err(|e| invalid(e.line, e.message))?;
+        let mut rules = Self::default();
+
+        for entry in &table.0 {
+            match entry.key.as_str() {
+                "passthrough" => {
+                    rules.passthrough = strings(entry)?.into_iter().map(HostPattern::new).collect()
+                }
+                "rewrite" => {
+                    for table in tables(entry)? {
+                        rules.rewrites.push(Rewrite::from_table(table)?);
+                    }
+                }
+                "mock" => {
+                    for table in tables(entry)? {
+                        rules.mocks = rules.mocks.with_mock(mock(table)?);
+                    }
+                }
+                "throttle" => {
+                    for table in tables(entry)? {
+                        rules.throttles.push(Throttle::from_table(table)?);
+                    }
+                }
+                _ => return Err(unknown(entry, "the rules file")),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    /// Whether `CONNECT` tunnels to a host are passed through without being intercepted.
+    pub fn is_passthrough(&self, host: &str) -> bool {
+        self.passthrough.iter().any(|pattern| pattern.matches(host))
+    }
+
+    /// Adds a pattern of hosts whose `CONNECT` tunnels are passed through.
+    pub fn add_passthrough(&mut self, pattern: HostPattern) {
+        if !self.passthrough.contains(&pattern) {
+            self.passthrough.push(pattern);
+        }
+    }
+
+    /// Removes a pattern of hosts whose `CONNECT` tunnels are passed through, returning whether
+    /// the pattern was in the rules.
+    pub fn remove_passthrough(&mut self, pattern: &str) -> bool {
+        let pattern = HostPattern::new(pattern);
+        let len = self.passthrough.len();
+
+        self.passthrough.retain(|p| *p != pattern);
+        self.passthrough.len() != len
+    }
+
+    /// Replaces the throttles with the `[[throttle]]` tables of `input`, which is parsed like a
+    /// rules file. The other rules in `input` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidData`] if the rules are invalid, in which
+    /// case the throttles are not changed.
+    pub fn set_throttles(&mut self, input: &str) -> io::Result<()> {
+        self.throttles = Self::parse(input)?.throttles;
+        Ok(())
+    }
+}
+
+struct Shared {
+    path: PathBuf,
+    rules: RwLock<Arc<Rules>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+/// Rules that are loaded from a file, and can be reloaded while the proxy is running.
+///
+/// Clones of a rules file share the same rules.
+#[derive(Clone)]
+pub struct RulesFile {
+    shared: Arc<Shared>,
+}
+
+impl RulesFile {
+    /// Loads rules from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can not be read, or if its rules are invalid.
+    pub async fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let (rules, modified) = read(&path).await?;
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                path,
+                rules: RwLock::new(Arc::new(rules)),
+                modified: Mutex::new(modified),
+            }),
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+
+    /// The rules that are currently loaded.
+    pub fn rules(&self) -> Arc<Rules> {
+        Arc::clone(&self.shared.rules.read().expect("Failed to lock rules"))
+    }
+
+    /// Changes the current rules, such as to add a passthrough host or to switch the network
+    /// conditions of throttles while the proxy is running.
+    ///
+    /// The changes apply to the flows and tunnels that start after this is called, and flows that
+    /// are in progress keep using the rules that they started with. They are replaced when the
+    /// file is next reloaded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hudsucker::rules::RulesFile;
+    ///
+    /// # fn example(rules: RulesFile) -> std::io::Result<()> {
+    /// rules.update(|rules| {
+    ///     rules.remove_passthrough("pinned.example.com");
+    ///     rules.set_throttles("[[throttle]]\npreset = \"3g\"")
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update<T>(&self, f: impl FnOnce(&mut Rules) -> T) -> T {
+        let mut current = self.shared.rules.write().expect("Failed to lock rules");
+        let mut rules = Rules::clone(&current);
+        let result = f(&mut rules);
+
+        *current = Arc::new(rules);
+        result
+    }
+
+    /// Reloads the rules from the file.
+    ///
+    /// The current rules are replaced only if the file is loaded successfully. Flows that are in
+    /// progress keep using the rules that they started with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can not be read, or if its rules are invalid.
+    pub async fn reload(&self) -> io::Result<()> {
+        let (rules, modified) = read(&self.shared.path).await?;
+
+        *self.shared.rules.write().expect("Failed to lock rules") = Arc::new(rules);
+        *self
+            .shared
+            .modified
+            .lock()
+            .expect("Failed to lock modification time") = modified;
+
+        info!("Reloaded rules from {}", self.shared.path.display());
+        Ok(())
+    }
+
+    /// Spawns a task that reloads the rules when the file's modification time changes, which is
+    /// checked every `interval`, and on Unix when the process receives `SIGHUP`.
+    ///
+    /// Failed reloads are logged, and the current rules are kept. The task runs until it is
+    /// aborted with the returned handle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime, or if `interval` is zero.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        let file = self.clone();
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| warn!("Failed to listen for SIGHUP: {}", e))
+                .ok();
+
+            loop {
+                #[cfg(unix)]
+                let forced = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = hangup_received(&mut hangup) => true,
+                };
+
+                #[cfg(not(unix))]
+                let forced = {
+                    interval.tick().await;
+                    false
+                };
+
+                if !forced && !file.modified().await {
+                    continue;
+                }
+
+                if let Err(e) = file.reload().await {
+                    warn!(
+                        "Failed to reload rules from {}: {}",
+                        file.shared.path.display(),
+                        e
+                    );
+                }
+            }
+        })
+    }
+
+    /// Whether the modification time of the file differs from when it was last loaded.
+    async fn modified(&self) -> bool {
+        let modified = modified(&self.shared.path).await;
+
+        modified.is_some()
+            && modified
+                != *self
+                    .shared
+                    .modified
+                    .lock()
+                    .expect("Failed to lock modification time")
+    }
+}
+
+impl std::fmt::Debug for RulesFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RulesFile")
+            .field("path", &self.shared.path)
+            .finish_non_exhaustive()
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+async fn read(path: &Path) -> io::Result<(Rules, Option<SystemTime>)> {
+    let modified = modified(path).await;
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok((Rules::parse(&contents)?, modified))
+}
+
+#[cfg(unix)]
+async fn hangup_received(signal: &mut Option<tokio::signal::unix::Signal>) {
+    if let Some(signal) = signal {
+        if signal.recv().await.is_some() {
+            return;
+        }
+    }
+
+    std::future::pending().await
+}
+
+/// The rules that a flow uses, from when its request was received.
+#[derive(Clone, Debug)]
+struct Flow {
+    rules: Arc<Rules>,
+    rewrites: Vec<usize>,
+    throttle: Option<usize>,
+}
+
+/// An HTTP handler that applies the rules of a [`RulesFile`].
+///
+/// See the [module documentation](self) for an example. Requests are passed to the wrapped handler
+/// before the rules are applied, and responses after. Mock responses are passed to the wrapped
+/// handler's [`HttpHandler::handle_response`].
+#[derive(Clone, Debug)]
+pub struct RulesHandler<H = NoopHandler> {
+    file: RulesFile,
+    flow: Option<Flow>,
+    inner: H,
+}
+
+impl RulesHandler {
+    /// Creates a new handler that applies the rules of a file.
+    pub fn new(file: RulesFile) -> Self {
+        Self {
+            file,
+            flow: None,
+            inner: NoopHandler::default(),
+        }
+    }
+}
+
+impl<H> RulesHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> RulesHandler<H2> {
+        RulesHandler {
+            file: self.file,
+            flow: None,
+            inner,
+        }
+    }
+
+    /// The rules file.
+    pub fn file(&self) -> &RulesFile {
+        &self.file
+    }
+}
+
+impl<H: HttpHandler> RulesHandler<H> {
+    async fn apply(&mut self, ctx: &HttpContext, mut req: Request<Body>) -> RequestOrResponse {
+        let rules = self.file.rules();
+        let flow = Flow {
+            rewrites: (0..rules.rewrites.len())
+                .filter(|&i| rules.rewrites[i].filter.matches(&req))
+                .collect(),
+            throttle: rules.throttles.iter().position(|t| t.matches(ctx, &req)),
+            rules,
+        };
+
+        if let Some(i) = flow.throttle {
+            let delay = flow.rules.throttles[i].conditions.request_delay();
+
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        self.flow = Some(flow.clone());
+
+        match flow.rules.mocks.clone().handle_request(ctx, req).await {
+            RequestOrResponse::Request(r) => req = r,
+            RequestOrResponse::Response(res) => {
+                return self.handle_response(ctx, res).await.into();
+            }
+        }
+
+        for &i in &flow.rewrites {
+            flow.rules.rewrites[i].apply_request(&mut req);
+        }
+
+        req.into()
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for RulesHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.apply(ctx, req).await,
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let mut res = self.inner.handle_response(ctx, res).await;
+
+        let Some(flow) = self.flow.take() else {
+            return res;
+        };
+
+        for &i in &flow.rewrites {
+            flow.rules.rewrites[i].apply_response(&mut res);
+        }
+
+        match flow
+            .throttle
+            .map(|i| flow.rules.throttles[i].conditions)
+            .filter(Conditions::throttles_bodies)
+        {
+            Some(conditions) => {
+                let (parts, body) = res.into_parts();
+                let body = Throttled {
+                    body,
+                    conditions,
+                    sleep: None,
+                };
+                Response::from_parts(parts, Body::from(BoxBody::new(body)))
+            }
+            None => res,
+        }
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        if host(req).is_some_and(|host| self.file.rules().is_passthrough(host)) {
+            return false;
+        }
+
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+/// A body that is streamed at a limited average rate, by waiting after each chunk for as long as
+/// the chunk would take to send at that rate, and for the retransmission timeout of lost chunks.
+struct Throttled {
+    body: Body,
+    conditions: Conditions,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl HttpBody for Throttled {
+    type Data = Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(sleep) = &mut self.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        if let Some(len) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok()?.data_ref().map(Bytes::len))
+        {
+            let delay = self.conditions.chunk_delay(len);
+
+            if !delay.is_zero() {
+                self.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.sleep.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty};
+
+    const RULES: &str = r#"
+        passthrough = ["*.bank.example"]
+
+        [[rewrite]]
+        host = "api.example.com"
+        to_host = "staging.example.com:8443"
+        set_headers = { x-environment = "staging" }
+        remove_headers = ["cookie"]
+        set_response_headers = { cache-control = "no-store" }
+        dscp = 1
+
+        [[mock]]
+        method = "GET"
+        path = "/health"
+        status = 204
+        headers = { x-mock = "1" }
+    "#;
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "hudsucker-rules-{}-{:?}.toml",
+            std::process::id(),
+            std::thread::current().id()
+        ))
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for (input, message) in [
+            (
+                "unknown = 1",
+                "line 1: unknown key `unknown` in the rules file",
+            ),
+            ("[[mock]]\nstatus = 1000", "line 2: invalid status 1000"),
+            (
+                "[[rewrite]]\nremove_headers = \"cookie\"",
+                "line 2: expected `remove_headers` to be an array, found a string",
+            ),
+            (
+                "[[throttle]]\nbytes_per_second = 0",
+                "line 2: `bytes_per_second` must not be 0",
+            ),
+            (
+                "[[throttle]]\npreset = \"5g\"",
+                "line 2: unknown preset `5g`",
+            ),
+            (
+                "[[throttle]]\nclient = \"10.0.0.0/33\"",
+                "line 2: invalid client network `10.0.0.0/33`",
+            ),
+            (
+                "[[rewrite]]\nanonymize = \"hide\"",
+                "line 2: unknown anonymization `hide`",
+            ),
+            (
+                "[[throttle]]\nloss_percent = 101",
+                "line 2: `loss_percent` must be at most 100",
+            ),
+            (
+                "[[rewrite]]\ndscp = 64",
+                "line 2: `dscp` must be at most 63",
+            ),
+            ("passthrough = [", "line 1: expected a value"),
+        ] {
+            let err = Rules::parse(input).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message, "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_rules() {
+        let path = path();
+        tokio::fs::write(&path, RULES).await.unwrap();
+        let mut handler = RulesHandler::new(RulesFile::load(&path).await.unwrap());
+
+        let connect = Request::builder()
+            .method(Method::CONNECT)
+            .uri("www.bank.example:443")
+            .body(Body::from(Empty::new()))
+            .unwrap();
+        assert!(
+            !handler
+                .should_intercept(&HttpContext::for_test(), &connect)
+                .await
+        );
+
+        let req = Request::builder()
+            .uri("https://api.example.com/users")
+            .header(hyper::header::HOST, "api.example.com")
+            .header(hyper::header::COOKIE, "a=b")
+            .body(Body::from(Empty::new()))
+            .unwrap();
+
+        let RequestOrResponse::Request(req) =
+            handler.handle_request(&HttpContext::for_test(), req).await
+        else {
+            panic!("expected a request");
+        };
+        assert_eq!(req.uri(), "https://staging.example.com:8443/users");
+        assert_eq!(req.headers()["host"], "staging.example.com:8443");
+        assert_eq!(req.headers()["x-environment"], "staging");
+        assert!(!req.headers().contains_key(hyper::header::COOKIE));
+        assert_eq!(req.extensions().get(), Some(&Dscp::LOWER_EFFORT));
+
+        let res = handler
+            .handle_response(
+                &HttpContext::for_test(),
+                Response::new(Body::from(Empty::new())),
+            )
+            .await;
+        assert_eq!(res.headers()["cache-control"], "no-store");
+
+        let req = Request::builder()
+            .uri("http://example.com/health")
+            .body(Body::from(Empty::new()))
+            .unwrap();
+
+        let RequestOrResponse::Response(res) =
+            handler.handle_request(&HttpContext::for_test(), req).await
+        else {
+            panic!("expected a response");
+        };
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()["x-mock"], "1");
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn updates_rules_at_runtime() {
+        let path = path();
+        tokio::fs::write(&path, RULES).await.unwrap();
+        let file = RulesFile::load(&path).await.unwrap();
+        let before = file.rules();
+
+        let updated = file.update(|rules| {
+            rules.add_passthrough(HostPattern::new("Pinned.example.com"));
+            assert!(rules.remove_passthrough("*.bank.example"));
+            rules.set_throttles("[[throttle]]\npreset = \"5g\"")
+        });
+        assert!(updated.is_err());
+
+        let rules = file.rules();
+        assert!(rules.is_passthrough("pinned.example.com"));
+        assert!(!rules.is_passthrough("www.bank.example"));
+        assert_eq!(rules.throttles.len(), before.throttles.len());
+        assert!(before.is_passthrough("www.bank.example"));
+
+        file.update(|rules| rules.set_throttles("[[throttle]]\npreset = \"4g\""))
+            .unwrap();
+        assert_eq!(file.rules().throttles.len(), 1);
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_atomically() {
+        let path = path();
+        tokio::fs::write(&path, RULES).await.unwrap();
+        let file = RulesFile::load(&path).await.unwrap();
+        let mut handler = RulesHandler::new(file.clone());
+
+        let req = Request::builder()
+            .uri("https://api.example.com/")
+            .body(Body::from(Empty::new()))
+            .unwrap();
+        handler.handle_request(&HttpContext::for_test(), req).await;
+
+        tokio::fs::write(&path, "passthrough = [").await.unwrap();
+        assert!(file.reload().await.is_err());
+        assert!(file.rules().is_passthrough("www.bank.example"));
+
+        tokio::fs::write(&path, "").await.unwrap();
+        file.reload().await.unwrap();
+        assert!(!file.rules().is_passthrough("www.bank.example"));
+
+        // The flow that started before the reload still uses the old rules.
+        let res = handler
+            .handle_response(
+                &HttpContext::for_test(),
+                Response::new(Body::from(Empty::new())),
+            )
+            .await;
+        assert_eq!(res.headers()["cache-control"], "no-store");
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[test]
+    fn anonymizes_headers() {
+        let rewrite = |mode: &str| {
+            let rules = Rules::parse(&format!("[[rewrite]]\nanonymize = \"{}\"", mode)).unwrap();
+            let mut req = Request::builder()
+                .header("forwarded", "for=192.0.2.1;proto=https, for=10.0.0.1")
+                .header("via", "1.1 proxy.internal")
+                .header("proxy-connection", "keep-alive")
+                .header("x-forwarded-for", "192.0.2.1, 10.0.0.1")
+                .header("x-forwarded-proto", "https, http")
+                .header("x-real-ip", "192.0.2.1")
+                .header("accept", "*/*")
+                .body(Body::from(Empty::new()))
+                .unwrap();
+            rules.rewrites[0].apply_request(&mut req);
+            req.headers().clone()
+        };
+
+        let headers = rewrite("strip");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["accept"], "*/*");
+
+        let headers = rewrite("normalize");
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers["forwarded"], "for=unknown");
+        assert_eq!(headers["x-forwarded-for"], "unknown");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-real-ip"], "unknown");
+        assert!(!headers.contains_key("via"));
+    }
+
+    #[test]
+    fn applies_presets_by_client() {
+        let rules = Rules::parse(
+            "[[throttle]]\nlatency_ms = 10\npreset = \"3g\"\nclient = \"10.0.0.0/8\"\n\n\
+             [[throttle]]\nclient = \"::ffff:192.0.2.1\"\npreset = \"flaky-wifi\"",
+        )
+        .unwrap();
+
+        let throttle = &rules.throttles[0];
+        assert_eq!(
+            throttle.conditions,
+            Conditions {
+                latency: Duration::from_millis(10),
+                ..Conditions::preset("3g").unwrap()
+            }
+        );
+
+        let req = Request::new(());
+        let matching = |client_addr: &str| {
+            let ctx = HttpContext {
+                client_addr: client_addr.parse().unwrap(),
+                flow_id: 0,
+                extensions: Default::default(),
+                downstream_version: hyper::Version::HTTP_11,
+                upstream_version: None,
+            };
+            rules.throttles.iter().position(|t| t.matches(&ctx, &req))
+        };
+
+        assert_eq!(matching("10.1.2.3:8080"), Some(0));
+        assert_eq!(matching("[::ffff:10.1.2.3]:8080"), Some(0));
+        assert_eq!(matching("192.0.2.1:8080"), Some(1));
+        assert_eq!(matching("192.0.2.2:8080"), None);
+    }
+
+    #[tokio::test]
+    async fn throttles_bodies() {
+        let rules = Rules::parse("[[throttle]]\nlatency_ms = 50\nbytes_per_second = 200").unwrap();
+        let path = path();
+        tokio::fs::write(&path, "").await.unwrap();
+        let file = RulesFile::load(&path).await.unwrap();
+        *file.shared.rules.write().unwrap() = Arc::new(rules);
+        let mut handler = RulesHandler::new(file);
+
+        let start = std::time::Instant::now();
+        let req = Request::builder()
+            .uri("http://example.com/")
+            .body(Body::from(Empty::new()))
+            .unwrap();
+        handler.handle_request(&HttpContext::for_test(), req).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let res = handler
+            .handle_response(
+                &HttpContext::for_test(),
+                Response::new(Body::from("0123456789")),
+            )
+            .await;
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "0123456789");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
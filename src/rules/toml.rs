@@ -0,0 +1,521 @@
+//! A parser for the subset of TOML that is used by rules files.
+//!
+//! Supported are `[table]` and `[[array]]` headers, bare and quoted keys, basic and literal
+//! strings, integers, booleans, arrays, which can span lines, and inline tables. Dotted keys,
+//! multi-line strings, floats, and dates are not supported.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct Table(pub(super) Vec<Entry>);
+
+impl Table {
+    fn insert(&mut self, entry: Entry) -> Result<(), ParseError> {
+        if self.0.iter().any(|e| e.key == entry.key) {
+            return Err(ParseError {
+                line: entry.line,
+                message: format!("duplicate key `{}`", entry.key),
+            });
+        }
+
+        self.0.push(entry);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Entry {
+    pub(super) key: String,
+    pub(super) value: Value,
+    pub(super) line: usize,
+}
+
+#[derive(Debug)]
+pub(super) struct ParseError {
+    pub(super) line: usize,
+    pub(super) message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Section {
+    Root,
+    Table(String, usize),
+    Array(String, usize),
+}
+
+pub(super) fn parse(input: &str) -> Result<Table, ParseError> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Table::default();
+    let mut section = Section::Root;
+    let mut current = Table::default();
+
+    loop {
+        parser.skip_blank();
+
+        match parser.peek() {
+            None => break,
+            Some('[') => {
+                let line = parser.line;
+                parser.bump();
+                let array = parser.eat('[');
+
+                parser.skip_whitespace();
+                let name = parser.key()?;
+                parser.skip_whitespace();
+                parser.expect(']')?;
+
+                if array {
+                    parser.expect(']')?;
+                }
+
+                parser.end_of_line()?;
+
+                let next = if array {
+                    Section::Array(name, line)
+                } else {
+                    Section::Table(name, line)
+                };
+
+                finish(&mut root, section, &mut current)?;
+                section = next;
+            }
+            Some(_) => {
+                let line = parser.line;
+                let key = parser.key()?;
+                parser.skip_whitespace();
+                parser.expect('=')?;
+                parser.skip_whitespace();
+                let value = parser.value()?;
+                parser.end_of_line()?;
+
+                let table = match section {
+                    Section::Root => &mut root,
+                    _ => &mut current,
+                };
+                table.insert(Entry { key, value, line })?;
+            }
+        }
+    }
+
+    finish(&mut root, section, &mut current)?;
+    Ok(root)
+}
+
+/// Adds the entries of a section to the root table.
+fn finish(root: &mut Table, section: Section, current: &mut Table) -> Result<(), ParseError> {
+    let table = std::mem::take(current);
+
+    match section {
+        Section::Root => Ok(()),
+        Section::Table(key, line) => root.insert(Entry {
+            key,
+            value: Value::Table(table),
+            line,
+        }),
+        Section::Array(key, line) => {
+            match root.0.iter_mut().find(|entry| entry.key == key) {
+                Some(Entry {
+                    value: Value::Array(values),
+                    ..
+                }) if values.iter().all(|v| matches!(v, Value::Table(_))) => {
+                    values.push(Value::Table(table));
+                }
+                Some(_) => {
+                    return Err(ParseError {
+                        line,
+                        message: format!("duplicate key `{}`", key),
+                    })
+                }
+                None => root.0.push(Entry {
+                    key,
+                    value: Value::Array(vec![Value::Table(table)]),
+                    line,
+                }),
+            }
+
+            Ok(())
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+        }
+
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+
+        if eaten {
+            self.bump();
+        }
+
+        eaten
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`", c))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skips whitespace, comments, and newlines.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+
+            if !self.eat('\n') && !self.eat('\r') {
+                break;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        self.skip_comment();
+        self.eat('\r');
+
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(_) => self.error("expected the end of the line"),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        let key = match self.peek() {
+            Some('"') => self.basic_string()?,
+            Some('\'') => self.literal_string()?,
+            _ => {
+                let start = self.pos;
+
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.bump();
+                }
+
+                if start == self.pos {
+                    return self.error("expected a key");
+                }
+
+                self.input[start..self.pos].to_owned()
+            }
+        };
+
+        if self.peek() == Some('.') {
+            return self.error("dotted keys are not supported");
+        }
+
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some('t' | 'f') => {
+                let rest = &self.input[self.pos..];
+
+                for (word, value) in [("true", true), ("false", false)] {
+                    if rest.starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(Value::Boolean(value));
+                    }
+                }
+
+                self.error("expected a value")
+            }
+            Some(c) if c.is_ascii_digit() || c == '+' || c == '-' => self.integer(),
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+
+        if self.input[self.pos..].starts_with("\"\"") {
+            return self.error("multi-line strings are not supported");
+        }
+
+        let mut out = String::new();
+
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('"') => return Ok(out),
+                Some('\\') => match self.bump() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some(c @ ('u' | 'U')) => {
+                        let len = if c == 'u' { 4 } else { 8 };
+                        let digits = self.input.get(self.pos..self.pos + len);
+
+                        let Some(c) = digits
+                            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                            .and_then(char::from_u32)
+                        else {
+                            return self.error("invalid unicode escape");
+                        };
+
+                        self.pos += len;
+                        out.push(c);
+                    }
+                    _ => return self.error("invalid escape"),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let start = self.pos;
+
+        loop {
+            match self.bump() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('\'') => return Ok(self.input[start..self.pos - 1].to_owned()),
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '_'))
+        {
+            self.bump();
+        }
+
+        if matches!(self.peek(), Some('.' | 'e' | 'E')) {
+            return self.error("floats are not supported");
+        }
+
+        match self.input[start..self.pos].replace('_', "").parse() {
+            Ok(n) => Ok(Value::Integer(n)),
+            Err(_) => self.error("invalid integer"),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+
+        loop {
+            self.skip_blank();
+
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+
+            values.push(self.value()?);
+            self.skip_blank();
+
+            if !self.eat(',') {
+                self.skip_blank();
+                self.expect(']')?;
+                return Ok(Value::Array(values));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::default();
+        self.skip_whitespace();
+
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let line = self.line;
+            let key = self.key()?;
+            self.skip_whitespace();
+            self.expect('=')?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            table.insert(Entry { key, value, line })?;
+            self.skip_whitespace();
+
+            if !self.eat(',') {
+                self.expect('}')?;
+                return Ok(Value::Table(table));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, value: Value, line: usize) -> Entry {
+        Entry {
+            key: key.to_owned(),
+            value,
+            line,
+        }
+    }
+
+    #[test]
+    fn parses_documents() {
+        let table = parse(
+            r#"
+            # Comment
+            name = "a \"b\"\u00e9" # Trailing comment
+            'literal' = 'C:\path'
+            n = -1_000
+            flags = [true, false,
+                # Comment
+            ]
+
+            [table]
+            inline = { "x-a" = "b", c = 1 }
+
+            [[items]]
+            a = 1
+
+            [[items]]
+            a = 2
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            table.0,
+            vec![
+                entry("name", Value::String("a \"b\"é".to_owned()), 3),
+                entry("literal", Value::String(r"C:\path".to_owned()), 4),
+                entry("n", Value::Integer(-1000), 5),
+                entry(
+                    "flags",
+                    Value::Array(vec![Value::Boolean(true), Value::Boolean(false)]),
+                    6
+                ),
+                entry(
+                    "table",
+                    Value::Table(Table(vec![entry(
+                        "inline",
+                        Value::Table(Table(vec![
+                            entry("x-a", Value::String("b".to_owned()), 11),
+                            entry("c", Value::Integer(1), 11),
+                        ])),
+                        11
+                    )])),
+                    10
+                ),
+                entry(
+                    "items",
+                    Value::Array(vec![
+                        Value::Table(Table(vec![entry("a", Value::Integer(1), 14)])),
+                        Value::Table(Table(vec![entry("a", Value::Integer(2), 17)])),
+                    ]),
+                    13
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        for (input, line) in [
+            ("a = ", 1),
+            ("a = \"b", 1),
+            ("a = 1\na = 2", 2),
+            ("a.b = 1", 1),
+            ("a = 1.5", 1),
+            ("\n[table\n", 2),
+            ("a = 1 b = 2", 1),
+            ("a = [1, 2", 1),
+        ] {
+            let err = parse(input).unwrap_err();
+            assert_eq!(err.line, line, "{:?}: {}", input, err);
+        }
+    }
+}
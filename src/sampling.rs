@@ -0,0 +1,399 @@
+//! Sampling of the traffic that is intercepted.
+//!
+//! [`SamplingHandler`] passes only a fraction of the eligible traffic to the handler it wraps, and
+//! passes the rest through untouched, so that high-volume deployments can collect representative
+//! data without decrypting everything.
+
+use crate::{
+    auth::host, Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use hyper::{Method, Request, Response};
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// What is sampled by a [`SamplingHandler`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum SampleUnit {
+    /// Sample `CONNECT` tunnels. Tunnels that the wrapped handler would intercept are only
+    /// intercepted if they are sampled, and are tunneled without being decrypted otherwise. Every
+    /// request is passed to the wrapped handler.
+    #[default]
+    Connection,
+    /// Sample requests. Requests that are not sampled, and their responses, are forwarded without
+    /// being passed to the wrapped handler. Tunnels are intercepted whenever the wrapped handler
+    /// would intercept them.
+    Flow,
+}
+
+/// What decides whether a connection or flow is sampled.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum SampleKey {
+    /// Sample each connection or flow at random.
+    #[default]
+    Random,
+    /// Sample by the host of the request, so that either all or none of the traffic for a host is
+    /// sampled.
+    Host,
+    /// Sample by the IP address of the client, so that either all or none of the traffic of a
+    /// client is sampled.
+    ClientAddr,
+}
+
+/// An HTTP handler that passes only a sample of the traffic to another handler.
+///
+/// The sample is taken at the configured rate, which is the fraction of the eligible connections
+/// or flows that are sampled. A rate of `0.1` samples roughly 10% of them. Decisions by
+/// [`SampleKey::Host`] and [`SampleKey::ClientAddr`] are deterministic, so the same hosts or
+/// clients are sampled by every proxy with the same rate.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::sampling::{SampleKey, SampleUnit, SamplingHandler};
+///
+/// // Decrypt the tunnels for roughly 5% of hosts.
+/// let handler = SamplingHandler::new(0.05).with_key(SampleKey::Host);
+///
+/// // Pass a random 1% of requests to the wrapped handler.
+/// let handler = SamplingHandler::new(0.01).with_unit(SampleUnit::Flow);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SamplingHandler<H = NoopHandler> {
+    threshold: u64,
+    unit: SampleUnit,
+    key: SampleKey,
+    random: Arc<(RandomState, AtomicU64)>,
+    /// Whether the current flow is passed to the wrapped handler.
+    sampled: Option<bool>,
+    inner: H,
+}
+
+impl SamplingHandler {
+    /// Creates a new handler that samples connections at random at a rate between `0.0` and
+    /// `1.0`.
+    pub fn new(rate: f64) -> Self {
+        Self::with_handler(rate, NoopHandler::new())
+    }
+}
+
+impl<H> SamplingHandler<H> {
+    /// Creates a new handler that passes a sample of the traffic to `inner`.
+    pub fn with_handler(rate: f64, inner: H) -> Self {
+        let rate = if rate.is_nan() {
+            0.0
+        } else {
+            rate.clamp(0.0, 1.0)
+        };
+
+        Self {
+            threshold: (rate * u64::MAX as f64) as u64,
+            unit: SampleUnit::default(),
+            key: SampleKey::default(),
+            random: Arc::new((RandomState::new(), AtomicU64::new(0))),
+            sampled: None,
+            inner,
+        }
+    }
+
+    /// Set what is sampled.
+    pub fn with_unit(mut self, unit: SampleUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Set what decides whether a connection or flow is sampled.
+    pub fn with_key(mut self, key: SampleKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Whether a connection or flow is sampled.
+    fn sample<T>(&self, ctx: &HttpContext, req: &Request<T>) -> bool {
+        if self.threshold == u64::MAX {
+            return true;
+        }
+
+        let hash = match self.key {
+            SampleKey::Random => {
+                let (state, counter) = &*self.random;
+                state.hash_one(counter.fetch_add(1, Ordering::Relaxed))
+            }
+            SampleKey::Host => {
+                let host = req
+                    .uri()
+                    .host()
+                    .or_else(|| host(req))
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                stable_hash(host.as_bytes())
+            }
+            SampleKey::ClientAddr => stable_hash(ctx.client_addr.ip().to_string().as_bytes()),
+        };
+
+        hash < self.threshold
+    }
+
+    /// Whether the current flow is passed to the wrapped handler, deciding it for the first hook
+    /// of the flow.
+    fn is_sampled(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        if self.unit == SampleUnit::Connection || req.method() == Method::CONNECT {
+            return true;
+        }
+
+        match self.sampled {
+            Some(sampled) => sampled,
+            None => {
+                let sampled = self.sample(ctx, req);
+                self.sampled = Some(sampled);
+                sampled
+            }
+        }
+    }
+
+    /// Whether the hooks after the request are passed to the wrapped handler.
+    fn is_flow_sampled(&self) -> bool {
+        self.sampled.unwrap_or(true)
+    }
+}
+
+/// A hash that is the same across processes, so that deterministic decisions are shared by every
+/// proxy.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    // FNV-1a, followed by the SplitMix64 finalizer to spread similar keys across the range.
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash: u64, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    });
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+impl<H: HttpHandler> HttpHandler for SamplingHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        if self.is_sampled(ctx, &req) {
+            self.inner.handle_request(ctx, req).await
+        } else {
+            req.into()
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        if self.is_sampled(ctx, req) {
+            self.inner.handle_expect_continue(ctx, req).await
+        } else {
+            None
+        }
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        if self.is_flow_sampled() {
+            self.inner.inspect_informational(ctx, res).await
+        } else {
+            Some(res)
+        }
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        if self.is_flow_sampled() {
+            self.inner.handle_response(ctx, res).await
+        } else {
+            res
+        }
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        if self.is_flow_sampled() {
+            self.inner.handle_body_limit_exceeded(ctx, direction).await
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        if self.is_flow_sampled() {
+            self.inner.handle_error(ctx, err).await
+        } else {
+            NoopHandler::new().handle_error(ctx, err).await
+        }
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        if self.is_flow_sampled() {
+            self.inner.handle_circuit_open(ctx, host).await
+        } else {
+            NoopHandler::new().handle_circuit_open(ctx, host).await
+        }
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        if !self.inner.should_intercept(ctx, req).await {
+            return false;
+        }
+
+        self.unit == SampleUnit::Flow || self.sample(ctx, req)
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        if self.is_flow_sampled() {
+            self.inner.handle_dns_query(ctx, query).await
+        } else {
+            query
+        }
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        if self.is_flow_sampled() {
+            self.inner.handle_dns_response(ctx, res).await
+        } else {
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use hyper::StatusCode;
+
+    fn ctx(client_addr: &str) -> HttpContext {
+        HttpContext {
+            client_addr: client_addr.parse().unwrap(),
+            flow_id: 0,
+            extensions: Default::default(),
+            downstream_version: hyper::Version::HTTP_11,
+            upstream_version: None,
+        }
+    }
+
+    fn connect(authority: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::CONNECT)
+            .uri(authority)
+            .body(Body::from(Empty::new()))
+            .unwrap()
+    }
+
+    #[derive(Clone, Default)]
+    struct TeapotHandler;
+
+    impl HttpHandler for TeapotHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            _req: Request<Body>,
+        ) -> RequestOrResponse {
+            Response::builder()
+                .status(StatusCode::IM_A_TEAPOT)
+                .body(Body::from(Empty::new()))
+                .unwrap()
+                .into()
+        }
+    }
+
+    #[tokio::test]
+    async fn samples_connections_by_host() {
+        let ctx = ctx("127.0.0.1:8080");
+        let handler = SamplingHandler::new(0.5).with_key(SampleKey::Host);
+
+        let mut sampled = 0;
+
+        for i in 0..1000 {
+            let req = connect(&format!("host{}.example:443", i));
+            let decision = handler.clone().should_intercept(&ctx, &req).await;
+
+            assert_eq!(handler.clone().should_intercept(&ctx, &req).await, decision);
+            sampled += usize::from(decision);
+        }
+
+        assert!((400..600).contains(&sampled), "{}", sampled);
+    }
+
+    #[tokio::test]
+    async fn samples_all_or_nothing() {
+        let ctx = ctx("127.0.0.1:8080");
+        let req = connect("example.com:443");
+
+        assert!(SamplingHandler::new(1.0).should_intercept(&ctx, &req).await);
+        assert!(!SamplingHandler::new(0.0).should_intercept(&ctx, &req).await);
+        assert!(
+            SamplingHandler::new(0.0)
+                .with_unit(SampleUnit::Flow)
+                .should_intercept(&ctx, &req)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_unsampled_flows_through() {
+        let ctx = ctx("127.0.0.1:8080");
+        let req = || {
+            Request::builder()
+                .uri("http://example.com/")
+                .body(Body::from(Empty::new()))
+                .unwrap()
+        };
+
+        let mut handler =
+            SamplingHandler::with_handler(0.0, TeapotHandler).with_unit(SampleUnit::Flow);
+        assert!(matches!(
+            handler.handle_request(&ctx, req()).await,
+            RequestOrResponse::Request(_)
+        ));
+
+        let mut handler =
+            SamplingHandler::with_handler(1.0, TeapotHandler).with_unit(SampleUnit::Flow);
+        assert!(matches!(
+            handler.handle_request(&ctx, req()).await,
+            RequestOrResponse::Response(res) if res.status() == StatusCode::IM_A_TEAPOT
+        ));
+
+        let mut handler = SamplingHandler::with_handler(0.0, TeapotHandler);
+        assert!(matches!(
+            handler.handle_request(&ctx, req()).await,
+            RequestOrResponse::Response(_)
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+//! Content sniffing for interception decisions.
+//!
+//! [`sniff`] recognizes common binary formats from the first bytes of a body, so that handlers
+//! can skip buffering payloads that they are not interested in, even when the `Content-Type`
+//! header is missing or wrong. Only formats with a reliable signature are recognized, so `None`
+//! does not mean that a body is text.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hudsucker::{
+//!     hyper::Response,
+//!     sniff,
+//!     Body, HttpContext, HttpHandler,
+//! };
+//!
+//! #[derive(Clone)]
+//! struct SkipMedia;
+//!
+//! impl HttpHandler for SkipMedia {
+//!     async fn handle_response(
+//!         &mut self,
+//!         _ctx: &HttpContext,
+//!         res: Response<Body>,
+//!     ) -> Response<Body> {
+//!         let (parts, body) = res.into_parts();
+//!
+//!         let Ok((kind, body)) = sniff::sniff_body(body).await else {
+//!             return Response::from_parts(parts, Body::from(""));
+//!         };
+//!
+//!         if kind.is_some_and(|kind| kind.is_image() || kind.is_video()) {
+//!             return Response::from_parts(parts, body);
+//!         }
+//!
+//!         // Buffer and inspect the body
+//!
+//!         Response::from_parts(parts, body)
+//!     }
+//! }
+//! ```
+
+use crate::{Body, Error};
+
+/// The number of bytes that [`sniff_body`] reads before sniffing a body.
+pub const SNIFF_LEN: usize = 16;
+
+/// A format recognized by [`sniff`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Kind {
+    /// A PNG image.
+    Png,
+    /// A JPEG image.
+    Jpeg,
+    /// A GIF image.
+    Gif,
+    /// A WebP image.
+    Webp,
+    /// A BMP image.
+    Bmp,
+    /// An ICO image.
+    Ico,
+    /// An AVIF image.
+    Avif,
+    /// An MP4 video, or another ISO base media file.
+    Mp4,
+    /// A WebM or Matroska video.
+    Webm,
+    /// An Ogg container.
+    Ogg,
+    /// A ZIP archive.
+    Zip,
+    /// A gzip stream.
+    Gzip,
+    /// A gRPC message, which is a length-prefixed protobuf message.
+    Protobuf,
+    /// A TLS record.
+    Tls,
+}
+
+impl Kind {
+    /// The media type of the format.
+    pub fn mime(self) -> &'static str {
+        match self {
+            Kind::Png => "image/png",
+            Kind::Jpeg => "image/jpeg",
+            Kind::Gif => "image/gif",
+            Kind::Webp => "image/webp",
+            Kind::Bmp => "image/bmp",
+            Kind::Ico => "image/x-icon",
+            Kind::Avif => "image/avif",
+            Kind::Mp4 => "video/mp4",
+            Kind::Webm => "video/webm",
+            Kind::Ogg => "application/ogg",
+            Kind::Zip => "application/zip",
+            Kind::Gzip => "application/gzip",
+            Kind::Protobuf => "application/grpc",
+            Kind::Tls => "application/octet-stream",
+        }
+    }
+
+    /// Whether the format is an image format.
+    pub fn is_image(self) -> bool {
+        matches!(
+            self,
+            Kind::Png | Kind::Jpeg | Kind::Gif | Kind::Webp | Kind::Bmp | Kind::Ico | Kind::Avif
+        )
+    }
+
+    /// Whether the format is a video or audio container.
+    pub fn is_video(self) -> bool {
+        matches!(self, Kind::Mp4 | Kind::Webm | Kind::Ogg)
+    }
+
+    /// Whether the format is a compressed archive or stream.
+    pub fn is_archive(self) -> bool {
+        matches!(self, Kind::Zip | Kind::Gzip)
+    }
+}
+
+/// Recognizes the format of a body from its first bytes.
+///
+/// At least [`SNIFF_LEN`] bytes should be passed if the body is that long, as some formats can not
+/// be recognized from fewer bytes.
+pub fn sniff(data: &[u8]) -> Option<Kind> {
+    const SIGNATURES: &[(&[u8], Kind)] = &[
+        (b"\x89PNG\r\n\x1a\n", Kind::Png),
+        (b"\xff\xd8\xff", Kind::Jpeg),
+        (b"GIF87a", Kind::Gif),
+        (b"GIF89a", Kind::Gif),
+        (b"\x00\x00\x01\x00", Kind::Ico),
+        (b"\x1a\x45\xdf\xa3", Kind::Webm),
+        (b"OggS\x00", Kind::Ogg),
+        (b"PK\x03\x04", Kind::Zip),
+        (b"PK\x05\x06", Kind::Zip),
+        (b"PK\x07\x08", Kind::Zip),
+        (b"\x1f\x8b\x08", Kind::Gzip),
+    ];
+
+    if let Some((_, kind)) = SIGNATURES.iter().find(|(sig, _)| data.starts_with(sig)) {
+        return Some(*kind);
+    }
+
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return Some(Kind::Webp);
+    }
+
+    if data.len() >= 10 && data.starts_with(b"BM") && data[6..10] == [0; 4] {
+        return Some(Kind::Bmp);
+    }
+
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return Some(match &data[8..12] {
+            b"avif" | b"avis" => Kind::Avif,
+            _ => Kind::Mp4,
+        });
+    }
+
+    if is_tls(data) {
+        return Some(Kind::Tls);
+    }
+
+    if is_grpc(data) {
+        return Some(Kind::Protobuf);
+    }
+
+    None
+}
+
+/// Whether the data starts with a TLS record header.
+fn is_tls(data: &[u8]) -> bool {
+    match data {
+        [content_type, 3, minor, _, _, ..] => (0x14..=0x17).contains(content_type) && *minor <= 4,
+        _ => false,
+    }
+}
+
+/// Whether the data starts with a gRPC message header, followed by a valid protobuf tag.
+fn is_grpc(data: &[u8]) -> bool {
+    let [flag, a, b, c, d, tag, ..] = *data else {
+        return false;
+    };
+
+    let len = u32::from_be_bytes([a, b, c, d]);
+
+    flag <= 1 && len > 0 && tag >> 3 != 0 && matches!(tag & 0x7, 0 | 1 | 2 | 5)
+}
+
+/// Recognizes the format of a body from its first bytes, without consuming it.
+///
+/// At least [`SNIFF_LEN`] bytes of the body are read, and the returned body yields the same data
+/// as the original body.
+///
+/// # Errors
+///
+/// Returns an error if the body fails before enough of it has been read.
+pub async fn sniff_body(body: Body) -> Result<(Option<Kind>, Body), Error> {
+    let (data, body) = body.peek(SNIFF_LEN).await?;
+    Ok((sniff(&data), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn sniffs_formats() {
+        for (data, kind) in [
+            (&b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"[..], Kind::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", Kind::Jpeg),
+            (b"GIF89a\x01\0\x01\0", Kind::Gif),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Kind::Webp),
+            (b"BM\x36\0\0\0\0\0\0\0\x36\0", Kind::Bmp),
+            (b"\0\0\0\x20ftypisom\0\0\x02\0", Kind::Mp4),
+            (b"\0\0\0\x1cftypavif\0\0\0\0", Kind::Avif),
+            (b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81", Kind::Webm),
+            (b"PK\x03\x04\x14\0\0\0", Kind::Zip),
+            (b"\x1f\x8b\x08\0\0\0\0\0", Kind::Gzip),
+            (b"\x16\x03\x01\x02\x00\x01\x00\x01", Kind::Tls),
+            (b"\0\0\0\0\x07\x0a\x05hello", Kind::Protobuf),
+        ] {
+            assert_eq!(sniff(data), Some(kind), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn ignores_text() {
+        for data in [
+            &b""[..],
+            b"{\"a\": 1}",
+            b"<!DOCTYPE html>",
+            b"hello world",
+            b"\0\0\0\0\0\0",
+        ] {
+            assert_eq!(sniff(data), None, "{:?}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn sniffs_body_without_consuming_it() {
+        let body = Body::from(&b"\x89PNG\r\n\x1a\n more data"[..]);
+
+        let (kind, body) = sniff_body(body).await.unwrap();
+        assert_eq!(kind, Some(Kind::Png));
+        assert_eq!(
+            body.collect().await.unwrap().to_bytes(),
+            &b"\x89PNG\r\n\x1a\n more data"[..]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{
+    Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse, TlsFailure,
+};
+use hyper::{http::uri::Authority, Request, Response};
+use std::future::Future;
+
+/// Handler for the request side of HTTP traffic.
+///
+/// This is the half of [`HttpHandler`] that is called before a request is forwarded, for tools
+/// that only need to see requests. Every method has a default implementation that behaves like
+/// [`NoopHandler`], so only the hooks that are needed have to be implemented. It is combined with
+/// a [`ResponseHandler`] by [`SplitHandler`], or by
+/// [`ProxyBuilder::with_request_handler`](crate::ProxyBuilder::with_request_handler).
+pub trait RequestHandler: Clone + Send + Sync + 'static {
+    /// This handler will be called for each HTTP request. See [`HttpHandler::handle_request`].
+    fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> impl Future<Output = RequestOrResponse> + Send {
+        async { req.into() }
+    }
+
+    /// This handler will be called for each HTTP request that has an `Expect: 100-continue`
+    /// header. See [`HttpHandler::handle_expect_continue`].
+    fn handle_expect_continue(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+    ) -> impl Future<Output = Option<Response<Body>>> + Send {
+        async { None }
+    }
+
+    /// This handler will be called when a request body exceeds the size limit set with
+    /// [`ProxyBuilder::with_max_request_body_size`](crate::ProxyBuilder::with_max_request_body_size).
+    fn handle_body_limit_exceeded(
+        &mut self,
+        _ctx: &HttpContext,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called instead of forwarding a request when the circuit breaker is
+    /// open for the request's host. See [`HttpHandler::handle_circuit_open`].
+    fn handle_circuit_open(
+        &mut self,
+        ctx: &HttpContext,
+        host: &str,
+    ) -> impl Future<Output = Response<Body>> + Send {
+        async move { HttpHandler::handle_circuit_open(&mut NoopHandler::new(), ctx, host).await }
+    }
+
+    /// Whether a CONNECT request should be intercepted. See [`HttpHandler::should_intercept`].
+    fn should_intercept(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+    ) -> impl Future<Output = bool> + Send {
+        async { true }
+    }
+
+    /// This handler will be called for each CONNECT request before its tunnel is established. See
+    /// [`HttpHandler::handle_connect_target`].
+    fn handle_connect_target(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+        authority: Authority,
+    ) -> impl Future<Output = Authority> + Send {
+        async { authority }
+    }
+
+    /// This handler will be called when the TLS handshake of an intercepted tunnel fails. See
+    /// [`HttpHandler::handle_tls_failure`].
+    fn handle_tls_failure(
+        &mut self,
+        _ctx: &HttpContext,
+        _failure: &TlsFailure,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called for each DNS query. See [`HttpHandler::handle_dns_query`].
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    fn handle_dns_query(
+        &mut self,
+        _ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> impl Future<Output = crate::dns::Message> + Send {
+        async { query }
+    }
+}
+
+/// Handler for the response side of HTTP traffic.
+///
+/// This is the half of [`HttpHandler`] that is called once a response has been received, or
+/// could not be received, for tools that only need to see responses. Every method has a default
+/// implementation that behaves like [`NoopHandler`], so only the hooks that are needed have to be
+/// implemented. It is combined with a [`RequestHandler`] by [`SplitHandler`], or by
+/// [`ProxyBuilder::with_response_handler`](crate::ProxyBuilder::with_response_handler).
+pub trait ResponseHandler: Clone + Send + Sync + 'static {
+    /// This handler will be called for each informational (1xx) response received from an HTTP/1
+    /// upstream server. See [`HttpHandler::inspect_informational`].
+    fn inspect_informational(
+        &mut self,
+        _ctx: &HttpContext,
+        res: Response<()>,
+    ) -> impl Future<Output = Option<Response<()>>> + Send {
+        async { Some(res) }
+    }
+
+    /// This handler will be called for each HTTP response. See [`HttpHandler::handle_response`].
+    fn handle_response(
+        &mut self,
+        _ctx: &HttpContext,
+        res: Response<Body>,
+    ) -> impl Future<Output = Response<Body>> + Send {
+        async { res }
+    }
+
+    /// This handler will be called when a response body exceeds the size limit set with
+    /// [`ProxyBuilder::with_max_response_body_size`](crate::ProxyBuilder::with_max_response_body_size).
+    fn handle_body_limit_exceeded(
+        &mut self,
+        _ctx: &HttpContext,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// This handler will be called if a proxy request fails. Default response is a 502 Bad
+    /// Gateway. See [`HttpHandler::handle_error`].
+    fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> impl Future<Output = Response<Body>> + Send {
+        async move { HttpHandler::handle_error(&mut NoopHandler::new(), ctx, err).await }
+    }
+
+    /// This handler will be called for each DNS response. See
+    /// [`HttpHandler::handle_dns_response`].
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    fn handle_dns_response(
+        &mut self,
+        _ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> impl Future<Output = crate::dns::Message> + Send {
+        async { res }
+    }
+}
+
+impl RequestHandler for NoopHandler {}
+impl ResponseHandler for NoopHandler {}
+
+/// An HTTP handler that passes requests to one handler and responses to another.
+///
+/// The two handlers may be of different types, and either of them may be a [`NoopHandler`], so
+/// tools that only intercept one side of the traffic do not have to implement the other.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     hyper::Request, Body, HttpContext, NoopHandler, RequestHandler, RequestOrResponse,
+///     SplitHandler,
+/// };
+///
+/// #[derive(Clone)]
+/// struct LogRequests;
+///
+/// impl RequestHandler for LogRequests {
+///     async fn handle_request(&mut self, _ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+///         println!("{} {}", req.method(), req.uri());
+///         req.into()
+///     }
+/// }
+///
+/// let handler = SplitHandler::new(LogRequests, NoopHandler::new());
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SplitHandler<Req, Res> {
+    request: Req,
+    response: Res,
+}
+
+impl<Req, Res> SplitHandler<Req, Res> {
+    /// Creates a new handler that passes requests to `request` and responses to `response`.
+    pub fn new(request: Req, response: Res) -> Self {
+        Self { request, response }
+    }
+
+    /// Returns the request handler.
+    pub fn request_handler(&self) -> &Req {
+        &self.request
+    }
+
+    /// Returns the response handler.
+    pub fn response_handler(&self) -> &Res {
+        &self.response
+    }
+
+    /// Replace the request handler.
+    pub fn with_request_handler<Req2>(self, request: Req2) -> SplitHandler<Req2, Res> {
+        SplitHandler::new(request, self.response)
+    }
+
+    /// Replace the response handler.
+    pub fn with_response_handler<Res2>(self, response: Res2) -> SplitHandler<Req, Res2> {
+        SplitHandler::new(self.request, response)
+    }
+}
+
+impl<Req: RequestHandler, Res: ResponseHandler> HttpHandler for SplitHandler<Req, Res> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.request.handle_request(ctx, req).await
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.request.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.response.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.response.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        match direction {
+            BodyDirection::Request => self.request.handle_body_limit_exceeded(ctx).await,
+            BodyDirection::Response => self.response.handle_body_limit_exceeded(ctx).await,
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.response.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.request.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.request.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: Authority,
+    ) -> Authority {
+        self.request
+            .handle_connect_target(ctx, req, authority)
+            .await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        self.request.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.request.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.response.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use hyper::StatusCode;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+    impl RequestHandler for Recorder {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.0.lock().unwrap().push("request");
+            req.into()
+        }
+
+        async fn handle_body_limit_exceeded(&mut self, _ctx: &HttpContext) {
+            self.0.lock().unwrap().push("request limit");
+        }
+
+        async fn handle_tls_failure(&mut self, _ctx: &HttpContext, _failure: &TlsFailure) {
+            self.0.lock().unwrap().push("tls failure");
+        }
+    }
+
+    impl ResponseHandler for Recorder {
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            res: Response<Body>,
+        ) -> Response<Body> {
+            self.0.lock().unwrap().push("response");
+            res
+        }
+
+        async fn handle_body_limit_exceeded(&mut self, _ctx: &HttpContext) {
+            self.0.lock().unwrap().push("response limit");
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_each_side_to_its_handler() {
+        let requests = Recorder::default();
+        let responses = Recorder::default();
+        let mut handler = SplitHandler::new(requests.clone(), NoopHandler::new())
+            .with_response_handler(responses.clone());
+
+        let req = Request::new(Body::from(Empty::new()));
+        assert!(matches!(
+            handler.handle_request(&HttpContext::for_test(), req).await,
+            RequestOrResponse::Request(_)
+        ));
+        let res = Response::new(Body::from(Empty::new()));
+        handler.handle_response(&HttpContext::for_test(), res).await;
+        handler
+            .handle_body_limit_exceeded(&HttpContext::for_test(), BodyDirection::Request)
+            .await;
+        handler
+            .handle_body_limit_exceeded(&HttpContext::for_test(), BodyDirection::Response)
+            .await;
+        let failure = TlsFailure::client(
+            Authority::from_static("example.com:443"),
+            &std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+        );
+        handler
+            .handle_tls_failure(&HttpContext::for_test(), &failure)
+            .await;
+
+        assert_eq!(
+            *requests.0.lock().unwrap(),
+            ["request", "request limit", "tls failure"]
+        );
+        assert_eq!(*responses.0.lock().unwrap(), ["response", "response limit"]);
+    }
+
+    #[tokio::test]
+    async fn defaults_match_noop_handler() {
+        let mut handler = SplitHandler::new(NoopHandler::new(), NoopHandler::new());
+
+        let res = handler
+            .handle_circuit_open(&HttpContext::for_test(), "example.com")
+            .await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            handler
+                .should_intercept(
+                    &HttpContext::for_test(),
+                    &Request::new(Body::from(Empty::new()))
+                )
+                .await
+        );
+        let authority = Authority::from_static("example.com:443");
+        assert_eq!(
+            handler
+                .handle_connect_target(
+                    &HttpContext::for_test(),
+                    &Request::new(Body::from(Empty::new())),
+                    authority.clone()
+                )
+                .await,
+            authority
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Downgrading of HTTPS to HTTP, for security research.
+//!
+//! **This module deliberately weakens the security of the clients that use the proxy.** It exists
+//! to test how clients and sites behave when an attacker on the network strips TLS, and should not
+//! be used outside of such testing.
+//!
+//! [`SslStripHandler`] removes `Strict-Transport-Security` headers and `upgrade-insecure-requests`
+//! directives from responses, and rewrites `https://` links to `http://`. Later plain HTTP
+//! requests to the hosts of rewritten links are sent upstream over HTTPS, so the client never
+//! leaves HTTP.
+
+use crate::{
+    decode_response, Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+        LOCATION, SET_COOKIE, STRICT_TRANSPORT_SECURITY, UPGRADE_INSECURE_REQUESTS,
+    },
+    http::uri::{Authority, Scheme},
+    HeaderMap, Request, Response, StatusCode, Uri,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+const HTTPS: &[u8] = b"https://";
+
+/// Content types whose bodies have their links rewritten.
+const TEXT_TYPES: [&str; 6] = [
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xhtml+xml",
+    "application/xml",
+    "application/x-javascript",
+];
+
+/// An HTTP handler that strips HTTPS from responses.
+///
+/// See the [module documentation](self) for details. Requests are passed to the wrapped handler
+/// before they are upgraded, and responses are stripped before they are passed to the wrapped
+/// handler.
+#[cfg_attr(docsrs, doc(cfg(feature = "sslstrip")))]
+#[derive(Clone, Debug)]
+pub struct SslStripHandler<H = NoopHandler> {
+    hosts: Arc<Mutex<HashSet<String>>>,
+    inner: H,
+}
+
+impl SslStripHandler {
+    /// Creates a new SSL stripping handler.
+    pub fn new() -> Self {
+        Self {
+            hosts: Default::default(),
+            inner: NoopHandler::default(),
+        }
+    }
+}
+
+impl Default for SslStripHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> SslStripHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> SslStripHandler<H2> {
+        SslStripHandler {
+            hosts: self.hosts,
+            inner,
+        }
+    }
+
+    /// Whether plain HTTP requests for a host are being upgraded to HTTPS.
+    pub fn is_stripped(&self, host: &str) -> bool {
+        self.hosts
+            .lock()
+            .unwrap()
+            .contains(&host.to_ascii_lowercase())
+    }
+
+    fn upgrade(&self, mut req: Request<Body>) -> Request<Body> {
+        req.headers_mut().remove(UPGRADE_INSECURE_REQUESTS);
+        // Links can only be rewritten in bodies that can be decoded.
+        req.headers_mut().insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, deflate, br, zstd"),
+        );
+
+        if req.uri().scheme() != Some(&Scheme::HTTP) {
+            return req;
+        }
+
+        let Some(authority) = req.uri().authority() else {
+            return req;
+        };
+
+        if !self.is_stripped(authority.host()) {
+            return req;
+        }
+
+        let authority = match authority.port_u16() {
+            Some(80) => Authority::try_from(authority.host()).ok(),
+            _ => Some(authority.clone()),
+        };
+
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = Some(Scheme::HTTPS);
+        parts.authority = authority;
+
+        match Uri::from_parts(parts) {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(e) => warn!("Failed to upgrade request to HTTPS: {}", e),
+        }
+
+        req
+    }
+
+    async fn strip(&self, res: Response<Body>) -> Response<Body> {
+        let mut res = match decode_response(res) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Failed to decode response: {}", e);
+                return bad_gateway();
+            }
+        };
+
+        let mut hosts = HashSet::new();
+        strip_headers(res.headers_mut(), &mut hosts);
+
+        let is_text = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .is_some_and(|val| TEXT_TYPES.iter().any(|ty| val.starts_with(ty)));
+
+        if is_text {
+            let (mut parts, body) = res.into_parts();
+
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    warn!("Failed to read response body: {}", e);
+                    return bad_gateway();
+                }
+            };
+
+            let body = strip_links(&body, &mut hosts);
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            res = Response::from_parts(parts, Body::from(Full::new(body.into())));
+        }
+
+        if !hosts.is_empty() {
+            self.hosts.lock().unwrap().extend(hosts);
+        }
+
+        res
+    }
+}
+
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(Empty::new()))
+        .expect("Failed to build response")
+}
+
+fn strip_headers(headers: &mut HeaderMap, hosts: &mut HashSet<String>) {
+    headers.remove(STRICT_TRANSPORT_SECURITY);
+
+    if let Some(location) = headers.get(LOCATION) {
+        let location = strip_links(location.as_bytes(), hosts);
+
+        if let Ok(location) = HeaderValue::from_bytes(&location) {
+            headers.insert(LOCATION, location);
+        }
+    }
+
+    let policies: Vec<_> = headers
+        .get_all(CONTENT_SECURITY_POLICY)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .map(|val| {
+            val.split(';')
+                .filter(|directive| {
+                    !directive
+                        .trim()
+                        .eq_ignore_ascii_case("upgrade-insecure-requests")
+                })
+                .collect::<Vec<_>>()
+                .join(";")
+        })
+        .collect();
+
+    headers.remove(CONTENT_SECURITY_POLICY);
+    for policy in policies {
+        if let Ok(policy) = HeaderValue::from_str(&policy) {
+            headers.append(CONTENT_SECURITY_POLICY, policy);
+        }
+    }
+
+    let cookies: Vec<_> = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .map(|val| {
+            val.split(';')
+                .filter(|attr| !attr.trim().eq_ignore_ascii_case("secure"))
+                .collect::<Vec<_>>()
+                .join(";")
+        })
+        .collect();
+
+    headers.remove(SET_COOKIE);
+    for cookie in cookies {
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.append(SET_COOKIE, cookie);
+        }
+    }
+}
+
+/// Rewrite `https://` links to `http://`, recording the hosts of the rewritten links.
+fn strip_links(input: &[u8], hosts: &mut HashSet<String>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(i) = find(rest, HTTPS) {
+        out.extend_from_slice(&rest[..i]);
+        out.extend_from_slice(b"http://");
+        rest = &rest[i + HTTPS.len()..];
+
+        let end = rest
+            .iter()
+            .position(|b| !(b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.')))
+            .unwrap_or(rest.len());
+
+        if end > 0 {
+            hosts.insert(String::from_utf8_lossy(&rest[..end]).to_ascii_lowercase());
+        }
+    }
+
+    out.extend_from_slice(rest);
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+impl<H: HttpHandler> HttpHandler for SslStripHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.upgrade(req).into(),
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.strip(res).await;
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod strip_links {
+        use super::*;
+
+        #[test]
+        fn rewrites_links() {
+            let mut hosts = HashSet::new();
+            let out = strip_links(
+                br#"<a href="https://Example.com/a">x</a> <img src='HTTPS://cdn.example.com:8443/i'>"#,
+                &mut hosts,
+            );
+
+            assert_eq!(
+                out,
+                br#"<a href="http://Example.com/a">x</a> <img src='http://cdn.example.com:8443/i'>"#
+            );
+            assert!(hosts.contains("example.com"));
+            assert!(hosts.contains("cdn.example.com"));
+        }
+    }
+
+    mod strip_headers {
+        use super::*;
+
+        #[test]
+        fn removes_upgrades() {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_static("max-age=31536000"),
+            );
+            headers.insert(
+                CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("default-src 'self'; upgrade-insecure-requests"),
+            );
+            headers.insert(
+                SET_COOKIE,
+                HeaderValue::from_static("id=1; Secure; HttpOnly"),
+            );
+            headers.insert(LOCATION, HeaderValue::from_static("https://example.com/"));
+
+            let mut hosts = HashSet::new();
+            strip_headers(&mut headers, &mut hosts);
+
+            assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+            assert_eq!(headers[CONTENT_SECURITY_POLICY], "default-src 'self'");
+            assert_eq!(headers[SET_COOKIE], "id=1; HttpOnly");
+            assert_eq!(headers[LOCATION], "http://example.com/");
+            assert!(hosts.contains("example.com"));
+        }
+    }
+
+    mod ssl_strip_handler {
+        use super::*;
+
+        #[tokio::test]
+        async fn upgrades_stripped_hosts() {
+            let mut handler = SslStripHandler::new();
+
+            let res = Response::builder()
+                .header(CONTENT_TYPE, "text/html")
+                .body(Body::from("<a href=\"https://example.com/login\">"))
+                .unwrap();
+            let res = handler.handle_response(&HttpContext::for_test(), res).await;
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "<a href=\"http://example.com/login\">");
+
+            let req = Request::builder()
+                .uri("http://example.com:80/login")
+                .body(Body::from(Empty::new()))
+                .unwrap();
+
+            match handler.handle_request(&HttpContext::for_test(), req).await {
+                RequestOrResponse::Request(req) => {
+                    assert_eq!(req.uri(), "https://example.com/login")
+                }
+                RequestOrResponse::Response(_) => panic!("expected request"),
+            }
+        }
+    }
+}
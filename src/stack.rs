@@ -0,0 +1,231 @@
+use crate::{Body, BodyDirection, HttpContext, HttpHandler, RequestOrResponse, TlsFailure};
+use hyper::{http::uri::Authority, Request, Response};
+
+/// An HTTP handler that chains two handlers, and that can be extended with more.
+///
+/// Requests are passed to the handlers in order, and responses in reverse order, so that the
+/// first handler sees each request first and each response last, like layers of middleware. If a
+/// handler responds to a request directly, the handlers after it are skipped. A handler may wrap
+/// the body of a request or response, so the handlers after it see the wrapped body.
+///
+/// The other hooks are combined as follows:
+///
+/// - [`HttpHandler::handle_expect_continue`] and [`HttpHandler::inspect_informational`] are called
+///   in order until a handler returns a response or drops an informational response.
+/// - [`HttpHandler::handle_body_limit_exceeded`] and [`HttpHandler::handle_tls_failure`] are
+///   called for every handler.
+/// - [`HttpHandler::handle_error`] and [`HttpHandler::handle_circuit_open`] are only called for
+///   the last handler, and their responses are not passed to the other handlers.
+/// - [`HttpHandler::should_intercept`] is called in order until a handler returns `false`, and a
+///   tunnel is only intercepted if every handler returns `true`.
+/// - [`HttpHandler::handle_connect_target`] is called in order with the authority returned by
+///   the handler before.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{HandlerStack, LogHandler, NoopHandler};
+///
+/// let handler = HandlerStack::new(LogHandler::new(), NoopHandler::new()).push(LogHandler::new());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HandlerStack<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> HandlerStack<A, B> {
+    /// Creates a new stack that passes requests to `first` and then to `second`.
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    /// Add a handler to the end of the stack.
+    pub fn push<C>(self, next: C) -> HandlerStack<Self, C> {
+        HandlerStack::new(self, next)
+    }
+}
+
+impl<A: HttpHandler, B: HttpHandler> HttpHandler for HandlerStack<A, B> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.first.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.second.handle_request(ctx, req).await,
+            res => res,
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        match self.first.handle_expect_continue(ctx, req).await {
+            Some(res) => Some(res),
+            None => self.second.handle_expect_continue(ctx, req).await,
+        }
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        let res = self.first.inspect_informational(ctx, res).await?;
+        self.second.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = self.second.handle_response(ctx, res).await;
+        self.first.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.first.handle_body_limit_exceeded(ctx, direction).await;
+        self.second.handle_body_limit_exceeded(ctx, direction).await;
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.second.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.second.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.first.should_intercept(ctx, req).await && self.second.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: Authority,
+    ) -> Authority {
+        let authority = self.first.handle_connect_target(ctx, req, authority).await;
+        self.second.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        self.first.handle_tls_failure(ctx, failure).await;
+        self.second.handle_tls_failure(ctx, failure).await;
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        let query = self.first.handle_dns_query(ctx, query).await;
+        self.second.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        let res = self.second.handle_dns_response(ctx, res).await;
+        self.first.handle_dns_response(ctx, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        respond: bool,
+    }
+
+    impl HttpHandler for Recorder {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("request {}", self.name));
+
+            if self.respond {
+                return RequestOrResponse::respond(StatusCode::FORBIDDEN, "Forbidden");
+            }
+
+            req.into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            res: Response<Body>,
+        ) -> Response<Body> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("response {}", self.name));
+            res
+        }
+    }
+
+    fn stack(respond: bool) -> (impl HttpHandler, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorder = |name, respond| Recorder {
+            name,
+            calls: Arc::clone(&calls),
+            respond,
+        };
+        let stack = HandlerStack::new(recorder("a", false), recorder("b", respond))
+            .push(recorder("c", false));
+
+        (stack, calls)
+    }
+
+    #[tokio::test]
+    async fn passes_requests_in_order_and_responses_in_reverse() {
+        let (mut stack, calls) = stack(false);
+
+        let req = stack
+            .handle_request(&HttpContext::for_test(), Request::new(Body::from("")))
+            .await;
+        assert!(matches!(req, RequestOrResponse::Request(_)));
+        stack
+            .handle_response(&HttpContext::for_test(), Response::new(Body::from("")))
+            .await;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "request a",
+                "request b",
+                "request c",
+                "response c",
+                "response b",
+                "response a"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_handlers_after_a_response() {
+        let (mut stack, calls) = stack(true);
+
+        let res = stack
+            .handle_request(&HttpContext::for_test(), Request::new(Body::from("")))
+            .await;
+        assert!(matches!(res, RequestOrResponse::Response(_)));
+        assert_eq!(*calls.lock().unwrap(), ["request a", "request b"]);
+    }
+}
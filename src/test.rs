@@ -0,0 +1,388 @@
+//! Utilities for testing code that runs through a proxy.
+//!
+//! [`TestProxy`] starts a proxy on an ephemeral port with a throwaway [`TestCa`], and provides a
+//! client that sends requests through the proxy and trusts the CA. [`EchoServer`] is an origin
+//! server that echoes requests back to the client.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::test::{EchoServer, TestProxy};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let proxy = TestProxy::start().await?;
+//! let server = EchoServer::start_https(proxy.ca()).await?;
+//!
+//! let res = proxy
+//!     .client()
+//!     .post(server.url("/echo"))
+//!     .body("hello")
+//!     .send()
+//!     .await?;
+//!
+//! assert_eq!(res.headers()["x-echo-method"], "POST");
+//! assert_eq!(res.text().await?, "hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    certificate_authority::{CertificateAuthority, RcgenAuthority},
+    rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose},
+    rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore},
+    Body, HttpHandler, NoopHandler, Proxy, WebSocketHandler,
+};
+use http_body_util::Empty;
+use hyper::{
+    body::Incoming, header::HeaderName, service::service_fn, Request, Response, StatusCode,
+};
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::{
+    client::legacy::Client,
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::oneshot};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::Connector;
+use tracing::debug;
+
+/// A throwaway certificate authority.
+///
+/// Each CA has a newly generated key pair, and should only be trusted by test clients.
+#[derive(Clone, Debug)]
+pub struct TestCa {
+    key_pem: String,
+    cert_pem: String,
+    cert_der: CertificateDer<'static>,
+}
+
+impl TestCa {
+    /// Generates a new certificate authority.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the CA certificate cannot be generated.
+    pub fn generate() -> Self {
+        let key_pair = KeyPair::generate().expect("Failed to generate key pair");
+
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Hudsucker Test CA");
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+
+        let cert = params
+            .self_signed(&key_pair)
+            .expect("Failed to sign CA certificate");
+
+        Self {
+            key_pem: key_pair.serialize_pem(),
+            cert_pem: cert.pem(),
+            cert_der: cert.der().clone(),
+        }
+    }
+
+    /// The CA certificate, in PEM format.
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// The CA certificate, in DER format.
+    pub fn cert_der(&self) -> &CertificateDer<'static> {
+        &self.cert_der
+    }
+
+    /// Creates an authority that issues certificates signed by the CA.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the CA certificate cannot be loaded.
+    pub fn authority(&self) -> RcgenAuthority {
+        let key_pair = KeyPair::from_pem(&self.key_pem).expect("Failed to parse private key");
+        let cert = CertificateParams::from_ca_cert_pem(&self.cert_pem)
+            .expect("Failed to parse CA certificate")
+            .self_signed(&key_pair)
+            .expect("Failed to sign CA certificate");
+
+        RcgenAuthority::new(key_pair, cert, 1_000)
+    }
+
+    /// A TLS client configuration that only trusts the CA.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the CA certificate cannot be added to the root store.
+    pub fn client_config(&self) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(self.cert_der.clone())
+            .expect("Failed to add CA certificate");
+
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+}
+
+/// A proxy running on an ephemeral port.
+///
+/// The proxy intercepts HTTPS requests with a [`TestCa`], and trusts only that CA when connecting
+/// to upstream servers, so it can be used with an [`EchoServer`] started with
+/// [`EchoServer::start_https`]. The proxy is shut down when it is dropped.
+#[derive(Debug)]
+pub struct TestProxy {
+    addr: SocketAddr,
+    ca: TestCa,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl TestProxy {
+    /// Starts a proxy that does not modify requests or responses.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proxy cannot listen on an ephemeral port.
+    pub async fn start() -> io::Result<Self> {
+        Self::start_with(NoopHandler::new(), NoopHandler::new()).await
+    }
+
+    /// Starts a proxy with an HTTP handler and a WebSocket handler.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the proxy cannot listen on an ephemeral port.
+    pub async fn start_with<H, W>(http_handler: H, websocket_handler: W) -> io::Result<Self>
+    where
+        H: HttpHandler,
+        W: WebSocketHandler,
+    {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let ca = TestCa::generate();
+        let client_config = Arc::new(ca.client_config());
+        let (tx, rx) = oneshot::channel();
+
+        let https = HttpsConnectorBuilder::new()
+            .with_tls_config((*client_config).clone())
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        let proxy = Proxy::builder()
+            .with_listener(listener)
+            .with_client(Client::builder(TokioExecutor::new()).build(https))
+            .with_ca(ca.authority())
+            .with_http_handler(http_handler)
+            .with_websocket_handler(websocket_handler)
+            .with_websocket_connector(Connector::Rustls(client_config))
+            .with_graceful_shutdown(async {
+                rx.await.unwrap_or_default();
+            })
+            .build();
+
+        tokio::spawn(async move {
+            if let Err(e) = proxy.start().await {
+                debug!("Test proxy failed: {}", e);
+            }
+        });
+
+        Ok(Self {
+            addr,
+            ca,
+            _shutdown: tx,
+        })
+    }
+
+    /// The address that the proxy is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of the proxy.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The CA that the proxy uses to intercept HTTPS requests.
+    pub fn ca(&self) -> &TestCa {
+        &self.ca
+    }
+
+    /// A client that sends requests through the proxy, and trusts the proxy's CA.
+    ///
+    /// The client does not decompress responses, so that they are received as the proxy sent
+    /// them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client cannot be built.
+    pub fn client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(self.url()).expect("Failed to configure proxy"))
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(self.ca.cert_pem.as_bytes())
+                    .expect("Failed to parse CA certificate"),
+            )
+            .no_brotli()
+            .no_deflate()
+            .no_gzip()
+            .build()
+            .expect("Failed to build client")
+    }
+}
+
+/// An origin server that echoes requests.
+///
+/// Responses have the body of the request, and the headers:
+///
+/// - `x-echo-method`: the method of the request.
+/// - `x-echo-path`: the path and query of the request.
+/// - `x-echo-header-<name>`: each header of the request.
+///
+/// Requests for `/status/<code>` are answered with that status code. The server is shut down when
+/// it is dropped.
+#[derive(Debug)]
+pub struct EchoServer {
+    addr: SocketAddr,
+    https: bool,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl EchoServer {
+    /// Starts a server that accepts HTTP connections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot listen on an ephemeral port.
+    pub async fn start() -> io::Result<Self> {
+        Self::serve(None).await
+    }
+
+    /// Starts a server that accepts HTTPS connections for `localhost`, with a certificate issued
+    /// by a CA.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot listen on an ephemeral port.
+    pub async fn start_https(ca: &TestCa) -> io::Result<Self> {
+        let config = ca
+            .authority()
+            .gen_server_config(&"localhost".parse().expect("Failed to parse authority"))
+            .await;
+
+        Self::serve(Some(TlsAcceptor::from(config))).await
+    }
+
+    async fn serve(acceptor: Option<TlsAcceptor>) -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = listener.local_addr()?;
+        let https = acceptor.is_some();
+        let (tx, mut rx) = oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            let server = auto::Builder::new(TokioExecutor::new());
+
+            loop {
+                let tcp = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok((tcp, _)) => tcp,
+                        Err(e) => {
+                            debug!("Failed to accept connection: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = &mut rx => break,
+                };
+
+                let server = server.clone();
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    let res = match acceptor {
+                        Some(acceptor) => match acceptor.accept(tcp).await {
+                            Ok(tls) => {
+                                server
+                                    .serve_connection(TokioIo::new(tls), service_fn(echo))
+                                    .await
+                            }
+                            Err(e) => Err(e.into()),
+                        },
+                        None => {
+                            server
+                                .serve_connection(TokioIo::new(tcp), service_fn(echo))
+                                .await
+                        }
+                    };
+
+                    if let Err(e) = res {
+                        debug!("Echo server connection failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            https,
+            _shutdown: tx,
+        })
+    }
+
+    /// The address that the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of a path on the server.
+    pub fn url(&self, path: &str) -> String {
+        if self.https {
+            format!("https://localhost:{}{}", self.addr.port(), path)
+        } else {
+            format!("http://{}{}", self.addr, path)
+        }
+    }
+}
+
+async fn echo(req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    if let Some(status) = req
+        .uri()
+        .path()
+        .strip_prefix("/status/")
+        .and_then(|code| code.parse().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+    {
+        let mut res = Response::new(Body::from(Empty::new()));
+        *res.status_mut() = status;
+        return Ok(res);
+    }
+
+    let (parts, body) = req.into_parts();
+    let mut res = Response::new(Body::from(body));
+    let headers = res.headers_mut();
+
+    headers.insert(
+        "x-echo-method",
+        parts.method.as_str().parse().expect("Invalid method"),
+    );
+
+    if let Some(path) = parts.uri.path_and_query() {
+        if let Ok(path) = path.as_str().parse() {
+            headers.insert("x-echo-path", path);
+        }
+    }
+
+    for (name, value) in &parts.headers {
+        if let Ok(name) = HeaderName::try_from(format!("x-echo-header-{}", name)) {
+            headers.append(name, value.clone());
+        }
+    }
+
+    Ok(res)
+}
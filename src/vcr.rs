@@ -0,0 +1,704 @@
+//! Recording and replaying of upstream responses.
+//!
+//! [`VcrHandler`] records the responses to requests in a [`Cassette`] file, and can later replay
+//! the recorded responses without contacting the upstream servers. This makes it possible to run
+//! integration tests through the proxy against recorded traffic.
+//!
+//! Requests are matched with recorded responses by a fingerprint of their method, URI, and body,
+//! and optionally some of their headers. Request and response bodies are read into memory while
+//! recording and replaying.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hudsucker::vcr::{Cassette, Mode, VcrHandler};
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! // Record responses to a new cassette.
+//! let handler = VcrHandler::new(Cassette::new("cassette.vcr"), Mode::Record);
+//!
+//! // Replay the recorded responses, without forwarding unmatched requests.
+//! let handler = VcrHandler::new(Cassette::load("cassette.vcr").await?, Mode::Replay)
+//!     .with_strict(true);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Body, BodyDirection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse};
+use bstr::ByteSlice;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Request, Response, StatusCode,
+};
+use std::{
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+const MAGIC: &[u8] = b"HUDSUCKER-CASSETTE-1\n";
+
+#[derive(Clone, Debug)]
+struct Interaction {
+    fingerprint: String,
+    request: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    played: bool,
+}
+
+impl Interaction {
+    fn to_response(&self) -> Response<Body> {
+        let body = if self.body.is_empty() {
+            Body::from(Empty::new())
+        } else {
+            Body::from(Full::new(self.body.clone()))
+        };
+
+        let mut res = Response::new(body);
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+/// A file of recorded responses.
+///
+/// Clones of a cassette share the same recorded responses.
+#[derive(Clone, Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+    write: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Cassette {
+    /// Creates a new empty cassette, which will be saved to `path`.
+    ///
+    /// An existing file at `path` will be overwritten when the first response is recorded.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::with_interactions(path.into(), Vec::new())
+    }
+
+    /// Loads a cassette from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, or does not contain a cassette.
+    pub async fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let contents = tokio::fs::read(&path).await?;
+        let interactions = decode(&contents).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a valid cassette", path.display()),
+            )
+        })?;
+
+        Ok(Self::with_interactions(path, interactions))
+    }
+
+    fn with_interactions(path: PathBuf, interactions: Vec<Interaction>) -> Self {
+        Self {
+            path,
+            interactions: Arc::new(Mutex::new(interactions)),
+            write: Default::default(),
+        }
+    }
+
+    /// The path that the cassette is saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of recorded responses.
+    pub fn len(&self) -> usize {
+        self.interactions.lock().unwrap().len()
+    }
+
+    /// Whether the cassette does not contain any recorded responses.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Saves the cassette to its file.
+    ///
+    /// Cassettes are saved automatically after each response is recorded.
+    pub async fn save(&self) -> io::Result<()> {
+        let _guard = self.write.lock().await;
+        let contents = encode(&self.interactions.lock().unwrap());
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(tmp, &self.path).await
+    }
+
+    fn record(&self, interaction: Interaction) {
+        self.interactions.lock().unwrap().push(interaction);
+
+        let cassette = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cassette.save().await {
+                warn!(
+                    "Failed to save cassette to {}: {}",
+                    cassette.path.display(),
+                    e
+                );
+            }
+        });
+    }
+
+    /// Finds the next recorded response for a fingerprint. Once every response for a fingerprint
+    /// has been played, the last one is repeated.
+    fn play(&self, fingerprint: &str) -> Option<Response<Body>> {
+        let mut interactions = self.interactions.lock().unwrap();
+        let index = interactions
+            .iter()
+            .position(|interaction| interaction.fingerprint == fingerprint && !interaction.played)
+            .or_else(|| {
+                interactions
+                    .iter()
+                    .rposition(|interaction| interaction.fingerprint == fingerprint)
+            })?;
+
+        let interaction = &mut interactions[index];
+        interaction.played = true;
+        Some(interaction.to_response())
+    }
+}
+
+/// Whether a [`VcrHandler`] records or replays responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Forward requests, and record their responses.
+    Record,
+    /// Respond to requests with recorded responses.
+    Replay,
+}
+
+/// An HTTP handler that records and replays upstream responses.
+///
+/// See the [module documentation](self) for an example. Requests are passed to the wrapped handler
+/// before they are fingerprinted, and replayed responses are passed to the wrapped handler's
+/// [`HttpHandler::handle_response`].
+#[derive(Debug)]
+pub struct VcrHandler<H = NoopHandler> {
+    cassette: Cassette,
+    mode: Mode,
+    strict: bool,
+    match_headers: Arc<Vec<HeaderName>>,
+    match_body: bool,
+    inner: H,
+    pending: Option<(String, String)>,
+}
+
+impl VcrHandler {
+    /// Creates a new handler that records to or replays from a cassette.
+    pub fn new(cassette: Cassette, mode: Mode) -> Self {
+        Self {
+            cassette,
+            mode,
+            strict: false,
+            match_headers: Arc::new(Vec::new()),
+            match_body: true,
+            inner: NoopHandler::default(),
+            pending: None,
+        }
+    }
+}
+
+impl<H: Clone> Clone for VcrHandler<H> {
+    fn clone(&self) -> Self {
+        Self {
+            cassette: self.cassette.clone(),
+            mode: self.mode,
+            strict: self.strict,
+            match_headers: Arc::clone(&self.match_headers),
+            match_body: self.match_body,
+            inner: self.inner.clone(),
+            pending: None,
+        }
+    }
+}
+
+impl<H> VcrHandler<H> {
+    /// Set the handler that requests and responses are passed to.
+    pub fn with_handler<H2: HttpHandler>(self, inner: H2) -> VcrHandler<H2> {
+        VcrHandler {
+            cassette: self.cassette,
+            mode: self.mode,
+            strict: self.strict,
+            match_headers: self.match_headers,
+            match_body: self.match_body,
+            inner,
+            pending: None,
+        }
+    }
+
+    /// Respond with `501 Not Implemented` to requests without a recorded response while replaying,
+    /// instead of forwarding them.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Include the values of a header in request fingerprints.
+    pub fn with_match_header(mut self, name: HeaderName) -> Self {
+        Arc::make_mut(&mut self.match_headers).push(name);
+        self
+    }
+
+    /// Set whether request bodies are included in request fingerprints. Defaults to `true`.
+    pub fn with_match_body(mut self, match_body: bool) -> Self {
+        self.match_body = match_body;
+        self
+    }
+
+    /// The cassette that responses are recorded to or replayed from.
+    pub fn cassette(&self) -> &Cassette {
+        &self.cassette
+    }
+
+    fn fingerprint<T>(&self, req: &Request<T>, body: &[u8]) -> String {
+        let mut key = format!("{} {}\n", req.method(), req.uri());
+
+        for name in self.match_headers.iter() {
+            for value in req.headers().get_all(name) {
+                key.push_str(name.as_str());
+                key.push_str(": ");
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+                key.push('\n');
+            }
+        }
+
+        if self.match_body {
+            let _ = write!(key, "{:016x}", fnv1a(body));
+        }
+
+        format!("{:016x}", fnv1a(key.as_bytes()))
+    }
+}
+
+impl<H: HttpHandler> VcrHandler<H> {
+    async fn intercept(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.pending = None;
+
+        let (parts, body) = req.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => {
+                warn!("Failed to read request body: {}", e);
+                return status(StatusCode::BAD_REQUEST).into();
+            }
+        };
+
+        let req = Request::from_parts(parts, ());
+        let fingerprint = self.fingerprint(&req, &body);
+        let (parts, ()) = req.into_parts();
+        let req = Request::from_parts(parts, Body::from(Full::new(body)));
+
+        match self.mode {
+            Mode::Record => {
+                let request = format!("{} {}", req.method(), req.uri());
+                self.pending = Some((fingerprint, request));
+                req.into()
+            }
+            Mode::Replay => match self.cassette.play(&fingerprint) {
+                Some(res) => self.inner.handle_response(ctx, res).await.into(),
+                None if self.strict => {
+                    warn!("No recorded response for {} {}", req.method(), req.uri());
+                    status(StatusCode::NOT_IMPLEMENTED).into()
+                }
+                None => req.into(),
+            },
+        }
+    }
+
+    async fn record(
+        &self,
+        (fingerprint, request): (String, String),
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => {
+                warn!("Failed to read response to {}: {}", request, e);
+                return status(StatusCode::BAD_GATEWAY);
+            }
+        };
+
+        self.cassette.record(Interaction {
+            fingerprint,
+            request,
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            played: false,
+        });
+
+        Response::from_parts(parts, Body::from(Full::new(body)))
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for VcrHandler<H> {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        match self.inner.handle_request(ctx, req).await {
+            RequestOrResponse::Request(req) => self.intercept(ctx, req).await,
+            res => {
+                self.pending = None;
+                res
+            }
+        }
+    }
+
+    async fn handle_expect_continue(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        self.inner.handle_expect_continue(ctx, req).await
+    }
+
+    async fn inspect_informational(
+        &mut self,
+        ctx: &HttpContext,
+        res: Response<()>,
+    ) -> Option<Response<()>> {
+        self.inner.inspect_informational(ctx, res).await
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let res = match self.pending.take() {
+            Some(pending) => self.record(pending, res).await,
+            None => res,
+        };
+
+        self.inner.handle_response(ctx, res).await
+    }
+
+    async fn handle_body_limit_exceeded(&mut self, ctx: &HttpContext, direction: BodyDirection) {
+        self.inner.handle_body_limit_exceeded(ctx, direction).await
+    }
+
+    async fn handle_error(
+        &mut self,
+        ctx: &HttpContext,
+        err: hyper_util::client::legacy::Error,
+    ) -> Response<Body> {
+        self.pending = None;
+        self.inner.handle_error(ctx, err).await
+    }
+
+    async fn handle_circuit_open(&mut self, ctx: &HttpContext, host: &str) -> Response<Body> {
+        self.pending = None;
+        self.inner.handle_circuit_open(ctx, host).await
+    }
+
+    async fn should_intercept(&mut self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+        self.inner.should_intercept(ctx, req).await
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        ctx: &HttpContext,
+        req: &Request<Body>,
+        authority: hyper::http::uri::Authority,
+    ) -> hyper::http::uri::Authority {
+        self.inner.handle_connect_target(ctx, req, authority).await
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &crate::TlsFailure) {
+        self.inner.handle_tls_failure(ctx, failure).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_query(
+        &mut self,
+        ctx: &HttpContext,
+        query: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_query(ctx, query).await
+    }
+
+    #[cfg(feature = "dns")]
+    async fn handle_dns_response(
+        &mut self,
+        ctx: &HttpContext,
+        res: crate::dns::Message,
+    ) -> crate::dns::Message {
+        self.inner.handle_dns_response(ctx, res).await
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(Empty::new()))
+        .expect("Failed to build response")
+}
+
+/// A stable 64-bit FNV-1a hash, used to fingerprint requests.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn encode(interactions: &[Interaction]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+
+    for interaction in interactions {
+        out.extend_from_slice(
+            format!(
+                "{} {}\n{} {}\n",
+                interaction.fingerprint,
+                interaction.request,
+                interaction.status.as_u16(),
+                interaction.body.len()
+            )
+            .as_bytes(),
+        );
+
+        for (name, value) in &interaction.headers {
+            out.extend_from_slice(name.as_str().as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.push(b'\n');
+        }
+
+        out.push(b'\n');
+        out.extend_from_slice(&interaction.body);
+        out.push(b'\n');
+    }
+
+    out
+}
+
+fn decode(contents: &[u8]) -> Option<Vec<Interaction>> {
+    let mut rest = contents.strip_prefix(MAGIC)?;
+    let mut interactions = Vec::new();
+
+    let line = |rest: &mut &[u8]| -> Option<String> {
+        let (line, tail) = rest.split_once_str(b"\n")?;
+        *rest = tail;
+        line.to_str().ok().map(str::to_owned)
+    };
+
+    while !rest.is_empty() {
+        let request = line(&mut rest)?;
+        let (fingerprint, request) = request.split_once(' ')?;
+        let meta = line(&mut rest)?;
+        let (status, len) = meta.split_once(' ')?;
+        let status = StatusCode::from_u16(status.parse().ok()?).ok()?;
+        let len: usize = len.parse().ok()?;
+
+        let mut headers = HeaderMap::new();
+
+        loop {
+            let (header, tail) = rest.split_once_str(b"\n")?;
+            rest = tail;
+
+            if header.is_empty() {
+                break;
+            }
+
+            let (name, value) = header.split_once_str(b": ")?;
+            headers.append(
+                HeaderName::from_bytes(name).ok()?,
+                HeaderValue::from_bytes(value).ok()?,
+            );
+        }
+
+        let body = rest.get(..len)?;
+        rest = rest.get(len..)?.strip_prefix(b"\n")?;
+
+        interactions.push(Interaction {
+            fingerprint: fingerprint.to_owned(),
+            request: request.to_owned(),
+            status,
+            headers,
+            body: Bytes::copy_from_slice(body),
+            played: false,
+        });
+    }
+
+    Some(interactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("http://example.com/")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn response(body: &'static str) -> Response<Body> {
+        Response::builder()
+            .header("x-test", "a")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "hudsucker-vcr-test-{}-{:?}.vcr",
+            std::process::id(),
+            std::thread::current().id()
+        ))
+    }
+
+    async fn body(res: Response<Body>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=b"));
+        headers.append("set-cookie", HeaderValue::from_static("c=d"));
+
+        let interactions = vec![
+            Interaction {
+                fingerprint: "0123456789abcdef".to_owned(),
+                request: "GET http://example.com/".to_owned(),
+                status: StatusCode::OK,
+                headers,
+                body: Bytes::from_static(b"hello\n\nworld"),
+                played: true,
+            },
+            Interaction {
+                fingerprint: "fedcba9876543210".to_owned(),
+                request: "HEAD http://example.com/".to_owned(),
+                status: StatusCode::NO_CONTENT,
+                headers: HeaderMap::new(),
+                body: Bytes::new(),
+                played: false,
+            },
+        ];
+
+        let decoded = decode(&encode(&interactions)).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].fingerprint, interactions[0].fingerprint);
+        assert_eq!(decoded[0].request, interactions[0].request);
+        assert_eq!(decoded[0].headers, interactions[0].headers);
+        assert_eq!(decoded[0].body, interactions[0].body);
+        assert!(!decoded[0].played);
+        assert_eq!(decoded[1].status, StatusCode::NO_CONTENT);
+        assert!(decode(b"not a cassette").is_none());
+    }
+
+    #[tokio::test]
+    async fn records_and_replays() {
+        let path = path();
+        let mut recorder = VcrHandler::new(Cassette::new(&path), Mode::Record);
+
+        for body in ["first", "second"] {
+            let req = match recorder
+                .handle_request(&HttpContext::for_test(), request(body))
+                .await
+            {
+                RequestOrResponse::Request(req) => req,
+                RequestOrResponse::Response(_) => panic!("expected request"),
+            };
+            assert_eq!(req.into_body().collect().await.unwrap().to_bytes(), body);
+
+            let res = recorder
+                .handle_response(&HttpContext::for_test(), response(body))
+                .await;
+            assert_eq!(self::body(res).await, body);
+        }
+
+        recorder.cassette().save().await.unwrap();
+
+        let cassette = Cassette::load(&path).await.unwrap();
+        assert_eq!(cassette.len(), 2);
+
+        let mut player = VcrHandler::new(cassette, Mode::Replay).with_strict(true);
+
+        for body in ["second", "first", "first"] {
+            match player
+                .handle_request(&HttpContext::for_test(), request(body))
+                .await
+            {
+                RequestOrResponse::Response(res) => {
+                    assert_eq!(res.headers()["x-test"], "a");
+                    assert_eq!(self::body(res).await, body);
+                }
+                RequestOrResponse::Request(_) => panic!("expected response"),
+            }
+        }
+
+        match player
+            .handle_request(&HttpContext::for_test(), request("third"))
+            .await
+        {
+            RequestOrResponse::Response(res) => {
+                assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED)
+            }
+            RequestOrResponse::Request(_) => panic!("expected response"),
+        }
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwards_unmatched_requests() {
+        let mut player = VcrHandler::new(Cassette::new(path()), Mode::Replay);
+
+        assert!(matches!(
+            player
+                .handle_request(&HttpContext::for_test(), request("body"))
+                .await,
+            RequestOrResponse::Request(_)
+        ));
+        assert!(player.cassette().is_empty());
+    }
+
+    #[test]
+    fn fingerprints_requests() {
+        let handler = VcrHandler::new(Cassette::new(path()), Mode::Record)
+            .with_match_header(HeaderName::from_static("x-user"));
+        let req = |user| {
+            Request::builder()
+                .uri("http://example.com/")
+                .header("x-user", user)
+                .body(())
+                .unwrap()
+        };
+
+        assert_eq!(
+            handler.fingerprint(&req("a"), b""),
+            handler.fingerprint(&req("a"), b"")
+        );
+        assert_ne!(
+            handler.fingerprint(&req("a"), b""),
+            handler.fingerprint(&req("b"), b"")
+        );
+        assert_ne!(
+            handler.fingerprint(&req("a"), b""),
+            handler.fingerprint(&req("a"), b"body")
+        );
+
+        let handler = handler.with_match_body(false);
+        assert_eq!(
+            handler.fingerprint(&req("a"), b""),
+            handler.fingerprint(&req("a"), b"body")
+        );
+    }
+}
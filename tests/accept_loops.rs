@@ -0,0 +1,44 @@
+use hudsucker::{
+    test::{EchoServer, TestCa},
+    Proxy,
+};
+use std::net::SocketAddr;
+use tokio::{net::TcpListener, sync::oneshot};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn accepts_connections_on_several_loops() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_accept_loops(4)
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    let proxy = tokio::spawn(proxy.start());
+
+    let server = EchoServer::start().await.unwrap();
+    // Concurrent requests are sent on separate connections.
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::http(format!("http://{addr}")).unwrap())
+        .build()
+        .unwrap();
+    let requests = (0..32).map(|i| {
+        let request = client.get(server.url(&format!("/{i}"))).send();
+        tokio::spawn(async move { request.await.unwrap().status() })
+    });
+
+    for request in requests.collect::<Vec<_>>() {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+
+    tx.send(()).unwrap();
+    proxy.await.unwrap().unwrap();
+}
@@ -0,0 +1,164 @@
+use hudsucker::{
+    admin::{Admin, Bandwidth, Interception},
+    auth::HostPattern,
+    hyper_util::{client::legacy::Client, rt::TokioExecutor},
+    test::{EchoServer, TestCa},
+    Proxy,
+};
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::{net::TcpListener, sync::oneshot};
+
+struct Running {
+    proxy: SocketAddr,
+    admin: SocketAddr,
+    ca: TestCa,
+    _stop: oneshot::Sender<()>,
+}
+
+async fn listen() -> TcpListener {
+    TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap()
+}
+
+async fn start(admin: Admin, admin_addr: SocketAddr) -> Running {
+    let listener = listen().await;
+    let proxy_addr = listener.local_addr().unwrap();
+    let ca = TestCa::generate();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(ca.client_config())
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(Client::builder(TokioExecutor::new()).build(https))
+        .with_ca(ca.authority())
+        .with_admin(admin)
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    Running {
+        proxy: proxy_addr,
+        admin: admin_addr,
+        ca,
+        _stop: tx,
+    }
+}
+
+fn proxied_client(running: &Running) -> reqwest::Client {
+    reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(format!("http://{}", running.proxy)).unwrap())
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(running.ca.cert_pem().as_bytes()).unwrap(),
+        )
+        .build()
+        .unwrap()
+}
+
+async fn get(running: &Running, path: &str) -> Value {
+    let res = reqwest::get(format!("http://{}{}", running.admin, path))
+        .await
+        .unwrap();
+    serde_json::from_str(&res.text().await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn reports_health_and_stats() {
+    let listener = listen().await;
+    let addr = listener.local_addr().unwrap();
+    let running = start(Admin::with_listener(listener), addr).await;
+    let server = EchoServer::start().await.unwrap();
+
+    assert_eq!(get(&running, "/health").await["status"], "ok");
+
+    let client = proxied_client(&running);
+    for _ in 0..2 {
+        client.get(server.url("/")).send().await.unwrap();
+    }
+
+    let stats = get(&running, "/stats").await;
+    assert_eq!(stats["requests"], 2);
+    assert_eq!(stats["active_connections"], 1);
+
+    let connections = get(&running, "/connections").await;
+    assert_eq!(connections[0]["requests"], 2);
+}
+
+#[tokio::test]
+async fn limits_interception() {
+    let listener = listen().await;
+    let addr = listener.local_addr().unwrap();
+    let admin = Admin::with_listener(listener);
+    let running = start(admin.clone(), addr).await;
+    let server = EchoServer::start_https(&running.ca).await.unwrap();
+    let client = proxied_client(&running);
+
+    client.get(server.url("/")).send().await.unwrap();
+    assert_eq!(admin.stats().intercepted_tunnels, 1);
+
+    admin.set_interception(Interception {
+        deny: vec![HostPattern::new("localhost")],
+        ..Default::default()
+    });
+
+    // The tunnel is not intercepted, but the server's certificate is still trusted.
+    let client = proxied_client(&running);
+    client.get(server.url("/")).send().await.unwrap();
+
+    let stats = admin.stats();
+    assert_eq!(stats.tunnels, 2);
+    assert_eq!(stats.intercepted_tunnels, 1);
+}
+
+#[tokio::test]
+async fn accounts_bandwidth_per_host() {
+    let listener = listen().await;
+    let addr = listener.local_addr().unwrap();
+    let admin = Admin::with_listener(listener);
+    let running = start(admin.clone(), addr).await;
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxied_client(&running)
+        .post(server.url("/"))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "hello");
+
+    let bandwidth = get(&running, "/bandwidth").await;
+    assert_eq!(bandwidth["127.0.0.1"]["sent"], 5);
+    assert_eq!(bandwidth["127.0.0.1"]["received"], 5);
+
+    admin.set_interception(Interception {
+        deny: vec![HostPattern::new("localhost")],
+        ..Default::default()
+    });
+
+    let server = EchoServer::start_https(&running.ca).await.unwrap();
+    let client = proxied_client(&running);
+    client.get(server.url("/")).send().await.unwrap();
+    drop(client);
+
+    // The bytes of the tunnel are counted once it is closed.
+    let mut tunneled = Bandwidth::default();
+    for _ in 0..50 {
+        if let Some(bandwidth) = admin.bandwidth().get("localhost") {
+            tunneled = *bandwidth;
+            break;
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    assert!(tunneled.sent > 0);
+    assert!(tunneled.received > 0);
+}
@@ -0,0 +1,98 @@
+use hudsucker::{
+    certificate_authority::{CertificateAuthority, RcgenAuthority},
+    hyper::http::uri::Authority,
+    hyper_util::{client::legacy::Client, rt::TokioExecutor},
+    rustls::ServerConfig,
+    test::TestCa,
+    Proxy,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// An authority that records the hosts that it prefetches configs for.
+#[derive(Clone)]
+struct RecordingCa {
+    inner: Arc<RcgenAuthority>,
+    prefetched: Arc<Mutex<Vec<String>>>,
+}
+
+impl CertificateAuthority for RecordingCa {
+    async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
+        self.inner.gen_server_config(authority).await
+    }
+
+    async fn prefetch_server_config(&self, authority: &Authority) {
+        self.prefetched
+            .lock()
+            .unwrap()
+            .push(authority.host().to_owned());
+        self.inner.prefetch_server_config(authority).await;
+    }
+}
+
+async fn start(prefetch: bool) -> (SocketAddr, RecordingCa) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ca = RecordingCa {
+        inner: Arc::new(TestCa::generate().authority()),
+        prefetched: Default::default(),
+    };
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(Client::builder(TokioExecutor::new()).build_http())
+        .with_ca(ca.clone())
+        .with_certificate_prefetch(prefetch)
+        .build();
+    tokio::spawn(proxy.start());
+
+    (addr, ca)
+}
+
+async fn connect(proxy: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await.unwrap();
+    assert!(buf[..len].starts_with(b"HTTP/1.1 200"));
+
+    stream
+}
+
+#[tokio::test]
+async fn prefetches_certificates_for_connect_hosts() {
+    let (proxy, ca) = start(true).await;
+    let _stream = connect(proxy).await;
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while ca.prefetched.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(*ca.prefetched.lock().unwrap(), ["example.com"]);
+}
+
+#[tokio::test]
+async fn does_not_prefetch_by_default() {
+    let (proxy, ca) = start(false).await;
+    let _stream = connect(proxy).await;
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(ca.prefetched.lock().unwrap().is_empty());
+}
@@ -0,0 +1,127 @@
+use hudsucker::{
+    hyper::{Method, Request, Response},
+    rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair},
+    rustls::{
+        pki_types::{PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    test::TestCa,
+    Body, ClientAuth, ClientCertificate, HttpContext, HttpHandler, Proxy, RequestOrResponse,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::TlsConnector;
+
+#[derive(Clone, Default)]
+struct IdentityHandler(Arc<Mutex<Vec<Option<ClientCertificate>>>>);
+
+impl HttpHandler for IdentityHandler {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> RequestOrResponse {
+        if req.method() == Method::CONNECT {
+            return req.into();
+        }
+
+        self.0
+            .lock()
+            .unwrap()
+            .push(req.extensions().get::<ClientCertificate>().cloned());
+        Response::new(Body::from("ok")).into()
+    }
+}
+
+/// Sends a request for `https://example.com/` through the proxy, returning the raw response.
+async fn get(proxy: SocketAddr, config: ClientConfig) -> io::Result<String> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nhost: example.com:443\r\n\r\n")
+        .await?;
+
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("example.com").unwrap(), stream)
+        .await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+        .await?;
+
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await?;
+    Ok(res)
+}
+
+#[tokio::test]
+async fn authenticates_clients_with_certificates() {
+    let client_ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Client CA");
+    let client_ca = params.self_signed(&client_ca_key).unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "alice");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = params
+        .signed_by(&client_key, &client_ca, &client_ca_key)
+        .unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(client_ca.der().clone()).unwrap();
+
+    let ca = TestCa::generate();
+    let handler = IdentityHandler::default();
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(ca.authority())
+        .with_http_handler(handler.clone())
+        .with_client_auth(ClientAuth::from_roots(roots).unwrap())
+        .build();
+    tokio::spawn(proxy.start());
+
+    let mut server_roots = RootCertStore::empty();
+    server_roots.add(ca.cert_der().clone()).unwrap();
+    let config = ClientConfig::builder().with_root_certificates(server_roots);
+
+    let authenticated = config
+        .clone()
+        .with_client_auth_cert(
+            vec![client_cert.der().clone()],
+            PrivatePkcs8KeyDer::from(client_key.serialize_der()).into(),
+        )
+        .unwrap();
+    let res = get(addr, authenticated).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.ends_with("\r\n\r\nok"), "{}", res);
+
+    assert!(get(addr, config.with_no_client_auth()).await.is_err());
+
+    let identities = handler.0.lock().unwrap().clone();
+    assert_eq!(identities.len(), 1);
+
+    let certificate = identities[0].as_ref().unwrap();
+    assert_eq!(certificate.end_entity(), client_cert.der());
+}
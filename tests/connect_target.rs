@@ -0,0 +1,81 @@
+use hudsucker::{
+    hyper::{http::uri::Authority, Request},
+    test::{EchoServer, TestProxy},
+    Body, HttpContext, HttpHandler, NoopHandler,
+};
+use std::sync::{Arc, OnceLock};
+
+#[derive(Clone)]
+struct Redirect {
+    target: Arc<OnceLock<Authority>>,
+    intercept: bool,
+}
+
+impl HttpHandler for Redirect {
+    async fn should_intercept(&mut self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
+        self.intercept
+    }
+
+    async fn handle_connect_target(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+        _authority: Authority,
+    ) -> Authority {
+        self.target.get().unwrap().clone()
+    }
+}
+
+/// Start a proxy that redirects every tunnel to an HTTPS echo server that it trusts.
+async fn start(intercept: bool) -> (TestProxy, EchoServer) {
+    let target = Arc::new(OnceLock::new());
+    let handler = Redirect {
+        target: Arc::clone(&target),
+        intercept,
+    };
+    let proxy = TestProxy::start_with(handler, NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start_https(proxy.ca()).await.unwrap();
+
+    target
+        .set(
+            format!("localhost:{}", server.addr().port())
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+    (proxy, server)
+}
+
+#[tokio::test]
+async fn redirects_intercepted_tunnels() {
+    let (proxy, _server) = start(true).await;
+
+    let res = proxy
+        .client()
+        .get("https://redirected.test/echo")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-header-host"], "redirected.test");
+}
+
+#[tokio::test]
+async fn redirects_tunnels() {
+    let (proxy, _server) = start(false).await;
+
+    // The client connects to the echo server through the tunnel, so it verifies the server's
+    // certificate for `localhost`.
+    let res = proxy
+        .client()
+        .get("https://localhost:1/echo")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-header-host"], "localhost:1");
+}
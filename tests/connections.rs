@@ -0,0 +1,62 @@
+use hudsucker::{
+    hyper::{Request, Response},
+    test::{EchoServer, TestProxy},
+    Body, FreshConnection, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+    UpstreamConnection,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct ConnectionHandler {
+    connections: Arc<Mutex<Vec<UpstreamConnection>>>,
+}
+
+impl HttpHandler for ConnectionHandler {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        if req.headers().contains_key("x-fresh") {
+            req.extensions_mut().insert(FreshConnection);
+        }
+
+        req.into()
+    }
+
+    async fn handle_response(&mut self, _ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let connection = *res.extensions().get::<UpstreamConnection>().unwrap();
+        self.connections.lock().unwrap().push(connection);
+        res
+    }
+}
+
+#[tokio::test]
+async fn reuses_connections() {
+    let handler = ConnectionHandler::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+    let client = proxy.client();
+
+    for fresh in [false, false, true, false] {
+        let mut req = client.get(server.url("/"));
+
+        if fresh {
+            req = req.header("x-fresh", "1");
+        }
+
+        req.send().await.unwrap().bytes().await.unwrap();
+    }
+
+    let connections = handler.connections.lock().unwrap().clone();
+    let reused: Vec<_> = connections.iter().map(|c| c.is_reused()).collect();
+
+    assert_eq!(reused, [false, true, false, true]);
+    assert_eq!(connections[0].id(), connections[1].id());
+    assert_eq!(connections[1].requests(), 2);
+    assert_ne!(connections[1].id(), connections[2].id());
+    assert_eq!(connections[2].id(), connections[3].id());
+    assert_eq!(connections[0].remote_addr(), server.addr());
+}
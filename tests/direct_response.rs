@@ -0,0 +1,69 @@
+use hudsucker::{
+    hyper::{header::UPGRADE, Request, Response, StatusCode},
+    test::{EchoServer, TestProxy},
+    Body, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[derive(Clone, Default)]
+struct RejectWebSockets {
+    responses: Arc<AtomicUsize>,
+}
+
+impl HttpHandler for RejectWebSockets {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> RequestOrResponse {
+        if req.headers().contains_key(UPGRADE) {
+            RequestOrResponse::respond(StatusCode::FORBIDDEN, "WebSockets are disabled")
+        } else {
+            req.into()
+        }
+    }
+
+    async fn handle_response(&mut self, _ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+}
+
+#[tokio::test]
+async fn rejects_websocket_upgrades() {
+    let handler = RejectWebSockets::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy.client().get(server.url("/")).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(handler.responses.load(Ordering::Relaxed), 1);
+
+    // Nothing is listening on port 1, so the upgrade would fail if the server was dialed.
+    let mut stream = TcpStream::connect(proxy.addr()).await.unwrap();
+    stream
+        .write_all(
+            b"GET http://127.0.0.1:1/ HTTP/1.1\r\n\
+              Host: 127.0.0.1:1\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let mut buf = [0; 38];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HTTP/1.1 403 WebSockets are disabled\r\n");
+    assert_eq!(handler.responses.load(Ordering::Relaxed), 1);
+}
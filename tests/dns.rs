@@ -0,0 +1,70 @@
+use hudsucker::{
+    dns::{self, Message, Question},
+    test::{EchoServer, TestProxy},
+    HttpContext, HttpHandler, NoopHandler,
+};
+
+#[derive(Clone)]
+struct Rename;
+
+impl HttpHandler for Rename {
+    async fn handle_dns_query(&mut self, _ctx: &HttpContext, mut query: Message) -> Message {
+        for question in &mut query.questions {
+            question.name = "example.org".to_owned();
+        }
+
+        query
+    }
+}
+
+fn query() -> Message {
+    Message {
+        id: 1,
+        flags: 0x0100,
+        questions: vec![Question {
+            name: "example.com".to_owned(),
+            kind: dns::kind::A,
+            class: dns::CLASS_IN,
+        }],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn rewrites_doh_queries() {
+    let proxy = TestProxy::start_with(Rename, NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy
+        .client()
+        .post(server.url("/dns-query"))
+        .header("content-type", "application/dns-message")
+        .body(query().to_bytes())
+        .send()
+        .await
+        .unwrap();
+
+    let message = Message::parse(&res.bytes().await.unwrap()).unwrap();
+    assert_eq!(message.questions[0].name, "example.org");
+}
+
+#[tokio::test]
+async fn forwards_other_bodies_unchanged() {
+    let proxy = TestProxy::start_with(Rename, NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy
+        .client()
+        .post(server.url("/dns-query"))
+        .header("content-type", "application/dns-message")
+        .body("not dns")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.text().await.unwrap(), "not dns");
+}
@@ -0,0 +1,20 @@
+use hudsucker::{
+    hyper_util::{
+        client::legacy::{connect::HttpConnector, Client},
+        rt::TokioExecutor,
+    },
+    test::TestCa,
+    Dscp, Proxy,
+};
+use std::net::SocketAddr;
+
+#[test]
+#[should_panic(expected = "DSCPs can only be set for the built-in clients")]
+fn rejects_dscp_for_custom_clients() {
+    Proxy::builder()
+        .with_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_client(Client::builder(TokioExecutor::new()).build(HttpConnector::new()))
+        .with_ca(TestCa::generate().authority())
+        .with_dscp("example.com", Dscp::LOWER_EFFORT)
+        .build();
+}
@@ -0,0 +1,173 @@
+use hudsucker::{
+    events::{Event, Events, TunnelCloseReason},
+    hyper_util::{client::legacy::Client, rt::TokioExecutor},
+    test::{EchoServer, TestCa},
+    BodyDirection, Proxy,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, oneshot},
+};
+
+async fn start(events: Events) -> (SocketAddr, oneshot::Sender<()>) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ca = TestCa::generate();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(Client::builder(TokioExecutor::new()).build_http())
+        .with_ca(ca.authority())
+        .with_events(events)
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    (addr, tx)
+}
+
+async fn next(rx: &mut broadcast::Receiver<Event>) -> Event {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("timed out waiting for an event")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn streams_flow_events() {
+    let events = Events::new(64);
+    let mut rx = events.subscribe();
+    let (addr, _stop) = start(events).await;
+    let server = EchoServer::start().await.unwrap();
+
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::http(format!("http://{}", addr)).unwrap())
+        .build()
+        .unwrap();
+
+    let res = client
+        .post(server.url("/hello"))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "hello");
+
+    let flow = match next(&mut rx).await {
+        Event::FlowStarted {
+            flow_id,
+            method,
+            uri,
+            ..
+        } => {
+            assert_eq!(method, "POST");
+            assert_eq!(uri, server.url("/hello"));
+            flow_id
+        }
+        event => panic!("unexpected event {:?}", event),
+    };
+
+    let mut request_bytes = 0;
+    let mut response_bytes = 0;
+
+    loop {
+        match next(&mut rx).await {
+            Event::BodyProgress {
+                flow_id,
+                direction,
+                bytes,
+                ..
+            } => {
+                assert_eq!(flow_id, flow);
+                match direction {
+                    BodyDirection::Request => request_bytes = bytes,
+                    BodyDirection::Response => response_bytes = bytes,
+                }
+            }
+            Event::ResponseHeaders { flow_id, .. } => {
+                assert_eq!(flow_id, flow);
+                assert_eq!(request_bytes, 5);
+            }
+            Event::FlowCompleted {
+                flow_id,
+                status,
+                bytes,
+                ..
+            } => {
+                assert_eq!(flow_id, flow);
+                assert_eq!(status, 200);
+                assert_eq!(bytes, response_bytes);
+                break;
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    assert_eq!(request_bytes, 5);
+    assert_eq!(response_bytes, 5);
+}
+
+#[tokio::test]
+async fn closes_idle_tunnels() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let events = Events::new(64);
+    let mut rx = events.subscribe();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(Client::builder(TokioExecutor::new()).build_http())
+        .with_ca(TestCa::generate().authority())
+        .with_events(events)
+        .with_tunnel_idle_timeout(Duration::from_millis(200))
+        .build();
+    tokio::spawn(proxy.start());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", upstream_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await.unwrap();
+    assert!(buf[..len].starts_with(b"HTTP/1.1 200"));
+
+    stream.write_all(b"ping").await.unwrap();
+    let (_server, _) = upstream.accept().await.unwrap();
+
+    let end = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("tunnel was not closed");
+    assert_eq!(end.unwrap(), 0);
+
+    loop {
+        if let Event::TunnelClosed {
+            authority,
+            reason,
+            bytes_sent,
+            bytes_received,
+            ..
+        } = next(&mut rx).await
+        {
+            assert_eq!(authority, upstream_addr.to_string());
+            assert_eq!(reason, TunnelCloseReason::IdleTimeout);
+            assert_eq!(bytes_sent, 4);
+            assert_eq!(bytes_received, 0);
+            break;
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use hudsucker::{
+    fingerprint::ClientIdentity,
+    hyper::Request,
+    test::{EchoServer, TestProxy},
+    Body, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Identities(Arc<Mutex<Vec<ClientIdentity>>>);
+
+impl HttpHandler for Identities {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        if let Some(identity) = ctx.extensions.get::<ClientIdentity>() {
+            self.0.lock().unwrap().push(identity);
+        }
+
+        req.into()
+    }
+}
+
+#[tokio::test]
+async fn identifies_clients_by_tls_fingerprint() {
+    let identities = Identities::default();
+    let proxy = TestProxy::start_with(identities.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start_https(proxy.ca()).await.unwrap();
+
+    for _ in 0..2 {
+        proxy.client().get(server.url("/")).send().await.unwrap();
+    }
+
+    let identities = identities.0.lock().unwrap();
+    let requests = identities
+        .iter()
+        .filter(|identity| identity.tls().is_some())
+        .collect::<Vec<_>>();
+
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0], requests[1]);
+
+    let tls = requests[0].tls().unwrap();
+    assert!(tls.ja4().starts_with("t13d"));
+    assert!(tls.ja3().starts_with("771,"));
+}
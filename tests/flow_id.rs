@@ -0,0 +1,92 @@
+use hudsucker::{
+    hyper::{Request, Response},
+    test::{EchoServer, TestProxy},
+    Body, FlowId, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct FlowHandler {
+    requests: Arc<Mutex<Vec<(u64, FlowId)>>>,
+    responses: Arc<Mutex<Vec<(u64, FlowId)>>>,
+}
+
+impl HttpHandler for FlowHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let flow_id = *req.extensions().get::<FlowId>().unwrap();
+        self.requests.lock().unwrap().push((ctx.flow_id, flow_id));
+        req.into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let flow_id = *res.extensions().get::<FlowId>().unwrap();
+        self.responses.lock().unwrap().push((ctx.flow_id, flow_id));
+        res
+    }
+}
+
+#[tokio::test]
+async fn correlates_requests_and_responses() {
+    let handler = FlowHandler::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+    let client = proxy.client();
+
+    for _ in 0..2 {
+        client.get(server.url("/")).send().await.unwrap();
+    }
+
+    let requests = handler.requests.lock().unwrap().clone();
+    let responses = handler.responses.lock().unwrap().clone();
+
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests, responses);
+    assert_ne!(requests[0].0, requests[1].0);
+
+    for (ctx, extension) in requests {
+        assert_eq!(FlowId(ctx), extension);
+    }
+}
+
+#[derive(Clone, Default)]
+struct ExtensionsHandler {
+    paths: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+#[derive(Clone)]
+struct Path(String);
+
+impl HttpHandler for ExtensionsHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        assert!(!ctx.extensions.contains::<Path>());
+        ctx.extensions.insert(Path(req.uri().path().to_owned()));
+        req.into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let path = ctx.extensions.get::<Path>().map(|path| path.0);
+        self.paths.lock().unwrap().push(path);
+        res
+    }
+}
+
+#[tokio::test]
+async fn shares_extensions_within_a_flow() {
+    let handler = ExtensionsHandler::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+    let client = proxy.client();
+
+    for path in ["/a", "/b"] {
+        client.get(server.url(path)).send().await.unwrap();
+    }
+
+    assert_eq!(
+        *handler.paths.lock().unwrap(),
+        [Some("/a".to_owned()), Some("/b".to_owned())]
+    );
+}
@@ -0,0 +1,162 @@
+use hudsucker::test::TestProxy;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// Starts an origin server that answers one request with a raw response, written in parts. The
+/// server waits for a message on the returned channel before writing each part after the first.
+async fn origin(parts: Vec<&'static [u8]>) -> (SocketAddr, mpsc::UnboundedSender<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let (mut tcp, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 4096];
+        let mut read = Vec::new();
+
+        while !read.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = tcp.read(&mut buf).await.unwrap();
+            read.extend_from_slice(&buf[..n]);
+        }
+
+        for (i, part) in parts.into_iter().enumerate() {
+            if i > 0 {
+                rx.recv().await;
+            }
+
+            tcp.write_all(part).await.unwrap();
+            tcp.flush().await.unwrap();
+        }
+
+        rx.recv().await;
+    });
+
+    (addr, tx)
+}
+
+/// Reads from a stream until the data read so far ends with `suffix`.
+async fn read_until(tcp: &mut TcpStream, read: &mut Vec<u8>, suffix: &[u8]) {
+    let mut buf = [0; 4096];
+
+    while !read.ends_with(suffix) {
+        let n = tokio::time::timeout(Duration::from_secs(5), tcp.read(&mut buf))
+            .await
+            .expect("timed out waiting for data")
+            .unwrap();
+        assert_ne!(
+            n,
+            0,
+            "connection closed, read {:?}",
+            String::from_utf8_lossy(read)
+        );
+        read.extend_from_slice(&buf[..n]);
+    }
+}
+
+async fn request(proxy: &TestProxy, origin: SocketAddr) -> TcpStream {
+    let mut tcp = TcpStream::connect(proxy.addr()).await.unwrap();
+    tcp.write_all(
+        format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+            origin, origin
+        )
+        .as_bytes(),
+    )
+    .await
+    .unwrap();
+    tcp
+}
+
+#[tokio::test]
+async fn streams_chunks_as_they_arrive() {
+    let proxy = TestProxy::start().await.unwrap();
+    let (addr, next) = origin(vec![
+        b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n",
+        b"6\r\n world\r\n",
+        b"0\r\n\r\n",
+    ])
+    .await;
+
+    let mut tcp = request(&proxy, addr).await;
+    let mut read = Vec::new();
+
+    read_until(&mut tcp, &mut read, b"5\r\nhello\r\n").await;
+    let head = String::from_utf8_lossy(&read).to_ascii_lowercase();
+    assert!(head.contains("transfer-encoding: chunked"), "{}", head);
+    assert!(!head.contains("content-length"), "{}", head);
+
+    next.send(()).unwrap();
+    read_until(&mut tcp, &mut read, b"6\r\n world\r\n").await;
+
+    next.send(()).unwrap();
+    read_until(&mut tcp, &mut read, b"0\r\n\r\n").await;
+}
+
+#[tokio::test]
+async fn preserves_content_length() {
+    let proxy = TestProxy::start().await.unwrap();
+    let (addr, next) = origin(vec![
+        b"HTTP/1.1 200 OK\r\ncontent-length: 11\r\n\r\nhello",
+        b" world",
+    ])
+    .await;
+
+    let mut tcp = request(&proxy, addr).await;
+    let mut read = Vec::new();
+
+    read_until(&mut tcp, &mut read, b"hello").await;
+    let head = String::from_utf8_lossy(&read).to_ascii_lowercase();
+    assert!(head.contains("content-length: 11"), "{}", head);
+    assert!(!head.contains("transfer-encoding"), "{}", head);
+
+    next.send(()).unwrap();
+    read_until(&mut tcp, &mut read, b" world").await;
+}
+
+#[tokio::test]
+async fn streams_request_chunks_as_they_arrive() {
+    let proxy = TestProxy::start().await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let (mut tcp, _) = listener.accept().await.unwrap();
+        let mut read = Vec::new();
+
+        read_until(&mut tcp, &mut read, b"5\r\nhello\r\n").await;
+        let head = String::from_utf8_lossy(&read).to_ascii_lowercase();
+        assert!(head.contains("transfer-encoding: chunked"), "{}", head);
+        tx.send(()).unwrap();
+
+        read_until(&mut tcp, &mut read, b"0\r\n\r\n").await;
+        tcp.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let mut tcp = TcpStream::connect(proxy.addr()).await.unwrap();
+    tcp.write_all(
+        format!(
+            "POST http://{}/ HTTP/1.1\r\nHost: {}\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n",
+            addr, addr
+        )
+        .as_bytes(),
+    )
+    .await
+    .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("timed out waiting for the first chunk");
+
+    tcp.write_all(b"0\r\n\r\n").await.unwrap();
+
+    let mut read = Vec::new();
+    read_until(&mut tcp, &mut read, b"\r\n\r\n").await;
+    assert!(read.starts_with(b"HTTP/1.1 204"));
+}
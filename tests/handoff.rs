@@ -0,0 +1,57 @@
+use hudsucker::{
+    handoff::Handoff,
+    test::{EchoServer, TestCa},
+    Proxy,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn hands_listeners_to_successors() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ca = TestCa::generate();
+    let handoff = Handoff::new();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(ca.authority())
+        .with_handoff(handoff.clone())
+        .build();
+    let running = tokio::spawn(proxy.start());
+
+    let inherited = loop {
+        match handoff.listener() {
+            Ok(listener) => break std::net::TcpListener::from(listener),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    inherited.set_nonblocking(true).unwrap();
+
+    // The successor accepts on the same socket while the predecessor drains.
+    let successor = Proxy::builder()
+        .with_listener(TcpListener::from_std(inherited).unwrap())
+        .with_rustls_client()
+        .with_ca(ca.authority())
+        .build();
+    tokio::spawn(successor.start());
+
+    handoff.drain();
+    tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(handoff.listener().is_err());
+
+    let server = EchoServer::start().await.unwrap();
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(format!("http://{}", addr)).unwrap())
+        .build()
+        .unwrap();
+    let res = client.get(server.url("/")).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+}
@@ -0,0 +1,139 @@
+use hudsucker::{
+    icap::IcapHandler,
+    test::{EchoServer, TestProxy},
+    NoopHandler,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Reads an ICAP request, returning it as a string.
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    loop {
+        let read = stream.read(&mut buf).await.unwrap();
+        assert_ne!(read, 0);
+        request.extend_from_slice(&buf[..read]);
+
+        let text = String::from_utf8_lossy(&request);
+        let Some((head, rest)) = text.split_once("\r\n\r\n") else {
+            continue;
+        };
+
+        let null_body = head
+            .split("null-body=")
+            .nth(1)
+            .and_then(|offset| offset.trim().parse::<usize>().ok());
+
+        let complete = match null_body {
+            Some(offset) => rest.len() >= offset,
+            None => rest.ends_with("0\r\n\r\n"),
+        };
+
+        if complete {
+            return text.into_owned();
+        }
+    }
+}
+
+/// Starts an ICAP server that blocks requests for `/blocked`, and upper-cases response bodies.
+async fn start_icap_server() -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            tokio::spawn(async move {
+                let request = read_request(&mut stream).await;
+
+                let response = if request.starts_with("REQMOD") {
+                    if request.contains("/blocked HTTP/1.1") {
+                        let head = "HTTP/1.1 403 Forbidden\r\nx-blocked-by: icap\r\n\r\n";
+                        format!(
+                            "ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, res-body={}\r\n\r\n{}7\r\nblocked\r\n0\r\n\r\n",
+                            head.len(),
+                            head
+                        )
+                    } else {
+                        "ICAP/1.0 204 No Content\r\nEncapsulated: null-body=0\r\n\r\n".to_owned()
+                    }
+                } else {
+                    let body = request.rsplit("\r\n\r\n").nth(1).unwrap().to_owned();
+                    let body = body.split("\r\n").nth(1).unwrap().to_uppercase();
+                    let head = "HTTP/1.1 200 OK\r\nx-adapted: yes\r\n\r\n";
+                    format!(
+                        "ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, res-body={}\r\n\r\n{}{:x}\r\n{}\r\n0\r\n\r\n",
+                        head.len(),
+                        head,
+                        body.len(),
+                        body
+                    )
+                };
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn adapts_requests_and_responses() {
+    let icap = start_icap_server().await;
+    let handler = IcapHandler::new()
+        .with_reqmod(format!("icap://{}/reqmod", icap).parse().unwrap())
+        .with_respmod(format!("icap://{}/respmod", icap).parse().unwrap());
+    let proxy = TestProxy::start_with(handler, NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy
+        .client()
+        .post(server.url("/echo"))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-adapted"], "yes");
+    assert_eq!(res.text().await.unwrap(), "HELLO");
+
+    let res = proxy
+        .client()
+        .get(server.url("/blocked"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403);
+    assert_eq!(res.headers()["x-blocked-by"], "icap");
+    assert_eq!(res.text().await.unwrap(), "blocked");
+}
+
+#[tokio::test]
+async fn fails_closed() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let icap = listener.local_addr().unwrap();
+    drop(listener);
+
+    let handler = IcapHandler::new()
+        .with_reqmod(format!("icap://{}/reqmod", icap).parse().unwrap())
+        .with_fail_closed(true);
+    let proxy = TestProxy::start_with(handler, NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy.client().get(server.url("/")).send().await.unwrap();
+    assert_eq!(res.status(), 502);
+}
@@ -0,0 +1,111 @@
+use hudsucker::{
+    builder::{ProxyBuilder, WantsHandlers},
+    certificate_authority::CertificateAuthority,
+    hyper::Uri,
+    hyper_util::client::legacy::connect::Connect,
+    test::{EchoServer, TestCa},
+    vcr::Cassette,
+    HttpHandler, NoopHandler, Proxy,
+};
+use std::{future::Pending, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+/// Start a proxy from a preset on an ephemeral port, and return its address.
+async fn start<C, CA, H>(
+    builder: ProxyBuilder<WantsHandlers<C, CA, H, NoopHandler, Pending<()>>>,
+) -> SocketAddr
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    CA: CertificateAuthority,
+    H: HttpHandler,
+{
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(builder.with_listener(listener).build().start());
+    addr
+}
+
+fn client(proxy: SocketAddr, ca: Option<&TestCa>) -> reqwest::Client {
+    let mut builder =
+        reqwest::Client::builder().proxy(reqwest::Proxy::all(format!("http://{proxy}")).unwrap());
+
+    if let Some(ca) = ca {
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(ca.cert_pem().as_bytes()).unwrap(),
+        );
+    }
+
+    builder.build().unwrap()
+}
+
+#[tokio::test]
+async fn recording_proxy_records_responses() {
+    let path = std::env::temp_dir().join(format!("hudsucker-preset-{}.vcr", std::process::id()));
+    let ca = TestCa::generate();
+    let proxy = start(Proxy::recording_proxy(ca.authority(), &path)).await;
+    let server = EchoServer::start().await.unwrap();
+
+    let res = client(proxy, Some(&ca))
+        .get(server.url("/recorded"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    // The cassette is saved in the background after the response is recorded.
+    let mut recorded = 0;
+    for _ in 0..50 {
+        if let Ok(cassette) = Cassette::load(&path).await {
+            recorded = cassette.len();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(recorded, 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn passthrough_gateway_tunnels_without_intercepting() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let proxy = start(Proxy::passthrough_gateway(addr)).await;
+    // The client only trusts the server's CA, so the tunnel must not be intercepted.
+    let ca = TestCa::generate();
+    let server = EchoServer::start_https(&ca).await.unwrap();
+
+    let res = client(proxy, Some(&ca))
+        .get(server.url("/tunneled"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-path"], "/tunneled");
+}
+
+#[tokio::test]
+async fn reverse_sends_requests_to_origin() {
+    let server = EchoServer::start().await.unwrap();
+    let origin: Uri = format!("http://{}", server.addr()).parse().unwrap();
+    let proxy = start(Proxy::reverse(origin)).await;
+
+    let res = reqwest::get(format!("http://{proxy}/reversed?a=b"))
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-path"], "/reversed?a=b");
+    assert_eq!(
+        res.headers()["x-echo-header-host"],
+        server.addr().to_string()
+    );
+
+    let res = client(proxy, None).get("https://example.com/").send().await;
+    assert!(res.is_err());
+}
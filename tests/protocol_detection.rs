@@ -0,0 +1,166 @@
+use hudsucker::{
+    hyper::Request,
+    rustls::pki_types::ServerName,
+    test::{EchoServer, TestCa},
+    Body, HttpContext, HttpHandler, ProtocolDetection, Proxy, RequestOrResponse, SniRoute,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+use tokio_rustls::TlsConnector;
+
+#[derive(Clone, Default)]
+struct ClientAddrs(Arc<Mutex<Vec<(String, SocketAddr)>>>);
+
+impl HttpHandler for ClientAddrs {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        self.0
+            .lock()
+            .unwrap()
+            .push((req.method().to_string(), ctx.client_addr));
+        req.into()
+    }
+
+    async fn should_intercept(&mut self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
+        false
+    }
+}
+
+async fn start(
+    handler: ClientAddrs,
+    routes: Vec<(&str, SniRoute)>,
+) -> (SocketAddr, oneshot::Sender<()>) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let mut proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_http_handler(handler)
+        .with_protocol_detection(
+            ProtocolDetection::new()
+                .with_tls(true)
+                .with_socks(true)
+                .with_proxy_protocol(true),
+        )
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        });
+
+    for (pattern, route) in routes {
+        proxy = proxy.with_sni_route(pattern, route);
+    }
+
+    let proxy = proxy.build();
+    tokio::spawn(proxy.start());
+
+    (addr, tx)
+}
+
+async fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !response.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0);
+        response.extend_from_slice(&buf[..n]);
+    }
+
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn tunnels_socks_connections() {
+    let handler = ClientAddrs::default();
+    let (addr, _stop) = start(handler.clone(), Vec::new()).await;
+    let server = EchoServer::start().await.unwrap();
+    let port = server.addr().port().to_be_bytes();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [5, 0]);
+
+    stream
+        .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+        .await
+        .unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0);
+
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+
+    let requests = handler.0.lock().unwrap().clone();
+    assert_eq!(requests[0].0, "CONNECT");
+}
+
+#[tokio::test]
+async fn reads_proxy_protocol_headers() {
+    let handler = ClientAddrs::default();
+    let (addr, _stop) = start(handler.clone(), Vec::new()).await;
+    let server = EchoServer::start().await.unwrap();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "PROXY TCP4 192.0.2.1 127.0.0.1 5000 {}\r\nGET {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                addr.port(),
+                server.url("/"),
+                server.addr()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+
+    let requests = handler.0.lock().unwrap().clone();
+    assert_eq!(
+        requests,
+        [("GET".to_owned(), "192.0.2.1:5000".parse().unwrap())]
+    );
+}
+
+#[tokio::test]
+async fn routes_tls_connections_to_backends() {
+    let handler = ClientAddrs::default();
+    let ca = TestCa::generate();
+    let server = EchoServer::start_https(&ca).await.unwrap();
+    let backend = SniRoute::Backend(server.addr().to_string().parse().unwrap());
+    let (addr, _stop) = start(handler.clone(), vec![("localhost", backend)]).await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(ca.client_config()))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200");
+
+    let requests = handler.0.lock().unwrap().clone();
+    assert_eq!(requests[0].0, "CONNECT");
+}
@@ -0,0 +1,63 @@
+use hudsucker::{
+    hyper::Request,
+    hyper_util::{
+        client::legacy::{connect::HttpConnector, Client},
+        rt::TokioExecutor,
+    },
+    test::{EchoServer, TestCa},
+    Body, ConnectTo, HttpContext, HttpHandler, Proxy, RequestOrResponse,
+};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+#[derive(Clone)]
+struct Pin(SocketAddr);
+
+impl HttpHandler for Pin {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        req.extensions_mut().insert(ConnectTo::new(self.0));
+        req.into()
+    }
+}
+
+#[tokio::test]
+async fn connects_to_pinned_address() {
+    let server = EchoServer::start().await.unwrap();
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_http_handler(Pin(server.addr()))
+        .build();
+    tokio::spawn(proxy.start());
+
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::http(format!("http://{}", addr)).unwrap())
+        .build()
+        .unwrap();
+
+    let res = client.get("http://pinned.test/echo").send().await.unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-header-host"], "pinned.test");
+}
+
+#[test]
+#[should_panic(expected = "Addresses can only be pinned for the built-in clients")]
+fn rejects_pinned_addresses_for_custom_clients() {
+    Proxy::builder()
+        .with_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .with_client(Client::builder(TokioExecutor::new()).build(HttpConnector::new()))
+        .with_ca(TestCa::generate().authority())
+        .with_resolve("example.com", 443, SocketAddr::from(([127, 0, 0, 1], 443)))
+        .build();
+}
@@ -0,0 +1,84 @@
+use hudsucker::{
+    hyper::{Request, Uri},
+    test::{EchoServer, TestProxy},
+    Body, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use std::sync::{Arc, OnceLock};
+
+/// Sends every request to a different server, with the scheme and authority of that server.
+#[derive(Clone, Default)]
+struct Retarget {
+    target: Arc<OnceLock<Uri>>,
+}
+
+impl HttpHandler for Retarget {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        let Some(target) = self.target.get() else {
+            return req.into();
+        };
+
+        if req.method() != hudsucker::hyper::Method::CONNECT {
+            let mut parts = target.clone().into_parts();
+            parts.path_and_query = req.uri().path_and_query().cloned();
+            *req.uri_mut() = Uri::from_parts(parts).unwrap();
+        }
+
+        req.into()
+    }
+}
+
+#[tokio::test]
+async fn sends_http_requests_to_https_servers() {
+    let handler = Retarget::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start_https(proxy.ca()).await.unwrap();
+    handler
+        .target
+        .set(server.url("/").parse().unwrap())
+        .unwrap();
+
+    let res = proxy
+        .client()
+        .get("http://original.test/echo")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers()["x-echo-header-host"],
+        format!("localhost:{}", server.addr().port())
+    );
+}
+
+#[tokio::test]
+async fn sends_intercepted_https_requests_to_http_servers() {
+    let handler = Retarget::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+    handler
+        .target
+        .set(server.url("/").parse().unwrap())
+        .unwrap();
+
+    let res = proxy
+        .client()
+        .get("https://localhost:1/echo")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers()["x-echo-header-host"],
+        server.addr().to_string()
+    );
+}
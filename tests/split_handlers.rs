@@ -0,0 +1,72 @@
+use hudsucker::{
+    hyper::{header::HeaderValue, Request, Response},
+    test::{EchoServer, TestCa},
+    Body, HttpContext, Proxy, RequestHandler, RequestOrResponse, ResponseHandler,
+};
+use std::net::SocketAddr;
+use tokio::{net::TcpListener, sync::oneshot};
+
+#[derive(Clone)]
+struct TagRequests;
+
+impl RequestHandler for TagRequests {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        req.headers_mut()
+            .insert("x-request-tag", HeaderValue::from_static("request"));
+        req.into()
+    }
+}
+
+#[derive(Clone)]
+struct TagResponses;
+
+impl ResponseHandler for TagResponses {
+    async fn handle_response(
+        &mut self,
+        _ctx: &HttpContext,
+        mut res: Response<Body>,
+    ) -> Response<Body> {
+        res.headers_mut()
+            .insert("x-response-tag", HeaderValue::from_static("response"));
+        res
+    }
+}
+
+#[tokio::test]
+async fn passes_requests_and_responses_to_separate_handlers() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_response_handler(TagResponses)
+        .with_request_handler(TagRequests)
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    let proxy = tokio::spawn(proxy.start());
+
+    let server = EchoServer::start().await.unwrap();
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::http(format!("http://{addr}")).unwrap())
+        .build()
+        .unwrap();
+    let res = client.get(server.url("/")).send().await.unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-header-x-request-tag"], "request");
+    assert_eq!(res.headers()["x-response-tag"], "response");
+
+    tx.send(()).unwrap();
+    proxy.await.unwrap().unwrap();
+}
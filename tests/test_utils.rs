@@ -0,0 +1,37 @@
+use hudsucker::test::{EchoServer, TestProxy};
+
+#[tokio::test]
+async fn echoes_http() {
+    let proxy = TestProxy::start().await.unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    let res = proxy
+        .client()
+        .post(server.url("/echo?a=b"))
+        .header("x-test", "value")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-echo-method"], "POST");
+    assert_eq!(res.headers()["x-echo-path"], "/echo?a=b");
+    assert_eq!(res.headers()["x-echo-header-x-test"], "value");
+    assert_eq!(res.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn echoes_https() {
+    let proxy = TestProxy::start().await.unwrap();
+    let server = EchoServer::start_https(proxy.ca()).await.unwrap();
+
+    let res = proxy
+        .client()
+        .get(server.url("/status/418"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 418);
+}
@@ -0,0 +1,169 @@
+use hudsucker::{
+    hyper::{http::uri::Authority, Request},
+    test::{EchoServer, TestCa},
+    Body, HttpContext, HttpHandler, PassthroughList, Proxy, TlsFailure, TlsFailureKind,
+    TlsFailureSide,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::oneshot};
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<TlsFailure>>>);
+
+impl HttpHandler for Recorder {
+    async fn handle_tls_failure(&mut self, _ctx: &HttpContext, failure: &TlsFailure) {
+        self.0.lock().unwrap().push(failure.clone());
+    }
+}
+
+impl Recorder {
+    async fn failures(&self) -> Vec<TlsFailure> {
+        for _ in 0..50 {
+            let failures = self.0.lock().unwrap().clone();
+            if !failures.is_empty() {
+                return failures;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        Vec::new()
+    }
+}
+
+#[derive(Clone)]
+struct Redirect {
+    target: Authority,
+    recorder: Recorder,
+}
+
+impl HttpHandler for Redirect {
+    async fn handle_connect_target(
+        &mut self,
+        _ctx: &HttpContext,
+        _req: &Request<Body>,
+        _authority: Authority,
+    ) -> Authority {
+        self.target.clone()
+    }
+
+    async fn handle_tls_failure(&mut self, ctx: &HttpContext, failure: &TlsFailure) {
+        self.recorder.handle_tls_failure(ctx, failure).await
+    }
+}
+
+#[tokio::test]
+async fn stops_intercepting_hosts_that_reject_certificates() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+    let recorder = Recorder::default();
+    let list = PassthroughList::new();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_http_handler(recorder.clone())
+        .with_auto_passthrough(list.clone())
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    let proxy = tokio::spawn(proxy.start());
+
+    let ca = TestCa::generate();
+    let server = EchoServer::start_https(&ca).await.unwrap();
+    let client = |ca: Option<&TestCa>| {
+        let builder = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(format!("http://{addr}")).unwrap());
+        match ca {
+            Some(ca) => builder.add_root_certificate(
+                reqwest::Certificate::from_pem(ca.cert_pem().as_bytes()).unwrap(),
+            ),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    };
+
+    // The client does not trust the proxy's CA, like a client that pins certificates, so it
+    // rejects the certificate of the proxy.
+    assert!(client(None).get(server.url("/")).send().await.is_err());
+
+    let failures = recorder.failures().await;
+    let [failure] = failures.as_slice() else {
+        panic!("expected one failure, got {failures:?}");
+    };
+    assert_eq!(failure.side, TlsFailureSide::Client);
+    assert_eq!(failure.kind, TlsFailureKind::CertificateRejected);
+    assert_eq!(failure.authority.port_u16(), Some(server.addr().port()));
+    assert!(failure.suggest_passthrough);
+    assert_eq!(list.hosts(), ["localhost"]);
+
+    // Once the host is tunneled, a client that trusts the server's CA can connect to it.
+    let res = client(Some(&ca)).get(server.url("/")).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    tx.send(()).unwrap();
+    proxy.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn reports_upstream_failures_for_the_original_authority() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+    let recorder = Recorder::default();
+    let list = PassthroughList::new();
+    let proxy_ca = TestCa::generate();
+
+    // The proxy does not trust the CA of the server that the tunnel is redirected to.
+    let server = EchoServer::start_https(&TestCa::generate()).await.unwrap();
+    let handler = Redirect {
+        target: server.addr().to_string().parse().unwrap(),
+        recorder: recorder.clone(),
+    };
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(proxy_ca.authority())
+        .with_http_handler(handler)
+        .with_auto_passthrough(list.clone())
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    let proxy = tokio::spawn(proxy.start());
+
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(format!("http://{addr}")).unwrap())
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(proxy_ca.cert_pem().as_bytes()).unwrap(),
+        )
+        .build()
+        .unwrap();
+    let res = client.get("https://original.test/").send().await.unwrap();
+    assert_eq!(res.status(), 502);
+
+    let failures = recorder.failures().await;
+    let [failure] = failures.as_slice() else {
+        panic!("expected one failure, got {failures:?}");
+    };
+    assert_eq!(failure.side, TlsFailureSide::Upstream);
+    assert_eq!(failure.kind, TlsFailureKind::InvalidCertificate);
+    assert_eq!(failure.authority, "original.test:443");
+    assert!(!failure.suggest_passthrough);
+    assert!(list.hosts().is_empty());
+
+    tx.send(()).unwrap();
+    proxy.await.unwrap().unwrap();
+}
@@ -0,0 +1,94 @@
+use hudsucker::{
+    hyper_util::{client::legacy::Client, rt::TokioExecutor},
+    test::TestCa,
+    Proxy,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+async fn start(half_close: bool) -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(Client::builder(TokioExecutor::new()).build_http())
+        .with_ca(TestCa::generate().authority())
+        .with_tunnel_half_close(half_close)
+        .build();
+    tokio::spawn(proxy.start());
+
+    addr
+}
+
+/// Starts a server that reads until the client shuts down its writes, then replies with the
+/// number of bytes that it read.
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _ = stream.write_all(buf.len().to_string().as_bytes()).await;
+    });
+
+    addr
+}
+
+async fn connect(proxy: SocketAddr, server: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server).as_bytes())
+        .await
+        .unwrap();
+
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await.unwrap();
+    assert!(buf[..len].starts_with(b"HTTP/1.1 200"));
+
+    stream
+}
+
+#[tokio::test]
+async fn forwards_half_close() {
+    let proxy = start(true).await;
+    let server = start_server().await;
+    let mut stream = connect(proxy, server).await;
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut buf = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, "5");
+}
+
+#[tokio::test]
+async fn closes_tunnel_on_eof() {
+    let proxy = start(false).await;
+    let server = start_server().await;
+    let mut stream = connect(proxy, server).await;
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut buf = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, "");
+}
@@ -0,0 +1,94 @@
+use hudsucker::{
+    hyper::Request, test::TestCa, Body, HttpContext, HttpHandler, Proxy, RequestOrResponse,
+    UpstreamProxy,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+#[derive(Clone)]
+struct ForwardHandler(UpstreamProxy);
+
+impl HttpHandler for ForwardHandler {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        req.extensions_mut().insert(self.0.clone());
+        req.into()
+    }
+}
+
+/// Starts a fake upstream proxy, which responds with the request line of each request.
+async fn start_upstream() -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+
+                let line = head.split(|&b| b == b'\r').next().unwrap().to_vec();
+                let mut res = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", line.len())
+                    .into_bytes();
+                res.extend(line);
+                stream.write_all(&res).await.unwrap();
+            });
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn forwards_requests_through_upstream_proxy() {
+    let upstream = start_upstream().await;
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_rustls_client()
+        .with_ca(TestCa::generate().authority())
+        .with_http_handler(ForwardHandler(UpstreamProxy::new(
+            upstream.to_string().parse().unwrap(),
+        )))
+        .build();
+    tokio::spawn(proxy.start());
+
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::http(format!("http://{}", addr)).unwrap())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get("http://unreachable.invalid/hello")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.text().await.unwrap(),
+        "GET http://unreachable.invalid/hello HTTP/1.1"
+    );
+}
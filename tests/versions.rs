@@ -0,0 +1,54 @@
+use hudsucker::{
+    hyper::{Request, Response, Version},
+    test::{EchoServer, TestProxy},
+    Body, HttpContext, HttpHandler, NoopHandler, RequestOrResponse,
+};
+use std::sync::{Arc, Mutex};
+
+type Versions = (Version, Option<Version>);
+
+#[derive(Clone, Default)]
+struct VersionHandler {
+    requests: Arc<Mutex<Vec<Versions>>>,
+    responses: Arc<Mutex<Vec<Versions>>>,
+}
+
+impl HttpHandler for VersionHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        let versions = (ctx.downstream_version, ctx.upstream_version);
+        self.requests.lock().unwrap().push(versions);
+        req.into()
+    }
+
+    async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let versions = (ctx.downstream_version, ctx.upstream_version);
+        self.responses.lock().unwrap().push(versions);
+        res
+    }
+}
+
+#[tokio::test]
+async fn exposes_protocol_versions() {
+    let handler = VersionHandler::default();
+    let proxy = TestProxy::start_with(handler.clone(), NoopHandler::default())
+        .await
+        .unwrap();
+    let server = EchoServer::start().await.unwrap();
+
+    proxy
+        .client()
+        .get(server.url("/"))
+        .version(Version::HTTP_10)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *handler.requests.lock().unwrap(),
+        [(Version::HTTP_10, None)]
+    );
+    assert_eq!(
+        *handler.responses.lock().unwrap(),
+        [(Version::HTTP_10, Some(Version::HTTP_11))]
+    );
+}
@@ -1,12 +1,27 @@
 use async_http_proxy::http_connect_tokio;
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use hudsucker::{
     certificate_authority::RcgenAuthority,
     rcgen::{CertificateParams, KeyPair},
-    tokio_tungstenite::tungstenite::Message,
+    tokio_tungstenite::tungstenite::{
+        self,
+        client::IntoClientRequest,
+        handshake::server::{Request, Response},
+        http::header::SEC_WEBSOCKET_PROTOCOL,
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Message,
+    },
+    Proxy, WebSocketContext, WebSocketDirection, WebSocketHandler,
+};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
 };
-use std::sync::atomic::Ordering;
-use tokio::net::TcpStream;
 
 #[allow(unused)]
 mod common;
@@ -195,3 +210,488 @@ async fn noop() {
     stop_server.send(()).unwrap();
     stop_proxy.send(()).unwrap();
 }
+
+#[tokio::test]
+async fn closes_with_message_too_big() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = tokio::sync::oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(common::native_tls_client())
+        .with_ca(build_ca())
+        .with_websocket_config(WebSocketConfig {
+            max_message_size: Some(16),
+            ..Default::default()
+        })
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    let server = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (tcp, _) = server.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        let mut received = Vec::new();
+
+        while let Some(Ok(msg)) = ws.next().await {
+            let close = msg.is_close();
+            received.push(msg);
+
+            if close {
+                break;
+            }
+        }
+
+        received
+    });
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    ws.send(Message::Text("small".to_owned())).await.unwrap();
+    ws.send(Message::Text("a".repeat(32))).await.unwrap();
+
+    let received = received.await.unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0], Message::Text("small".to_owned()));
+    assert!(matches!(
+        &received[1],
+        Message::Close(Some(frame)) if frame.code == CloseCode::Size
+    ));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn forwards_messages_through_buffer() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = tokio::sync::oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(common::native_tls_client())
+        .with_ca(build_ca())
+        .with_websocket_buffer(2)
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    let server = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (tcp, _) = server.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        let mut received = Vec::new();
+
+        while let Some(Ok(msg)) = ws.next().await {
+            // A slow peer, which the proxy buffers messages for.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let close = msg.is_close();
+            received.push(msg);
+
+            if close {
+                break;
+            }
+        }
+
+        received
+    });
+
+    let stream = connect_through(proxy_addr, server_addr).await;
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    for i in 0..20 {
+        ws.send(Message::Text(i.to_string())).await.unwrap();
+    }
+    ws.close(None).await.unwrap();
+
+    let received = received.await.unwrap();
+    assert_eq!(received.len(), 21);
+    for (i, msg) in received[..20].iter().enumerate() {
+        assert_eq!(*msg, Message::Text(i.to_string()));
+    }
+    assert!(received[20].is_close());
+
+    stop_proxy.send(()).unwrap();
+}
+
+/// A handler that stops forwarding messages as soon as the WebSocket is opened.
+#[derive(Clone)]
+struct Hangup;
+
+impl WebSocketHandler for Hangup {
+    async fn handle_websocket(
+        self,
+        _ctx: WebSocketContext,
+        _stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        _sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+    }
+}
+
+#[tokio::test]
+async fn closes_buffered_sink() {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(common::native_tls_client())
+        .with_ca(build_ca())
+        .with_websocket_handler(Hangup)
+        .with_websocket_buffer(2)
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    let server = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (tcp, _) = server.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        ws.next().await
+    });
+
+    let stream = connect_through(proxy_addr, server_addr).await;
+    let (_ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), received)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        matches!(received, Some(Ok(Message::Close(_)))),
+        "{:?}",
+        received
+    );
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn connects_to_pinned_address() {
+    let (server_addr, stop_server) = common::start_http_server().await.unwrap();
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, stopped) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_native_tls_client()
+        .with_ca(build_ca())
+        .with_resolve("pinned.test", 80, server_addr)
+        .with_graceful_shutdown(async {
+            stopped.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(&mut stream, "pinned.test", 80)
+        .await
+        .unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async("ws://pinned.test", stream)
+        .await
+        .unwrap();
+
+    ws.send(Message::Text("hello".to_owned())).await.unwrap();
+
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_string(), common::WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[derive(Clone, Default)]
+struct EventHandler {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl EventHandler {
+    fn push(&self, ctx: &WebSocketContext, event: String) {
+        let side = match ctx {
+            WebSocketContext::ClientToServer { .. } => "client",
+            WebSocketContext::ServerToClient { .. } => "server",
+        };
+
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", side, event));
+    }
+
+    async fn wait_for(&self, len: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let events = self.events.lock().unwrap().clone();
+
+            if events.len() >= len {
+                return events;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("received {:?}", self.events.lock().unwrap());
+    }
+}
+
+impl WebSocketHandler for EventHandler {
+    async fn handle_close(&mut self, ctx: &WebSocketContext, code: CloseCode, reason: &str) {
+        self.push(ctx, format!("close {} {}", u16::from(code), reason));
+    }
+
+    async fn handle_protocol_error(&mut self, ctx: &WebSocketContext, _err: &tungstenite::Error) {
+        self.push(ctx, "error".to_owned());
+    }
+
+    async fn handle_connect_error(&mut self, ctx: &WebSocketContext, _err: &tungstenite::Error) {
+        self.push(ctx, "connect error".to_owned());
+    }
+}
+
+async fn start_event_proxy(handler: EventHandler) -> (SocketAddr, oneshot::Sender<()>) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(common::native_tls_client())
+        .with_ca(build_ca())
+        .with_websocket_handler(handler)
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    (addr, tx)
+}
+
+async fn connect_through(proxy_addr: SocketAddr, server_addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn reports_close() {
+    let handler = EventHandler::default();
+    let (proxy_addr, stop_proxy) = start_event_proxy(handler.clone()).await;
+    let (server_addr, stop_server) = common::start_http_server().await.unwrap();
+
+    let stream = connect_through(proxy_addr, server_addr).await;
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    ws.close(Some(CloseFrame {
+        code: CloseCode::Normal,
+        reason: "bye".into(),
+    }))
+    .await
+    .unwrap();
+
+    let events = handler.wait_for(1).await;
+    assert_eq!(events[0], "client close 1000 bye", "{:?}", events);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn reports_connect_error() {
+    let handler = EventHandler::default();
+    let (proxy_addr, stop_proxy) = start_event_proxy(handler.clone()).await;
+
+    let server_addr = {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let stream = connect_through(proxy_addr, server_addr).await;
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    match ws.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Error),
+        msg => panic!("unexpected message {:?}", msg),
+    }
+
+    assert_eq!(handler.wait_for(1).await, ["client connect error"]);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[derive(Clone, Default)]
+struct ContextHandler {
+    contexts: Arc<Mutex<Vec<(WebSocketContext, Message)>>>,
+}
+
+impl WebSocketHandler for ContextHandler {
+    async fn handle_message(&mut self, ctx: &WebSocketContext, msg: Message) -> Option<Message> {
+        self.contexts
+            .lock()
+            .unwrap()
+            .push((ctx.clone(), msg.clone()));
+        Some(msg)
+    }
+}
+
+/// Starts a websocket echo server that selects the `chat` subprotocol.
+#[allow(clippy::result_large_err)]
+async fn start_subprotocol_server() -> SocketAddr {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            tokio::spawn(async move {
+                let callback = |_: &Request, mut res: Response| {
+                    res.headers_mut()
+                        .insert(SEC_WEBSOCKET_PROTOCOL, "chat".parse().unwrap());
+                    Ok(res)
+                };
+                let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+                    .await
+                    .unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_text() {
+                        ws.send(msg).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn passes_socket_context() {
+    let handler = ContextHandler::default();
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (stop_proxy, rx) = oneshot::channel::<()>();
+
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(common::native_tls_client())
+        .with_ca(build_ca())
+        .with_websocket_handler(handler.clone())
+        .with_graceful_shutdown(async {
+            rx.await.unwrap_or_default();
+        })
+        .build();
+    tokio::spawn(proxy.start());
+
+    let server_addr = start_subprotocol_server().await;
+    let mut sockets = Vec::new();
+
+    for text in ["first", "second"] {
+        let stream = connect_through(proxy_addr, server_addr).await;
+        let mut req = format!("ws://{}/{}", server_addr, text)
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, "chat".parse().unwrap());
+
+        let (mut ws, _) = tokio_tungstenite::client_async(req, stream).await.unwrap();
+        ws.send(Message::Text(text.to_owned())).await.unwrap();
+        sockets.push(ws);
+    }
+
+    for (ws, text) in sockets.iter_mut().zip(["first", "second"]) {
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Text(text.to_owned())
+        );
+    }
+
+    let contexts = handler.contexts.lock().unwrap().clone();
+    assert_eq!(contexts.len(), 4);
+
+    for text in ["first", "second"] {
+        let contexts: Vec<_> = contexts
+            .iter()
+            .filter(|(_, msg)| *msg == Message::Text(text.to_owned()))
+            .map(|(ctx, _)| ctx)
+            .collect();
+
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(contexts[0].direction(), WebSocketDirection::ClientToServer);
+        assert_eq!(contexts[1].direction(), WebSocketDirection::ServerToClient);
+        assert_eq!(contexts[0].socket_id(), contexts[1].socket_id());
+
+        for ctx in contexts {
+            assert_eq!(
+                ctx.uri().to_string(),
+                format!("ws://{}/{}", server_addr, text)
+            );
+            assert_eq!(ctx.subprotocol(), Some("chat"));
+            assert_eq!(ctx.client_addr().ip(), proxy_addr.ip());
+        }
+    }
+
+    assert_ne!(contexts[0].0.socket_id(), contexts[3].0.socket_id());
+
+    stop_proxy.send(()).unwrap();
+}